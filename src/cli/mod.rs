@@ -1,9 +1,1248 @@
-use clap::Parser;
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
+    path::{Path, PathBuf},
+};
+
+use clap::{parser::ValueSource, ArgMatches, Args, Parser, Subcommand};
+use clap_complete::Shell;
+
+#[cfg(feature = "scripting")]
+use crate::server::Schedule;
+#[cfg(feature = "mdns")]
+use crate::server::ServiceRegistration;
+use crate::server::{
+    parse_percent, parse_record_type, parse_size, AdMode, BlockMode, ClasslessDelegation,
+    DomainSuffix, ForwardZone, ListenerSpec, LocalRecord, MailExchange, MismatchPolicy, NtaSpec,
+    NxdomainRedirect, RecordType, Retention, ReverseMapping, SearchList, SecondarySpec,
+    ServiceLocation, SloSpec, Subnet, ZoneSpec, DEFAULT_PORT, DEFAULT_PROVENANCE_OPTION,
+    DUMMY_IPV6_ADDRESS, UDP_PAYLOAD_SIZE,
+};
+use crate::server::{Json, REDACTED};
+
+// Without the mdns feature --service is only parsed to be refused (see check_features).
+#[cfg(not(feature = "mdns"))]
+type ServiceRegistration = String;
+
+pub mod setup;
+
+// An example for the long help: what it does, and the environment and arguments of
+// a command line that does it, and the file it writes its output to if any.
+struct Example {
+    what: &'static str,
+    env: &'static [(&'static str, &'static str)],
+    args: &'static [&'static str],
+    stdout: Option<&'static str>,
+}
+
+const EXAMPLES: &[Example] = &[
+    Example {
+        what: "Answer every query with fake data (the default)",
+        env: &[],
+        args: &[],
+        stdout: None,
+    },
+    Example {
+        what: "Forward queries to an upstream resolver",
+        env: &[],
+        args: &["--resolver", "8.8.8.8:53"],
+        stdout: None,
+    },
+    Example {
+        what: "Forward queries to 1.1.1.1, failing over to 8.8.8.8 when it doesn't answer",
+        env: &[],
+        args: &["--resolver", "1.1.1.1:53", "--resolver", "8.8.8.8:53"],
+        stdout: None,
+    },
+    Example {
+        what: "Advertise a web server for DNS-SD browsing, forwarding everything else",
+        env: &[],
+        args: &[
+            "--resolver",
+            "8.8.8.8:53",
+            "--service",
+            "web:_http._tcp:host.lan:8080:path=/",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Route a test domain's mail to a local server, with a backup",
+        env: &[],
+        args: &[
+            "--mx",
+            "example.test=10:mail.example.test",
+            "--mx",
+            "example.test=20:backup.example.test",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Override a few names locally, the alias answered with its target's address",
+        env: &[],
+        args: &[
+            "--resolver",
+            "8.8.8.8:53",
+            "--record",
+            "dev.local A 127.0.0.1",
+            "--record",
+            "www.local CNAME dev.local",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Point service lookups for a test domain at a local web server",
+        env: &[],
+        args: &[
+            "--srv",
+            "_http._tcp.example.test=10:0:8080:web.example.test",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Answer the names in a hosts file, forwarding everything else",
+        env: &[],
+        args: &[
+            "--resolver",
+            "8.8.8.8:53",
+            "--hosts-file",
+            "/etc/hosts",
+            "--hosts-ttl",
+            "60",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Send a VPN's internal names to its own server, everything else to a public one",
+        env: &[],
+        args: &[
+            "--resolver",
+            "1.1.1.1:53",
+            "--forward-zone",
+            "corp.example.com=10.0.0.2:53",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Block the domains of a hosts-format blocklist, answering 0.0.0.0 and :: for them",
+        env: &[],
+        args: &[
+            "--resolver",
+            "8.8.8.8:53",
+            "--blocklist",
+            "ads.txt",
+            "--block-mode",
+            "null",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Serve a zone from its zone file, refusing other names",
+        env: &[],
+        args: &[
+            "--zone",
+            "example.test",
+            "zones/example.test.zone",
+            "--zone-refuse-outside",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Serve a zone as a secondary of its primary, telling another server of new versions",
+        env: &[],
+        args: &[
+            "--secondary",
+            "example.test=192.0.2.53:53",
+            "--also-notify",
+            "192.0.2.54:53",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Expose a test instance for a single zone, A and AAAA only",
+        env: &[],
+        args: &[
+            "--resolver",
+            "8.8.8.8:53",
+            "--only-names",
+            "example.com",
+            "--only-types",
+            "A,AAAA",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Serve the LAN on the standard port",
+        env: &[],
+        args: &[
+            "--bind",
+            "0.0.0.0",
+            "--port",
+            "53",
+            "--resolver",
+            "8.8.8.8:53",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Serve two networks, telling their queries apart in the logs and stats",
+        env: &[],
+        args: &["--bind", "office=10.0.0.1:53", "--bind", "vpn=10.8.0.1:53"],
+        stdout: None,
+    },
+    Example {
+        what: "Same, configured through the environment",
+        env: &[("DNS_SERVER_RESOLVER", "1.1.1.1:53")],
+        args: &[],
+        stdout: None,
+    },
+    Example {
+        what: "Send typos under your own domain to a landing page",
+        env: &[],
+        args: &[
+            "--resolver",
+            "8.8.8.8:53",
+            "--nxdomain-redirect",
+            "example.com=portal.example.com",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Let an appliance that asks for \"printer\" find printer.lan",
+        env: &[],
+        args: &[
+            "--resolver",
+            "8.8.8.8:53",
+            "--search-list",
+            "192.168.1.20/32=lan",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Block what a threat-intelligence feed lists, in the order of the feeds",
+        env: &[],
+        args: &[
+            "--resolver",
+            "8.8.8.8:53",
+            "--rpz",
+            "local.rpz",
+            "--rpz",
+            "feed.rpz",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Stop a zone with expired signatures from failing for the next two hours",
+        env: &[],
+        args: &["--resolver", "8.8.8.8:53", "--nta", "broken.example=2h"],
+        stdout: None,
+    },
+    Example {
+        what: "Replay yesterday's traffic against a new policy, answering from the capture",
+        env: &[],
+        args: &[
+            "--only-types",
+            "A,AAAA",
+            "replay",
+            "--from-capture",
+            "queries.capture",
+        ],
+        stdout: None,
+    },
+    Example {
+        what: "Check the local records without serving them",
+        env: &[],
+        args: &["--service", "web:_http._tcp:host.lan:8080", "check"],
+        stdout: None,
+    },
+    Example {
+        what: "See what a new upstream supports before forwarding to it",
+        env: &[],
+        args: &["probe", "9.9.9.9:53", "--samples", "50"],
+        stdout: None,
+    },
+    Example {
+        what: "Ask an upstream for a name's mail exchangers, with DNSSEC records",
+        env: &[],
+        args: &["query", "9.9.9.9:53", "example.com", "MX", "--dnssec"],
+        stdout: None,
+    },
+    Example {
+        what: "Install bash completions",
+        env: &[],
+        args: &["completions", "bash"],
+        stdout: Some("/etc/bash_completion.d/codecrafters-dns-server"),
+    },
+];
+
+// The Examples section of the long help, written out from EXAMPLES, so that the
+// command lines shown are the ones the tests parse.
+fn examples_help() -> String {
+    let quote = |word: &str| match word
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_.,:/=@%+".contains(c))
+    {
+        true => word.to_string(),
+        false => format!("'{}'", word),
+    };
+    let mut help = String::from("Examples:");
+    for example in EXAMPLES {
+        let env = example
+            .env
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value));
+        let args = example.args.iter().map(|arg| quote(arg));
+        let mut command: Vec<String> = env
+            .chain([env!("CARGO_PKG_NAME").to_string()])
+            .chain(args)
+            .collect();
+        if let Some(path) = example.stdout {
+            command.push(format!("> {}", path));
+        }
+        help.push_str(&format!(
+            "\n  {}:\n    {}\n",
+            example.what,
+            command.join(" ")
+        ));
+    }
+    help.pop();
+    help
+}
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 #[command(next_line_help = true)]
+#[command(after_long_help = examples_help())]
 pub struct CliArgs {
-    #[arg(long)]
-    pub resolver: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub listeners: ListenerArgs,
+
+    #[command(flatten)]
+    pub upstreams: UpstreamArgs,
+
+    #[command(flatten)]
+    pub records: RecordArgs,
+
+    #[command(flatten)]
+    pub transfers: TransferArgs,
+
+    #[command(flatten)]
+    pub security: SecurityArgs,
+
+    #[command(flatten)]
+    pub overload: OverloadArgs,
+
+    #[command(flatten)]
+    pub responses: ResponseArgs,
+
+    #[command(flatten)]
+    pub debugging: DebuggingArgs,
+
+    #[command(flatten)]
+    pub artifacts: ArtifactArgs,
+
+    #[command(flatten)]
+    pub anomalies: AnomalyArgs,
+
+    #[command(flatten)]
+    pub slos: SloArgs,
+}
+
+impl CliArgs {
+    // Fails on a flag whose cargo feature this build was compiled without, rather than
+    // ignoring it: the flags of the optional features are parsed in every build.
+    pub fn check_features(&self) -> Result<(), String> {
+        let flags = [
+            (
+                "--service",
+                "mdns",
+                cfg!(feature = "mdns") || self.records.service.is_empty(),
+            ),
+            (
+                "--trust-anchors",
+                "dnssec",
+                cfg!(feature = "dnssec") || self.security.trust_anchors.is_none(),
+            ),
+            (
+                "--policy-script",
+                "scripting",
+                cfg!(feature = "scripting") || self.security.policy_script.is_none(),
+            ),
+        ];
+        match flags.into_iter().find(|(_, _, available)| !available) {
+            Some((flag, feature, _)) => Err(format!(
+                "{flag} needs the '{feature}' feature, and this build was compiled without it \
+                 (rebuild with --features {feature})"
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+// The effective settings, for support bundles: each option that has a value, by its
+// long name, with its values and where they came from (the command line, the
+// environment or the default). An option declared with hide_env_values holds a
+// secret, and its values are written as REDACTED.
+pub fn effective_config(command: &clap::Command, matches: &ArgMatches) -> Json {
+    Json::object(command.get_arguments().filter_map(|arg| {
+        let id = arg.get_id().as_str();
+        let source = match matches.value_source(id)? {
+            ValueSource::CommandLine => "command-line",
+            ValueSource::EnvVariable => "env",
+            _ => "default",
+        };
+        let values: Vec<String> = match arg.is_hide_env_values_set() {
+            true => vec![String::from(REDACTED)],
+            false => matches
+                .get_raw(id)?
+                .map(|value| value.to_string_lossy().into_owned())
+                .collect(),
+        };
+        Some((
+            arg.get_long().unwrap_or(id),
+            Json::object([
+                ("source", Json::from(source)),
+                ("values", Json::strings(values)),
+            ]),
+        ))
+    }))
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Prints a shell completion script to stdout.
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Checks the local records configured by the other options, like named-checkzone,
+    /// and exits; the exit status is 1 if any of them are in error.
+    Check,
+    /// Handles the queries of a capture (--capture) again, with the configuration given
+    /// by the other options, and compares the responses with the captured ones; the
+    /// exit status is 1 if more of them diverge than --max-divergences.
+    Replay {
+        /// The capture to replay.
+        capture: PathBuf,
+
+        /// Answers from the captured responses instead of the upstream, to test the
+        /// local configuration alone.
+        #[arg(long)]
+        from_capture: bool,
+
+        /// Spaces the queries as they were captured, instead of replaying them as fast
+        /// as possible.
+        #[arg(long)]
+        original_pacing: bool,
+
+        /// How many responses may diverge before the replay fails.
+        #[arg(long, value_name = "COUNT", default_value_t = 0)]
+        max_divergences: usize,
+    },
+    /// Runs a battery of checks against an upstream (EDNS, the largest query it gets
+    /// over UDP, TCP, DNSSEC, cookies, 0x20, NXDOMAIN and ANY handling, response
+    /// times), prints what it found and the settings suggested for it, and exits; the
+    /// exit status is 1 if the upstream answered none of the timed queries.
+    Probe {
+        /// The upstream's address, e.g. 192.0.2.53:53.
+        upstream: SocketAddr,
+
+        /// A name the upstream can answer for; nonexistent names are made up under it.
+        #[arg(long, value_name = "NAME", default_value = "example.com")]
+        name: String,
+
+        /// How many queries to time.
+        #[arg(long, value_name = "COUNT", default_value_t = 20)]
+        samples: usize,
+
+        /// How long to wait for each response, in milliseconds.
+        #[arg(long, value_name = "MS", default_value_t = 2000)]
+        timeout: u64,
+    },
+    /// Asks an upstream a single question, like dig, prints the answers and exits; the
+    /// exit status is 1 if the upstream didn't answer or failed to (e.g. SERVFAIL).
+    Query {
+        /// The upstream's address, e.g. 192.0.2.53:53.
+        upstream: SocketAddr,
+
+        /// The name to ask about.
+        name: String,
+
+        /// The record type to ask for.
+        #[arg(value_name = "TYPE", default_value = "A", value_parser = parse_record_type)]
+        r#type: RecordType,
+
+        /// How long to wait for the response, in milliseconds.
+        #[arg(long, value_name = "MS", default_value_t = 2000)]
+        timeout: u64,
+
+        /// The UDP payload size to advertise with EDNS; 0 sends no OPT record.
+        #[arg(long, value_name = "BYTES", default_value_t = UDP_PAYLOAD_SIZE)]
+        edns_size: u16,
+
+        /// Asks for DNSSEC records (sets DO).
+        #[arg(long)]
+        dnssec: bool,
+
+        /// Fails on a truncated response instead of asking again over TCP.
+        #[arg(long)]
+        no_tcp: bool,
+    },
+}
+
+// Every option can also be set through a DNS_SERVER_* environment variable;
+// an explicit command-line flag takes precedence over the environment.
+
+#[derive(Args)]
+#[command(next_help_heading = "Listeners")]
+pub struct ListenerArgs {
+    /// Address to serve on, optionally named for logs and stats (e.g. office=10.0.0.1:53),
+    /// on --port unless given with a port; repeat to serve on several.
+    #[arg(
+        long,
+        env = "DNS_SERVER_BIND",
+        value_name = "[NAME=]ADDR[:PORT]",
+        value_delimiter = ',',
+        default_value = "127.0.0.1",
+        value_parser = ListenerSpec::parse
+    )]
+    pub bind: Vec<ListenerSpec>,
+
+    /// Port to serve on, for the --bind addresses given without one; 0 picks a free port.
+    #[arg(long, env = "DNS_SERVER_PORT", value_name = "PORT", default_value_t = DEFAULT_PORT)]
+    pub port: u16,
+
+    /// Unix socket on which to take operator commands, like pinning an RRset ("pin
+    /// example.com A 300 192.0.2.1"), one per connection.
+    #[arg(long, env = "DNS_SERVER_CONTROL_SOCKET", value_name = "PATH")]
+    pub control_socket: Option<PathBuf>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Upstreams")]
+pub struct UpstreamArgs {
+    /// Address (IP:port, or host:port) of the DNS resolver to forward queries to
+    /// (repeatable): a query the first doesn't answer in time, or answers SERVFAIL,
+    /// goes to the next.
+    #[arg(
+        long,
+        env = "DNS_SERVER_RESOLVER",
+        value_name = "ADDR",
+        value_delimiter = ','
+    )]
+    pub resolver: Vec<String>,
+
+    /// Forwards the names at or below ZONE to the upstream at IP:port rather than to
+    /// --resolver (repeatable), e.g. corp.example.com=10.0.0.2:53; the closest zone wins.
+    #[arg(long, env = "DNS_SERVER_FORWARD_ZONES", value_name = "ZONE=IP:PORT", value_delimiter = ',', value_parser = ForwardZone::parse)]
+    pub forward_zone: Vec<ForwardZone>,
+
+    /// IPv6 address the dummy resolver (without --resolver) answers AAAA questions with.
+    #[arg(
+        long,
+        env = "DNS_SERVER_DUMMY_IPV6_ADDRESS",
+        value_name = "ADDR",
+        default_value_t = DUMMY_IPV6_ADDRESS
+    )]
+    pub dummy_ipv6_address: Ipv6Addr,
+
+    /// File in which learned upstream state (e.g. round-trip times) is kept across restarts.
+    #[arg(long, env = "DNS_SERVER_UPSTREAM_STATE_FILE", value_name = "PATH")]
+    pub upstream_state_file: Option<PathBuf>,
+
+    /// Entries of the upstream state file older than this many seconds are discarded on load.
+    #[arg(
+        long,
+        env = "DNS_SERVER_UPSTREAM_STATE_MAX_AGE",
+        value_name = "SECONDS",
+        default_value_t = 86400
+    )]
+    pub upstream_state_max_age: u64,
+
+    /// Most queries per second sent upstream for bulk work like warm-up; queries
+    /// forwarded for clients are never held back.
+    #[arg(
+        long,
+        env = "DNS_SERVER_UPSTREAM_MAX_QPS",
+        value_name = "QPS",
+        default_value_t = 10.0
+    )]
+    pub upstream_max_qps: f64,
+
+    /// How long to wait for the upstream's response before asking again, in
+    /// milliseconds.
+    #[arg(
+        long,
+        env = "DNS_SERVER_UPSTREAM_TIMEOUT_MS",
+        value_name = "MS",
+        default_value_t = 2000,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub upstream_timeout_ms: u64,
+
+    /// How long to wait for the upstream's answer over TCP, in milliseconds, when it
+    /// truncated it over UDP; 0 passes truncated answers on as they are.
+    #[arg(
+        long,
+        env = "DNS_SERVER_UPSTREAM_TCP_TIMEOUT_MS",
+        value_name = "MS",
+        default_value_t = 2000
+    )]
+    pub upstream_tcp_timeout_ms: u64,
+
+    /// How many times a query the upstream didn't answer in time is sent again, with a
+    /// new ID, before the client gets SERVFAIL.
+    #[arg(
+        long,
+        env = "DNS_SERVER_UPSTREAM_RETRIES",
+        value_name = "COUNT",
+        default_value_t = 2
+    )]
+    pub upstream_retries: u32,
+
+    /// With several --resolver upstreams, how many queries in a row one fails before it
+    /// is asked last, after the others, for --upstream-skip-secs.
+    #[arg(
+        long,
+        env = "DNS_SERVER_UPSTREAM_SKIP_FAILURES",
+        value_name = "COUNT",
+        default_value = "3"
+    )]
+    pub upstream_skip_failures: NonZeroU32,
+
+    /// How long an upstream that kept failing is asked last, in seconds.
+    #[arg(
+        long,
+        env = "DNS_SERVER_UPSTREAM_SKIP_SECS",
+        value_name = "SECONDS",
+        default_value_t = 30
+    )]
+    pub upstream_skip_secs: u64,
+
+    /// Opens the circuit breaker after this many forwarded queries in a row got no
+    /// response from the upstream: queries then fail fast with SERVFAIL, but for one
+    /// probe per interval, until a probe gets a response. Off by default.
+    #[arg(long, env = "DNS_SERVER_BREAKER_FAILURES", value_name = "COUNT")]
+    pub breaker_failures: Option<NonZeroU32>,
+
+    /// How long the open circuit breaker waits before its first probe, in
+    /// milliseconds; each probe without a response doubles it.
+    #[arg(
+        long,
+        env = "DNS_SERVER_BREAKER_PROBE_INTERVAL_MS",
+        value_name = "MS",
+        default_value_t = 1000,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub breaker_probe_interval_ms: u64,
+
+    /// The longest the open circuit breaker waits between probes, in milliseconds.
+    #[arg(
+        long,
+        env = "DNS_SERVER_BREAKER_MAX_PROBE_INTERVAL_MS",
+        value_name = "MS",
+        default_value_t = 30000,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub breaker_max_probe_interval_ms: u64,
+
+    /// Caches the upstream's positive answers until their TTLs run out. Each worker
+    /// thread keeps a cache of its own.
+    #[arg(long, env = "DNS_SERVER_CACHE")]
+    pub cache: bool,
+
+    /// Most answer sets the cache keeps; when full, the least recently used go.
+    #[arg(
+        long,
+        env = "DNS_SERVER_CACHE_SIZE",
+        value_name = "ENTRIES",
+        default_value_t = 10000,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub cache_size: u64,
+
+    /// Names to look up (A) through the upstream at startup, paced, to warm its cache
+    /// and our round-trip estimates.
+    #[arg(
+        long,
+        env = "DNS_SERVER_WARM_UP",
+        value_name = "NAMES",
+        value_delimiter = ','
+    )]
+    pub warm_up: Vec<String>,
+
+    /// Asks this share of the forwarded questions (e.g. 5%) a second time, over another
+    /// socket, and alerts if the answers differ in more than TTLs and order.
+    #[arg(long, env = "DNS_SERVER_VERIFY_ANSWERS", value_name = "PERCENT", value_parser = parse_percent)]
+    pub verify_answers: Option<u32>,
+
+    /// Upstream (IP:port, or host:port) asked for the second answers; by default the
+    /// --resolver.
+    #[arg(long, env = "DNS_SERVER_VERIFY_ANSWERS_UPSTREAM", value_name = "ADDR")]
+    pub verify_answers_upstream: Option<String>,
+
+    /// Waits for the second answer before answering the client, rather than checking
+    /// afterwards, so that a mismatch is settled by --verify-answers-policy.
+    #[arg(long, env = "DNS_SERVER_VERIFY_ANSWERS_STRICT")]
+    pub verify_answers_strict: bool,
+
+    /// What a strict check serves when the answers differ: intersection (the records
+    /// both have), prefer-reputable (the upstream that failed less) or servfail.
+    #[arg(
+        long,
+        env = "DNS_SERVER_VERIFY_ANSWERS_POLICY",
+        value_name = "POLICY",
+        default_value = "servfail",
+        value_parser = MismatchPolicy::parse
+    )]
+    pub verify_answers_policy: MismatchPolicy,
+
+    /// Asks the questions the upstream truncates over UDP again over TCP, and counts and
+    /// logs answers that differ beyond the truncation (e.g. a load balancer fronting
+    /// different pools on 53/udp and 53/tcp); clients are served as before.
+    #[arg(long, env = "DNS_SERVER_CHECK_UDP_TCP_CONSISTENCY")]
+    pub check_udp_tcp_consistency: bool,
+
+    /// Samples up to this many hot names among the upstream's answers, and compares
+    /// the TTLs it advertises for them with how often their data changes (see the
+    /// control socket's ttlreport); asks the upstream nothing more.
+    #[arg(long, env = "DNS_SERVER_TTL_HONESTY_SAMPLE", value_name = "NAMES")]
+    pub ttl_honesty_sample: Option<NonZeroUsize>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Local records")]
+pub struct RecordArgs {
+    /// Registers a DNS-SD service as instance:_service._proto:target:port[:key=value,...] (repeatable).
+    #[arg(
+        long,
+        env = "DNS_SERVER_SERVICES",
+        value_name = "SPEC",
+        value_delimiter = ';'
+    )]
+    #[cfg_attr(feature = "mdns", arg(value_parser = ServiceRegistration::parse))]
+    pub service: Vec<ServiceRegistration>,
+
+    /// Domain under which registered services are published.
+    #[arg(
+        long,
+        env = "DNS_SERVER_SERVICE_DOMAIN",
+        value_name = "DOMAIN",
+        default_value = "local"
+    )]
+    pub service_domain: String,
+
+    /// Serves a PTR record for ADDRESS=NAME (repeatable); IPv6 uses the ip6.arpa nibble form.
+    #[arg(long, env = "DNS_SERVER_REVERSE", value_name = "ADDRESS=NAME", value_delimiter = ',', value_parser = ReverseMapping::parse)]
+    pub reverse: Vec<ReverseMapping>,
+
+    /// Serves an MX record for NAME=PREFERENCE:EXCHANGE (repeatable), e.g. to route a test
+    /// domain's mail to a local server.
+    #[arg(long, env = "DNS_SERVER_MX", value_name = "NAME=PREFERENCE:EXCHANGE", value_delimiter = ',', value_parser = MailExchange::parse)]
+    pub mx: Vec<MailExchange>,
+
+    /// Serves an SRV record for NAME=PRIORITY:WEIGHT:PORT:TARGET (repeatable), e.g. to
+    /// answer _http._tcp service lookups without a DNS-SD registration.
+    #[arg(long, env = "DNS_SERVER_SRV", value_name = "NAME=PRIORITY:WEIGHT:PORT:TARGET", value_delimiter = ',', value_parser = ServiceLocation::parse)]
+    pub srv: Vec<ServiceLocation>,
+
+    /// Serves a record given as "NAME TYPE VALUE [TTL]" (repeatable), the value as in a
+    /// zone file, e.g. "dev.local A 127.0.0.1" or "local MX 10 mail.local"; CNAMEs to
+    /// other such records are followed.
+    #[arg(long, env = "DNS_SERVER_RECORDS", value_name = "RECORD", value_delimiter = ';', value_parser = LocalRecord::parse)]
+    pub record: Vec<LocalRecord>,
+
+    /// Serves an IPv4 block smaller than /24 as an RFC 2317 classless delegation (repeatable).
+    #[arg(long, env = "DNS_SERVER_CLASSLESS_DELEGATIONS", value_name = "CIDR", value_delimiter = ',', value_parser = ClasslessDelegation::parse)]
+    pub classless_delegation: Vec<ClasslessDelegation>,
+
+    /// Answers authoritatively for the zone ORIGIN from the master file FILE
+    /// (repeatable), e.g. --zone example.com zones/example.com.zone.
+    #[arg(
+        long,
+        env = "DNS_SERVER_ZONES",
+        num_args = 2,
+        value_names = ["ORIGIN", "FILE"],
+        value_delimiter = ' '
+    )]
+    pub zone: Vec<String>,
+
+    /// Answers REFUSED for names outside the --zone zones, rather than passing them
+    /// on to the secondary zones, the cache and the upstream.
+    #[arg(long, env = "DNS_SERVER_ZONE_REFUSE_OUTSIDE")]
+    pub zone_refuse_outside: bool,
+
+    /// Answers A and AAAA queries for the names in FILE, in the /etc/hosts format,
+    /// aliases included; other names go on to the zones, the cache and the upstream.
+    #[arg(long, env = "DNS_SERVER_HOSTS_FILE", value_name = "FILE")]
+    pub hosts_file: Option<PathBuf>,
+
+    /// TTL of the records answered from --hosts-file.
+    #[arg(
+        long,
+        env = "DNS_SERVER_HOSTS_TTL",
+        value_name = "SECONDS",
+        default_value_t = 300
+    )]
+    pub hosts_ttl: u32,
+}
+
+impl RecordArgs {
+    // The --zone values, in pairs.
+    pub fn zones(&self) -> Result<Vec<ZoneSpec>, String> {
+        self.zone
+            .chunks(2)
+            .map(|pair| match pair {
+                [origin, path] => ZoneSpec::new(origin, Path::new(path)),
+                _ => Err(String::from("--zone needs an origin and a file.")),
+            })
+            .collect()
+    }
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Zone transfers")]
+pub struct TransferArgs {
+    /// Serves ZONE as a secondary of the primary server at IP:port (repeatable): the
+    /// zone is transferred from it (AXFR), refreshed as its SOA says, and answered from
+    /// the copy.
+    #[arg(long, env = "DNS_SERVER_SECONDARY", value_name = "ZONE=PRIMARY", value_delimiter = ',', value_parser = SecondarySpec::parse)]
+    pub secondary: Vec<SecondarySpec>,
+
+    /// Most zone transfers in flight at once; the zones waiting for one queue.
+    #[arg(
+        long,
+        env = "DNS_SERVER_MAX_CONCURRENT_TRANSFERS",
+        value_name = "COUNT",
+        default_value_t = 2,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_concurrent_transfers: u64,
+
+    /// The most each secondary zone's refresh and retry timers are shortened by, so
+    /// that zones with the same timers don't refresh together; each zone draws its
+    /// share of it once.
+    #[arg(long, env = "DNS_SERVER_REFRESH_JITTER", value_name = "PERCENT", default_value = "10%", value_parser = parse_percent)]
+    pub refresh_jitter: u32,
+
+    /// Window over which the secondary zones' first refreshes after startup, and the
+    /// NOTIFYs of a new version of a zone, are spread, in milliseconds.
+    #[arg(
+        long,
+        env = "DNS_SERVER_TRANSFER_SPREAD_MS",
+        value_name = "MS",
+        default_value_t = 5000
+    )]
+    pub transfer_spread_ms: u64,
+
+    /// Server to send a NOTIFY to when a secondary zone takes a new version (repeatable);
+    /// unanswered NOTIFYs are sent again, further and further apart, up to 5 times.
+    #[arg(
+        long,
+        env = "DNS_SERVER_ALSO_NOTIFY",
+        value_name = "IP:PORT",
+        value_delimiter = ','
+    )]
+    pub also_notify: Vec<SocketAddr>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Security")]
+pub struct SecurityArgs {
+    /// Only serve names at or below this domain (repeatable); everything else is REFUSED.
+    #[arg(long, env = "DNS_SERVER_ONLY_NAMES", value_name = "DOMAIN", value_delimiter = ',', value_parser = DomainSuffix::parse)]
+    pub only_names: Vec<DomainSuffix>,
+
+    /// Only serve these query types, as mnemonics or TYPEnnn (e.g. A,AAAA,TXT).
+    #[arg(long, env = "DNS_SERVER_ONLY_TYPES", value_name = "TYPES", value_delimiter = ',', value_parser = parse_record_type)]
+    pub only_types: Vec<RecordType>,
+
+    /// Root trust anchors, as DS records or in the IANA root-anchors.xml format; enables
+    /// root key sentinel answers (RFC 8509) and key tag signaling (RFC 8145).
+    #[arg(long, env = "DNS_SERVER_TRUST_ANCHORS", value_name = "FILE")]
+    pub trust_anchors: Option<PathBuf>,
+
+    /// Negative trust anchor, as DOMAIN[=LIFETIME] (repeatable; e.g. example.net=30m, an
+    /// hour if left out): names at or below DOMAIN are forwarded with CD set, for the
+    /// upstream not to fail them on broken DNSSEC, until the anchor lapses.
+    #[arg(long, env = "DNS_SERVER_NTA", value_name = "DOMAIN[=LIFETIME]", value_delimiter = ',', value_parser = NtaSpec::parse)]
+    pub nta: Vec<NtaSpec>,
+
+    /// Allows negative trust anchors for the root and top-level domains.
+    #[arg(long, env = "DNS_SERVER_NTA_FORCE")]
+    pub nta_force: bool,
+
+    /// Which upstream answers keep their AD (Authentic Data) bit for clients that ask
+    /// for it: none (strip) or all (trust-all). We don't validate DNSSEC, so AD is the
+    /// upstream's word. trust-secure-upstream is refused until upstreams can be asked
+    /// over TLS, HTTPS or QUIC.
+    #[arg(
+        long,
+        env = "DNS_SERVER_AD_MODE",
+        value_name = "MODE",
+        default_value = "strip",
+        value_parser = AdMode::parse
+    )]
+    pub ad_mode: AdMode,
+
+    /// Response policy zone file (repeatable; zones are tried in order); reloaded when it changes.
+    #[arg(
+        long,
+        env = "DNS_SERVER_RPZ",
+        value_name = "FILE",
+        value_delimiter = ','
+    )]
+    pub rpz: Vec<PathBuf>,
+
+    /// Tells clients that policy zone blocks are Censored (EDE 16), i.e. required by
+    /// an outside authority, rather than Filtered (EDE 17).
+    #[arg(long, env = "DNS_SERVER_RPZ_CENSORED")]
+    pub rpz_censored: bool,
+
+    /// Blocks the domains in FILE, a domain per line or in the hosts format
+    /// ("0.0.0.0 ads.example.com"), and their subdomains.
+    #[arg(long, env = "DNS_SERVER_BLOCKLIST", value_name = "FILE")]
+    pub blocklist: Option<PathBuf>,
+
+    /// How --blocklist names are answered: nxdomain, null (0.0.0.0 and ::) or refused.
+    #[arg(
+        long,
+        env = "DNS_SERVER_BLOCK_MODE",
+        value_name = "MODE",
+        default_value = "nxdomain",
+        value_parser = BlockMode::parse
+    )]
+    pub block_mode: BlockMode,
+
+    /// Policy script deciding, per query, to allow, refuse, nxdomain, drop,
+    /// forward-to(TAG) or rewrite-to(NAME) (see src/server/script.rs); reloaded when it changes.
+    #[arg(long, env = "DNS_SERVER_POLICY_SCRIPT", value_name = "FILE")]
+    pub policy_script: Option<PathBuf>,
+
+    /// Most steps a policy script may take to decide on one query.
+    #[cfg(feature = "scripting")]
+    #[arg(
+        long,
+        env = "DNS_SERVER_POLICY_SCRIPT_MAX_STEPS",
+        value_name = "STEPS",
+        default_value_t = 10_000
+    )]
+    pub policy_script_max_steps: u32,
+
+    /// Refuses queries the policy script fails to decide on, instead of allowing them.
+    #[cfg(feature = "scripting")]
+    #[arg(long, env = "DNS_SERVER_POLICY_SCRIPT_FAIL_CLOSED")]
+    pub policy_script_fail_closed: bool,
+
+    /// Only applies the policy script during SCHEDULE, as DAYS HH:MM-HH:MM[,...] [TIME-ZONE]
+    /// (e.g. 'mon-fri 20:00-07:00 Europe/Berlin'); otherwise queries are handled without it.
+    #[cfg(feature = "scripting")]
+    #[arg(long, env = "DNS_SERVER_POLICY_SCRIPT_SCHEDULE", value_name = "SCHEDULE", value_parser = Schedule::parse)]
+    pub policy_script_schedule: Option<Schedule>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Overload")]
+pub struct OverloadArgs {
+    /// Datagrams waiting at once from which queries that need an upstream get SERVFAIL
+    /// (and are dropped from twice as many); local answers are still served.
+    #[arg(
+        long,
+        env = "DNS_SERVER_OVERLOAD_HIGH_WATER",
+        value_name = "DATAGRAMS",
+        default_value_t = 32
+    )]
+    pub overload_high_water: usize,
+
+    /// Datagrams waiting at once at or below which an overloaded server goes back to normal.
+    #[arg(
+        long,
+        env = "DNS_SERVER_OVERLOAD_LOW_WATER",
+        value_name = "DATAGRAMS",
+        default_value_t = 8
+    )]
+    pub overload_low_water: usize,
+
+    /// Queries a TCP connection may have in flight at once; more wait on the connection,
+    /// and a connection with four times as many waiting is closed.
+    #[arg(
+        long,
+        env = "DNS_SERVER_MAX_INFLIGHT_PER_CONNECTION",
+        value_name = "QUERIES",
+        default_value_t = 16
+    )]
+    pub max_inflight_per_connection: usize,
+
+    /// Queries a client address may have in flight at once over UDP and TCP; its further
+    /// datagrams are served as if the server were overloaded.
+    #[arg(
+        long,
+        env = "DNS_SERVER_MAX_INFLIGHT_PER_CLIENT",
+        value_name = "QUERIES",
+        default_value_t = 64
+    )]
+    pub max_inflight_per_client: usize,
+
+    /// Threads answering queries, over UDP and TCP, so that one waiting on a slow
+    /// upstream doesn't hold up the others; defaults to the number of CPUs.
+    #[arg(long, env = "DNS_SERVER_WORKERS", value_name = "THREADS")]
+    pub workers: Option<NonZeroUsize>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Responses")]
+pub struct ResponseArgs {
+    /// Most A or AAAA records of a name kept when a UDP response has to be trimmed to
+    /// fit, before resorting to truncation (TC).
+    #[arg(
+        long,
+        env = "DNS_SERVER_MAX_ADDRESSES_PER_RRSET",
+        value_name = "COUNT",
+        default_value_t = 8
+    )]
+    pub max_addresses_per_rrset: usize,
+
+    /// Largest UDP response sent, whatever size a client advertises with EDNS; larger
+    /// responses are truncated (TC) for the client to ask again over TCP. Clients
+    /// without EDNS always get 512 bytes at most.
+    #[arg(
+        long,
+        env = "DNS_SERVER_MAX_UDP_SIZE",
+        value_name = "BYTES",
+        default_value_t = UDP_PAYLOAD_SIZE,
+        value_parser = clap::value_parser!(u16).range(512..)
+    )]
+    pub max_udp_size: u16,
+
+    /// Answers queries for names that don't exist under DOMAIN with a CNAME to LANDING
+    /// instead of NXDOMAIN (repeatable); for domains you own, and never signed ones.
+    #[arg(long, env = "DNS_SERVER_NXDOMAIN_REDIRECTS", value_name = "DOMAIN=LANDING", value_delimiter = ',', value_parser = NxdomainRedirect::parse)]
+    pub nxdomain_redirect: Vec<NxdomainRedirect>,
+
+    /// Searches the names that clients in CIDR ask for under SUFFIX... on their behalf,
+    /// as CIDR[,CIDR...]=SUFFIX[,SUFFIX...][:NDOTS] (repeatable; NDOTS is 1 unless
+    /// given), answering under the name asked for; for clients that can't be given a
+    /// search domain.
+    #[arg(long, env = "DNS_SERVER_SEARCH_LISTS", value_name = "SPEC", value_delimiter = ';', value_parser = SearchList::parse)]
+    pub search_list: Vec<SearchList>,
+
+    /// Removes AAAA records (and IPv6 hints) from responses, for clients that can't
+    /// cope with them; AAAA queries get NODATA.
+    #[arg(long, env = "DNS_SERVER_FILTER_AAAA")]
+    pub filter_aaaa: bool,
+
+    /// Like --filter-aaaa, but only for queries that came over IPv4.
+    #[arg(long, env = "DNS_SERVER_FILTER_AAAA_ON_V4_TRANSPORT")]
+    pub filter_aaaa_on_v4_transport: bool,
+
+    /// Removes A records (and IPv4 hints) from responses, for IPv6-only networks; A
+    /// queries get NODATA.
+    #[arg(long, env = "DNS_SERVER_FILTER_A", conflicts_with_all = ["filter_aaaa", "filter_aaaa_on_v4_transport"])]
+    pub filter_a: bool,
+
+    /// Applies the address filters only to clients in these blocks, e.g.
+    /// 192.168.7.0/24.
+    #[arg(long, env = "DNS_SERVER_FILTER_CLIENTS", value_name = "CIDR", value_delimiter = ',', value_parser = Subnet::parse)]
+    pub filter_clients: Vec<Subnet>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Debugging")]
+pub struct DebuggingArgs {
+    /// EDNS option code with which clients ask for a TXT record (provenance.invalid) telling where the answers came from.
+    #[arg(
+        long,
+        env = "DNS_SERVER_PROVENANCE_OPTION",
+        value_name = "CODE",
+        default_value_t = DEFAULT_PROVENANCE_OPTION
+    )]
+    pub provenance_option: u16,
+
+    /// Adds the provenance record to every response, asked for or not (for test instances).
+    #[arg(long, env = "DNS_SERVER_PROVENANCE_ALWAYS")]
+    pub provenance_always: bool,
+
+    /// Reads every response back after encoding it and answers SERVFAIL, logging the
+    /// differences, if it doesn't match; always on in debug builds.
+    #[arg(long, env = "DNS_SERVER_VERIFY_ENCODING")]
+    pub verify_encoding: bool,
+
+    /// Logs each request that doesn't parse as a hex dump marking where it failed;
+    /// the latest are kept for the control socket's badpackets command either way.
+    #[arg(long, env = "DNS_SERVER_LOG_BAD_PACKETS")]
+    pub log_bad_packets: bool,
+
+    /// Directory into which a panic writes a support bundle (the build and the
+    /// effective settings, with secrets redacted) before the server exits.
+    #[arg(long, env = "DNS_SERVER_DUMP_STATE_ON_CRASH", value_name = "DIR")]
+    pub dump_state_on_crash: Option<PathBuf>,
+
+    /// Seeds everything randomized (e.g. timer jitter), so that a run can be
+    /// reproduced; the seed is taken from the clock otherwise.
+    #[arg(long, env = "DNS_SERVER_RANDOM_SEED", value_name = "SEED")]
+    pub random_seed: Option<u64>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Logs and artifacts")]
+pub struct ArtifactArgs {
+    /// File to which a line per response is appended; reopened on SIGHUP, for logrotate.
+    #[arg(long, env = "DNS_SERVER_QUERY_LOG", value_name = "PATH")]
+    pub query_log: Option<PathBuf>,
+
+    /// Size past which the query log is renamed to PATH.<unix millis> and started afresh.
+    #[arg(
+        long,
+        env = "DNS_SERVER_QUERY_LOG_ROTATE_SIZE",
+        value_name = "SIZE",
+        default_value = "10M",
+        value_parser = parse_size
+    )]
+    pub query_log_rotate_size: u64,
+
+    /// File to which every query and its response are appended in full, for the replay
+    /// command; rotated at --query-log-rotate-size and reopened on SIGHUP like the query
+    /// log.
+    #[arg(long, env = "DNS_SERVER_CAPTURE", value_name = "PATH")]
+    pub capture: Option<PathBuf>,
+
+    /// How many rotated files of an artifact to keep, e.g.
+    /// query-log:max-size=100M,max-age=7d,max-files=10 (repeatable); the oldest beyond
+    /// any limit are deleted.
+    #[arg(long, env = "DNS_SERVER_RETENTION", value_name = "KIND:LIMITS", value_delimiter = ';', value_parser = Retention::parse)]
+    pub retention: Vec<Retention>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Anomaly detection")]
+pub struct AnomalyArgs {
+    /// Watches for DGA-like and tunneling-like query patterns, logging [ANOMALY] events
+    /// and counting them in the stats; nothing is blocked.
+    #[arg(long, env = "DNS_SERVER_DETECT_ANOMALIES")]
+    pub detect_anomalies: bool,
+
+    /// Seconds over which query patterns are measured before starting afresh.
+    #[arg(
+        long,
+        env = "DNS_SERVER_ANOMALY_WINDOW",
+        value_name = "SECONDS",
+        default_value_t = 60
+    )]
+    pub anomaly_window: u64,
+
+    /// Queries a client must send in a window before its ratios and averages count.
+    #[arg(
+        long,
+        env = "DNS_SERVER_ANOMALY_MIN_QUERIES",
+        value_name = "COUNT",
+        default_value_t = 20
+    )]
+    pub anomaly_min_queries: u32,
+
+    /// Share of a client's responses that may be NXDOMAIN.
+    #[arg(
+        long,
+        env = "DNS_SERVER_ANOMALY_NXDOMAIN_RATIO",
+        value_name = "RATIO",
+        default_value_t = 0.5
+    )]
+    pub anomaly_nxdomain_ratio: f64,
+
+    /// Average entropy, in bits per character, the subdomains a client queries may
+    /// have (the labels before the last two).
+    #[arg(
+        long,
+        env = "DNS_SERVER_ANOMALY_ENTROPY",
+        value_name = "BITS",
+        default_value_t = 3.2
+    )]
+    pub anomaly_entropy: f64,
+
+    /// Average length the subdomains a client queries may have.
+    #[arg(
+        long,
+        env = "DNS_SERVER_ANOMALY_NAME_LENGTH",
+        value_name = "CHARACTERS",
+        default_value_t = 30.0
+    )]
+    pub anomaly_name_length: f64,
+
+    /// TXT or NULL answers of 200 bytes or more a client may get in a window.
+    #[arg(
+        long,
+        env = "DNS_SERVER_ANOMALY_LARGE_ANSWERS",
+        value_name = "COUNT",
+        default_value_t = 20
+    )]
+    pub anomaly_large_answers: u32,
+
+    /// Distinct names that may be queried under one domain (its last two labels) in a
+    /// window, by all clients together.
+    #[arg(
+        long,
+        env = "DNS_SERVER_ANOMALY_SUBDOMAINS",
+        value_name = "COUNT",
+        default_value_t = 200
+    )]
+    pub anomaly_subdomains: usize,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Service level objectives")]
+pub struct SloArgs {
+    /// Latency SLO as CLASS:pPERCENTILE<THRESHOLD, e.g. forwarded:p95<50ms, for the
+    /// forwarded, authoritative, cache-hit or overall queries (repeatable); breaches are
+    /// logged as [SLO] events and compliance is in the stats.
+    #[arg(long, env = "DNS_SERVER_SLO", value_name = "SLO", value_delimiter = ',', value_parser = SloSpec::parse)]
+    pub slo: Vec<SloSpec>,
+
+    /// Seconds of queries over which the SLOs are evaluated; a breach that lasts is
+    /// logged again once per window.
+    #[arg(
+        long,
+        env = "DNS_SERVER_SLO_WINDOW",
+        value_name = "SECONDS",
+        default_value_t = 60
+    )]
+    pub slo_window: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn examples_are_valid_command_lines() {
+        let command = CliArgs::command();
+        for example in EXAMPLES {
+            let args = [env!("CARGO_PKG_NAME")].iter().chain(example.args);
+            if let Err(err) = CliArgs::try_parse_from(args) {
+                panic!("{}: {}", example.what, err);
+            }
+            for (name, _) in example.env {
+                assert!(
+                    command
+                        .get_arguments()
+                        .any(|arg| arg.get_env().is_some_and(|env| env == *name)),
+                    "{}: no option reads {}",
+                    example.what,
+                    name
+                );
+            }
+        }
+        let help = examples_help();
+        assert!(
+            help.starts_with("Examples:\n  Answer every query"),
+            "{help}"
+        );
+        assert!(help.contains(
+            "    codecrafters-dns-server --resolver 8.8.8.8:53 --record 'dev.local A 127.0.0.1'"
+        ));
+        assert!(help.contains("    DNS_SERVER_RESOLVER=1.1.1.1:53 codecrafters-dns-server\n"));
+        assert!(help.ends_with("completions bash > /etc/bash_completion.d/codecrafters-dns-server"));
+    }
 }
@@ -0,0 +1,844 @@
+// The server the command line describes: the local records, the chain of resolvers
+// and the query handler asking it, and the server answering with them, its
+// listeners, control socket, workers and maintainer. The binary (main.rs) serves with it; each
+// step says what it's set up with as it goes.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::CliArgs;
+use crate::server::{
+    bind_failure, AaaaFiltering, AddressFilter, AnomalyDetector, AnomalyThresholds, BadPackets,
+    Blocklist, BlocklistResolver, CachingResolver, CircuitBreaker, ClientQuotas,
+    ConditionalForwarder, ControlSocket, CrossCheckingResolver, DnsServer, DummyDnsResolver,
+    ExpiryCounters, Fallthrough, ForwardZone, ForwardingDnsResolver, HandleOpcode, HostsResolver,
+    Housekeeping, Json, Listener, Maintainer, MinimizationPolicy, NegativeTrustAnchors, Notifier,
+    NxdomainRedirectResolver, OverloadPolicy, Pacer, PinStore, PinnedResolver, ProvenancePolicy,
+    QueryOpcodeHandler, QueryPolicy, Randomness, Resolve, ResolverChain, ResponsePolicy,
+    RotatingWriter, SecondaryResolver, SecondaryZones, SloTracker, SplitBrainCheck,
+    StaticDnsResolver, StaticRecords, Stats, SystemRng, TruncationTracker, TtlHonesty, UpstreamSet,
+    UpstreamStateStore, WorkerPool, Zone, ZoneResolver,
+};
+#[cfg(feature = "scripting")]
+use crate::server::{ScriptedPolicy, ScriptedQueryHandler, DEFAULT_FORWARDER};
+#[cfg(feature = "dnssec")]
+use crate::server::{SystemClock, TrustAnchors};
+
+// The records configured on the command line, unchecked.
+pub fn local_records(cli: &CliArgs) -> StaticRecords {
+    let mut records = StaticRecords::new();
+    #[cfg(feature = "mdns")]
+    for service in &cli.records.service {
+        service
+            .records(&cli.records.service_domain)
+            .expect("Failed to register service")
+            .into_iter()
+            .for_each(|answer| {
+                records.add(answer);
+            });
+    }
+    for delegation in &cli.records.classless_delegation {
+        delegation
+            .glue_records()
+            .expect("Failed to build classless delegation")
+            .into_iter()
+            .for_each(|answer| {
+                records.add(answer);
+            });
+    }
+    for exchange in &cli.records.mx {
+        records.add(exchange.mx_record().expect("Failed to build MX record"));
+    }
+    for location in &cli.records.srv {
+        records.add(location.srv_record().expect("Failed to build SRV record"));
+    }
+    for record in &cli.records.record {
+        records.add(record.record().expect("Failed to build record"));
+    }
+    for mapping in &cli.records.reverse {
+        records.add(
+            mapping
+                .ptr_record(&cli.records.classless_delegation)
+                .expect("Failed to build reverse record"),
+        );
+    }
+    records
+}
+
+// The resolvers a query handler asks, from the local records down to the upstream
+// (or the dummy, or `captured` in its place), and what they share with the handler,
+// the control socket and the stats.
+pub struct Resolvers {
+    pub resolver: Box<dyn Resolve>,
+    #[cfg(feature = "dnssec")]
+    pub trust_anchors: Option<Arc<TrustAnchors>>,
+    pub negative_trust_anchors: Option<Arc<NegativeTrustAnchors>>,
+    pub split_brain: Option<Arc<SplitBrainCheck>>,
+    pub ttl_honesty: Option<Arc<TtlHonesty>>,
+    pub rpz: Option<Arc<ResponsePolicy>>,
+    pub records: Option<Arc<StaticRecords>>,
+    pub secondary: Option<Arc<SecondaryZones>>,
+    pub breaker: Option<Arc<CircuitBreaker>>,
+    pub upstreams: Option<Arc<UpstreamSet>>,
+    pub blocklist: Option<Arc<Blocklist>>,
+    pub cache: Option<Arc<ExpiryCounters>>,
+}
+
+// Builds the resolvers, shared by the server loop and the workers, says what
+// they're set up with, and warms the upstream up.
+pub fn resolvers(
+    cli: &CliArgs,
+    records: StaticRecords,
+    captured: Option<Box<dyn Resolve>>,
+    randomness: &Randomness,
+) -> Resolvers {
+    // One circuit breaker for the upstream, shared by the server loop and the workers.
+    let breaker: Option<Arc<CircuitBreaker>> = cli
+        .upstreams
+        .breaker_failures
+        .filter(|_| captured.is_none() && !cli.upstreams.resolver.is_empty())
+        .map(|failures| {
+            println!(
+                "Failing queries fast after {failures} in a row get no response from the \
+                 upstream, probing every {}ms to {}ms.",
+                cli.upstreams.breaker_probe_interval_ms,
+                cli.upstreams.breaker_max_probe_interval_ms
+            );
+            Arc::new(CircuitBreaker::new(
+                failures.get(),
+                Duration::from_millis(cli.upstreams.breaker_probe_interval_ms),
+                Duration::from_millis(cli.upstreams.breaker_max_probe_interval_ms),
+            ))
+        });
+    // The upstreams, shared by the server loop and the workers, which fail over from
+    // one to the next and learn which to skip together.
+    let upstreams: Option<Arc<UpstreamSet>> =
+        (captured.is_none() && !cli.upstreams.resolver.is_empty()).then(|| {
+            let addresses: Vec<SocketAddr> = cli
+                .upstreams
+                .resolver
+                .iter()
+                .map(|address| upstream_address(address))
+                .collect();
+            if addresses.len() > 1 {
+                println!(
+                    "Failing over between {} upstreams, asking one last for {}s after {} \
+                 failure(s) in a row.",
+                    addresses.len(),
+                    cli.upstreams.upstream_skip_secs,
+                    cli.upstreams.upstream_skip_failures
+                );
+            }
+            Arc::new(UpstreamSet::new(
+                addresses,
+                cli.upstreams.upstream_skip_failures.get(),
+                Duration::from_secs(cli.upstreams.upstream_skip_secs),
+            ))
+        });
+    // One blocklist, shared by the server loop and the workers, which count the
+    // queries they block in it.
+    let blocklist: Option<Arc<Blocklist>> = cli.security.blocklist.as_ref().map(|path| {
+        let (blocklist, warnings) = Blocklist::load(path)
+            .unwrap_or_else(|err| panic!("Failed to load the blocklist: {err}"));
+        for warning in &warnings {
+            println!("Skipping a line of {}: {}", path.display(), warning);
+        }
+        println!(
+            "Blocking {} domain(s) from {}, answering {:?}.",
+            blocklist.len(),
+            path.display(),
+            cli.security.block_mode
+        );
+        Arc::new(blocklist)
+    });
+
+    #[cfg(feature = "dnssec")]
+    let trust_anchors: Option<Arc<TrustAnchors>> =
+        cli.security.trust_anchors.as_ref().map(|path| {
+            let anchors = TrustAnchors::load(path, Arc::new(SystemClock))
+                .expect("Failed to load trust anchors");
+            println!(
+                "Loaded {} trust anchor(s); active root key tags: {:?}.",
+                anchors.len(),
+                anchors.active_root_key_tags()
+            );
+            Arc::new(anchors)
+        });
+
+    // Only forwarded queries can go unvalidated; the control socket can add more.
+    let negative_trust_anchors: Option<Arc<NegativeTrustAnchors>> =
+        (!cli.upstreams.resolver.is_empty()).then(|| {
+            let anchors = NegativeTrustAnchors::new(cli.security.nta_force);
+            for nta in &cli.security.nta {
+                anchors
+                    .add(&nta.domain, nta.lifetime)
+                    .unwrap_or_else(|err| panic!("Invalid negative trust anchor: {err}"));
+            }
+            Arc::new(anchors)
+        });
+    if negative_trust_anchors.is_none() && !cli.security.nta.is_empty() {
+        println!("Ignoring --nta: negative trust anchors only apply when forwarding (--resolver).");
+    }
+    let split_brain: Option<Arc<SplitBrainCheck>> = (cli.upstreams.check_udp_tcp_consistency
+        && !cli.upstreams.resolver.is_empty())
+    .then(|| Arc::new(SplitBrainCheck::new(Duration::from_secs(2))));
+    let tcp_fallback = (cli.upstreams.upstream_tcp_timeout_ms > 0)
+        .then(|| Duration::from_millis(cli.upstreams.upstream_tcp_timeout_ms));
+    let ttl_honesty: Option<Arc<TtlHonesty>> = cli
+        .upstreams
+        .ttl_honesty_sample
+        .filter(|_| !cli.upstreams.resolver.is_empty())
+        .map(|sample| Arc::new(TtlHonesty::new(sample.get())));
+
+    // How the cache's entries expire, for the stats, if there is a cache.
+    let mut cache: Option<Arc<ExpiryCounters>> = None;
+    let resolver: Box<dyn Resolve> = if let Some(captured) = captured {
+        captured
+    } else if let Some(upstreams) = &upstreams {
+        let fwd_addr = upstreams.first();
+        println!(
+            "DNS resolver type: Forward (will forward DNS requests to {}).",
+            upstreams
+                .addresses
+                .iter()
+                .map(SocketAddr::to_string)
+                .collect::<Vec<String>>()
+                .join(", then ")
+        );
+        println!(
+            "Waiting {}ms for each upstream response, retrying {} time(s).",
+            cli.upstreams.upstream_timeout_ms, cli.upstreams.upstream_retries
+        );
+        let upstream_state = match &cli.upstreams.upstream_state_file {
+            Some(path) => {
+                let store = UpstreamStateStore::load(
+                    path.clone(),
+                    Duration::from_secs(cli.upstreams.upstream_state_max_age),
+                );
+                println!("Loaded learned state for {} upstream(s).", store.len());
+                store
+            }
+            None => UpstreamStateStore::in_memory(),
+        };
+        if let Some(state) = upstream_state.get(&format!("udp/{fwd_addr}")) {
+            println!("Warm upstream state for {fwd_addr}: SRTT {:?}.", state.srtt);
+        }
+        assert!(
+            cli.upstreams.upstream_max_qps > 0.0,
+            "--upstream-max-qps must be positive"
+        );
+        if let Some(check) = &split_brain {
+            println!(
+                "Comparing truncated answers with {fwd_addr}'s over TCP (timeout {:?}).",
+                check.timeout
+            );
+        }
+        if let Some(sample) = cli.upstreams.ttl_honesty_sample {
+            println!(
+                "Comparing {fwd_addr}'s TTLs with how often the data changes, for up to {sample} name(s)."
+            );
+        }
+        let forwarder = ForwardingDnsResolver {
+            upstreams: Arc::clone(upstreams),
+            upstream_state: Mutex::new(upstream_state),
+            #[cfg(feature = "dnssec")]
+            trust_anchors: trust_anchors.clone(),
+            negative_trust_anchors: negative_trust_anchors.clone(),
+            pacer: Mutex::new(Pacer::new(cli.upstreams.upstream_max_qps, Instant::now())),
+            pacing: Default::default(),
+            prefetched: Default::default(),
+            split_brain: split_brain.clone(),
+            tcp_fallback,
+            ttl_honesty: ttl_honesty.clone(),
+            timeout: Duration::from_millis(cli.upstreams.upstream_timeout_ms),
+            retries: cli.upstreams.upstream_retries,
+            ids: Arc::new(SystemRng::open().expect("Failed to open the system's randomness")),
+            breaker: breaker.clone(),
+        };
+        for name in &cli.upstreams.warm_up {
+            forwarder.warm_up(name).expect("Invalid warm-up name");
+        }
+        if !cli.upstreams.warm_up.is_empty() {
+            println!(
+                "Warming up {} name(s) at up to {} queries/s.",
+                cli.upstreams.warm_up.len(),
+                cli.upstreams.upstream_max_qps
+            );
+        }
+        // A forwarder to an upstream other than the default, for cross-checks and
+        // forward zones, without the state learned about the default one.
+        let other_forwarder = |upstream: SocketAddr| ForwardingDnsResolver {
+            upstreams: Arc::new(UpstreamSet::single(upstream)),
+            upstream_state: Mutex::new(UpstreamStateStore::in_memory()),
+            #[cfg(feature = "dnssec")]
+            trust_anchors: trust_anchors.clone(),
+            negative_trust_anchors: negative_trust_anchors.clone(),
+            pacer: Mutex::new(Pacer::new(cli.upstreams.upstream_max_qps, Instant::now())),
+            pacing: Default::default(),
+            prefetched: Default::default(),
+            split_brain: None,
+            tcp_fallback,
+            ttl_honesty: None,
+            timeout: Duration::from_millis(cli.upstreams.upstream_timeout_ms),
+            retries: cli.upstreams.upstream_retries,
+            ids: Arc::new(SystemRng::open().expect("Failed to open the system's randomness")),
+            breaker: None,
+        };
+        let forwarding: Box<dyn Resolve> = match cli.upstreams.verify_answers {
+            Some(percent) => {
+                let check_addr = cli
+                    .upstreams
+                    .verify_answers_upstream
+                    .as_ref()
+                    .map_or(fwd_addr, |address| upstream_address(address));
+                println!(
+                    "Cross-checking {percent}% of the answers against {check_addr} ({}).",
+                    if cli.upstreams.verify_answers_strict {
+                        "strict"
+                    } else {
+                        "after answering"
+                    }
+                );
+                let secondary = other_forwarder(check_addr);
+                Box::new(CrossCheckingResolver::new(
+                    Box::new(forwarder),
+                    Box::new(secondary),
+                    percent,
+                    cli.upstreams.verify_answers_strict,
+                    cli.upstreams.verify_answers_policy,
+                ))
+            }
+            None => Box::new(forwarder),
+        };
+        let forwarding: Box<dyn Resolve> = if cli.upstreams.forward_zone.is_empty() {
+            forwarding
+        } else {
+            let routes: Vec<(ForwardZone, Box<dyn Resolve>)> = cli
+                .upstreams
+                .forward_zone
+                .iter()
+                .map(|route| {
+                    let upstream: Box<dyn Resolve> = Box::new(other_forwarder(route.upstream));
+                    (route.clone(), upstream)
+                })
+                .collect();
+            let forwarder = ConditionalForwarder::new(routes, fwd_addr, forwarding);
+            println!("Forwarding {}.", forwarder);
+            Box::new(forwarder)
+        };
+        if cli.upstreams.cache {
+            println!(
+                "Caching up to {} answer set(s) from the upstream.",
+                cli.upstreams.cache_size
+            );
+            let caching = CachingResolver::new(forwarding, cli.upstreams.cache_size as usize);
+            cache = Some(caching.expiry_counters());
+            Box::new(caching)
+        } else {
+            forwarding
+        }
+    } else {
+        println!("DNS resolver type: Dummy (will respond with fake data).");
+        if !cli.upstreams.warm_up.is_empty() {
+            println!("Ignoring --warm-up: there is no upstream to warm up.");
+        }
+        if cli.upstreams.ttl_honesty_sample.is_some() {
+            println!("Ignoring --ttl-honesty-sample: there is no upstream to observe.");
+        }
+        if cli.upstreams.cache {
+            println!("Ignoring --cache: there is no upstream to cache the answers of.");
+        }
+        if !cli.upstreams.forward_zone.is_empty() {
+            println!("Ignoring --forward-zone: there is no default upstream (--resolver).");
+        }
+        Box::new(DummyDnsResolver {
+            ipv6_address: cli.upstreams.dummy_ipv6_address,
+        })
+    };
+
+    let secondary: Option<Arc<SecondaryZones>> = (!cli.transfers.secondary.is_empty()).then(|| {
+        let mut zones = SecondaryZones::new(
+            &cli.transfers.secondary,
+            cli.transfers.max_concurrent_transfers as usize,
+            cli.transfers.refresh_jitter as f64 / 100.0,
+            Duration::from_millis(cli.transfers.transfer_spread_ms),
+            randomness.stream("secondary").as_ref(),
+            Arc::new(SystemRng::open().expect("Failed to open the system's randomness")),
+            Instant::now(),
+        )
+        .expect("Invalid secondary zone");
+        if !cli.transfers.also_notify.is_empty() {
+            zones.notifier = Some(Notifier::new(
+                cli.transfers.also_notify.clone(),
+                Duration::from_millis(cli.transfers.transfer_spread_ms),
+                randomness.stream("notify"),
+                Arc::new(SystemRng::open().expect("Failed to open the system's randomness")),
+            ));
+        }
+        for spec in &cli.transfers.secondary {
+            println!("Serving {} as a secondary of {}.", spec.zone, spec.primary);
+        }
+        println!(
+            "Transferring {} zone(s) at most {} at a time, first refreshing them \
+                 over {}ms.",
+            zones.len(),
+            zones.max_transfers,
+            cli.transfers.transfer_spread_ms
+        );
+        if let Some(notifier) = &zones.notifier {
+            println!(
+                "Notifying {} server(s) of new versions.",
+                notifier.targets.len()
+            );
+        }
+        Arc::new(zones)
+    });
+    let resolver: Box<dyn Resolve> = match &secondary {
+        None => resolver,
+        Some(zones) => Box::new(SecondaryResolver {
+            zones: Arc::clone(zones),
+            next: resolver,
+        }),
+    };
+
+    // The local sources come first, each a stage of the chain answering the names it
+    // knows and passing the others on (see resolver_chain.rs), in this order: the
+    // blocklist, the static records, the hosts file, the zones; then the secondary
+    // zones, the cache and the upstream, or the dummy.
+    let mut stages: Vec<Box<dyn Resolve>> = Vec::new();
+    if let Some(blocklist) = &blocklist {
+        stages.push(Box::new(BlocklistResolver {
+            blocklist: Arc::clone(blocklist),
+            mode: cli.security.block_mode,
+            next: Box::new(Fallthrough),
+        }));
+    }
+
+    // With a control socket, the local records can be edited in transactions, so
+    // they are asked even if there are none yet.
+    let editable = cli.listeners.control_socket.is_some();
+    let records: Option<Arc<StaticRecords>> =
+        (!records.is_empty() || editable).then(|| Arc::new(records));
+    if let Some(records) = &records {
+        if !records.is_empty() {
+            println!("Serving {} local record(s).", records.len());
+        }
+        stages.push(Box::new(StaticDnsResolver {
+            records: Arc::clone(records),
+            next: Box::new(Fallthrough),
+        }));
+    }
+
+    if let Some(path) = &cli.records.hosts_file {
+        let (hosts, warnings) =
+            HostsResolver::load(path, cli.records.hosts_ttl, Box::new(Fallthrough))
+                .unwrap_or_else(|err| panic!("Failed to load the hosts file: {err}"));
+        for warning in &warnings {
+            println!("Skipping a line of {}: {}", path.display(), warning);
+        }
+        println!("Answering {} name(s) from {}.", hosts.len(), path.display());
+        stages.push(Box::new(hosts));
+    }
+
+    let zone_specs = cli
+        .records
+        .zones()
+        .unwrap_or_else(|err| panic!("Invalid --zone: {err}"));
+    if zone_specs.is_empty() {
+        if cli.records.zone_refuse_outside {
+            println!("Ignoring --zone-refuse-outside: there are no zones (--zone).");
+        }
+    } else {
+        let zones: Vec<Zone> = zone_specs
+            .iter()
+            .map(|spec| {
+                Zone::load(spec).unwrap_or_else(|err| panic!("Failed to load a zone: {err}"))
+            })
+            .collect();
+        for zone in &zones {
+            println!(
+                "Serving the zone {} ({} record(s)).",
+                zone.origin,
+                zone.len()
+            );
+        }
+        if cli.records.zone_refuse_outside {
+            println!("Refusing the names outside of the zones.");
+        }
+        stages.push(Box::new(ZoneResolver {
+            zones,
+            refuse_outside: cli.records.zone_refuse_outside,
+            next: Box::new(Fallthrough),
+        }));
+    }
+
+    stages.push(resolver);
+    let resolver: Box<dyn Resolve> = Box::new(ResolverChain { stages });
+
+    let resolver: Box<dyn Resolve> = if cli.responses.nxdomain_redirect.is_empty() {
+        resolver
+    } else {
+        for redirect in &cli.responses.nxdomain_redirect {
+            println!(
+                "Redirecting NXDOMAIN under {} to {}.",
+                redirect.suffix, redirect.landing
+            );
+        }
+        Box::new(NxdomainRedirectResolver {
+            redirects: cli.responses.nxdomain_redirect.clone(),
+            next: resolver,
+            redirected: Default::default(),
+        })
+    };
+
+    let rpz: Option<Arc<ResponsePolicy>> = (!cli.security.rpz.is_empty()).then(|| {
+        let mut rpz =
+            ResponsePolicy::load(&cli.security.rpz).expect("Invalid response policy zone");
+        rpz.censored = cli.security.rpz_censored;
+        for zone in rpz.report() {
+            println!("Applying response policy zone {zone}.");
+        }
+        Arc::new(rpz)
+    });
+
+    Resolvers {
+        resolver,
+        #[cfg(feature = "dnssec")]
+        trust_anchors,
+        negative_trust_anchors,
+        split_brain,
+        ttl_honesty,
+        rpz,
+        records,
+        secondary,
+        breaker,
+        upstreams,
+        blocklist,
+        cache,
+    }
+}
+
+// The handler of standard queries, asking `resolver`, as the command line sets it up
+// apart from the trust anchors, which the dnssec feature adds, and the policy script
+// (see query_handler).
+pub fn query_opcode_handler(
+    cli: &CliArgs,
+    resolver: Box<dyn Resolve>,
+    rpz: Option<Arc<ResponsePolicy>>,
+) -> QueryOpcodeHandler {
+    QueryOpcodeHandler {
+        policy: QueryPolicy::new(&cli.security.only_names, &cli.security.only_types),
+        provenance: ProvenancePolicy {
+            option_code: cli.debugging.provenance_option,
+            always: cli.debugging.provenance_always,
+        },
+        ad_mode: cli.security.ad_mode,
+        rpz,
+        search: cli.responses.search_list.clone(),
+        ..QueryOpcodeHandler::new(Arc::from(resolver))
+    }
+}
+
+// The handler of standard queries, `query_handler` behind the policy script if there
+// is one.
+fn query_handler(cli: &CliArgs, query_handler: QueryOpcodeHandler) -> Arc<dyn HandleOpcode> {
+    for list in &cli.responses.search_list {
+        println!(
+            "Searching {} (ndots {}) for the clients in {} block(s).",
+            list.suffixes.join(", "),
+            list.ndots,
+            list.clients.len()
+        );
+    }
+    #[cfg(feature = "scripting")]
+    if let Some(path) = &cli.security.policy_script {
+        let mut policy = ScriptedPolicy::load(
+            path.clone(),
+            vec![String::from(DEFAULT_FORWARDER)],
+            cli.security.policy_script_max_steps,
+            cli.security.policy_script_fail_closed,
+        )
+        .expect("Invalid policy script");
+        policy.schedule = cli.security.policy_script_schedule.clone();
+        println!(
+            "Judging queries by {} ({} rule(s), failing {}{}).",
+            policy.path.display(),
+            policy.rule_count(),
+            if policy.fail_closed { "closed" } else { "open" },
+            match &policy.schedule {
+                Some(schedule) => format!(", during {schedule}"),
+                None => String::new(),
+            }
+        );
+        return Arc::new(ScriptedQueryHandler {
+            policy,
+            inner: query_handler,
+            forwarders: Vec::new(),
+        });
+    }
+    Arc::new(query_handler)
+}
+
+// The server the command line sets up, answering with `resolvers`: it binds the
+// listeners (exiting if one can't be) and the control socket, opens the files it
+// writes and starts the workers.
+pub fn server(
+    cli: CliArgs,
+    config: Json,
+    resolvers: Resolvers,
+    randomness: Randomness,
+) -> DnsServer {
+    let Resolvers {
+        resolver,
+        #[cfg(feature = "dnssec")]
+        trust_anchors,
+        negative_trust_anchors,
+        split_brain,
+        ttl_honesty,
+        rpz,
+        records,
+        secondary,
+        breaker,
+        upstreams,
+        blocklist,
+        cache,
+    } = resolvers;
+    let quotas = Arc::new(
+        ClientQuotas::new(
+            cli.overload.max_inflight_per_connection,
+            cli.overload.max_inflight_per_client,
+        )
+        .expect("Invalid in-flight quotas"),
+    );
+
+    // Pins answer ahead of every other source.
+    let (resolver, control): (Box<dyn Resolve>, Option<ControlSocket>) =
+        match &cli.listeners.control_socket {
+            Some(path) => {
+                let pins = Arc::new(PinStore::default());
+                let mut control = ControlSocket::bind(path.clone(), Arc::clone(&pins))
+                    .unwrap_or_else(|err| panic!("Failed to bind to {}: {}", path.display(), err));
+                control.quotas = Some(Arc::clone(&quotas));
+                control.rpz = rpz.clone();
+                control.ntas = negative_trust_anchors.clone();
+                control.split_brain = split_brain.clone();
+                control.ttl_honesty = ttl_honesty.clone();
+                control.records = records.clone();
+                control.secondary = secondary.clone();
+                control.config = config;
+                #[cfg(feature = "scripting")]
+                if let (Some(_), Some(schedule)) = (
+                    &cli.security.policy_script,
+                    &cli.security.policy_script_schedule,
+                ) {
+                    control
+                        .schedules
+                        .push((String::from("policy-script"), schedule.clone()));
+                }
+                println!("Taking control commands on {}.", path.display());
+                (
+                    Box::new(PinnedResolver {
+                        pins,
+                        next: resolver,
+                    }),
+                    Some(control),
+                )
+            }
+            None => (resolver, None),
+        };
+
+    let listeners: Vec<Listener> = cli
+        .listeners
+        .bind
+        .iter()
+        .map(|spec| {
+            let spec = spec.clone().or_port(cli.listeners.port);
+            let listener = Listener::bind(&spec).unwrap_or_else(|err| {
+                eprintln!("{}", bind_failure(&spec, &err));
+                std::process::exit(1);
+            });
+            // The port the system picked, with --port 0.
+            let address = listener.socket.local_addr().unwrap_or(spec.address);
+            println!("Listening on {} as [{}].", address, spec.tag);
+            listener
+        })
+        .collect();
+    let tags: Vec<String> = listeners
+        .iter()
+        .map(|listener| listener.tag.clone())
+        .collect();
+    let handler = query_handler(
+        &cli,
+        QueryOpcodeHandler {
+            #[cfg(feature = "dnssec")]
+            trust_anchors,
+            ..query_opcode_handler(&cli, resolver, rpz)
+        },
+    );
+
+    let query_log = cli.artifacts.query_log.as_ref().map(|path| {
+        let log = RotatingWriter::open(path.clone(), cli.artifacts.query_log_rotate_size)
+            .unwrap_or_else(|err| panic!("Failed to open {}: {}", path.display(), err));
+        println!("Logging queries to {}.", path.display());
+        Arc::new(Mutex::new(log))
+    });
+    let capture = cli.artifacts.capture.as_ref().map(|path| {
+        let capture = RotatingWriter::open(path.clone(), cli.artifacts.query_log_rotate_size)
+            .unwrap_or_else(|err| panic!("Failed to open {}: {}", path.display(), err));
+        println!("Capturing queries and responses to {}.", path.display());
+        Arc::new(Mutex::new(capture))
+    });
+    let housekeeping = Housekeeping {
+        query_log,
+        capture,
+        retention: cli.artifacts.retention.clone(),
+        ..Housekeeping::with_randomness(&randomness)
+    };
+    let anomalies = cli.anomalies.detect_anomalies.then(|| {
+        println!("Watching for anomalous query patterns.");
+        Arc::new(AnomalyDetector::new(
+            AnomalyThresholds {
+                window: Duration::from_secs(cli.anomalies.anomaly_window),
+                min_queries: cli.anomalies.anomaly_min_queries,
+                nxdomain_ratio: cli.anomalies.anomaly_nxdomain_ratio,
+                entropy: cli.anomalies.anomaly_entropy,
+                name_length: cli.anomalies.anomaly_name_length,
+                large_answers: cli.anomalies.anomaly_large_answers,
+                subdomains: cli.anomalies.anomaly_subdomains,
+                ..Default::default()
+            },
+            Instant::now(),
+        ))
+    });
+    assert!(cli.slos.slo_window > 0, "--slo-window must be positive");
+    let mut server = DnsServer {
+        overload: OverloadPolicy::new(
+            cli.overload.overload_high_water,
+            cli.overload.overload_low_water,
+        )
+        .expect("Invalid overload marks"),
+        quotas,
+        minimization: MinimizationPolicy {
+            max_addresses_per_rrset: cli.responses.max_addresses_per_rrset,
+            max_udp_size: cli.responses.max_udp_size as usize,
+        },
+        address_filter: address_filter(&cli, true),
+        verify_encoding: cli.debugging.verify_encoding || cfg!(debug_assertions),
+        control,
+        housekeeping,
+        anomalies,
+        stats: Arc::new(Stats {
+            slos: SloTracker::new(
+                &cli.slos.slo,
+                Duration::from_secs(cli.slos.slo_window),
+                Instant::now(),
+            ),
+            bad_packets: BadPackets::new(cli.debugging.log_bad_packets),
+            breaker,
+            blocklist,
+            cache,
+            upstreams,
+            truncation: TruncationTracker::new(&tags, cli.responses.max_udp_size as usize),
+            ..Stats::for_listeners(&tags)
+        }),
+        randomness,
+        ..DnsServer::new(listeners, vec![handler])
+    };
+    for slo in &cli.slos.slo {
+        println!(
+            "Tracking the SLO {slo} over {}s windows.",
+            cli.slos.slo_window
+        );
+    }
+
+    let workers = cli.overload.workers.map_or_else(
+        || thread::available_parallelism().map_or(1, usize::from),
+        usize::from,
+    );
+    if workers > 1 {
+        let cli = Arc::new(cli);
+        let randomness = server.randomness.clone();
+        let stats = Arc::clone(&server.stats);
+        // The server loop's handlers, and so its resolvers, answer for the workers
+        // too; the maintainer alone maintains them.
+        let handlers = server.handlers.clone();
+        // Written and watched for all; the server loop alone rotates and sweeps.
+        let query_log = server.housekeeping.query_log.clone();
+        let capture = server.housekeeping.capture.clone();
+        let anomalies = server.anomalies.clone();
+        let pool = WorkerPool::spawn(workers, &server.listeners, move |index, listeners| {
+            let randomness = randomness.for_worker(index);
+            // The server loop judges the load and admits the queries.
+            DnsServer {
+                overload: OverloadPolicy::new(
+                    cli.overload.overload_high_water,
+                    cli.overload.overload_low_water,
+                )
+                .expect("Invalid overload marks"),
+                minimization: MinimizationPolicy {
+                    max_addresses_per_rrset: cli.responses.max_addresses_per_rrset,
+                    max_udp_size: cli.responses.max_udp_size as usize,
+                },
+                address_filter: address_filter(&cli, false),
+                verify_encoding: cli.debugging.verify_encoding || cfg!(debug_assertions),
+                housekeeping: Housekeeping {
+                    query_log: query_log.clone(),
+                    capture: capture.clone(),
+                    ..Housekeeping::with_randomness(&randomness)
+                },
+                anomalies: anomalies.clone(),
+                randomness,
+                stats: Arc::clone(&stats),
+                ..DnsServer::new(listeners, handlers.clone())
+            }
+        })
+        .expect("Failed to start the workers");
+        println!("Answering queries on {} worker threads.", pool.len());
+        server.workers = Some(pool);
+    }
+    server.maintainer = Some(
+        Maintainer::spawn(server.handlers.clone(), &server.randomness)
+            .expect("Failed to start the maintainer"),
+    );
+    server
+}
+
+// The address of an upstream given as IP:port or host:port, e.g. dns.google:53; a
+// host is looked up once, at startup, and its first address used.
+fn upstream_address(address: &str) -> SocketAddr {
+    address
+        .to_socket_addrs()
+        .unwrap_or_else(|err| panic!("Failed to resolve the upstream {address}: {err}"))
+        .next()
+        .unwrap_or_else(|| panic!("The upstream {address} has no addresses"))
+}
+
+fn address_filter(cli: &CliArgs, primary: bool) -> AddressFilter {
+    let address_filter = AddressFilter::new(
+        if cli.responses.filter_aaaa {
+            AaaaFiltering::Always
+        } else if cli.responses.filter_aaaa_on_v4_transport {
+            AaaaFiltering::OnV4Transport
+        } else {
+            AaaaFiltering::Off
+        },
+        cli.responses.filter_a,
+        &cli.responses.filter_clients,
+    )
+    .expect("Invalid address filter");
+    if primary && address_filter.is_active() {
+        println!(
+            "Filtering {} addresses out of responses.",
+            if cli.responses.filter_a {
+                "IPv4"
+            } else {
+                "IPv6"
+            }
+        );
+    }
+    address_filter
+}
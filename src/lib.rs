@@ -0,0 +1,8 @@
+// The server as a library: the binary (main.rs) parses its command line (cli), sets
+// the server up from it (cli::setup) and serves with it (server), and other crates
+// can reuse the codec (dns::message) and transports, e.g. through the blocking
+// client (server::client), or run a server of their own (see tests/integration.rs).
+pub mod cli;
+pub mod server;
+
+pub use server::dns;
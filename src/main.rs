@@ -1,42 +1,197 @@
-#[allow(unused_imports)]
-use std::net::SocketAddrV4;
-use std::net::UdpSocket;
+use std::time::Duration;
 
-mod cli;
-use clap::Parser;
-use cli::CliArgs;
+use clap::{CommandFactory, FromArgMatches};
+use codecrafters_dns_server::cli::setup::{self, Resolvers};
+use codecrafters_dns_server::cli::{effective_config, CliArgs, Command};
+use codecrafters_dns_server::server;
 
-mod server;
-
-use server::DnsServer;
-use server::DummyDnsResolver;
-use server::ForwardingDnsResolver;
+use server::dump_state_on_crash;
+use server::install_hangup_handler;
+use server::install_termination_handler;
+use server::CapturedResolver;
+use server::Client;
+use server::Probe;
+use server::QueryOpcodeHandler;
+use server::Randomness;
 use server::Resolve;
+use server::Severity;
+use server::UpstreamSpec;
+use server::{read_capture, replay};
 
 fn main() {
+    let matches = CliArgs::command().get_matches();
+    let cli = CliArgs::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    // With secrets redacted, for support bundles.
+    let config = effective_config(&CliArgs::command(), &matches);
+    if let Err(err) = cli.check_features() {
+        CliArgs::command()
+            .error(clap::error::ErrorKind::ArgumentConflict, err)
+            .exit();
+    }
+
+    if let Some(dir) = &cli.debugging.dump_state_on_crash {
+        dump_state_on_crash(dir.clone(), config.clone());
+    }
+
+    if let Some(Command::Completions { shell }) = cli.command {
+        let mut command = CliArgs::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        return;
+    }
+
+    if let Some(Command::Probe {
+        upstream,
+        name,
+        samples,
+        timeout,
+    }) = &cli.command
+    {
+        let randomness = cli
+            .debugging
+            .random_seed
+            .map_or_else(Randomness::from_clock, Randomness::seeded);
+        let mut probe = Probe::new(*upstream, name, &randomness)
+            .unwrap_or_else(|err| panic!("Invalid probe name: {err}"));
+        probe.samples = *samples;
+        probe.timeout = Duration::from_millis(*timeout);
+        let report = probe.run();
+        println!("{report}");
+        std::process::exit(if report.reachable() { 0 } else { 1 });
+    }
+
+    if let Some(Command::Query {
+        upstream,
+        name,
+        r#type,
+        timeout,
+        edns_size,
+        dnssec,
+        no_tcp,
+    }) = &cli.command
+    {
+        let mut spec = UpstreamSpec::new(*upstream);
+        spec.set_timeout(Duration::from_millis(*timeout))
+            .set_edns_size((*edns_size > 0).then_some(*edns_size))
+            .set_dnssec_ok(*dnssec)
+            .set_tcp_fallback(!no_tcp);
+        match Client::new(spec).query(name, *r#type) {
+            Ok(resolution) => {
+                println!(
+                    ";; status: {}, aa: {}, ra: {}, {} answer(s)",
+                    resolution.rcode,
+                    resolution.aa,
+                    resolution.ra,
+                    resolution.answers.len()
+                );
+                for answer in resolution.answers.iter() {
+                    println!("{answer}");
+                }
+            }
+            Err(err) => {
+                eprintln!("{upstream}: {err}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let records = setup::local_records(&cli);
+    let diagnostics = records.validate();
+    for diagnostic in &diagnostics {
+        println!("{diagnostic}");
+    }
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count();
+    if let Some(Command::Check) = cli.command {
+        println!(
+            "Checked {} local record(s): {} error(s), {} warning(s).",
+            records.len(),
+            errors,
+            diagnostics.len() - errors
+        );
+        std::process::exit(if errors == 0 { 0 } else { 1 });
+    }
+    assert!(errors == 0, "The local records have {errors} error(s)");
+    for redirect in &cli.responses.nxdomain_redirect {
+        redirect
+            .check_unsigned(&records)
+            .expect("Invalid NXDOMAIN redirect");
+    }
+
+    let capture = match &cli.command {
+        Some(Command::Replay { capture, .. }) => Some(
+            read_capture(capture).unwrap_or_else(|err| panic!("Failed to read the capture: {err}")),
+        ),
+        _ => None,
+    };
+
     // You can use print statements as follows for debugging, they'll be visible when running tests.
     println!("Logs from your program will appear here!");
 
-    let cli: CliArgs = CliArgs::parse();
-
-    let resolver: Box<dyn Resolve> = if let Some(fwd_address) = cli.resolver {
-        let fwd_socket =
-            UdpSocket::bind("0.0.0.0:2060").expect("Failed to bind to DNS resolver address");
-        let fwd_addr: SocketAddrV4 = fwd_address.parse().expect("Failed to parse IPv4 address.");
-        println!("DNS resolver type: Forward (will forward DNS requests to {fwd_address}).");
-        fwd_socket
-            .connect(fwd_addr)
-            .expect("Failed to connect to forward DNS resolver");
-        Box::new(ForwardingDnsResolver {
-            fwd_endpoint: fwd_socket,
-        })
-    } else {
-        println!("DNS resolver type: Dummy (will respond with fake data).");
-        Box::new(DummyDnsResolver {})
+    let captured: Option<Box<dyn Resolve>> = match (&cli.command, &capture) {
+        (
+            Some(Command::Replay {
+                from_capture: true, ..
+            }),
+            Some(exchanges),
+        ) => {
+            println!(
+                "DNS resolver type: Captured (will answer from {} captured exchange(s)).",
+                exchanges.len()
+            );
+            Some(Box::new(CapturedResolver::new(exchanges)))
+        }
+        _ => None,
     };
+    let randomness = cli
+        .debugging
+        .random_seed
+        .map_or_else(Randomness::from_clock, Randomness::seeded);
+    println!("Randomness: {}.", randomness);
+    let resolvers = setup::resolvers(&cli, records, captured, &randomness);
 
-    let endpoint = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
-    let server = DnsServer { endpoint, resolver };
+    if let (
+        Some(Command::Replay {
+            original_pacing,
+            max_divergences,
+            ..
+        }),
+        Some(exchanges),
+    ) = (&cli.command, &capture)
+    {
+        let Resolvers {
+            resolver,
+            #[cfg(feature = "dnssec")]
+            trust_anchors,
+            rpz,
+            ..
+        } = resolvers;
+        let handler = QueryOpcodeHandler {
+            #[cfg(feature = "dnssec")]
+            trust_anchors,
+            ..setup::query_opcode_handler(&cli, resolver, rpz)
+        };
+        let report = replay(&handler, exchanges, *original_pacing);
+        for group in report.summary() {
+            println!("{group}");
+        }
+        println!(
+            "Replayed {} exchange(s): {} diverged.",
+            report.replayed,
+            report.divergences.len()
+        );
+        std::process::exit(if report.divergences.len() <= *max_divergences {
+            0
+        } else {
+            1
+        });
+    }
 
+    install_hangup_handler();
+    let server = setup::server(cli, config, resolvers, randomness);
+    install_termination_handler();
     server.work();
 }
@@ -0,0 +1,363 @@
+// Filtering of one IP version's addresses out of responses (--filter-aaaa,
+// --filter-aaaa-on-v4-transport, --filter-a), for clients that can't cope with it,
+// e.g. embedded clients that crash on AAAA answers, or hosts on v6-only networks.
+// With --filter-clients, only the clients in those blocks are filtered for.
+//
+// Records of the filtered type are removed from the answer and additional sections,
+// and the address hints of that version from HTTPS and SVCB records (RFC 9460, 7.3).
+// A query for the filtered type itself thus gets NODATA; as the removed records came
+// without an SOA, one owned by the question's name is made up for the authority
+// section, with the removed RRset's TTL as its negative TTL (RFC 2308, 5), so that
+// the client caches the NODATA as long as it would have cached the answer instead of
+// asking again right away.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use super::dns::message::{
+    Answer, LabelSequence, Message, RCode, RData, RecordClass, RecordType, SvcParam,
+};
+use super::policy::Subnet;
+use super::stats::Stats;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AaaaFiltering {
+    #[default]
+    Off,
+    Always,
+    // Only for clients that queried over IPv4, as dnsmasq's filter-AAAA does.
+    OnV4Transport,
+}
+
+#[derive(Default)]
+pub struct AddressFilter {
+    aaaa: AaaaFiltering,
+    a: bool,
+    // The clients filtered for; all of them if empty.
+    clients: Vec<Subnet>,
+}
+
+impl AddressFilter {
+    pub fn new(aaaa: AaaaFiltering, a: bool, clients: &[Subnet]) -> Result<AddressFilter, String> {
+        if a && aaaa != AaaaFiltering::Off {
+            return Err(String::from(
+                "Filtering both A and AAAA records would leave no addresses.",
+            ));
+        }
+        Ok(AddressFilter {
+            aaaa,
+            a,
+            clients: clients.to_vec(),
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.a || self.aaaa != AaaaFiltering::Off
+    }
+
+    // The type of the addresses filtered out of responses to `client`, if any.
+    fn filtered_type(&self, client: SocketAddr) -> Option<RecordType> {
+        let address = client.ip().to_canonical();
+        if !self.clients.is_empty() && !self.clients.iter().any(|block| block.contains(address)) {
+            return None;
+        }
+        match self.aaaa {
+            AaaaFiltering::Always => Some(RecordType::Aaaa),
+            AaaaFiltering::OnV4Transport if address.is_ipv4() => Some(RecordType::Aaaa),
+            _ if self.a => Some(RecordType::A),
+            _ => None,
+        }
+    }
+
+    // Returns a copy of the response to `client` with the filtered addresses removed,
+    // counting what was done in `stats`.
+    pub fn apply(&self, client: SocketAddr, response: &Message, stats: &Stats) -> Message {
+        let Some(r#type) = self.filtered_type(client) else {
+            return response.clone();
+        };
+        let hint = if r#type == RecordType::Aaaa {
+            SvcParam::IPV6_HINT
+        } else {
+            SvcParam::IPV4_HINT
+        };
+        let mut removed: u64 = 0;
+        let mut stripped: u64 = 0;
+        let mut filter = |records: &[Answer]| -> Vec<Answer> {
+            let mut kept: Vec<Answer> = Vec::new();
+            for record in records {
+                if record.get_type() == r#type {
+                    removed += 1;
+                    continue;
+                }
+                match strip_hint(record.get_rdata(), hint) {
+                    Some(data) => {
+                        stripped += 1;
+                        kept.push(Answer::from_rdata(
+                            /* name= */ record.get_name(),
+                            /* class= */ record.get_class(),
+                            /* ttl= */ record.get_ttl(),
+                            /* data= */ data,
+                        ));
+                    }
+                    None => kept.push(record.clone()),
+                }
+            }
+            kept
+        };
+        let answers = filter(response.get_answers());
+        let additionals = filter(response.get_additionals());
+        if removed == 0 && stripped == 0 {
+            return response.clone();
+        }
+        stats.filtered_records.add(removed);
+        stats.filtered_hints.add(stripped);
+        let mut filtered = response
+            .with_answers(&answers.into())
+            .with_additionals(&additionals.into());
+
+        // A query for the filtered type that was answered now gets NODATA.
+        let answered_ttl = response
+            .get_answers()
+            .iter()
+            .filter(|record| record.get_type() == r#type)
+            .map(Answer::get_ttl)
+            .min();
+        let question = response
+            .get_questions()
+            .iter()
+            .find(|question| question.get_type() == r#type);
+        let no_error = matches!(response.get_header().get_rcode(), RCode::NoError);
+        if let (Some(question), Some(ttl)) = (question, answered_ttl.filter(|_| no_error)) {
+            stats.filtered_to_nodata.increment();
+            let has_soa = filtered
+                .get_authorities()
+                .iter()
+                .any(|record| record.get_type() == RecordType::Soa);
+            if !has_soa {
+                let mut authorities = filtered.get_authorities().to_vec();
+                authorities.push(made_up_soa(question.get_name(), ttl));
+                filtered = filtered.with_authorities(&authorities.into());
+            }
+        }
+        filtered
+    }
+}
+
+// An SOA for a NODATA made by filtering: owned by the question's name, with empty
+// names and counters, and `ttl` as both its TTL and its MINIMUM.
+fn made_up_soa(name: &Arc<LabelSequence>, ttl: u32) -> Answer {
+    let root = LabelSequence::new(&Arc::from([]));
+    Answer::from_rdata(
+        /* name= */ name,
+        /* class= */ RecordClass::In,
+        /* ttl= */ ttl,
+        /* data= */
+        RData::Soa {
+            mname: root.clone(),
+            rname: root,
+            serial: 0,
+            refresh: 0,
+            retry: 0,
+            expire: 0,
+            minimum: ttl,
+        },
+    )
+}
+
+// The RDATA of an SVCB or HTTPS record without its SvcParam `key`, or None if it
+// isn't one or has no such parameter.
+fn strip_hint(data: &RData, key: u16) -> Option<RData> {
+    let RData::Svcb {
+        https,
+        priority,
+        target,
+        params,
+    } = data
+    else {
+        return None;
+    };
+    params
+        .iter()
+        .any(|param| param.key() == key)
+        .then(|| RData::Svcb {
+            https: *https,
+            priority: *priority,
+            target: target.clone(),
+            params: params
+                .iter()
+                .filter(|param| param.key() != key)
+                .cloned()
+                .collect(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dns::message::{Header, Question, RecordClass};
+    use super::*;
+
+    fn name(value: &str) -> Arc<LabelSequence> {
+        Arc::new(value.parse().unwrap())
+    }
+
+    fn record(owner: &str, r#type: RecordType, ttl: u32, data: &[u8]) -> Answer {
+        Answer::new(
+            /* name= */ &name(owner),
+            /* type= */ r#type,
+            /* class= */ RecordClass::In,
+            /* ttl= */ ttl,
+            /* data= */ &Arc::from(data),
+        )
+    }
+
+    fn response(qtype: RecordType, answers: Vec<Answer>) -> Message {
+        let mut header = Header::default();
+        header.set_qr(true).set_qd_count(1);
+        let question = Question::new(&name("www.example.com"), qtype, RecordClass::In);
+        Message::new(&header.into(), &Arc::from([question]), &Arc::from([]))
+            .with_answers(&answers.into())
+    }
+
+    fn types(records: &[Answer]) -> Vec<RecordType> {
+        records.iter().map(Answer::get_type).collect()
+    }
+
+    const V6_ADDRESS: [u8; 16] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+    #[test]
+    fn aaaa_records_are_filtered_only_for_clients_over_ipv4() {
+        let mixed = response(
+            RecordType::Any,
+            vec![
+                record("www.example.com", RecordType::A, 300, &[192, 0, 2, 1]),
+                record("www.example.com", RecordType::Aaaa, 300, &V6_ADDRESS),
+            ],
+        );
+        let filter = AddressFilter::new(AaaaFiltering::OnV4Transport, false, &[]).unwrap();
+        let stats = Stats::default();
+
+        let over_v4 = filter.apply("192.0.2.10:5353".parse().unwrap(), &mixed, &stats);
+        assert_eq!(types(over_v4.get_answers()), [RecordType::A]);
+        assert_eq!(over_v4.get_header().get_an_count(), 1);
+        // Not a query for AAAA: the other records stay, and no SOA is added.
+        assert!(over_v4.get_authorities().is_empty());
+        // Mapped IPv4 addresses on a dual-stack socket count as IPv4.
+        let mapped = filter.apply("[::ffff:192.0.2.10]:5353".parse().unwrap(), &mixed, &stats);
+        assert_eq!(types(mapped.get_answers()), [RecordType::A]);
+        let over_v6 = filter.apply("[2001:db8::10]:5353".parse().unwrap(), &mixed, &stats);
+        assert_eq!(
+            types(over_v6.get_answers()),
+            [RecordType::A, RecordType::Aaaa]
+        );
+        assert_eq!(stats.filtered_records.get(), 2);
+        assert_eq!(stats.filtered_to_nodata.get(), 0);
+
+        // Scoped to some clients, the others are left alone.
+        let scoped = AddressFilter::new(
+            AaaaFiltering::Always,
+            false,
+            &[Subnet::parse("10.0.0.0/8").unwrap()],
+        )
+        .unwrap();
+        let outside = scoped.apply("192.0.2.10:5353".parse().unwrap(), &mixed, &stats);
+        assert_eq!(
+            types(outside.get_answers()),
+            [RecordType::A, RecordType::Aaaa]
+        );
+        let inside = scoped.apply("10.1.2.3:5353".parse().unwrap(), &mixed, &stats);
+        assert_eq!(types(inside.get_answers()), [RecordType::A]);
+
+        assert!(AddressFilter::new(AaaaFiltering::Always, true, &[]).is_err());
+    }
+
+    #[test]
+    fn filtered_queries_get_nodata_with_an_soa() {
+        let answered = response(
+            RecordType::Aaaa,
+            vec![
+                record(
+                    "www.example.com",
+                    RecordType::Cname,
+                    3600,
+                    &name("web.example.net").encode(),
+                ),
+                record("web.example.net", RecordType::Aaaa, 300, &V6_ADDRESS),
+                record("web.example.net", RecordType::Aaaa, 120, &V6_ADDRESS),
+            ],
+        );
+        let filter = AddressFilter::new(AaaaFiltering::Always, false, &[]).unwrap();
+        let stats = Stats::default();
+        let filtered = filter.apply("192.0.2.10:5353".parse().unwrap(), &answered, &stats);
+
+        // The CNAME stays, and the SOA is negatively cached as long as the shortest
+        // TTL of the removed records.
+        assert_eq!(types(filtered.get_answers()), [RecordType::Cname]);
+        assert_eq!(filtered.get_header().get_ns_count(), 1);
+        let soa = &filtered.get_authorities()[0];
+        assert_eq!(soa.get_type(), RecordType::Soa);
+        assert_eq!(soa.get_name().to_string(), "www.example.com");
+        assert_eq!(soa.get_ttl(), 120);
+        assert_eq!(soa.get_data()[18..], 120u32.to_be_bytes());
+        assert_eq!(stats.filtered_records.get(), 2);
+        assert_eq!(stats.filtered_to_nodata.get(), 1);
+        let encoded = filtered.encode();
+        assert_eq!(Message::parse_from(&encoded).unwrap().encode(), encoded);
+
+        // The same with A records on an IPv6-only network.
+        let answered = response(
+            RecordType::A,
+            vec![record(
+                "www.example.com",
+                RecordType::A,
+                60,
+                &[192, 0, 2, 1],
+            )],
+        );
+        let filter = AddressFilter::new(AaaaFiltering::Off, true, &[]).unwrap();
+        let filtered = filter.apply("[2001:db8::10]:5353".parse().unwrap(), &answered, &stats);
+        assert!(filtered.get_answers().is_empty());
+        assert_eq!(types(filtered.get_authorities()), [RecordType::Soa]);
+        assert_eq!(stats.filtered_to_nodata.get(), 2);
+    }
+
+    #[test]
+    fn address_hints_are_stripped_from_https_records() {
+        // Priority 1, target ".", then alpn=h2, ipv4hint and ipv6hint.
+        let mut data: Vec<u8> = vec![0, 1, 0];
+        data.extend_from_slice(&[0, 1, 0, 3, 2, b'h', b'2']);
+        data.extend_from_slice(&[0, 4, 0, 4, 192, 0, 2, 1]);
+        data.extend_from_slice(&[0, 6, 0, 16]);
+        data.extend_from_slice(&V6_ADDRESS);
+        let https = response(
+            RecordType::Https,
+            vec![record("www.example.com", RecordType::Https, 300, &data)],
+        );
+        let stats = Stats::default();
+        let client: SocketAddr = "192.0.2.10:5353".parse().unwrap();
+
+        let filter = AddressFilter::new(AaaaFiltering::Always, false, &[]).unwrap();
+        let filtered = filter.apply(client, &https, &stats);
+        assert_eq!(filtered.get_answers()[0].get_data().as_ref(), &data[..18]);
+        assert!(filtered.get_authorities().is_empty());
+        assert_eq!(stats.filtered_hints.get(), 1);
+
+        let filter = AddressFilter::new(AaaaFiltering::Off, true, &[]).unwrap();
+        let filtered = filter.apply(client, &https, &stats);
+        let mut without_v4: Vec<u8> = data[..10].to_vec();
+        without_v4.extend_from_slice(&data[18..]);
+        assert_eq!(filtered.get_answers()[0].get_data().as_ref(), without_v4);
+
+        // Records without the hint are passed on as they are.
+        let bare = response(
+            RecordType::Https,
+            vec![record(
+                "www.example.com",
+                RecordType::Https,
+                300,
+                &data[..10],
+            )],
+        );
+        filter.apply(client, &bare, &stats);
+        assert_eq!(stats.filtered_hints.get(), 2);
+        assert_eq!(stats.filtered_records.get(), 0);
+    }
+}
@@ -0,0 +1,429 @@
+// Cheap heuristics for clients whose queries look like a DGA (many NXDOMAINs for
+// random-looking names) or like DNS tunneling (long high-entropy names, large TXT
+// or NULL answers, many distinct names under one domain). Nothing is blocked: a
+// client or domain crossing a threshold within a window is logged as an
+// [ANOMALY] event and counted under the reason, once per window.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::dns::message::{LabelSequence, Message, RCode, RecordType};
+use super::legacy::NULL;
+use super::stats::Stats;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AnomalyReason {
+    NxdomainRatio,
+    HighEntropy,
+    LongNames,
+    LargeTxt,
+    SubdomainSpread,
+}
+
+impl AnomalyReason {
+    pub const ALL: [AnomalyReason; 5] = [
+        AnomalyReason::NxdomainRatio,
+        AnomalyReason::HighEntropy,
+        AnomalyReason::LongNames,
+        AnomalyReason::LargeTxt,
+        AnomalyReason::SubdomainSpread,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            AnomalyReason::NxdomainRatio => "nxdomain-ratio",
+            AnomalyReason::HighEntropy => "high-entropy",
+            AnomalyReason::LongNames => "long-names",
+            AnomalyReason::LargeTxt => "large-txt",
+            AnomalyReason::SubdomainSpread => "subdomain-spread",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AnomalyThresholds {
+    pub window: Duration,
+    // Queries a client must send in a window before its ratios and averages count.
+    pub min_queries: u32,
+    // Share of a client's responses that are NXDOMAIN.
+    pub nxdomain_ratio: f64,
+    // Average Shannon entropy, in bits per character, of the labels below the
+    // registered domain of a client's queries.
+    pub entropy: f64,
+    // Average length of those labels, dots included.
+    pub name_length: f64,
+    // TXT or NULL answers of at least `large_rdata` bytes a client may get.
+    pub large_rdata: usize,
+    pub large_answers: u32,
+    // Distinct names queried under one registered domain, by all clients.
+    pub subdomains: usize,
+}
+
+impl Default for AnomalyThresholds {
+    fn default() -> AnomalyThresholds {
+        AnomalyThresholds {
+            window: Duration::from_secs(60),
+            min_queries: 20,
+            nxdomain_ratio: 0.5,
+            entropy: 3.2,
+            name_length: 30.0,
+            large_rdata: 200,
+            large_answers: 20,
+            subdomains: 200,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ClientWindow {
+    queries: u32,
+    nxdomains: u32,
+    // Over the queries with labels below the registered domain.
+    named_queries: u32,
+    entropy_sum: f64,
+    length_sum: u64,
+    large_answers: u32,
+    reported: HashSet<AnomalyReason>,
+}
+
+#[derive(Default)]
+struct SuffixWindow {
+    // Hashes of the names seen, to keep a flood of names cheap.
+    names: HashSet<u64>,
+    reported: bool,
+}
+
+struct Window {
+    started: Instant,
+    clients: HashMap<IpAddr, ClientWindow>,
+    suffixes: HashMap<String, SuffixWindow>,
+}
+
+pub struct AnomalyDetector {
+    pub thresholds: AnomalyThresholds,
+    // Shared by the server loop and the workers (see workers.rs).
+    window: Mutex<Window>,
+}
+
+// The Shannon entropy, in bits per byte, and the length, dots included, of the
+// labels of `name` before the last two, which stand in for the registered domain
+// (there is no public suffix list here). Reads the parsed labels in place.
+fn subdomain_entropy(name: &LabelSequence) -> Option<(f64, usize)> {
+    let labels = name.get_labels();
+    let below = labels.len().checked_sub(2).filter(|&count| count > 0)?;
+    let mut counts = [0u32; 256];
+    let mut length = below - 1;
+    for label in &labels[..below] {
+        for &byte in label.get_content().as_bytes() {
+            counts[byte.to_ascii_lowercase() as usize] += 1;
+        }
+        length += label.get_content().len();
+    }
+    let characters = (length + 1 - below) as f64;
+    let entropy = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / characters;
+            -p * p.log2()
+        })
+        .sum();
+    Some((entropy, length))
+}
+
+// The last two labels of `name`, lowercased.
+fn registered_domain(name: &LabelSequence) -> Option<String> {
+    let labels = name.get_labels();
+    let start = labels.len().checked_sub(2)?;
+    Some(
+        labels[start..]
+            .iter()
+            .map(|label| label.get_content().to_ascii_lowercase())
+            .collect::<Vec<String>>()
+            .join("."),
+    )
+}
+
+impl AnomalyDetector {
+    pub fn new(thresholds: AnomalyThresholds, now: Instant) -> AnomalyDetector {
+        AnomalyDetector {
+            thresholds,
+            window: Mutex::new(Window {
+                started: now,
+                clients: HashMap::new(),
+                suffixes: HashMap::new(),
+            }),
+        }
+    }
+
+    // Accounts a response sent to `client`, reporting the thresholds it takes the
+    // client or the queried domain over.
+    pub fn observe(&self, client: IpAddr, response: &Message, stats: &Stats, now: Instant) {
+        let mut window = self.window.lock().unwrap();
+        if now.saturating_duration_since(window.started) >= self.thresholds.window {
+            window.started = now;
+            window.clients.clear();
+            window.suffixes.clear();
+        }
+        let thresholds = &self.thresholds;
+        let mut events: Vec<(AnomalyReason, String, f64, f64)> = Vec::new();
+
+        let state = window.clients.entry(client).or_default();
+        state.queries += 1;
+        if *response.get_header().get_rcode() == RCode::NameError {
+            state.nxdomains += 1;
+        }
+        for question in response.get_questions().iter() {
+            if let Some((entropy, length)) = subdomain_entropy(question.get_name()) {
+                state.named_queries += 1;
+                state.entropy_sum += entropy;
+                state.length_sum += length as u64;
+            }
+        }
+        state.large_answers += response
+            .get_answers()
+            .iter()
+            .filter(|answer| matches!(answer.get_type(), RecordType::Txt | NULL))
+            .filter(|answer| answer.get_data().len() >= thresholds.large_rdata)
+            .count() as u32;
+
+        let mut checks: Vec<(AnomalyReason, f64, f64)> = vec![(
+            AnomalyReason::LargeTxt,
+            state.large_answers as f64,
+            thresholds.large_answers as f64,
+        )];
+        if state.queries >= thresholds.min_queries {
+            checks.push((
+                AnomalyReason::NxdomainRatio,
+                state.nxdomains as f64 / state.queries as f64,
+                thresholds.nxdomain_ratio,
+            ));
+        }
+        if state.named_queries >= thresholds.min_queries {
+            let named = state.named_queries as f64;
+            checks.push((
+                AnomalyReason::HighEntropy,
+                state.entropy_sum / named,
+                thresholds.entropy,
+            ));
+            checks.push((
+                AnomalyReason::LongNames,
+                state.length_sum as f64 / named,
+                thresholds.name_length,
+            ));
+        }
+        for (reason, value, threshold) in checks {
+            if value >= threshold && state.reported.insert(reason) {
+                events.push((reason, format!("client={}", client), value, threshold));
+            }
+        }
+
+        for question in response.get_questions().iter() {
+            let Some(domain) = registered_domain(question.get_name()) else {
+                continue;
+            };
+            let mut hasher = DefaultHasher::new();
+            question
+                .get_name()
+                .to_string()
+                .to_ascii_lowercase()
+                .hash(&mut hasher);
+            let suffix = window.suffixes.entry(domain.clone()).or_default();
+            suffix.names.insert(hasher.finish());
+            if suffix.names.len() >= thresholds.subdomains && !suffix.reported {
+                suffix.reported = true;
+                events.push((
+                    AnomalyReason::SubdomainSpread,
+                    format!("domain={}", domain),
+                    suffix.names.len() as f64,
+                    thresholds.subdomains as f64,
+                ));
+            }
+        }
+
+        for (reason, subject, value, threshold) in events {
+            stats.record_anomaly(reason);
+            println!(
+                "[ANOMALY] reason={} {} value={:.2} threshold={:.2}",
+                reason.label(),
+                subject,
+                value,
+                threshold
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    use super::super::dns::message::{Answer, Header, Question, RecordClass};
+    use super::*;
+
+    fn response(name: &str, r#type: RecordType, rcode: RCode, data: Option<&[u8]>) -> Message {
+        let name: Arc<LabelSequence> = Arc::new(name.parse().unwrap());
+        let mut header = Header::default();
+        header.set_rcode(rcode);
+        let answers: Vec<Answer> = data
+            .map(|data| {
+                Answer::new(
+                    /* name= */ &name,
+                    /* type= */ r#type,
+                    /* class= */ RecordClass::In,
+                    /* ttl= */ 0,
+                    /* data= */ &Arc::from(data),
+                )
+            })
+            .into_iter()
+            .collect();
+        Message::new(
+            &Arc::new(header),
+            &Arc::from([Question::new(&name, r#type, RecordClass::In)]),
+            &Arc::from(answers),
+        )
+    }
+
+    // Letters and digits from a fixed xorshift sequence, for repeatable random names.
+    fn random_label(state: &mut u64, length: usize) -> String {
+        const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+        (0..length)
+            .map(|_| {
+                *state ^= *state << 13;
+                *state ^= *state >> 7;
+                *state ^= *state << 17;
+                ALPHABET[(*state % ALPHABET.len() as u64) as usize] as char
+            })
+            .collect()
+    }
+
+    fn reasons(stats: &Stats) -> Vec<&'static str> {
+        AnomalyReason::ALL
+            .iter()
+            .filter(|reason| stats.anomalies[**reason as usize].get() > 0)
+            .map(AnomalyReason::label)
+            .collect()
+    }
+
+    fn client(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    #[test]
+    fn normal_workloads_raise_nothing() {
+        let detector = AnomalyDetector::new(AnomalyThresholds::default(), Instant::now());
+        let stats = Stats::default();
+        let names = [
+            "www.example.com",
+            "mail.google.com",
+            "api.github.com",
+            "example.org",
+            "cdn.jsdelivr.net",
+            "login.microsoftonline.com",
+            "missing.example.com",
+        ];
+        for round in 0..50 {
+            for (index, name) in names.iter().enumerate() {
+                let rcode = if index == 6 {
+                    RCode::NameError
+                } else {
+                    RCode::NoError
+                };
+                let txt = [b'v'; 60];
+                let data = (round % 2 == 0).then_some(&txt[..]);
+                detector.observe(
+                    client(1),
+                    &response(name, RecordType::Txt, rcode, data),
+                    &stats,
+                    Instant::now(),
+                );
+            }
+        }
+        assert_eq!(reasons(&stats), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn dga_workloads_raise_nxdomain_and_entropy() {
+        let detector = AnomalyDetector::new(AnomalyThresholds::default(), Instant::now());
+        let stats = Stats::default();
+        let mut state = 0x9E3779B97F4A7C15;
+        for _ in 0..100 {
+            let name = format!("{}.dga.net", random_label(&mut state, 14));
+            detector.observe(
+                client(2),
+                &response(&name, RecordType::A, RCode::NameError, None),
+                &stats,
+                Instant::now(),
+            );
+        }
+        assert_eq!(reasons(&stats), ["nxdomain-ratio", "high-entropy"]);
+        // Once per window.
+        assert_eq!(
+            stats.anomalies[AnomalyReason::NxdomainRatio as usize].get(),
+            1
+        );
+    }
+
+    #[test]
+    fn tunneling_workloads_raise_length_txt_and_spread() {
+        let thresholds = AnomalyThresholds::default();
+        let window = thresholds.window;
+        let started = Instant::now();
+        let detector = AnomalyDetector::new(thresholds, started);
+        let stats = Stats::default();
+        let mut state = 0x2545F4914F6CDD1D;
+        for _ in 0..250 {
+            let name = format!(
+                "{}.{}.t.tunnel.example",
+                random_label(&mut state, 50),
+                random_label(&mut state, 20)
+            );
+            detector.observe(
+                client(3),
+                &response(&name, RecordType::Txt, RCode::NoError, Some(&[b'x'; 250])),
+                &stats,
+                started,
+            );
+        }
+        assert_eq!(
+            reasons(&stats),
+            [
+                "high-entropy",
+                "long-names",
+                "large-txt",
+                "subdomain-spread"
+            ]
+        );
+
+        // A new window starts afresh.
+        detector.observe(
+            client(3),
+            &response(
+                "t.tunnel.example",
+                RecordType::Txt,
+                RCode::NoError,
+                Some(&[b'x'; 250]),
+            ),
+            &stats,
+            started + window,
+        );
+        assert_eq!(
+            detector.window.lock().unwrap().clients[&client(3)].queries,
+            1
+        );
+    }
+
+    #[test]
+    fn entropy_reads_the_labels_below_the_registered_domain() {
+        let name = |name: &str| name.parse::<LabelSequence>().unwrap();
+        assert!(subdomain_entropy(&name("example.com")).is_none());
+        assert_eq!(subdomain_entropy(&name("www.example.com")), Some((0.0, 3)));
+        let (entropy, length) = subdomain_entropy(&name("ab.CD.example.com")).unwrap();
+        assert_eq!((entropy, length), (2.0, 5));
+    }
+}
@@ -0,0 +1,110 @@
+// What becomes of the AD (Authentic Data) bit of the upstream's answers (--ad-mode).
+// We don't validate DNSSEC ourselves, so an AD we pass on is the upstream's word, and
+// worth only as much as the channel it came over: `strip` clears it in every response
+// and `trust-all` passes it on from any upstream. `trust-secure-upstream`, which would
+// pass it on from upstreams asked over TLS, HTTPS or QUIC, is refused for now: the
+// upstream is only asked over UDP, so it could never pass anything on.
+//
+// Whatever the mode, AD is only set for clients that asked for it, with AD or DO in
+// their query (RFC 6840, 5.7 and 5.8), and never on answers of our own: none of the
+// local records are signed.
+
+use super::dns::message::Header;
+use super::edns::request_dnssec_ok;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AdMode {
+    #[default]
+    Strip,
+    TrustAll,
+}
+
+impl AdMode {
+    pub fn parse(value: &str) -> Result<AdMode, String> {
+        match value {
+            "strip" => Ok(AdMode::Strip),
+            "trust-all" => Ok(AdMode::TrustAll),
+            "trust-secure-upstream" => Err(String::from(
+                "AD mode 'trust-secure-upstream' needs an upstream asked over TLS, HTTPS or \
+                 QUIC, and upstreams are only asked over UDP; use strip or trust-all.",
+            )),
+            _ => Err(format!(
+                "Unknown AD mode '{}'; expected strip or trust-all.",
+                value
+            )),
+        }
+    }
+
+    // Whether the response to the request with `header` and raw `data` keeps the AD
+    // bit of the upstream's answer.
+    pub fn keeps_ad(&self, header: &Header, data: &[u8]) -> bool {
+        let asked = header.get_ad() || request_dnssec_ok(data);
+        asked
+            && match self {
+                AdMode::Strip => false,
+                AdMode::TrustAll => true,
+            }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::super::listener::{QueryInfo, Transport};
+    use super::super::overload::Load;
+    use super::super::testing::{forwarder, mock_upstream, query, query_handler};
+    use super::*;
+    use crate::server::{HandleOpcode, QueryOpcodeHandler, Stats};
+
+    #[test]
+    fn upstream_ad_is_only_passed_on_as_the_mode_says() {
+        // The mock sets AD on the answers for names starting with "signed".
+        let upstream = mock_upstream(&[[192, 0, 2, 1]], 60, Duration::ZERO);
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        // A query for `name` asking for AD with the bit itself, with DO, or not at all.
+        let request = |name: &str, ad: bool, dnssec_ok: bool| {
+            let mut request = query(name, dnssec_ok.then_some(&[]));
+            request[3] |= u8::from(ad) << 5;
+            if dnssec_ok {
+                let flags = request.len() - 4;
+                request[flags] |= 0x80;
+            }
+            request
+        };
+        for mode in [AdMode::Strip, AdMode::TrustAll] {
+            let handler = QueryOpcodeHandler {
+                ad_mode: mode,
+                ..query_handler(forwarder(upstream.local_addr().unwrap(), 1000.0))
+            };
+            for name in ["signed.example.com", "www.example.com"] {
+                for (ad, dnssec_ok) in [(false, false), (true, false), (false, true)] {
+                    let request = request(name, ad, dnssec_ok);
+                    let header = Header::parse_from(request[..12].try_into().unwrap());
+                    let response = handler
+                        .handle(&info, &header, &request, &Stats::default())
+                        .unwrap();
+                    assert_eq!(response.get_answers().len(), 1);
+                    assert_eq!(
+                        response.get_header().get_ad(),
+                        mode == AdMode::TrustAll && name.starts_with("signed") && (ad || dnssec_ok),
+                        "{mode:?} {name} ad={ad} do={dnssec_ok}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn trust_secure_upstream_is_refused_without_a_secure_upstream() {
+        assert_eq!(AdMode::parse("trust-all"), Ok(AdMode::TrustAll));
+        let err = AdMode::parse("trust-secure-upstream").unwrap_err();
+        assert!(err.contains("only asked over UDP"), "{err}");
+        assert!(AdMode::parse("trust").is_err());
+    }
+}
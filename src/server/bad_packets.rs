@@ -0,0 +1,252 @@
+// The requests answered FORMERR for not parsing, kept with the bytes that didn't, for
+// debugging interop problems: the latest MAX_PACKETS, up to MAX_BYTES of data in all,
+// shown by the control socket's badpackets command as annotated hex dumps, and logged
+// as they come with --log-bad-packets. A dump names the header fields under the
+// first line and marks the byte at which parsing failed, with the error:
+//
+//     0000  12 34 01 00 00 01 00 00 00 00 00 00 07 65 78 61  .4...........exa
+//           id    flags qd    an    ns    ar
+//     0010  6d 70 6c 65 03 63                                mple.c
+//                       ^^ the name at byte 20 runs past the end (offset 20)
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::dns::message::DnsParseError;
+use super::support::Json;
+use super::verify::hex_dump;
+
+const MAX_PACKETS: usize = 32;
+const MAX_BYTES: usize = 32 * 1024;
+
+// The header fields, by the offset of their first byte.
+const HEADER_FIELDS: [(usize, &str); 6] = [
+    (0, "id"),
+    (2, "flags"),
+    (4, "qd"),
+    (6, "an"),
+    (8, "ns"),
+    (10, "ar"),
+];
+
+// Where the hex digits of a line start, after the offset column.
+const HEX_COLUMN: usize = 6;
+
+pub struct BadPacket {
+    pub at: SystemTime,
+    pub listener: String,
+    pub client: SocketAddr,
+    pub data: Vec<u8>,
+    pub error: DnsParseError,
+}
+
+impl BadPacket {
+    // The packet's hex dump, with the header fields named and the failure marked.
+    pub fn annotated_dump(&self) -> Vec<String> {
+        let offset = self.error.offset();
+        let mut lines: Vec<String> = hex_dump(&self.data).lines().map(String::from).collect();
+        // A failure at the end of data that fills its last line marks a line of its own.
+        if offset / 16 == lines.len() {
+            lines.push(format!("{:04x}", offset));
+        }
+        let marker = format!(
+            "{}^^ {} (offset {})",
+            " ".repeat(HEX_COLUMN + 3 * (offset % 16)),
+            self.error,
+            offset
+        );
+        let mut annotated = Vec::new();
+        for (index, line) in lines.into_iter().enumerate() {
+            annotated.push(line);
+            if index == 0 {
+                let mut fields = String::new();
+                for (start, name) in HEADER_FIELDS {
+                    if start < self.data.len() {
+                        let column = HEX_COLUMN + 3 * start;
+                        fields.push_str(&" ".repeat(column.saturating_sub(fields.len())));
+                        fields.push_str(name);
+                    }
+                }
+                annotated.push(fields);
+            }
+            if index == offset / 16 {
+                annotated.push(marker.clone());
+            }
+        }
+        annotated
+    }
+
+    fn millis(&self) -> u64 {
+        self.at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64)
+    }
+
+    // A line about the packet, then its annotated dump.
+    pub fn report(&self) -> Vec<String> {
+        let millis = self.millis();
+        let mut report = vec![format!(
+            "{}.{:03} [{}] {} byte(s) from {}: {}",
+            millis / 1000,
+            millis % 1000,
+            self.listener,
+            self.data.len(),
+            self.client,
+            self.error
+        )];
+        report.extend(self.annotated_dump());
+        report
+    }
+
+    pub fn to_json(&self) -> Json {
+        let hex: String = self
+            .data
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Json::object([
+            ("at_millis", Json::Number(self.millis())),
+            ("listener", Json::String(self.listener.clone())),
+            ("client", Json::String(self.client.to_string())),
+            ("error", Json::String(self.error.to_string())),
+            ("offset", Json::Number(self.error.offset() as u64)),
+            ("hex", Json::String(hex)),
+        ])
+    }
+}
+
+#[derive(Default)]
+pub struct BadPackets {
+    // Whether each packet is logged as it is kept.
+    log: bool,
+    packets: Mutex<VecDeque<BadPacket>>,
+}
+
+impl BadPackets {
+    pub fn new(log: bool) -> BadPackets {
+        BadPackets {
+            log,
+            ..Default::default()
+        }
+    }
+
+    // Keeps the packet, dropping the oldest ones to make room.
+    pub fn record(&self, packet: BadPacket) {
+        if self.log {
+            println!(
+                "[{}] Malformed request from {}:\n{}",
+                packet.listener,
+                packet.client,
+                packet.annotated_dump().join("\n")
+            );
+        }
+        let mut packets = self.packets.lock().unwrap();
+        let mut bytes = packets.iter().map(|kept| kept.data.len()).sum::<usize>();
+        while !packets.is_empty()
+            && (packets.len() >= MAX_PACKETS || bytes + packet.data.len() > MAX_BYTES)
+        {
+            bytes -= packets.pop_front().map_or(0, |dropped| dropped.data.len());
+        }
+        if packet.data.len() <= MAX_BYTES {
+            packets.push_back(packet);
+        }
+    }
+
+    // The packets kept, oldest first, each with its dump.
+    pub fn report(&self) -> Vec<String> {
+        self.packets
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(BadPacket::report)
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::Array(
+            self.packets
+                .lock()
+                .unwrap()
+                .iter()
+                .map(BadPacket::to_json)
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::super::dns::message::Message;
+    use super::super::testing::{dummy_server, query};
+    use super::super::LoopState;
+    use super::*;
+
+    #[test]
+    fn dumps_mark_where_parsing_failed() {
+        // "example.com" cut short in its second label.
+        let mut data = query("example.com", None);
+        data.truncate(22);
+        let error = Message::parse_from(&data).unwrap_err();
+        assert_eq!(error, DnsParseError::LabelOverrun { offset: 20 });
+        let packet = BadPacket {
+            at: UNIX_EPOCH,
+            listener: String::from("udp"),
+            client: "192.0.2.1:5353".parse().unwrap(),
+            data,
+            error,
+        };
+        assert_eq!(
+            packet.annotated_dump(),
+            [
+                "0000  12 34 01 00 00 01 00 00 00 00 00 00 07 65 78 61  .4...........exa",
+                "      id    flags qd    an    ns    ar",
+                "0010  6d 70 6c 65 03 63                                mple.c",
+                "                  ^^ the name at byte 20 runs past the end (offset 20)",
+            ]
+        );
+
+        // Past the end of data that fills its last line, the marker gets a line of its own.
+        let packet = BadPacket {
+            data: vec![0; 16],
+            error: DnsParseError::TrailingGarbage { offset: 16 },
+            ..packet
+        };
+        let dump = packet.annotated_dump();
+        assert_eq!(dump[2], "0010");
+        assert_eq!(dump[3], "      ^^ trailing bytes from byte 16 (offset 16)");
+    }
+
+    #[test]
+    fn rejected_requests_are_kept_for_the_control_socket() {
+        let server = dummy_server();
+        let mut state = LoopState::new(&server.randomness);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        let mut data = query("example.com", None);
+        data.push(0xff);
+        client.send_to(&data, address).unwrap();
+        server.run_once(&mut state).unwrap();
+        client.recv(&mut [0; 512]).unwrap();
+
+        let report = server.stats.bad_packets.report();
+        assert!(
+            report[0].ends_with(&format!(
+                "30 byte(s) from {}: trailing bytes from byte 29",
+                client.local_addr().unwrap()
+            )),
+            "{report:?}"
+        );
+        assert_eq!(
+            report[4],
+            "                                             ^^ trailing bytes from byte 29 (offset 29)"
+        );
+        let json = server.stats.bad_packets.to_json().to_string();
+        assert!(json.contains("\"offset\": 29"), "{json}");
+    }
+}
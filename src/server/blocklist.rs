@@ -0,0 +1,323 @@
+// The blocklist (--blocklist): domains, e.g. of ad and tracker networks, whose names
+// are answered without asking anyone, as --block-mode says: NXDOMAIN, the null
+// address (0.0.0.0 for A, :: for AAAA, NODATA for other types) or REFUSED. A domain
+// blocks its subdomains too, label by label: example.com blocks ads.example.com, not
+// notexample.com. Matching ignores case, and takes a hash lookup per label of the
+// name asked for, however long the list.
+//
+// The file has a domain per line, or lines in the hosts format most published lists
+// use, "0.0.0.0 ads.example.com", whose address is ignored; comments (from #) and
+// blank lines are skipped, as are the names such lists carry for the loopback, like
+// localhost. Lines that don't parse are skipped with a warning.
+//
+// It's loaded once and shared by the worker threads, which count the queries they
+// block in it.
+
+use std::{
+    collections::HashSet,
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::Arc,
+    time::Instant,
+};
+
+use super::dns::message::{Answer, Header, LabelSequence, Question, RCode, RecordType};
+use super::stats::ShardedCounter;
+use super::{Lookup, PolicyOutcome, Resolve, Resolved};
+
+// TTL of the null addresses answered for blocked names.
+const BLOCKED_TTL: u32 = 60;
+
+// Names of hosts-format lists that are the host's own rather than blocked.
+const LOCAL_NAMES: [&str; 7] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "0.0.0.0",
+];
+
+// How blocked names are answered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockMode {
+    NxDomain,
+    // 0.0.0.0 or ::.
+    Null,
+    Refused,
+}
+
+impl BlockMode {
+    pub fn parse(value: &str) -> Result<BlockMode, String> {
+        match value {
+            "nxdomain" => Ok(BlockMode::NxDomain),
+            "null" => Ok(BlockMode::Null),
+            "refused" => Ok(BlockMode::Refused),
+            _ => Err(format!(
+                "Unknown block mode '{}'; expected nxdomain, null or refused.",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Blocklist {
+    // Lowercased, without the trailing dot.
+    domains: HashSet<String>,
+    // Queries answered for a blocked name.
+    pub blocked: ShardedCounter,
+}
+
+impl Blocklist {
+    // Reads the list at `path`; returns it and a warning for each line skipped.
+    pub fn load(path: &Path) -> Result<(Blocklist, Vec<String>), String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("Can't read {}: {}", path.display(), err))?;
+        Ok(Blocklist::parse(&text))
+    }
+
+    pub fn parse(text: &str) -> (Blocklist, Vec<String>) {
+        let mut blocklist = Blocklist::default();
+        let mut warnings: Vec<String> = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            if fields.len() > 1 {
+                if fields[0].parse::<IpAddr>().is_err() {
+                    warnings.push(format!(
+                        "line {}: expected a domain, or an address and domains.",
+                        index + 1
+                    ));
+                    continue;
+                }
+                fields.remove(0);
+            }
+            for domain in fields {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                if LOCAL_NAMES.contains(&domain.as_str()) {
+                    continue;
+                }
+                if domain.is_empty() || domain.parse::<LabelSequence>().is_err() {
+                    warnings.push(format!("line {}: '{}' is not a domain.", index + 1, domain));
+                    continue;
+                }
+                blocklist.domains.insert(domain);
+            }
+        }
+        (blocklist, warnings)
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    // The listed domain that blocks `name`, if one does: the name itself, or the
+    // closest domain above it.
+    pub fn find(&self, name: &LabelSequence) -> Option<&str> {
+        let name = name.to_string().trim_end_matches('.').to_ascii_lowercase();
+        let mut rest = name.as_str();
+        loop {
+            if let Some(domain) = self.domains.get(rest) {
+                return Some(domain);
+            }
+            rest = rest.split_once('.')?.1;
+        }
+    }
+}
+
+pub struct BlocklistResolver {
+    pub blocklist: Arc<Blocklist>,
+    pub mode: BlockMode,
+    pub next: Box<dyn Resolve>,
+}
+
+impl Resolve for BlocklistResolver {
+    fn lookup(&self, header: &Header, question: &Question) -> Resolved {
+        let Some(domain) = self.blocklist.find(question.get_name()) else {
+            return self.next.lookup(header, question);
+        };
+        self.blocklist.blocked.increment();
+        println!(
+            "[BLOCKLIST] Blocking {} ({} is listed); answering {:?}.",
+            question, domain, self.mode
+        );
+        let name = question.get_name();
+        let lookup = match (self.mode, question.get_type()) {
+            (BlockMode::Null, RecordType::A) => {
+                Lookup::Found(vec![Answer::a(name, BLOCKED_TTL, Ipv4Addr::UNSPECIFIED)])
+            }
+            (BlockMode::Null, RecordType::Aaaa) => {
+                Lookup::Found(vec![Answer::aaaa(name, BLOCKED_TTL, Ipv6Addr::UNSPECIFIED)])
+            }
+            (BlockMode::Null, _) => Lookup::FoundNoData,
+            (BlockMode::NxDomain | BlockMode::Refused, _) => Lookup::NotFound,
+        };
+        let rcode = match self.mode {
+            BlockMode::NxDomain => RCode::NameError,
+            BlockMode::Null => RCode::NoError,
+            BlockMode::Refused => RCode::Refused,
+        };
+        let mut header = Header::default();
+        header.set_rcode(rcode);
+        Resolved {
+            lookup,
+            header: Some(Arc::new(header)),
+            authorities: Arc::from([]),
+            policy_outcome: Some(PolicyOutcome::filtered(format!("blocklist {}", domain))),
+            provenance: None,
+            cached: false,
+            encoded_answers: None,
+        }
+    }
+
+    fn provenance(&self, question: &Question) -> String {
+        match self.blocklist.find(question.get_name()) {
+            Some(domain) => format!("source=blocklist domain={}", domain),
+            None => self.next.provenance(question),
+        }
+    }
+
+    fn answers_locally(&self, question: &Question) -> bool {
+        self.blocklist.find(question.get_name()).is_some() || self.next.answers_locally(question)
+    }
+
+    fn claims(&self, question: &Question) -> bool {
+        self.blocklist.find(question.get_name()).is_some() || self.next.claims(question)
+    }
+
+    fn maintain(&self) {
+        self.next.maintain();
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        self.next.persist()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.next.next_due()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dns::message::{RData, RecordClass};
+    use super::super::DummyDnsResolver;
+    use super::*;
+
+    const LIST: &str = "\
+        # A hosts-format list.\n\
+        127.0.0.1 localhost\n\
+        0.0.0.0 0.0.0.0\n\
+        0.0.0.0 Ads.Example.com tracker.example.net # two on a line\n\
+        \n\
+        example.org\n\
+        metrics.example.\n\
+        ads..example\n\
+        not-an-address ads.example\n";
+
+    fn name(name: &str) -> LabelSequence {
+        name.parse().unwrap()
+    }
+
+    #[test]
+    fn domains_block_their_subdomains_only() {
+        let (blocklist, warnings) = Blocklist::parse(LIST);
+        assert_eq!(
+            warnings,
+            [
+                "line 8: 'ads..example' is not a domain.",
+                "line 9: expected a domain, or an address and domains.",
+            ]
+        );
+        assert_eq!(blocklist.len(), 4);
+        for (queried, listed) in [
+            ("ads.example.com", Some("ads.example.com")),
+            ("CDN.ads.EXAMPLE.com", Some("ads.example.com")),
+            ("example.com", None),
+            ("www.example.org", Some("example.org")),
+            ("notexample.org", None),
+            ("metrics.example", Some("metrics.example")),
+            ("localhost", None),
+        ] {
+            assert_eq!(blocklist.find(&name(queried)), listed, "{queried}");
+        }
+    }
+
+    #[test]
+    fn blocked_names_are_answered_as_the_mode_says() {
+        let blocklist = Arc::new(Blocklist::parse("example.org\n").0);
+        let resolver = |mode| BlocklistResolver {
+            blocklist: Arc::clone(&blocklist),
+            mode,
+            next: Box::new(DummyDnsResolver {
+                ipv6_address: Ipv6Addr::LOCALHOST,
+            }),
+        };
+        let question = |name: &str, r#type| {
+            Question::new(&Arc::new(name.parse().unwrap()), r#type, RecordClass::In)
+        };
+        let header = Header::default();
+        let ads = question("ads.example.org", RecordType::A);
+
+        let nxdomain = resolver(BlockMode::NxDomain);
+        let blocked = nxdomain.lookup(&header, &ads);
+        assert!(matches!(blocked.lookup, Lookup::NotFound));
+        let rcode = |resolver: &BlocklistResolver, question| {
+            resolver
+                .lookup(&header, question)
+                .header
+                .map(|header| header.get_rcode().clone())
+        };
+        assert_eq!(rcode(&nxdomain, &ads), Some(RCode::NameError));
+        assert_eq!(
+            blocked.policy_outcome,
+            Some(PolicyOutcome::filtered(String::from(
+                "blocklist example.org"
+            )))
+        );
+
+        let null = resolver(BlockMode::Null);
+        let addresses: Vec<String> = [RecordType::A, RecordType::Aaaa]
+            .into_iter()
+            .flat_map(|r#type| {
+                null.lookup(&header, &question("ads.example.org", r#type))
+                    .into_answers()
+            })
+            .map(|answer| match answer.get_rdata() {
+                RData::A(address) => format!("{} {}", address, answer.get_ttl()),
+                RData::Aaaa(address) => format!("{} {}", address, answer.get_ttl()),
+                data => panic!("{data:?}"),
+            })
+            .collect();
+        assert_eq!(addresses, ["0.0.0.0 60", ":: 60"]);
+        let mx = question("ads.example.org", RecordType::Mx);
+        assert!(matches!(
+            null.lookup(&header, &mx).lookup,
+            Lookup::FoundNoData
+        ));
+        assert_eq!(rcode(&null, &mx), Some(RCode::NoError));
+
+        let refused = resolver(BlockMode::Refused);
+        assert!(matches!(
+            refused.lookup(&header, &ads).lookup,
+            Lookup::NotFound
+        ));
+        assert_eq!(rcode(&refused, &ads), Some(RCode::Refused));
+
+        // Names not listed go on; every blocked query was counted.
+        let other = question("www.example.com", RecordType::A);
+        assert_eq!(refused.lookup(&header, &other).into_answers().len(), 1);
+        assert_eq!(rcode(&refused, &other), None);
+        assert_eq!(blocklist.blocked.get(), 8);
+    }
+}
@@ -0,0 +1,373 @@
+// A circuit breaker around the upstream (--breaker-failures). With the upstream
+// down, every forwarded query would wait out the timeout and the retries before its
+// SERVFAIL, holding up the server loop and the workers with it. So once that many
+// queries in a row got no response, the breaker opens: forwarded queries fail at
+// once, with SERVFAIL and the Not Ready Extended DNS Error, without a datagram sent.
+// While open, one query per probe interval is let through to test the upstream (the
+// breaker is half-open while it's out): a response closes the breaker, no response
+// doubles the interval, up to --breaker-max-probe-interval-ms. Answers that don't
+// need the upstream (local records, pins, redirects) never reach the breaker. One
+// breaker is shared by the forwarders of the server loop and of the workers.
+
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Whether a forwarded query may be sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Admission {
+    Pass,
+    // Sent as the probe of an open breaker; its result decides what comes next.
+    Probe,
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    // Counting the failures in a row.
+    Closed {
+        failures: u32,
+    },
+    Open {
+        next_probe: Instant,
+        interval: Duration,
+    },
+    // A probe is out; its interval is what the next one waits if it fails.
+    HalfOpen {
+        interval: Duration,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BreakerCounts {
+    pub opened: u64,
+    pub closed: u64,
+    // Queries failed without asking the upstream.
+    pub rejected: u64,
+    pub probes: u64,
+}
+
+struct Inner {
+    state: State,
+    counts: BreakerCounts,
+}
+
+pub struct CircuitBreaker {
+    failures: u32,
+    probe_interval: Duration,
+    max_probe_interval: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    // A breaker that opens after `failures` in a row, then probes every
+    // `probe_interval`, backing off to `max_probe_interval`.
+    pub fn new(
+        failures: u32,
+        probe_interval: Duration,
+        max_probe_interval: Duration,
+    ) -> CircuitBreaker {
+        CircuitBreaker {
+            failures: failures.max(1),
+            probe_interval,
+            max_probe_interval: max_probe_interval.max(probe_interval),
+            inner: Mutex::new(Inner {
+                state: State::Closed { failures: 0 },
+                counts: BreakerCounts::default(),
+            }),
+        }
+    }
+
+    pub fn admit(&self, now: Instant) -> Admission {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::Closed { .. } => Admission::Pass,
+            State::Open {
+                next_probe,
+                interval,
+            } if now >= next_probe => {
+                inner.state = State::HalfOpen { interval };
+                inner.counts.probes += 1;
+                println!("[BREAKER] Half-open: probing the upstream.");
+                Admission::Probe
+            }
+            State::Open { .. } | State::HalfOpen { .. } => {
+                inner.counts.rejected += 1;
+                Admission::Reject
+            }
+        }
+    }
+
+    // Records whether an admitted query got a response from the upstream.
+    pub fn record(&self, admission: Admission, responded: bool, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = match (inner.state, responded) {
+            (State::Closed { .. }, true) => State::Closed { failures: 0 },
+            (State::Closed { failures }, false) if failures + 1 < self.failures => State::Closed {
+                failures: failures + 1,
+            },
+            (State::Closed { .. }, false) => {
+                inner.counts.opened += 1;
+                println!(
+                    "[BREAKER] Open: {} queries in a row got no response from the upstream; \
+                     probing again in {:?}.",
+                    self.failures, self.probe_interval
+                );
+                State::Open {
+                    next_probe: now + self.probe_interval,
+                    interval: self.probe_interval,
+                }
+            }
+            (State::HalfOpen { .. }, true) if admission == Admission::Probe => {
+                inner.counts.closed += 1;
+                println!("[BREAKER] Closed: the upstream answered the probe.");
+                State::Closed { failures: 0 }
+            }
+            (State::HalfOpen { interval }, false) if admission == Admission::Probe => {
+                let interval = (interval * 2).min(self.max_probe_interval);
+                println!(
+                    "[BREAKER] Open: the probe got no response; probing again in {:?}.",
+                    interval
+                );
+                State::Open {
+                    next_probe: now + interval,
+                    interval,
+                }
+            }
+            // The result of a query sent before the breaker opened changes nothing.
+            (state, _) => state,
+        };
+    }
+
+    pub fn status(&self, now: Instant) -> BreakerStatus {
+        let inner = self.inner.lock().unwrap();
+        let (state, next_probe) = match inner.state {
+            State::Closed { .. } => ("closed", None),
+            State::Open { next_probe, .. } => {
+                ("open", Some(next_probe.saturating_duration_since(now)))
+            }
+            State::HalfOpen { .. } => ("half-open", None),
+        };
+        BreakerStatus {
+            state,
+            next_probe,
+            counts: inner.counts,
+        }
+    }
+}
+
+// Where the breaker stands, for the stats.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BreakerStatus {
+    pub state: &'static str,
+    // How long until the next probe, while open.
+    pub next_probe: Option<Duration>,
+    pub counts: BreakerCounts,
+}
+
+impl fmt::Display for BreakerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.state)?;
+        if let Some(next_probe) = self.next_probe {
+            write!(f, ", probing in {}ms", next_probe.as_millis())?;
+        }
+        write!(
+            f,
+            ", opened {} time(s), {} failed fast, {} probe(s)",
+            self.counts.opened, self.counts.rejected, self.counts.probes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, UdpSocket},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use super::super::dns::message::{
+        Answer, Header, Message, Question, RCode, RecordClass, RecordType,
+    };
+    use super::super::edns::EDE_NOT_READY;
+    use super::super::testing::{self, extended_error_in};
+    use super::super::{response_header, HandleOpcode, Load, QueryInfo, Resolve, Stats, Transport};
+    use super::*;
+
+    #[test]
+    fn probes_back_off_until_the_upstream_answers() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let breaker = CircuitBreaker::new(2, second, 3 * second);
+        assert_eq!(breaker.admit(start), Admission::Pass);
+        breaker.record(Admission::Pass, false, start);
+        assert_eq!(breaker.status(start).state, "closed");
+        breaker.record(Admission::Pass, false, start);
+        assert_eq!(breaker.admit(start), Admission::Reject);
+
+        // Probes after 1s, then 2s, then 3s (the cap), then 3s.
+        let mut at = start;
+        for wait in [1, 2, 3, 3] {
+            let before = at + wait * second - Duration::from_millis(1);
+            assert_eq!(breaker.admit(before), Admission::Reject, "{wait}s");
+            at += wait * second;
+            assert_eq!(breaker.admit(at), Admission::Probe, "{wait}s");
+            // Only one probe at a time.
+            assert_eq!(breaker.admit(at), Admission::Reject);
+            breaker.record(Admission::Probe, false, at);
+        }
+        at += 3 * second;
+        assert_eq!(breaker.admit(at), Admission::Probe);
+        breaker.record(Admission::Probe, true, at);
+        assert_eq!(breaker.admit(at), Admission::Pass);
+        let status = breaker.status(at);
+        assert_eq!(status.state, "closed");
+        assert_eq!(
+            status.counts,
+            BreakerCounts {
+                opened: 1,
+                closed: 1,
+                rejected: 9,
+                probes: 5,
+            }
+        );
+    }
+
+    #[test]
+    fn forwarded_queries_fail_fast_while_the_upstream_is_down() {
+        // Answers with an A record while `up`, and counts the queries it gets.
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let up = Arc::new(AtomicBool::new(false));
+        let received = Arc::new(AtomicUsize::new(0));
+        let mock = upstream.try_clone().unwrap();
+        let (mock_up, mock_received) = (Arc::clone(&up), Arc::clone(&received));
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = mock.recv_from(&mut buf) {
+                mock_received.fetch_add(1, Ordering::SeqCst);
+                if !mock_up.load(Ordering::SeqCst) {
+                    continue;
+                }
+                let request = Message::parse_from(&buf[..size]).unwrap();
+                let mut header = response_header(request.get_header(), RCode::NoError);
+                header.set_qd_count(1).set_an_count(1);
+                let answer = Answer::a(
+                    request.get_questions()[0].get_name(),
+                    60,
+                    Ipv4Addr::new(192, 0, 2, 1),
+                );
+                let response = Message::new(
+                    &header.into(),
+                    request.get_questions(),
+                    &Arc::from([answer]),
+                );
+                let _ = mock.send_to(&response.encode(), source);
+            }
+        });
+        let interval = Duration::from_millis(200);
+        let breaker = Arc::new(CircuitBreaker::new(2, interval, 4 * interval));
+        let mut forwarder = testing::forwarder(upstream.local_addr().unwrap(), 10.0);
+        forwarder.timeout = Duration::from_millis(50);
+        forwarder.breaker = Some(Arc::clone(&breaker));
+        let handler = testing::query_handler(forwarder);
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        let ask = || -> (RCode, Option<(u16, String)>, Duration) {
+            let request = testing::query("www.example.com", Some(&[]));
+            let header = Header::parse_from(request[..12].try_into().unwrap());
+            let started = Instant::now();
+            let response = handler
+                .handle(&info, &header, &request, &Stats::default())
+                .unwrap();
+            (
+                response.get_header().get_rcode().clone(),
+                extended_error_in(&response),
+                started.elapsed(),
+            )
+        };
+
+        // Two timeouts open the breaker; then no datagram goes out.
+        for _ in 0..2 {
+            let (rcode, ede, _) = ask();
+            assert_eq!(rcode, RCode::ServerError);
+            assert_eq!(ede, None);
+        }
+        let (rcode, ede, elapsed) = ask();
+        assert_eq!(rcode, RCode::ServerError);
+        assert_eq!(ede.map(|(code, _)| code), Some(EDE_NOT_READY));
+        assert!(elapsed < Duration::from_millis(20), "{elapsed:?}");
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+
+        // One probe per interval, and the interval doubles when it fails.
+        thread::sleep(interval);
+        assert_eq!(ask().0, RCode::ServerError);
+        assert_eq!(received.load(Ordering::SeqCst), 3);
+        thread::sleep(interval);
+        assert_eq!(ask().1.map(|(code, _)| code), Some(EDE_NOT_READY));
+        assert_eq!(received.load(Ordering::SeqCst), 3);
+
+        // Back up, the breaker closes with the next probe, within its interval.
+        up.store(true, Ordering::SeqCst);
+        thread::sleep(interval);
+        let (rcode, ede, _) = ask();
+        assert_eq!((rcode, ede), (RCode::NoError, None));
+        assert_eq!(breaker.status(Instant::now()).state, "closed");
+        assert_eq!(ask().0, RCode::NoError);
+        assert_eq!(received.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn prefetches_go_through_the_breaker() {
+        // Never answers, and counts the queries it gets.
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let received = Arc::new(AtomicUsize::new(0));
+        let mock = upstream.try_clone().unwrap();
+        let mock_received = Arc::clone(&received);
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while mock.recv_from(&mut buf).is_ok() {
+                mock_received.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let interval = Duration::from_millis(200);
+        let breaker = Arc::new(CircuitBreaker::new(1, interval, 4 * interval));
+        let mut forwarder = testing::forwarder(upstream.local_addr().unwrap(), 10.0);
+        forwarder.timeout = Duration::from_millis(50);
+        forwarder.breaker = Some(Arc::clone(&breaker));
+        let questions: Arc<[Question]> = ["a.example.com", "b.example.com"]
+            .iter()
+            .map(|name| {
+                Question::new(
+                    &Arc::new(name.parse().unwrap()),
+                    RecordType::A,
+                    RecordClass::In,
+                )
+            })
+            .collect();
+        breaker.record(Admission::Pass, false, Instant::now());
+
+        // Open, the breaker lets neither question out.
+        let resolution = forwarder.resolve(&Header::default(), &questions);
+        assert_eq!(resolution.rcode, RCode::ServerError);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(received.load(Ordering::SeqCst), 0);
+
+        // Half-open, it lets one out as the probe, and opens again when it fails.
+        thread::sleep(interval);
+        let resolution = forwarder.resolve(&Header::default(), &questions);
+        assert_eq!(resolution.rcode, RCode::ServerError);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+        assert_eq!(breaker.status(Instant::now()).state, "open");
+    }
+}
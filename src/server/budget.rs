@@ -0,0 +1,306 @@
+// The work a single client query may cause. Several layers follow names on a query's
+// behalf (CNAME chains in the local records, search list candidates, NXDOMAIN
+// redirects) or ask upstream, and each has a bound of its own; put together, a
+// crafted zone or upstream could still make one query expensive, e.g. with a
+// redirect to a name whose CNAME points back at the redirected one. So the query
+// handler opens a WorkBudget for every query, and every layer charges the work it
+// does to it: upstream queries, names followed, rewrites and response bytes parsed.
+// Going over a soft limit is logged; going over a hard one exhausts the budget, the
+// layers give up, and the query is answered SERVFAIL. Every name followed is also
+// checked against those already visited for the query, across layers, so that loops
+// are cut short before any limit is reached.
+//
+// The budget is per thread, as queries are answered one at a time on a thread, and
+// work done outside a query (e.g. paced warm-up queries) isn't charged.
+
+use std::{cell::RefCell, collections::HashSet, fmt};
+
+use super::dns::message::{LabelSequence, Question};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Work {
+    UpstreamQueries,
+    NamesFollowed,
+    Rewrites,
+    BytesParsed,
+}
+
+impl Work {
+    pub const ALL: [Work; 4] = [
+        Work::UpstreamQueries,
+        Work::NamesFollowed,
+        Work::Rewrites,
+        Work::BytesParsed,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Work::UpstreamQueries => "upstream queries",
+            Work::NamesFollowed => "names followed",
+            Work::Rewrites => "rewrites",
+            Work::BytesParsed => "bytes parsed",
+        }
+    }
+
+    // Past this, the work is logged.
+    pub fn soft_limit(&self) -> u64 {
+        match self {
+            Work::UpstreamQueries => 4,
+            Work::NamesFollowed => 8,
+            Work::Rewrites => 2,
+            Work::BytesParsed => 8192,
+        }
+    }
+
+    // Past this, the query is given up on.
+    pub fn hard_limit(&self) -> u64 {
+        match self {
+            Work::UpstreamQueries => 12,
+            Work::NamesFollowed => 16,
+            Work::Rewrites => 4,
+            Work::BytesParsed => 65536,
+        }
+    }
+}
+
+// Why a query's budget was exhausted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Exhausted {
+    Limit(Work),
+    // The name was followed to a second time.
+    Loop(String),
+}
+
+impl fmt::Display for Exhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exhausted::Limit(work) => write!(f, "too many {}", work.name()),
+            Exhausted::Loop(name) => write!(f, "loop at {}", name),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct WorkBudget {
+    spent: [u64; Work::ALL.len()],
+    // Names visited, lowercased.
+    visited: HashSet<String>,
+    exhausted: Option<Exhausted>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<WorkBudget>> = const { RefCell::new(None) };
+}
+
+// Ends the budget opened by WorkBudget::open when dropped.
+pub struct OpenBudget;
+
+impl OpenBudget {
+    // What the query has spent so far.
+    pub fn spent(&self, work: Work) -> u64 {
+        CURRENT.with(|current| {
+            current
+                .borrow()
+                .as_ref()
+                .map_or(0, |budget| budget.spent[work as usize])
+        })
+    }
+
+    pub fn exhausted(&self) -> Option<Exhausted> {
+        CURRENT.with(|current| {
+            current
+                .borrow()
+                .as_ref()
+                .and_then(|budget| budget.exhausted.clone())
+        })
+    }
+}
+
+impl Drop for OpenBudget {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.replace(None));
+    }
+}
+
+impl WorkBudget {
+    // Opens the budget of a query for `questions`, whose names count as visited.
+    pub fn open(questions: &[Question]) -> OpenBudget {
+        let budget = WorkBudget {
+            visited: questions
+                .iter()
+                .map(|question| question.get_name().to_string().to_ascii_lowercase())
+                .collect(),
+            ..Default::default()
+        };
+        CURRENT.with(|current| current.replace(Some(budget)));
+        OpenBudget
+    }
+
+    fn charge(&mut self, work: Work, amount: u64) -> bool {
+        if self.exhausted.is_some() {
+            return false;
+        }
+        let spent = &mut self.spent[work as usize];
+        let before = *spent;
+        *spent += amount;
+        if *spent > work.hard_limit() {
+            println!(
+                "[BUDGET] Giving up on the query: {} {} (hard limit {}).",
+                *spent,
+                work.name(),
+                work.hard_limit()
+            );
+            self.exhausted = Some(Exhausted::Limit(work));
+            return false;
+        }
+        if before <= work.soft_limit() && *spent > work.soft_limit() {
+            println!(
+                "[BUDGET] The query is over its soft limit: {} {} (soft limit {}).",
+                *spent,
+                work.name(),
+                work.soft_limit()
+            );
+        }
+        true
+    }
+}
+
+// Charges `amount` of `work` to the current query, if there is one; false if its
+// budget is exhausted, and the work shouldn't be done.
+pub fn spend(work: Work, amount: u64) -> bool {
+    CURRENT.with(|current| {
+        current
+            .borrow_mut()
+            .as_mut()
+            .map_or(true, |budget| budget.charge(work, amount))
+    })
+}
+
+// Charges following an alias or a rewrite to `name`; false if the budget is
+// exhausted, or if the query already visited `name`, which would loop.
+pub fn follow(name: &LabelSequence) -> bool {
+    CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        let Some(budget) = current.as_mut() else {
+            return true;
+        };
+        if !budget.charge(Work::NamesFollowed, 1) {
+            return false;
+        }
+        if !budget.visited.insert(name.to_string().to_ascii_lowercase()) {
+            println!("[BUDGET] Giving up on the query: {} again.", name);
+            budget.exhausted = Some(Exhausted::Loop(name.to_string()));
+            return false;
+        }
+        true
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::super::dns::message::{Answer, Header, RCode, RecordClass, RecordType};
+    use super::super::edns::EDE_OTHER;
+    use super::super::handlers::HandleOpcode;
+    use super::super::listener::{QueryInfo, Transport};
+    use super::super::overload::Load;
+    use super::super::testing::{extended_error_in, query, query_handler};
+    use super::super::{
+        Lookup, NxdomainRedirect, NxdomainRedirectResolver, Resolve, Resolved, SearchList,
+        StaticDnsResolver, StaticRecords, Stats,
+    };
+    use super::*;
+
+    struct Nowhere;
+
+    impl Resolve for Nowhere {
+        fn lookup(&self, _header: &Header, _question: &Question) -> Resolved {
+            Lookup::NotFound.into()
+        }
+
+        fn provenance(&self, _question: &Question) -> String {
+            String::from("source=nowhere")
+        }
+
+        fn answers_locally(&self, _question: &Question) -> bool {
+            true
+        }
+    }
+
+    fn cname(name: &str, target: &str) -> Answer {
+        Answer::new(
+            /* name= */ &Arc::new(name.parse().unwrap()),
+            /* type= */ RecordType::Cname,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */ &target.parse::<LabelSequence>().unwrap().encode(),
+        )
+    }
+
+    #[test]
+    fn a_redirect_into_a_cname_loop_is_cut_short() {
+        // typo.example.com doesn't exist, so it is redirected to the landing name,
+        // whose CNAME leads back to it.
+        let mut records = StaticRecords::new();
+        records.add(cname("portal.example.com", "typo.example.com"));
+        let handler = query_handler(NxdomainRedirectResolver {
+            redirects: vec![NxdomainRedirect::parse("example.com=portal.example.com").unwrap()],
+            next: Box::new(StaticDnsResolver {
+                records: Arc::new(records),
+                next: Box::new(Nowhere),
+            }),
+            redirected: Default::default(),
+        });
+        let stats = Stats::default();
+        let request = query("typo.example.com", Some(&[]));
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        let header = Header::parse_from(request[..12].try_into().unwrap());
+        let response = handler.handle(&info, &header, &request, &stats).unwrap();
+        assert_eq!(response.get_header().get_rcode(), &RCode::ServerError);
+        assert!(response.get_answers().is_empty());
+        assert_eq!(
+            extended_error_in(&response),
+            Some((
+                EDE_OTHER,
+                String::from("work budget: loop at typo.example.com")
+            ))
+        );
+        assert_eq!(stats.over_work_budget.get(), 1);
+
+        // Outside of a query, nothing is charged.
+        assert!(follow(&"typo.example.com".parse().unwrap()));
+    }
+
+    #[test]
+    fn long_searches_run_out_of_names_to_follow() {
+        let suffixes: Vec<String> = (0..20).map(|n| format!("s{}.example", n)).collect();
+        let list = SearchList::parse(&format!("0.0.0.0/0={}", suffixes.join(","))).unwrap();
+        let question = Question::new(
+            &Arc::new("host".parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        );
+        let budget = WorkBudget::open(std::slice::from_ref(&question));
+        let resolved = list.lookup(&Nowhere, &Header::default(), &question);
+        assert!(matches!(resolved.lookup, Lookup::Failed));
+        assert_eq!(
+            budget.exhausted(),
+            Some(Exhausted::Limit(Work::NamesFollowed))
+        );
+        assert_eq!(
+            budget.spent(Work::NamesFollowed),
+            Work::NamesFollowed.hard_limit() + 1
+        );
+        // Nothing more is done for the query.
+        assert!(!spend(Work::UpstreamQueries, 1));
+        assert_eq!(budget.spent(Work::UpstreamQueries), 0);
+        drop(budget);
+        assert!(spend(Work::UpstreamQueries, 1));
+    }
+}
@@ -0,0 +1,531 @@
+// The cache (--cache): the positive answers of the resolver it wraps, the upstream,
+// kept by question (the name lowercased, the type and the class) until the smallest
+// TTL among them runs out, and served with their TTLs less the time they were kept.
+// At most --cache-size answer sets are kept; when full, the least recently used one
+// makes room. Expired entries are swept from the maintenance timer (see expiry.rs)
+// rather than holding their room until they are next asked for. Negative answers and
+// failures aren't cached, and neither are answers with a TTL of zero.
+//
+// An entry keeps its answers encoded as they follow the question they were last
+// asked with (see EncodedAnswers), so that a hit only has its TTLs written rather
+// than its names compressed again; asked in another casing, the answers are encoded
+// anew for it.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::dns::message::{Answer, EncodedAnswers, Header, LabelSequence, Question};
+use super::expiry::{ExpiryCounters, ExpiryIndex, Freshness};
+use super::signature_time::{Clock, SystemClock};
+use super::{Lookup, Resolve, Resolved};
+
+// Most expired entries dropped per maintenance tick.
+const SWEEP_BATCH: usize = 256;
+
+// A question's name lowercased, its type and its class.
+type CacheKey = (String, u16, u16);
+
+fn cache_key(question: &Question) -> CacheKey {
+    (
+        question.get_name().to_string().to_ascii_lowercase(),
+        u16::from(question.get_type()),
+        u16::from(question.get_class()),
+    )
+}
+
+struct CacheEntry {
+    answers: Vec<Answer>,
+    // The header of the upstream's response, for its RA bit.
+    header: Option<Arc<Header>>,
+    // When the answers were stored, in the clock's seconds.
+    stored_at: u64,
+    // The entry's place in the recency order.
+    used: u64,
+    // The answers encoded after the question they were last asked with, if they
+    // have been asked for since they were stored.
+    encoded: Option<Arc<EncodedAnswers>>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    // The keys by when they were last used, least recently first.
+    recency: BTreeMap<u64, CacheKey>,
+    next_use: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: &CacheKey) {
+        let used = self.next_use;
+        self.next_use += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.used);
+            entry.used = used;
+            self.recency.insert(used, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used);
+        }
+    }
+}
+
+pub struct CachingResolver {
+    pub next: Box<dyn Resolve>,
+    pub clock: Arc<dyn Clock>,
+    capacity: usize,
+    // Locked before expiry when both are.
+    state: Mutex<CacheState>,
+    expiry: Mutex<ExpiryIndex<CacheKey>>,
+    // The instant the clock's second zero stands for, in the expiry index.
+    epoch: Instant,
+}
+
+impl CachingResolver {
+    // A cache of up to `capacity` answer sets in front of `next`.
+    pub fn new(next: Box<dyn Resolve>, capacity: usize) -> CachingResolver {
+        CachingResolver {
+            next,
+            clock: Arc::new(SystemClock),
+            capacity: capacity.max(1),
+            state: Mutex::default(),
+            expiry: Mutex::new(ExpiryIndex::new(SWEEP_BATCH)),
+            epoch: Instant::now(),
+        }
+    }
+
+    // Answer sets kept.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // How entries expired, for the stats.
+    pub fn expiry_counters(&self) -> Arc<ExpiryCounters> {
+        Arc::clone(&self.expiry.lock().unwrap().counters)
+    }
+
+    fn instant(&self, seconds: u64) -> Instant {
+        self.epoch + Duration::from_secs(seconds)
+    }
+
+    // The answers kept for `question`, with their TTLs decreased by their age and the
+    // question's name as it was spelled, if they haven't expired by `now`, encoded;
+    // the age; and the upstream's header they came with.
+    fn fresh(
+        &self,
+        question: &Question,
+        now: u64,
+    ) -> Option<(Arc<EncodedAnswers>, u64, Option<Arc<Header>>)> {
+        let key = cache_key(question);
+        let mut state = self.state.lock().unwrap();
+        let stored_at = state.entries.get(&key)?.stored_at;
+        if self.expiry.lock().unwrap().check(&key, self.instant(now)) != Freshness::Fresh {
+            state.remove(&key);
+            return None;
+        }
+        state.touch(&key);
+        let age = now.saturating_sub(stored_at);
+        let entry = state.entries.get_mut(&key).expect("the entry is fresh");
+        let name = question.get_name();
+        let encoded = match &entry.encoded {
+            Some(encoded) if encoded.follows(question) => Arc::clone(encoded),
+            _ => {
+                let answers: Arc<[Answer]> = entry
+                    .answers
+                    .iter()
+                    .map(|answer| spelled_as(answer, name, answer.get_ttl()))
+                    .collect();
+                let encoded = Arc::new(EncodedAnswers::new(question, &answers));
+                entry.encoded = Some(Arc::clone(&encoded));
+                encoded
+            }
+        };
+        let answers: Arc<[Answer]> = encoded
+            .get_answers()
+            .iter()
+            .map(|answer| spelled_as(answer, name, answer.get_ttl().saturating_sub(age as u32)))
+            .collect();
+        Some((
+            Arc::new(encoded.with_ttls(&answers)),
+            age,
+            entry.header.clone(),
+        ))
+    }
+
+    fn store(&self, key: CacheKey, answers: &[Answer], header: Option<Arc<Header>>, now: u64) {
+        let ttl = answers.iter().map(Answer::get_ttl).min().unwrap_or(0);
+        if ttl == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let mut expiry = self.expiry.lock().unwrap();
+        state.remove(&key);
+        while state.entries.len() >= self.capacity {
+            let Some((_, oldest)) = state.recency.pop_first() else {
+                break;
+            };
+            println!(
+                "[CACHE] Full; dropping the least recently used {:?}.",
+                oldest
+            );
+            state.entries.remove(&oldest);
+            expiry.remove(&oldest);
+        }
+        expiry.insert(key.clone(), self.instant(now + ttl as u64));
+        let used = state.next_use;
+        state.next_use += 1;
+        state.recency.insert(used, key.clone());
+        state.entries.insert(
+            key,
+            CacheEntry {
+                answers: answers.to_vec(),
+                header,
+                stored_at: now,
+                used,
+                encoded: None,
+            },
+        );
+    }
+
+    // Whether a fresh answer to `question` is cached.
+    fn cached(&self, question: &Question) -> bool {
+        let key = cache_key(question);
+        let now = self.instant(self.clock.now().seconds());
+        let state = self.state.lock().unwrap();
+        state.entries.contains_key(&key)
+            && self.expiry.lock().unwrap().check(&key, now) == Freshness::Fresh
+    }
+}
+
+// `answer` with a TTL of `ttl`, owned by `name` if it is owned by that name in any
+// case, as the client is answered in the casing of its question (see
+// echo_qname_casing).
+fn spelled_as(answer: &Answer, name: &Arc<LabelSequence>, ttl: u32) -> Answer {
+    let owner = if answer.get_name().eq_ignore_case(name) {
+        name
+    } else {
+        answer.get_name()
+    };
+    Answer::from_rdata(
+        /* name= */ owner,
+        /* class= */ answer.get_class(),
+        /* ttl= */ ttl,
+        /* data= */ answer.get_rdata().clone(),
+    )
+}
+
+impl Resolve for CachingResolver {
+    // A cached answer isn't authoritative, whatever the upstream said of it.
+    fn lookup(&self, header: &Header, question: &Question) -> Resolved {
+        let key = cache_key(question);
+        let now = self.clock.now().seconds();
+        if let Some((encoded, age, cached)) = self.fresh(question, now) {
+            let answers = encoded.get_answers().to_vec();
+            println!(
+                "[CACHE] Answering {} with {} record(s) kept for {}s.",
+                question,
+                answers.len(),
+                age
+            );
+            return Resolved {
+                header: cached.map(|cached| {
+                    let mut header = cached.as_ref().clone();
+                    header.set_aa(false);
+                    Arc::new(header)
+                }),
+                provenance: Some(format!("source=cache age={}", age)),
+                cached: true,
+                encoded_answers: Some(encoded),
+                ..Lookup::Found(answers).into()
+            };
+        }
+        let resolved = self.next.lookup(header, question);
+        if let Lookup::Found(answers) = &resolved.lookup {
+            self.store(key, answers, resolved.header.clone(), now);
+        }
+        resolved
+    }
+
+    fn provenance(&self, question: &Question) -> String {
+        self.next.provenance(question)
+    }
+
+    fn answers_locally(&self, question: &Question) -> bool {
+        self.cached(question) || self.next.answers_locally(question)
+    }
+
+    fn prefetch(&self, header: &Header, questions: &[Question]) {
+        let missing: Vec<Question> = questions
+            .iter()
+            .filter(|question| !self.cached(question))
+            .cloned()
+            .collect();
+        self.next.prefetch(header, &missing);
+    }
+
+    fn maintain(&self) {
+        let now = self.instant(self.clock.now().seconds());
+        let expired = self.expiry.lock().unwrap().sweep(now);
+        if !expired.is_empty() {
+            let mut state = self.state.lock().unwrap();
+            for key in &expired {
+                state.remove(key);
+            }
+            println!(
+                "[CACHE] Dropped {} expired answer set(s); {} kept.",
+                expired.len(),
+                state.entries.len()
+            );
+        }
+        self.next.maintain();
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        self.next.persist()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.next.next_due()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::super::dns::message::{Appendix, RData, RecordClass, RecordType};
+    use super::super::expiry::ExpiryCounts;
+    use super::super::handlers::HandleOpcode;
+    use super::super::listener::{QueryInfo, Transport};
+    use super::super::overload::Load;
+    use super::super::slo::{SloSpec, SloTracker};
+    use super::super::stats::Stats;
+    use super::super::testing::{query, query_handler, Counter, ManualClock};
+    use super::*;
+
+    // Stands in for the upstream: an A record for every name, with a TTL of 60 and
+    // a CNAME's of 300 before it, and counts the questions.
+    struct Upstream {
+        asked: Counter,
+    }
+
+    impl Resolve for Upstream {
+        fn lookup(&self, _header: &Header, question: &Question) -> Resolved {
+            self.asked.increment();
+            Resolved {
+                provenance: Some(String::from("source=upstream")),
+                ..Lookup::Found(vec![
+                    Answer::from_rdata(
+                        /* name= */ question.get_name(),
+                        /* class= */ RecordClass::In,
+                        /* ttl= */ 300,
+                        /* data= */ RData::Cname(question.get_name().as_ref().clone()),
+                    ),
+                    Answer::a(question.get_name(), 60, Ipv4Addr::new(192, 0, 2, 1)),
+                ])
+                .into()
+            }
+        }
+
+        fn provenance(&self, _question: &Question) -> String {
+            String::from("source=upstream")
+        }
+
+        fn answers_locally(&self, _question: &Question) -> bool {
+            false
+        }
+    }
+
+    fn question(name: &str) -> Question {
+        Question::new(
+            &Arc::new(name.parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        )
+    }
+
+    fn ttls(resolved: &Resolved) -> Vec<u32> {
+        resolved
+            .clone()
+            .into_answers()
+            .iter()
+            .map(Answer::get_ttl)
+            .collect()
+    }
+
+    fn cache(capacity: usize) -> (CachingResolver, Counter, Arc<ManualClock>) {
+        let asked = Counter::default();
+        let clock = Arc::new(ManualClock::at(1_700_000_000));
+        let mut cache = CachingResolver::new(
+            Box::new(Upstream {
+                asked: asked.clone(),
+            }),
+            capacity,
+        );
+        cache.clock = Arc::clone(&clock) as Arc<dyn Clock>;
+        (cache, asked, clock)
+    }
+
+    #[test]
+    fn answers_are_kept_until_their_smallest_ttl_runs_out() {
+        let (cache, asked, clock) = cache(16);
+        let header = Header::default();
+        assert_eq!(
+            ttls(&cache.lookup(&header, &question("www.example.com"))),
+            [300, 60]
+        );
+        assert_eq!(asked.get(), 1);
+
+        // Asked again, in any case, the answers come from the cache, aged.
+        clock.advance(10);
+        let www = question("WWW.Example.com");
+        assert!(cache.answers_locally(&www));
+        let hit = cache.lookup(&header, &www);
+        assert_eq!(ttls(&hit), [290, 50]);
+        assert_eq!(hit.provenance.as_deref(), Some("source=cache age=10"));
+        assert!(hit.cached);
+        assert_eq!(asked.get(), 1);
+
+        // Once the A record's TTL runs out, the upstream is asked again.
+        clock.advance(50);
+        assert!(!cache.answers_locally(&www));
+        let miss = cache.lookup(&header, &www);
+        assert_eq!(ttls(&miss), [300, 60]);
+        assert_eq!(miss.provenance.as_deref(), Some("source=upstream"));
+        assert!(!miss.cached);
+        assert_eq!(asked.get(), 2);
+
+        // Expired entries are swept without being asked for.
+        cache.lookup(&header, &question("mail.example.com"));
+        assert_eq!(cache.len(), 2);
+        clock.advance(60);
+        cache.maintain();
+        assert_eq!(cache.len(), 0);
+
+        // The stats tell the two apart.
+        let stats = Stats {
+            cache: Some(cache.expiry_counters()),
+            ..Stats::default()
+        };
+        let counts = ExpiryCounts {
+            lazily_expired: 1,
+            swept: 2,
+        };
+        assert_eq!(stats.snapshot().cache, Some(counts));
+        assert!(stats
+            .snapshot()
+            .to_string()
+            .contains(", cache: 1 expired when asked for, 2 swept"));
+    }
+
+    #[test]
+    fn the_least_recently_used_answers_make_room() {
+        let (cache, asked, _clock) = cache(2);
+        let header = Header::default();
+        for name in ["a.example", "b.example", "a.example", "c.example"] {
+            cache.lookup(&header, &question(name));
+        }
+        assert_eq!(asked.get(), 3);
+        assert_eq!(cache.len(), 2);
+
+        // b was used least recently, so it went to make room for c.
+        cache.lookup(&header, &question("a.example"));
+        cache.lookup(&header, &question("c.example"));
+        assert_eq!(asked.get(), 3);
+        cache.lookup(&header, &question("b.example"));
+        assert_eq!(asked.get(), 4);
+    }
+
+    #[test]
+    fn cache_hits_count_towards_their_own_slos() {
+        let (cache, asked, _clock) = cache(16);
+        let handler = query_handler(cache);
+        let specs: Vec<SloSpec> = ["cache-hit:p99<1s", "forwarded:p99<1s"]
+            .iter()
+            .map(|spec| SloSpec::parse(spec).unwrap())
+            .collect();
+        let stats = Stats {
+            slos: SloTracker::new(&specs, Duration::from_secs(60), Instant::now()),
+            ..Stats::default()
+        };
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        let request = query("www.example.com", None);
+        let header = Header::parse_from(request[..12].try_into().unwrap());
+        for _ in 0..3 {
+            handler.handle(&info, &header, &request, &stats).unwrap();
+        }
+        assert_eq!(asked.get(), 1);
+
+        // The first query went upstream; the others were answered from the cache.
+        let samples: Vec<u64> = stats
+            .slos
+            .statuses(Instant::now())
+            .iter()
+            .map(|status| status.samples)
+            .collect();
+        assert_eq!(samples, [2, 1]);
+    }
+
+    #[test]
+    fn hits_are_encoded_as_the_answers_would_be() {
+        let (cache, asked, clock) = cache(16);
+        let handler = query_handler(cache);
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        let padding = Appendix {
+            padding: Some((128, 1232)),
+            ..Appendix::default()
+        };
+        let answer = |name: &str, options: Option<&[u16]>| {
+            let request = query(name, options);
+            let header = Header::parse_from(request[..12].try_into().unwrap());
+            handler
+                .handle(&info, &header, &request, &Stats::default())
+                .unwrap()
+        };
+        let miss = answer("www.example.com", None);
+        assert!(!miss.uses_encoded_answers());
+        assert_eq!(asked.get(), 1);
+
+        // Hits come with their answers encoded, in the casing of the question, with
+        // or without EDNS, as their TTLs run down.
+        for (name, options) in [
+            ("www.example.com", None),
+            ("WWW.Example.COM", Some(&[10][..])),
+            ("WWW.Example.COM", None),
+            ("www.example.com", Some(&[12][..])),
+        ] {
+            clock.advance(7);
+            let hit = answer(name, options);
+            assert!(hit.uses_encoded_answers());
+            assert_eq!(hit.get_answers()[0].get_name().to_string(), name);
+            let slow = hit.with_encoded_answers(None);
+            assert!(!slow.uses_encoded_answers());
+            assert_eq!(hit.encode(), slow.encode());
+            assert_eq!(hit.encode_with(&padding), slow.encode_with(&padding));
+        }
+        assert_eq!(asked.get(), 1);
+        let hit = answer("www.example.com", None);
+        let ttls: Vec<u32> = hit.get_answers().iter().map(Answer::get_ttl).collect();
+        assert_eq!(ttls, [272, 32]);
+    }
+}
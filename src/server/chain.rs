@@ -0,0 +1,299 @@
+// CNAME chain order in answer sections. A response's answers can come from several
+// places at once (local records and the CNAMEs among them, an upstream, a search
+// list candidate, an NXDOMAIN redirect, a policy zone rewrite), and stub resolvers,
+// like our own read-back check (verify.rs), need every CNAME to come before the
+// records of its target (RFC 1034, 3.6.2). So before a response is encoded, its
+// answer RRsets are put in chain order, starting from the question's name: the
+// RRsets owned by the name, its CNAME first, then those owned by the CNAME's
+// target, and so on. A record found twice (e.g. from two sources) is kept once, a
+// chain that loops back on itself ends where it would repeat, and RRsets that
+// aren't on the chain, which there shouldn't be, go after it and are counted.
+
+use std::{collections::HashSet, sync::Arc};
+
+use super::dns::message::{Answer, LabelSequence, Message, RData, RecordType};
+use super::stats::Stats;
+
+const RRSIG: RecordType = RecordType::Unknown(46);
+
+// Answers in chain order.
+#[derive(Debug)]
+pub struct Chained {
+    pub answers: Vec<Answer>,
+    // Records dropped as already there.
+    pub duplicates: usize,
+    // RRsets placed after the chain, as not on it.
+    pub off_chain: usize,
+}
+
+// The type of the RRset a record belongs to: an RRSIG's is the type it covers.
+fn rrset_type(record: &Answer) -> RecordType {
+    let data = record.get_data();
+    if record.get_type() == RRSIG && data.len() >= 2 {
+        RecordType::from(u16::from_be_bytes([data[0], data[1]]))
+    } else {
+        record.get_type()
+    }
+}
+
+fn owner_key(name: &LabelSequence) -> String {
+    name.to_string().to_ascii_lowercase()
+}
+
+fn cname_target(record: &Answer) -> Option<LabelSequence> {
+    match record.get_rdata() {
+        RData::Cname(target) if record.get_type() == RecordType::Cname => Some(target.clone()),
+        _ => None,
+    }
+}
+
+// Puts `answers` in chain order from `name`. Each owner's RRsets stay together, its
+// CNAME first and the others in the order they came in.
+pub fn order(name: &LabelSequence, answers: &[Answer]) -> Chained {
+    let mut seen: HashSet<(String, RecordType, Arc<[u8]>)> = HashSet::new();
+    let records: Vec<&Answer> = answers
+        .iter()
+        .filter(|record| {
+            seen.insert((
+                owner_key(record.get_name()),
+                record.get_type(),
+                record.get_data(),
+            ))
+        })
+        .collect();
+    let duplicates = answers.len() - records.len();
+
+    let mut placed = vec![false; records.len()];
+    let mut ordered: Vec<Answer> = Vec::with_capacity(records.len());
+    let mut visited: HashSet<String> = HashSet::new();
+    let mut owner = name.clone();
+    while visited.insert(owner_key(&owner)) {
+        let owned: Vec<usize> = (0..records.len())
+            .filter(|&index| !placed[index] && records[index].get_name().eq_ignore_case(&owner))
+            .collect();
+        // By RRset, CNAME first, then as they came in.
+        let first_of = |r#type: RecordType| {
+            owned
+                .iter()
+                .position(|&index| rrset_type(records[index]) == r#type)
+                .unwrap_or(0)
+        };
+        let ranks: Vec<(bool, usize)> = owned
+            .iter()
+            .map(|&index| {
+                let r#type = rrset_type(records[index]);
+                (r#type != RecordType::Cname, first_of(r#type))
+            })
+            .collect();
+        let mut ranked: Vec<(usize, (bool, usize))> = owned.into_iter().zip(ranks).collect();
+        ranked.sort_by_key(|(_, rank)| *rank);
+        let mut next = None;
+        for (index, _) in ranked {
+            placed[index] = true;
+            next = next.or_else(|| cname_target(records[index]));
+            ordered.push(records[index].clone());
+        }
+        match next {
+            Some(target) => owner = target,
+            None => break,
+        }
+    }
+
+    let mut off_chain: Vec<(String, RecordType)> = Vec::new();
+    for (index, record) in records.iter().enumerate() {
+        if placed[index] {
+            continue;
+        }
+        let rrset = (owner_key(record.get_name()), rrset_type(record));
+        if !off_chain.contains(&rrset) {
+            off_chain.push(rrset);
+        }
+        ordered.push((*record).clone());
+    }
+    Chained {
+        answers: ordered,
+        duplicates,
+        off_chain: off_chain.len(),
+    }
+}
+
+// The response with its answers in chain order from its question's name; RRsets not
+// on the chain are counted in `stats`.
+pub fn in_chain_order(response: &Message, stats: &Stats) -> Message {
+    let Some(question) = response.get_questions().first() else {
+        return response.clone();
+    };
+    let answers = response.get_answers();
+    if answers.len() < 2 {
+        return response.clone();
+    }
+    let chained = order(question.get_name(), answers);
+    if chained.duplicates > 0 {
+        println!(
+            "[CHAIN] Dropped {} duplicate record(s) from the answers to {}.",
+            chained.duplicates, question
+        );
+    }
+    if chained.off_chain > 0 {
+        stats.off_chain_rrsets.add(chained.off_chain as u64);
+        println!(
+            "[CHAIN] {} RRset(s) in the answers to {} aren't on its CNAME chain; placed after it.",
+            chained.off_chain, question
+        );
+    }
+    let unchanged = chained.answers.len() == answers.len()
+        && chained
+            .answers
+            .iter()
+            .zip(answers.iter())
+            .all(|(a, b)| a.encode() == b.encode());
+    if unchanged {
+        return response.clone();
+    }
+    response.with_answers(&chained.answers.into())
+}
+
+// How `answers` break chain order from `name`: a record that comes before the CNAME
+// to its owner. Records owned by `name` itself can't be out of order, so a CNAME
+// loop back to it isn't one.
+pub fn violations(name: &LabelSequence, answers: &[Answer]) -> Vec<String> {
+    let mut violations = Vec::new();
+    for (index, record) in answers.iter().enumerate() {
+        if record.get_name().eq_ignore_case(name) {
+            continue;
+        }
+        let later = answers[index + 1..].iter().position(|cname| {
+            cname_target(cname).is_some_and(|target| target.eq_ignore_case(record.get_name()))
+        });
+        if let Some(offset) = later {
+            violations.push(format!(
+                "answer #{} ({}) comes before the CNAME to it, #{}",
+                index,
+                record.get_name(),
+                index + 1 + offset
+            ));
+        }
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::super::dns::message::{Header, Question, RecordClass};
+    use super::super::random::Randomness;
+    use super::*;
+
+    fn name(name: &str) -> LabelSequence {
+        name.parse().unwrap()
+    }
+
+    fn cname(owner: &str, target: &str) -> Answer {
+        Answer::from_rdata(
+            /* name= */ &Arc::new(name(owner)),
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */ RData::Cname(name(target)),
+        )
+    }
+
+    fn a(owner: &str, last: u8) -> Answer {
+        Answer::a(&Arc::new(name(owner)), 300, Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    fn shown(answers: &[Answer]) -> Vec<String> {
+        answers.iter().map(|answer| answer.to_string()).collect()
+    }
+
+    // As shown, lowercased, with the addresses at the end of the chain (the last
+    // RRset, which keeps the order its records came in) sorted.
+    fn normalized(answers: &[Answer]) -> Vec<String> {
+        let mut lines: Vec<String> = shown(answers)
+            .iter()
+            .map(|line| line.to_ascii_lowercase())
+            .collect();
+        lines[2..].sort();
+        lines
+    }
+
+    // Shuffles `records` with the stream `seed` draws from.
+    fn shuffled(records: &[Answer], seed: u64) -> Vec<Answer> {
+        let rng = Randomness::seeded(seed).stream("chain");
+        let mut records = records.to_vec();
+        for index in (1..records.len()).rev() {
+            records.swap(index, (rng.next_u64() % (index as u64 + 1)) as usize);
+        }
+        records
+    }
+
+    #[test]
+    fn chains_from_several_sources_come_out_in_order_whatever_the_order_in() {
+        // A local CNAME to a name whose own chain and addresses came from upstream,
+        // with one record from both.
+        let chain = [
+            cname("www.example.com", "cdn.example.net"),
+            cname("cdn.example.net", "edge.cdn.example.net"),
+            a("edge.cdn.example.net", 1),
+            a("edge.cdn.example.net", 2),
+        ];
+        let mut sources = chain.to_vec();
+        sources.push(cname("CDN.example.net", "edge.cdn.example.net"));
+        for seed in 0..20 {
+            let chained = order(&name("www.example.com"), &shuffled(&sources, seed));
+            assert_eq!(chained.duplicates, 1);
+            assert_eq!(chained.off_chain, 0);
+            assert_eq!(
+                normalized(&chained.answers),
+                normalized(&chain),
+                "seed {}",
+                seed
+            );
+            assert!(violations(&name("www.example.com"), &chained.answers).is_empty());
+        }
+        assert_eq!(
+            violations(
+                &name("www.example.com"),
+                &[chain[2].clone(), chain[1].clone(), chain[0].clone()]
+            ),
+            [
+                "answer #0 (edge.cdn.example.net) comes before the CNAME to it, #1",
+                "answer #1 (cdn.example.net) comes before the CNAME to it, #2",
+            ]
+        );
+    }
+
+    #[test]
+    fn loops_end_the_chain_and_strays_go_after_it() {
+        let answers = [
+            a("stray.example.org", 9),
+            cname("b.example.com", "a.example.com"),
+            cname("a.example.com", "b.example.com"),
+        ];
+        let chained = order(&name("a.example.com"), &answers);
+        assert_eq!(
+            shown(&chained.answers),
+            shown(&[answers[2].clone(), answers[1].clone(), answers[0].clone()])
+        );
+        assert_eq!(chained.off_chain, 1);
+        assert!(violations(&name("a.example.com"), &chained.answers).is_empty());
+
+        // Counted when a response is put in order, which keeps its header in step.
+        let question = Question::new(
+            &Arc::new(name("a.example.com")),
+            RecordType::A,
+            RecordClass::In,
+        );
+        let mut header = Header::default();
+        header.set_qr(true).set_qd_count(1);
+        let mut doubled = answers.to_vec();
+        doubled.push(answers[1].clone());
+        let response = Message::new(&Arc::new(header), &Arc::from([question]), &Arc::from([]))
+            .with_answers(&doubled.into());
+        let stats = Stats::default();
+        let ordered = in_chain_order(&response, &stats);
+        assert_eq!(shown(ordered.get_answers()), shown(&chained.answers));
+        assert_eq!(ordered.get_header().get_an_count(), 3);
+        assert_eq!(stats.off_chain_rrsets.get(), 1);
+    }
+}
@@ -0,0 +1,322 @@
+// A blocking DNS client, for other crates (see lib.rs) and the query subcommand: one
+// question at a time to one upstream, sent with the forwarder's Query type, checked
+// as the forwarder checks responses (Query::matches_response, skipping up to
+// MAX_STRAY_RESPONSES that don't answer it), over UDP first and over TCP (as the
+// split-brain check asks) when the UDP response comes back truncated. A response is
+// handed back as a Resolution unless its RCODE says the upstream failed, which is a
+// ClientError; NXDOMAIN is an answer like NOERROR.
+
+use std::{
+    error::Error,
+    fmt, io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+
+use super::dns::message::{
+    Answer, LabelSequence, LabelSequenceParseError, Message, RCode, RData, RecordType,
+};
+use super::edns::UDP_PAYLOAD_SIZE;
+use super::query::Query;
+use super::random::{Randomness, Rng};
+use super::split_brain::exchange_over_tcp;
+use super::{Resolution, MAX_STRAY_RESPONSES};
+
+// Most CNAMEs lookup_ip follows from a name.
+const MAX_CNAME_HOPS: usize = 8;
+
+/// Where a [`Client`] sends its queries, and how.
+#[derive(Clone, Debug)]
+pub struct UpstreamSpec {
+    address: SocketAddr,
+    timeout: Duration,
+    edns_size: Option<u16>,
+    dnssec_ok: bool,
+    tcp_fallback: bool,
+}
+
+impl UpstreamSpec {
+    /// The upstream at `address`, asked with EDNS (a 1232-byte payload size) but
+    /// without DO, given 2 seconds per exchange, and asked again over TCP when its
+    /// UDP response is truncated.
+    pub fn new(address: SocketAddr) -> UpstreamSpec {
+        UpstreamSpec {
+            address,
+            timeout: Duration::from_secs(2),
+            edns_size: Some(UDP_PAYLOAD_SIZE),
+            dnssec_ok: false,
+            tcp_fallback: true,
+        }
+    }
+
+    pub fn get_address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// How long each exchange may take, over UDP and over TCP alike.
+    pub fn set_timeout(&mut self, timeout: Duration) -> &'_ mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The UDP payload size to advertise with EDNS; None sends no OPT record.
+    pub fn set_edns_size(&mut self, size: Option<u16>) -> &'_ mut Self {
+        self.edns_size = size;
+        self
+    }
+
+    /// Whether to ask for DNSSEC records (DO), which needs EDNS.
+    pub fn set_dnssec_ok(&mut self, dnssec_ok: bool) -> &'_ mut Self {
+        self.dnssec_ok = dnssec_ok;
+        self
+    }
+
+    /// Whether to ask again over TCP when the UDP response is truncated, rather than
+    /// fail with [`ClientError::Truncated`].
+    pub fn set_tcp_fallback(&mut self, tcp_fallback: bool) -> &'_ mut Self {
+        self.tcp_fallback = tcp_fallback;
+        self
+    }
+}
+
+/// Why a [`Client`] got no answer.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The name asked for isn't a domain name.
+    InvalidName(String),
+    /// The upstream didn't answer within the timeout.
+    Timeout,
+    /// Sending the query or receiving the response failed.
+    Io(io::Error),
+    /// No response that answers the query came, e.g. only malformed ones.
+    NoResponse(String),
+    /// The UDP response was truncated, and TCP fallback is off.
+    Truncated,
+    /// The upstream couldn't answer (SERVFAIL).
+    ServerFailure,
+    /// The upstream wouldn't answer (REFUSED).
+    Refused,
+    /// The upstream answered with another error RCODE, e.g. FORMERR.
+    Rcode(RCode),
+    /// The name doesn't exist (NXDOMAIN), for [`Client::lookup_ip`].
+    NoSuchName(String),
+    /// The name's CNAME chain loops or is too long, for [`Client::lookup_ip`].
+    CnameChain(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidName(message) => write!(f, "invalid name: {}", message),
+            Self::Timeout => write!(f, "the upstream didn't answer in time"),
+            Self::Io(err) => write!(f, "{}", err),
+            Self::NoResponse(reason) => write!(f, "no response answered the query: {}", reason),
+            Self::Truncated => write!(f, "the response was truncated, and TCP is off"),
+            Self::ServerFailure => write!(f, "the upstream failed to answer (SERVFAIL)"),
+            Self::Refused => write!(f, "the upstream refused to answer (REFUSED)"),
+            Self::Rcode(rcode) => write!(f, "the upstream answered {}", rcode),
+            Self::NoSuchName(name) => write!(f, "{} doesn't exist (NXDOMAIN)", name),
+            Self::CnameChain(name) => write!(
+                f,
+                "{}'s CNAME chain loops or has over {} links",
+                name, MAX_CNAME_HOPS
+            ),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> ClientError {
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ClientError::Timeout,
+            _ => ClientError::Io(err),
+        }
+    }
+}
+
+/// Asks one upstream, a question at a time, blocking until it answers.
+///
+/// ```no_run
+/// use codecrafters_dns_server::server::{Client, RecordType, UpstreamSpec};
+///
+/// let mut upstream = UpstreamSpec::new("9.9.9.9:53".parse().unwrap());
+/// upstream.set_dnssec_ok(true);
+/// let client = Client::new(upstream);
+/// for answer in client.query("example.com", RecordType::Mx)?.answers.iter() {
+///     println!("{}", answer);
+/// }
+/// println!("{:?}", client.lookup_ip("www.example.com")?);
+/// # Ok::<(), codecrafters_dns_server::server::ClientError>(())
+/// ```
+pub struct Client {
+    upstream: UpstreamSpec,
+    // Where the query IDs come from.
+    rng: Arc<dyn Rng>,
+}
+
+impl Client {
+    pub fn new(upstream: UpstreamSpec) -> Client {
+        Client {
+            upstream,
+            rng: Randomness::from_clock().stream("client"),
+        }
+    }
+
+    /// Asks for the records of `type` owned by `name`, with recursion desired. The
+    /// answers are those of the response, CNAMEs included, and its RCODE is NOERROR
+    /// or NXDOMAIN; any other is an error.
+    pub fn query(&self, name: &str, r#type: RecordType) -> Result<Resolution, ClientError> {
+        self.ask(&parse_name(name)?, r#type)
+    }
+
+    /// The IPv4 and then the IPv6 addresses of `name`, following its CNAMEs, asking
+    /// for the name at the end of the chain if the upstream didn't answer for it.
+    /// Empty if the name has none.
+    pub fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>, ClientError> {
+        let name = parse_name(name)?;
+        let mut addresses = Vec::new();
+        for r#type in [RecordType::A, RecordType::Aaaa] {
+            let mut owner = Arc::clone(&name);
+            let mut hops = 0;
+            loop {
+                let resolution = self.ask(&owner, r#type)?;
+                if resolution.rcode == RCode::NameError {
+                    return Err(ClientError::NoSuchName(owner.to_string()));
+                }
+                let (end, found) = follow(&owner, &resolution.answers, &mut hops)?;
+                if found.is_empty() && !end.eq_ignore_case(&owner) {
+                    owner = Arc::new(end);
+                    continue;
+                }
+                addresses.extend(found);
+                break;
+            }
+        }
+        Ok(addresses)
+    }
+
+    fn ask(
+        &self,
+        name: &Arc<LabelSequence>,
+        r#type: RecordType,
+    ) -> Result<Resolution, ClientError> {
+        let mut query = Query::new(name, r#type);
+        query.set_rd(true);
+        if let Some(size) = self.upstream.edns_size {
+            query.set_edns_size(size);
+        }
+        if self.upstream.dnssec_ok {
+            query.set_do_bit(true);
+        }
+        let (mut response, mut rcode) = self.over_udp(&query)?;
+        if response.get_header().get_tc() {
+            if !self.upstream.tcp_fallback {
+                return Err(ClientError::Truncated);
+            }
+            let id = self.rng.next_u64() as u16;
+            response = exchange_over_tcp(
+                self.upstream.address,
+                &query.encode(id),
+                self.upstream.timeout,
+            )?;
+            rcode = query
+                .matches_response(id, &response)
+                .map_err(|mismatch| ClientError::NoResponse(mismatch.to_string()))?;
+        }
+        let header = response.get_header();
+        match rcode {
+            RCode::NoError | RCode::NameError => Ok(Resolution {
+                rcode,
+                answers: Arc::clone(response.get_answers()),
+                aa: header.get_aa(),
+                ra: header.get_ra(),
+                tc: header.get_tc(),
+                ad: header.get_ad(),
+                authorities: Arc::clone(response.get_authorities()),
+                policy_outcome: None,
+                provenance: Vec::new(),
+                cached: false,
+                encoded_answers: None,
+            }),
+            RCode::ServerError => Err(ClientError::ServerFailure),
+            RCode::Refused => Err(ClientError::Refused),
+            rcode => Err(ClientError::Rcode(rcode)),
+        }
+    }
+
+    // Sends `query` over UDP, and waits for the response that answers it.
+    fn over_udp(&self, query: &Query) -> Result<(Message, RCode), ClientError> {
+        let local = match self.upstream.address {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(self.upstream.address)?;
+        socket.set_read_timeout(Some(self.upstream.timeout))?;
+        let id = self.rng.next_u64() as u16;
+        socket.send(&query.encode(id))?;
+        let mut buf = [0; 65535];
+        let mut skipped = String::from("none came");
+        for _ in 0..=MAX_STRAY_RESPONSES {
+            let size = socket.recv(&mut buf)?;
+            let response = match Message::parse_from(&buf[..size]) {
+                Ok(response) => response,
+                Err(err) => {
+                    skipped = format!("malformed: {}", err);
+                    continue;
+                }
+            };
+            match query.matches_response(id, &response) {
+                Ok(rcode) => return Ok((response, rcode)),
+                Err(mismatch) => skipped = mismatch.to_string(),
+            }
+        }
+        Err(ClientError::NoResponse(skipped))
+    }
+}
+
+fn parse_name(name: &str) -> Result<Arc<LabelSequence>, ClientError> {
+    name.parse()
+        .map(Arc::new)
+        .map_err(|err: LabelSequenceParseError| ClientError::InvalidName(err.message))
+}
+
+// Follows the CNAMEs in `answers` from `name`, counting them in `hops`, and returns
+// the name at the end of the chain and the addresses it has among them.
+fn follow(
+    name: &LabelSequence,
+    answers: &[Answer],
+    hops: &mut usize,
+) -> Result<(LabelSequence, Vec<IpAddr>), ClientError> {
+    let mut owner = name.clone();
+    while let Some(target) = answers.iter().find_map(|answer| match answer.get_rdata() {
+        RData::Cname(target) if answer.get_name().eq_ignore_case(&owner) => Some(target.clone()),
+        _ => None,
+    }) {
+        *hops += 1;
+        if *hops > MAX_CNAME_HOPS {
+            return Err(ClientError::CnameChain(name.to_string()));
+        }
+        owner = target;
+    }
+    let addresses = answers
+        .iter()
+        .filter(|answer| answer.get_name().eq_ignore_case(&owner))
+        .filter_map(|answer| match answer.get_rdata() {
+            RData::A(address) => Some(IpAddr::V4(*address)),
+            RData::Aaaa(address) => Some(IpAddr::V6(*address)),
+            _ => None,
+        })
+        .collect();
+    Ok((owner, addresses))
+}
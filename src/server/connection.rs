@@ -0,0 +1,177 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    os::fd::{AsRawFd, RawFd},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use super::dso::DsoSession;
+
+// How long a connection without a DSO session may stay idle (RFC 7766, 6.2.3).
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Longest a client that doesn't read its responses may stall the thread answering it.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// A DNS over TCP connection, carrying messages with a two-byte length prefix.
+pub struct Connection {
+    // Index of the listener that accepted it.
+    pub listener: usize,
+    pub peer: SocketAddr,
+    pub dso: DsoSession,
+    // Set on EOF, errors and aborts; the server loop then drops the connection.
+    pub closed: bool,
+    // Messages received but not admitted yet, over the connection's quota (see quota.rs).
+    pub queue: VecDeque<Vec<u8>>,
+    stream: TcpStream,
+    writer: Arc<StreamWriter>,
+    // Bytes received that don't make up a whole message yet.
+    buffer: Vec<u8>,
+    last_activity: Instant,
+}
+
+impl Connection {
+    // The stream is blocking, but only read after poll reported it readable, so
+    // reads return what has arrived without waiting.
+    pub fn new(listener: usize, stream: TcpStream, peer: SocketAddr) -> io::Result<Connection> {
+        stream.set_nonblocking(false)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let writer = Arc::new(StreamWriter {
+            peer,
+            stream: Mutex::new(stream.try_clone()?),
+            inflight: AtomicUsize::new(0),
+            failed: AtomicBool::new(false),
+        });
+        Ok(Connection {
+            listener,
+            peer,
+            dso: DsoSession::default(),
+            closed: false,
+            queue: VecDeque::new(),
+            stream,
+            writer,
+            buffer: Vec::new(),
+            last_activity: Instant::now(),
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+
+    // Reads nothing more from the peer, at shutdown; responses can still be sent.
+    pub fn stop_reading(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Read);
+        self.buffer.clear();
+    }
+
+    // Reads what has arrived and returns the messages completed by it.
+    pub fn receive(&mut self) -> Vec<Vec<u8>> {
+        let mut buf = [0; 4096];
+        match self.stream.read(&mut buf) {
+            Ok(0) => self.closed = true,
+            Ok(size) => {
+                self.buffer.extend_from_slice(&buf[..size]);
+                self.last_activity = Instant::now();
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                eprintln!("Error receiving data from {}: {}", self.peer, e);
+                self.closed = true;
+            }
+        }
+
+        let mut messages: Vec<Vec<u8>> = Vec::new();
+        while self.buffer.len() >= 2 {
+            let length = u16::from_be_bytes([self.buffer[0], self.buffer[1]]) as usize;
+            if self.buffer.len() < 2 + length {
+                break;
+            }
+            messages.push(self.buffer[2..2 + length].to_vec());
+            self.buffer.drain(..2 + length);
+        }
+        messages
+    }
+
+    pub fn send(&mut self, message: &[u8]) {
+        if !self.writer.send(message) {
+            self.closed = true;
+        }
+    }
+
+    // A query of the connection's handed to a worker to answer.
+    pub fn pending(&self) -> Pending {
+        self.writer.inflight.fetch_add(1, Ordering::Relaxed);
+        Pending(Arc::clone(&self.writer))
+    }
+
+    // Queries handed to the workers and not answered yet.
+    pub fn inflight(&self) -> usize {
+        self.writer.inflight.load(Ordering::Relaxed)
+    }
+
+    // Whether sending a worker's response failed, for the server loop to close the
+    // connection.
+    pub fn send_failed(&self) -> bool {
+        self.writer.failed.load(Ordering::Relaxed)
+    }
+
+    // Whether the connection has been idle for longer than the inactivity timeout
+    // of its DSO session, or than IDLE_TIMEOUT without one.
+    pub fn is_idle(&self, now: Instant) -> bool {
+        let timeout = if self.dso.established {
+            self.dso.inactivity_timeout
+        } else {
+            IDLE_TIMEOUT
+        };
+        now.saturating_duration_since(self.last_activity) > timeout
+    }
+}
+
+// The sending side of a connection, shared with the workers answering its queries;
+// one response is written at a time, whole.
+struct StreamWriter {
+    peer: SocketAddr,
+    stream: Mutex<TcpStream>,
+    // Queries handed to the workers and not answered yet.
+    inflight: AtomicUsize,
+    // Set when a send fails.
+    failed: AtomicBool,
+}
+
+impl StreamWriter {
+    // Sends `message` with its length prefix; returns whether it was sent.
+    fn send(&self, message: &[u8]) -> bool {
+        let mut data: Vec<u8> = (message.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(message);
+        if let Err(e) = self.stream.lock().unwrap().write_all(&data) {
+            eprintln!("Error sending data to {}: {}", self.peer, e);
+            self.failed.store(true, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+}
+
+// A query received on a connection and handed to a worker, which sends the response
+// with it. It counts against the connection's quota until dropped, however its
+// handling ends. Responses may go out in another order than their queries came in,
+// which clients must expect (RFC 7766, 7).
+pub struct Pending(Arc<StreamWriter>);
+
+impl Pending {
+    pub fn send(&self, message: &[u8]) {
+        self.0.send(message);
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.inflight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
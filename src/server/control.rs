@@ -0,0 +1,341 @@
+// The control socket (--control-socket): a Unix stream socket on which an operator
+// sends one command per connection, as a line of text, and reads back its output,
+// e.g. with `echo pins | socat - UNIX-CONNECT:/run/dns.sock`. Commands:
+//
+//     pin NAME TYPE TTL VALUE...   serve NAME TYPE as VALUE... until unpinned
+//     unpin NAME TYPE
+//     pins                         list the pinned RRsets
+//     clients                      list the clients with queries in flight or waiting
+//     rpz                          list the response policy zones and their hits
+//     schedules                    list the scheduled rules, whether they apply now
+//                                  and until when
+//     nta add NAME [LIFETIME]      stop validating NAME and below for LIFETIME
+//                                  (e.g. 30m; an hour if left out)
+//     nta remove NAME
+//     ntas                         list the negative trust anchors and their uses
+//     divergence                   list how often each upstream answered differently
+//                                  over UDP and TCP (see split_brain.rs)
+//     ttlreport                    list the sampled names whose TTLs over-claim and
+//                                  under-claim most (see ttl_honesty.rs)
+//     txn EDIT [; EDIT]...         edit the local records in one transaction, all or
+//                                  none (see transaction.rs); an EDIT is one of
+//                                    add NAME TYPE TTL VALUE...
+//                                    remove NAME TYPE VALUE...
+//                                    delete NAME TYPE
+//                                    replace NAME TYPE TTL VALUE...
+//     journal                      list the diffs of the latest transactions
+//     transfers                    list the secondary zones, their serials and next
+//                                  refreshes, the transfers in flight and queued, and
+//                                  the NOTIFYs not yet answered (see secondary.rs)
+//     support-bundle DIR           write the server's state into DIR, for a bug
+//                                  report (see support.rs)
+//     stats                        print the server's counters
+//     truncation                   list how truncated responses fared on each listener,
+//                                  and the advice on them (see truncation.rs)
+//     badpackets [json]            dump the latest requests that didn't parse, marking
+//                                  where (see bad_packets.rs), or list them as JSON
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::nta::{parse_lifetime, NegativeTrustAnchors, DEFAULT_NTA_LIFETIME};
+use super::pins::PinStore;
+use super::quota::ClientQuotas;
+use super::records::StaticRecords;
+use super::rpz::ResponsePolicy;
+use super::schedule::Schedule;
+use super::secondary::SecondaryZones;
+use super::signature_time::SystemClock;
+use super::split_brain::SplitBrainCheck;
+use super::stats::Stats;
+use super::support::{Json, SupportBundle, ToJson};
+use super::ttl_honesty::TtlHonesty;
+
+// How long a connected client has to send its command; the server loop waits on it.
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct ControlSocket {
+    pub path: PathBuf,
+    listener: UnixListener,
+    pub pins: Arc<PinStore>,
+    // The server's in-flight quotas, for the per-client view; set by the caller.
+    pub quotas: Option<Arc<ClientQuotas>>,
+    // The response policy zones, if any; set by the caller.
+    pub rpz: Option<Arc<ResponsePolicy>>,
+    // The scheduled rules other than policy zones, by what they schedule; set by the
+    // caller.
+    pub schedules: Vec<(String, Schedule)>,
+    // The negative trust anchors, if forwarding; set by the caller.
+    pub ntas: Option<Arc<NegativeTrustAnchors>>,
+    // The UDP/TCP consistency check, if on; set by the caller.
+    pub split_brain: Option<Arc<SplitBrainCheck>>,
+    // The TTL honesty sample, if on; set by the caller.
+    pub ttl_honesty: Option<Arc<TtlHonesty>>,
+    // The local records, for transactions; set by the caller.
+    pub records: Option<Arc<StaticRecords>>,
+    // The secondary zones, if any; set by the caller.
+    pub secondary: Option<Arc<SecondaryZones>>,
+    // The effective settings, for support bundles; set by the caller.
+    pub config: Json,
+}
+
+impl ControlSocket {
+    // Binds the socket at `path`, replacing a stale socket left by an earlier run.
+    pub fn bind(path: PathBuf, pins: Arc<PinStore>) -> io::Result<ControlSocket> {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok(ControlSocket {
+            path,
+            listener,
+            pins,
+            quotas: None,
+            rpz: None,
+            schedules: Vec::new(),
+            ntas: None,
+            split_brain: None,
+            ttl_honesty: None,
+            records: None,
+            secondary: None,
+            config: Json::Null,
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    // Serves the connections waiting on the socket, one command each; `stats` are the
+    // server's counters, for the stats command.
+    pub fn serve(&self, stats: &Stats) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.serve_connection(stream, stats) {
+                        eprintln!("[CONTROL] Error serving a command: {}", e);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("[CONTROL] Error accepting a connection: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn serve_connection(&self, stream: UnixStream, stats: &Stats) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(COMMAND_TIMEOUT))?;
+        let mut command = String::new();
+        BufReader::new(&stream).read_line(&mut command)?;
+        let output = match command.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            ["stats"] => format!("{}\n", stats.snapshot()),
+            ["truncation"] => self.truncation_report(stats),
+            ["badpackets"] => stats
+                .bad_packets
+                .report()
+                .iter()
+                .map(|line| format!("{}\n", line))
+                .collect(),
+            ["badpackets", "json"] => format!("{}\n", stats.bad_packets.to_json()),
+            ["support-bundle", dir] => match self.support_bundle(stats).write(Path::new(dir)) {
+                Ok(count) => format!("wrote {} files to {}\n", count, dir),
+                Err(e) => format!("error: Failed to write to {}: {}\n", dir, e),
+            },
+            _ => self.execute(command.trim()),
+        };
+        println!("[CONTROL] {}: {}", command.trim(), output.trim_end());
+        (&stream).write_all(output.as_bytes())
+    }
+
+    // How truncated responses fared on each listener, with the advice on them.
+    fn truncation_report(&self, stats: &Stats) -> String {
+        let mut report = String::new();
+        for (tag, counts) in stats.truncation.counts(Instant::now()) {
+            report += &format!("[{}] {}\n", tag, counts);
+            for advice in counts.advice(stats.truncation.max_udp_size) {
+                report += &format!("[{}] advice: {}\n", tag, advice);
+            }
+        }
+        report
+    }
+
+    // The scheduled rules, with whether they apply and until when.
+    fn schedule_report(&self) -> Vec<String> {
+        self.rpz
+            .iter()
+            .flat_map(|rpz| rpz.schedules())
+            .chain(self.schedules.iter().map(|(rule, schedule)| {
+                format!(
+                    "{} \"{}\": {}",
+                    rule,
+                    schedule,
+                    schedule.status(&SystemClock)
+                )
+            }))
+            .collect()
+    }
+
+    // Everything the socket can see, with `stats`, for a bug report.
+    pub fn support_bundle(&self, stats: &Stats) -> SupportBundle {
+        let mut bundle = SupportBundle::new(&self.config);
+        bundle
+            .add("stats.json", stats.snapshot().to_json())
+            .add(
+                "zones.json",
+                Json::object([(
+                    "rpz",
+                    self.rpz
+                        .as_ref()
+                        .map_or(Json::Array(Vec::new()), |rpz| rpz.to_json()),
+                )]),
+            )
+            .add(
+                "overrides.json",
+                Json::object([
+                    ("pins", Json::strings(self.pins.list())),
+                    (
+                        "negative_trust_anchors",
+                        Json::strings(self.ntas.iter().flat_map(|ntas| ntas.list())),
+                    ),
+                    ("schedules", Json::strings(self.schedule_report())),
+                    (
+                        "clients",
+                        Json::strings(self.quotas.iter().flat_map(|quotas| quotas.report())),
+                    ),
+                ]),
+            )
+            .add(
+                "ttl.json",
+                Json::object([(
+                    "ttl_honesty",
+                    Json::strings(self.ttl_honesty.iter().flat_map(|honesty| honesty.report())),
+                )]),
+            );
+        bundle
+    }
+
+    // The negative trust anchors, for the commands that change them.
+    fn negative_trust_anchors(&self) -> Result<&NegativeTrustAnchors, String> {
+        self.ntas.as_deref().ok_or_else(|| {
+            String::from("Negative trust anchors only apply when forwarding (--resolver).")
+        })
+    }
+
+    fn records(&self) -> Result<&StaticRecords, String> {
+        self.records
+            .as_deref()
+            .ok_or_else(|| String::from("There are no local records to edit."))
+    }
+
+    // Commits the edits of a txn command, separated by semicolons, as one
+    // transaction, and returns its diff.
+    fn transaction(&self, edits: &str) -> Result<String, String> {
+        let records = self.records()?;
+        let mut transaction = records.begin();
+        for edit in edits.split(';') {
+            let words: Vec<&str> = edit.split_whitespace().collect();
+            if !words.is_empty() {
+                transaction.edit(&words)?;
+            }
+        }
+        transaction
+            .commit()
+            .map(|diff| diff.lines().join("\n"))
+            .map_err(|err| format!("Nothing was changed: {}.", err))
+    }
+
+    // Runs a command and returns its output, ending with a newline.
+    pub fn execute(&self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let result = match words.as_slice() {
+            ["pin", name, r#type, ttl, values @ ..] => self
+                .pins
+                .pin(name, r#type, ttl, values)
+                .map(|()| String::from("ok")),
+            ["unpin", name, r#type] => self.pins.unpin(name, r#type).map(|()| String::from("ok")),
+            ["pins"] => Ok(self.pins.list().join("\n")),
+            ["clients"] => Ok(self
+                .quotas
+                .as_ref()
+                .map(|quotas| quotas.report().join("\n"))
+                .unwrap_or_default()),
+            ["rpz"] => Ok(self
+                .rpz
+                .as_ref()
+                .map(|rpz| rpz.report().join("\n"))
+                .unwrap_or_default()),
+            ["schedules"] => Ok(self.schedule_report().join("\n")),
+            ["nta", "add", name, lifetime @ ..] if lifetime.len() <= 1 => {
+                let lifetime = match lifetime {
+                    [lifetime] => parse_lifetime(lifetime),
+                    _ => Ok(DEFAULT_NTA_LIFETIME),
+                };
+                self.negative_trust_anchors()
+                    .and_then(|ntas| ntas.add(name, lifetime?).map(|()| String::from("ok")))
+            }
+            ["nta", "remove", name] => self
+                .negative_trust_anchors()
+                .and_then(|ntas| ntas.remove(name).map(|()| String::from("ok"))),
+            ["ntas"] => Ok(self
+                .ntas
+                .as_ref()
+                .map(|ntas| ntas.list().join("\n"))
+                .unwrap_or_default()),
+            ["divergence"] => Ok(self
+                .split_brain
+                .as_ref()
+                .map(|check| check.report().join("\n"))
+                .unwrap_or_default()),
+            ["ttlreport"] => self
+                .ttl_honesty
+                .as_ref()
+                .map(|honesty| honesty.report().join("\n"))
+                .ok_or_else(|| String::from("TTLs are only compared with --ttl-honesty-sample.")),
+            ["txn", ..] => self.transaction(command.trim_start()["txn".len()..].trim()),
+            ["journal"] => self.records().map(|records| {
+                records
+                    .journal()
+                    .iter()
+                    .flat_map(|diff| diff.lines())
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            }),
+            ["transfers"] => self
+                .secondary
+                .as_ref()
+                .map(|zones| zones.report(Instant::now()).join("\n"))
+                .ok_or_else(|| String::from("There are no secondary zones (--secondary).")),
+            _ => Err(String::from(
+                "Usage: pin NAME TYPE TTL VALUE... | unpin NAME TYPE | pins | clients | rpz \
+                 | schedules | nta add NAME [LIFETIME] | nta remove NAME | ntas | divergence \
+                 | ttlreport | txn EDIT [; EDIT]... | journal | transfers | stats \
+                 | truncation | badpackets [json] | support-bundle DIR",
+            )),
+        };
+        match result {
+            Ok(output) if output.is_empty() => output,
+            Ok(output) => output + "\n",
+            Err(err) => format!("error: {}\n", err),
+        }
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
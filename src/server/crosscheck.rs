@@ -0,0 +1,445 @@
+// Cross-checking of upstream answers (--verify-answers), as a defense in depth
+// against cache poisoning over UDP: for a sample of the questions forwarded, the
+// question is asked a second time, over another socket and with another ID (and,
+// if configured, of another upstream), and the two answers are compared. Answers
+// that differ in more than their TTLs and order raise an alert.
+//
+// Normally the client is served the first answer right away and the second query
+// goes out later, from maintenance, so that agreeing answers cost no latency; a
+// mismatch can then only be reported. In strict mode both answers are in before
+// the client's is sent, and a mismatch is settled by the MismatchPolicy.
+
+use std::{
+    collections::VecDeque,
+    fmt, io,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use super::dns::message::{Answer, Header, Question};
+use super::stats::ShardedCounter;
+use super::verify::{diff_rrsets, normalized_rrsets};
+use super::{Lookup, Resolve, Resolved};
+
+// What a strict cross-check serves when the answers disagree.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MismatchPolicy {
+    // The records both answers have.
+    Intersection,
+    // The answer of the upstream that has failed less often.
+    PreferReputable,
+    // Nothing: the query gets SERVFAIL.
+    ServFail,
+}
+
+impl MismatchPolicy {
+    pub fn parse(value: &str) -> Result<MismatchPolicy, String> {
+        match value {
+            "intersection" => Ok(MismatchPolicy::Intersection),
+            "prefer-reputable" => Ok(MismatchPolicy::PreferReputable),
+            "servfail" => Ok(MismatchPolicy::ServFail),
+            _ => Err(format!(
+                "Unknown mismatch policy '{}'; expected intersection, prefer-reputable or servfail.",
+                value
+            )),
+        }
+    }
+}
+
+// Parses a sample size given as "25%" or "25".
+pub fn parse_percent(value: &str) -> Result<u32, String> {
+    value
+        .trim()
+        .trim_end_matches('%')
+        .parse::<u32>()
+        .ok()
+        .filter(|&percent| percent <= 100)
+        .ok_or_else(|| format!("'{}' is not a percentage from 0% to 100%.", value))
+}
+
+#[derive(Default)]
+pub struct CrossCheckStats {
+    pub checked: ShardedCounter,
+    pub mismatches: ShardedCounter,
+}
+
+impl fmt::Display for CrossCheckStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "answers cross-checked: {}, mismatches: {}",
+            self.checked.get(),
+            self.mismatches.get()
+        )
+    }
+}
+
+fn describe(lookup: &Lookup) -> String {
+    match lookup {
+        Lookup::Found(records) => records
+            .iter()
+            .map(Answer::to_string)
+            .collect::<Vec<String>>()
+            .join(", "),
+        Lookup::FoundNoData => String::from("no data"),
+        Lookup::NotFound => String::from("no such name"),
+        Lookup::Failed => String::from("failure"),
+    }
+}
+
+// A sampled question answered from the primary, waiting for its second opinion.
+struct PendingCheck {
+    header: Header,
+    question: Question,
+    first: Lookup,
+}
+
+pub struct CrossCheckingResolver {
+    pub primary: Box<dyn Resolve>,
+    // Asks over a socket of its own, of the same upstream or another one.
+    pub secondary: Box<dyn Resolve>,
+    pub sample_percent: u32,
+    pub strict: bool,
+    pub policy: MismatchPolicy,
+    pub stats: CrossCheckStats,
+    forwarded: AtomicU64,
+    pending: Mutex<VecDeque<PendingCheck>>,
+    // Per upstream, primary first: answers minus failures.
+    reputation: [AtomicI64; 2],
+}
+
+impl CrossCheckingResolver {
+    pub fn new(
+        primary: Box<dyn Resolve>,
+        secondary: Box<dyn Resolve>,
+        sample_percent: u32,
+        strict: bool,
+        policy: MismatchPolicy,
+    ) -> CrossCheckingResolver {
+        CrossCheckingResolver {
+            primary,
+            secondary,
+            sample_percent: sample_percent.min(100),
+            strict,
+            policy,
+            stats: CrossCheckStats::default(),
+            forwarded: AtomicU64::new(0),
+            pending: Mutex::new(VecDeque::new()),
+            reputation: Default::default(),
+        }
+    }
+
+    // Whether to check the next question: exactly `sample_percent` of every hundred,
+    // spread evenly.
+    fn sampled(&self) -> bool {
+        let count = self.forwarded.fetch_add(1, Ordering::Relaxed) + 1;
+        let percent = self.sample_percent as u64;
+        count * percent / 100 != (count - 1) * percent / 100
+    }
+
+    fn rate(&self, upstream: usize, lookup: &Lookup) {
+        let change = match lookup {
+            Lookup::Failed => -1,
+            _ => 1,
+        };
+        self.reputation[upstream].fetch_add(change, Ordering::Relaxed);
+    }
+
+    fn ask_secondary(&self, header: &Header, question: &Question) -> Lookup {
+        // Another ID, so that a spoofer who guessed the first query's doesn't match.
+        let mut second_header = header.clone();
+        second_header.set_id(header.get_id().wrapping_add(0x8000));
+        let second = self.secondary.lookup(&second_header, question).lookup;
+        self.rate(1, &second);
+        second
+    }
+
+    // Describes how the answers differ; an upstream failing isn't a mismatch.
+    fn differences(first: &Lookup, second: &Lookup) -> Vec<String> {
+        match (first, second) {
+            (Lookup::Failed, _) | (_, Lookup::Failed) => Vec::new(),
+            (Lookup::Found(first), Lookup::Found(second)) => diff_rrsets(first, second),
+            (Lookup::FoundNoData, Lookup::FoundNoData) | (Lookup::NotFound, Lookup::NotFound) => {
+                Vec::new()
+            }
+            _ => vec![format!(
+                "answered {}, then {}",
+                describe(first),
+                describe(second)
+            )],
+        }
+    }
+
+    // Compares the answers, counting and logging a mismatch; returns whether there was one.
+    fn compare(&self, question: &Question, first: &Lookup, second: &Lookup) -> bool {
+        self.stats.checked.increment();
+        let differences = Self::differences(first, second);
+        if differences.is_empty() {
+            return false;
+        }
+        self.stats.mismatches.increment();
+        println!(
+            "[CROSSCHECK] ALERT: the upstream answers to {} disagree ({}):\n  first: {}\n  second: {}\n  {}",
+            question,
+            self.stats,
+            describe(first),
+            describe(second),
+            differences.join("\n  ")
+        );
+        true
+    }
+
+    fn settle(&self, first: Lookup, second: Lookup) -> Lookup {
+        match self.policy {
+            MismatchPolicy::ServFail => Lookup::Failed,
+            MismatchPolicy::PreferReputable
+                if self.reputation[1].load(Ordering::Relaxed)
+                    > self.reputation[0].load(Ordering::Relaxed) =>
+            {
+                second
+            }
+            MismatchPolicy::PreferReputable => first,
+            MismatchPolicy::Intersection => {
+                let (Lookup::Found(first), Lookup::Found(second)) = (&first, &second) else {
+                    return Lookup::Failed;
+                };
+                let second: Vec<Arc<[u8]>> = normalized_rrsets(second)
+                    .iter()
+                    .map(Answer::encode)
+                    .collect();
+                let common: Vec<Answer> = first
+                    .iter()
+                    .filter(|record| {
+                        second
+                            .contains(&normalized_rrsets(std::slice::from_ref(record))[0].encode())
+                    })
+                    .cloned()
+                    .collect();
+                if common.is_empty() {
+                    Lookup::Failed
+                } else {
+                    Lookup::Found(common)
+                }
+            }
+        }
+    }
+}
+
+impl Resolve for CrossCheckingResolver {
+    fn lookup(&self, header: &Header, question: &Question) -> Resolved {
+        let resolved = self.primary.lookup(header, question);
+        if !self.sampled() {
+            return resolved;
+        }
+        self.rate(0, &resolved.lookup);
+        if !self.strict {
+            self.pending.lock().unwrap().push_back(PendingCheck {
+                header: header.clone(),
+                question: question.clone(),
+                first: resolved.lookup.clone(),
+            });
+            return resolved;
+        }
+        let second = self.ask_secondary(header, question);
+        if !self.compare(question, &resolved.lookup, &second) {
+            return resolved;
+        }
+        let settled = self.settle(resolved.lookup.clone(), second);
+        println!(
+            "[CROSSCHECK] Serving {} for {} ({:?}).",
+            describe(&settled),
+            question,
+            self.policy
+        );
+        Resolved {
+            lookup: settled,
+            encoded_answers: None,
+            ..resolved
+        }
+    }
+
+    fn provenance(&self, question: &Question) -> String {
+        self.primary.provenance(question)
+    }
+
+    fn answers_locally(&self, question: &Question) -> bool {
+        self.primary.answers_locally(question)
+    }
+
+    // Second opinions are asked one by one, as the lookups are sampled.
+    fn prefetch(&self, header: &Header, questions: &[Question]) {
+        self.primary.prefetch(header, questions);
+    }
+
+    // Gets the second opinions on the answers already served.
+    fn maintain(&self) {
+        loop {
+            let next = self.pending.lock().unwrap().pop_front();
+            let Some(check) = next else {
+                break;
+            };
+            let second = self.ask_secondary(&check.header, &check.question);
+            if self.compare(&check.question, &check.first, &second) {
+                println!(
+                    "[CROSSCHECK] The first answer to {} was already served.",
+                    check.question
+                );
+            }
+        }
+        self.primary.maintain();
+        self.secondary.maintain();
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        self.primary.persist()?;
+        self.secondary.persist()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        if !self.pending.lock().unwrap().is_empty() {
+            return Some(Instant::now());
+        }
+        [self.primary.next_due(), self.secondary.next_due()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::super::dns::message::{RecordClass, RecordType};
+    use super::super::testing::{forwarder, mock_upstream};
+    use super::*;
+
+    fn upstream(addresses: &[[u8; 4]], ttl: u32, delay: Duration) -> Box<dyn Resolve> {
+        let socket = mock_upstream(addresses, ttl, delay);
+        let resolver = forwarder(socket.local_addr().unwrap(), 10.0);
+        // The mock runs as long as its socket is open.
+        std::mem::forget(socket);
+        Box::new(resolver)
+    }
+
+    fn resolver(
+        primary: &[[u8; 4]],
+        secondary: &[[u8; 4]],
+        strict: bool,
+        policy: MismatchPolicy,
+    ) -> CrossCheckingResolver {
+        CrossCheckingResolver::new(
+            upstream(primary, 60, Duration::ZERO),
+            upstream(secondary, 300, Duration::ZERO),
+            100,
+            strict,
+            policy,
+        )
+    }
+
+    fn lookup(resolver: &CrossCheckingResolver, name: &str) -> Lookup {
+        let question = Question::new(
+            &Arc::new(name.parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        );
+        resolver.lookup(&Header::default(), &question).lookup
+    }
+
+    fn addresses(lookup: &Lookup) -> Vec<Vec<u8>> {
+        match lookup {
+            Lookup::Found(records) => records
+                .iter()
+                .map(|record| record.get_data().to_vec())
+                .collect(),
+            _ => panic!("expected records, got {}", describe(lookup)),
+        }
+    }
+
+    const A: [u8; 4] = [192, 0, 2, 1];
+    const B: [u8; 4] = [192, 0, 2, 2];
+    const C: [u8; 4] = [192, 0, 2, 3];
+
+    #[test]
+    fn answers_differing_in_ttl_and_order_agree() {
+        let resolver = resolver(&[A, B], &[B, A], true, MismatchPolicy::ServFail);
+        assert_eq!(addresses(&lookup(&resolver, "www.example.com")), [A, B]);
+        assert_eq!(resolver.stats.checked.get(), 1);
+        assert_eq!(resolver.stats.mismatches.get(), 0);
+    }
+
+    #[test]
+    fn strict_mismatches_are_settled_by_the_policy() {
+        let servfail = resolver(&[A, B], &[B, C], true, MismatchPolicy::ServFail);
+        assert!(matches!(
+            lookup(&servfail, "www.example.com"),
+            Lookup::Failed
+        ));
+        assert_eq!(servfail.stats.mismatches.get(), 1);
+
+        let intersection = resolver(&[A, B], &[B, C], true, MismatchPolicy::Intersection);
+        assert_eq!(addresses(&lookup(&intersection, "www.example.com")), [B]);
+        let disjoint = resolver(&[A], &[C], true, MismatchPolicy::Intersection);
+        assert!(matches!(
+            lookup(&disjoint, "www.example.com"),
+            Lookup::Failed
+        ));
+
+        // Reputation is answers minus failures; ties go to the primary.
+        let reputable = resolver(&[A], &[C], true, MismatchPolicy::PreferReputable);
+        assert_eq!(addresses(&lookup(&reputable, "www.example.com")), [A]);
+        // The mocks fail names starting with "down"; a failure isn't a mismatch.
+        for _ in 0..3 {
+            assert!(matches!(
+                lookup(&reputable, "down.example.com"),
+                Lookup::Failed
+            ));
+        }
+        assert_eq!(reputable.stats.mismatches.get(), 1);
+        assert_eq!(reputable.reputation[0].load(Ordering::Relaxed), -2);
+        reputable.reputation[0].store(-5, Ordering::Relaxed);
+        assert_eq!(addresses(&lookup(&reputable, "www.example.com")), [C]);
+        reputable.reputation[1].store(-10, Ordering::Relaxed);
+        assert_eq!(addresses(&lookup(&reputable, "www.example.com")), [A]);
+        assert_eq!(reputable.stats.mismatches.get(), 3);
+    }
+
+    #[test]
+    fn checks_run_after_answering_unless_strict() {
+        let slow = Duration::from_millis(300);
+        let resolver = CrossCheckingResolver::new(
+            upstream(&[A], 60, Duration::ZERO),
+            upstream(&[C], 60, slow),
+            100,
+            false,
+            MismatchPolicy::ServFail,
+        );
+        let asked = Instant::now();
+        assert_eq!(addresses(&lookup(&resolver, "www.example.com")), [A]);
+        assert!(asked.elapsed() < slow);
+        assert_eq!(resolver.stats.checked.get(), 0);
+        assert!(resolver.next_due().is_some_and(|due| due <= Instant::now()));
+
+        resolver.maintain();
+        assert_eq!(resolver.stats.checked.get(), 1);
+        assert_eq!(resolver.stats.mismatches.get(), 1);
+        assert!(resolver.next_due().is_none());
+    }
+
+    #[test]
+    fn only_the_sample_is_checked() {
+        let mut resolver = resolver(&[A], &[A], true, MismatchPolicy::ServFail);
+        resolver.sample_percent = 25;
+        for _ in 0..16 {
+            lookup(&resolver, "www.example.com");
+        }
+        assert_eq!(resolver.stats.checked.get(), 4);
+
+        assert_eq!(parse_percent("5%"), Ok(5));
+        assert_eq!(parse_percent("100"), Ok(100));
+        assert!(parse_percent("101%").is_err());
+    }
+}
@@ -1,5 +1,13 @@
 pub mod message {
-    use std::{fmt, rc::Rc, str, u8};
+    use std::{
+        collections::HashMap,
+        fmt,
+        net::{Ipv4Addr, Ipv6Addr},
+        str,
+        sync::Arc,
+    };
+
+    use super::super::legacy::LegacyRdata;
 
     #[derive(Clone, Debug, Default, PartialEq)]
     pub enum OpCode {
@@ -79,7 +87,23 @@ pub mod message {
         NameError,
         NotImplemented,
         Refused,
+        DsoTypeNotImplemented,
         Unassigned(u8),
+        // Extended RCODEs (RFC 6891, 6.1.3): the upper 8 of their 12 bits are carried
+        // in the OPT record, only the lower 4 in the header.
+        // BADVERS, and BADSIG for TSIG (RFC 8945), which shares its value.
+        BadVersion,
+        BadKey,
+        BadTime,
+        BadCookie,
+        Extended(u16),
+    }
+
+    impl RCode {
+        // Whether the value needs the extended RCODE mechanism.
+        pub fn is_extended(&self) -> bool {
+            u16::from(self) > 0x0F
+        }
     }
 
     #[derive(Debug)]
@@ -87,7 +111,7 @@ pub mod message {
         pub message: String,
     }
 
-    impl From<&RCode> for u8 {
+    impl From<&RCode> for u16 {
         fn from(value: &RCode) -> Self {
             match value {
                 RCode::NoError => 0,
@@ -96,7 +120,13 @@ pub mod message {
                 RCode::NameError => 3,
                 RCode::NotImplemented => 4,
                 RCode::Refused => 5,
-                RCode::Unassigned(x) => *x,
+                RCode::DsoTypeNotImplemented => 11,
+                RCode::Unassigned(x) => *x as u16,
+                RCode::BadVersion => 16,
+                RCode::BadKey => 17,
+                RCode::BadTime => 18,
+                RCode::BadCookie => 23,
+                RCode::Extended(x) => *x,
             }
         }
     }
@@ -112,6 +142,7 @@ pub mod message {
                 3 => Ok(Self::NameError),
                 4 => Ok(Self::NotImplemented),
                 5 => Ok(Self::Refused),
+                11 => Ok(Self::DsoTypeNotImplemented),
                 6..=15 => Ok(Self::Unassigned(value)),
                 16..=u8::MAX => Err(RCodeParseError {
                     message: format!(
@@ -123,6 +154,28 @@ pub mod message {
         }
     }
 
+    // A whole 12-bit extended RCODE, as assembled from the header and the OPT record.
+    impl TryFrom<u16> for RCode {
+        type Error = RCodeParseError;
+
+        fn try_from(value: u16) -> Result<Self, Self::Error> {
+            match value {
+                0..=15 => RCode::try_from(value as u8),
+                16 => Ok(Self::BadVersion),
+                17 => Ok(Self::BadKey),
+                18 => Ok(Self::BadTime),
+                23 => Ok(Self::BadCookie),
+                19..=0x0FFF => Ok(Self::Extended(value)),
+                _ => Err(RCodeParseError {
+                    message: format!(
+                        "Extended RCode must be from 0 to 4095 (12 bits), but the value is {}.",
+                        value
+                    ),
+                }),
+            }
+        }
+    }
+
     impl fmt::Display for RCode {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             let code: &str = match self {
@@ -132,23 +185,271 @@ pub mod message {
                 Self::NameError => "NAME_ERROR (3)",
                 Self::NotImplemented => "NOT_IMPLEMENTED (4)",
                 Self::Refused => "REFUSED (5)",
+                Self::DsoTypeNotImplemented => "DSO_TYPE_NOT_IMPLEMENTED (11)",
                 Self::Unassigned(value) => &format!("UNASSIGNED ({})", value),
+                Self::BadVersion => "BADVERS (16)",
+                Self::BadKey => "BADKEY (17)",
+                Self::BadTime => "BADTIME (18)",
+                Self::BadCookie => "BADCOOKIE (23)",
+                Self::Extended(value) => &format!("EXTENDED ({})", value),
             };
             write!(f, "{}", code)
         }
     }
 
-    #[derive(Debug, Default)]
+    // The TYPE of a question or record. Types without a variant of their own are kept
+    // as their value, so that they go back on the wire as they came.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub enum RecordType {
+        A,     // 1
+        Ns,    // 2
+        Cname, // 5
+        Soa,   // 6
+        Ptr,   // 12
+        Mx,    // 15
+        Txt,   // 16
+        Aaaa,  // 28
+        Srv,   // 33
+        Opt,   // 41
+        Svcb,  // 64
+        Https, // 65
+        Any,   // 255
+        Caa,   // 257
+        // Any other value; never one of the above.
+        Unknown(u16),
+    }
+
+    // The types with a variant of their own.
+    const RECORD_TYPES: [(RecordType, u16); 14] = [
+        (RecordType::A, 1),
+        (RecordType::Ns, 2),
+        (RecordType::Cname, 5),
+        (RecordType::Soa, 6),
+        (RecordType::Ptr, 12),
+        (RecordType::Mx, 15),
+        (RecordType::Txt, 16),
+        (RecordType::Aaaa, 28),
+        (RecordType::Srv, 33),
+        (RecordType::Opt, 41),
+        (RecordType::Svcb, 64),
+        (RecordType::Https, 65),
+        (RecordType::Any, 255),
+        (RecordType::Caa, 257),
+    ];
+
+    // Type mnemonics, as printed and as accepted wherever a type is configured.
+    const RECORD_TYPE_MNEMONICS: [(&str, u16); 22] = [
+        ("A", 1),
+        ("NS", 2),
+        ("MD", 3),
+        ("MF", 4),
+        ("CNAME", 5),
+        ("SOA", 6),
+        ("MB", 7),
+        ("MG", 8),
+        ("MR", 9),
+        ("NULL", 10),
+        ("WKS", 11),
+        ("PTR", 12),
+        ("MX", 15),
+        ("TXT", 16),
+        ("AAAA", 28),
+        ("SRV", 33),
+        ("OPT", 41),
+        ("RRSIG", 46),
+        ("SVCB", 64),
+        ("HTTPS", 65),
+        ("ANY", 255),
+        ("CAA", 257),
+    ];
+
+    impl From<u16> for RecordType {
+        fn from(value: u16) -> Self {
+            RECORD_TYPES
+                .iter()
+                .find(|(_, code)| *code == value)
+                .map_or(RecordType::Unknown(value), |(r#type, _)| *r#type)
+        }
+    }
+
+    impl From<RecordType> for u16 {
+        fn from(value: RecordType) -> Self {
+            match value {
+                RecordType::Unknown(code) => code,
+                _ => RECORD_TYPES
+                    .iter()
+                    .find(|(r#type, _)| *r#type == value)
+                    .map(|(_, code)| *code)
+                    .expect("every named type is in the table"),
+            }
+        }
+    }
+
+    // Types sort by value, as they do on the wire (e.g. in NSEC type bitmaps).
+    impl PartialOrd for RecordType {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for RecordType {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            u16::from(*self).cmp(&u16::from(*other))
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct RecordTypeParseError {
+        pub message: String,
+    }
+
+    // A mnemonic ("AAAA") or the generic RFC 3597 form ("TYPE28"), in any case.
+    impl str::FromStr for RecordType {
+        type Err = RecordTypeParseError;
+
+        fn from_str(value: &str) -> Result<Self, Self::Err> {
+            let upper = value.trim().to_ascii_uppercase();
+            RECORD_TYPE_MNEMONICS
+                .iter()
+                .find(|(mnemonic, _)| *mnemonic == upper)
+                .map(|(_, code)| *code)
+                .or_else(|| {
+                    upper
+                        .strip_prefix("TYPE")
+                        .and_then(|number| number.parse::<u16>().ok())
+                })
+                .map(RecordType::from)
+                .ok_or_else(|| RecordTypeParseError {
+                    message: format!("Unknown record type '{}'.", value),
+                })
+        }
+    }
+
+    // The mnemonic if there is one, the RFC 3597 form otherwise.
+    impl fmt::Display for RecordType {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let code = u16::from(*self);
+            match RECORD_TYPE_MNEMONICS
+                .iter()
+                .find(|(_, known)| *known == code)
+            {
+                Some((mnemonic, _)) => write!(f, "{}", mnemonic),
+                None => write!(f, "TYPE{}", code),
+            }
+        }
+    }
+
+    // The CLASS of a question or record. As with types, classes without a variant
+    // are kept as their value.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub enum RecordClass {
+        In,   // 1
+        Ch,   // 3
+        Hs,   // 4
+        None, // 254
+        Any,  // 255
+        // Any other value; never one of the above.
+        Unknown(u16),
+    }
+
+    // The classes with a variant of their own, and their mnemonics as dig prints them.
+    const RECORD_CLASSES: [(RecordClass, u16, &str); 5] = [
+        (RecordClass::In, 1, "IN"),
+        (RecordClass::Ch, 3, "CH"),
+        (RecordClass::Hs, 4, "HS"),
+        (RecordClass::None, 254, "NONE"),
+        (RecordClass::Any, 255, "ANY"),
+    ];
+
+    impl From<u16> for RecordClass {
+        fn from(value: u16) -> Self {
+            RECORD_CLASSES
+                .iter()
+                .find(|(_, code, _)| *code == value)
+                .map_or(RecordClass::Unknown(value), |(class, _, _)| *class)
+        }
+    }
+
+    impl From<RecordClass> for u16 {
+        fn from(value: RecordClass) -> Self {
+            match value {
+                RecordClass::Unknown(code) => code,
+                _ => RECORD_CLASSES
+                    .iter()
+                    .find(|(class, _, _)| *class == value)
+                    .map(|(_, code, _)| *code)
+                    .expect("every named class is in the table"),
+            }
+        }
+    }
+
+    // Classes sort by value, like types.
+    impl PartialOrd for RecordClass {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for RecordClass {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            u16::from(*self).cmp(&u16::from(*other))
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct RecordClassParseError {
+        pub message: String,
+    }
+
+    // A mnemonic ("IN", or "CHAOS" for CH) or the generic RFC 3597 form ("CLASS1"),
+    // in any case.
+    impl str::FromStr for RecordClass {
+        type Err = RecordClassParseError;
+
+        fn from_str(value: &str) -> Result<Self, Self::Err> {
+            let upper = value.trim().to_ascii_uppercase();
+            let upper = if upper == "CHAOS" {
+                String::from("CH")
+            } else {
+                upper
+            };
+            RECORD_CLASSES
+                .iter()
+                .find(|(_, _, mnemonic)| *mnemonic == upper)
+                .map(|(class, _, _)| *class)
+                .or_else(|| {
+                    upper
+                        .strip_prefix("CLASS")
+                        .and_then(|number| number.parse::<u16>().ok())
+                        .map(RecordClass::from)
+                })
+                .ok_or_else(|| RecordClassParseError {
+                    message: format!("Unknown record class '{}'.", value),
+                })
+        }
+    }
+
+    // The mnemonic if there is one, the RFC 3597 form otherwise.
+    impl fmt::Display for RecordClass {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match RECORD_CLASSES.iter().find(|(class, _, _)| class == self) {
+                Some((_, _, mnemonic)) => write!(f, "{}", mnemonic),
+                None => write!(f, "CLASS{}", u16::from(*self)),
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Default)]
     pub struct Header {
         id: u16,
         qr: bool,
-        opcode: Rc<OpCode>,
+        opcode: OpCode,
         aa: bool,
         tc: bool,
         rd: bool,
         ra: bool,
         z: u8,
-        rcode: Rc<RCode>,
+        rcode: RCode,
         qd_count: u16,
         an_count: u16,
         ns_count: u16,
@@ -182,12 +483,35 @@ pub mod message {
 
         // Operation Code (OPCODE)
         // 4 bits
-        pub fn get_opcode(&'_ self) -> &'_ Rc<OpCode> {
+        pub fn get_opcode(&'_ self) -> &'_ OpCode {
             &self.opcode
         }
 
-        pub fn set_opcode(&mut self, opcode: &Rc<OpCode>) -> &'_ mut Self {
-            self.opcode = Rc::clone(opcode);
+        pub fn set_opcode(&mut self, opcode: OpCode) -> &'_ mut Self {
+            self.opcode = opcode;
+            self
+        }
+
+        // Authoritative Answer (AA)
+        // 1 bit
+        pub fn get_aa(&self) -> bool {
+            self.aa
+        }
+
+        pub fn set_aa(&mut self, aa: bool) -> &'_ mut Self {
+            self.aa = aa;
+            self
+        }
+
+        // Truncation (TC)
+        // 1 bit
+        // 1 if the message was truncated to fit the transport's size limit.
+        pub fn get_tc(&self) -> bool {
+            self.tc
+        }
+
+        pub fn set_tc(&mut self, tc: bool) -> &'_ mut Self {
+            self.tc = tc;
             self
         }
 
@@ -202,12 +526,47 @@ pub mod message {
             self
         }
 
-        pub fn get_rcode(&'_ self) -> &'_ Rc<RCode> {
+        // Authentic Data (AD)
+        // 1 bit, the middle one of Z (RFC 4035, 3.2.3)
+        // 1 if the answer was validated; in a query, if the client wants to know.
+        pub fn get_ad(&self) -> bool {
+            self.z & 0b010 != 0
+        }
+
+        pub fn set_ad(&mut self, ad: bool) -> &'_ mut Self {
+            self.z = (self.z & !0b010) | (u8::from(ad) << 1);
+            self
+        }
+
+        // Recursion Available (RA)
+        // 1 bit
+        pub fn get_ra(&self) -> bool {
+            self.ra
+        }
+
+        pub fn set_ra(&mut self, ra: bool) -> &'_ mut Self {
+            self.ra = ra;
+            self
+        }
+
+        // Checking Disabled (CD)
+        // 1 bit, the lowest of Z (RFC 4035, 3.2.2)
+        // 1 if a validating resolver is to answer without validating.
+        pub fn get_cd(&self) -> bool {
+            self.z & 0b001 != 0
+        }
+
+        pub fn set_cd(&mut self, cd: bool) -> &'_ mut Self {
+            self.z = (self.z & !0b001) | u8::from(cd);
+            self
+        }
+
+        pub fn get_rcode(&'_ self) -> &'_ RCode {
             &self.rcode
         }
 
-        pub fn set_rcode(&mut self, rcode: &Rc<RCode>) -> &'_ mut Self {
-            self.rcode = Rc::clone(rcode);
+        pub fn set_rcode(&mut self, rcode: RCode) -> &'_ mut Self {
+            self.rcode = rcode;
             self
         }
 
@@ -233,16 +592,39 @@ pub mod message {
             self
         }
 
+        // Authority Record Count (NSCOUNT)
+        // Number of records in the Authority section.
+        pub fn get_ns_count(&self) -> u16 {
+            self.ns_count
+        }
+
+        pub fn set_ns_count(&mut self, ns_count: u16) -> &'_ mut Self {
+            self.ns_count = ns_count;
+            self
+        }
+
+        // Additional Record Count (ARCOUNT)
+        // Number of records in the Additional section.
+        pub fn get_ar_count(&self) -> u16 {
+            self.ar_count
+        }
+
+        pub fn set_ar_count(&mut self, ar_count: u16) -> &'_ mut Self {
+            self.ar_count = ar_count;
+            self
+        }
+
         pub fn encode(&self) -> [u8; 12] {
             let id: [u8; 2] = self.id.to_be_bytes();
             let qr: u8 = if self.qr { 0x80 } else { 0 };
-            let opcode = u8::from(self.opcode.as_ref()) << 3;
+            let opcode = u8::from(&self.opcode) << 3;
             let aa: u8 = if self.aa { 0x04 } else { 0 };
             let tc: u8 = if self.tc { 0x02 } else { 0 };
             let rd: u8 = if self.rd { 0x01 } else { 0 };
-            let ra: u8 = if self.ra { 0x01 } else { 0 };
+            let ra: u8 = if self.ra { 0x80 } else { 0 };
             let z: u8 = self.z << 4;
-            let rcode: u8 = u8::from(self.rcode.as_ref());
+            // Only the lower 4 bits of an extended RCODE go in the header.
+            let rcode: u8 = (u16::from(&self.rcode) & 0x0F) as u8;
             let qd_count: [u8; 2] = self.qd_count.to_be_bytes();
             let an_count: [u8; 2] = self.an_count.to_be_bytes();
             let ns_count: [u8; 2] = self.ns_count.to_be_bytes();
@@ -269,21 +651,17 @@ pub mod message {
             Header {
                 id: u16::from_be_bytes([data[0], data[1]]),
                 qr: qr_opcode_aa_tc_rd & 0x80 == 0x80,
-                opcode: Rc::new(
-                    ((qr_opcode_aa_tc_rd & 0x78) >> 3)
-                        .try_into()
-                        .expect("Could not parse opcode."),
-                ),
+                opcode: ((qr_opcode_aa_tc_rd & 0x78) >> 3)
+                    .try_into()
+                    .expect("Could not parse opcode."),
                 aa: qr_opcode_aa_tc_rd & 0x04 == 0x04,
                 tc: qr_opcode_aa_tc_rd & 0x02 == 0x02,
                 rd: qr_opcode_aa_tc_rd & 0x01 == 0x01,
                 ra: ra_z_rcode & 0x80 == 0x80,
-                z: ra_z_rcode & 0x70 >> 4,
-                rcode: Rc::new(
-                    (ra_z_rcode & 0x0F)
-                        .try_into()
-                        .expect("Could not parse rcode."),
-                ),
+                z: (ra_z_rcode & 0x70) >> 4,
+                rcode: (ra_z_rcode & 0x0F)
+                    .try_into()
+                    .expect("Could not parse rcode."),
                 qd_count: u16::from_be_bytes([data[4], data[5]]),
                 an_count: u16::from_be_bytes([data[6], data[7]]),
                 ns_count: u16::from_be_bytes([data[8], data[9]]),
@@ -337,21 +715,21 @@ pub mod message {
 
     #[derive(Clone, Debug)]
     pub struct Label {
-        content: Rc<str>,
+        content: Arc<str>,
     }
 
     impl Label {
-        pub fn new(content: &Rc<str>) -> Label {
+        pub fn new(content: &Arc<str>) -> Label {
             Label {
-                content: Rc::clone(content),
+                content: Arc::clone(content),
             }
         }
 
-        pub fn get_content(&self) -> &Rc<str> {
+        pub fn get_content(&self) -> &Arc<str> {
             &self.content
         }
 
-        pub fn encode(&self) -> Rc<[u8]> {
+        pub fn encode(&self) -> Arc<[u8]> {
             let length = self.content.len();
             assert!(
                 length <= u8::MAX as usize,
@@ -368,21 +746,31 @@ pub mod message {
 
     #[derive(Clone, Debug)]
     pub struct LabelSequence {
-        labels: Rc<[Label]>,
+        labels: Arc<[Label]>,
     }
 
     impl LabelSequence {
-        pub fn new(labels: &Rc<[Label]>) -> LabelSequence {
+        pub fn new(labels: &Arc<[Label]>) -> LabelSequence {
             LabelSequence {
-                labels: Rc::clone(labels),
+                labels: Arc::clone(labels),
             }
         }
 
-        pub fn get_labels(&self) -> &Rc<[Label]> {
+        pub fn get_labels(&self) -> &Arc<[Label]> {
             &self.labels
         }
 
-        pub fn encode(&self) -> Rc<[u8]> {
+        // Domain names compare case-insensitively (RFC 4343).
+        pub fn eq_ignore_case(&self, other: &LabelSequence) -> bool {
+            self.labels.len() == other.labels.len()
+                && self
+                    .labels
+                    .iter()
+                    .zip(other.labels.iter())
+                    .all(|(a, b)| a.content.eq_ignore_ascii_case(&b.content))
+        }
+
+        pub fn encode(&self) -> Arc<[u8]> {
             let mut result: Vec<u8> = Vec::new();
             self.labels
                 .iter()
@@ -390,6 +778,160 @@ pub mod message {
             result.push(b'\0');
             result.into()
         }
+
+        // Reads an uncompressed name from the start of `data`, e.g. from the RDATA of a
+        // locally built record. Returns the name and the number of bytes it occupies.
+        pub fn decode_uncompressed(data: &[u8]) -> Option<(LabelSequence, usize)> {
+            let mut labels: Vec<Label> = Vec::new();
+            let mut index: usize = 0;
+            loop {
+                let length = *data.get(index)? as usize;
+                if length == 0 {
+                    break;
+                }
+                if length >= 0xC0 {
+                    return None;
+                }
+                let content = str::from_utf8(data.get((index + 1)..(index + 1 + length))?).ok()?;
+                labels.push(Label {
+                    content: content.into(),
+                });
+                index += length + 1;
+            }
+            Some((
+                LabelSequence {
+                    labels: labels.into(),
+                },
+                index + 1,
+            ))
+        }
+
+        // 192.0.2.10 -> 10.2.0.192.in-addr.arpa
+        pub fn from_reverse_ipv4(address: Ipv4Addr) -> LabelSequence {
+            let mut labels: Vec<Label> = address
+                .octets()
+                .iter()
+                .rev()
+                .map(|octet| Label {
+                    content: octet.to_string().into(),
+                })
+                .collect();
+            labels.push(Label {
+                content: "in-addr".into(),
+            });
+            labels.push(Label {
+                content: "arpa".into(),
+            });
+            LabelSequence {
+                labels: labels.into(),
+            }
+        }
+
+        // 2001:db8::1 -> 1.0.0.0.(...).8.b.d.0.1.0.0.2.ip6.arpa (32 nibble labels, RFC 3596).
+        pub fn from_reverse_ipv6(address: Ipv6Addr) -> LabelSequence {
+            let mut labels: Vec<Label> = address
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|octet| [octet & 0x0F, octet >> 4])
+                .map(|nibble| Label {
+                    content: format!("{:x}", nibble).into(),
+                })
+                .collect();
+            labels.push(Label {
+                content: "ip6".into(),
+            });
+            labels.push(Label {
+                content: "arpa".into(),
+            });
+            LabelSequence {
+                labels: labels.into(),
+            }
+        }
+
+        // The inverse of from_reverse_ipv4: None unless the name is exactly four
+        // decimal octets under in-addr.arpa.
+        pub fn to_reverse_ipv4(&self) -> Option<Ipv4Addr> {
+            let labels = self.reverse_address_labels("in-addr", 4)?;
+            let mut octets = [0u8; 4];
+            for (index, label) in labels.iter().rev().enumerate() {
+                if label.content.len() > 1 && label.content.starts_with('0') {
+                    return None;
+                }
+                octets[index] = label.content.parse().ok()?;
+            }
+            Some(Ipv4Addr::from(octets))
+        }
+
+        // The inverse of from_reverse_ipv6: None unless the name is exactly 32 hex
+        // nibbles under ip6.arpa.
+        pub fn to_reverse_ipv6(&self) -> Option<Ipv6Addr> {
+            let labels = self.reverse_address_labels("ip6", 32)?;
+            let mut octets = [0u8; 16];
+            for (index, label) in labels.iter().rev().enumerate() {
+                if label.content.len() != 1 {
+                    return None;
+                }
+                let nibble = u8::from_str_radix(&label.content, 16).ok()?;
+                octets[index / 2] |= if index % 2 == 0 { nibble << 4 } else { nibble };
+            }
+            Some(Ipv6Addr::from(octets))
+        }
+
+        fn reverse_address_labels(&self, zone: &str, count: usize) -> Option<&[Label]> {
+            let length = self.labels.len();
+            if length != count + 2
+                || !self.labels[length - 2].content.eq_ignore_ascii_case(zone)
+                || !self.labels[length - 1].content.eq_ignore_ascii_case("arpa")
+            {
+                return None;
+            }
+            Some(&self.labels[..count])
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct LabelSequenceParseError {
+        pub message: String,
+    }
+
+    impl str::FromStr for LabelSequence {
+        type Err = LabelSequenceParseError;
+
+        // Parses a name in presentation format ("www.example.com", with or without
+        // the trailing dot). Underscore labels such as "_http" are accepted.
+        fn from_str(value: &str) -> Result<Self, Self::Err> {
+            let trimmed = value.trim().trim_end_matches('.');
+            let mut labels: Vec<Label> = Vec::new();
+            if !trimmed.is_empty() {
+                for part in trimmed.split('.') {
+                    if part.is_empty() || part.len() > 63 {
+                        return Err(LabelSequenceParseError {
+                            message: format!(
+                                "'{}' is not a valid domain name: labels must be 1 to 63 bytes long.",
+                                value
+                            ),
+                        });
+                    }
+                    labels.push(Label {
+                        content: part.into(),
+                    });
+                }
+            }
+            let sequence = LabelSequence {
+                labels: labels.into(),
+            };
+            let length = sequence.encode().len();
+            if length > 255 {
+                return Err(LabelSequenceParseError {
+                    message: format!(
+                        "'{}' is not a valid domain name: its encoded length {} exceeds 255 bytes.",
+                        value, length
+                    ),
+                });
+            }
+            Ok(sequence)
+        }
     }
 
     impl fmt::Display for LabelSequence {
@@ -405,357 +947,1743 @@ pub mod message {
 
     #[derive(Clone, Debug)]
     pub struct Question {
-        name: Rc<LabelSequence>,
-        r#type: u16,
-        class: u16,
+        name: Arc<LabelSequence>,
+        r#type: RecordType,
+        class: RecordClass,
     }
 
     impl Question {
-        pub fn new(name: &Rc<LabelSequence>, r#type: u16, class: u16) -> Question {
+        pub fn new(name: &Arc<LabelSequence>, r#type: RecordType, class: RecordClass) -> Question {
             Question {
-                name: Rc::clone(name),
-                r#type: r#type,
-                class: class,
+                name: Arc::clone(name),
+                r#type,
+                class,
             }
         }
 
-        pub fn get_name(&self) -> &Rc<LabelSequence> {
+        pub fn get_name(&self) -> &Arc<LabelSequence> {
             &self.name
         }
 
-        pub fn get_type(&self) -> u16 {
+        pub fn get_type(&self) -> RecordType {
             self.r#type
         }
 
-        pub fn get_class(&self) -> u16 {
+        pub fn get_class(&self) -> RecordClass {
             self.class
         }
 
-        pub fn encode(&self) -> Rc<[u8]> {
+        pub fn encode(&self) -> Arc<[u8]> {
             let mut result: Vec<u8> = Vec::new();
             result.extend(self.name.encode().iter());
-            result.push(((self.r#type & 0xFF00) >> 8) as u8);
-            result.push((self.r#type & 0x00FF) as u8);
-            result.push(((self.class & 0xFF00) >> 8) as u8);
-            result.push((self.class & 0x00FF) as u8);
+            result.extend_from_slice(&u16::from(self.r#type).to_be_bytes());
+            result.extend_from_slice(&u16::from(self.class).to_be_bytes());
             result.into()
         }
     }
 
     impl fmt::Display for Question {
         // Example:
-        // ;codecrafters.io.    IN       A
+        // ;codecrafters.io.    IN    A
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             let name = &self.name;
             let _type = self.r#type;
             let class = self.class;
-            write!(f, "{name}    {_type}    {class}")
+            write!(f, ";{name}.    {class}    {_type}")
         }
     }
 
+    // The RDATA of a record: typed for the types we look inside (RFC 1035, 3.3; RFC
+    // 3596; RFC 2782; RFC 8659; RFC 9460), and as it is on the wire for the others, or when it doesn't
+    // fit its type. Names in it are kept uncompressed; EncodeContext compresses those
+    // of the types that allow it.
     #[derive(Clone, Debug)]
-    pub struct Answer {
-        name: Rc<LabelSequence>,
-        r#type: u16,
-        class: u16,
-        ttl: u32,
-        data: Rc<[u8]>,
+    pub enum RData {
+        A(Ipv4Addr),
+        Aaaa(Ipv6Addr),
+        Cname(LabelSequence),
+        Ns(LabelSequence),
+        Ptr(LabelSequence),
+        Mx {
+            preference: u16,
+            exchange: LabelSequence,
+        },
+        // The character-strings, up to 255 bytes each.
+        Txt(Vec<Arc<str>>),
+        Soa {
+            mname: LabelSequence,
+            rname: LabelSequence,
+            serial: u32,
+            refresh: u32,
+            retry: u32,
+            expire: u32,
+            minimum: u32,
+        },
+        Srv {
+            priority: u16,
+            weight: u16,
+            port: u16,
+            target: LabelSequence,
+        },
+        // A certification authority authorization: the flags (128 for critical), a
+        // property tag such as "issue", and its value.
+        Caa {
+            flags: u8,
+            tag: Arc<str>,
+            value: Arc<[u8]>,
+        },
+        // A service binding, of type HTTPS if `https` and SVCB otherwise. Priority 0
+        // is AliasMode, which has no parameters; the others are ServiceMode.
+        Svcb {
+            https: bool,
+            priority: u16,
+            target: LabelSequence,
+            params: Vec<SvcParam>,
+        },
+        // The type's code, and the RDATA.
+        Raw(u16, Arc<[u8]>),
     }
 
-    impl Answer {
-        pub fn new(
-            name: &Rc<LabelSequence>,
-            r#type: u16,
-            class: u16,
-            ttl: u32,
-            data: &Rc<[u8]>,
-        ) -> Answer {
-            Answer {
-                name: Rc::clone(name),
-                r#type: r#type,
-                class: class,
-                ttl: ttl,
-                data: Rc::clone(data),
-            }
+    impl RData {
+        // Reads the uncompressed RDATA of a record of type `r#type`.
+        pub fn decode(r#type: RecordType, data: &[u8]) -> RData {
+            RData::decode_typed(r#type, data)
+                .unwrap_or_else(|| RData::Raw(u16::from(r#type), Arc::from(data)))
         }
 
-        pub fn get_name(&self) -> &Rc<LabelSequence> {
-            &self.name
+        fn decode_typed(r#type: RecordType, data: &[u8]) -> Option<RData> {
+            // A name at `index`, and the index after it.
+            let name_at = |index: usize| -> Option<(LabelSequence, usize)> {
+                let (name, length) = LabelSequence::decode_uncompressed(data.get(index..)?)?;
+                Some((name, index + length))
+            };
+            let u16_at = |index: usize| {
+                Some(u16::from_be_bytes(
+                    data.get(index..index + 2)?.try_into().ok()?,
+                ))
+            };
+            let u32_at = |index: usize| {
+                Some(u32::from_be_bytes(
+                    data.get(index..index + 4)?.try_into().ok()?,
+                ))
+            };
+            let (rdata, end) = match r#type {
+                RecordType::A => (RData::A(<[u8; 4]>::try_from(data).ok()?.into()), data.len()),
+                RecordType::Aaaa => (
+                    RData::Aaaa(<[u8; 16]>::try_from(data).ok()?.into()),
+                    data.len(),
+                ),
+                RecordType::Cname => {
+                    let (name, end) = name_at(0)?;
+                    (RData::Cname(name), end)
+                }
+                RecordType::Ns => {
+                    let (name, end) = name_at(0)?;
+                    (RData::Ns(name), end)
+                }
+                RecordType::Ptr => {
+                    let (name, end) = name_at(0)?;
+                    (RData::Ptr(name), end)
+                }
+                RecordType::Mx => {
+                    let (exchange, end) = name_at(2)?;
+                    let preference = u16_at(0)?;
+                    (
+                        RData::Mx {
+                            preference,
+                            exchange,
+                        },
+                        end,
+                    )
+                }
+                RecordType::Txt => {
+                    let mut strings: Vec<Arc<str>> = Vec::new();
+                    let mut index: usize = 0;
+                    while index < data.len() {
+                        let length = data[index] as usize;
+                        let string = data.get(index + 1..index + 1 + length)?;
+                        strings.push(str::from_utf8(string).ok()?.into());
+                        index += 1 + length;
+                    }
+                    (RData::Txt(strings), index)
+                }
+                RecordType::Soa => {
+                    let (mname, index) = name_at(0)?;
+                    let (rname, index) = name_at(index)?;
+                    (
+                        RData::Soa {
+                            mname,
+                            rname,
+                            serial: u32_at(index)?,
+                            refresh: u32_at(index + 4)?,
+                            retry: u32_at(index + 8)?,
+                            expire: u32_at(index + 12)?,
+                            minimum: u32_at(index + 16)?,
+                        },
+                        index + 20,
+                    )
+                }
+                RecordType::Srv => {
+                    let (target, end) = name_at(6)?;
+                    (
+                        RData::Srv {
+                            priority: u16_at(0)?,
+                            weight: u16_at(2)?,
+                            port: u16_at(4)?,
+                            target,
+                        },
+                        end,
+                    )
+                }
+                RecordType::Svcb | RecordType::Https => {
+                    let (target, mut index) = name_at(2)?;
+                    let priority = u16_at(0)?;
+                    let mut params: Vec<SvcParam> = Vec::new();
+                    while index < data.len() {
+                        let key = u16_at(index)?;
+                        let length = u16_at(index + 2)? as usize;
+                        let value = data.get(index + 4..index + 4 + length)?;
+                        // Keys are in strictly increasing order (RFC 9460, 2.2).
+                        if params.last().is_some_and(|last| last.key() >= key) {
+                            return None;
+                        }
+                        params.push(SvcParam::decode(key, value));
+                        index += 4 + length;
+                    }
+                    if priority == 0 && !params.is_empty() {
+                        return None;
+                    }
+                    (
+                        RData::Svcb {
+                            https: r#type == RecordType::Https,
+                            priority,
+                            target,
+                            params,
+                        },
+                        index,
+                    )
+                }
+                RecordType::Caa => {
+                    let length = *data.get(1)? as usize;
+                    let tag = data.get(2..2 + length).filter(|tag| !tag.is_empty())?;
+                    (
+                        RData::Caa {
+                            flags: data[0],
+                            tag: str::from_utf8(tag).ok()?.into(),
+                            value: Arc::from(&data[2 + length..]),
+                        },
+                        data.len(),
+                    )
+                }
+                _ => return None,
+            };
+            (end == data.len()).then_some(rdata)
         }
 
-        pub fn get_type(&self) -> u16 {
-            self.r#type
+        pub fn get_type(&self) -> RecordType {
+            match self {
+                Self::A(_) => RecordType::A,
+                Self::Aaaa(_) => RecordType::Aaaa,
+                Self::Cname(_) => RecordType::Cname,
+                Self::Ns(_) => RecordType::Ns,
+                Self::Ptr(_) => RecordType::Ptr,
+                Self::Mx { .. } => RecordType::Mx,
+                Self::Txt(_) => RecordType::Txt,
+                Self::Soa { .. } => RecordType::Soa,
+                Self::Srv { .. } => RecordType::Srv,
+                Self::Caa { .. } => RecordType::Caa,
+                Self::Svcb { https: true, .. } => RecordType::Https,
+                Self::Svcb { https: false, .. } => RecordType::Svcb,
+                Self::Raw(code, _) => RecordType::from(*code),
+            }
         }
 
-        pub fn get_class(&self) -> u16 {
-            self.class
+        // The RDATA on the wire, its names uncompressed. Panics if it doesn't fit on
+        // the wire; RDATA built from outside input is checked with `try_encode` first.
+        pub fn encode(&self) -> Arc<[u8]> {
+            self.try_encode()
+                .unwrap_or_else(|err| panic!("{}", err.message))
         }
 
-        pub fn get_ttl(&self) -> u32 {
-            self.ttl
+        // The RDATA on the wire, or why it can't be put there: a TXT string or a CAA
+        // tag longer than its length byte can tell.
+        pub fn try_encode(&self) -> Result<Arc<[u8]>, RDataEncodeError> {
+            let mut result: Vec<u8> = Vec::new();
+            match self {
+                Self::A(address) => result.extend_from_slice(&address.octets()),
+                Self::Aaaa(address) => result.extend_from_slice(&address.octets()),
+                Self::Cname(name) | Self::Ns(name) | Self::Ptr(name) => {
+                    result.extend_from_slice(&name.encode())
+                }
+                Self::Mx {
+                    preference,
+                    exchange,
+                } => {
+                    result.extend_from_slice(&preference.to_be_bytes());
+                    result.extend_from_slice(&exchange.encode());
+                }
+                Self::Txt(strings) => {
+                    for string in strings {
+                        result.push(u8::try_from(string.len()).map_err(|_| RDataEncodeError {
+                            message: format!(
+                                "TXT string's length {} is too big (should be less than or equal to {}).",
+                                string.len(),
+                                u8::MAX
+                            ),
+                        })?);
+                        result.extend_from_slice(string.as_bytes());
+                    }
+                }
+                Self::Soa {
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                } => {
+                    result.extend_from_slice(&mname.encode());
+                    result.extend_from_slice(&rname.encode());
+                    for number in [serial, refresh, retry, expire, minimum] {
+                        result.extend_from_slice(&number.to_be_bytes());
+                    }
+                }
+                Self::Srv {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                } => {
+                    for number in [priority, weight, port] {
+                        result.extend_from_slice(&number.to_be_bytes());
+                    }
+                    result.extend_from_slice(&target.encode());
+                }
+                Self::Caa { flags, tag, value } => {
+                    let length = u8::try_from(tag.len())
+                        .ok()
+                        .filter(|&length| length > 0)
+                        .ok_or_else(|| RDataEncodeError {
+                            message: format!(
+                                "CAA tag's length {} is out of range (should be 1 to {}).",
+                                tag.len(),
+                                u8::MAX
+                            ),
+                        })?;
+                    result.push(*flags);
+                    result.push(length);
+                    result.extend_from_slice(tag.as_bytes());
+                    result.extend_from_slice(value);
+                }
+                Self::Svcb {
+                    priority,
+                    target,
+                    params,
+                    ..
+                } => {
+                    result.extend_from_slice(&priority.to_be_bytes());
+                    result.extend_from_slice(&target.encode());
+                    // In increasing key order, whatever the order they were given in.
+                    let mut params: Vec<&SvcParam> = params.iter().collect();
+                    params.sort_by_key(|param| param.key());
+                    for param in params {
+                        let value = param.encode();
+                        result.extend_from_slice(&param.key().to_be_bytes());
+                        result.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                        result.extend_from_slice(&value);
+                    }
+                }
+                Self::Raw(_, data) => return Ok(Arc::clone(data)),
+            }
+            Ok(result.into())
         }
+    }
 
-        pub fn get_data_length(&self) -> u16 {
-            self.data.len() as u16
+    // `bytes` as in a character-string's presentation form, without the quotes: quotes
+    // and backslashes escaped, and bytes other than printable ASCII as \DDD.
+    fn escaped(bytes: &[u8]) -> String {
+        bytes
+            .iter()
+            .map(|&byte| match byte {
+                b'"' | b'\\' => format!("\\{}", byte as char),
+                b' '..=b'~' => String::from(byte as char),
+                _ => format!("\\{byte:03}"),
+            })
+            .collect()
+    }
+
+    // An SvcParam of an SVCB or HTTPS record (RFC 9460, 7): typed for the keys we look
+    // inside, and as it is on the wire for the others, or when it doesn't fit its key.
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum SvcParam {
+        // The protocol IDs, e.g. "h2".
+        Alpn(Vec<Arc<str>>),
+        Port(u16),
+        Ipv4Hint(Vec<Ipv4Addr>),
+        Ipv6Hint(Vec<Ipv6Addr>),
+        // The key, and the value.
+        Other(u16, Arc<[u8]>),
+    }
+
+    impl SvcParam {
+        pub const ALPN: u16 = 1;
+        pub const PORT: u16 = 3;
+        pub const IPV4_HINT: u16 = 4;
+        pub const IPV6_HINT: u16 = 6;
+
+        pub fn key(&self) -> u16 {
+            match self {
+                Self::Alpn(_) => SvcParam::ALPN,
+                Self::Port(_) => SvcParam::PORT,
+                Self::Ipv4Hint(_) => SvcParam::IPV4_HINT,
+                Self::Ipv6Hint(_) => SvcParam::IPV6_HINT,
+                Self::Other(key, _) => *key,
+            }
         }
 
-        pub fn get_data(&self) -> &Rc<[u8]> {
-            &self.data
+        fn decode(key: u16, value: &[u8]) -> SvcParam {
+            SvcParam::decode_typed(key, value)
+                .unwrap_or_else(|| SvcParam::Other(key, Arc::from(value)))
         }
 
-        pub fn encode(&self) -> Rc<[u8]> {
-            let mut result: Vec<u8> = Vec::new();
-            result.extend_from_slice(&self.name.encode());
-            result.push(((self.r#type & 0xFF00) >> 8) as u8);
-            result.push((self.r#type & 0x00FF) as u8);
-            result.push(((self.class & 0xFF00) >> 8) as u8);
-            result.push((self.class & 0x00FF) as u8);
-            result.push(((self.ttl & 0xFF000000) >> 24) as u8);
-            result.push(((self.ttl & 0x00FF0000) >> 16) as u8);
-            result.push(((self.ttl & 0x0000FF00) >> 8) as u8);
-            result.push((self.ttl & 0x000000FF) as u8);
+        fn decode_typed(key: u16, value: &[u8]) -> Option<SvcParam> {
+            match key {
+                SvcParam::ALPN => {
+                    let mut ids: Vec<Arc<str>> = Vec::new();
+                    let mut index: usize = 0;
+                    while index < value.len() {
+                        let length = value[index] as usize;
+                        let id = value.get(index + 1..index + 1 + length)?;
+                        if id.is_empty() {
+                            return None;
+                        }
+                        ids.push(str::from_utf8(id).ok()?.into());
+                        index += 1 + length;
+                    }
+                    (!ids.is_empty()).then_some(SvcParam::Alpn(ids))
+                }
+                SvcParam::PORT => Some(SvcParam::Port(u16::from_be_bytes(value.try_into().ok()?))),
+                SvcParam::IPV4_HINT if !value.is_empty() && value.len() % 4 == 0 => {
+                    Some(SvcParam::Ipv4Hint(
+                        value
+                            .chunks(4)
+                            .map(|chunk| <[u8; 4]>::try_from(chunk).unwrap().into())
+                            .collect(),
+                    ))
+                }
+                SvcParam::IPV6_HINT if !value.is_empty() && value.len() % 16 == 0 => {
+                    Some(SvcParam::Ipv6Hint(
+                        value
+                            .chunks(16)
+                            .map(|chunk| <[u8; 16]>::try_from(chunk).unwrap().into())
+                            .collect(),
+                    ))
+                }
+                _ => None,
+            }
+        }
 
-            let length = self.data.len() as u16;
-            result.push(((length & 0xFF00) >> 8) as u8);
-            result.push((length & 0x00FF) as u8);
-            result.extend_from_slice(&self.data);
-            result.into()
+        // The value on the wire.
+        fn encode(&self) -> Vec<u8> {
+            match self {
+                Self::Alpn(ids) => ids
+                    .iter()
+                    .flat_map(|id| std::iter::once(id.len() as u8).chain(id.bytes()))
+                    .collect(),
+                Self::Port(port) => port.to_be_bytes().to_vec(),
+                Self::Ipv4Hint(addresses) => addresses
+                    .iter()
+                    .flat_map(|address| address.octets())
+                    .collect(),
+                Self::Ipv6Hint(addresses) => addresses
+                    .iter()
+                    .flat_map(|address| address.octets())
+                    .collect(),
+                Self::Other(_, value) => value.to_vec(),
+            }
         }
     }
 
-    impl fmt::Display for Answer {
-        // Example
-        // codecrafters.io.     3600    IN      A       76.76.21.21
+    impl fmt::Display for SvcParam {
+        // As dig shows it, e.g. alpn="h2,h3" or key65280="x" (RFC 9460, 2.1 and 7).
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            let name = &self.name;
-            let ttl = self.ttl;
-            let _type = self.r#type;
-            let class = self.class;
-            let address_parts: Vec<String> = self.data.iter().map(u8::to_string).collect();
-            let address = address_parts.join("."); // TODO: IPv6 representation
-            write!(f, "{name}    {ttl}    {_type}    {class}    {address}")
+            let join = |items: Vec<String>| items.join(",");
+            match self {
+                Self::Alpn(ids) => {
+                    let ids: Vec<String> = ids
+                        .iter()
+                        .map(|id| escaped(id.as_bytes()).replace(',', "\\\\,"))
+                        .collect();
+                    write!(f, "alpn=\"{}\"", ids.join(","))
+                }
+                Self::Port(port) => write!(f, "port={port}"),
+                Self::Ipv4Hint(addresses) => write!(
+                    f,
+                    "ipv4hint={}",
+                    join(addresses.iter().map(Ipv4Addr::to_string).collect())
+                ),
+                Self::Ipv6Hint(addresses) => write!(
+                    f,
+                    "ipv6hint={}",
+                    join(addresses.iter().map(Ipv6Addr::to_string).collect())
+                ),
+                Self::Other(key, value) if value.is_empty() => write!(f, "key{key}"),
+                Self::Other(key, value) => write!(f, "key{key}=\"{}\"", escaped(value)),
+            }
         }
     }
 
     #[derive(Debug)]
+    pub struct RDataEncodeError {
+        pub message: String,
+    }
+
+    impl fmt::Display for RData {
+        // As dig shows it, e.g. "10 mail.example.com." for MX; RDATA of the other
+        // types in the generic form of RFC 3597, 5, unless it is of a legacy type.
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::A(address) => write!(f, "{address}"),
+                Self::Aaaa(address) => write!(f, "{address}"),
+                Self::Cname(name) | Self::Ns(name) | Self::Ptr(name) => write!(f, "{name}."),
+                Self::Mx {
+                    preference,
+                    exchange,
+                } => write!(f, "{preference} {exchange}."),
+                Self::Txt(strings) => {
+                    let quoted: Vec<String> = strings
+                        .iter()
+                        .map(|string| {
+                            format!("\"{}\"", string.replace('\\', "\\\\").replace('"', "\\\""))
+                        })
+                        .collect();
+                    write!(f, "{}", quoted.join(" "))
+                }
+                Self::Soa {
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                } => write!(
+                    f,
+                    "{mname}. {rname}. {serial} {refresh} {retry} {expire} {minimum}"
+                ),
+                Self::Srv {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                } => write!(f, "{priority} {weight} {port} {target}."),
+                // The value as a quoted character-string (RFC 8659, 4.1.1).
+                Self::Caa { flags, tag, value } => {
+                    write!(f, "{flags} {tag} \"{}\"", escaped(value))
+                }
+                Self::Svcb {
+                    priority,
+                    target,
+                    params,
+                    ..
+                } => {
+                    write!(f, "{priority} {target}.")?;
+                    for param in params {
+                        write!(f, " {param}")?;
+                    }
+                    Ok(())
+                }
+                Self::Raw(code, data) => {
+                    if let Some(rdata) = LegacyRdata::decode(RecordType::from(*code), data) {
+                        return write!(f, "{rdata}");
+                    }
+                    let hex: String = data.iter().map(|byte| format!(" {byte:02x}")).collect();
+                    write!(f, "\\# {}{hex}", data.len())
+                }
+            }
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct Answer {
+        name: Arc<LabelSequence>,
+        class: RecordClass,
+        ttl: u32,
+        data: RData,
+    }
+
+    impl Answer {
+        // A record from its RDATA as it is on the wire, uncompressed.
+        pub fn new(
+            name: &Arc<LabelSequence>,
+            r#type: RecordType,
+            class: RecordClass,
+            ttl: u32,
+            data: &Arc<[u8]>,
+        ) -> Answer {
+            Answer::from_rdata(name, class, ttl, RData::decode(r#type, data))
+        }
+
+        pub fn from_rdata(
+            name: &Arc<LabelSequence>,
+            class: RecordClass,
+            ttl: u32,
+            data: RData,
+        ) -> Answer {
+            Answer {
+                name: Arc::clone(name),
+                class,
+                ttl,
+                data,
+            }
+        }
+
+        pub fn get_name(&self) -> &Arc<LabelSequence> {
+            &self.name
+        }
+
+        pub fn get_type(&self) -> RecordType {
+            self.data.get_type()
+        }
+
+        pub fn get_class(&self) -> RecordClass {
+            self.class
+        }
+
+        pub fn get_ttl(&self) -> u32 {
+            self.ttl
+        }
+
+        pub fn get_data_length(&self) -> u16 {
+            self.data.encode().len() as u16
+        }
+
+        // The RDATA as it is on the wire, uncompressed.
+        pub fn get_data(&self) -> Arc<[u8]> {
+            self.data.encode()
+        }
+
+        pub fn get_rdata(&self) -> &RData {
+            &self.data
+        }
+
+        // An IN A record for `address`.
+        pub fn a(name: &Arc<LabelSequence>, ttl: u32, address: Ipv4Addr) -> Answer {
+            Answer::from_rdata(name, RecordClass::In, ttl, RData::A(address))
+        }
+
+        // An IN AAAA record for `address`.
+        pub fn aaaa(name: &Arc<LabelSequence>, ttl: u32, address: Ipv6Addr) -> Answer {
+            Answer::from_rdata(name, RecordClass::In, ttl, RData::Aaaa(address))
+        }
+
+        pub fn with_name(&self, name: &Arc<LabelSequence>) -> Answer {
+            Answer {
+                name: Arc::clone(name),
+                ..self.clone()
+            }
+        }
+
+        pub fn encode(&self) -> Arc<[u8]> {
+            let mut result: Vec<u8> = Vec::new();
+            result.extend_from_slice(&self.name.encode());
+            result.extend_from_slice(&u16::from(self.get_type()).to_be_bytes());
+            result.extend_from_slice(&u16::from(self.class).to_be_bytes());
+            result.push(((self.ttl & 0xFF000000) >> 24) as u8);
+            result.push(((self.ttl & 0x00FF0000) >> 16) as u8);
+            result.push(((self.ttl & 0x0000FF00) >> 8) as u8);
+            result.push((self.ttl & 0x000000FF) as u8);
+
+            let data = self.data.encode();
+            let length = data.len() as u16;
+            result.push(((length & 0xFF00) >> 8) as u8);
+            result.push((length & 0x00FF) as u8);
+            result.extend_from_slice(&data);
+            result.into()
+        }
+    }
+
+    impl fmt::Display for Answer {
+        // Example
+        // codecrafters.io.     3600    IN      A       76.76.21.21
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let name = &self.name;
+            let ttl = self.ttl;
+            let _type = self.get_type();
+            let class = self.class;
+            let rdata = &self.data;
+            write!(f, "{name}.    {ttl}    {class}    {_type}    {rdata}")
+        }
+    }
+
+    // The OPT pseudo-record of EDNS (RFC 6891, 6.1.2), as carried in the additional
+    // section: the CLASS field holds the sender's UDP payload size, and the TTL field
+    // the upper 8 bits of the extended RCODE, the EDNS version and the flags.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct OptRecord {
+        udp_payload_size: u16,
+        extended_rcode: u8,
+        version: u8,
+        flags: u16,
+        // The options, {code: u16, length: u16, data} each, as they are on the wire.
+        options: Arc<[u8]>,
+    }
+
+    impl OptRecord {
+        pub const TYPE: RecordType = RecordType::Opt;
+        // DNSSEC OK (RFC 3225), the only flag defined.
+        const DO: u16 = 0x8000;
+
+        pub fn new(udp_payload_size: u16) -> OptRecord {
+            OptRecord {
+                udp_payload_size,
+                extended_rcode: 0,
+                version: 0,
+                flags: 0,
+                options: Arc::from([]),
+            }
+        }
+
+        // Reads an OPT record; None if the record is of another type.
+        pub fn from_answer(record: &Answer) -> Option<OptRecord> {
+            if record.get_type() != OptRecord::TYPE {
+                return None;
+            }
+            Some(OptRecord {
+                udp_payload_size: u16::from(record.class),
+                extended_rcode: (record.ttl >> 24) as u8,
+                version: (record.ttl >> 16) as u8,
+                flags: record.ttl as u16,
+                options: record.get_data(),
+            })
+        }
+
+        // The first OPT record among `records` (a message has at most one).
+        pub fn find(records: &[Answer]) -> Option<OptRecord> {
+            records.iter().find_map(OptRecord::from_answer)
+        }
+
+        pub fn to_answer(&self) -> Answer {
+            Answer::new(
+                /* name= */ &Arc::new(LabelSequence::new(&Arc::from([]))),
+                /* type= */ OptRecord::TYPE,
+                /* class= */ RecordClass::from(self.udp_payload_size),
+                /* ttl= */
+                (self.extended_rcode as u32) << 24
+                    | (self.version as u32) << 16
+                    | self.flags as u32,
+                /* data= */ &self.options,
+            )
+        }
+
+        // Requestor's (or responder's) UDP payload size: the largest UDP message it
+        // can receive.
+        pub fn get_udp_payload_size(&self) -> u16 {
+            self.udp_payload_size
+        }
+
+        // The upper 8 bits of the 12-bit extended RCODE; the lower 4 are in the header.
+        pub fn get_extended_rcode(&self) -> u8 {
+            self.extended_rcode
+        }
+
+        pub fn set_extended_rcode(&mut self, extended_rcode: u8) -> &'_ mut Self {
+            self.extended_rcode = extended_rcode;
+            self
+        }
+
+        pub fn get_version(&self) -> u8 {
+            self.version
+        }
+
+        pub fn set_version(&mut self, version: u8) -> &'_ mut Self {
+            self.version = version;
+            self
+        }
+
+        pub fn get_do(&self) -> bool {
+            self.flags & OptRecord::DO != 0
+        }
+
+        pub fn set_do(&mut self, r#do: bool) -> &'_ mut Self {
+            if r#do {
+                self.flags |= OptRecord::DO;
+            } else {
+                self.flags &= !OptRecord::DO;
+            }
+            self
+        }
+
+        // The options as (code, data) pairs; an option running past the end of the
+        // data ends them.
+        pub fn get_options(&self) -> Vec<(u16, &[u8])> {
+            let mut options: Vec<(u16, &[u8])> = Vec::new();
+            let mut index = 0;
+            while let Some(fixed) = self.options.get(index..index + 4) {
+                let code = u16::from_be_bytes([fixed[0], fixed[1]]);
+                let length = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+                let Some(data) = self.options.get(index + 4..index + 4 + length) else {
+                    break;
+                };
+                options.push((code, data));
+                index += 4 + length;
+            }
+            options
+        }
+
+        pub fn add_option(&mut self, code: u16, data: &[u8]) -> &'_ mut Self {
+            let mut options = self.options.to_vec();
+            options.extend_from_slice(&code.to_be_bytes());
+            options.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            options.extend_from_slice(data);
+            self.options = options.into();
+            self
+        }
+
+        // Removes the last option if it has `code`; returns whether it did.
+        pub fn remove_trailing_option(&mut self, code: u16) -> bool {
+            let options = self.get_options();
+            let Some(&(last, data)) = options.last() else {
+                return false;
+            };
+            let end: usize = options.iter().map(|(_, data)| 4 + data.len()).sum();
+            if last != code || end != self.options.len() {
+                return false;
+            }
+            self.options = Arc::from(&self.options[..end - 4 - data.len()]);
+            true
+        }
+    }
+
+    // Why a message couldn't be parsed; offsets count from the start of the message.
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum DnsParseError {
+        // Fewer bytes than the 12 of a header.
+        TruncatedHeader { length: usize },
+        // A name whose labels run past the end of the data.
+        LabelOverrun { offset: usize },
+        // A compression pointer that doesn't point back at an earlier name.
+        BadPointer { offset: usize, target: usize },
+        // A name longer than the 255 bytes it may take uncompressed (RFC 1035, 3.1).
+        NameTooLong { offset: usize },
+        // A question or record whose fixed fields or data run past the end, or that is
+        // missing although the header counts it.
+        TruncatedRecord { offset: usize },
+        // Bytes after the last section the header counts.
+        TrailingGarbage { offset: usize },
+        InvalidUtf8Label { offset: usize },
+        // A label whose length byte starts with 01 or 10, the bits of the extended and
+        // unallocated label types (RFC 6891, 5), none of which we read.
+        ReservedLabelType { offset: usize },
+        // RDATA that doesn't hold the names and fields its type calls for.
+        BadRdata { offset: usize },
+        // A header counting more questions than MAX_QUESTIONS.
+        TooManyQuestions { count: u16 },
+        // A query without a question. Only QUERY needs one (DSO messages, for one,
+        // have none), so it's the query handler that checks, not Message::parse_from.
+        NoQuestion,
+    }
+
+    impl DnsParseError {
+        // The byte at which parsing failed: the end of a short header, and the
+        // question count for the errors about it.
+        pub fn offset(&self) -> usize {
+            match self {
+                Self::TruncatedHeader { length } => *length,
+                Self::LabelOverrun { offset }
+                | Self::BadPointer { offset, .. }
+                | Self::TruncatedRecord { offset }
+                | Self::TrailingGarbage { offset }
+                | Self::NameTooLong { offset }
+                | Self::InvalidUtf8Label { offset }
+                | Self::ReservedLabelType { offset }
+                | Self::BadRdata { offset } => *offset,
+                Self::TooManyQuestions { .. } | Self::NoQuestion => 4,
+            }
+        }
+    }
+
+    impl fmt::Display for DnsParseError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::TruncatedHeader { length } => {
+                    write!(f, "{} byte(s) is shorter than a header", length)
+                }
+                Self::LabelOverrun { offset } => {
+                    write!(f, "the name at byte {} runs past the end", offset)
+                }
+                Self::BadPointer { offset, target } => write!(
+                    f,
+                    "the pointer at byte {} points to byte {}, not back at a name",
+                    offset, target
+                ),
+                Self::NameTooLong { offset } => {
+                    write!(f, "the name at byte {} is longer than 255 bytes", offset)
+                }
+                Self::TruncatedRecord { offset } => {
+                    write!(f, "the data ends within a record at byte {}", offset)
+                }
+                Self::TrailingGarbage { offset } => {
+                    write!(f, "trailing bytes from byte {}", offset)
+                }
+                Self::InvalidUtf8Label { offset } => {
+                    write!(f, "the label at byte {} is not UTF-8", offset)
+                }
+                Self::ReservedLabelType { offset } => {
+                    write!(f, "the label at byte {} has a reserved type", offset)
+                }
+                Self::BadRdata { offset } => {
+                    write!(f, "the data at byte {} doesn't fit its record type", offset)
+                }
+                Self::TooManyQuestions { count } => write!(
+                    f,
+                    "{} questions is more than the {} a message may have",
+                    count, MAX_QUESTIONS
+                ),
+                Self::NoQuestion => write!(f, "the query has no question"),
+            }
+        }
+    }
+
+    // Most questions a message may count; no server answers more than one, and a
+    // count in the thousands would only have the parser look for them.
+    const MAX_QUESTIONS: u16 = 32;
+
+    // The question, answer, authority and additional sections of a message.
+    type Sections = (Arc<[Question]>, Arc<[Answer]>, Arc<[Answer]>, Arc<[Answer]>);
+
+    // A part of the RDATA of a type that holds names.
+    pub enum RdataField {
+        Name,
+        Fixed(usize),
+    }
+
+    // The layout of the RDATA of the types whose names may be compressed (RFC 3597, 4),
+    // as far as we handle them: NS, CNAME, PTR, MX and SOA. Other types' RDATA is
+    // opaque, and copied as it is.
+    pub fn rdata_layout(r#type: RecordType) -> Option<&'static [RdataField]> {
+        match r#type {
+            RecordType::Ns | RecordType::Cname | RecordType::Ptr => Some(&[RdataField::Name]),
+            RecordType::Mx => Some(&[RdataField::Fixed(2), RdataField::Name]),
+            RecordType::Soa => Some(&[RdataField::Name, RdataField::Name, RdataField::Fixed(20)]),
+            _ => None,
+        }
+    }
+
+    // The sections records go in; they are added to an EncodeContext in this order.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Section {
+        Answer = 1,
+        Authority = 2,
+        Additional = 3,
+    }
+
+    // The EDNS Padding option (RFC 7830).
+    pub const PADDING_OPTION: u16 = 12;
+
+    // A message being encoded, in a single pass: everything in it, down to the records
+    // that depend on the bytes before them (padding, signatures), is added through the
+    // context, and nothing is appended to the bytes afterwards.
+    //
+    // Names are compressed in questions, owner names and the RDATA of the types that
+    // allow it (RFC 1035, 4.1.4): the offset of every suffix of a name written is
+    // recorded, and a later name ending in one of them points to it instead of
+    // repeating it. Suffixes match byte for byte, casing included, so that names read
+    // back exactly as they were written.
+    //
+    // The header's section counts are those of what was added, and are only written
+    // into it by `finish`, so they can't disagree with the sections.
+    pub struct EncodeContext {
+        bytes: Vec<u8>,
+        // Encoded suffixes, by the offset they were written at.
+        suffixes: HashMap<Vec<u8>, u16>,
+        // QDCOUNT, ANCOUNT, NSCOUNT and ARCOUNT so far.
+        counts: [u16; 4],
+        // Where the RDATA of the OPT record starts, while it is the last record added.
+        trailing_opt: Option<usize>,
+    }
+
+    // How far an encoding had got, to get back the message as it was at that point
+    // (see EncodeContext::encoded_at).
+    #[derive(Clone, Debug)]
+    pub struct Checkpoint {
+        length: usize,
+        counts: [u16; 4],
+    }
+
+    impl EncodeContext {
+        pub fn new(header: &Header) -> EncodeContext {
+            EncodeContext {
+                bytes: header.encode().to_vec(),
+                suffixes: HashMap::new(),
+                counts: [0; 4],
+                trailing_opt: None,
+            }
+        }
+
+        pub fn add_question(&mut self, question: &Question) {
+            assert!(
+                self.counts[1..].iter().all(|&count| count == 0),
+                "questions are added before records"
+            );
+            self.write_question(question);
+            self.counts[0] += 1;
+        }
+
+        pub fn add_record(&mut self, section: Section, record: &Answer) {
+            assert!(
+                self.counts[section as usize + 1..]
+                    .iter()
+                    .all(|&count| count == 0),
+                "records are added section by section"
+            );
+            let rdata = self.write_record(record);
+            self.counts[section as usize] += 1;
+            self.trailing_opt = (record.get_type() == OptRecord::TYPE).then_some(rdata);
+        }
+
+        // Pads the message with an EDNS Padding option in its OPT record, so that
+        // its length is a multiple of `block`, or `limit` if that is less. Only done
+        // while the OPT record is the last one added, as the padding has to account
+        // for everything before it; returns whether it was.
+        pub fn pad(&mut self, block: usize, limit: usize) -> bool {
+            let Some(rdata) = self.trailing_opt else {
+                return false;
+            };
+            let unpadded = self.bytes.len() + 4;
+            let target = unpadded.next_multiple_of(block.max(1)).min(limit);
+            if target < unpadded {
+                return false;
+            }
+            let length = target - unpadded;
+            self.bytes.extend_from_slice(&PADDING_OPTION.to_be_bytes());
+            self.bytes.extend_from_slice(&(length as u16).to_be_bytes());
+            self.bytes.resize(target, 0);
+            let rdlength = (self.bytes.len() - rdata) as u16;
+            self.bytes[rdata - 2..rdata].copy_from_slice(&rdlength.to_be_bytes());
+            true
+        }
+
+        // Adds answers encoded beforehand, right after the question they were encoded
+        // after, as add_record would have encoded them.
+        fn add_encoded_answers(&mut self, encoded: &EncodedAnswers) {
+            assert!(
+                self.counts[0] == 1 && self.counts[1..].iter().all(|&count| count == 0),
+                "encoded answers follow their question"
+            );
+            self.bytes.extend_from_slice(&encoded.bytes);
+            self.suffixes.extend(encoded.suffixes.iter().cloned());
+            self.counts[Section::Answer as usize] += encoded.answers.len() as u16;
+            self.trailing_opt = None;
+        }
+
+        pub fn checkpoint(&self) -> Checkpoint {
+            Checkpoint {
+                length: self.bytes.len(),
+                counts: self.counts,
+            }
+        }
+
+        // The message as it was encoded at `checkpoint`, its counts included.
+        pub fn encoded_at(&self, checkpoint: &Checkpoint) -> Vec<u8> {
+            let mut bytes = self.bytes[..checkpoint.length].to_vec();
+            EncodeContext::write_counts(&mut bytes, &checkpoint.counts);
+            bytes
+        }
+
+        // Adds the record `sign` makes of the message so far, e.g. a TSIG or SIG(0),
+        // which cover the message before them (RFC 8945, 4.3.1; RFC 2931, 3.1). It
+        // is the last additional record, and nothing may be added after it.
+        pub fn add_signature(&mut self, sign: impl FnOnce(&[u8]) -> Answer) {
+            let signature = sign(&self.encoded_at(&self.checkpoint()));
+            self.add_record(Section::Additional, &signature);
+        }
+
+        pub fn finish(mut self) -> Arc<[u8]> {
+            EncodeContext::write_counts(&mut self.bytes, &self.counts);
+            self.bytes.into()
+        }
+
+        fn write_counts(bytes: &mut [u8], counts: &[u16; 4]) {
+            for (index, count) in counts.iter().enumerate() {
+                bytes[4 + 2 * index..6 + 2 * index].copy_from_slice(&count.to_be_bytes());
+            }
+        }
+
+        fn write_name(&mut self, name: &LabelSequence) {
+            let encoded = name.encode();
+            let mut start: usize = 0;
+            for label in name.get_labels().iter() {
+                let suffix = &encoded[start..];
+                if let Some(&offset) = self.suffixes.get(suffix) {
+                    self.bytes
+                        .extend_from_slice(&(0xC000 | offset).to_be_bytes());
+                    return;
+                }
+                // Pointers have 14 bits for the offset.
+                if self.bytes.len() < 0x4000 {
+                    self.suffixes
+                        .insert(suffix.to_vec(), self.bytes.len() as u16);
+                }
+                let length = label.get_content().len() + 1;
+                self.bytes
+                    .extend_from_slice(&encoded[start..start + length]);
+                start += length;
+            }
+            self.bytes.push(0);
+        }
+
+        fn write_question(&mut self, question: &Question) {
+            self.write_name(&question.name);
+            self.bytes
+                .extend_from_slice(&u16::from(question.r#type).to_be_bytes());
+            self.bytes
+                .extend_from_slice(&u16::from(question.class).to_be_bytes());
+        }
+
+        // Writes a record, and returns where its RDATA starts.
+        fn write_record(&mut self, record: &Answer) -> usize {
+            self.write_name(&record.name);
+            self.bytes
+                .extend_from_slice(&u16::from(record.get_type()).to_be_bytes());
+            self.bytes
+                .extend_from_slice(&u16::from(record.class).to_be_bytes());
+            self.bytes.extend_from_slice(&record.ttl.to_be_bytes());
+            let length_index = self.bytes.len();
+            self.bytes.extend_from_slice(&[0, 0]);
+            match &record.data {
+                RData::Cname(name) | RData::Ns(name) | RData::Ptr(name) => self.write_name(name),
+                RData::Mx {
+                    preference,
+                    exchange,
+                } => {
+                    self.bytes.extend_from_slice(&preference.to_be_bytes());
+                    self.write_name(exchange);
+                }
+                RData::Soa {
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                } => {
+                    self.write_name(mname);
+                    self.write_name(rname);
+                    for number in [serial, refresh, retry, expire, minimum] {
+                        self.bytes.extend_from_slice(&number.to_be_bytes());
+                    }
+                }
+                data => self.bytes.extend_from_slice(&data.encode()),
+            }
+            let length = (self.bytes.len() - length_index - 2) as u16;
+            self.bytes[length_index..length_index + 2].copy_from_slice(&length.to_be_bytes());
+            length_index + 2
+        }
+    }
+
+    // Makes the signature record of the message before it (see
+    // EncodeContext::add_signature).
+    pub type Signer<'a> = dyn Fn(&[u8]) -> Answer + 'a;
+
+    // An answer section encoded once, to be copied into the encodings of messages
+    // that answer the same question with the same records, but for their TTLs, rather
+    // than encoded again (see CachingResolver). Names are compressed against the
+    // question and against each other, so the bytes only stand for the answers after
+    // that question, casing included; what comes before it (the header) and after the
+    // answers (EDNS, padding) doesn't matter. A message uses them only if its answers
+    // are the very ones they were made for: whatever changes the answers (a filter,
+    // trimming, truncation) makes new ones, and the message is then encoded as usual.
+    #[derive(Debug)]
+    pub struct EncodedAnswers {
+        answers: Arc<[Answer]>,
+        // The question they follow, as encoded.
+        question: Arc<[u8]>,
+        bytes: Arc<[u8]>,
+        // The suffixes the answers' names left for the records after them to point to
+        // (see EncodeContext).
+        suffixes: Arc<[(Vec<u8>, u16)]>,
+        // Where each answer's TTL is in `bytes`.
+        ttls: Arc<[usize]>,
+    }
+
+    impl EncodedAnswers {
+        // Encodes `answers` as they follow `question` in a message.
+        pub fn new(question: &Question, answers: &Arc<[Answer]>) -> EncodedAnswers {
+            let mut context = EncodeContext::new(&Header::default());
+            context.add_question(question);
+            let start = context.bytes.len();
+            let ttls: Vec<usize> = answers
+                .iter()
+                .map(|answer| {
+                    let rdata = context.write_record(answer);
+                    // The TTL and RDLENGTH come right before the RDATA.
+                    rdata - 6 - start
+                })
+                .collect();
+            let suffixes = context
+                .suffixes
+                .into_iter()
+                .filter(|(_, offset)| *offset as usize >= start)
+                .collect();
+            EncodedAnswers {
+                answers: Arc::clone(answers),
+                question: question.encode(),
+                bytes: context.bytes[start..].into(),
+                suffixes,
+                ttls: ttls.into(),
+            }
+        }
+
+        // The encoding of `answers`, which are the records encoded here in the same
+        // order, with other TTLs, e.g. decreased by the time they were cached. Only the
+        // TTLs are written.
+        pub fn with_ttls(&self, answers: &Arc<[Answer]>) -> EncodedAnswers {
+            assert_eq!(answers.len(), self.answers.len(), "the same records");
+            let mut bytes = self.bytes.to_vec();
+            for (&offset, answer) in self.ttls.iter().zip(answers.iter()) {
+                bytes[offset..offset + 4].copy_from_slice(&answer.get_ttl().to_be_bytes());
+            }
+            EncodedAnswers {
+                answers: Arc::clone(answers),
+                question: Arc::clone(&self.question),
+                bytes: bytes.into(),
+                suffixes: Arc::clone(&self.suffixes),
+                ttls: Arc::clone(&self.ttls),
+            }
+        }
+
+        pub fn get_answers(&self) -> &Arc<[Answer]> {
+            &self.answers
+        }
+
+        // Whether they were encoded after `question`, casing included.
+        pub fn follows(&self, question: &Question) -> bool {
+            *question.encode() == *self.question
+        }
+
+        // Whether they stand for `answers` after `questions`.
+        fn fits(&self, questions: &[Question], answers: &Arc<[Answer]>) -> bool {
+            Arc::ptr_eq(&self.answers, answers)
+                && matches!(questions, [question] if self.follows(question))
+        }
+    }
+
+    // What is added to a message as it is encoded, as it depends on the encoding.
+    #[derive(Default)]
+    pub struct Appendix<'a> {
+        // Padding to a multiple of a block size, up to a limit (see EncodeContext::pad).
+        pub padding: Option<(usize, usize)>,
+        pub signer: Option<&'a Signer<'a>>,
+    }
+
+    #[derive(Clone, Debug)]
     pub struct Message {
-        header: Rc<Header>,
-        questions: Rc<[Question]>,
-        answers: Rc<[Answer]>,
+        header: Arc<Header>,
+        questions: Arc<[Question]>,
+        answers: Arc<[Answer]>,
+        authorities: Arc<[Answer]>,
+        // Including the OPT pseudo-record (RFC 6891), kept as raw RDATA like the rest.
+        additionals: Arc<[Answer]>,
+        // The answer section encoded beforehand, if it was (see EncodedAnswers).
+        encoded_answers: Option<Arc<EncodedAnswers>>,
     }
 
     impl Message {
+        // A message with the given questions and answers, and the header's counts set
+        // to match.
         pub fn new(
-            header: &Rc<Header>,
-            questions: &Rc<[Question]>,
-            answers: &Rc<[Answer]>,
+            header: &Arc<Header>,
+            questions: &Arc<[Question]>,
+            answers: &Arc<[Answer]>,
         ) -> Message {
+            let mut header = header.as_ref().clone();
+            header
+                .set_qd_count(questions.len() as u16)
+                .set_an_count(answers.len() as u16)
+                .set_ns_count(0)
+                .set_ar_count(0);
             Message {
-                header: Rc::clone(header),
+                header: Arc::new(header),
                 questions: questions.clone(),
                 answers: answers.clone(),
+                authorities: Arc::from([]),
+                additionals: Arc::from([]),
+                encoded_answers: None,
+            }
+        }
+
+        // Returns a copy of the message with the given authority section (and NSCOUNT).
+        pub fn with_authorities(&self, authorities: &Arc<[Answer]>) -> Message {
+            let mut header = self.header.as_ref().clone();
+            header.set_ns_count(authorities.len() as u16);
+            Message {
+                header: Arc::new(header),
+                questions: self.questions.clone(),
+                answers: self.answers.clone(),
+                authorities: authorities.clone(),
+                additionals: self.additionals.clone(),
+                encoded_answers: self.encoded_answers.clone(),
+            }
+        }
+
+        // Returns a copy of the message with the given additional section (and ARCOUNT).
+        pub fn with_additionals(&self, additionals: &Arc<[Answer]>) -> Message {
+            let mut header = self.header.as_ref().clone();
+            header.set_ar_count(additionals.len() as u16);
+            Message {
+                header: Arc::new(header),
+                questions: self.questions.clone(),
+                answers: self.answers.clone(),
+                authorities: self.authorities.clone(),
+                additionals: additionals.clone(),
+                encoded_answers: self.encoded_answers.clone(),
+            }
+        }
+
+        // Returns a copy of the message with the given answer section (and ANCOUNT).
+        pub fn with_answers(&self, answers: &Arc<[Answer]>) -> Message {
+            let mut header = self.header.as_ref().clone();
+            header.set_an_count(answers.len() as u16);
+            Message {
+                header: Arc::new(header),
+                questions: self.questions.clone(),
+                answers: answers.clone(),
+                authorities: self.authorities.clone(),
+                additionals: self.additionals.clone(),
+                encoded_answers: self.encoded_answers.clone(),
+            }
+        }
+
+        // Returns a copy of the message whose answer section is encoded as `encoded`
+        // says, if it still stands for the message's answers, or as usual if None.
+        pub fn with_encoded_answers(&self, encoded: Option<Arc<EncodedAnswers>>) -> Message {
+            Message {
+                encoded_answers: encoded,
+                ..self.clone()
             }
         }
 
-        pub fn get_header(&self) -> &Rc<Header> {
+        // Whether the answer section is copied from an encoding made beforehand.
+        pub fn uses_encoded_answers(&self) -> bool {
+            self.encoded_answers
+                .as_ref()
+                .is_some_and(|encoded| encoded.fits(&self.questions, &self.answers))
+        }
+
+        pub fn get_header(&self) -> &Arc<Header> {
             &self.header
         }
 
-        pub fn get_questions(&self) -> &Rc<[Question]> {
+        pub fn get_questions(&self) -> &Arc<[Question]> {
             &self.questions
         }
 
-        pub fn get_answers(&self) -> &Rc<[Answer]> {
+        pub fn get_answers(&self) -> &Arc<[Answer]> {
             &self.answers
         }
 
-        pub fn encode(&self) -> Rc<[u8]> {
-            let mut result: Vec<u8> = Vec::new();
-            result.extend_from_slice(&self.header.encode());
-            self.questions
+        pub fn get_authorities(&self) -> &Arc<[Answer]> {
+            &self.authorities
+        }
+
+        pub fn get_additionals(&self) -> &Arc<[Answer]> {
+            &self.additionals
+        }
+
+        // The message's OPT record, if it uses EDNS.
+        pub fn get_opt(&self) -> Option<OptRecord> {
+            OptRecord::find(&self.additionals)
+        }
+
+        // The SOA record in the authority section, which negative responses carry.
+        pub fn get_soa(&self) -> Option<&Answer> {
+            self.authorities
                 .iter()
-                .for_each(|question| result.extend_from_slice(&question.encode()));
-            self.answers.iter().for_each(|answer| {
-                result.extend_from_slice(&answer.encode());
-            });
-            result.into()
+                .find(|record| matches!(record.get_rdata(), RData::Soa { .. }))
         }
 
-        pub fn parse_from(data: &[u8]) -> Message {
-            let header: Header =
-                Header::parse_from(data.get(..12).and_then(|s| s.try_into().ok()).expect(
-                    "data array length is less than 12 (12 bytes is the size of DNS header).",
-                ));
-            let payload = &data[12..];
-            let (questions, answers) = Message::parse_questions_and_answers(payload, &header);
+        // How long the message may be cached as a negative response (RFC 2308, 5): the
+        // lower of its SOA record's TTL and MINIMUM, if it has one.
+        pub fn get_negative_ttl(&self) -> Option<u32> {
+            let soa = self.get_soa()?;
+            match soa.get_rdata() {
+                RData::Soa { minimum, .. } => Some(soa.get_ttl().min(*minimum)),
+                _ => None,
+            }
+        }
 
-            Message {
-                header: Rc::new(header),
-                questions: questions,
-                answers: answers,
+        // Encodes the message, with its names compressed (see EncodeContext).
+        pub fn encode(&self) -> Arc<[u8]> {
+            self.encode_with(&Appendix::default())
+        }
+
+        // Encodes the message followed by what `appendix` adds to it.
+        pub fn encode_with(&self, appendix: &Appendix) -> Arc<[u8]> {
+            let mut context = EncodeContext::new(&self.header);
+            for question in self.questions.iter() {
+                context.add_question(question);
             }
+            let encoded = self
+                .encoded_answers
+                .as_ref()
+                .filter(|encoded| encoded.fits(&self.questions, &self.answers));
+            match encoded {
+                Some(encoded) => context.add_encoded_answers(encoded),
+                None => {
+                    for record in self.answers.iter() {
+                        context.add_record(Section::Answer, record);
+                    }
+                }
+            }
+            for (section, records) in [
+                (Section::Authority, &self.authorities),
+                (Section::Additional, &self.additionals),
+            ] {
+                for record in records.iter() {
+                    context.add_record(section, record);
+                }
+            }
+            if let Some((block, limit)) = appendix.padding {
+                context.pad(block, limit);
+            }
+            if let Some(sign) = appendix.signer {
+                context.add_signature(sign);
+            }
+            context.finish()
+        }
+
+        // Returns a copy of the message that encodes to at most `max_size` bytes.
+        // The additional section goes first, without setting TC, as nothing in it is
+        // needed to use the answer (RFC 2181, 9), and the authority section after it.
+        // Then answers are dropped as whole RRsets (together with the RRSIGs covering them)
+        // from the end of the answer section; if a record had to be dropped, TC is set.
+        // Because only a prefix of the RRsets is ever kept, a CNAME is never kept
+        // without the chain links that precede it. The OPT record is always kept.
+        pub fn truncate_to(&self, max_size: usize) -> Message {
+            if self.encode().len() <= max_size {
+                return self.clone();
+            }
+            let without_additionals = self.without_additionals();
+            if without_additionals.encode().len() <= max_size {
+                return without_additionals;
+            }
+            let without_authorities = without_additionals.with_authorities(&Arc::from([]));
+            if without_authorities.encode().len() <= max_size {
+                return without_authorities;
+            }
+
+            // Sizes are measured by encoding, as compression makes a record's size
+            // depend on the names before it.
+            let opt = without_authorities.get_additionals();
+            let mut kept: Vec<Answer> = Vec::new();
+            for rrset in self.group_answers_into_rrsets() {
+                let mut candidate = kept.clone();
+                candidate.extend(rrset);
+                let size = Message::new(&self.header, &self.questions, &candidate.clone().into())
+                    .with_additionals(opt)
+                    .encode()
+                    .len();
+                if size > max_size {
+                    break;
+                }
+                kept = candidate;
+            }
+
+            let mut header = self.header.as_ref().clone();
+            header.set_tc(true);
+            Message::new(&Arc::new(header), &self.questions, &kept.into()).with_additionals(opt)
+        }
+
+        // Returns a copy of the message without its additional records, but for the
+        // OPT record: an EDNS response must carry one (RFC 6891, 7), and with it the
+        // upper bits of its RCODE and its options.
+        pub fn without_additionals(&self) -> Message {
+            let opt: Vec<Answer> = self.get_opt().iter().map(OptRecord::to_answer).collect();
+            self.with_additionals(&opt.into())
+        }
+
+        // Groups answers by (name, type, class), in order of first appearance.
+        // An RRSIG joins the RRset of the same owner whose type it covers.
+        pub fn group_answers_into_rrsets(&self) -> Vec<Vec<Answer>> {
+            const RRSIG: RecordType = RecordType::Unknown(46);
+            let mut keys: Vec<(String, RecordType, RecordClass)> = Vec::new();
+            let mut groups: Vec<Vec<Answer>> = Vec::new();
+            for answer in self.answers.iter() {
+                let data = answer.get_data();
+                let r#type = if answer.get_type() == RRSIG && data.len() >= 2 {
+                    RecordType::from(u16::from_be_bytes([data[0], data[1]]))
+                } else {
+                    answer.get_type()
+                };
+                let key = (
+                    answer.name.to_string().to_ascii_lowercase(),
+                    r#type,
+                    answer.class,
+                );
+                match keys.iter().position(|existing| *existing == key) {
+                    Some(index) => groups[index].push(answer.clone()),
+                    None => {
+                        keys.push(key);
+                        groups.push(vec![answer.clone()]);
+                    }
+                }
+            }
+            groups
+        }
+
+        // Parses the header and all the sections of a message. Anything
+        // malformed is an error rather than a panic, as the data comes off the network.
+        pub fn parse_from(data: &[u8]) -> Result<Message, DnsParseError> {
+            let header: &[u8; 12] = data
+                .get(..12)
+                .and_then(|s| s.try_into().ok())
+                .ok_or(DnsParseError::TruncatedHeader { length: data.len() })?;
+            let header = Header::parse_from(header);
+            let payload = &data[12..];
+            let (questions, answers, authorities, additionals) =
+                Message::parse_sections(payload, &header)?;
+
+            Ok(Message {
+                header: Arc::new(header),
+                questions,
+                answers,
+                authorities,
+                additionals,
+                encoded_answers: None,
+            })
+        }
+
+        // Reads `length` bytes at `index` of the data after the header, or fails with
+        // `error` built from the offset in the whole message.
+        fn take(
+            data: &[u8],
+            index: usize,
+            length: usize,
+            error: impl FnOnce(usize) -> DnsParseError,
+        ) -> Result<&[u8], DnsParseError> {
+            data.get(index..index + length)
+                .ok_or_else(|| error(index + 12))
         }
 
         fn parse_label_sequence(
             data: &[u8],
             label_sequence_start_index: usize,
-        ) -> (Rc<LabelSequence>, usize) {
+        ) -> Result<(Arc<LabelSequence>, usize), DnsParseError> {
+            let overrun = |offset| DnsParseError::LabelOverrun { offset };
             let mut labels: Vec<Label> = Vec::new();
-            let mut compressed_label_index: usize = 0;
+            // Position of the first pointer; the name ends right after it in the data,
+            // however many more pointers are followed.
+            let mut compressed_label_index: Option<usize> = None;
             let mut current_index: usize = label_sequence_start_index;
-            let mut null_byte_found = false;
-            while current_index < data.len() {
-                let control_byte: u8 = data[current_index];
+            // Where the last pointer jumped to (at first, the start of the name): the
+            // next one must jump to before it.
+            let mut pointer_bound: usize = label_sequence_start_index;
+            // The name's length uncompressed, the root label's byte included.
+            let mut name_length: usize = 1;
+            loop {
+                let control_byte: u8 = Message::take(data, current_index, 1, overrun)?[0];
                 match control_byte {
-                    0 => {
-                        null_byte_found = true;
-                        break;
-                    }
+                    0 => break,
                     /* uncompressed label */
-                    1..0xC0 => {
+                    1..0x40 => {
                         let label_length: usize = control_byte as usize;
-                        let content = String::from_utf8(
-                            data[(current_index + 1)..=(current_index + label_length)].to_vec(),
-                        )
-                        .expect("Failed to read label's content");
+                        let content = Message::take(data, current_index + 1, label_length, |_| {
+                            DnsParseError::LabelOverrun {
+                                offset: current_index + 12,
+                            }
+                        })?;
+                        let content = str::from_utf8(content).map_err(|_| {
+                            DnsParseError::InvalidUtf8Label {
+                                offset: current_index + 12,
+                            }
+                        })?;
+                        name_length += label_length + 1;
+                        if name_length > 255 {
+                            return Err(DnsParseError::NameTooLong {
+                                offset: label_sequence_start_index + 12,
+                            });
+                        }
                         labels.push(Label {
                             content: content.into(),
                         });
                         current_index += label_length + 1;
                     }
+                    /* extended (0x40) and unallocated (0x80) label types */
+                    0x40..0xC0 => {
+                        return Err(DnsParseError::ReservedLabelType {
+                            offset: current_index + 12,
+                        });
+                    }
                     /* compressed label */
                     0xC0..=0xFF => {
-                        compressed_label_index = current_index;
-                        // We have to subtract 12, as the compressed offset is relative to the entire message's byte array,
-                        // and 'data' is a slice of it without the header bytes.
-                        let offset_index: u16 = ((((control_byte & 0x3F) as u16) << 8)
-                            | data[current_index + 1] as u16)
-                            - 12;
-                        current_index = offset_index as usize;
+                        let low = Message::take(data, current_index + 1, 1, |_| {
+                            DnsParseError::LabelOverrun {
+                                offset: current_index + 12,
+                            }
+                        })?[0];
+                        compressed_label_index.get_or_insert(current_index);
+                        // The offset is relative to the whole message, and 'data' is a
+                        // slice of it without the 12 header bytes. A pointer must point
+                        // back at a name before it (RFC 1035, 4.1.4). Pointing back at
+                        // the read position isn't enough to end the name: a pointer to
+                        // the start of its own name loops. Each jump has to land before
+                        // the previous one, so the jumps can't go on forever.
+                        let target = (((control_byte & 0x3F) as usize) << 8) | low as usize;
+                        if target < 12 || target - 12 >= pointer_bound {
+                            return Err(DnsParseError::BadPointer {
+                                offset: current_index + 12,
+                                target,
+                            });
+                        }
+                        current_index = target - 12;
+                        pointer_bound = current_index;
                     }
                 }
             }
 
-            assert!(null_byte_found,
-                "Could not parse label sequence starting from index #{}: end of data was reached but no null-byte was found.", 
-                label_sequence_start_index);
-
-            let label_sequence_end_index: usize = if compressed_label_index == 0 {
-                current_index
-            } else {
-                compressed_label_index + 1
+            let label_sequence_end_index: usize = match compressed_label_index {
+                None => current_index,
+                Some(index) => index + 1,
             };
             let length: usize = (label_sequence_end_index - label_sequence_start_index) + 1;
 
-            (
-                Rc::new(LabelSequence {
+            Ok((
+                Arc::new(LabelSequence {
                     labels: labels.into(),
                 }),
                 length,
-            )
+            ))
         }
 
         fn parse_question_section(
             data: &[u8],
             expected_questions_count: u16,
-        ) -> (Rc<[Question]>, usize) {
-            let mut questions_count: u16 = 0;
+        ) -> Result<(Arc<[Question]>, usize), DnsParseError> {
+            if expected_questions_count > MAX_QUESTIONS {
+                return Err(DnsParseError::TooManyQuestions {
+                    count: expected_questions_count,
+                });
+            }
+            let truncated = |offset| DnsParseError::TruncatedRecord { offset };
             let mut current_index: usize = 0;
             let mut questions: Vec<Question> = Vec::new();
-            while current_index < data.len() && questions_count < expected_questions_count {
+            for _ in 0..expected_questions_count {
                 let (label_sequence, label_sequence_length) =
-                    Message::parse_label_sequence(data, current_index);
+                    Message::parse_label_sequence(data, current_index)?;
                 current_index += label_sequence_length;
 
-                let r#type = ((data[current_index] as u16) << 8) | (data[current_index + 1] as u16);
-                current_index += 2;
-
-                let class = ((data[current_index] as u16) << 8) | (data[current_index + 1] as u16);
-                current_index += 2;
+                let fields = Message::take(data, current_index, 4, truncated)?;
+                current_index += 4;
 
                 questions.push(Question {
                     name: label_sequence,
-                    r#type: r#type,
-                    class: class,
+                    r#type: RecordType::from(u16::from_be_bytes([fields[0], fields[1]])),
+                    class: RecordClass::from(u16::from_be_bytes([fields[2], fields[3]])),
                 });
-                questions_count += 1;
             }
 
-            assert!(
-                questions_count == expected_questions_count,
-                "Expected to have {} questions but was able to parse {}.",
-                expected_questions_count,
-                questions_count
-            );
-
-            (questions.into(), current_index)
+            Ok((questions.into(), current_index))
         }
 
         fn parse_answer_section(
             data: &[u8],
             section_start_index: usize,
             expected_answers_count: u16,
-        ) -> (Rc<[Answer]>, usize) {
-            let mut answers_count: u16 = 0;
+        ) -> Result<(Arc<[Answer]>, usize), DnsParseError> {
+            let truncated = |offset| DnsParseError::TruncatedRecord { offset };
             let mut current_index: usize = section_start_index;
             let mut answers: Vec<Answer> = Vec::new();
-            while current_index < data.len() && answers_count < expected_answers_count {
+            for _ in 0..expected_answers_count {
                 let (label_sequence, label_sequence_length) =
-                    Message::parse_label_sequence(data, current_index);
+                    Message::parse_label_sequence(data, current_index)?;
                 current_index += label_sequence_length;
 
-                let r#type: u16 =
-                    ((data[current_index] as u16) << 8) | (data[current_index + 1] as u16);
-                current_index += 2;
-
-                let class: u16 =
-                    ((data[current_index] as u16) << 8) | (data[current_index + 1] as u16);
-                current_index += 2;
-
-                let ttl: u32 = ((data[current_index] as u32) << 24)
-                    | ((data[current_index + 1] as u32) << 16)
-                    | ((data[current_index + 2] as u32) << 8)
-                    | (data[current_index + 3] as u32);
-                current_index += 4;
-
-                let data_length: usize = (((data[current_index] as u16) << 8)
-                    | (data[current_index + 1] as u16))
-                    as usize;
-                current_index += 2;
+                let fields = Message::take(data, current_index, 10, truncated)?;
+                current_index += 10;
+                let data_length = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+                let rdata = Message::take(data, current_index, data_length, truncated)?;
+                let r#type = RecordType::from(u16::from_be_bytes([fields[0], fields[1]]));
+                let rdata: Arc<[u8]> = match rdata_layout(r#type) {
+                    Some(layout) => {
+                        Message::decompress_rdata(data, current_index, data_length, layout)?
+                    }
+                    None => rdata.into(),
+                };
 
                 answers.push(Answer {
                     name: label_sequence,
-                    r#type: r#type,
-                    class: class,
-                    ttl: ttl,
-                    data: data[current_index..(current_index + data_length)].into(),
+                    class: RecordClass::from(u16::from_be_bytes([fields[2], fields[3]])),
+                    ttl: u32::from_be_bytes([fields[4], fields[5], fields[6], fields[7]]),
+                    data: RData::decode(r#type, &rdata),
                 });
                 current_index += data_length;
-                answers_count += 1;
             }
 
-            assert!(
-                answers_count == expected_answers_count,
-                "Expected to have {} answers but was able to parse {}.",
-                expected_answers_count,
-                answers_count
-            );
-
-            (answers.into(), current_index)
+            Ok((answers.into(), current_index))
         }
 
-        fn parse_questions_and_answers(
+        // Reads the RDATA at `start`, laid out as `layout`, with its names decompressed
+        // against the whole message, so that the record means the same in any other.
+        fn decompress_rdata(
             data: &[u8],
-            header: &Header,
-        ) -> (Rc<[Question]>, Rc<[Answer]>) {
+            start: usize,
+            length: usize,
+            layout: &[RdataField],
+        ) -> Result<Arc<[u8]>, DnsParseError> {
+            let bad = DnsParseError::BadRdata { offset: start + 12 };
+            let end = start + length;
+            let mut rdata: Vec<u8> = Vec::new();
+            let mut index = start;
+            for field in layout {
+                match field {
+                    RdataField::Name => {
+                        let (name, name_length) = Message::parse_label_sequence(data, index)?;
+                        rdata.extend_from_slice(&name.encode());
+                        index += name_length;
+                    }
+                    RdataField::Fixed(size) => {
+                        let bytes = data.get(index..index + size).ok_or(bad.clone())?;
+                        rdata.extend_from_slice(bytes);
+                        index += size;
+                    }
+                }
+                if index > end {
+                    return Err(bad);
+                }
+            }
+            if index != end {
+                return Err(bad);
+            }
+            Ok(rdata.into())
+        }
+
+        fn parse_sections(data: &[u8], header: &Header) -> Result<Sections, DnsParseError> {
             let (qd, question_section_end_index) =
-                Message::parse_question_section(data, header.get_qd_count());
-            let (an, _) = Message::parse_answer_section(
+                Message::parse_question_section(data, header.get_qd_count())?;
+            let (an, answer_section_end_index) = Message::parse_answer_section(
                 data,
                 question_section_end_index,
                 header.get_an_count(),
-            );
-            (qd, an)
+            )?;
+            let (ns, authority_section_end_index) = Message::parse_answer_section(
+                data,
+                answer_section_end_index,
+                header.get_ns_count(),
+            )?;
+            let (ar, additional_section_end_index) = Message::parse_answer_section(
+                data,
+                authority_section_end_index,
+                header.get_ar_count(),
+            )?;
+            if additional_section_end_index < data.len() {
+                return Err(DnsParseError::TrailingGarbage {
+                    offset: additional_section_end_index + 12,
+                });
+            }
+            Ok((qd, an, ns, ar))
         }
     }
 
@@ -770,12 +2698,31 @@ pub mod message {
                 .iter()
                 .map(|question| question.to_string())
                 .collect();
-            let question_section = format!("QUESTION SECTION:\n;; {}", questions.join("\n;;"));
+            let question_section = format!("QUESTION SECTION:\n{}", questions.join("\n"));
 
             let answers: Vec<String> = self.answers.iter().map(Answer::to_string).collect();
             let answer_section = format!("ANSWER SECTION:\n;; {}", answers.join("\n;; "));
 
-            write!(f, "{header}\n;\n;; {question_section}\n;; {answer_section}")
+            write!(f, "{header}\n;\n;; {question_section}\n;; {answer_section}")?;
+            if !self.authorities.is_empty() {
+                let authorities: Vec<String> =
+                    self.authorities.iter().map(Answer::to_string).collect();
+                write!(
+                    f,
+                    "\n;; AUTHORITY SECTION:\n;; {}",
+                    authorities.join("\n;; ")
+                )?;
+            }
+            if !self.additionals.is_empty() {
+                let additionals: Vec<String> =
+                    self.additionals.iter().map(Answer::to_string).collect();
+                write!(
+                    f,
+                    "\n;; ADDITIONAL SECTION:\n;; {}",
+                    additionals.join("\n;; ")
+                )?;
+            }
+            Ok(())
         }
     }
 }
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use super::dns::message::{Answer, LabelSequence, LabelSequenceParseError, RData, RecordClass};
+
+// TTL of the records generated for registered services (RFC 6762, 10).
+const SERVICE_TTL: u32 = 120;
+
+// A DNS-SD service instance (RFC 6763), given on the command line as
+// "instance:service-type:target:port[:key=value,...]",
+// e.g. "web:_http._tcp:host.lan:8080:path=/".
+#[derive(Clone, Debug)]
+pub struct ServiceRegistration {
+    instance: String,
+    service_type: String,
+    target: String,
+    port: u16,
+    txt: Vec<String>,
+}
+
+impl ServiceRegistration {
+    pub fn parse(spec: &str) -> Result<ServiceRegistration, String> {
+        let parts: Vec<&str> = spec.splitn(5, ':').collect();
+        if parts.len() < 4 {
+            return Err(format!(
+                "Service '{}' must look like instance:_service._proto:target:port[:key=value,...].",
+                spec
+            ));
+        }
+
+        let instance = parts[0].to_string();
+        if instance.is_empty() || instance.len() > 63 || instance.contains('.') {
+            return Err(format!(
+                "Service instance name '{}' must be a single label of 1 to 63 bytes.",
+                instance
+            ));
+        }
+
+        let service_type = parts[1].trim_end_matches('.').to_string();
+        let service_labels: Vec<&str> = service_type.split('.').collect();
+        if service_labels.len() != 2
+            || !service_labels[0].starts_with('_')
+            || service_labels[0].len() < 2
+            || !matches!(service_labels[1], "_tcp" | "_udp")
+        {
+            return Err(format!(
+                "Service type '{}' must look like _service._tcp or _service._udp.",
+                service_type
+            ));
+        }
+
+        let target = parts[2].to_string();
+        target
+            .parse::<LabelSequence>()
+            .map_err(|err: LabelSequenceParseError| err.message)?;
+        let port: u16 = parts[3]
+            .parse()
+            .map_err(|_| format!("Service port '{}' is not a valid port number.", parts[3]))?;
+        let txt: Vec<String> = match parts.get(4) {
+            Some(pairs) if !pairs.is_empty() => pairs.split(',').map(str::to_string).collect(),
+            _ => Vec::new(),
+        };
+        if let Some(entry) = txt.iter().find(|entry| entry.len() > 255) {
+            return Err(format!("TXT entry '{}' is longer than 255 bytes.", entry));
+        }
+
+        Ok(ServiceRegistration {
+            instance,
+            service_type,
+            target,
+            port,
+            txt,
+        })
+    }
+
+    // Expands the registration into the records a DNS-SD browser walks through:
+    //   _services._dns-sd._udp.<domain> PTR <service-type>.<domain>
+    //   <service-type>.<domain>          PTR <instance>.<service-type>.<domain>
+    //   <instance>.<service-type>.<domain> SRV 0 0 <port> <target>
+    //   <instance>.<service-type>.<domain> TXT <key=value>...
+    pub fn records(&self, domain: &str) -> Result<Vec<Answer>, String> {
+        let name = |prefix: &str| -> Result<Arc<LabelSequence>, String> {
+            format!("{}.{}", prefix, domain)
+                .parse()
+                .map(Arc::new)
+                .map_err(|err: LabelSequenceParseError| err.message)
+        };
+        let enumeration_name = name("_services._dns-sd._udp")?;
+        let service_name = name(&self.service_type)?;
+        let instance_name = name(&format!("{}.{}", self.instance, self.service_type))?;
+        let target: LabelSequence = self
+            .target
+            .parse()
+            .map_err(|err: LabelSequenceParseError| err.message)?;
+
+        // An empty TXT record holds a single zero-length string (RFC 6763, 6.1).
+        let mut txt: Vec<Arc<str>> = self
+            .txt
+            .iter()
+            .map(|entry| Arc::from(entry.as_str()))
+            .collect();
+        if txt.is_empty() {
+            txt.push(Arc::from(""));
+        }
+
+        // The SRV target is never compressed (RFC 2782); EncodeContext leaves it be.
+        Ok(vec![
+            Answer::from_rdata(
+                /* name= */ &enumeration_name,
+                /* class= */ RecordClass::In,
+                /* ttl= */ SERVICE_TTL,
+                /* data= */ RData::Ptr(service_name.as_ref().clone()),
+            ),
+            Answer::from_rdata(
+                /* name= */ &service_name,
+                /* class= */ RecordClass::In,
+                /* ttl= */ SERVICE_TTL,
+                /* data= */ RData::Ptr(instance_name.as_ref().clone()),
+            ),
+            Answer::from_rdata(
+                /* name= */ &instance_name,
+                /* class= */ RecordClass::In,
+                /* ttl= */ SERVICE_TTL,
+                /* data= */
+                RData::Srv {
+                    priority: 0,
+                    weight: 0,
+                    port: self.port,
+                    target,
+                },
+            ),
+            Answer::from_rdata(
+                /* name= */ &instance_name,
+                /* class= */ RecordClass::In,
+                /* ttl= */ SERVICE_TTL,
+                /* data= */ RData::Txt(txt),
+            ),
+        ])
+    }
+}
@@ -0,0 +1,343 @@
+// DNS Stateful Operations (RFC 8490): the session framework and its Keepalive and
+// Retry Delay TLVs. DSO is only allowed over connections; push notifications
+// (RFC 8765) are not supported.
+
+use std::time::Duration;
+
+use super::dns::message::{Header, OpCode, RCode};
+use super::overload::Load;
+
+const KEEPALIVE: u16 = 1;
+const RETRY_DELAY: u16 = 2;
+const ENCRYPTION_PADDING: u16 = 3;
+
+// Longest inactivity timeout granted to a client (RFC 8490, 6.2).
+const MAX_INACTIVITY_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Shortest keepalive interval allowed (RFC 8490, 6.5.2).
+const MIN_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// How long clients refused under load are asked to wait before trying again.
+const OVERLOAD_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// The DSO state of one connection.
+pub struct DsoSession {
+    // Set once a DSO request has been acknowledged (RFC 8490, 5.1).
+    pub established: bool,
+    pub inactivity_timeout: Duration,
+    pub keepalive_interval: Duration,
+}
+
+impl Default for DsoSession {
+    // The timeouts before any were negotiated (RFC 8490, 6.2).
+    fn default() -> DsoSession {
+        DsoSession {
+            established: false,
+            inactivity_timeout: Duration::from_secs(15),
+            keepalive_interval: Duration::from_secs(3600),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tlv {
+    pub r#type: u16,
+    pub data: Vec<u8>,
+}
+
+impl Tlv {
+    fn keepalive(inactivity_timeout: Duration, keepalive_interval: Duration) -> Tlv {
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(&milliseconds(inactivity_timeout).to_be_bytes());
+        data.extend_from_slice(&milliseconds(keepalive_interval).to_be_bytes());
+        Tlv {
+            r#type: KEEPALIVE,
+            data,
+        }
+    }
+
+    fn retry_delay(delay: Duration) -> Tlv {
+        Tlv {
+            r#type: RETRY_DELAY,
+            data: milliseconds(delay).to_be_bytes().to_vec(),
+        }
+    }
+}
+
+fn milliseconds(duration: Duration) -> u32 {
+    duration.as_millis().min(u32::MAX as u128) as u32
+}
+
+// Parses the TLVs following the (empty) sections of a DSO message.
+pub fn parse_tlvs(mut data: &[u8]) -> Result<Vec<Tlv>, String> {
+    let mut tlvs: Vec<Tlv> = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 4 {
+            return Err(format!(
+                "{} trailing byte(s) after the last TLV",
+                data.len()
+            ));
+        }
+        let r#type = u16::from_be_bytes([data[0], data[1]]);
+        let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let value = data
+            .get(4..4 + length)
+            .ok_or_else(|| format!("TLV {} is longer than the message", r#type))?;
+        tlvs.push(Tlv {
+            r#type,
+            data: value.to_vec(),
+        });
+        data = &data[4 + length..];
+    }
+    Ok(tlvs)
+}
+
+pub fn encode_tlvs(tlvs: &[Tlv]) -> Vec<u8> {
+    let mut result: Vec<u8> = Vec::new();
+    for tlv in tlvs {
+        result.extend_from_slice(&tlv.r#type.to_be_bytes());
+        result.extend_from_slice(&(tlv.data.len() as u16).to_be_bytes());
+        result.extend_from_slice(&tlv.data);
+    }
+    result
+}
+
+// What to do with a DSO message received on a connection.
+#[derive(Debug, PartialEq)]
+pub enum DsoOutcome {
+    Respond(Vec<u8>),
+    // A fatal protocol error: the connection must be closed (RFC 8490, 5.3).
+    Abort(String),
+}
+
+fn response(request: &Header, rcode: RCode, tlvs: &[Tlv]) -> DsoOutcome {
+    let mut header = Header::default();
+    header
+        .set_id(request.get_id())
+        .set_qr(true)
+        .set_opcode(OpCode::DnsStatefulOperations)
+        .set_rcode(rcode);
+    let mut data = header.encode().to_vec();
+    data.extend(encode_tlvs(tlvs));
+    DsoOutcome::Respond(data)
+}
+
+// Handles a DSO message received on a connection with the given session. A Keepalive
+// request establishes the session, and its timeouts are granted within our limits;
+// under load it is refused with a Retry Delay instead. Requests with other primary
+// TLVs get DSOTYPENI. Clients may not send unidirectional messages (Keepalive and
+// Retry Delay are server-to-client only that way) and we send no requests, so
+// either aborts the connection.
+pub fn respond(session: &mut DsoSession, data: &[u8], load: Load) -> DsoOutcome {
+    let header = Header::parse_from(
+        data.get(..12)
+            .and_then(|s| s.try_into().ok())
+            .expect("DSO messages are at least a header long"),
+    );
+    if header.get_qr() {
+        return DsoOutcome::Abort(String::from("a DSO response to a request never sent"));
+    }
+    if header.get_id() == 0 {
+        return DsoOutcome::Abort(String::from("a unidirectional DSO message from a client"));
+    }
+    // All four section counts must be zero (RFC 8490, 5.4).
+    if data[4..12].iter().any(|&byte| byte != 0) {
+        return response(&header, RCode::FormatError, &[]);
+    }
+    let tlvs = match parse_tlvs(&data[12..]) {
+        Ok(tlvs) if !tlvs.is_empty() => tlvs,
+        _ => return response(&header, RCode::FormatError, &[]),
+    };
+
+    let primary = &tlvs[0];
+    match primary.r#type {
+        KEEPALIVE => {
+            let Ok(values) = <[u8; 8]>::try_from(primary.data.as_slice()) else {
+                return response(&header, RCode::FormatError, &[]);
+            };
+            if load != Load::Normal {
+                return response(
+                    &header,
+                    RCode::ServerError,
+                    &[Tlv::retry_delay(OVERLOAD_RETRY_DELAY)],
+                );
+            }
+            let inactivity = u32::from_be_bytes(values[..4].try_into().unwrap());
+            let interval = u32::from_be_bytes(values[4..].try_into().unwrap());
+            session.inactivity_timeout =
+                Duration::from_millis(inactivity as u64).min(MAX_INACTIVITY_TIMEOUT);
+            session.keepalive_interval =
+                Duration::from_millis(interval as u64).max(MIN_KEEPALIVE_INTERVAL);
+            session.established = true;
+            response(
+                &header,
+                RCode::NoError,
+                &[Tlv::keepalive(
+                    session.inactivity_timeout,
+                    session.keepalive_interval,
+                )],
+            )
+        }
+        // Only ever sent by servers, or only allowed as an additional TLV.
+        RETRY_DELAY | ENCRYPTION_PADDING => response(&header, RCode::FormatError, &[]),
+        _ => response(&header, RCode::DsoTypeNotImplemented, &[]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpStream, UdpSocket},
+    };
+
+    use super::super::testing::dummy_server;
+    use super::super::{DnsServer, LoopState};
+    use super::*;
+
+    fn request(id: u16, tlvs: &[Tlv]) -> Vec<u8> {
+        let mut header = Header::default();
+        header.set_id(id).set_opcode(OpCode::DnsStatefulOperations);
+        let mut data = header.encode().to_vec();
+        data.extend(encode_tlvs(tlvs));
+        data
+    }
+
+    fn keepalive(inactivity_ms: u32, interval_ms: u32) -> Tlv {
+        Tlv::keepalive(
+            Duration::from_millis(inactivity_ms as u64),
+            Duration::from_millis(interval_ms as u64),
+        )
+    }
+
+    // Sends `message` over the connection and runs the server until it responds.
+    fn exchange(
+        server: &DnsServer,
+        state: &mut LoopState,
+        client: &mut TcpStream,
+        message: &[u8],
+    ) -> (Header, Vec<Tlv>) {
+        let mut data = (message.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(message);
+        client.write_all(&data).unwrap();
+        client.set_nonblocking(true).unwrap();
+        for _ in 0..5 {
+            server.run_once(state).unwrap();
+            if client.peek(&mut [0; 2]).is_ok() {
+                break;
+            }
+        }
+        client.set_nonblocking(false).unwrap();
+        let mut length = [0; 2];
+        client.read_exact(&mut length).unwrap();
+        let mut response = vec![0; u16::from_be_bytes(length) as usize];
+        client.read_exact(&mut response).unwrap();
+        let header = Header::parse_from(response[..12].try_into().unwrap());
+        (header, parse_tlvs(&response[12..]).unwrap())
+    }
+
+    #[test]
+    fn keepalive_establishes_a_session_with_negotiated_timeouts() {
+        let server = dummy_server();
+        let mut state = LoopState::new(&server.randomness);
+        let address = server.listeners[0].stream_listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+
+        // Asks for a longer inactivity timeout and a shorter interval than allowed.
+        let (header, tlvs) = exchange(
+            &server,
+            &mut state,
+            &mut client,
+            &request(7, &[keepalive(600_000, 1_000)]),
+        );
+        assert_eq!(header.get_id(), 7);
+        assert!(header.get_qr());
+        assert_eq!(*header.get_opcode(), OpCode::DnsStatefulOperations);
+        assert_eq!(*header.get_rcode(), RCode::NoError);
+        assert_eq!(tlvs, [keepalive(300_000, 10_000)]);
+        assert_eq!(state.connections.len(), 1);
+        assert!(state.connections[0].dso.established);
+        assert_eq!(
+            state.connections[0].dso.inactivity_timeout,
+            Duration::from_secs(300)
+        );
+
+        // Within limits, the client's values are granted as asked.
+        let (_, tlvs) = exchange(
+            &server,
+            &mut state,
+            &mut client,
+            &request(8, &[keepalive(20_000, 15_000)]),
+        );
+        assert_eq!(tlvs, [keepalive(20_000, 15_000)]);
+
+        // An unknown primary TLV gets DSOTYPENI; padding after it is ignored.
+        let unknown = Tlv {
+            r#type: 0xF000,
+            data: vec![1, 2, 3],
+        };
+        let padding = Tlv {
+            r#type: ENCRYPTION_PADDING,
+            data: vec![0; 8],
+        };
+        let (header, tlvs) = exchange(
+            &server,
+            &mut state,
+            &mut client,
+            &request(9, &[unknown, padding]),
+        );
+        assert_eq!(*header.get_rcode(), RCode::DsoTypeNotImplemented);
+        assert!(tlvs.is_empty());
+    }
+
+    #[test]
+    fn unidirectional_messages_from_clients_abort_the_session() {
+        let mut session = DsoSession::default();
+        let outcome = respond(
+            &mut session,
+            &request(0, &[keepalive(1_000, 1_000)]),
+            Load::Normal,
+        );
+        assert!(matches!(outcome, DsoOutcome::Abort(_)));
+        assert!(!session.established);
+    }
+
+    #[test]
+    fn keepalive_is_refused_with_a_retry_delay_under_load() {
+        let mut session = DsoSession::default();
+        let outcome = respond(
+            &mut session,
+            &request(1, &[keepalive(1_000, 1_000)]),
+            Load::Overloaded,
+        );
+        let DsoOutcome::Respond(data) = outcome else {
+            panic!("expected a response");
+        };
+        assert_eq!(data[3] & 0x0F, 2);
+        assert_eq!(
+            parse_tlvs(&data[12..]).unwrap(),
+            [Tlv::retry_delay(OVERLOAD_RETRY_DELAY)]
+        );
+        assert!(!session.established);
+    }
+
+    #[test]
+    fn dso_over_udp_is_a_format_error() {
+        let server = dummy_server();
+        let mut state = LoopState::new(&server.randomness);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .send_to(
+                &request(3, &[keepalive(1_000, 1_000)]),
+                server.listeners[0].socket.local_addr().unwrap(),
+            )
+            .unwrap();
+        server.run_once(&mut state).unwrap();
+        let mut response = [0; 512];
+        let size = client.recv(&mut response).unwrap();
+        let header = Header::parse_from(response[..12].try_into().unwrap());
+        assert_eq!(header.get_id(), 3);
+        assert_eq!(*header.get_rcode(), RCode::FormatError);
+        assert_eq!(size, 12);
+    }
+}
@@ -0,0 +1,445 @@
+use super::dns::message::{Answer, Message, OptRecord, RCode, PADDING_OPTION};
+
+// UDP payload size advertised in the OPT records we send (DNS Flag Day 2020), and the
+// largest UDP response we send to EDNS clients that can take more.
+pub const UDP_PAYLOAD_SIZE: u16 = 1232;
+
+// Block size padded responses are a multiple of.
+const RESPONSE_PADDING_BLOCK: usize = 468;
+
+// Maximum size of a DNS message carried over UDP without EDNS (RFC 1035, 4.2.1).
+const MAX_UDP_MESSAGE_SIZE: usize = 512;
+
+// The Extended DNS Error option and the info-codes we use (RFC 8914).
+const EXTENDED_DNS_ERROR: u16 = 15;
+pub const EDE_OTHER: u16 = 0;
+pub const EDE_FORGED_ANSWER: u16 = 4;
+pub const EDE_NOT_READY: u16 = 14;
+pub const EDE_CENSORED: u16 = 16;
+pub const EDE_FILTERED: u16 = 17;
+
+// Longest EXTRA-TEXT we send, in bytes; rule names can be long, and the text is
+// only meant for the logs of whoever is debugging.
+const MAX_EXTRA_TEXT: usize = 64;
+
+// The request's OPT record, if it has one.
+fn request_opt(request: &[u8]) -> Option<OptRecord> {
+    Message::parse_from(request).ok()?.get_opt()
+}
+
+// Codes of the EDNS options in the request's OPT record, or None if it has no OPT
+// record.
+pub fn request_option_codes(request: &[u8]) -> Option<Vec<u16>> {
+    let opt = request_opt(request)?;
+    Some(opt.get_options().iter().map(|(code, _)| *code).collect())
+}
+
+// The EDNS version of the request, or None if it has no OPT record.
+pub fn request_edns_version(request: &[u8]) -> Option<u8> {
+    request_opt(request).map(|opt| opt.get_version())
+}
+
+// How large a UDP response to `request` may be: what the client advertised in its
+// OPT record, up to `max_size` (--max-udp-size), and never less than the 512 bytes
+// every client takes (RFC 6891, 6.2.5).
+pub fn udp_response_limit(request: &[u8], max_size: usize) -> usize {
+    request_opt(request).map_or(MAX_UDP_MESSAGE_SIZE, |opt| {
+        (opt.get_udp_payload_size() as usize)
+            .clamp(MAX_UDP_MESSAGE_SIZE, max_size.max(MAX_UDP_MESSAGE_SIZE))
+    })
+}
+
+// Whether the request has the DO (DNSSEC OK) bit set in its OPT record (RFC 3225, 3).
+pub fn request_dnssec_ok(request: &[u8]) -> bool {
+    request_opt(request).is_some_and(|opt| opt.get_do())
+}
+
+// How a response to `request` is padded, as (block, limit): to blocks of 468 bytes
+// (RFC 8467, 4.1) if the request was padded itself (RFC 7830, 4), but never past
+// `limit`; not at all otherwise.
+pub fn response_padding(request: &[u8], limit: usize) -> Option<(usize, usize)> {
+    request_option_codes(request)?
+        .contains(&PADDING_OPTION)
+        .then_some((RESPONSE_PADDING_BLOCK, limit))
+}
+
+// Splits an extended RCODE into its lower 4 bits, for the header, and its upper 8,
+// for the OPT record.
+pub fn split_extended_rcode(rcode: &RCode) -> (u8, u8) {
+    let value = u16::from(rcode);
+    ((value & 0x0F) as u8, (value >> 4) as u8)
+}
+
+fn join_extended_rcode(header_bits: u8, opt_bits: u8) -> u16 {
+    ((opt_bits as u16) << 4) | (header_bits & 0x0F) as u16
+}
+
+// Returns the response with `rcode` set: the lower 4 bits in the header and, for an
+// extended RCODE, the upper 8 in the OPT record, which is added if the response has
+// none. Fails for an extended RCODE if the client didn't use EDNS, as it then can't
+// be expressed.
+pub fn with_rcode(response: &Message, rcode: &RCode, client_edns: bool) -> Result<Message, String> {
+    let (header_bits, opt_bits) = split_extended_rcode(rcode);
+    if rcode.is_extended() && !client_edns {
+        return Err(format!("{} can't be sent to a client without EDNS", rcode));
+    }
+    let mut header = response.get_header().as_ref().clone();
+    header.set_rcode(RCode::try_from(header_bits).expect("4 bits make a valid RCODE"));
+    let response = Message::new(
+        &header.into(),
+        response.get_questions(),
+        response.get_answers(),
+    )
+    .with_authorities(response.get_authorities())
+    .with_additionals(response.get_additionals());
+    if !rcode.is_extended() && response.get_opt().is_none() {
+        return Ok(response);
+    }
+
+    let with_bits = |mut opt: OptRecord| opt.set_extended_rcode(opt_bits).to_answer();
+    let mut additionals: Vec<Answer> = response
+        .get_additionals()
+        .iter()
+        .map(|record| match OptRecord::from_answer(record) {
+            Some(opt) => with_bits(opt),
+            None => record.clone(),
+        })
+        .collect();
+    if response.get_opt().is_none() {
+        additionals.push(with_bits(OptRecord::new(UDP_PAYLOAD_SIZE)));
+    }
+    Ok(response.with_additionals(&additionals.into()))
+}
+
+// The full RCODE of a message, taking the OPT record's upper bits into account.
+pub fn extended_rcode(message: &Message) -> RCode {
+    let header_bits = u16::from(message.get_header().get_rcode()) as u8;
+    let opt_bits = message.get_opt().map_or(0, |opt| opt.get_extended_rcode());
+    RCode::try_from(join_extended_rcode(header_bits, opt_bits)).expect("12 bits make a valid RCODE")
+}
+
+// The OPT record for the response to `request`: ours, without options, if the client
+// sent one, so that it knows we speak EDNS (RFC 6891, 7).
+pub fn response_opt(request: &Message) -> Option<Answer> {
+    request
+        .get_opt()
+        .map(|_| OptRecord::new(UDP_PAYLOAD_SIZE).to_answer())
+}
+
+// An OPT record carrying an Extended DNS Error (RFC 8914, 2). The EXTRA-TEXT is cut
+// to MAX_EXTRA_TEXT bytes, on a character boundary, as it must stay valid UTF-8.
+pub fn extended_error(info_code: u16, extra_text: &str) -> Answer {
+    let mut length = extra_text.len().min(MAX_EXTRA_TEXT);
+    while !extra_text.is_char_boundary(length) {
+        length -= 1;
+    }
+    let mut option_data: Vec<u8> = info_code.to_be_bytes().to_vec();
+    option_data.extend_from_slice(&extra_text.as_bytes()[..length]);
+    opt_record(EXTENDED_DNS_ERROR, &option_data)
+}
+
+// An OPT record (RFC 6891, 6.1.2) carrying a single option.
+pub fn opt_record(code: u16, option_data: &[u8]) -> Answer {
+    OptRecord::new(UDP_PAYLOAD_SIZE)
+        .add_option(code, option_data)
+        .to_answer()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpStream, UdpSocket},
+        sync::Arc,
+    };
+
+    use super::super::dns::message::{Header, Question, RecordClass, RecordType};
+    use super::super::testing::{dummy_server, query, query_handler};
+    use super::super::verify::parse_strict;
+    use super::super::{DnsServer, LoopState, StaticDnsResolver, StaticRecords};
+    use super::*;
+
+    // "example.com IN A" as sent by dig with its defaults: an OPT record with a
+    // 4096-byte UDP payload size and a client cookie.
+    const DIG_QUERY: [u8; 52] = [
+        0x4d, 0x2f, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x07, b'e', b'x',
+        b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01, 0x00,
+        0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x0a, 0x00, 0x08, 0x3a,
+        0x9c, 0x5e, 0x1d, 0x7b, 0x02, 0xc4, 0xf8,
+    ];
+
+    fn response() -> Message {
+        let mut header = Header::default();
+        header.set_id(7).set_qr(true).set_qd_count(1);
+        let question = Question::new(
+            &Arc::new("example.com".parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        );
+        Message::new(&header.into(), &Arc::from([question]), &Arc::from([]))
+    }
+
+    #[test]
+    fn extended_rcodes_round_trip_split_between_header_and_opt() {
+        for (rcode, header_bits, opt_bits) in [
+            (RCode::BadVersion, 0x0, 0x01),
+            (RCode::BadCookie, 0x7, 0x01),
+            (RCode::Extended(0xABC), 0xC, 0xAB),
+        ] {
+            let encoded = with_rcode(&response(), &rcode, true).unwrap().encode();
+            assert_eq!(encoded[3] & 0x0F, header_bits, "{}", rcode);
+            // The OPT record is last: root name, type, class, then the TTL field.
+            let ttl = &encoded[encoded.len() - 6..encoded.len() - 2];
+            assert_eq!(ttl, [opt_bits, 0, 0, 0], "{}", rcode);
+
+            let parsed = parse_strict(&encoded).unwrap();
+            assert_eq!(parsed.get_opt().unwrap().get_extended_rcode(), opt_bits);
+            assert_eq!(
+                u16::from(parsed.get_header().get_rcode()),
+                header_bits as u16
+            );
+            assert_eq!(extended_rcode(&parsed), rcode);
+        }
+    }
+
+    #[test]
+    fn existing_opt_records_are_augmented_and_plain_rcodes_left_alone() {
+        let response = response().with_additionals(&Arc::from([extended_error(EDE_NOT_READY, "")]));
+        let with_bad_cookie = with_rcode(&response, &RCode::BadCookie, true).unwrap();
+        assert_eq!(with_bad_cookie.get_additionals().len(), 1);
+        assert_eq!(
+            with_bad_cookie.get_additionals()[0].get_data(),
+            response.get_additionals()[0].get_data()
+        );
+        assert_eq!(extended_rcode(&with_bad_cookie), RCode::BadCookie);
+
+        // Back to a 4-bit code: the OPT record's upper bits are cleared.
+        let refused = with_rcode(&with_bad_cookie, &RCode::Refused, true).unwrap();
+        assert_eq!(extended_rcode(&refused), RCode::Refused);
+        assert_eq!(refused.get_additionals()[0].get_ttl(), 0);
+
+        assert!(with_rcode(&self::response(), &RCode::ServerError, false)
+            .unwrap()
+            .get_additionals()
+            .is_empty());
+        assert!(with_rcode(&response, &RCode::BadVersion, false).is_err());
+    }
+
+    #[test]
+    fn unsupported_edns_versions_get_badvers() {
+        let server = dummy_server();
+        let mut state = LoopState::new(&server.randomness);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut request = query("example.com", Some(&[]));
+        // The OPT record's TTL field is {extended RCODE, version, flags}.
+        let version = request.len() - 5;
+        request[version] = 1;
+        client
+            .send_to(&request, server.listeners[0].socket.local_addr().unwrap())
+            .unwrap();
+        server.run_once(&mut state).unwrap();
+        let mut buf = [0; 512];
+        let size = client.recv(&mut buf).unwrap();
+        let response = parse_strict(&buf[..size]).unwrap();
+        assert_eq!(extended_rcode(&response), RCode::BadVersion);
+        assert!(response.get_answers().is_empty());
+        assert_eq!(request_edns_version(&buf[..size]), Some(0));
+    }
+
+    #[test]
+    fn opt_records_are_parsed_and_answered_with_ours() {
+        let request = Message::parse_from(&DIG_QUERY).unwrap();
+        let [opt] = request.get_additionals().as_ref() else {
+            panic!("expected one additional record");
+        };
+        assert_eq!(opt.get_type(), OptRecord::TYPE);
+        assert_eq!(u16::from(opt.get_class()), 4096);
+        assert_eq!(request.encode().as_ref(), DIG_QUERY);
+        assert_eq!(request_option_codes(&DIG_QUERY), Some(vec![10]));
+        assert_eq!(request_edns_version(&DIG_QUERY), Some(0));
+
+        let server = dummy_server();
+        let mut state = LoopState::new(&server.randomness);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        let mut buf = [0; 512];
+        client.send_to(&DIG_QUERY, address).unwrap();
+        server.run_once(&mut state).unwrap();
+        let size = client.recv(&mut buf).unwrap();
+        let response = parse_strict(&buf[..size]).unwrap();
+        assert_eq!(response.get_answers().len(), 1);
+        let [opt] = response.get_additionals().as_ref() else {
+            panic!("expected our OPT record");
+        };
+        assert_eq!(opt.get_type(), OptRecord::TYPE);
+        assert_eq!(u16::from(opt.get_class()), UDP_PAYLOAD_SIZE);
+
+        // Clients without EDNS get no OPT record.
+        client
+            .send_to(&query("example.com", None), address)
+            .unwrap();
+        server.run_once(&mut state).unwrap();
+        let size = client.recv(&mut buf).unwrap();
+        assert!(parse_strict(&buf[..size])
+            .unwrap()
+            .get_additionals()
+            .is_empty());
+    }
+
+    #[test]
+    fn opt_records_round_trip_with_their_options() {
+        let cookie = [0x3a, 0x9c, 0x5e, 0x1d, 0x7b, 0x02, 0xc4, 0xf8];
+        let mut opt = OptRecord::new(4096);
+        opt.set_do(true)
+            .set_extended_rcode(0xAB)
+            .add_option(10, &cookie)
+            .add_option(12, &[0; 3]);
+        let message = response().with_additionals(&Arc::from([opt.to_answer()]));
+
+        let encoded = message.encode();
+        // Root name, type, class (the payload size), then the TTL field: extended
+        // RCODE, version, and the flags with DO as the top bit.
+        let record = &encoded[encoded.len() - 11 - 19..];
+        assert_eq!(record[..5], [0, 0, 41, 0x10, 0x00]);
+        assert_eq!(record[5..9], [0xAB, 0, 0x80, 0x00]);
+
+        let parsed = parse_strict(&encoded).unwrap().get_opt().unwrap();
+        assert_eq!(parsed, opt);
+        assert_eq!(parsed.get_udp_payload_size(), 4096);
+        assert!(parsed.get_do());
+        assert_eq!(parsed.get_version(), 0);
+        assert_eq!(
+            parsed.get_options(),
+            [(10, &cookie[..]), (12, &[0, 0, 0][..])]
+        );
+        assert_eq!(request_option_codes(&encoded), Some(vec![10, 12]));
+        assert!(response().get_opt().is_none());
+    }
+
+    #[test]
+    fn udp_responses_fit_the_clients_payload_size() {
+        // The OPT record of query() is last: root name, type, class, TTL, length.
+        let with_payload_size = |size: u16| -> Vec<u8> {
+            let mut request = query("big.example", Some(&[]));
+            let class = request.len() - 8;
+            request[class..class + 2].copy_from_slice(&size.to_be_bytes());
+            request
+        };
+        let limit = |request: &[u8]| udp_response_limit(request, UDP_PAYLOAD_SIZE as usize);
+        assert_eq!(limit(&query("big.example", None)), 512);
+        assert_eq!(limit(&with_payload_size(100)), 512);
+        assert_eq!(limit(&with_payload_size(800)), 800);
+        assert_eq!(limit(&with_payload_size(4096)), 1232);
+        assert_eq!(udp_response_limit(&with_payload_size(4096), 700), 700);
+        assert_eq!(udp_response_limit(&with_payload_size(4096), 100), 512);
+
+        // 40 addresses make a response of about 1100 bytes, which is trimmed to fit
+        // in 512 bytes for a client without EDNS, and sent whole to the others.
+        let mut records = StaticRecords::new();
+        for index in 0..40 {
+            records.add(Answer::new(
+                /* name= */ &Arc::new("big.example".parse().unwrap()),
+                /* type= */ RecordType::A,
+                /* class= */ RecordClass::In,
+                /* ttl= */ 300,
+                /* data= */ &Arc::from([192, 0, 2, index]),
+            ));
+        }
+        let mut server = dummy_server();
+        server.handlers = vec![Arc::new(query_handler(StaticDnsResolver {
+            records: Arc::new(records),
+            next: Box::new(super::super::DummyDnsResolver::default()),
+        }))];
+        let mut state = LoopState::new(&server.randomness);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        let answers = |server: &DnsServer, state: &mut LoopState, request: &[u8]| {
+            client.send_to(request, address).unwrap();
+            server.run_once(state).unwrap();
+            let mut buf = [0; 4096];
+            let size = client.recv(&mut buf).unwrap();
+            let response = parse_strict(&buf[..size]).unwrap();
+            let header = response.get_header();
+            assert_eq!(header.get_an_count() as usize, response.get_answers().len());
+            (
+                size,
+                response.get_answers().len(),
+                header.get_tc(),
+                response.get_opt(),
+            )
+        };
+        let (size, count, _, opt) = answers(&server, &mut state, &query("big.example", None));
+        assert!(size <= 512);
+        assert_eq!(count, 8);
+        assert!(opt.is_none());
+        let (size, count, _, _) = answers(&server, &mut state, &with_payload_size(4096));
+        assert!(size > 512 && size <= 1232);
+        assert_eq!(count, 40);
+        // Minimizing drops the additional records, but an EDNS client still gets
+        // our OPT record.
+        let (size, count, _, opt) = answers(&server, &mut state, &with_payload_size(600));
+        assert!(size <= 600);
+        assert!(count < 40);
+        assert_eq!(opt.unwrap().get_udp_payload_size(), UDP_PAYLOAD_SIZE);
+
+        // With no room to trim to, the answers are truncated at --max-udp-size however
+        // large a payload the client advertised, and come whole over TCP.
+        server.minimization.max_addresses_per_rrset = 40;
+        server.minimization.max_udp_size = 600;
+        let (size, count, truncated, opt) = answers(&server, &mut state, &with_payload_size(4096));
+        assert!(size <= 600);
+        assert!(count < 40);
+        assert!(truncated);
+        assert!(opt.is_some());
+        let address = server.listeners[0].stream_listener.local_addr().unwrap();
+        let mut stream = TcpStream::connect(address).unwrap();
+        let request = with_payload_size(4096);
+        let mut framed = (request.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&request);
+        stream.write_all(&framed).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        while stream.peek(&mut [0; 2]).is_err() {
+            server.run_once(&mut state).unwrap();
+        }
+        stream.set_nonblocking(false).unwrap();
+        let mut length = [0; 2];
+        stream.read_exact(&mut length).unwrap();
+        let mut response = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut response).unwrap();
+        let response = parse_strict(&response).unwrap();
+        assert!(!response.get_header().get_tc());
+        assert_eq!(response.get_answers().len(), 40);
+    }
+
+    #[test]
+    fn padded_queries_get_padded_responses() {
+        let server = dummy_server();
+        let mut state = LoopState::new(&server.randomness);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        let mut respond = |request: &[u8]| -> Vec<u8> {
+            client.send_to(request, address).unwrap();
+            server.run_once(&mut state).unwrap();
+            let mut buf = [0; 4096];
+            let size = client.recv(&mut buf).unwrap();
+            buf[..size].to_vec()
+        };
+
+        let padded = respond(&query("example.com", Some(&[10, PADDING_OPTION])));
+        assert_eq!(padded.len(), RESPONSE_PADDING_BLOCK);
+        let response = parse_strict(&padded).unwrap();
+        assert_eq!(response.get_header().get_ar_count(), 1);
+        let opt = response.get_opt().unwrap();
+        let options = opt.get_options();
+        assert_eq!(options.last().unwrap().0, PADDING_OPTION);
+        assert!(options.last().unwrap().1.iter().all(|&byte| byte == 0));
+
+        let unpadded = respond(&query("example.com", Some(&[10])));
+        assert!(unpadded.len() < 100);
+        assert_eq!(
+            request_option_codes(&unpadded),
+            Some(vec![]),
+            "our OPT record carries no options"
+        );
+    }
+}
@@ -0,0 +1,241 @@
+// The expiry index of the cache: when each entry expires, kept in a heap alongside
+// the cache's map, so that expired entries are swept in the background instead of
+// lingering until they are next asked for (and skewing the LRU accounting while
+// they do). A sweep handles at most `batch` entries per maintenance tick, so that a
+// mass expiry is spread over several ticks rather than stalling the server loop.
+//
+// Overwriting or removing an entry doesn't search the heap: its new expiry is
+// pushed, and the old one is skipped when popped, as it no longer matches the map.
+// Once such outdated heap entries outnumber the live ones, the heap is rebuilt from
+// the map, so that a cache that keeps evicting doesn't grow it without bound.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    fmt,
+    hash::Hash,
+    sync::Arc,
+    time::Instant,
+};
+
+use super::stats::ShardedCounter;
+
+// Smallest heap rebuilt for its outdated entries; below it, they're left to sweeps.
+const MIN_COMPACTED_HEAP: usize = 64;
+
+// How fresh an entry is, when asked for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Freshness {
+    Fresh,
+    // Expired, or never indexed; the cache drops it.
+    Expired,
+}
+
+// How entries left the index, shared with the stats.
+#[derive(Default)]
+pub struct ExpiryCounters {
+    // Found expired when asked for, before a sweep got to them.
+    pub lazily_expired: ShardedCounter,
+    // Removed by sweeps.
+    pub swept: ShardedCounter,
+}
+
+impl ExpiryCounters {
+    pub fn counts(&self) -> ExpiryCounts {
+        ExpiryCounts {
+            lazily_expired: self.lazily_expired.get(),
+            swept: self.swept.get(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ExpiryCounts {
+    pub lazily_expired: u64,
+    pub swept: u64,
+}
+
+impl fmt::Display for ExpiryCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} expired when asked for, {} swept",
+            self.lazily_expired, self.swept
+        )
+    }
+}
+
+pub struct ExpiryIndex<K> {
+    // Most heap entries handled per sweep.
+    pub batch: usize,
+    pub counters: Arc<ExpiryCounters>,
+    live: HashMap<K, Instant>,
+    heap: BinaryHeap<Reverse<(Instant, K)>>,
+}
+
+impl<K: Clone + Eq + Hash + Ord> ExpiryIndex<K> {
+    pub fn new(batch: usize) -> ExpiryIndex<K> {
+        ExpiryIndex {
+            batch,
+            counters: Arc::default(),
+            live: HashMap::new(),
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    // Indexes an entry stored (or overwritten) in the cache, expiring at `expires_at`.
+    pub fn insert(&mut self, key: K, expires_at: Instant) {
+        self.live.insert(key.clone(), expires_at);
+        self.heap.push(Reverse((expires_at, key)));
+        self.compact_if_outdated();
+    }
+
+    // Forgets an entry the cache dropped on its own, e.g. to make room.
+    pub fn remove(&mut self, key: &K) {
+        if self.live.remove(key).is_some() {
+            self.compact_if_outdated();
+        }
+    }
+
+    // How fresh an entry is at `now`, for a cache hit. An expired entry is removed
+    // here, as the sweep hasn't got to it yet.
+    pub fn check(&mut self, key: &K, now: Instant) -> Freshness {
+        match self.live.get(key) {
+            Some(&at) if at > now => Freshness::Fresh,
+            Some(_) => {
+                self.live.remove(key);
+                self.counters.lazily_expired.increment();
+                self.compact_if_outdated();
+                Freshness::Expired
+            }
+            None => Freshness::Expired,
+        }
+    }
+
+    // Pops up to `batch` heap entries due by `now`, outdated ones included, and
+    // returns the keys of those that still match the index, for the cache to drop.
+    pub fn sweep(&mut self, now: Instant) -> Vec<K> {
+        let mut dropped: Vec<K> = Vec::new();
+        for _ in 0..self.batch {
+            match self.heap.peek() {
+                Some(Reverse((at, _))) if *at <= now => {}
+                _ => break,
+            }
+            let Reverse((at, key)) = self.heap.pop().expect("peeked");
+            if self.live.get(&key) == Some(&at) {
+                self.live.remove(&key);
+                dropped.push(key);
+            }
+        }
+        self.counters.swept.add(dropped.len() as u64);
+        dropped
+    }
+
+    // Rebuilds the heap from the live entries once the outdated ones outnumber them.
+    fn compact_if_outdated(&mut self) {
+        if self.heap.len() < MIN_COMPACTED_HEAP || self.heap.len() <= 2 * self.live.len() {
+            return;
+        }
+        self.heap = self
+            .live
+            .iter()
+            .map(|(key, &at)| Reverse((at, key.clone())))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    // Time under the test's control: an instant `seconds` after the start.
+    struct ManualClock(Instant);
+
+    impl ManualClock {
+        fn at(&self, seconds: u64) -> Instant {
+            self.0 + Duration::from_secs(seconds)
+        }
+    }
+
+    fn sorted(mut keys: Vec<u32>) -> Vec<u32> {
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn sweeps_remove_exactly_the_expired_entries_in_bounded_batches() {
+        let clock = ManualClock(Instant::now());
+        let mut index: ExpiryIndex<u32> = ExpiryIndex::new(4);
+        // Entry n expires after n seconds, for n from 1 to 20.
+        for key in 1..=20 {
+            index.insert(key, clock.at(key as u64));
+        }
+        // Overwriting keeps only the latest expiry; removing takes an entry out.
+        index.insert(3, clock.at(100));
+        index.remove(&4);
+        assert_eq!(index.live.len(), 19);
+
+        // Ten seconds in, 1 to 10 but 3 and 4 have expired; four heap entries a sweep.
+        let now = clock.at(10);
+        // The outdated entries of 3 and 4 count against the batch too.
+        assert_eq!(sorted(index.sweep(now)), [1, 2]);
+        assert_eq!(sorted(index.sweep(now)), [5, 6, 7, 8]);
+        assert_eq!(sorted(index.sweep(now)), [9, 10]);
+        assert!(index.sweep(now).is_empty());
+        assert_eq!(index.live.len(), 11);
+        assert_eq!(index.heap.len(), 11);
+        assert_eq!(index.check(&3, now), Freshness::Fresh);
+        assert_eq!(index.check(&4, now), Freshness::Expired);
+
+        // An entry asked for before the sweep gets to it is expired there and then.
+        assert_eq!(index.check(&11, clock.at(12)), Freshness::Expired);
+        assert_eq!(sorted(index.sweep(clock.at(12))), [12]);
+        assert_eq!(
+            index.counters.counts(),
+            ExpiryCounts {
+                lazily_expired: 1,
+                swept: 9,
+            }
+        );
+
+        // Everything left expires; the index empties out.
+        let mut swept: Vec<u32> = Vec::new();
+        for _ in 0..5 {
+            swept.extend(index.sweep(clock.at(200)));
+        }
+        assert_eq!(sorted(swept), [3, 13, 14, 15, 16, 17, 18, 19, 20]);
+        assert!(index.live.is_empty());
+        assert!(index.heap.is_empty());
+    }
+
+    #[test]
+    fn outdated_heap_entries_are_compacted_away() {
+        let clock = ManualClock(Instant::now());
+        let mut index: ExpiryIndex<u32> = ExpiryIndex::new(4);
+        // A full cache evicting an entry for every one it stores, none expiring.
+        for key in 0..10_000 {
+            index.insert(key, clock.at(3600));
+            if key >= 100 {
+                index.remove(&(key - 100));
+            }
+            assert!(index.heap.len() <= (2 * index.live.len()).max(MIN_COMPACTED_HEAP) + 1);
+        }
+        assert_eq!(index.live.len(), 100);
+
+        // Overwrites and lazy expiries leave outdated entries too.
+        for round in 0..100 {
+            for key in 9_900..10_000 {
+                index.insert(key, clock.at(3600 + round));
+            }
+        }
+        assert!(index.heap.len() <= 2 * index.live.len() + 1);
+        let later = clock.at(10_000);
+        for key in 9_900..10_000 {
+            assert_eq!(index.check(&key, later), Freshness::Expired);
+        }
+        assert!(index.heap.len() <= MIN_COMPACTED_HEAP);
+        assert_eq!(index.counters.counts().lazily_expired, 100);
+    }
+}
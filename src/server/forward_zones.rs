@@ -0,0 +1,214 @@
+// Conditional forwarding (--forward-zone): the names at or below a zone go to an
+// upstream of their own, e.g. a VPN's internal names to its DNS server, and all
+// others to the default upstream (--resolver). Zones match on whole labels and
+// without regard to case, and when they nest the closest one wins: with corp.example.com
+// and example.com both routed, www.corp.example.com goes to the former's upstream.
+
+use std::{fmt, io, net::SocketAddr, time::Instant};
+
+use super::dns::message::{Header, Question};
+use super::policy::DomainSuffix;
+use super::{Resolve, Resolved};
+
+// A zone forwarded to an upstream of its own, given as "ZONE=IP:PORT",
+// e.g. "corp.example.com=10.0.0.2:53".
+#[derive(Clone, Debug)]
+pub struct ForwardZone {
+    pub zone: DomainSuffix,
+    pub upstream: SocketAddr,
+}
+
+impl ForwardZone {
+    pub fn parse(spec: &str) -> Result<ForwardZone, String> {
+        let (zone, upstream) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("Forward zone '{}' must look like ZONE=IP:PORT.", spec))?;
+        let upstream = upstream
+            .parse()
+            .map_err(|_| format!("'{}' is not an IP:port.", upstream))?;
+        Ok(ForwardZone {
+            zone: DomainSuffix::parse(zone)?,
+            upstream,
+        })
+    }
+}
+
+pub struct ConditionalForwarder {
+    // Closest zones first.
+    routes: Vec<(ForwardZone, Box<dyn Resolve>)>,
+    default_upstream: SocketAddr,
+    default: Box<dyn Resolve>,
+}
+
+impl ConditionalForwarder {
+    pub fn new(
+        routes: Vec<(ForwardZone, Box<dyn Resolve>)>,
+        default_upstream: SocketAddr,
+        default: Box<dyn Resolve>,
+    ) -> ConditionalForwarder {
+        let mut routes = routes;
+        routes.sort_by_key(|(route, _)| std::cmp::Reverse(route.zone.label_count()));
+        ConditionalForwarder {
+            routes,
+            default_upstream,
+            default,
+        }
+    }
+
+    // The index of the route for `question`, the default's being past the others.
+    fn route_index(&self, question: &Question) -> usize {
+        self.routes
+            .iter()
+            .position(|(route, _)| route.zone.matches(question.get_name()))
+            .unwrap_or(self.routes.len())
+    }
+
+    // The upstream for `question`, and the zone that chose it if it isn't the default.
+    fn route(&self, question: &Question) -> (&dyn Resolve, SocketAddr, Option<&DomainSuffix>) {
+        match self.routes.get(self.route_index(question)) {
+            Some((route, upstream)) => (upstream.as_ref(), route.upstream, Some(&route.zone)),
+            None => (self.default.as_ref(), self.default_upstream, None),
+        }
+    }
+
+    fn upstreams(&self) -> impl Iterator<Item = &dyn Resolve> {
+        self.routes
+            .iter()
+            .map(|(_, upstream)| upstream.as_ref())
+            .chain([self.default.as_ref()])
+    }
+}
+
+impl fmt::Display for ConditionalForwarder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (route, _) in &self.routes {
+            write!(f, "{} to {}, ", route.zone, route.upstream)?;
+        }
+        write!(f, "everything else to {}", self.default_upstream)
+    }
+}
+
+impl Resolve for ConditionalForwarder {
+    fn lookup(&self, header: &Header, question: &Question) -> Resolved {
+        let (upstream, address, zone) = self.route(question);
+        match zone {
+            Some(zone) => println!(
+                "[FORWARD] Sending {} to {} (zone {}).",
+                question, address, zone
+            ),
+            None => println!(
+                "[FORWARD] Sending {} to the default upstream {}.",
+                question, address
+            ),
+        }
+        upstream.lookup(header, question)
+    }
+
+    fn provenance(&self, question: &Question) -> String {
+        self.route(question).0.provenance(question)
+    }
+
+    fn answers_locally(&self, question: &Question) -> bool {
+        self.route(question).0.answers_locally(question)
+    }
+
+    fn claims(&self, question: &Question) -> bool {
+        self.route(question).0.claims(question)
+    }
+
+    // Each upstream is sent the questions routed to it together.
+    fn prefetch(&self, header: &Header, questions: &[Question]) {
+        let mut routed: Vec<Vec<Question>> = vec![Vec::new(); self.routes.len() + 1];
+        for question in questions {
+            routed[self.route_index(question)].push(question.clone());
+        }
+        for (upstream, questions) in self.upstreams().zip(routed) {
+            upstream.prefetch(header, &questions);
+        }
+    }
+
+    fn maintain(&self) {
+        for upstream in self.upstreams() {
+            upstream.maintain();
+        }
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        for upstream in self.upstreams() {
+            upstream.persist()?;
+        }
+        Ok(())
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.upstreams()
+            .filter_map(|upstream| upstream.next_due())
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::super::dns::message::{RecordClass, RecordType};
+    use super::super::Lookup;
+    use super::*;
+
+    // Stands in for an upstream, answering with its own name for provenance.
+    struct Named(&'static str);
+
+    impl Resolve for Named {
+        fn lookup(&self, _header: &Header, _question: &Question) -> Resolved {
+            Lookup::FoundNoData.into()
+        }
+
+        fn provenance(&self, _question: &Question) -> String {
+            format!("source={}", self.0)
+        }
+
+        fn answers_locally(&self, _question: &Question) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn the_closest_zone_picks_the_upstream() {
+        let route = |spec: &str, name| -> (ForwardZone, Box<dyn Resolve>) {
+            (ForwardZone::parse(spec).unwrap(), Box::new(Named(name)))
+        };
+        let forwarder = ConditionalForwarder::new(
+            vec![
+                route("example.com=192.0.2.1:53", "example"),
+                route("Corp.Example.com.=10.0.0.2:53", "corp"),
+            ],
+            "1.1.1.1:53".parse().unwrap(),
+            Box::new(Named("default")),
+        );
+        assert_eq!(
+            forwarder.to_string(),
+            "corp.example.com. to 10.0.0.2:53, example.com. to 192.0.2.1:53, \
+             everything else to 1.1.1.1:53"
+        );
+        for (name, upstream) in [
+            ("www.corp.example.com", "source=corp"),
+            ("CORP.example.COM", "source=corp"),
+            ("www.example.com", "source=example"),
+            ("xcorp.example.com", "source=example"),
+            ("notexample.com", "source=default"),
+            ("example.net", "source=default"),
+        ] {
+            let question = Question::new(
+                &Arc::new(name.parse().unwrap()),
+                RecordType::A,
+                RecordClass::In,
+            );
+            assert_eq!(forwarder.provenance(&question), upstream, "{name}");
+        }
+
+        assert_eq!(
+            ForwardZone::parse("corp.example.com=10.0.0.2").unwrap_err(),
+            "'10.0.0.2' is not an IP:port."
+        );
+    }
+}
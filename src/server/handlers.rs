@@ -0,0 +1,498 @@
+use std::{
+    io,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+
+use super::authenticated::AdMode;
+use super::bad_packets::BadPacket;
+use super::budget::{self, Work, WorkBudget};
+use super::dns::message::{Answer, DnsParseError, Header, Message, OpCode, Question, RCode};
+use super::edns::{
+    extended_error, request_edns_version, request_option_codes, response_opt, with_rcode,
+    EDE_NOT_READY,
+};
+use super::listener::QueryInfo;
+use super::overload::Load;
+use super::policy::{PolicyOutcome, PolicyVerdict, QueryPolicy};
+use super::provenance::{annotations, ProvenancePolicy, DEFAULT_PROVENANCE_OPTION};
+use super::rpz::{ResponsePolicy, Rewrite, RpzHit};
+use super::search::SearchList;
+use super::slo::QueryClass;
+use super::stats::Stats;
+#[cfg(feature = "dnssec")]
+use super::trust_anchors::TrustAnchors;
+use super::{Lookup, Resolution, Resolve, Resolved};
+
+// Handles requests of one opcode. DnsServer only parses the header and hands the
+// whole datagram to the handler registered for its opcode, which parses the rest
+// by the rules of that opcode (UPDATE's sections, for one, differ from QUERY's).
+// The handlers are shared by the server loop and the workers (see workers.rs).
+pub trait HandleOpcode: Send + Sync {
+    fn opcode(&self) -> OpCode;
+
+    // Returns the response, or None if the request is to be dropped without one.
+    fn handle(
+        &self,
+        info: &QueryInfo,
+        header: &Header,
+        data: &[u8],
+        stats: &Stats,
+    ) -> Option<Message>;
+
+    // Periodic housekeeping; see Resolve::maintain.
+    fn maintain(&self) {}
+
+    // See Resolve::persist.
+    fn persist(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // See Resolve::next_due.
+    fn next_due(&self) -> Option<Instant> {
+        None
+    }
+}
+
+// Builds the header of a response to `request`, echoing the fields a client
+// expects to see back.
+pub fn response_header(request: &Header, rcode: RCode) -> Header {
+    let mut header: Header = Header::default();
+    header
+        .set_id(request.get_id())
+        .set_qr(true)
+        .set_opcode(request.get_opcode().clone())
+        .set_rd(request.get_rd())
+        .set_rcode(rcode);
+    header
+}
+
+// Answers a request that couldn't be parsed past its header with FORMERR, and keeps
+// its `data` for the badpackets command.
+pub fn format_error(
+    info: &QueryInfo,
+    request: &Header,
+    data: &[u8],
+    err: &DnsParseError,
+    stats: &Stats,
+) -> Message {
+    println!(
+        "[{}] Answering FORMERR to {}: {}.",
+        info.listener, info.client, err
+    );
+    stats.bad_packets.record(BadPacket {
+        at: SystemTime::now(),
+        listener: info.listener.to_string(),
+        client: info.client,
+        data: data.to_vec(),
+        error: err.clone(),
+    });
+    let header = response_header(request, RCode::FormatError);
+    Message::new(&header.into(), &Arc::from([]), &Arc::from([]))
+}
+
+// Gives the records owned by a question's name that name exactly as the client
+// spelled it. Lookups (and the cache) ignore case, so the records may come back
+// spelled as stored or as upstream spelled them, and some clients check that the
+// answer repeats their question's casing. Other owners, like those further down
+// a CNAME chain, keep their own casing. Answers already spelled so are handed back
+// as they are, for what was encoded of them beforehand to still stand for them (see
+// EncodedAnswers).
+pub fn echo_qname_casing(questions: &[Question], answers: &Arc<[Answer]>) -> Arc<[Answer]> {
+    let spelling = |answer: &Answer| {
+        questions
+            .iter()
+            .find(|question| question.get_name().eq_ignore_case(answer.get_name()))
+            .map(|question| question.get_name())
+    };
+    let echoed = answers.iter().all(|answer| {
+        spelling(answer).map_or(true, |name| name.encode() == answer.get_name().encode())
+    });
+    if echoed {
+        return Arc::clone(answers);
+    }
+    answers
+        .iter()
+        .map(|answer| match spelling(answer) {
+            Some(name) => answer.with_name(name),
+            None => answer.clone(),
+        })
+        .collect()
+}
+
+// Standard queries (opcode 0), answered through the allow-lists and the resolver chain.
+pub struct QueryOpcodeHandler {
+    pub resolver: Arc<dyn Resolve>,
+    pub policy: QueryPolicy,
+    pub provenance: ProvenancePolicy,
+    // Whether the upstream's AD bit is passed on (see authenticated.rs).
+    pub ad_mode: AdMode,
+    // When configured, RFC 8509 sentinel queries are answered against these.
+    #[cfg(feature = "dnssec")]
+    pub trust_anchors: Option<Arc<TrustAnchors>>,
+    // Response policy zones (see rpz.rs), if any.
+    pub rpz: Option<Arc<ResponsePolicy>>,
+    // Search lists for the clients that need them (see search.rs); the first one
+    // whose blocks a client is in applies.
+    pub search: Vec<SearchList>,
+}
+
+impl QueryOpcodeHandler {
+    // A handler in front of `resolver` that lets every name and type through, adds
+    // provenance on the default option to the responses of clients that ask for it,
+    // strips AD, and has no trust anchors, policy zones or search lists. Callers set
+    // the fields they configure.
+    pub fn new(resolver: Arc<dyn Resolve>) -> QueryOpcodeHandler {
+        QueryOpcodeHandler {
+            resolver,
+            policy: QueryPolicy::new(&[], &[]),
+            provenance: ProvenancePolicy {
+                option_code: DEFAULT_PROVENANCE_OPTION,
+                always: false,
+            },
+            ad_mode: AdMode::default(),
+            #[cfg(feature = "dnssec")]
+            trust_anchors: None,
+            rpz: None,
+            search: Vec::new(),
+        }
+    }
+
+    // Whether the request is a root key sentinel query (RFC 8509) that gets SERVFAIL
+    // given our trust anchors. There is no DNSSEC validation yet, so the sentinel
+    // applies whether or not the answer would validate.
+    #[cfg(feature = "dnssec")]
+    fn sentinel_fails(&self, request: &Message) -> bool {
+        self.trust_anchors.as_ref().is_some_and(|anchors| {
+            request
+                .get_questions()
+                .iter()
+                .any(|question| anchors.sentinel_fails(question))
+        })
+    }
+
+    // Without trust anchors there are no sentinel queries.
+    #[cfg(not(feature = "dnssec"))]
+    fn sentinel_fails(&self, _request: &Message) -> bool {
+        false
+    }
+
+    // Whether the response to `request` keeps the AD bit of the upstream's answer to
+    // its first question, resolved as `resolution`, as far as --ad-mode trusts it.
+    fn authentic(&self, request: &Message, data: &[u8], resolution: &Resolution) -> bool {
+        self.ad_mode.keeps_ad(request.get_header(), data) && resolution.ad
+    }
+
+    // The answers to the request's questions, searched for with the client's search
+    // list if it has one, and then SERVFAIL if the lookup of any of them Failed.
+    fn resolve(&self, info: &QueryInfo, request: &Message) -> Resolution {
+        let client = info.client.ip();
+        let Some(list) = self.search.iter().find(|list| list.applies_to(client)) else {
+            return self
+                .resolver
+                .resolve(request.get_header(), request.get_questions());
+        };
+        let mut lookups: Vec<Resolved> = Vec::new();
+        for question in request.get_questions().iter() {
+            let resolved = list.lookup(self.resolver.as_ref(), request.get_header(), question);
+            if matches!(resolved.lookup, Lookup::Failed) {
+                return Resolution::failed(vec![resolved]);
+            }
+            // The answers found were asked for under another name, so their status
+            // is ours.
+            lookups.push(Resolved {
+                header: None,
+                ..resolved
+            });
+        }
+        Resolution::new(lookups)
+    }
+
+    // Resolves the request as `resolve` does, and records how long it took against
+    // the latency SLOs of its class.
+    fn timed_resolve(&self, info: &QueryInfo, request: &Message, stats: &Stats) -> Resolution {
+        if stats.slos.is_empty() {
+            return self.resolve(info, request);
+        }
+        // Asked before resolving, as a forwarded answer is cached by the time it's in.
+        let local = request
+            .get_questions()
+            .iter()
+            .all(|question| self.resolver.answers_locally(question));
+        let started = Instant::now();
+        let resolution = self.resolve(info, request);
+        let class = if resolution.cached {
+            QueryClass::CacheHit
+        } else if local {
+            QueryClass::Authoritative
+        } else {
+            QueryClass::Forwarded
+        };
+        stats.slos.record(class, started.elapsed());
+        resolution
+    }
+
+    // Sheds a query that needs the network while the server is overloaded: answers
+    // SERVFAIL right away, with an Extended DNS Error for EDNS clients, so that the
+    // client can retry elsewhere instead of waiting out its timeout. Once saturated,
+    // even that is skipped and the query is dropped.
+    fn shed(
+        &self,
+        info: &QueryInfo,
+        request: &Message,
+        data: &[u8],
+        stats: &Stats,
+    ) -> Option<Message> {
+        if info.load == Load::Saturated {
+            stats.shed_by_dropping.increment();
+            return None;
+        }
+        stats.shed_with_servfail.increment();
+        let mut header = response_header(request.get_header(), RCode::ServerError);
+        header.set_qd_count(request.get_header().get_qd_count());
+        let response = Message::new(&header.into(), request.get_questions(), &Arc::from([]));
+        if request_option_codes(data).is_none() {
+            return Some(response);
+        }
+        Some(response.with_additionals(&Arc::from([extended_error(EDE_NOT_READY, "overloaded")])))
+    }
+
+    // Adds to the additional section of a response the provenance annotations, from
+    // `source` given each question and its index, for clients that ask for them, and our OPT record for EDNS clients,
+    // with an Extended DNS Error if a policy shaped the answer.
+    fn finish(
+        &self,
+        response: Message,
+        request: &Message,
+        data: &[u8],
+        outcome: Option<PolicyOutcome>,
+        source: impl Fn(usize, &Question) -> String,
+    ) -> Message {
+        let annotate = matches!(response.get_header().get_rcode(), RCode::NoError)
+            && self.provenance.requested(data);
+        let mut additionals: Vec<Answer> = response.get_additionals().to_vec();
+        if annotate {
+            additionals.extend(annotations(request.get_questions(), source).iter().cloned());
+        }
+        match (response_opt(request), outcome) {
+            (Some(_), Some(outcome)) => {
+                additionals.push(extended_error(outcome.info_code, &outcome.rule))
+            }
+            (opt, _) => additionals.extend(opt),
+        }
+        if additionals.len() == response.get_additionals().len() {
+            return response;
+        }
+        response.with_additionals(&additionals.into())
+    }
+
+    // Applies the action of a policy zone trigger: None if the query is answered as
+    // usual, Some(None) if it is dropped.
+    fn rewrite(
+        &self,
+        info: &QueryInfo,
+        request: &Message,
+        data: &[u8],
+        hit: &RpzHit,
+    ) -> Option<Option<Message>> {
+        match hit.rewrite(request, info.transport) {
+            Rewrite::Pass => None,
+            Rewrite::Drop => Some(None),
+            Rewrite::Respond(response) => {
+                budget::spend(Work::Rewrites, 1);
+                Some(Some(self.finish(
+                    response,
+                    request,
+                    data,
+                    hit.outcome(),
+                    |_, _| hit.trace(),
+                )))
+            }
+        }
+    }
+}
+
+impl HandleOpcode for QueryOpcodeHandler {
+    fn opcode(&self) -> OpCode {
+        OpCode::Query
+    }
+
+    fn handle(
+        &self,
+        info: &QueryInfo,
+        header: &Header,
+        data: &[u8],
+        stats: &Stats,
+    ) -> Option<Message> {
+        let request = match Message::parse_from(data) {
+            Ok(request) => request,
+            Err(err) => return Some(format_error(info, header, data, &err, stats)),
+        };
+        if request.get_questions().is_empty() {
+            return Some(format_error(
+                info,
+                header,
+                data,
+                &DnsParseError::NoQuestion,
+                stats,
+            ));
+        }
+        println!("[{}] Received DNS message:\n{}", info.listener, &request);
+        // We implement EDNS version 0 only (RFC 6891, 6.1.3).
+        if let Some(version) = request_edns_version(data).filter(|&version| version > 0) {
+            println!(
+                "[{}] Answering BADVERS to {}: EDNS version {} is not supported.",
+                info.listener, info.client, version
+            );
+            let mut header = response_header(request.get_header(), RCode::NoError);
+            header.set_qd_count(request.get_header().get_qd_count());
+            let response = Message::new(&header.into(), request.get_questions(), &Arc::from([]));
+            return Some(
+                with_rcode(&response, &RCode::BadVersion, true).expect("the client uses EDNS"),
+            );
+        }
+
+        // Everything done to answer the query is charged to its work budget.
+        let budget = WorkBudget::open(request.get_questions());
+        let mut gave_up = None;
+        // AA, RA and TC, as the upstream set them on a forwarded answer.
+        let mut forwarded_flags = (false, false, false);
+        let verdict = self.policy.check(request.get_questions());
+        let sentinel_fails = self.sentinel_fails(&request);
+        // QNAME triggers apply before resolution, so that blocked names never reach
+        // the upstream; a PASSTHRU exempts the query from the IP triggers as well.
+        let mut passed = false;
+        if verdict == PolicyVerdict::Allow && !sentinel_fails {
+            if let Some(hit) = self
+                .rpz
+                .as_ref()
+                .and_then(|rpz| rpz.check_qname(request.get_questions()))
+            {
+                match self.rewrite(info, &request, data, &hit) {
+                    Some(response) => return response,
+                    None => passed = true,
+                }
+            }
+        }
+
+        let mut ad = false;
+        // What came with the answers besides them: what a policy did to them, where
+        // they came from, by question, and their encoding if the lookup kept one.
+        let mut outcome = None;
+        let mut provenance: Vec<Option<String>> = Vec::new();
+        let mut encoded_answers = None;
+        let (rcode, answers, authorities) = match verdict {
+            PolicyVerdict::Allow if sentinel_fails => {
+                println!(
+                    "[{}] Failing a root key sentinel query from {}.",
+                    info.listener, info.client
+                );
+                (RCode::ServerError, Arc::from([]), Arc::from([]))
+            }
+            PolicyVerdict::Allow
+                if info.load != Load::Normal
+                    && !request
+                        .get_questions()
+                        .iter()
+                        .all(|question| self.resolver.answers_locally(question)) =>
+            {
+                return self.shed(info, &request, data, stats);
+            }
+            PolicyVerdict::Allow => match (
+                self.timed_resolve(info, &request, stats),
+                budget.exhausted(),
+            ) {
+                (_, Some(exhausted)) => {
+                    stats.over_work_budget.increment();
+                    println!(
+                        "[{}] Answering SERVFAIL to {}: {} ({} names followed, {} upstream \
+                         queries).",
+                        info.listener,
+                        info.client,
+                        exhausted,
+                        budget.spent(Work::NamesFollowed),
+                        budget.spent(Work::UpstreamQueries)
+                    );
+                    gave_up = Some(PolicyOutcome::other(format!("work budget: {}", exhausted)));
+                    (RCode::ServerError, Arc::from([]), Arc::from([]))
+                }
+                (resolution, None) => {
+                    // IP triggers apply to the addresses in the answers.
+                    let hit = self.rpz.as_ref().filter(|_| !passed).and_then(|rpz| {
+                        rpz.check_addresses(request.get_questions(), &resolution.answers)
+                    });
+                    if let Some(response) =
+                        hit.and_then(|hit| self.rewrite(info, &request, data, &hit))
+                    {
+                        return response;
+                    }
+                    ad = self.authentic(&request, data, &resolution);
+                    forwarded_flags = (resolution.aa, resolution.ra, resolution.tc);
+                    outcome = resolution.policy_outcome;
+                    provenance = resolution.provenance;
+                    encoded_answers = resolution.encoded_answers;
+                    (
+                        resolution.rcode,
+                        echo_qname_casing(request.get_questions(), &resolution.answers),
+                        resolution.authorities,
+                    )
+                }
+            },
+            verdict => {
+                match verdict {
+                    PolicyVerdict::RefuseClass => stats.refused_by_class.increment(),
+                    PolicyVerdict::RefuseName => stats.refused_by_name.increment(),
+                    _ => stats.refused_by_type.increment(),
+                }
+                println!(
+                    "[{}] Refusing query from {} ({:?}); {}.",
+                    info.listener,
+                    info.client,
+                    verdict,
+                    stats.snapshot()
+                );
+                (RCode::Refused, Arc::from([]), Arc::from([]))
+            }
+        };
+
+        let mut header = response_header(request.get_header(), rcode);
+        header
+            .set_ad(ad)
+            .set_aa(forwarded_flags.0)
+            .set_ra(forwarded_flags.1)
+            .set_tc(forwarded_flags.2)
+            .set_qd_count(request.get_header().get_qd_count())
+            .set_an_count(answers.len() as u16);
+        let response = Message::new(&header.into(), request.get_questions(), &answers)
+            .with_authorities(&authorities)
+            .with_encoded_answers(encoded_answers);
+        let outcome = gave_up.or(outcome);
+        Some(
+            self.finish(
+                response,
+                &request,
+                data,
+                outcome,
+                |index, question| match provenance.get(index) {
+                    Some(Some(source)) => source.clone(),
+                    _ => self.resolver.provenance(question),
+                },
+            ),
+        )
+    }
+
+    fn maintain(&self) {
+        if let Some(rpz) = &self.rpz {
+            rpz.reload();
+        }
+        self.resolver.maintain();
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        self.resolver.persist()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.resolver.next_due()
+    }
+}
@@ -0,0 +1,267 @@
+// The hosts file (--hosts-file): A and AAAA records read from a file in the
+// /etc/hosts format, an address and then its names, the canonical one first and its
+// aliases after it, all answered alike. Names are matched without regard to case;
+// a name on several lines has the addresses of all of them. Comments (from #) and
+// blank lines are ignored, and lines that don't parse are skipped with a warning.
+//
+// A name in the file is answered from it, authoritatively, with --hosts-ttl; one
+// with no address of the type asked for is answered NODATA rather than forwarded.
+// Other names go to the resolver it wraps.
+
+use std::{collections::HashMap, fs, io, net::IpAddr, path::Path, sync::Arc, time::Instant};
+
+use super::dns::message::{Answer, Header, LabelSequence, Question, RecordType};
+use super::{Lookup, Resolve, Resolved};
+
+pub struct HostsResolver {
+    pub next: Box<dyn Resolve>,
+    pub ttl: u32,
+    // The addresses of each name, lowercased, in the order of the file.
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl HostsResolver {
+    // Reads the hosts file at `path`; returns the resolver and a warning for each line
+    // skipped.
+    pub fn load(
+        path: &Path,
+        ttl: u32,
+        next: Box<dyn Resolve>,
+    ) -> Result<(HostsResolver, Vec<String>), String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("Can't read {}: {}", path.display(), err))?;
+        Ok(HostsResolver::parse(&text, ttl, next))
+    }
+
+    pub fn parse(text: &str, ttl: u32, next: Box<dyn Resolve>) -> (HostsResolver, Vec<String>) {
+        let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        let mut warnings: Vec<String> = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(address) = fields.next() else {
+                continue;
+            };
+            let Ok(address) = address.parse::<IpAddr>() else {
+                warnings.push(format!(
+                    "line {}: '{}' is not an IP address.",
+                    index + 1,
+                    address
+                ));
+                continue;
+            };
+            let names: Vec<&str> = fields.collect();
+            if names.is_empty() {
+                warnings.push(format!("line {}: {} has no names.", index + 1, address));
+                continue;
+            }
+            if let Some(name) = names
+                .iter()
+                .find(|name| name.parse::<LabelSequence>().is_err())
+            {
+                warnings.push(format!("line {}: '{}' is not a name.", index + 1, name));
+                continue;
+            }
+            for name in names {
+                let addresses = hosts.entry(host_key(name)).or_default();
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+            }
+        }
+        (HostsResolver { next, ttl, hosts }, warnings)
+    }
+
+    // Names with addresses.
+    pub fn len(&self) -> usize {
+        self.hosts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    fn addresses(&self, question: &Question) -> Option<&Vec<IpAddr>> {
+        self.hosts.get(&host_key(&question.get_name().to_string()))
+    }
+}
+
+// A name as the file is keyed by: lowercased, without a trailing dot.
+fn host_key(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Resolve for HostsResolver {
+    // The file is the authority on its names.
+    fn lookup(&self, header: &Header, question: &Question) -> Resolved {
+        let Some(addresses) = self.addresses(question) else {
+            return self.next.lookup(header, question);
+        };
+        let name = question.get_name();
+        let answers: Vec<Answer> = addresses
+            .iter()
+            .filter_map(|address| match (address, question.get_type()) {
+                (IpAddr::V4(address), RecordType::A) => Some(Answer::a(name, self.ttl, *address)),
+                (IpAddr::V6(address), RecordType::Aaaa) => {
+                    Some(Answer::aaaa(name, self.ttl, *address))
+                }
+                _ => None,
+            })
+            .collect();
+        println!(
+            "[HOSTS] Answering {} with {} address(es) from the hosts file.",
+            question,
+            answers.len()
+        );
+        let mut header = Header::default();
+        header.set_aa(true);
+        Resolved {
+            header: Some(Arc::new(header)),
+            ..if answers.is_empty() {
+                Lookup::FoundNoData
+            } else {
+                Lookup::Found(answers)
+            }
+            .into()
+        }
+    }
+
+    fn provenance(&self, question: &Question) -> String {
+        match self.addresses(question) {
+            Some(_) => String::from("source=hosts"),
+            None => self.next.provenance(question),
+        }
+    }
+
+    fn answers_locally(&self, question: &Question) -> bool {
+        self.addresses(question).is_some() || self.next.answers_locally(question)
+    }
+
+    fn claims(&self, question: &Question) -> bool {
+        self.addresses(question).is_some() || self.next.claims(question)
+    }
+
+    fn maintain(&self) {
+        self.next.maintain();
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        self.next.persist()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.next.next_due()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::super::dns::message::{RData, RecordClass};
+    use super::super::DummyDnsResolver;
+    use super::*;
+
+    const HOSTS: &str = "\
+        # The loopback names.\n\
+        127.0.0.1   localhost\n\
+        ::1         localhost ip6-localhost   # and an alias\n\
+        \n\
+        192.0.2.10  printer.lan  Printer  scanner.lan.\n\
+        192.0.2.11  printer.lan\n\
+        2001:db8::10 printer.lan\n\
+        192.0.2.300 broken.lan\n\
+        192.0.2.12\n\
+        192.0.2.13  bad..name\n";
+
+    fn hosts() -> HostsResolver {
+        let next = Box::new(DummyDnsResolver {
+            ipv6_address: Ipv6Addr::LOCALHOST,
+        });
+        let (hosts, warnings) = HostsResolver::parse(HOSTS, 600, next);
+        assert_eq!(
+            warnings,
+            [
+                "line 8: '192.0.2.300' is not an IP address.",
+                "line 9: 192.0.2.12 has no names.",
+                "line 10: 'bad..name' is not a name.",
+            ]
+        );
+        hosts
+    }
+
+    fn addresses(hosts: &HostsResolver, name: &str, r#type: RecordType) -> Vec<String> {
+        let question = Question::new(&Arc::new(name.parse().unwrap()), r#type, RecordClass::In);
+        match hosts.lookup(&Header::default(), &question).lookup {
+            Lookup::Found(answers) => answers
+                .iter()
+                .map(|answer| match answer.get_rdata() {
+                    RData::A(address) => format!("{} {}", address, answer.get_ttl()),
+                    RData::Aaaa(address) => format!("{} {}", address, answer.get_ttl()),
+                    data => panic!("{data:?}"),
+                })
+                .collect(),
+            lookup => panic!("{lookup:?}"),
+        }
+    }
+
+    #[test]
+    fn names_and_aliases_have_all_their_addresses() {
+        let hosts = hosts();
+        assert_eq!(hosts.len(), 5);
+        // A name on several lines, in any case, has every address of its type.
+        assert_eq!(
+            addresses(&hosts, "PRINTER.lan", RecordType::A),
+            ["192.0.2.10 600", "192.0.2.11 600"]
+        );
+        assert_eq!(
+            addresses(&hosts, "printer.lan", RecordType::Aaaa),
+            ["2001:db8::10 600"]
+        );
+        assert_eq!(
+            addresses(&hosts, "localhost", RecordType::Aaaa),
+            ["::1 600"]
+        );
+        // Names only there as aliases are answered as well.
+        assert_eq!(
+            addresses(&hosts, "ip6-localhost", RecordType::Aaaa),
+            ["::1 600"]
+        );
+        assert_eq!(
+            addresses(&hosts, "scanner.lan", RecordType::A),
+            ["192.0.2.10 600"]
+        );
+        assert_eq!(
+            addresses(&hosts, "printer", RecordType::A),
+            ["192.0.2.10 600"]
+        );
+    }
+
+    #[test]
+    fn other_names_fall_through() {
+        let hosts = hosts();
+        let header = Header::default();
+        let question = |name: &str, r#type| {
+            Question::new(&Arc::new(name.parse().unwrap()), r#type, RecordClass::In)
+        };
+
+        // A name in the file is answered authoritatively, NODATA without an address
+        // of the type.
+        let ip6_only = question("ip6-localhost", RecordType::A);
+        let resolved = hosts.lookup(&header, &ip6_only);
+        assert!(matches!(resolved.lookup, Lookup::FoundNoData));
+        assert!(resolved.header.unwrap().get_aa());
+        assert_eq!(hosts.provenance(&ip6_only), "source=hosts");
+
+        // Other names, and malformed lines' names, go to the next resolver.
+        for name in ["www.example.com", "broken.lan"] {
+            let other = question(name, RecordType::A);
+            assert_eq!(
+                addresses(&hosts, name, RecordType::A),
+                [format!("{} 60", Ipv4Addr::new(8, 8, 8, 8))]
+            );
+            assert!(hosts.lookup(&header, &other).header.is_none());
+            assert_eq!(hosts.provenance(&other), "source=dummy");
+        }
+    }
+}
@@ -0,0 +1,492 @@
+// Files the server writes over time (the query log, the capture) and their retention. A
+// RotatingWriter moves its file aside once it grows past a size, under the same
+// name plus a timestamp, and reopens it on SIGHUP so that logrotate can move it
+// instead. The housekeeping task, run from the maintenance scheduler, deletes the
+// oldest rotated files of each artifact beyond its limits. The artifacts are written
+// by the server loop and the workers alike, and maintained by the server loop alone.
+
+use std::{
+    cell::RefCell,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::pacing::JitteredInterval;
+use super::random::Randomness;
+use super::replay;
+
+// How often the rotated files are checked against the retention limits.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const SWEEP_JITTER: f64 = 0.15;
+
+static HANGUP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_hangup(_: libc::c_int) {
+    HANGUP.store(true, Ordering::Relaxed);
+}
+
+// Makes SIGHUP ask for the artifacts to be reopened instead of ending the process.
+pub fn install_hangup_handler() {
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(
+            libc::SIGHUP,
+            on_hangup as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+// Whether a SIGHUP arrived since the last call.
+fn take_hangup() -> bool {
+    HANGUP.swap(false, Ordering::Relaxed)
+}
+
+// Appends lines to a file, moving it aside once it would grow past `max_bytes`.
+pub struct RotatingWriter {
+    pub path: PathBuf,
+    pub max_bytes: u64,
+    file: BufWriter<File>,
+    written: u64,
+}
+
+fn open_append(path: &Path) -> io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let written = file.metadata()?.len();
+    Ok((BufWriter::new(file), written))
+}
+
+impl RotatingWriter {
+    pub fn open(path: PathBuf, max_bytes: u64) -> io::Result<RotatingWriter> {
+        let (file, written) = open_append(&path)?;
+        Ok(RotatingWriter {
+            path,
+            max_bytes,
+            file,
+            written,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let size = line.len() as u64 + 1;
+        if self.written > 0 && self.written + size > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.written += size;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    // Renames the file to `<path>.<unix millis>` and starts a new one. The rename is
+    // atomic, so a reader sees either the old file or the rotated one, never half.
+    pub fn rotate(&mut self) -> io::Result<PathBuf> {
+        self.file.flush()?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis());
+        let mut rotated = suffixed(&self.path, &millis.to_string());
+        let mut attempt = 1;
+        while rotated.exists() {
+            rotated = suffixed(&self.path, &format!("{}-{}", millis, attempt));
+            attempt += 1;
+        }
+        fs::rename(&self.path, &rotated)?;
+        self.reopen()?;
+        Ok(rotated)
+    }
+
+    // Flushes what's buffered to the file as it was opened, then opens the path
+    // afresh, e.g. after logrotate moved the file.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        (self.file, self.written) = open_append(&self.path)?;
+        Ok(())
+    }
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// Parses a size in bytes, optionally with a K, M or G (binary) suffix.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let (digits, unit) = match value.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&value[..index], 1 << 10),
+        Some((index, 'M' | 'm')) => (&value[..index], 1 << 20),
+        Some((index, 'G' | 'g')) => (&value[..index], 1 << 30),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|count| count.checked_mul(unit))
+        .ok_or_else(|| format!("'{}' is not a size (e.g. 512K, 10M).", value))
+}
+
+// Parses an age in seconds, optionally with an s, m, h or d suffix.
+pub fn parse_age(value: &str) -> Result<Duration, String> {
+    let (digits, unit) = match value.char_indices().last() {
+        Some((index, 's')) => (&value[..index], 1),
+        Some((index, 'm')) => (&value[..index], 60),
+        Some((index, 'h')) => (&value[..index], 3600),
+        Some((index, 'd')) => (&value[..index], 86400),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|count| count.checked_mul(unit))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("'{}' is not an age (e.g. 90m, 7d).", value))
+}
+
+// The artifacts that have retention limits.
+pub const ARTIFACT_KINDS: &[&str] = &["query-log", "capture"];
+
+// How much of an artifact's rotated files to keep; a file beyond any limit goes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Retention {
+    pub kind: String,
+    // Of the rotated files and the current one together.
+    pub max_total_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub max_files: Option<usize>,
+}
+
+impl Retention {
+    // Parses e.g. "query-log:max-size=100M,max-age=7d,max-files=10".
+    pub fn parse(spec: &str) -> Result<Retention, String> {
+        let (kind, limits) = spec
+            .split_once(':')
+            .ok_or_else(|| format!("'{}' is not KIND:LIMIT,...", spec))?;
+        if !ARTIFACT_KINDS.contains(&kind) {
+            return Err(format!(
+                "Unknown artifact '{}'; expected one of {}.",
+                kind,
+                ARTIFACT_KINDS.join(", ")
+            ));
+        }
+        let mut retention = Retention {
+            kind: String::from(kind),
+            ..Default::default()
+        };
+        for limit in limits.split(',') {
+            match limit.split_once('=') {
+                Some(("max-size", value)) => retention.max_total_bytes = Some(parse_size(value)?),
+                Some(("max-age", value)) => retention.max_age = Some(parse_age(value)?),
+                Some(("max-files", value)) => {
+                    retention.max_files = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("'{}' is not a file count.", value))?,
+                    )
+                }
+                _ => {
+                    return Err(format!(
+                        "Unknown limit '{}'; expected max-size, max-age or max-files.",
+                        limit
+                    ))
+                }
+            }
+        }
+        Ok(retention)
+    }
+
+    // Deletes the rotated files of the artifact at `path` beyond the limits and
+    // returns them. Files are kept newest first, so the oldest are the ones to go.
+    pub fn sweep(&self, path: &Path, now: SystemTime) -> io::Result<Vec<PathBuf>> {
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let prefix = format!(
+            "{}.",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        let mut rotated: Vec<(SystemTime, u64, PathBuf)> = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                rotated.push((metadata.modified()?, metadata.len(), entry.path()));
+            }
+        }
+        // Newest first, so that the files kept are a prefix.
+        rotated.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| b.2.cmp(&a.2)));
+
+        let mut total = fs::metadata(path).map_or(0, |metadata| metadata.len());
+        let mut removed = Vec::new();
+        for (index, (modified, size, file)) in rotated.into_iter().enumerate() {
+            total += size;
+            let age = now.duration_since(modified).unwrap_or_default();
+            let keep = self.max_files.map_or(true, |max| index < max)
+                && self.max_total_bytes.map_or(true, |max| total <= max)
+                && self.max_age.map_or(true, |max| age <= max);
+            if !keep {
+                fs::remove_file(&file)?;
+                removed.push(file);
+            }
+        }
+        Ok(removed)
+    }
+}
+
+// The files the server writes and their retention, kept by the maintenance scheduler.
+pub struct Housekeeping {
+    pub query_log: Option<Arc<Mutex<RotatingWriter>>>,
+    // Every exchange, in full, for the replay command (see replay.rs).
+    pub capture: Option<Arc<Mutex<RotatingWriter>>>,
+    pub retention: Vec<Retention>,
+    pub sweep: RefCell<JitteredInterval>,
+}
+
+impl Default for Housekeeping {
+    fn default() -> Housekeeping {
+        Housekeeping::with_randomness(&Randomness::default())
+    }
+}
+
+impl Housekeeping {
+    // Nothing written yet; the sweeps are jittered from `randomness`.
+    pub fn with_randomness(randomness: &Randomness) -> Housekeeping {
+        Housekeeping {
+            query_log: None,
+            capture: None,
+            retention: Vec::new(),
+            sweep: RefCell::new(JitteredInterval::new(
+                SWEEP_INTERVAL,
+                SWEEP_JITTER,
+                Instant::now(),
+                randomness.stream("housekeeping"),
+            )),
+        }
+    }
+
+    pub fn log_query(&self, line: &str) {
+        if let Some(log) = &self.query_log {
+            if let Err(e) = log.lock().unwrap().write_line(line) {
+                eprintln!("[HOUSEKEEPING] Error writing to the query log: {}", e);
+            }
+        }
+    }
+
+    // Writes out what the artifacts have buffered, e.g. at shutdown.
+    pub fn flush(&self) -> io::Result<()> {
+        for artifact in [&self.query_log, &self.capture].into_iter().flatten() {
+            artifact.lock().unwrap().flush()?;
+        }
+        Ok(())
+    }
+
+    // Records a query and the response sent to it, as encoded.
+    pub fn capture(&self, query: &[u8], response: &[u8]) {
+        if let Some(capture) = &self.capture {
+            let line = replay::capture_line(SystemTime::now(), query, response);
+            if let Err(e) = capture.lock().unwrap().write_line(&line) {
+                eprintln!("[HOUSEKEEPING] Error writing to the capture: {}", e);
+            }
+        }
+    }
+
+    // The artifact of a kind, if the server is writing it.
+    fn artifact(&self, kind: &str) -> Option<&Mutex<RotatingWriter>> {
+        match kind {
+            "query-log" => self.query_log.as_deref(),
+            "capture" => self.capture.as_deref(),
+            _ => None,
+        }
+    }
+
+    // Flushes the artifacts, reopens them if a SIGHUP arrived, and sweeps the rotated
+    // files when that is due.
+    pub fn maintain(&self, now: Instant) {
+        let hangup = take_hangup();
+        for log in self.query_log.iter().chain(self.capture.iter()) {
+            let mut log = log.lock().unwrap();
+            let result = if hangup { log.reopen() } else { log.flush() };
+            if let Err(e) = result {
+                eprintln!("[HOUSEKEEPING] Error writing {}: {}", log.path.display(), e);
+            }
+        }
+        if hangup {
+            println!("[HOUSEKEEPING] Reopened the artifacts on SIGHUP.");
+        }
+
+        let mut sweep = self.sweep.borrow_mut();
+        if !sweep.is_due(now) {
+            return;
+        }
+        sweep.reschedule(now);
+        self.sweep(SystemTime::now());
+    }
+
+    fn sweep(&self, now: SystemTime) {
+        for retention in &self.retention {
+            let Some(artifact) = self.artifact(&retention.kind) else {
+                continue;
+            };
+            let path = artifact.lock().unwrap().path.clone();
+            match retention.sweep(&path, now) {
+                Ok(removed) => {
+                    for file in removed {
+                        println!(
+                            "[HOUSEKEEPING] Removed {} ({} retention).",
+                            file.display(),
+                            retention.kind
+                        );
+                    }
+                }
+                Err(e) => eprintln!("[HOUSEKEEPING] Error sweeping {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("housekeeping-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir(&path).unwrap();
+        path
+    }
+
+    fn rotated_files(directory: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension != "log"))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn files_rotate_at_the_size_threshold() {
+        let directory = directory("rotate");
+        let path = directory.join("queries.log");
+        let mut log = RotatingWriter::open(path.clone(), 100).unwrap();
+        for index in 0..10 {
+            // 30 bytes with the newline: three fit in 100.
+            log.write_line(&format!("query {:02} {}", index, "x".repeat(20)))
+                .unwrap();
+        }
+        log.flush().unwrap();
+
+        let rotated = rotated_files(&directory);
+        assert_eq!(rotated.len(), 3);
+        for file in &rotated {
+            assert_eq!(fs::read_to_string(file).unwrap().lines().count(), 3);
+        }
+        assert!(fs::read_to_string(&rotated[0])
+            .unwrap()
+            .starts_with("query 00"));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        // Reopening continues the count where the file left off.
+        let mut log = RotatingWriter::open(path.clone(), 100).unwrap();
+        log.write_line("query 10").unwrap();
+        log.write_line("query 11").unwrap();
+        assert_eq!(rotated_files(&directory).len(), 3);
+        log.write_line(&"y".repeat(60)).unwrap();
+        assert_eq!(rotated_files(&directory).len(), 4);
+    }
+
+    #[test]
+    fn the_sweep_removes_the_oldest_files_beyond_the_limits() {
+        let directory = directory("sweep");
+        let path = directory.join("queries.log");
+        fs::write(&path, "x".repeat(100)).unwrap();
+        // Unrelated files that only share a prefix are left alone.
+        fs::write(directory.join("queries.logger"), "").unwrap();
+        let now = SystemTime::now();
+        for hours in 1..=6u64 {
+            let file = directory.join(format!("queries.log.{}", 100 - hours));
+            fs::write(&file, "x".repeat(100)).unwrap();
+            File::options()
+                .write(true)
+                .open(&file)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(3600 * hours))
+                .unwrap();
+        }
+
+        let count = Retention::parse("query-log:max-files=5").unwrap();
+        assert_eq!(
+            count.sweep(&path, now).unwrap(),
+            [directory.join("queries.log.94")]
+        );
+        let age = Retention::parse("query-log:max-age=3h").unwrap();
+        assert_eq!(
+            age.sweep(&path, now).unwrap(),
+            [
+                directory.join("queries.log.96"),
+                directory.join("queries.log.95")
+            ]
+        );
+        // The current file counts towards the total but is never removed.
+        let size = Retention::parse("query-log:max-size=350").unwrap();
+        assert_eq!(
+            size.sweep(&path, now).unwrap(),
+            [directory.join("queries.log.97")]
+        );
+        assert_eq!(
+            rotated_files(&directory),
+            [
+                directory.join("queries.log.98"),
+                directory.join("queries.log.99"),
+                directory.join("queries.logger"),
+            ]
+        );
+
+        assert!(Retention::parse("dnstap:max-files=5").is_err());
+        assert!(Retention::parse("query-log:max-size=lots").is_err());
+        assert!(Retention::parse("query-log:max-hours=5").is_err());
+    }
+
+    #[test]
+    fn sighup_reopens_without_losing_buffered_lines() {
+        let directory = directory("hangup");
+        let path = directory.join("queries.log");
+        let housekeeping = Housekeeping {
+            query_log: Some(Arc::new(Mutex::new(
+                RotatingWriter::open(path.clone(), 1 << 20).unwrap(),
+            ))),
+            ..Default::default()
+        };
+        install_hangup_handler();
+        housekeeping.log_query("before");
+        // As logrotate does: move the file, then signal.
+        let moved = directory.join("queries.log.1");
+        fs::rename(&path, &moved).unwrap();
+        housekeeping.log_query("buffered");
+        // SAFETY: raising a signal for which a handler is installed.
+        unsafe {
+            libc::raise(libc::SIGHUP);
+        }
+        housekeeping.maintain(Instant::now());
+        housekeeping.log_query("after");
+        housekeeping.maintain(Instant::now());
+
+        assert_eq!(fs::read_to_string(&moved).unwrap(), "before\nbuffered\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "after\n");
+    }
+}
@@ -0,0 +1,329 @@
+// The record types RFC 1035 defined and later retired, which old authoritative
+// servers and some monitoring tools still serve: MD and MF (obsoleted by MX, RFC
+// 973), the experimental mail types MB, MG and MR, WKS (RFC 1123, 6.1.3.6) and NULL.
+// They are relayed byte for byte like any other type; this gives them presentation
+// forms, so that they can be pinned, shown and checked.
+
+use std::{fmt, net::Ipv4Addr, sync::Arc};
+
+use super::dns::message::{LabelSequence, LabelSequenceParseError, RecordType};
+
+pub const MD: RecordType = RecordType::Unknown(3);
+pub const MF: RecordType = RecordType::Unknown(4);
+pub const MB: RecordType = RecordType::Unknown(7);
+pub const MG: RecordType = RecordType::Unknown(8);
+pub const MR: RecordType = RecordType::Unknown(9);
+pub const NULL: RecordType = RecordType::Unknown(10);
+pub const WKS: RecordType = RecordType::Unknown(11);
+
+// What a zone check says about each type.
+pub fn deprecation(r#type: RecordType) -> Option<&'static str> {
+    match r#type {
+        MD | MF => Some("obsolete; use MX (RFC 973)"),
+        MB | MG | MR => Some("experimental and never widely used (RFC 1035, 3.3)"),
+        NULL => Some("experimental and not allowed in master files (RFC 1035, 3.3.10)"),
+        WKS => Some("not to be relied on (RFC 1123, 6.1.3.6)"),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum LegacyRdata {
+    // MD, MF, MB, MG and MR: a host or mailbox name.
+    Name(LabelSequence),
+    // A host's address, an IP protocol number, and a bitmap of the ports it serves
+    // with that protocol, kept as given so that trailing zero bytes survive.
+    Wks {
+        address: Ipv4Addr,
+        protocol: u8,
+        bitmap: Vec<u8>,
+    },
+    // Anything at all.
+    Null(Vec<u8>),
+}
+
+fn parse_name(value: &str) -> Result<LegacyRdata, String> {
+    value
+        .parse::<LabelSequence>()
+        .map(LegacyRdata::Name)
+        .map_err(|err: LabelSequenceParseError| err.message)
+}
+
+// WKS as ADDRESS PROTOCOL PORT..., the protocol by number or as TCP or UDP.
+fn parse_wks(values: &[&str]) -> Result<LegacyRdata, String> {
+    let [address, protocol, ports @ ..] = values else {
+        return Err(String::from("WKS needs an address, a protocol and ports."));
+    };
+    let address: Ipv4Addr = address
+        .parse()
+        .map_err(|_| format!("'{}' is not an IPv4 address.", address))?;
+    let protocol: u8 = match protocol.to_ascii_uppercase().as_str() {
+        "TCP" => 6,
+        "UDP" => 17,
+        number => number
+            .parse()
+            .map_err(|_| format!("'{}' is not an IP protocol.", protocol))?,
+    };
+    let mut bitmap: Vec<u8> = Vec::new();
+    for port in ports {
+        let port: u16 = port
+            .parse()
+            .map_err(|_| format!("'{}' is not a port.", port))?;
+        let index = port as usize / 8;
+        if bitmap.len() <= index {
+            bitmap.resize(index + 1, 0);
+        }
+        bitmap[index] |= 0x80 >> (port % 8);
+    }
+    Ok(LegacyRdata::Wks {
+        address,
+        protocol,
+        bitmap,
+    })
+}
+
+// NULL in the generic form of RFC 3597, 5: \# LENGTH HEX...
+fn parse_null(values: &[&str]) -> Result<LegacyRdata, String> {
+    let [r"\#", length, hex @ ..] = values else {
+        return Err(String::from(r"NULL data is written as \# LENGTH HEX..."));
+    };
+    let length: usize = length
+        .parse()
+        .map_err(|_| format!("'{}' is not a length.", length))?;
+    let hex: String = hex.concat();
+    if hex.len() % 2 != 0 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(format!("'{}' is not hexadecimal.", hex));
+    }
+    let data: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).unwrap())
+        .collect();
+    if data.len() != length {
+        return Err(format!(
+            "{} byte(s) of data, but a length of {}.",
+            data.len(),
+            length
+        ));
+    }
+    Ok(LegacyRdata::Null(data))
+}
+
+impl LegacyRdata {
+    // Parses the presentation form of the records of an RRset: a name per record for
+    // the mail types, and one record of all the values for WKS and NULL.
+    pub fn parse(r#type: RecordType, values: &[&str]) -> Result<Vec<LegacyRdata>, String> {
+        match r#type {
+            MD | MF | MB | MG | MR => values.iter().map(|value| parse_name(value)).collect(),
+            WKS => Ok(vec![parse_wks(values)?]),
+            NULL => Ok(vec![parse_null(values)?]),
+            _ => Err(format!("{} is not a legacy type.", r#type)),
+        }
+    }
+
+    // Reads the RDATA of a record of a legacy type; None for other types, or if the
+    // data is malformed.
+    pub fn decode(r#type: RecordType, data: &[u8]) -> Option<LegacyRdata> {
+        match r#type {
+            MD | MF | MB | MG | MR => match LabelSequence::decode_uncompressed(data)? {
+                (name, size) if size == data.len() => Some(LegacyRdata::Name(name)),
+                _ => None,
+            },
+            WKS => Some(LegacyRdata::Wks {
+                address: Ipv4Addr::from(<[u8; 4]>::try_from(data.get(..4)?).ok()?),
+                protocol: *data.get(4)?,
+                bitmap: data[5..].to_vec(),
+            }),
+            NULL => Some(LegacyRdata::Null(data.to_vec())),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Arc<[u8]> {
+        match self {
+            LegacyRdata::Name(name) => name.encode(),
+            LegacyRdata::Wks {
+                address,
+                protocol,
+                bitmap,
+            } => {
+                let mut data = address.octets().to_vec();
+                data.push(*protocol);
+                data.extend_from_slice(bitmap);
+                data.into()
+            }
+            LegacyRdata::Null(data) => Arc::from(data.as_slice()),
+        }
+    }
+}
+
+impl fmt::Display for LegacyRdata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LegacyRdata::Name(name) => write!(f, "{}", name),
+            LegacyRdata::Wks {
+                address,
+                protocol,
+                bitmap,
+            } => {
+                write!(f, "{} {}", address, protocol)?;
+                for (index, byte) in bitmap.iter().enumerate() {
+                    for bit in 0..8 {
+                        if byte & (0x80 >> bit) != 0 {
+                            write!(f, " {}", index * 8 + bit)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            LegacyRdata::Null(data) => {
+                write!(f, r"\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " ")?;
+                }
+                data.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dns::message::{Answer, Header, Message, Question, RecordClass};
+    use super::super::zone_check::validate;
+    use super::super::{parse_record_type, PinStore, Severity};
+    use super::*;
+
+    // A dump of a legacy zone, one record per line: NAME TTL TYPE RDATA.
+    const LEGACY_ZONE: &str = r"
+        legacy.example 3600 MD mail.legacy.example
+        legacy.example 3600 MF relay.legacy.example
+        postmaster.legacy.example 3600 MB mail.legacy.example
+        staff.legacy.example 3600 MG alice.legacy.example
+        staff.legacy.example 3600 MG bob.legacy.example
+        alias.legacy.example 3600 MR alice.legacy.example
+        blob.legacy.example 3600 NULL \# 4 deadbeef
+        empty.legacy.example 3600 NULL \# 0
+        host.legacy.example 3600 WKS 192.0.2.1 6 21 25 80 1023
+    ";
+
+    fn lines(zone: &str) -> Vec<Vec<&str>> {
+        zone.lines()
+            .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+            .filter(|fields| !fields.is_empty())
+            .collect()
+    }
+
+    // Loads a zone into pins, an RRset per name and type, in the order of the lines.
+    fn load(zone: &str) -> Vec<Answer> {
+        let pins = PinStore::default();
+        let mut questions: Vec<Question> = Vec::new();
+        let lines = lines(zone);
+        for fields in &lines {
+            let [name, ttl, r#type, ..] = fields.as_slice() else {
+                panic!("malformed line: {:?}", fields);
+            };
+            let question = Question::new(
+                &Arc::new(name.parse().unwrap()),
+                parse_record_type(r#type).unwrap(),
+                RecordClass::In,
+            );
+            if questions
+                .iter()
+                .any(|other| other.encode() == question.encode())
+            {
+                continue;
+            }
+            // WKS and NULL take all of a line; the mail types a name per line.
+            let values: Vec<&str> = if question.get_type() == WKS || question.get_type() == NULL {
+                fields[3..].to_vec()
+            } else {
+                lines
+                    .iter()
+                    .filter(|other| other[0] == *name && other[2] == *r#type)
+                    .map(|other| other[3])
+                    .collect()
+            };
+            pins.pin(name, r#type, ttl, &values).unwrap();
+            questions.push(question);
+        }
+        questions
+            .iter()
+            .flat_map(|question| pins.lookup(question).unwrap())
+            .collect()
+    }
+
+    fn wire(records: &[Answer]) -> Vec<Arc<[u8]>> {
+        records.iter().map(Answer::encode).collect()
+    }
+
+    #[test]
+    fn legacy_zones_load_and_dump_losslessly() {
+        let records = load(LEGACY_ZONE);
+        assert_eq!(records.len(), 9);
+
+        // Dumped back to presentation form, the zone reads the same.
+        let dump: Vec<String> = records
+            .iter()
+            .map(|record| {
+                let rdata = LegacyRdata::decode(record.get_type(), &record.get_data()).unwrap();
+                format!(
+                    "{} {} {} {}",
+                    record.get_name(),
+                    record.get_ttl(),
+                    record.get_type(),
+                    rdata
+                )
+            })
+            .collect();
+        let expected: Vec<String> = lines(LEGACY_ZONE)
+            .iter()
+            .map(|fields| fields.join(" "))
+            .collect();
+        assert_eq!(dump, expected);
+        assert_eq!(wire(&load(&dump.join("\n"))), wire(&records));
+
+        // So does the wire form, as in a zone transfer.
+        let mut header = Header::default();
+        header.set_an_count(records.len() as u16);
+        let response = Message::new(
+            &Arc::new(header),
+            &Arc::from([]),
+            &Arc::from(records.as_slice()),
+        );
+        let transferred = Message::parse_from(&response.encode()).unwrap();
+        assert_eq!(wire(transferred.get_answers()), wire(&records));
+        assert_eq!(
+            records[8].to_string(),
+            "host.legacy.example.    3600    IN    WKS    192.0.2.1 6 21 25 80 1023"
+        );
+
+        // Served as is, with a warning per RRset.
+        let diagnostics = validate(&records, None);
+        assert_eq!(diagnostics.len(), 8);
+        assert!(diagnostics
+            .iter()
+            .all(|diagnostic| diagnostic.severity == Severity::Warning));
+    }
+
+    #[test]
+    fn malformed_legacy_data_is_rejected() {
+        for (r#type, values) in [
+            (WKS, &["192.0.2.1"][..]),
+            (WKS, &["192.0.2", "6", "25"]),
+            (WKS, &["192.0.2.1", "SCTP", "25"]),
+            (WKS, &["192.0.2.1", "6", "65536"]),
+            (NULL, &["deadbeef"]),
+            (NULL, &[r"\#", "3", "deadbeef"]),
+            (NULL, &[r"\#", "1", "xy"]),
+            (MB, &["a..example"]),
+        ] {
+            assert!(LegacyRdata::parse(r#type, values).is_err(), "{:?}", values);
+        }
+        assert_eq!(
+            LegacyRdata::parse(WKS, &["192.0.2.1", "udp", "53"]).unwrap()[0].to_string(),
+            "192.0.2.1 17 53"
+        );
+        assert!(LegacyRdata::decode(WKS, &[192, 0, 2]).is_none());
+        assert!(LegacyRdata::decode(MD, &[3, b'c', b'o', b'm']).is_none());
+    }
+}
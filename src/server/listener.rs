@@ -0,0 +1,226 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
+    os::fd::RawFd,
+    time::Duration,
+};
+
+use super::overload::Load;
+
+// The port listeners are on unless told otherwise, the one the codecrafters tests use.
+pub const DEFAULT_PORT: u16 = 2053;
+
+// A listener as given on the command line: "[NAME=]ADDRESS[:PORT]", on DEFAULT_PORT
+// (or --port) without a port. Unnamed listeners are tagged with their address.
+#[derive(Clone, Debug)]
+pub struct ListenerSpec {
+    pub tag: String,
+    pub address: SocketAddr,
+    port_given: bool,
+}
+
+impl ListenerSpec {
+    pub fn parse(value: &str) -> Result<ListenerSpec, String> {
+        let (tag, address) = match value.split_once('=') {
+            Some((tag, address)) => (Some(tag.trim()), address.trim()),
+            None => (None, value.trim()),
+        };
+        let (address, port_given) = match address.parse::<SocketAddr>() {
+            Ok(address) => (address, true),
+            Err(_) => match address.parse::<IpAddr>() {
+                Ok(ip) => (SocketAddr::new(ip, DEFAULT_PORT), false),
+                Err(_) => {
+                    return Err(format!(
+                    "'{}' is not a valid ADDRESS[:PORT] (e.g. 0.0.0.0, 192.0.2.1:53, [::1]:53).",
+                    address
+                ))
+                }
+            },
+        };
+        let tag = match tag {
+            Some(tag) if tag.is_empty() || tag.contains(char::is_whitespace) => {
+                return Err(format!(
+                    "Listener name '{}' must be non-empty and contain no whitespace.",
+                    tag
+                ))
+            }
+            Some(tag) => tag.to_string(),
+            None => address.to_string(),
+        };
+        Ok(ListenerSpec {
+            tag,
+            address,
+            port_given,
+        })
+    }
+
+    // The listener on `port` (--port) if it was given without a port of its own.
+    pub fn or_port(mut self, port: u16) -> ListenerSpec {
+        if self.port_given {
+            return self;
+        }
+        let unnamed = self.tag == self.address.to_string();
+        self.address.set_port(port);
+        if unnamed {
+            self.tag = self.address.to_string();
+        }
+        self
+    }
+}
+
+// Why binding the listener `spec` failed, for the operator, with a hint at the
+// usual causes.
+pub fn bind_failure(spec: &ListenerSpec, err: &io::Error) -> String {
+    let hint = match err.kind() {
+        io::ErrorKind::PermissionDenied => "; ports below 1024 need root or CAP_NET_BIND_SERVICE",
+        io::ErrorKind::AddrInUse => {
+            "; something else is serving there (see --port; 0 picks a free port)"
+        }
+        io::ErrorKind::AddrNotAvailable => "; no interface of this host has that address",
+        _ => "",
+    };
+    format!(
+        "Couldn't bind [{}] to {} (UDP and TCP): {}{}",
+        spec.tag, spec.address, err, hint
+    )
+}
+
+pub struct Listener {
+    pub tag: String,
+    pub socket: UdpSocket,
+    // Accepts DNS over TCP (RFC 7766) on the same address and port.
+    pub stream_listener: TcpListener,
+}
+
+impl Listener {
+    // Binds non-blocking sockets, so that the server loop can drain them without
+    // ever blocking on one listener.
+    pub fn bind(spec: &ListenerSpec) -> io::Result<Listener> {
+        // The UDP socket's address, so that port 0 gets the same port for both. The
+        // port the system picked for UDP may be taken for TCP; then pick another.
+        let mut attempts = 0;
+        let (socket, stream_listener) = loop {
+            let socket = UdpSocket::bind(spec.address)?;
+            match TcpListener::bind(socket.local_addr()?) {
+                Ok(stream_listener) => break (socket, stream_listener),
+                Err(e)
+                    if e.kind() == io::ErrorKind::AddrInUse
+                        && spec.address.port() == 0
+                        && attempts < 16 =>
+                {
+                    attempts += 1
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        socket.set_nonblocking(true)?;
+        stream_listener.set_nonblocking(true)?;
+        Ok(Listener {
+            tag: spec.tag.clone(),
+            socket,
+            stream_listener,
+        })
+    }
+
+    // A listener on the same sockets, for another thread to answer from.
+    pub fn try_clone(&self) -> io::Result<Listener> {
+        Ok(Listener {
+            tag: self.tag.clone(),
+            socket: self.socket.try_clone()?,
+            stream_listener: self.stream_listener.try_clone()?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+// What the handlers know about where a request came from, and how busy the server
+// was when it arrived.
+pub struct QueryInfo<'a> {
+    pub listener: &'a str,
+    pub client: SocketAddr,
+    pub transport: Transport,
+    pub load: Load,
+}
+
+// Blocks until at least one of the sockets is readable (or, for a listening TCP
+// socket, has a connection waiting) or `timeout` passes, and returns the indices of
+// the readable ones (none on timeout, or when a signal interrupts the wait, for the
+// caller to see to it, e.g. to shut down on SIGTERM).
+pub fn wait_readable(sockets: &[RawFd], timeout: Duration) -> io::Result<Vec<usize>> {
+    // Rounded up, so that a timer is never woken for just before its deadline.
+    let timeout_ms = timeout
+        .as_micros()
+        .div_ceil(1000)
+        .min(libc::c_int::MAX as u128) as libc::c_int;
+    let mut fds: Vec<libc::pollfd> = sockets
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    // SAFETY: `fds` is a valid, exclusively borrowed array of `fds.len()` pollfd structs.
+    let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+    if result < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(Vec::new());
+        }
+        return Err(err);
+    }
+    Ok(fds
+        .iter()
+        .enumerate()
+        .filter(|(_, fd)| fd.revents != 0)
+        .map(|(index, _)| index)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_without_a_port_take_the_default_or_port() {
+        let spec = ListenerSpec::parse("0.0.0.0").unwrap();
+        assert_eq!(spec.address, "0.0.0.0:2053".parse().unwrap());
+        assert_eq!(spec.tag, "0.0.0.0:2053");
+        let spec = spec.or_port(53);
+        assert_eq!(spec.address, "0.0.0.0:53".parse().unwrap());
+        assert_eq!(spec.tag, "0.0.0.0:53");
+
+        // Names are kept, and ports given win over --port.
+        let spec = ListenerSpec::parse("lan=::1").unwrap().or_port(53);
+        assert_eq!(
+            (spec.tag.as_str(), spec.address),
+            ("lan", "[::1]:53".parse().unwrap())
+        );
+        let spec = ListenerSpec::parse("192.0.2.1:5353").unwrap().or_port(53);
+        assert_eq!(spec.address, "192.0.2.1:5353".parse().unwrap());
+
+        for value in ["localhost", "192.0.2.1:dns", "=127.0.0.1", "a b=127.0.0.1"] {
+            assert!(ListenerSpec::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn bind_failures_say_where_and_why() {
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let spec = ListenerSpec::parse(&format!("lan={}", taken.local_addr().unwrap())).unwrap();
+        let err = Listener::bind(&spec).err().unwrap();
+        assert_eq!(
+            bind_failure(&spec, &err),
+            format!(
+                "Couldn't bind [lan] to {} (UDP and TCP): {}; something else is serving there \
+                 (see --port; 0 picks a free port)",
+                spec.address, err
+            )
+        );
+    }
+}
@@ -0,0 +1,166 @@
+// Maintains the handlers on a thread of their own, for the server loop not to wait
+// on what their maintenance does: paced upstream queries (see pacing.rs), saving
+// upstream state, reloading files. It runs each handler's maintenance on the regular
+// interval, and early for a handler with work due sooner. Work a query schedules
+// while the maintainer sleeps (e.g. a warm-up) is noticed within POLL_INTERVAL.
+
+use std::{
+    io,
+    sync::{mpsc, Arc},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use super::pacing::JitteredInterval;
+use super::random::Randomness;
+use super::{HandleOpcode, MAINTENANCE_INTERVAL, MAINTENANCE_JITTER};
+
+// Longest the maintainer sleeps before looking at what the handlers have due again.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub struct Maintainer {
+    // Dropped to stop the thread.
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Maintainer {
+    pub fn spawn(
+        handlers: Vec<Arc<dyn HandleOpcode>>,
+        randomness: &Randomness,
+    ) -> io::Result<Maintainer> {
+        let (stop, stopped) = mpsc::channel();
+        let interval = JitteredInterval::new(
+            MAINTENANCE_INTERVAL,
+            MAINTENANCE_JITTER,
+            Instant::now(),
+            randomness.stream("maintainer"),
+        );
+        let thread = thread::Builder::new()
+            .name(String::from("maintainer"))
+            .spawn(move || maintain(&handlers, &stopped, interval))?;
+        Ok(Maintainer { stop, thread })
+    }
+
+    // Waits for the maintenance under way, if any, to finish, and stops the thread.
+    pub fn stop(self) -> io::Result<()> {
+        drop(self.stop);
+        self.thread
+            .join()
+            .map_err(|_| io::Error::other("the maintainer panicked"))
+    }
+}
+
+fn maintain(
+    handlers: &[Arc<dyn HandleOpcode>],
+    stopped: &mpsc::Receiver<()>,
+    mut interval: JitteredInterval,
+) {
+    loop {
+        let now = Instant::now();
+        let wake_at = handlers
+            .iter()
+            .filter_map(|handler| handler.next_due())
+            .fold(interval.next_due, Instant::min)
+            .min(now + POLL_INTERVAL);
+        match stopped.recv_timeout(wake_at.saturating_duration_since(now)) {
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            _ => return,
+        }
+
+        let now = Instant::now();
+        let regular = interval.is_due(now);
+        for handler in handlers {
+            if regular || handler.next_due().is_some_and(|due| due <= now) {
+                handler.maintain();
+            }
+        }
+        if regular {
+            interval.reschedule(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, sync::Mutex};
+
+    use super::super::dns::message::{Header, Message, OpCode, RCode};
+    use super::super::handlers::response_header;
+    use super::super::testing::{dummy_server, query, Counter};
+    use super::super::{LoopState, QueryInfo, Stats};
+    use super::*;
+
+    // Answers every query at once; its maintenance, when due, takes a second, as
+    // paced queries to a slow upstream would.
+    struct SlowMaintenance {
+        due: Mutex<Option<Instant>>,
+        maintained: Counter,
+    }
+
+    impl HandleOpcode for SlowMaintenance {
+        fn opcode(&self) -> OpCode {
+            OpCode::Query
+        }
+
+        fn handle(
+            &self,
+            _info: &QueryInfo,
+            header: &Header,
+            _data: &[u8],
+            _stats: &Stats,
+        ) -> Option<Message> {
+            Some(Message::new(
+                &response_header(header, RCode::NoError).into(),
+                &Arc::from([]),
+                &Arc::from([]),
+            ))
+        }
+
+        fn maintain(&self) {
+            if self.due.lock().unwrap().take().is_some() {
+                thread::sleep(Duration::from_secs(1));
+                self.maintained.increment();
+            }
+        }
+
+        fn next_due(&self) -> Option<Instant> {
+            *self.due.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn queries_are_answered_while_the_maintainer_waits_on_an_upstream() {
+        let maintained = Counter::default();
+        let handler: Arc<dyn HandleOpcode> = Arc::new(SlowMaintenance {
+            due: Mutex::new(Some(Instant::now())),
+            maintained: maintained.clone(),
+        });
+        let mut server = dummy_server();
+        server.handlers = vec![handler];
+        server.maintainer =
+            Some(Maintainer::spawn(server.handlers.clone(), &server.randomness).unwrap());
+        // The maintenance is under way.
+        thread::sleep(Duration::from_millis(200));
+
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let started = Instant::now();
+        client
+            .send_to(&query("example.com", None), address)
+            .unwrap();
+        let mut state = LoopState::new(&server.randomness);
+        server.run_once(&mut state).unwrap();
+        let mut buf = [0; 512];
+        client.recv_from(&mut buf).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(maintained.get(), 0);
+
+        // Stopping waits for it to finish.
+        server.maintainer.take().unwrap().stop().unwrap();
+        assert_eq!(maintained.get(), 1);
+    }
+}
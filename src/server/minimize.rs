@@ -0,0 +1,203 @@
+use std::sync::Arc;
+
+use super::dns::message::{Answer, Message, RecordType};
+use super::stats::Stats;
+
+const RRSIG: RecordType = RecordType::Unknown(46);
+
+// How hard a response is squeezed to fit a size budget before TC is set, which
+// costs the client a retry over TCP.
+pub struct MinimizationPolicy {
+    // Most A or AAAA records kept per RRset when trimming; clients rarely need more.
+    pub max_addresses_per_rrset: usize,
+    // Largest UDP response sent, whatever the client advertised; 512 bytes at least.
+    pub max_udp_size: usize,
+}
+
+impl MinimizationPolicy {
+    // Returns a copy of the response that encodes to at most `max_size` bytes, trying
+    // in turn: dropping the additional section but for the OPT record, dropping the
+    // authority section, which no more than the additional one is needed to use the
+    // answer (RFC 2181, 9), trimming address RRsets to `max_addresses_per_rrset`, and
+    // only then truncating with TC (Message::truncate_to). Every step that had to be
+    // taken is counted in `stats`.
+    //
+    // RRsets covered by an RRSIG are never trimmed, as the signature covers the whole
+    // set; truncation keeps or drops them together with their RRSIGs.
+    pub fn fit(&self, response: &Message, max_size: usize, stats: &Stats) -> Message {
+        if response.encode().len() <= max_size {
+            return response.clone();
+        }
+
+        let mut response = response.clone();
+        let without_additionals = response.without_additionals();
+        if without_additionals.get_additionals().len() < response.get_additionals().len() {
+            response = without_additionals;
+            stats.minimized_additionals.increment();
+            if response.encode().len() <= max_size {
+                return response;
+            }
+        }
+
+        if !response.get_authorities().is_empty() {
+            response = response.with_authorities(&Arc::from([]));
+            stats.minimized_authorities.increment();
+            if response.encode().len() <= max_size {
+                return response;
+            }
+        }
+
+        let trimmed = self.trim_addresses(&response);
+        if trimmed.get_answers().len() < response.get_answers().len() {
+            response = trimmed;
+            stats.minimized_addresses.increment();
+            if response.encode().len() <= max_size {
+                return response;
+            }
+        }
+
+        response.truncate_to(max_size)
+    }
+
+    fn trim_addresses(&self, response: &Message) -> Message {
+        let answers: Vec<Answer> = response
+            .group_answers_into_rrsets()
+            .into_iter()
+            .flat_map(|mut rrset| {
+                let trimmable = matches!(rrset[0].get_type(), RecordType::A | RecordType::Aaaa)
+                    && rrset.iter().all(|answer| answer.get_type() != RRSIG);
+                if trimmable {
+                    rrset.truncate(self.max_addresses_per_rrset.max(1));
+                }
+                rrset
+            })
+            .collect();
+        response.with_answers(&answers.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dns::message::{Header, Question, RData, RecordClass};
+    use super::super::edns::UDP_PAYLOAD_SIZE;
+    use super::super::provenance::annotation;
+    use super::*;
+
+    const BUDGET: usize = 512;
+
+    fn record(r#type: RecordType, data: &[u8]) -> Answer {
+        Answer::new(
+            /* name= */ &Arc::new("www.example.com".parse().unwrap()),
+            /* type= */ r#type,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */ &Arc::from(data),
+        )
+    }
+
+    // A response for www.example.com A with `count` addresses, an annotation in
+    // the additional section and, if `signed`, an RRSIG over the addresses.
+    fn response(count: u8, signed: bool) -> Message {
+        let mut answers: Vec<Answer> = (0..count)
+            .map(|i| record(RecordType::A, &[192, 0, 2, i]))
+            .collect();
+        if signed {
+            let mut rrsig = vec![0, 1];
+            rrsig.extend([0; 100]);
+            answers.push(record(RRSIG, &rrsig));
+        }
+        let question = Question::new(
+            &Arc::new("www.example.com".parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        );
+        let mut header = Header::default();
+        header
+            .set_qr(true)
+            .set_qd_count(1)
+            .set_an_count(answers.len() as u16);
+        Message::new(&Arc::new(header), &Arc::from([question]), &answers.into())
+            .with_additionals(&Arc::from([annotation("source=static")]))
+    }
+
+    fn policy() -> MinimizationPolicy {
+        MinimizationPolicy {
+            max_addresses_per_rrset: 8,
+            max_udp_size: UDP_PAYLOAD_SIZE as usize,
+        }
+    }
+
+    #[test]
+    fn fitting_responses_are_left_alone() {
+        let stats = Stats::default();
+        let response = response(3, false);
+        let fitted = policy().fit(&response, BUDGET, &stats);
+        assert_eq!(fitted.encode(), response.encode());
+        assert_eq!(stats.snapshot().minimized_additionals, 0);
+        assert_eq!(stats.snapshot().minimized_addresses, 0);
+    }
+
+    #[test]
+    fn trimming_addresses_avoids_truncation() {
+        let stats = Stats::default();
+        let response = response(40, false);
+        assert!(response.encode().len() > BUDGET);
+
+        let fitted = policy().fit(&response, BUDGET, &stats);
+        assert!(fitted.encode().len() <= BUDGET);
+        assert!(!fitted.get_header().get_tc());
+        assert_eq!(fitted.get_answers().len(), 8);
+        assert_eq!(fitted.get_header().get_an_count(), 8);
+        assert!(fitted.get_additionals().is_empty());
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.minimized_additionals, 1);
+        assert_eq!(snapshot.minimized_addresses, 1);
+    }
+
+    // `count` NS records for example.com, with long names, for the authority section.
+    fn name_servers(count: usize) -> Arc<[Answer]> {
+        (0..count)
+            .map(|i| {
+                let server = format!("name-server-{:02}.hosting-provider.example.net", i);
+                Answer::from_rdata(
+                    /* name= */ &Arc::new("example.com".parse().unwrap()),
+                    /* class= */ RecordClass::In,
+                    /* ttl= */ 300,
+                    /* data= */ RData::Ns(server.parse().unwrap()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dropping_authorities_keeps_the_addresses() {
+        let stats = Stats::default();
+        let response = response(12, false).with_authorities(&name_servers(12));
+        assert!(response.without_additionals().encode().len() > BUDGET);
+
+        let fitted = policy().fit(&response, BUDGET, &stats);
+        assert!(fitted.encode().len() <= BUDGET);
+        assert!(!fitted.get_header().get_tc());
+        // More than max_addresses_per_rrset, as they fit without the authorities.
+        assert_eq!(fitted.get_answers().len(), 12);
+        assert!(fitted.get_authorities().is_empty());
+        assert_eq!(fitted.get_header().get_ns_count(), 0);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.minimized_additionals, 1);
+        assert_eq!(snapshot.minimized_authorities, 1);
+        assert_eq!(snapshot.minimized_addresses, 0);
+    }
+
+    #[test]
+    fn signed_rrsets_are_truncated_rather_than_trimmed() {
+        let stats = Stats::default();
+        let response = response(40, true);
+
+        let fitted = policy().fit(&response, BUDGET, &stats);
+        assert!(fitted.get_header().get_tc());
+        assert!(fitted.get_answers().is_empty());
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.minimized_additionals, 1);
+        assert_eq!(snapshot.minimized_addresses, 0);
+    }
+}
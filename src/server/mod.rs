@@ -1,123 +1,3197 @@
 use std::{
-    net::{SocketAddrV4, UdpSocket},
-    os::unix::net::SocketAddr,
-    rc::Rc,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    os::fd::{AsRawFd, RawFd},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-mod dns;
+mod address_filter;
+mod anomaly;
+mod authenticated;
+mod bad_packets;
+mod blocklist;
+mod breaker;
+mod budget;
+mod cache;
+mod chain;
+mod client;
+mod connection;
+mod control;
+mod crosscheck;
+pub mod dns;
+#[cfg(feature = "mdns")]
+mod dns_sd;
+mod dso;
+mod edns;
+mod expiry;
+mod forward_zones;
+mod handlers;
+mod hosts;
+mod housekeeping;
+mod legacy;
+mod listener;
+mod maintainer;
+mod minimize;
+mod notify;
+mod nta;
+mod overload;
+mod pacing;
+mod pins;
+mod policy;
+mod probe;
+#[cfg(feature = "profiling")]
+mod profiling;
+mod provenance;
+mod query;
+mod quota;
+mod random;
+mod records;
+mod redirect;
+mod replay;
+mod resolver_chain;
+mod reverse;
+mod rpz;
+mod schedule;
+#[cfg(feature = "scripting")]
+mod script;
+mod search;
+mod secondary;
+mod shutdown;
+mod signature_time;
+// Not called outside its tests until TSIG or SIG(0) verification lands.
+#[cfg(test)]
+mod signature_window;
+mod slo;
+mod split_brain;
+mod stats;
+mod support;
+#[cfg(test)]
+mod testing;
+mod transaction;
+mod truncation;
+#[cfg(feature = "dnssec")]
+mod trust_anchors;
+mod ttl_honesty;
+mod upstream_state;
+mod upstreams;
+mod verify;
+#[cfg(test)]
+mod wire_corpus;
+mod workers;
+mod zone;
+mod zone_check;
+mod zone_file;
 
-use dns::message::{Answer, Header, Message, OpCode, Question, RCode};
+pub use address_filter::{AaaaFiltering, AddressFilter};
+pub use anomaly::{AnomalyDetector, AnomalyThresholds};
+pub use authenticated::AdMode;
+pub use bad_packets::BadPackets;
+pub use blocklist::{BlockMode, Blocklist, BlocklistResolver};
+pub use breaker::CircuitBreaker;
+use budget::Work;
+pub use cache::CachingResolver;
+pub use client::{Client, ClientError, UpstreamSpec};
+use connection::Connection;
+pub use control::ControlSocket;
+pub use crosscheck::{parse_percent, CrossCheckingResolver, MismatchPolicy};
+pub use dns::message::{Answer, RCode, RData, RecordClass, RecordType};
+use dns::message::{
+    Appendix, DnsParseError, EncodedAnswers, Header, LabelSequence, LabelSequenceParseError,
+    Message, OpCode, Question,
+};
+#[cfg(feature = "mdns")]
+pub use dns_sd::ServiceRegistration;
+use dso::DsoOutcome;
+pub use edns::UDP_PAYLOAD_SIZE;
+use edns::{extended_rcode, response_padding, udp_response_limit};
+pub use expiry::ExpiryCounters;
+pub use forward_zones::{ConditionalForwarder, ForwardZone};
+use handlers::{format_error, response_header};
+pub use handlers::{HandleOpcode, QueryOpcodeHandler};
+pub use hosts::HostsResolver;
+pub use housekeeping::{
+    install_hangup_handler, parse_size, Housekeeping, Retention, RotatingWriter,
+};
+pub use listener::{bind_failure, Listener, ListenerSpec, DEFAULT_PORT};
+use listener::{wait_readable, QueryInfo, Transport};
+pub use maintainer::Maintainer;
+pub use minimize::MinimizationPolicy;
+pub use notify::Notifier;
+pub use nta::{NegativeTrustAnchors, NtaSpec};
+use overload::Load;
+pub use overload::OverloadPolicy;
+use pacing::JitteredInterval;
+pub use pacing::{Pacer, PacingStats};
+pub use pins::{PinStore, PinnedResolver};
+pub use policy::{parse_record_type, DomainSuffix, PolicyOutcome, QueryPolicy, Subnet};
+pub use probe::Probe;
+pub use provenance::{ProvenancePolicy, DEFAULT_PROVENANCE_OPTION};
+use query::Query;
+pub use quota::ClientQuotas;
+use quota::{Admission, QUEUE_FACTOR};
+use random::Rng;
+pub use random::{Randomness, SystemRng};
+pub use records::{LocalRecord, MailExchange, ServiceLocation, StaticDnsResolver, StaticRecords};
+pub use redirect::{NxdomainRedirect, NxdomainRedirectResolver};
+pub use replay::{read_capture, replay, CapturedResolver};
+pub use resolver_chain::{Fallthrough, ResolverChain};
+pub use reverse::{ClasslessDelegation, ReverseMapping};
+pub use rpz::ResponsePolicy;
+#[cfg(feature = "scripting")]
+pub use schedule::Schedule;
+#[cfg(feature = "scripting")]
+pub use script::{ScriptedPolicy, ScriptedQueryHandler, DEFAULT_FORWARDER};
+pub use search::SearchList;
+pub use secondary::{SecondaryResolver, SecondarySpec, SecondaryZones};
+pub use shutdown::install_termination_handler;
+use shutdown::{Phase, Shutdown};
+#[cfg(feature = "dnssec")]
+pub use signature_time::SystemClock;
+pub use slo::{SloSpec, SloTracker};
+use split_brain::exchange_over_tcp;
+pub use split_brain::SplitBrainCheck;
+pub use stats::Stats;
+pub use support::{dump_state_on_crash, Json, REDACTED};
+pub use transaction::{Transaction, TransactionError, ZoneDiff};
+pub use truncation::TruncationTracker;
+#[cfg(feature = "dnssec")]
+pub use trust_anchors::TrustAnchors;
+#[cfg(feature = "dnssec")]
+use trust_anchors::EDNS_KEY_TAG;
+pub use ttl_honesty::TtlHonesty;
+pub use upstream_state::UpstreamStateStore;
+pub use upstreams::{UpstreamCounts, UpstreamSet};
+pub use workers::WorkerPool;
+use workers::{Job, Reply};
+pub use zone::{Zone, ZoneResolver, ZoneSpec};
+pub use zone_check::Severity;
 
 pub struct DnsServer {
-    pub endpoint: UdpSocket,
-    pub resolver: Box<dyn Resolve>,
+    pub listeners: Vec<Listener>,
+    pub handlers: Vec<Arc<dyn HandleOpcode>>,
+    pub overload: OverloadPolicy,
+    // How many queries a connection, and a client, may have in flight at once.
+    pub quotas: Arc<ClientQuotas>,
+    pub minimization: MinimizationPolicy,
+    // Which addresses are filtered out of responses, and for which clients.
+    pub address_filter: AddressFilter,
+    // Whether encoded responses are read back and checked before being sent.
+    pub verify_encoding: bool,
+    // Where operators send commands at runtime, if anywhere.
+    pub control: Option<ControlSocket>,
+    // The files the server writes (e.g. the query log) and their retention.
+    pub housekeeping: Housekeeping,
+    // Watches the responses for DGA-like and tunneling-like query patterns.
+    pub anomalies: Option<Arc<AnomalyDetector>>,
+    // Where the randomized behaviour (e.g. timer jitter) draws from.
+    pub randomness: Randomness,
+    // The threads queries are handed to, if any; otherwise they're answered on this
+    // one.
+    pub workers: Option<WorkerPool>,
+    // The thread the handlers are maintained on, if any; otherwise they're maintained
+    // on this one, between queries.
+    pub maintainer: Option<Maintainer>,
+    pub stats: Arc<Stats>,
+}
+
+// Most datagrams served from one listener per loop iteration, so that a flood on
+// one listener can't starve the others or the maintenance timer.
+const LISTENER_BUDGET: usize = 64;
+
+// How often the maintenance tasks (e.g. saving upstream state) run, give or take
+// MAINTENANCE_JITTER of it.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+const MAINTENANCE_JITTER: f64 = 0.15;
+
+// Most responses the forwarder skips, as not answering its query, before giving up
+// on the upstream's answer.
+const MAX_STRAY_RESPONSES: usize = 4;
+
+// How long the queries already received may take to be answered at shutdown, and
+// how long each of the other shutdown steps should take.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+const TEARDOWN_TIMEOUT: Duration = Duration::from_millis(500);
+
+// What the dummy resolver answers AAAA questions with unless told otherwise: the
+// IPv6 counterpart of its 8.8.8.8.
+pub const DUMMY_IPV6_ADDRESS: Ipv6Addr = Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888);
+
+// Where the server loop is between iterations.
+struct LoopState {
+    // Listener served first in the next iteration; rotates for round-robin fairness.
+    first_listener: usize,
+    maintenance: JitteredInterval,
+    load: Load,
+    connections: Vec<Connection>,
+}
+
+impl LoopState {
+    fn new(randomness: &Randomness) -> LoopState {
+        LoopState {
+            first_listener: 0,
+            maintenance: JitteredInterval::new(
+                MAINTENANCE_INTERVAL,
+                MAINTENANCE_JITTER,
+                Instant::now(),
+                randomness.stream("maintenance"),
+            ),
+            load: Load::Normal,
+            connections: Vec::new(),
+        }
+    }
+}
+
+// What is torn down at shutdown: the server, and where its loop was.
+struct Stopping {
+    server: DnsServer,
+    state: LoopState,
 }
 
 impl DnsServer {
-    pub fn work(&self) {
-        let mut buf = [0; 512];
-        loop {
-            match self.endpoint.recv_from(&mut buf) {
-                Ok((size, source)) => {
-                    println!("Received {} bytes from client at {}", size, source);
-                    let request = Message::parse_from(&buf);
-                    println!("Received DNS message:\n{}", &request);
-
-                    let answers = self
-                        .resolver
-                        .resolve(&request.get_header(), request.get_questions());
-
-                    let mut header: Header = Header::default();
-                    header.set_id(request.get_header().get_id());
-                    header.set_qr(true);
-                    header.set_opcode(&request.get_header().get_opcode());
-                    header.set_rd(request.get_header().get_rd());
-                    header.set_rcode(&Rc::new(match request.get_header().get_opcode().as_ref() {
-                        OpCode::Query => RCode::NoError,
-                        _ => RCode::NotImplemented,
-                    }));
-                    header.set_qd_count(request.get_header().get_qd_count());
-                    header.set_an_count(answers.len() as u16);
-
-                    let response =
-                        Message::new(&header.into(), request.get_questions(), &answers.into());
-                    println!("Response:\n{}", &response);
-                    let encoded_response = response.encode();
-                    self.endpoint
-                        .send_to(&encoded_response, source)
-                        .expect("Failed to send response");
+    // A server answering on `listeners` with `handlers` and setting no limits of its
+    // own: it never judges itself overloaded and lets clients have any number of
+    // queries in flight. Responses are trimmed as --max-addresses-per-rrset and
+    // --max-udp-size have it by default, and nothing is filtered, logged or watched.
+    // Queries are answered, and the handlers maintained, on this thread. The fields are set on the result to
+    // change any of this (see cli::setup).
+    pub fn new(listeners: Vec<Listener>, handlers: Vec<Arc<dyn HandleOpcode>>) -> DnsServer {
+        let tags: Vec<String> = listeners
+            .iter()
+            .map(|listener| listener.tag.clone())
+            .collect();
+        let randomness = Randomness::default();
+        DnsServer {
+            listeners,
+            handlers,
+            overload: OverloadPolicy::new(usize::MAX / 2, 0).expect("Invalid overload marks"),
+            quotas: Arc::new(
+                ClientQuotas::new(usize::MAX, usize::MAX).expect("Invalid in-flight quotas"),
+            ),
+            minimization: MinimizationPolicy {
+                max_addresses_per_rrset: 8,
+                max_udp_size: UDP_PAYLOAD_SIZE as usize,
+            },
+            address_filter: Default::default(),
+            verify_encoding: cfg!(debug_assertions),
+            control: None,
+            housekeeping: Housekeeping::with_randomness(&randomness),
+            anomalies: None,
+            randomness,
+            workers: None,
+            maintainer: None,
+            stats: Arc::new(Stats::for_listeners(&tags)),
+        }
+    }
+
+    // Serves until SIGTERM or SIGINT (see shutdown.rs), then shuts down.
+    pub fn work(self) {
+        let mut state = LoopState::new(&self.randomness);
+        while !shutdown::termination_requested() {
+            if let Err(e) = self.run_once(&mut state) {
+                eprintln!("Error waiting for data: {}", e);
+                break;
+            }
+        }
+        self.shut_down(state);
+    }
+
+    // Answers the queries already received, flushes the files being written,
+    // persists the handlers' state and closes the sockets, in that order.
+    fn shut_down(self, state: LoopState) -> Vec<shutdown::StepReport> {
+        println!("[SHUTDOWN] Shutting down; {}.", self.stats.snapshot());
+        let started = Instant::now();
+        let mut shutdown: Shutdown<Stopping> = Shutdown::default();
+        shutdown
+            .register(
+                Phase::StopIntake,
+                "connections",
+                TEARDOWN_TIMEOUT,
+                |stopping, _| {
+                    for connection in stopping.state.connections.iter_mut() {
+                        connection.stop_reading();
+                    }
+                    Ok(())
+                },
+            )
+            .register(
+                Phase::Drain,
+                "workers",
+                DRAIN_TIMEOUT,
+                |stopping, deadline| match stopping.server.workers.take() {
+                    Some(workers) => workers.stop(deadline),
+                    None => Ok(()),
+                },
+            )
+            .register(
+                Phase::Drain,
+                "connections",
+                DRAIN_TIMEOUT,
+                |stopping, deadline| {
+                    let Stopping { server, state } = stopping;
+                    server.drain(&mut state.connections, state.load, deadline)
+                },
+            )
+            .register(
+                Phase::Drain,
+                "maintainer",
+                DRAIN_TIMEOUT,
+                |stopping, _| match stopping.server.maintainer.take() {
+                    Some(maintainer) => maintainer.stop(),
+                    None => Ok(()),
+                },
+            )
+            .register(
+                Phase::FlushWriters,
+                "artifacts",
+                TEARDOWN_TIMEOUT,
+                |stopping, _| stopping.server.housekeeping.flush(),
+            )
+            .register(
+                Phase::PersistState,
+                "handlers",
+                TEARDOWN_TIMEOUT,
+                |stopping, _| {
+                    for handler in &stopping.server.handlers {
+                        handler.persist()?;
+                    }
+                    Ok(())
+                },
+            )
+            .register(
+                Phase::CloseSockets,
+                "control socket",
+                TEARDOWN_TIMEOUT,
+                |stopping, _| {
+                    // Removes its path, too.
+                    stopping.server.control = None;
+                    Ok(())
+                },
+            )
+            .register(
+                Phase::CloseSockets,
+                "listeners",
+                TEARDOWN_TIMEOUT,
+                |stopping, _| {
+                    stopping.server.listeners.clear();
+                    Ok(())
+                },
+            );
+        let mut stopping = Stopping {
+            server: self,
+            state,
+        };
+        let reports = shutdown.run(&mut stopping);
+        let steps: Vec<String> = reports
+            .iter()
+            .map(|report| format!("{} {}: {:?}", report.phase, report.name, report.took))
+            .collect();
+        println!(
+            "[SHUTDOWN] Done in {:?} ({}); {}.",
+            started.elapsed(),
+            steps.join(", "),
+            stopping.server.stats.snapshot()
+        );
+        reports
+    }
+
+    // Answers the queries left waiting on the connections, until `deadline`, then
+    // closes them. Fails if some had to be left unanswered.
+    fn drain(
+        &self,
+        connections: &mut Vec<Connection>,
+        load: Load,
+        deadline: Instant,
+    ) -> io::Result<()> {
+        let mut unanswered = 0;
+        for connection in connections.iter_mut() {
+            while let Some(data) = connection.queue.pop_front() {
+                if connection.closed || Instant::now() >= deadline {
+                    unanswered += 1 + connection.queue.len();
+                    connection.queue.clear();
+                    break;
                 }
+                self.serve_stream(connection, &data, load);
+            }
+        }
+        connections.clear();
+        if unanswered > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} queries left unanswered", unanswered),
+            ));
+        }
+        Ok(())
+    }
+
+    // One iteration of the server loop: waits until a socket is readable or
+    // maintenance is due, reads from the readable listeners one datagram at a time in
+    // turn, each up to LISTENER_BUDGET datagrams, and what has arrived on the readable
+    // connections, judges the load by how many messages were waiting, serves them,
+    // accepts new connections, then runs maintenance if it's due. Without a
+    // maintainer, the handlers are maintained here too, and those with work due
+    // sooner (e.g. paced upstream queries) early, on their own.
+    fn run_once(&self, state: &mut LoopState) -> io::Result<()> {
+        let wake_at = self
+            .handlers
+            .iter()
+            .filter(|_| self.maintainer.is_none())
+            .filter_map(|handler| handler.next_due())
+            .fold(state.maintenance.next_due, Instant::min);
+        // Queries waiting on connections are admitted without waiting for more data.
+        let timeout = if state
+            .connections
+            .iter()
+            .any(|connection| !connection.queue.is_empty())
+        {
+            Duration::ZERO
+        } else {
+            wake_at.saturating_duration_since(Instant::now())
+        };
+        // UDP sockets, then TCP listeners, then connections, then the control socket.
+        let count = self.listeners.len();
+        let mut sockets: Vec<RawFd> = self
+            .listeners
+            .iter()
+            .map(|listener| listener.socket.as_raw_fd())
+            .collect();
+        sockets.extend(
+            self.listeners
+                .iter()
+                .map(|listener| listener.stream_listener.as_raw_fd()),
+        );
+        sockets.extend(state.connections.iter().map(Connection::fd));
+        sockets.extend(self.control.iter().map(ControlSocket::fd));
+        let control_index = 2 * count + state.connections.len();
+        let ready = wait_readable(&sockets, timeout)?;
+
+        let mut pending: Vec<usize> = (0..count)
+            .map(|offset| (state.first_listener + offset) % count)
+            .filter(|index| ready.contains(index))
+            .collect();
+        state.first_listener = (state.first_listener + 1) % count.max(1);
+        let mut batch: Vec<(usize, SocketAddr, Vec<u8>, Option<Admission>)> = Vec::new();
+        for _ in 0..LISTENER_BUDGET {
+            if pending.is_empty() {
+                break;
+            }
+            pending.retain(|&index| match self.receive(&self.listeners[index]) {
+                Some((source, data)) => {
+                    let admission = self.quotas.admit(source.ip());
+                    batch.push((index, source, data, admission));
+                    true
+                }
+                None => false,
+            });
+        }
+        let mut messages: Vec<(usize, Vec<u8>, Admission)> = Vec::new();
+        for (index, connection) in state.connections.iter_mut().enumerate() {
+            let queued = connection.queue.len();
+            if ready.contains(&(2 * count + index)) {
+                let received = connection.receive();
+                connection.queue.extend(received);
+            }
+            messages.extend(self.admit(index, connection, queued));
+        }
+
+        // Datagrams handed to the workers are waiting too, until they're answered.
+        let waiting =
+            batch.len() + messages.len() + self.workers.as_ref().map_or(0, WorkerPool::queued);
+        let load = self.overload.next(state.load, waiting);
+        if load != state.load {
+            println!(
+                "Load changed from {:?} to {:?} ({} message(s) waiting); {}.",
+                state.load,
+                load,
+                waiting,
+                self.stats.snapshot()
+            );
+            state.load = load;
+        }
+        for (index, source, data, admission) in batch {
+            let listener = &self.listeners[index];
+            let mut info = QueryInfo {
+                listener: &listener.tag,
+                client: source,
+                transport: Transport::Udp,
+                load,
+            };
+            if admission.is_none() {
+                if load == Load::Normal {
+                    info.load = Load::Overloaded;
+                }
+                self.stats.over_client_quota.increment();
+                println!(
+                    "[{}] {} is over its quota of {} queries in flight; serving it as overloaded.",
+                    info.listener, info.client, self.quotas.per_client
+                );
+            }
+            match &self.workers {
+                Some(workers) => workers.dispatch(Job {
+                    listener: index,
+                    client: source,
+                    load: info.load,
+                    data,
+                    admission,
+                    reply: Reply::Datagram,
+                }),
+                None => self.serve_datagram(listener, &info, &data),
+            }
+        }
+        // Each query stops counting against its quotas once served. The messages
+        // that act on the connection itself are served here.
+        for (index, data, admission) in messages {
+            let connection = &mut state.connections[index];
+            match &self.workers {
+                Some(workers) if !acts_on_connection(&data) => workers.dispatch(Job {
+                    listener: connection.listener,
+                    client: connection.peer,
+                    load,
+                    data,
+                    admission: Some(admission),
+                    reply: Reply::Stream(connection.pending()),
+                }),
+                _ => self.serve_stream(connection, &data, load),
+            }
+        }
+
+        if let Some(control) = &self.control {
+            if ready.contains(&control_index) {
+                control.serve(&self.stats);
+            }
+        }
+
+        // New connections are read from the next iteration on.
+        for index in 0..count {
+            if ready.contains(&(count + index)) {
+                self.accept(index, &mut state.connections);
+            }
+        }
+
+        let now = Instant::now();
+        let regular = state.maintenance.is_due(now);
+        for handler in self.handlers.iter().filter(|_| self.maintainer.is_none()) {
+            if regular || handler.next_due().is_some_and(|due| due <= now) {
+                handler.maintain();
+            }
+        }
+        if regular {
+            self.housekeeping.maintain(now);
+            for advice in self.stats.truncation.advise(now) {
+                println!("[ADVICE] {}", advice);
+            }
+            for connection in state.connections.iter_mut() {
+                if connection.is_idle(now) {
+                    println!("Closing the idle connection from {}.", connection.peer);
+                    connection.closed = true;
+                }
+            }
+            state.maintenance.reschedule(now);
+        }
+        for connection in state.connections.iter_mut() {
+            if connection.send_failed() {
+                connection.closed = true;
+            }
+            if connection.closed && !connection.queue.is_empty() {
+                self.quotas
+                    .requeued(connection.peer.ip(), connection.queue.len(), 0);
+                connection.queue.clear();
+            }
+        }
+        state.connections.retain(|connection| !connection.closed);
+        Ok(())
+    }
+
+    // Admits the queries waiting on a connection, up to its quota and its client's,
+    // and closes the connection if too many are left waiting. `queued` is how many
+    // were waiting before this iteration.
+    fn admit(
+        &self,
+        index: usize,
+        connection: &mut Connection,
+        queued: usize,
+    ) -> Vec<(usize, Vec<u8>, Admission)> {
+        let client = connection.peer.ip();
+        let mut admitted: Vec<(usize, Vec<u8>, Admission)> = Vec::new();
+        while connection.inflight() + admitted.len() < self.quotas.per_connection
+            && !connection.queue.is_empty()
+        {
+            let Some(admission) = self.quotas.admit(client) else {
+                break;
+            };
+            let data = connection.queue.pop_front().expect("the queue isn't empty");
+            admitted.push((index, data, admission));
+        }
+        if connection.queue.len() > self.quotas.per_connection.saturating_mul(QUEUE_FACTOR) {
+            self.stats.abusive_connections.increment();
+            println!(
+                "Closing the connection from {}: {} queries waiting, over {} times its quota of {}.",
+                connection.peer,
+                connection.queue.len(),
+                QUEUE_FACTOR,
+                self.quotas.per_connection
+            );
+            connection.closed = true;
+            connection.queue.clear();
+            admitted.clear();
+        }
+        self.quotas.requeued(client, queued, connection.queue.len());
+        admitted
+    }
+
+    // Reads one datagram from the listener; returns None once it has none left.
+    // EDNS lets queries, like responses, be larger than 512 bytes (RFC 6891, 6.2.5).
+    fn receive(&self, listener: &Listener) -> Option<(SocketAddr, Vec<u8>)> {
+        let mut buf = [0; 65535];
+        match listener.socket.recv_from(&mut buf) {
+            Ok((size, source)) => Some((source, buf[..size].to_vec())),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Err(e) => {
+                eprintln!("[{}] Error receiving data: {}", listener.tag, e);
+                None
+            }
+        }
+    }
+
+    // Accepts the connections waiting on the listener's TCP socket.
+    fn accept(&self, index: usize, connections: &mut Vec<Connection>) {
+        let listener = &self.listeners[index];
+        loop {
+            let (stream, peer) = match listener.stream_listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
                 Err(e) => {
-                    eprintln!("Error receiving data: {}", e);
+                    eprintln!("[{}] Error accepting a connection: {}", listener.tag, e);
                     break;
                 }
+            };
+            match Connection::new(index, stream, peer) {
+                Ok(connection) => {
+                    println!("[{}] Accepted a connection from {}.", listener.tag, peer);
+                    connections.push(connection);
+                }
+                Err(e) => eprintln!("[{}] Error setting up a connection: {}", listener.tag, e),
+            }
+        }
+    }
+
+    fn serve_datagram(&self, listener: &Listener, info: &QueryInfo, data: &[u8]) {
+        let Some(response) = self.answer(info, data) else {
+            return;
+        };
+        let limit = udp_response_limit(data, self.minimization.max_udp_size);
+        let size = response.encode().len();
+        let response = self.minimization.fit(&response, limit, &self.stats);
+
+        let truncated = response.get_header().get_tc();
+        if truncated {
+            self.stats.truncated.increment();
+        }
+        if let Some(question) = response.get_questions().first() {
+            self.stats.truncation.record_udp(
+                info.listener,
+                info.client.ip(),
+                &question.get_name().to_string(),
+                u16::from(question.get_type()),
+                size,
+                limit,
+                truncated,
+                Instant::now(),
+            );
+        }
+        self.log_response(info, &response);
+        let encoded_response = self.encode(info, &response, response_padding(data, limit));
+        self.housekeeping.capture(data, &encoded_response);
+        // The socket is non-blocking and shared with the workers, so a send can fail
+        // under load (WouldBlock) or for a spoofed source (e.g. port 0); the response
+        // is lost, but the thread carries on serving.
+        if let Err(e) = listener.socket.send_to(&encoded_response, info.client) {
+            self.stats.send_failures.increment();
+            eprintln!(
+                "[{}] Error sending the response to {}: {}",
+                info.listener, info.client, e
+            );
+            return;
+        }
+        self.count_sent(info);
+    }
+
+    // Serves a message received on a connection. DSO messages are handled here, as
+    // they act on the connection's session rather than being answered by a handler.
+    fn serve_stream(&self, connection: &mut Connection, data: &[u8], load: Load) {
+        let listener = &self.listeners[connection.listener];
+        let info = QueryInfo {
+            listener: &listener.tag,
+            client: connection.peer,
+            transport: Transport::Tcp,
+            load,
+        };
+        let Some(header) = data.get(..12).and_then(|s| s.try_into().ok()) else {
+            println!(
+                "[{}] Closing the connection from {}: a {}-byte message is shorter than a header.",
+                info.listener,
+                info.client,
+                data.len()
+            );
+            connection.closed = true;
+            return;
+        };
+
+        let response: Vec<u8> =
+            if *Header::parse_from(header).get_opcode() == OpCode::DnsStatefulOperations {
+                self.count_received(&info, data);
+                match dso::respond(&mut connection.dso, data, load) {
+                    DsoOutcome::Respond(response) => response,
+                    DsoOutcome::Abort(reason) => {
+                        println!(
+                            "[{}] Aborting the connection from {}: {}.",
+                            info.listener, info.client, reason
+                        );
+                        connection.closed = true;
+                        return;
+                    }
+                }
+            } else {
+                let Some(response) = self.answer_stream(&info, data) else {
+                    return;
+                };
+                response.to_vec()
+            };
+        connection.send(&response);
+        self.count_sent(&info);
+    }
+
+    // Answers a query received on a connection, here or on a worker; returns the
+    // encoded response, or None if the query was dropped.
+    fn answer_stream(&self, info: &QueryInfo, data: &[u8]) -> Option<Arc<[u8]>> {
+        let response = self.answer(info, data)?;
+        if let Some(question) = response.get_questions().first() {
+            self.stats.truncation.record_tcp(
+                info.client.ip(),
+                &question.get_name().to_string(),
+                u16::from(question.get_type()),
+                Instant::now(),
+            );
+        }
+        let limit = u16::MAX as usize;
+        let response = self.minimization.fit(&response, limit, &self.stats);
+        self.log_response(info, &response);
+        let encoded = self.encode(info, &response, response_padding(data, limit));
+        self.housekeeping.capture(data, &encoded);
+        Some(encoded)
+    }
+
+    fn count_received(&self, info: &QueryInfo, data: &[u8]) {
+        println!(
+            "[{}] Received {} bytes from client at {} over {:?}",
+            info.listener,
+            data.len(),
+            info.client,
+            info.transport
+        );
+        self.stats.queries_received.increment();
+        if let Some(listener_stats) = self.stats.listener(info.listener) {
+            listener_stats.queries_received.increment();
+        }
+    }
+
+    fn count_sent(&self, info: &QueryInfo) {
+        self.stats.responses_sent.increment();
+        if let Some(listener_stats) = self.stats.listener(info.listener) {
+            listener_stats.responses_sent.increment();
+        }
+    }
+
+    // Counts the request and has the handler for its opcode answer it. Returns None
+    // if the request was dropped.
+    fn answer(&self, info: &QueryInfo, data: &[u8]) -> Option<Message> {
+        #[cfg(feature = "profiling")]
+        let allocations = profiling::AllocationScope::start();
+        self.count_received(info, data);
+        let Some(header) = data.get(..12).and_then(|s| s.try_into().ok()) else {
+            // Too short to be handled; FORMERR, echoing the ID if there is one.
+            let mut request = Header::default();
+            if let Some(&[high, low]) = data.get(..2) {
+                request.set_id(u16::from_be_bytes([high, low]));
+            }
+            let err = DnsParseError::TruncatedHeader { length: data.len() };
+            return Some(format_error(info, &request, data, &err, &self.stats));
+        };
+        let header = Header::parse_from(header);
+
+        let opcode = header.get_opcode();
+        let response = match self
+            .handlers
+            .iter()
+            .find(|handler| handler.opcode() == *opcode)
+        {
+            // DSO is only allowed over connections (RFC 8490, 5.1).
+            _ if *opcode == OpCode::DnsStatefulOperations => {
+                println!(
+                    "[{}] Received a DSO message from {} over {:?}.",
+                    info.listener, info.client, info.transport
+                );
+                let header = response_header(&header, RCode::FormatError);
+                Some(Message::new(&header.into(), &Arc::from([]), &Arc::from([])))
+            }
+            Some(handler) => handler
+                .handle(info, &header, data, &self.stats)
+                .map(|response| {
+                    let response = self
+                        .address_filter
+                        .apply(info.client, &response, &self.stats);
+                    chain::in_chain_order(&response, &self.stats)
+                }),
+            None => {
+                println!(
+                    "[{}] No handler is registered for opcode {}.",
+                    info.listener, opcode
+                );
+                let header = response_header(&header, RCode::NotImplemented);
+                Some(Message::new(&header.into(), &Arc::from([]), &Arc::from([])))
             }
+        };
+        if response.is_none() {
+            println!(
+                "[{}] Dropped the query from {} without a response.",
+                info.listener, info.client
+            );
+        }
+        #[cfg(feature = "profiling")]
+        self.stats.record_query_allocations(allocations.finish());
+        response
+    }
+
+    // Encodes a response, padded as given, verifying the encoding if configured to. A
+    // response that doesn't read back as itself is logged and replaced by a bare
+    // SERVFAIL, rather than sending the client bytes that say something else.
+    fn encode(
+        &self,
+        info: &QueryInfo,
+        response: &Message,
+        padding: Option<(usize, usize)>,
+    ) -> Arc<[u8]> {
+        let appendix = Appendix {
+            padding,
+            ..Appendix::default()
+        };
+        let mismatch = match verify::encode_verified(response, &appendix, self.verify_encoding) {
+            Ok(encoded) => return encoded,
+            Err(mismatch) => mismatch,
+        };
+        self.stats.encoding_failures.increment();
+        println!(
+            "[{}] The response to {} didn't read back as encoded; answering SERVFAIL.\n{}\n{}",
+            info.listener,
+            info.client,
+            mismatch.differences.join("\n"),
+            verify::hex_dump(&mismatch.encoded)
+        );
+        let mut header = response_header(response.get_header(), RCode::ServerError);
+        header.set_qd_count(response.get_header().get_qd_count());
+        Message::new(&header.into(), response.get_questions(), &Arc::from([])).encode()
+    }
+
+    fn log_response(&self, info: &QueryInfo, response: &Message) {
+        println!("[{}] Response:\n{}", info.listener, response);
+        let summary = format!(
+            "[{}] {} {} -> {}, {} answer(s)",
+            info.listener,
+            info.client,
+            response
+                .get_questions()
+                .iter()
+                .map(|question| question.to_string())
+                .collect::<Vec<String>>()
+                .join(" "),
+            extended_rcode(response),
+            response.get_answers().len()
+        );
+        println!("{}", summary);
+        self.housekeeping.log_query(&summary);
+        if let Some(anomalies) = &self.anomalies {
+            anomalies.observe(info.client.ip(), response, &self.stats, Instant::now());
         }
     }
 }
 
-pub struct DummyDnsResolver {}
+// Whether a message received on a connection acts on the connection itself, and so
+// is served by the server loop: a DSO message, which acts on its session, or one too
+// short to have a header, which closes it.
+fn acts_on_connection(data: &[u8]) -> bool {
+    data.get(..12)
+        .and_then(|s| s.try_into().ok())
+        .map_or(true, |header| {
+            *Header::parse_from(header).get_opcode() == OpCode::DnsStatefulOperations
+        })
+}
+
+// Answers every question with made-up addresses: AAAA questions with `ipv6_address`,
+// all others with an A record for 8.8.8.8.
+pub struct DummyDnsResolver {
+    pub ipv6_address: Ipv6Addr,
+}
+
+impl Default for DummyDnsResolver {
+    fn default() -> Self {
+        DummyDnsResolver {
+            ipv6_address: DUMMY_IPV6_ADDRESS,
+        }
+    }
+}
 
 pub struct ForwardingDnsResolver {
-    pub fwd_endpoint: UdpSocket,
+    // Asked in turn, failing over from one to the next (see upstreams.rs). Each query
+    // is sent from a socket of its own, on a port the system picks, for a spoofer to
+    // guess the port as well as the ID.
+    pub upstreams: Arc<UpstreamSet>,
+    pub upstream_state: Mutex<UpstreamStateStore>,
+    // When configured, root DNSKEY queries signal their key tags (RFC 8145).
+    #[cfg(feature = "dnssec")]
+    pub trust_anchors: Option<Arc<TrustAnchors>>,
+    // Names under these are forwarded with CD set, to go unvalidated upstream.
+    pub negative_trust_anchors: Option<Arc<NegativeTrustAnchors>>,
+    // Bulk queries (e.g. warm-up) waiting to be sent at the --upstream-max-qps rate.
+    pub pacer: Mutex<Pacer<Question>>,
+    pub pacing: PacingStats,
+    // The lookups of the questions of a request that were sent together, for their
+    // lookups to take (see prefetch). Those left untaken go stale after the timeout.
+    pub prefetched: Mutex<Vec<Prefetched>>,
+    // When set, truncated answers are asked again over TCP, to compare.
+    pub split_brain: Option<Arc<SplitBrainCheck>>,
+    // When set (--upstream-tcp-timeout-ms), a truncated answer is asked again of the
+    // same upstream over TCP, waiting this long, and the client gets the full one. If
+    // that fails, the truncated answer is passed on, TC and all.
+    pub tcp_fallback: Option<Duration>,
+    // When set, answers to its sampled names are observed, to compare TTLs with how
+    // often the data changes.
+    pub ttl_honesty: Option<Arc<TtlHonesty>>,
+    // How long each attempt waits for the upstream's response (--upstream-timeout-ms),
+    // and how many times the query is sent again before it fails (--upstream-retries).
+    pub timeout: Duration,
+    pub retries: u32,
+    // Where the IDs of the queries sent upstream come from, one for each attempt:
+    // random, for a spoofer not to guess, rather than the client's (SystemRng).
+    pub ids: Arc<dyn Rng>,
+    // When set (--breaker-failures), queries fail fast while the upstream is down.
+    pub breaker: Option<Arc<CircuitBreaker>>,
 }
 
-pub trait Resolve {
-    fn resolve(&self, header: &Header, questions: &Rc<[Question]>) -> Rc<[Answer]>;
+// The lookup of a question sent upstream together with the others of its request,
+// made at `at`: Failed if the response didn't come in time.
+pub struct Prefetched {
+    question: Question,
+    at: Instant,
+    resolved: Resolved,
 }
 
-impl Resolve for DummyDnsResolver {
-    fn resolve(&self, _header: &Header, questions: &Rc<[Question]>) -> Rc<[Answer]> {
+impl ForwardingDnsResolver {
+    // The lookup of `question` as `upstream` answered it, in `response`.
+    fn resolved(upstream: SocketAddr, lookup: Lookup, response: &Message) -> Resolved {
+        Resolved {
+            lookup,
+            header: Some(Arc::clone(response.get_header())),
+            authorities: Arc::clone(response.get_authorities()),
+            policy_outcome: None,
+            provenance: Some(format!("source=upstream upstream={}", upstream)),
+            cached: false,
+            encoded_answers: None,
+        }
+    }
+
+    fn upstream_key(upstream: SocketAddr) -> String {
+        format!("udp/{}", upstream)
+    }
+
+    // A socket for one query, on an ephemeral port, connected to the upstream so
+    // that only its datagrams are received.
+    fn query_socket(upstream: SocketAddr) -> io::Result<UdpSocket> {
+        let local = if upstream.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(upstream)?;
+        Ok(socket)
+    }
+}
+
+// What the resolver chain made of a query's questions: the answers, the status of
+// the response, which is that of the first question, and what else the response
+// carries.
+#[derive(Clone, Debug)]
+pub struct Resolution {
+    pub rcode: RCode,
+    pub answers: Arc<[Answer]>,
+    // Authoritative Answer, Recursion Available, TrunCation and Authentic Data, as the
+    // upstream set them.
+    pub aa: bool,
+    pub ra: bool,
+    pub tc: bool,
+    pub ad: bool,
+    // The authority records that came with the answers, in question order.
+    pub authorities: Arc<[Answer]>,
+    // What a policy did to the answers to the first question it shaped.
+    pub policy_outcome: Option<PolicyOutcome>,
+    // Where the answers to each question came from, in question order, as far as
+    // their lookup said (see Resolved::provenance).
+    pub provenance: Vec<Option<String>>,
+    // Whether every question was answered from the cache.
+    pub cached: bool,
+    // The answers encoded beforehand, if there was one question and its lookup
+    // kept them so; they stand for `answers` as long as those aren't replaced.
+    pub encoded_answers: Option<Arc<EncodedAnswers>>,
+}
+
+impl Resolution {
+    // The resolution of the questions looked up as `lookups`, in order. The status is
+    // that of the upstream's response if the answer to the first question was
+    // forwarded as it came, and otherwise NXDOMAIN if no source knew the name,
+    // NOERROR if one did.
+    pub fn new(lookups: Vec<Resolved>) -> Resolution {
+        let upstream = lookups.first().and_then(|first| first.header.clone());
+        let rcode = match (&upstream, lookups.first()) {
+            (Some(header), _) => header.get_rcode().clone(),
+            (None, Some(first)) if matches!(first.lookup, Lookup::NotFound) => RCode::NameError,
+            (None, _) => RCode::NoError,
+        };
+        let authorities: Arc<[Answer]> = lookups
+            .iter()
+            .flat_map(|resolved| resolved.authorities.iter().cloned())
+            .collect();
+        let policy_outcome = lookups
+            .iter()
+            .find_map(|resolved| resolved.policy_outcome.clone());
+        let cached = !lookups.is_empty() && lookups.iter().all(|resolved| resolved.cached);
+        // The template is kept only as long as the answers are the ones it was made
+        // for, as a wrapper may have replaced them (see EncodedAnswers).
+        let encoded_answers = match &lookups[..] {
+            [resolved] => resolved.encoded_answers.clone().filter(|encoded| {
+                let answers = match &resolved.lookup {
+                    Lookup::Found(answers) => &answers[..],
+                    _ => &[],
+                };
+                answers.len() == encoded.get_answers().len()
+                    && answers
+                        .iter()
+                        .zip(encoded.get_answers().iter())
+                        .all(|(a, b)| a.encode() == b.encode())
+            }),
+            _ => None,
+        };
+        let mut provenance: Vec<Option<String>> = Vec::new();
         let mut answers: Vec<Answer> = Vec::new();
-        for question in questions.as_ref() {
-            answers.push(Answer::new(
-                /* name= */ &question.get_name(),
-                /* type= */ 1,
-                /* class= */ 1,
-                /* ttl= */ 60,
-                /* data= */ &Vec::from_iter([0x8, 0x8, 0x8, 0x8]).into(),
-            ));
+        for resolved in lookups {
+            provenance.push(resolved.provenance);
+            answers.extend(resolved.lookup.into_answers());
+        }
+        let answers = match &encoded_answers {
+            Some(encoded) => Arc::clone(encoded.get_answers()),
+            None => answers.into(),
+        };
+        Resolution {
+            rcode,
+            answers,
+            aa: upstream.as_ref().is_some_and(|header| header.get_aa()),
+            ra: upstream.as_ref().is_some_and(|header| header.get_ra()),
+            tc: upstream.as_ref().is_some_and(|header| header.get_tc()),
+            ad: upstream.as_ref().is_some_and(|header| header.get_ad()),
+            authorities,
+            policy_outcome,
+            provenance,
+            cached,
+            encoded_answers,
         }
-        answers.into()
+    }
+
+    // SERVFAIL for the questions that Failed as `lookups`, with what a policy did to
+    // them, e.g. a circuit breaker failing them fast.
+    pub fn failed(lookups: Vec<Resolved>) -> Resolution {
+        Resolution {
+            rcode: RCode::ServerError,
+            answers: Arc::from([]),
+            aa: false,
+            ra: false,
+            tc: false,
+            ad: false,
+            authorities: Arc::from([]),
+            policy_outcome: lookups
+                .iter()
+                .find_map(|resolved| resolved.policy_outcome.clone()),
+            provenance: vec![None; lookups.len()],
+            cached: false,
+            encoded_answers: None,
+        }
+    }
+}
+
+// The outcome of looking up one question in one source of answers.
+#[derive(Clone, Debug)]
+pub enum Lookup {
+    Found(Vec<Answer>),
+    // The name exists in this source, but has no records of the type asked for.
+    FoundNoData,
+    // The source doesn't know the name; the next one down is asked.
+    NotFound,
+    // The source should know but couldn't get an answer (e.g. the upstream failed);
+    // the query is answered SERVFAIL.
+    Failed,
+}
+
+impl Lookup {
+    pub fn into_answers(self) -> Vec<Answer> {
+        match self {
+            Lookup::Found(answers) => answers,
+            Lookup::FoundNoData | Lookup::NotFound | Lookup::Failed => Vec::new(),
+        }
+    }
+}
+
+// A lookup, with what came with it for the response besides the answers. Wrapping
+// resolvers hand back the wrapped one's as it is for the questions they don't answer
+// themselves, so that it always describes the lookup that answered.
+#[derive(Clone, Debug)]
+pub struct Resolved {
+    pub lookup: Lookup,
+    // The header of the upstream's response the answers came in, for its RCODE and
+    // AA, RA and AD bits to be passed on to the client (AD as far as --ad-mode says;
+    // see authenticated.rs); None if they weren't forwarded as they came.
+    pub header: Option<Arc<Header>>,
+    // The authority records that came with the answers, e.g. the SOA of a negative
+    // answer from the upstream, to be passed on to the client as they are.
+    pub authorities: Arc<[Answer]>,
+    // What a policy did to the answers, e.g. an NXDOMAIN redirect, for the client to
+    // be told with an Extended DNS Error.
+    pub policy_outcome: Option<PolicyOutcome>,
+    // Where the answers came from when that was down to the lookup, e.g. which
+    // upstream answered; None where Resolve::provenance says it.
+    pub provenance: Option<String>,
+    // Whether the answers came from the cache, for the latency SLOs (see slo.rs).
+    pub cached: bool,
+    // The answers encoded beforehand (see EncodedAnswers), if the lookup keeps them so.
+    pub encoded_answers: Option<Arc<EncodedAnswers>>,
+}
+
+impl Resolved {
+    pub fn into_answers(self) -> Vec<Answer> {
+        self.lookup.into_answers()
+    }
+}
+
+impl From<Lookup> for Resolved {
+    fn from(lookup: Lookup) -> Resolved {
+        Resolved {
+            lookup,
+            header: None,
+            authorities: Arc::from([]),
+            policy_outcome: None,
+            provenance: None,
+            cached: false,
+            encoded_answers: None,
+        }
+    }
+}
+
+// A source of answers. Sources are stacked, each wrapping the next one down, in this
+// order of precedence: the query policy (in the handler), the blocklist, static
+// records, the hosts file, zones, the cache, the upstream; the local ones are the
+// stages of a ResolverChain. A question is answered by the first source that knows
+// its name, Found or FoundNoData alike, so answers from different sources are never
+// merged and a name that exists locally without the type never leaks upstream.
+//
+// Resolvers are Send + Sync, as one chain is shared by the worker threads (see
+// workers.rs); what a lookup brings besides the answers comes back with it.
+pub trait Resolve: Send + Sync {
+    fn lookup(&self, header: &Header, question: &Question) -> Resolved;
+
+    // The answers to all the questions, SERVFAIL if their lookups all Failed. When
+    // there are several, they're prefetched together, and a question that Failed
+    // contributes no answers to those of the others.
+    fn resolve(&self, header: &Header, questions: &Arc<[Question]>) -> Resolution {
+        if questions.len() > 1 {
+            self.prefetch(header, questions);
+        }
+        let lookups: Vec<Resolved> = questions
+            .iter()
+            .map(|question| self.lookup(header, question))
+            .collect();
+        if !lookups.is_empty()
+            && lookups
+                .iter()
+                .all(|resolved| matches!(resolved.lookup, Lookup::Failed))
+        {
+            return Resolution::failed(lookups);
+        }
+        Resolution::new(lookups)
+    }
+
+    // Where the answers to `question` come from, e.g. "source=static", unless their
+    // lookup said (see Resolved::provenance); responses are annotated with it for
+    // clients that ask (see provenance.rs). Wrapping resolvers describe the questions
+    // they answer themselves and ask the wrapped one otherwise.
+    fn provenance(&self, question: &Question) -> String;
+
+    // Whether `question` is answered without waiting on the network. Under overload
+    // only such questions are served. Wrapping resolvers check their own records
+    // and ask the wrapped one otherwise.
+    fn answers_locally(&self, question: &Question) -> bool;
+
+    // Whether `question` is answered here, with an answer or a negative one (NODATA,
+    // NXDOMAIN, REFUSED), rather than passed on; a ResolverChain asks its stages in
+    // turn until one claims it (see resolver_chain.rs). Sources at the bottom, like
+    // the upstream, claim every question. Wrapping resolvers claim the questions
+    // they answer themselves and ask the wrapped one otherwise.
+    fn claims(&self, _question: &Question) -> bool {
+        true
+    }
+
+    // Starts looking up `questions`, the questions of one request, all at once, for
+    // their lookups to follow without a round trip each; the upstream sends its
+    // queries together (see ForwardingDnsResolver::prefetch). Wrapping resolvers pass
+    // on the questions they don't answer themselves.
+    fn prefetch(&self, _header: &Header, _questions: &[Question]) {}
+
+    // Periodic housekeeping, run from the server's maintenance timer (see
+    // maintainer.rs).
+    // Wrapping resolvers must pass it on to the resolvers they wrap.
+    fn maintain(&self) {}
+
+    // Saves what should outlive the process (e.g. upstream state), at shutdown.
+    // Wrapping resolvers must pass it on as well.
+    fn persist(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // When maintenance next has work that shouldn't wait for the regular interval,
+    // e.g. paced upstream queries waiting for their turn. Wrapping resolvers must
+    // pass it on as well.
+    fn next_due(&self) -> Option<Instant> {
+        None
+    }
+}
+
+impl Resolve for DummyDnsResolver {
+    fn lookup(&self, _header: &Header, question: &Question) -> Resolved {
+        Lookup::Found(vec![match question.get_type() {
+            RecordType::Aaaa => Answer::aaaa(question.get_name(), 60, self.ipv6_address),
+            _ => Answer::a(question.get_name(), 60, Ipv4Addr::new(8, 8, 8, 8)),
+        }])
+        .into()
+    }
+
+    fn provenance(&self, _question: &Question) -> String {
+        String::from("source=dummy")
+    }
+
+    fn answers_locally(&self, _question: &Question) -> bool {
+        true
     }
 }
 
 impl Resolve for ForwardingDnsResolver {
-    fn resolve(&self, header: &Header, questions: &Rc<[Question]>) -> Rc<[Answer]> {
-        let mut fwd_header_stub = Header::default();
-        fwd_header_stub
-            .set_id(header.get_id())
-            .set_qr(false)
-            .set_opcode(header.get_opcode())
-            .set_rd(header.get_rd())
-            .set_qd_count(1);
-        let fwd_header = Rc::new(fwd_header_stub);
+    fn lookup(&self, header: &Header, question: &Question) -> Resolved {
+        self.pacing.immediate.increment();
+        let Some(breaker) = &self.breaker else {
+            return self.exchange(header, question);
+        };
+        let admission = breaker.admit(Instant::now());
+        if admission == breaker::Admission::Reject {
+            println!(
+                "[FORWARD] Failing {} fast: the circuit breaker is open.",
+                question
+            );
+            self.take_prefetched(question);
+            return Resolved {
+                policy_outcome: Some(PolicyOutcome::not_ready(String::from(
+                    "upstream unavailable",
+                ))),
+                ..Lookup::Failed.into()
+            };
+        }
+        let resolved = self.exchange(header, question);
+        breaker.record(
+            admission,
+            !matches!(resolved.lookup, Lookup::Failed),
+            Instant::now(),
+        );
+        resolved
+    }
 
-        let mut answers: Vec<Answer> = Vec::new();
-        for question in questions.as_ref() {
-            let fwd_request = Message::new(&fwd_header, &[question.clone()].into(), &[].into());
-            println!("[FORWARD] Request:\n{}", &fwd_request);
-            self.fwd_endpoint
-                .send(&fwd_request.encode())
-                .expect("Failed to send message to the DNS resolver.");
-            println!("Sent DNS query to the resolver");
-            let mut buf = [0; 512];
-            match self.fwd_endpoint.recv_from(&mut buf) {
-                Ok((sz, src)) => {
-                    println!("Received {} bytes from the resolver at {}.", sz, &src);
-                    let fwd_response = Message::parse_from(&buf);
-                    println!("Received response from the resolver: {}", &fwd_response);
-                    fwd_response.get_answers().iter().for_each(|answer| {
-                        println!("Pushing fwd answer:\n{}", answer.clone());
-                        answers.push(answer.clone());
-                    });
+    // The lookups say which upstream answered; this is the one asked first.
+    fn provenance(&self, _question: &Question) -> String {
+        format!("source=upstream upstream={}", self.upstreams.first())
+    }
+
+    fn answers_locally(&self, _question: &Question) -> bool {
+        false
+    }
+
+    // The questions are sent to the first upstream, each from a socket of its own,
+    // and their responses taken as they come, up to the timeout; a question the
+    // upstream doesn't answer by then Fails, without retries or failover, for the
+    // others not to wait on it. They go through the circuit breaker as one: none is
+    // sent while it's open, and only the first, as the probe, while it's half-open.
+    fn prefetch(&self, header: &Header, questions: &[Question]) {
+        self.drop_stale_prefetches();
+        if questions.len() < 2 {
+            return;
+        }
+        let Some(breaker) = &self.breaker else {
+            self.exchange_together(header, questions);
+            return;
+        };
+        match breaker.admit(Instant::now()) {
+            breaker::Admission::Reject => println!(
+                "[FORWARD] Not prefetching {} questions: the circuit breaker is open.",
+                questions.len()
+            ),
+            breaker::Admission::Pass => {
+                self.exchange_together(header, questions);
+            }
+            breaker::Admission::Probe => {
+                let answered = self.exchange_together(header, &questions[..1]);
+                breaker.record(breaker::Admission::Probe, answered > 0, Instant::now());
+            }
+        }
+    }
+
+    fn maintain(&self) {
+        self.drop_stale_prefetches();
+        self.upstream_state.lock().unwrap().save_if_due();
+        self.send_paced();
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        self.upstream_state.lock().unwrap().save_if_dirty()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.pacer.lock().unwrap().next_due(Instant::now())
+    }
+}
+
+impl ForwardingDnsResolver {
+    // Schedules a paced lookup of `name` (A), to warm the upstream's cache and our
+    // round-trip estimates for it.
+    pub fn warm_up(&self, name: &str) -> Result<(), String> {
+        let name: LabelSequence = name
+            .parse()
+            .map_err(|err: LabelSequenceParseError| err.message)?;
+        self.schedule_bulk(Question::new(
+            &Arc::new(name),
+            RecordType::A,
+            RecordClass::In,
+        ));
+        Ok(())
+    }
+
+    // Queues a query that isn't answering a client, to be sent when the pacer allows.
+    fn schedule_bulk(&self, question: Question) {
+        let mut pacer = self.pacer.lock().unwrap();
+        pacer.push(question);
+        self.pacing.set_backlog(pacer.backlog());
+    }
+
+    // Sends the queued queries whose turn has come.
+    fn send_paced(&self) {
+        let mut sent = 0;
+        loop {
+            let next = self.pacer.lock().unwrap().next_ready(Instant::now());
+            let Some(question) = next else {
+                break;
+            };
+            self.pacing.paced.increment();
+            let mut header = Header::default();
+            header.set_id(self.pacing.paced.get() as u16).set_rd(true);
+            self.exchange(&header, &question);
+            sent += 1;
+        }
+        let backlog = self.pacer.lock().unwrap().backlog();
+        self.pacing.set_backlog(backlog);
+        if sent > 0 && backlog == 0 {
+            println!("[FORWARD] Paced queries done; {}.", self.pacing);
+        }
+    }
+
+    // The lookup of `question` sent with the others of its request, if it was. Any
+    // request's will do: they answer the same question.
+    fn take_prefetched(&self, question: &Question) -> Option<Resolved> {
+        let mut prefetched = self.prefetched.lock().unwrap();
+        let index = prefetched
+            .iter()
+            .position(|prefetched| prefetched.question.encode() == question.encode())?;
+        Some(prefetched.remove(index).resolved)
+    }
+
+    // Forgets the prefetched lookups no lookup took, e.g. of questions a cache
+    // answered in the meantime, once they're older than the timeout: a request's
+    // lookups follow its prefetch right away.
+    fn drop_stale_prefetches(&self) {
+        let now = Instant::now();
+        self.prefetched
+            .lock()
+            .unwrap()
+            .retain(|prefetched| now.duration_since(prefetched.at) < self.timeout);
+    }
+
+    // The query to send upstream for `question`, asked by a client with `header`.
+    fn query_for(&self, header: &Header, question: &Question) -> Query {
+        let mut query = Query::from_question(question);
+        query.set_rd(header.get_rd());
+        #[cfg(feature = "dnssec")]
+        if let Some(key_tags) = self
+            .trust_anchors
+            .as_ref()
+            .and_then(|anchors| anchors.key_tag_option(question))
+        {
+            query.add_option(EDNS_KEY_TAG, &key_tags);
+        }
+        if let Some(anchors) = &self.negative_trust_anchors {
+            query.set_cd(anchors.covers(question.get_name()));
+        }
+        query
+    }
+
+    fn exchange(&self, header: &Header, question: &Question) -> Resolved {
+        if let Some(resolved) = self.take_prefetched(question) {
+            return resolved;
+        }
+        if !budget::spend(Work::UpstreamQueries, 1) {
+            return Lookup::Failed.into();
+        }
+        let query = self.query_for(header, question);
+        let order = self.upstreams.order(Instant::now());
+        // Each upstream's attempts share a socket, opened when it's first asked: a
+        // late response to an earlier one answers the query as well as one to the
+        // latest. An upstream whose response failed the query isn't asked again.
+        let mut sockets: Vec<Option<(UdpSocket, Vec<u16>)>> = order.iter().map(|_| None).collect();
+        let mut given_up: Vec<bool> = vec![false; order.len()];
+        let mut sent = 0;
+        for attempt in 0..=self.retries {
+            for (index, &upstream) in order.iter().enumerate() {
+                if given_up[index] {
+                    continue;
+                }
+                if sockets[index].is_none() {
+                    match ForwardingDnsResolver::query_socket(upstream) {
+                        Ok(socket) => sockets[index] = Some((socket, Vec::new())),
+                        Err(err) => {
+                            println!("[FORWARD] Failed to open a socket to {}: {}", upstream, err);
+                            given_up[index] = true;
+                            continue;
+                        }
+                    }
+                }
+                let Some((socket, ids)) = &mut sockets[index] else {
+                    continue;
+                };
+                let id = self.ids.next_u64() as u16;
+                ids.push(id);
+                let fwd_request = query.to_message(id);
+                if sent == 0 {
+                    println!("[FORWARD] Request:\n{}", &fwd_request);
+                } else if attempt == 0 {
+                    println!("[FORWARD] Failing over to {} with ID {}.", upstream, id);
+                } else {
+                    println!(
+                        "[FORWARD] No response in {:?}; asking {} again with ID {} (retry {} of {}).",
+                        self.timeout, upstream, id, attempt, self.retries
+                    );
+                }
+                sent += 1;
+                if let Err(err) = socket.send(&fwd_request.encode()) {
+                    println!(
+                        "[FORWARD] Failed to send the query to {}: {}",
+                        upstream, err
+                    );
+                    given_up[index] = true;
+                    continue;
+                }
+                println!("Sent DNS query to the resolver");
+                match self.receive(socket, upstream, question, &query, ids, &fwd_request) {
+                    None => {}
+                    Some(resolved) if matches!(resolved.lookup, Lookup::Failed) => {
+                        given_up[index] = true
+                    }
+                    Some(resolved) => {
+                        self.record_upstreams(&order, &sockets, Some(upstream));
+                        return resolved;
+                    }
+                }
+            }
+            if given_up.iter().all(|&given_up| given_up) {
+                break;
+            }
+        }
+        self.record_upstreams(&order, &sockets, None);
+        println!(
+            "[FORWARD] No answer from the resolver after {} attempt(s); failing the query.",
+            sent
+        );
+        Lookup::Failed.into()
+    }
+
+    // Sends the queries for `questions` at once, and takes their responses as they
+    // come (see prefetch), for their lookups; returns how many were answered.
+    fn exchange_together(&self, header: &Header, questions: &[Question]) -> usize {
+        struct Pending<'a> {
+            question: &'a Question,
+            query: Query,
+            fwd_request: Message,
+            socket: UdpSocket,
+        }
+        let upstream = self.upstreams.order(Instant::now())[0];
+        let mut pending: Vec<Pending> = Vec::new();
+        for question in questions {
+            if !budget::spend(Work::UpstreamQueries, 1) {
+                break;
+            }
+            let query = self.query_for(header, question);
+            let fwd_request = query.to_message(self.ids.next_u64() as u16);
+            let sent = ForwardingDnsResolver::query_socket(upstream)
+                .and_then(|socket| socket.send(&fwd_request.encode()).map(|_| socket));
+            match sent {
+                Ok(socket) => pending.push(Pending {
+                    question,
+                    query,
+                    fwd_request,
+                    socket,
+                }),
+                Err(err) => println!(
+                    "[FORWARD] Failed to send the query for {} to {}: {}",
+                    question, upstream, err
+                ),
+            }
+        }
+        println!(
+            "[FORWARD] Sent {} queries to {} together.",
+            pending.len(),
+            upstream
+        );
+        let sent_at = Instant::now();
+        let mut answered = 0;
+        // As large as any datagram, as in receive.
+        let mut buf = [0; 65535];
+        while !pending.is_empty() {
+            let remaining = self.timeout.saturating_sub(sent_at.elapsed());
+            if remaining.is_zero() {
+                break;
+            }
+            let sockets: Vec<RawFd> = pending
+                .iter()
+                .map(|query| query.socket.as_raw_fd())
+                .collect();
+            let ready = match wait_readable(&sockets, remaining) {
+                Ok(ready) => ready,
+                Err(err) => {
+                    println!("Error waiting for the resolver: {}", err);
+                    break;
+                }
+            };
+            // Highest first, for the indices of the others to hold as answered ones
+            // are removed.
+            for index in ready.into_iter().rev() {
+                let query = &pending[index];
+                let fwd_response = match query.socket.recv(&mut buf) {
+                    Ok(size) if budget::spend(Work::BytesParsed, size as u64) => {
+                        Message::parse_from(&buf[..size]).map_err(|err| err.to_string())
+                    }
+                    Ok(_) => Err(String::from("over the work budget")),
+                    Err(err) => Err(err.to_string()),
+                };
+                let fwd_response = match fwd_response {
+                    Ok(response) => response,
+                    Err(err) => {
+                        println!("[FORWARD] Giving up on {}: {}", query.question, err);
+                        pending.remove(index);
+                        continue;
+                    }
+                };
+                let id = query.fwd_request.get_header().get_id();
+                let rcode = match query.query.matches_response(id, &fwd_response) {
+                    Ok(rcode) => rcode,
+                    Err(mismatch) => {
+                        println!(
+                            "[FORWARD] Skipping a response that doesn't answer the query: {}.",
+                            mismatch
+                        );
+                        continue;
+                    }
+                };
+                self.upstream_state.lock().unwrap().record_rtt(
+                    &ForwardingDnsResolver::upstream_key(upstream),
+                    sent_at.elapsed(),
+                );
+                let query = pending.remove(index);
+                let resolved = self.accept(
+                    upstream,
+                    query.question,
+                    &query.query,
+                    &query.fwd_request,
+                    fwd_response,
+                    rcode,
+                );
+                answered += 1;
+                self.prefetched.lock().unwrap().push(Prefetched {
+                    question: query.question.clone(),
+                    at: sent_at,
+                    resolved,
+                });
+            }
+        }
+        for query in pending {
+            println!(
+                "[FORWARD] No response for {} in {:?}; it goes unanswered.",
+                query.question, self.timeout
+            );
+            self.prefetched.lock().unwrap().push(Prefetched {
+                question: query.question.clone(),
+                at: sent_at,
+                resolved: Lookup::Failed.into(),
+            });
+        }
+        self.upstreams
+            .record(upstream, answered > 0, Instant::now());
+        answered
+    }
+
+    // Records how the upstreams asked about a query fared: `answered` answered it,
+    // the others failed it.
+    fn record_upstreams(
+        &self,
+        order: &[SocketAddr],
+        sockets: &[Option<(UdpSocket, Vec<u16>)>],
+        answered: Option<SocketAddr>,
+    ) {
+        let now = Instant::now();
+        for (&upstream, socket) in order.iter().zip(sockets) {
+            if socket.is_some() {
+                self.upstreams
+                    .record(upstream, Some(upstream) == answered, now);
+            }
+        }
+    }
+
+    // Waits for the response to the latest attempt of `query` (with one of `ids`),
+    // up to the timeout; None if it didn't come in time, to try again.
+    fn receive(
+        &self,
+        socket: &UdpSocket,
+        upstream: SocketAddr,
+        question: &Question,
+        query: &Query,
+        ids: &[u16],
+        fwd_request: &Message,
+    ) -> Option<Resolved> {
+        let sent_at = Instant::now();
+        // As large as any datagram, as the query may advertise an EDNS payload size
+        // over 512 bytes (e.g. with a key tag option).
+        let mut buf = [0; 65535];
+        // Responses that don't answer the query (e.g. late ones to an earlier query)
+        // are skipped, up to MAX_STRAY_RESPONSES.
+        for _ in 0..=MAX_STRAY_RESPONSES {
+            let remaining = self.timeout.saturating_sub(sent_at.elapsed());
+            if remaining.is_zero() {
+                return None;
+            }
+            socket
+                .set_read_timeout(Some(remaining))
+                .expect("Failed to set the resolver's timeout.");
+            let (sz, src) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return None;
                 }
                 Err(err) => {
                     println!("Error receiving from the resolver: {}", &err);
+                    return Some(Lookup::Failed.into());
                 }
+            };
+            println!("Received {} bytes from the resolver at {}.", sz, &src);
+            if !budget::spend(Work::BytesParsed, sz as u64) {
+                return Some(Lookup::Failed.into());
             }
+            let fwd_response = match Message::parse_from(&buf[..sz]) {
+                Ok(response) => response,
+                Err(err) => {
+                    println!("Malformed response from the resolver: {}", err);
+                    return Some(Lookup::Failed.into());
+                }
+            };
+            let matched = ids
+                .iter()
+                .find_map(|&id| query.matches_response(id, &fwd_response).ok());
+            let rcode = match matched {
+                Some(rcode) => rcode,
+                None => {
+                    if let Err(mismatch) = query.matches_response(ids[ids.len() - 1], &fwd_response)
+                    {
+                        println!(
+                            "[FORWARD] Skipping a response that doesn't answer the query: {}.",
+                            mismatch
+                        );
+                    }
+                    continue;
+                }
+            };
+            self.upstream_state.lock().unwrap().record_rtt(
+                &ForwardingDnsResolver::upstream_key(upstream),
+                sent_at.elapsed(),
+            );
+            return Some(self.accept(upstream, question, query, fwd_request, fwd_response, rcode));
+        }
+        println!("[FORWARD] No response from the resolver answered the query.");
+        Some(Lookup::Failed.into())
+    }
+
+    // What `question` is answered with, given `fwd_response`, `upstream`'s response
+    // to `fwd_request`, with `rcode`, along with the header and authorities of the
+    // response the answer came in: the TCP one if this one was truncated and the TCP
+    // fallback got it.
+    fn accept(
+        &self,
+        upstream: SocketAddr,
+        question: &Question,
+        query: &Query,
+        fwd_request: &Message,
+        fwd_response: Message,
+        rcode: RCode,
+    ) -> Resolved {
+        println!("Received response from the resolver: {}", &fwd_response);
+        let (mut fwd_response, mut rcode) = (fwd_response, rcode);
+        if fwd_response.get_header().get_tc() {
+            let full = self
+                .tcp_fallback
+                .and_then(|timeout| self.ask_over_tcp(upstream, query, fwd_request, timeout));
+            if let Some(check) = &self.split_brain {
+                let key = ForwardingDnsResolver::upstream_key(upstream);
+                match &full {
+                    Some((full, _)) => {
+                        check.compare(&key, upstream, fwd_request, &fwd_response, full)
+                    }
+                    None => check.check(&key, upstream, fwd_request, &fwd_response),
+                }
+            }
+            if let Some((full, full_rcode)) = full {
+                fwd_response = full;
+                rcode = full_rcode;
+            }
+        }
+        let answers = fwd_response.get_answers();
+        if let Some(honesty) = &self.ttl_honesty {
+            honesty.observe(question, answers, Instant::now());
+        }
+        let lookup = match rcode {
+            _ if !answers.is_empty() => Lookup::Found(answers.to_vec()),
+            RCode::NoError => Lookup::FoundNoData,
+            RCode::ServerError => Lookup::Failed,
+            _ => Lookup::NotFound,
+        };
+        ForwardingDnsResolver::resolved(upstream, lookup, &fwd_response)
+    }
+
+    // Asks `fwd_request`, which `upstream` answered truncated, again over TCP; the
+    // full response and its RCODE, or None if it couldn't be had within `timeout`.
+    fn ask_over_tcp(
+        &self,
+        upstream: SocketAddr,
+        query: &Query,
+        fwd_request: &Message,
+        timeout: Duration,
+    ) -> Option<(Message, RCode)> {
+        let full = exchange_over_tcp(upstream, &fwd_request.encode(), timeout)
+            .map_err(|err| err.to_string())
+            .and_then(|full| {
+                query
+                    .matches_response(fwd_request.get_header().get_id(), &full)
+                    .map(|rcode| (full, rcode))
+                    .map_err(|mismatch| mismatch.to_string())
+            });
+        match full {
+            Ok((full, rcode)) => {
+                println!(
+                    "[FORWARD] {} truncated the answer over UDP; got {} record(s) over TCP.",
+                    upstream,
+                    full.get_answers().len()
+                );
+                Some((full, rcode))
+            }
+            Err(err) => {
+                println!(
+                    "[FORWARD] {} truncated the answer over UDP, and asking over TCP failed \
+                     ({}); passing the truncated answer on.",
+                    upstream, err
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        fs,
+        io::{Read, Write},
+        net::TcpStream,
+        thread,
+    };
+
+    use super::dns::message::{OpCode, RData};
+    use super::edns::opt_record;
+    use super::testing::Counter;
+    use super::*;
+
+    // "example.com IN A" with RD set, as sent by dig +noedns.
+    const QUERY: [u8; 29] = [
+        0x9e, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, b'e', b'x',
+        b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01,
+    ];
+
+    struct CountingHandler {
+        maintained: Counter,
+    }
+
+    impl HandleOpcode for CountingHandler {
+        fn opcode(&self) -> OpCode {
+            OpCode::Query
+        }
+
+        fn handle(
+            &self,
+            _info: &QueryInfo,
+            header: &Header,
+            _data: &[u8],
+            _stats: &Stats,
+        ) -> Option<Message> {
+            Some(Message::new(
+                &response_header(header, RCode::NoError).into(),
+                &Arc::from([]),
+                &Arc::from([]),
+            ))
+        }
+
+        fn maintain(&self) {
+            self.maintained.increment();
+        }
+    }
+
+    fn server(tags: &[&str], maintained: &Counter) -> DnsServer {
+        let listeners: Vec<Listener> = tags
+            .iter()
+            .map(|tag| {
+                Listener::bind(&ListenerSpec::parse(&format!("{}=127.0.0.1:0", tag)).unwrap())
+                    .unwrap()
+            })
+            .collect();
+        DnsServer {
+            verify_encoding: true,
+            ..DnsServer::new(
+                listeners,
+                vec![Arc::new(CountingHandler {
+                    maintained: maintained.clone(),
+                })],
+            )
+        }
+    }
+
+    fn served(server: &DnsServer, tag: &str) -> u64 {
+        server.stats.listener(tag).unwrap().responses_sent.get()
+    }
+
+    // One resolver chain is shared by the worker threads (see workers.rs), and so is
+    // what the handler in front of it holds.
+    #[test]
+    fn resolvers_and_handlers_can_be_shared_across_threads() {
+        fn shared<T: Send + Sync + ?Sized>() {}
+        shared::<dyn Resolve>();
+        shared::<ForwardingDnsResolver>();
+        shared::<cache::CachingResolver>();
+        shared::<PinnedResolver>();
+        shared::<StaticDnsResolver>();
+        shared::<rpz::ResponsePolicy>();
+        shared::<QueryOpcodeHandler>();
+        shared::<Message>();
+    }
+
+    #[test]
+    fn flooded_listener_does_not_starve_the_others() {
+        let server = server(&["flooded", "quiet"], &Counter::default());
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let flooded = server.listeners[0].socket.local_addr().unwrap();
+        let quiet = server.listeners[1].socket.local_addr().unwrap();
+        for _ in 0..LISTENER_BUDGET * 3 {
+            client.send_to(&QUERY, flooded).unwrap();
+        }
+        client.send_to(&QUERY, quiet).unwrap();
+
+        let mut state = LoopState::new(&server.randomness);
+        server.run_once(&mut state).unwrap();
+        assert_eq!(served(&server, "quiet"), 1);
+        assert_eq!(served(&server, "flooded"), LISTENER_BUDGET as u64);
+
+        // The backlog keeps being worked off in later iterations.
+        server.run_once(&mut state).unwrap();
+        server.run_once(&mut state).unwrap();
+        assert_eq!(served(&server, "flooded"), 3 * LISTENER_BUDGET as u64);
+    }
+
+    #[test]
+    fn maintenance_runs_while_a_listener_is_flooded() {
+        let maintained = Counter::default();
+        let server = server(&["flooded"], &maintained);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let flooded = server.listeners[0].socket.local_addr().unwrap();
+        for _ in 0..LISTENER_BUDGET * 2 {
+            client.send_to(&QUERY, flooded).unwrap();
+        }
+
+        let mut state = LoopState::new(&server.randomness);
+        state.maintenance.next_due = Instant::now();
+        server.run_once(&mut state).unwrap();
+        assert_eq!(maintained.get(), 1);
+        assert_eq!(served(&server, "flooded"), LISTENER_BUDGET as u64);
+    }
+
+    #[test]
+    fn idle_loop_wakes_up_for_maintenance() {
+        let maintained = Counter::default();
+        let server = server(&["idle"], &maintained);
+
+        let mut state = LoopState::new(&server.randomness);
+        state.maintenance.next_due = Instant::now() + Duration::from_millis(20);
+        let started = Instant::now();
+        server.run_once(&mut state).unwrap();
+        assert_eq!(maintained.get(), 1);
+        assert!(started.elapsed() < MAINTENANCE_INTERVAL);
+    }
+
+    #[test]
+    fn malformed_messages_are_parse_errors() {
+        let with_question = |question: &[u8]| -> Vec<u8> {
+            let mut data = QUERY[..12].to_vec();
+            data.extend_from_slice(question);
+            data
+        };
+        let cases: [(Vec<u8>, DnsParseError); 11] = [
+            (
+                QUERY[..7].to_vec(),
+                DnsParseError::TruncatedHeader { length: 7 },
+            ),
+            (
+                with_question(&[7, b'e', b'x', b'a']),
+                DnsParseError::LabelOverrun { offset: 12 },
+            ),
+            (
+                with_question(&[3, b'c', b'o', b'm']),
+                DnsParseError::LabelOverrun { offset: 16 },
+            ),
+            // Pointers past the end, at themselves, and into the header.
+            (
+                with_question(&[0xC0, 0xFF, 0, 1, 0, 1]),
+                DnsParseError::BadPointer {
+                    offset: 12,
+                    target: 0xFF,
+                },
+            ),
+            (
+                with_question(&[1, b'a', 0xC0, 14, 0, 1, 0, 1]),
+                DnsParseError::BadPointer {
+                    offset: 14,
+                    target: 14,
+                },
+            ),
+            // A pointer back at the start of its own name, which would loop.
+            (
+                vec![
+                    0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+                    0x61, 0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01,
+                ],
+                DnsParseError::BadPointer {
+                    offset: 14,
+                    target: 12,
+                },
+            ),
+            (
+                with_question(&[[63].as_slice(), &[b'a'; 63]].concat().repeat(4)),
+                DnsParseError::NameTooLong { offset: 12 },
+            ),
+            (
+                with_question(&[3, 0xFF, 0xFE, b'x', 0, 0, 1, 0, 1]),
+                DnsParseError::InvalidUtf8Label { offset: 12 },
+            ),
+            // Length bytes of the extended and unallocated label types, with as many
+            // bytes after them as a label of that length would take.
+            (
+                with_question(&[[0x40].as_slice(), &[b'a'; 64], &[0, 0, 1, 0, 1]].concat()),
+                DnsParseError::ReservedLabelType { offset: 12 },
+            ),
+            (
+                with_question(
+                    &[[1, b'a', 0xBF].as_slice(), &[b'a'; 191], &[0, 0, 1, 0, 1]].concat(),
+                ),
+                DnsParseError::ReservedLabelType { offset: 14 },
+            ),
+            (
+                QUERY[..27].to_vec(),
+                DnsParseError::TruncatedRecord { offset: 25 },
+            ),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(Message::parse_from(&data).unwrap_err(), expected);
+        }
+        let mut trailing = QUERY.to_vec();
+        trailing.push(0);
+        assert_eq!(
+            Message::parse_from(&trailing).unwrap_err(),
+            DnsParseError::TrailingGarbage { offset: 29 }
+        );
+        // A pointer back at an earlier name is fine.
+        let mut compressed = QUERY.to_vec();
+        compressed[7] = 1;
+        compressed.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        assert_eq!(
+            Message::parse_from(&compressed)
+                .unwrap()
+                .get_answers()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn malformed_queries_get_formerr_and_the_server_carries_on() {
+        let server = testing::dummy_server();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        let mut state = LoopState::new(&server.randomness);
+        let mut ask = |data: &[u8]| -> Vec<u8> {
+            client.send_to(data, address).unwrap();
+            server.run_once(&mut state).unwrap();
+            let mut buf = [0; 512];
+            let size = client.recv(&mut buf).unwrap();
+            buf[..size].to_vec()
+        };
+
+        let mut overrun = QUERY.to_vec();
+        overrun[12] = 60;
+        // An extended label type (0x40), as if its label were 64 bytes long.
+        let reserved = [&QUERY[..12], &[0x40], &[b'a'; 64], &QUERY[24..]].concat();
+        for (data, id) in [
+            (&QUERY[..5], [0x9e, 0x01]),
+            (&QUERY[..1], [0, 0]),
+            (&overrun[..], [0x9e, 0x01]),
+            (&reserved[..], [0x9e, 0x01]),
+        ] {
+            let response = ask(data);
+            let header = Header::parse_from(response[..12].try_into().unwrap());
+            assert_eq!(response[..2], id);
+            assert_eq!(*header.get_rcode(), RCode::FormatError);
+            assert_eq!(header.get_qd_count(), 0);
+        }
+        let response = Message::parse_from(&ask(&QUERY)).unwrap();
+        assert_eq!(*response.get_header().get_rcode(), RCode::NoError);
+        assert_eq!(response.get_answers().len(), 1);
+    }
+
+    #[test]
+    fn edns_queries_over_512_bytes_are_received_whole() {
+        let server = testing::dummy_server();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        // "example.com IN A" with an OPT record padded to 648 bytes (RFC 7830).
+        let mut request = QUERY.to_vec();
+        request[11] = 1;
+        let padding = 648 - request.len() - 15;
+        request.extend_from_slice(&[0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        request.extend_from_slice(&(padding as u16 + 4).to_be_bytes());
+        request.extend_from_slice(&[0x00, 0x0C]);
+        request.extend_from_slice(&(padding as u16).to_be_bytes());
+        request.resize(648, 0);
+        client.send_to(&request, address).unwrap();
+        server
+            .run_once(&mut LoopState::new(&server.randomness))
+            .unwrap();
+
+        let mut buf = [0; 4096];
+        let size = client.recv(&mut buf).unwrap();
+        let response = Message::parse_from(&buf[..size]).unwrap();
+        assert_eq!(*response.get_header().get_rcode(), RCode::NoError);
+        assert_eq!(response.get_answers().len(), 1);
+    }
+
+    #[test]
+    fn question_counts_that_lie_get_formerr() {
+        let server = testing::dummy_server();
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        let with_count = |count: u16, data: &[u8]| -> Vec<u8> {
+            let mut query = data.to_vec();
+            query[4..6].copy_from_slice(&count.to_be_bytes());
+            query
+        };
+        for (query, err) in [
+            (with_count(0, &QUERY[..12]), DnsParseError::NoQuestion),
+            (
+                with_count(3, &QUERY),
+                DnsParseError::LabelOverrun { offset: 29 },
+            ),
+            (
+                with_count(65535, &QUERY),
+                DnsParseError::TooManyQuestions { count: 65535 },
+            ),
+        ] {
+            if err != DnsParseError::NoQuestion {
+                assert_eq!(Message::parse_from(&query).unwrap_err(), err);
+            }
+            let response = server.answer(&info, &query).unwrap();
+            let header = response.get_header();
+            assert_eq!(header.get_id(), 0x9e01);
+            assert!(header.get_qr());
+            assert_eq!(*header.get_rcode(), RCode::FormatError);
+            assert!(response.get_questions().is_empty());
+        }
+    }
+
+    #[test]
+    fn responses_that_cannot_be_sent_are_counted() {
+        let server = testing::dummy_server();
+        // Port 0 can't be sent to, as a spoofed source might have it.
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:0".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        server.serve_datagram(&server.listeners[0], &info, &QUERY);
+        let snapshot = server.stats.snapshot();
+        assert_eq!(snapshot.send_failures, 1);
+        assert_eq!(snapshot.responses_sent, 0);
+    }
+
+    #[test]
+    fn classes_other_than_in_are_refused() {
+        for (text, class, code) in [
+            ("IN", RecordClass::In, 1),
+            ("chaos", RecordClass::Ch, 3),
+            ("CLASS4", RecordClass::Hs, 4),
+            ("class9", RecordClass::Unknown(9), 9),
+        ] {
+            assert_eq!(text.parse::<RecordClass>().unwrap(), class);
+            assert_eq!(RecordClass::from(code), class);
+            assert_eq!(u16::from(class), code);
         }
+        assert_eq!(RecordClass::Unknown(9).to_string(), "CLASS9");
+        assert!("INTERNET".parse::<RecordClass>().is_err());
+
+        let server = testing::dummy_server();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        let mut state = LoopState::new(&server.randomness);
+        let mut ask = |class: u16| -> Message {
+            let mut data = testing::query("version.bind", None);
+            let at = data.len() - 2;
+            data[at..].copy_from_slice(&class.to_be_bytes());
+            client.send_to(&data, address).unwrap();
+            server.run_once(&mut state).unwrap();
+            let mut buf = [0; 512];
+            let size = client.recv(&mut buf).unwrap();
+            Message::parse_from(&buf[..size]).unwrap()
+        };
+
+        for class in [3, 4, 255] {
+            let response = ask(class);
+            assert_eq!(*response.get_header().get_rcode(), RCode::Refused);
+            assert_eq!(
+                response.get_questions()[0].get_class(),
+                RecordClass::from(class)
+            );
+        }
+        let response = ask(1);
+        assert_eq!(*response.get_header().get_rcode(), RCode::NoError);
+        assert_eq!(
+            response.get_answers()[0].to_string(),
+            "version.bind.    60    IN    A    8.8.8.8"
+        );
+        assert_eq!(server.stats.refused_by_class.get(), 3);
+    }
+
+    #[test]
+    fn aaaa_records_display_as_ipv6_and_are_answered_by_the_dummy() {
+        let name: Arc<LabelSequence> = Arc::new("example.com".parse().unwrap());
+        let answer = Answer::aaaa(&name, 300, "2001:db8::1".parse().unwrap());
+        assert_eq!(answer.get_data_length(), 16);
+        assert_eq!(
+            answer.to_string(),
+            "example.com.    300    IN    AAAA    2001:db8::1"
+        );
+        // RDATA of the wrong size for its type isn't taken for an address.
+        let short = Answer::new(
+            /* name= */ &name,
+            /* type= */ RecordType::Aaaa,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */ &Arc::from([1, 2, 3, 4]),
+        );
+        assert_eq!(
+            short.to_string(),
+            "example.com.    300    IN    AAAA    \\# 4 01 02 03 04"
+        );
+
+        // One request asking for both A and AAAA.
+        let mut header = Header::default();
+        header.set_id(0x4242).set_rd(true);
+        let questions: Arc<[Question]> = Arc::from([
+            Question::new(&name, RecordType::A, RecordClass::In),
+            Question::new(&name, RecordType::Aaaa, RecordClass::In),
+        ]);
+        let request = Message::new(&header.into(), &questions, &Arc::from([])).encode();
+
+        let server = testing::dummy_server();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        client
+            .send_to(&request, server.listeners[0].socket.local_addr().unwrap())
+            .unwrap();
+        server
+            .run_once(&mut LoopState::new(&server.randomness))
+            .unwrap();
+        let mut buf = [0; 512];
+        let size = client.recv(&mut buf).unwrap();
+        let response = Message::parse_from(&buf[..size]).unwrap();
+        let answers: Vec<String> = response
+            .get_answers()
+            .iter()
+            .map(Answer::to_string)
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                "example.com.    60    IN    A    8.8.8.8",
+                "example.com.    60    IN    AAAA    2001:4860:4860::8888",
+            ]
+        );
+        assert_eq!(response.get_answers()[1].get_data_length(), 16);
+    }
+
+    // A referral for sub.example.com from "example.com"'s servers: no answers, the
+    // delegation's NS RRset in the authority section.
+    fn referral(request: &Message) -> Message {
+        let zone: Arc<LabelSequence> = Arc::new("sub.example.com".parse().unwrap());
+        let authorities: Vec<Answer> = ["ns1.sub.example.com", "ns2.sub.example.com"]
+            .iter()
+            .map(|server| {
+                Answer::new(
+                    /* name= */ &zone,
+                    /* type= */ RecordType::Ns,
+                    /* class= */ RecordClass::In,
+                    /* ttl= */ 86400,
+                    /* data= */ &server.parse::<LabelSequence>().unwrap().encode(),
+                )
+            })
+            .collect();
+        let mut header = response_header(request.get_header(), RCode::NoError);
+        header.set_qd_count(request.get_header().get_qd_count());
+        Message::new(&header.into(), request.get_questions(), &Arc::from([]))
+            .with_authorities(&authorities.into())
+    }
+
+    #[test]
+    fn authority_sections_round_trip() {
+        let query = Message::parse_from(&testing::query("www.sub.example.com", None)).unwrap();
+        let response = referral(&query);
+        assert_eq!(response.get_header().get_ns_count(), 2);
+
+        let encoded = response.encode();
+        let parsed = Message::parse_from(&encoded).unwrap();
+        assert_eq!(parsed.get_authorities().len(), 2);
+        assert_eq!(parsed.encode(), encoded);
+        assert!(verify::encode_verified(&response, &Appendix::default(), true).is_ok());
+        let text = response.to_string();
+        let authority_section = text.split_once(";; AUTHORITY SECTION:\n").unwrap().1;
+        assert_eq!(authority_section.lines().count(), 2);
+        assert!(authority_section.starts_with(";; sub.example.com.    86400    IN    NS    "));
+
+        // Authority records that run past the end, or leave bytes after them.
+        assert!(matches!(
+            Message::parse_from(&encoded[..encoded.len() - 1]),
+            Err(DnsParseError::TruncatedRecord { .. })
+        ));
+        let mut trailing = encoded.to_vec();
+        trailing.push(0);
+        assert!(matches!(
+            Message::parse_from(&trailing),
+            Err(DnsParseError::TrailingGarbage { .. })
+        ));
+
+        // Truncation drops the authority section before any answers.
+        let truncated = response.truncate_to(encoded.len() - 1);
+        assert!(truncated.get_authorities().is_empty());
+        assert_eq!(truncated.get_header().get_ns_count(), 0);
+        assert!(!truncated.get_header().get_tc());
+    }
+
+    #[test]
+    fn truncation_keeps_the_opt_record() {
+        let query = Message::parse_from(&testing::query("www.example.com", None)).unwrap();
+        let name = query.get_questions()[0].get_name();
+        let answers: Vec<Answer> = (0..40)
+            .map(|host| Answer::a(name, 60, Ipv4Addr::new(192, 0, 2, host)))
+            .collect();
+        let mut header = response_header(query.get_header(), RCode::NoError);
+        header.set_qd_count(1);
+        let opt = edns::extended_error(edns::EDE_FILTERED, "filtered");
+        let response = Message::new(&header.into(), query.get_questions(), &answers.into())
+            .with_authorities(referral(&query).get_authorities())
+            .with_additionals(&Arc::from([provenance::annotation("source=static"), opt]));
+
+        let truncated = response.truncate_to(512);
+        assert!(truncated.encode().len() <= 512);
+        assert!(truncated.get_header().get_tc());
+        assert!(truncated.get_authorities().is_empty());
+        assert_eq!(truncated.get_additionals().len(), 1);
+        assert_eq!(truncated.get_header().get_ar_count(), 1);
+        assert_eq!(truncated.get_opt(), response.get_opt());
+    }
+
+    #[test]
+    fn repeated_names_are_compressed() {
+        let request =
+            Message::parse_from(&testing::query("a-fairly-long-name.example.com", None)).unwrap();
+        let name = request.get_questions()[0].get_name();
+        let answers: Vec<Answer> = (1..=4)
+            .map(|host| {
+                Answer::new(
+                    /* name= */ name,
+                    /* type= */ RecordType::A,
+                    /* class= */ RecordClass::In,
+                    /* ttl= */ 60,
+                    /* data= */ &Arc::from([192, 0, 2, host]),
+                )
+            })
+            .collect();
+        let mut header = response_header(request.get_header(), RCode::NoError);
+        header.set_qd_count(1);
+        let response = Message::new(&header.into(), request.get_questions(), &Arc::from([]))
+            .with_answers(&answers.into());
+
+        // The header, the question (a 32-byte name), then four records whose owner is a
+        // pointer to the question's name: 16 bytes each, data included, instead of 46.
+        let encoded = response.encode();
+        assert_eq!(encoded.len(), 12 + 36 + 4 * (2 + 10 + 4));
+        let parsed = Message::parse_from(&encoded).unwrap();
+        assert_eq!(parsed.to_string(), response.to_string());
+        assert_eq!(parsed.encode(), encoded);
+        assert!(verify::encode_verified(&response, &Appendix::default(), true).is_ok());
+    }
+
+    #[test]
+    fn appended_records_keep_the_counts_and_the_compression_valid() {
+        let request = Message::parse_from(&testing::query("www.example.com", None)).unwrap();
+        let mut header = response_header(request.get_header(), RCode::NoError);
+        // Counts left over from the request are overwritten with those of the sections.
+        header.set_ar_count(7);
+        let answer = Answer::new(
+            /* name= */ request.get_questions()[0].get_name(),
+            /* type= */ RecordType::A,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 60,
+            /* data= */ &Arc::from([192, 0, 2, 1]),
+        );
+        let response = Message::new(
+            &header.into(),
+            request.get_questions(),
+            &Arc::from([answer]),
+        )
+        .with_additionals(&Arc::from([
+            provenance::annotation("source=static"),
+            opt_record(10, &[0xAB; 8]),
+        ]));
+
+        // A stand-in for TSIG: owned by a key name that compresses against the
+        // question, signing with a checksum of the bytes before it.
+        let signed: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+        let signer = |before: &[u8]| -> Answer {
+            *signed.borrow_mut() = before.to_vec();
+            let checksum = before.iter().map(|&byte| byte as u32).sum::<u32>();
+            Answer::new(
+                /* name= */ &Arc::new("key.example.com".parse().unwrap()),
+                /* type= */ RecordType::Unknown(250),
+                /* class= */ RecordClass::Any,
+                /* ttl= */ 0,
+                /* data= */ &checksum.to_be_bytes().into(),
+            )
+        };
+        let appendix = Appendix {
+            padding: Some((128, 1232)),
+            signer: Some(&signer),
+        };
+        let encoded = verify::encode_verified(&response, &appendix, true).unwrap();
+        let parsed = verify::parse_strict(&encoded).unwrap();
+        assert_eq!(parsed.get_header().get_ar_count(), 3);
+        let additionals = parsed.get_additionals();
+        assert_eq!(additionals[1].get_type(), RecordType::Opt);
+        assert_eq!(additionals[2].get_name().to_string(), "key.example.com");
+        // "key" and a pointer to the question's example.com.
+        assert!(encoded
+            .windows(5)
+            .any(|window| window == [3, b'k', b'e', b'y', 0xC0]));
+
+        // The signature covers the padded message before it, counting two additionals.
+        let signed = signed.into_inner();
+        assert_eq!(signed.len(), 128);
+        assert_eq!(&signed[10..12], [0, 2]);
+        assert_eq!(signed[12..], encoded[12..signed.len()]);
+        let checksum = signed.iter().map(|&byte| byte as u32).sum::<u32>();
+        assert_eq!(additionals[2].get_data().as_ref(), checksum.to_be_bytes());
+    }
+
+    #[test]
+    fn names_in_rdata_are_decompressed_and_compressed_again() {
+        // An upstream's answer: www.example.com is a CNAME for cdn.example.com, the
+        // target's "example.com" a pointer into the question (offset 16).
+        let mut upstream = testing::query("www.example.com", None);
+        upstream[2] |= 0x80;
+        upstream[7] = 1;
+        upstream.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6]);
+        upstream.extend_from_slice(&[3, b'c', b'd', b'n', 0xC0, 16]);
+        let parsed = Message::parse_from(&upstream).unwrap();
+        let target: LabelSequence = "cdn.example.com".parse().unwrap();
+        assert_eq!(parsed.get_answers()[0].get_data(), target.encode());
+        assert_eq!(parsed.encode().as_ref(), upstream.as_slice());
+
+        // In a message of our own the target means the same, wherever it points.
+        let request = Message::parse_from(&testing::query("elsewhere.example.org", None)).unwrap();
+        let mut header = response_header(request.get_header(), RCode::NoError);
+        header.set_qd_count(1);
+        let response = Message::new(&header.into(), request.get_questions(), &Arc::from([]))
+            .with_answers(parsed.get_answers());
+        let encoded = verify::encode_verified(&response, &Appendix::default(), true).unwrap();
+        let reparsed = Message::parse_from(&encoded).unwrap();
+        assert_eq!(reparsed.get_answers()[0].get_data(), target.encode());
+
+        // RDATA that doesn't hold just a name is malformed.
+        let mut trailing = upstream.clone();
+        trailing[upstream.len() - 7] = 7;
+        trailing.push(0);
+        assert!(matches!(
+            Message::parse_from(&trailing),
+            Err(DnsParseError::BadRdata { offset: 45 })
+        ));
+    }
+
+    #[test]
+    fn rdata_is_typed_round_trips_and_displays_like_dig() {
+        let name: Arc<LabelSequence> = Arc::new("example.com".parse().unwrap());
+        let host = |host: &str| -> LabelSequence { host.parse().unwrap() };
+        let records = [
+            (
+                RData::Mx {
+                    preference: 10,
+                    exchange: host("mail.example.com"),
+                },
+                "10 mail.example.com.",
+            ),
+            (
+                RData::Txt(vec![Arc::from("v=spf1 -all"), Arc::from("say \"hi\"")]),
+                r#""v=spf1 -all" "say \"hi\"""#,
+            ),
+            (
+                RData::Soa {
+                    mname: host("ns.example.com"),
+                    rname: host("hostmaster.example.com"),
+                    serial: 2026101601,
+                    refresh: 3600,
+                    retry: 600,
+                    expire: 86400,
+                    minimum: 60,
+                },
+                "ns.example.com. hostmaster.example.com. 2026101601 3600 600 86400 60",
+            ),
+            (
+                RData::Srv {
+                    priority: 1,
+                    weight: 5,
+                    port: 5060,
+                    target: host("sip.example.com"),
+                },
+                "1 5 5060 sip.example.com.",
+            ),
+            (RData::Raw(99, Arc::from([0xAB, 0x01])), "\\# 2 ab 01"),
+        ];
+        for (rdata, shown) in records {
+            let answer = Answer::from_rdata(&name, RecordClass::In, 300, rdata);
+            assert_eq!(answer.get_rdata().to_string(), shown);
+            // Read back from the wire, it is the same record.
+            let reread = Answer::new(
+                /* name= */ &name,
+                /* type= */ answer.get_type(),
+                /* class= */ RecordClass::In,
+                /* ttl= */ 300,
+                /* data= */ &answer.get_data(),
+            );
+            assert_eq!(reread.to_string(), answer.to_string());
+        }
+
+        // Names in MX RDATA are compressed against the owner's, and read back whole.
+        let mut header = Header::default();
+        header.set_qr(true);
+        let mx = Answer::from_rdata(
+            /* name= */ &name,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */
+            RData::Mx {
+                preference: 10,
+                exchange: host("mail.example.com"),
+            },
+        );
+        let message = Message::new(&Arc::new(header), &Arc::from([]), &Arc::from([mx]));
+        let encoded = message.encode();
+        assert_eq!(
+            encoded[encoded.len() - 11..],
+            [0, 9, 0, 10, 4, b'm', b'a', b'i', b'l', 0xC0, 12]
+        );
+        match Message::parse_from(&encoded).unwrap().get_answers()[0].get_rdata() {
+            RData::Mx { exchange, .. } => assert_eq!(exchange.to_string(), "mail.example.com"),
+            other => panic!("expected MX, got {other:?}"),
+        }
+
+        // RDATA that doesn't fit its type stays raw.
+        let short = Answer::new(
+            /* name= */ &name,
+            /* type= */ RecordType::Mx,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */ &Arc::from([0, 1]),
+        );
+        assert!(matches!(short.get_rdata(), RData::Raw(15, _)));
+    }
+
+    #[test]
+    fn soa_round_trips_with_its_names_compressed() {
+        let zone: Arc<LabelSequence> = Arc::new("example.com".parse().unwrap());
+        let soa = Answer::from_rdata(
+            /* name= */ &zone,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 3600,
+            /* data= */
+            RData::Soa {
+                mname: "ns.example.com".parse().unwrap(),
+                rname: "hostmaster.example.com".parse().unwrap(),
+                serial: 2026101601,
+                refresh: 7200,
+                retry: 900,
+                expire: 1209600,
+                minimum: 300,
+            },
+        );
+        let mut header = Header::default();
+        header.set_qr(true);
+        let message = Message::new(&Arc::new(header), &Arc::from([]), &Arc::from([]))
+            .with_authorities(&Arc::from([soa.clone()]));
+        let encoded = message.encode();
+        // Both names end in a pointer to the owner.
+        assert_eq!(
+            encoded
+                .windows(2)
+                .filter(|pair| pair == &[0xC0, 12])
+                .count(),
+            2
+        );
+
+        let parsed = Message::parse_from(&encoded).unwrap();
+        let reread = parsed.get_soa().unwrap();
+        assert_eq!(reread.to_string(), soa.to_string());
+        assert_eq!(reread.get_data(), soa.get_data());
+        assert_eq!(parsed.get_negative_ttl(), Some(300));
+    }
+
+    #[test]
+    fn soa_of_an_nxdomain_from_the_corpus_is_read_whole() {
+        let captured = wire_corpus::corpus_entry("nxdomain-soa");
+        let message = Message::parse_from(&captured).unwrap();
+        let soa = message.get_soa().unwrap();
+        assert_eq!(soa.get_name().to_string(), "example.com");
+        assert_eq!(
+            soa.get_rdata().to_string(),
+            "ns.icann.org. noc.dns.icann.org. 2024081457 7200 3600 1209600 3600"
+        );
+        match soa.get_rdata() {
+            RData::Soa { rname, minimum, .. } => {
+                // Its last two labels come through a pointer into MNAME.
+                assert_eq!(rname.to_string(), "noc.dns.icann.org");
+                assert_eq!(*minimum, 3600);
+            }
+            other => panic!("expected SOA, got {other:?}"),
+        }
+        assert_eq!(message.get_negative_ttl(), Some(3600));
+        assert_eq!(
+            Message::parse_from(&QUERY).unwrap().get_negative_ttl(),
+            None
+        );
+    }
+
+    #[test]
+    fn upstream_authority_records_are_passed_through() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mock = upstream.try_clone().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = mock.recv_from(&mut buf) {
+                let request = Message::parse_from(&buf[..size]).unwrap();
+                let _ = mock.send_to(&referral(&request).encode(), source);
+            }
+        });
+        let handler =
+            testing::query_handler(testing::forwarder(upstream.local_addr().unwrap(), 10.0));
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        let request = testing::query("www.sub.example.com", None);
+        let header = Header::parse_from(request[..12].try_into().unwrap());
+        let response = handler
+            .handle(&info, &header, &request, &Stats::default())
+            .unwrap();
+        assert!(response.get_answers().is_empty());
+        // As the upstream sent them.
+        let sent = referral(&Message::parse_from(&request).unwrap());
+        let records = |message: &Message| -> Vec<Arc<[u8]>> {
+            message
+                .get_authorities()
+                .iter()
+                .map(Answer::encode)
+                .collect()
+        };
+        assert_eq!(records(&response), records(&sent));
+        assert_eq!(response.get_header().get_ns_count(), 2);
+    }
+
+    #[test]
+    fn unanswered_queries_are_sent_again_then_fail() {
+        // Drops the first attempt at every query, and answers the retries for
+        // retried.example.com only; reports the IDs it was sent.
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mock = upstream.try_clone().unwrap();
+        let (sent_ids, ids) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let mut asked = std::collections::HashSet::new();
+            while let Ok((size, source)) = mock.recv_from(&mut buf) {
+                let request = Message::parse_from(&buf[..size]).unwrap();
+                let _ = sent_ids.send(request.get_header().get_id());
+                let name = request.get_questions()[0].get_name().to_string();
+                if asked.insert(name.clone()) || name != "retried.example.com" {
+                    continue;
+                }
+                let mut header = response_header(request.get_header(), RCode::NoError);
+                header.set_qd_count(1).set_an_count(1);
+                let answer = Answer::a(
+                    request.get_questions()[0].get_name(),
+                    60,
+                    Ipv4Addr::new(192, 0, 2, 1),
+                );
+                let response = Message::new(
+                    &header.into(),
+                    request.get_questions(),
+                    &Arc::from([answer]),
+                );
+                let _ = mock.send_to(&response.encode(), source);
+            }
+        });
+        let mut forwarder = testing::forwarder(upstream.local_addr().unwrap(), 10.0);
+        forwarder.timeout = Duration::from_millis(100);
+        forwarder.retries = 2;
+        let handler = testing::query_handler(forwarder);
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        let ask = |name: &str| -> Message {
+            let request = testing::query(name, None);
+            let header = Header::parse_from(request[..12].try_into().unwrap());
+            handler
+                .handle(&info, &header, &request, &Stats::default())
+                .unwrap()
+        };
+
+        let started = Instant::now();
+        let response = ask("retried.example.com");
+        assert_eq!(response.get_header().get_rcode(), &RCode::NoError);
+        assert_eq!(response.get_answers().len(), 1);
+        assert!(started.elapsed() < Duration::from_millis(200));
+
+        // Three attempts of 100ms, each with an ID of its own, then SERVFAIL.
+        let started = Instant::now();
+        let response = ask("silent.example.com");
+        let elapsed = started.elapsed();
+        assert_eq!(response.get_header().get_rcode(), &RCode::ServerError);
+        assert!(
+            elapsed >= Duration::from_millis(300) && elapsed < Duration::from_secs(1),
+            "{elapsed:?}"
+        );
+        let mut ids: Vec<u16> = ids.try_iter().collect();
+        assert_eq!(ids.len(), 5);
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 5);
+    }
+
+    #[test]
+    fn responses_must_answer_the_query_as_sent() {
+        // Answers each query three times: with another ID, for another name, then
+        // for real; reports the IDs it was sent.
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mock = upstream.try_clone().unwrap();
+        let (sent_ids, ids) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = mock.recv_from(&mut buf) {
+                let request = Message::parse_from(&buf[..size]).unwrap();
+                let id = request.get_header().get_id();
+                let _ = sent_ids.send(id);
+                let other = Question::new(
+                    &Arc::new("spoofed.example.com".parse().unwrap()),
+                    RecordType::A,
+                    RecordClass::In,
+                );
+                let respond = |id: u16, question: &Question, last: u8| {
+                    let mut header = response_header(request.get_header(), RCode::NoError);
+                    header.set_id(id).set_qd_count(1).set_an_count(1);
+                    let answer = Answer::a(question.get_name(), 60, Ipv4Addr::new(192, 0, 2, last));
+                    let response = Message::new(
+                        &header.into(),
+                        &Arc::from([question.clone()]),
+                        &Arc::from([answer]),
+                    );
+                    let _ = mock.send_to(&response.encode(), source);
+                };
+                respond(id.wrapping_add(1), &request.get_questions()[0], 66);
+                respond(id, &other, 66);
+                respond(id, &request.get_questions()[0], 1);
+            }
+        });
+        let handler =
+            testing::query_handler(testing::forwarder(upstream.local_addr().unwrap(), 10.0));
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        let request = testing::query("www.example.com", None);
+        let header = Header::parse_from(request[..12].try_into().unwrap());
+        let response = handler
+            .handle(&info, &header, &request, &Stats::default())
+            .unwrap();
+
+        assert_eq!(response.get_header().get_id(), 0x1234);
+        let answers: Vec<String> = response
+            .get_answers()
+            .iter()
+            .map(|answer| answer.to_string())
+            .collect();
+        assert_eq!(answers, ["www.example.com.    60    IN    A    192.0.2.1"]);
+        // Upstream saw an ID of the forwarder's, not the client's.
+        let sent: Vec<u16> = ids.try_iter().collect();
+        assert_eq!(sent.len(), 1);
+        assert_ne!(sent[0], 0x1234);
+    }
+
+    #[test]
+    fn each_query_goes_out_from_a_port_of_its_own() {
+        // Answers as the mock upstream does, reporting the ports queries came from.
+        let upstream = testing::mock_upstream(&[[192, 0, 2, 1]], 60, Duration::ZERO);
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (ports, sources) = std::sync::mpsc::channel();
+        let target = upstream.local_addr().unwrap();
+        let mock = relay.try_clone().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let forward = UdpSocket::bind("127.0.0.1:0").unwrap();
+            forward.connect(target).unwrap();
+            while let Ok((size, source)) = mock.recv_from(&mut buf) {
+                let _ = ports.send(source.port());
+                forward.send(&buf[..size]).unwrap();
+                let size = forward.recv(&mut buf).unwrap();
+                let _ = mock.send_to(&buf[..size], source);
+            }
+        });
+        let forwarder = testing::forwarder(relay.local_addr().unwrap(), 10.0);
+        let request = testing::query("www.example.com", None);
+        let header = Header::parse_from(request[..12].try_into().unwrap());
+        let question = Question::new(
+            &Arc::new("www.example.com".parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        );
+        for _ in 0..2 {
+            assert!(matches!(
+                forwarder.lookup(&header, &question).lookup,
+                Lookup::Found(_)
+            ));
+        }
+
+        let ports: Vec<u16> = sources.try_iter().collect();
+        assert_eq!(ports.len(), 2);
+        assert_ne!(ports[0], ports[1]);
+    }
+
+    #[test]
+    fn upstream_answers_over_512_bytes_are_received_whole() {
+        // 40 addresses make an answer of about 670 bytes, as an EDNS query allows.
+        let addresses: Vec<[u8; 4]> = (0..40).map(|host| [192, 0, 2, host]).collect();
+        let upstream = testing::mock_upstream(&addresses, 60, Duration::ZERO);
+        let mut forwarder = testing::forwarder(upstream.local_addr().unwrap(), 10.0);
+        forwarder.tcp_fallback = None;
+        let question = Question::new(
+            &Arc::new("www.example.com".parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        );
+        match forwarder.lookup(&Header::default(), &question).lookup {
+            Lookup::Found(answers) => assert_eq!(answers.len(), 40),
+            lookup => panic!("{:?}", lookup),
+        }
+
+        // The same goes for questions sent together.
+        let questions: Arc<[Question]> = Arc::from([question.clone(), question]);
+        let resolution = forwarder.resolve(&Header::default(), &questions);
+        assert_eq!(resolution.answers.len(), 80);
+        let counts = &forwarder.upstreams.counts(Instant::now())[0];
+        assert_eq!((counts.successes, counts.failures), (2, 0));
+    }
+
+    #[test]
+    fn upstream_rcodes_and_flags_are_passed_on() {
+        // NXDOMAIN for nx.example.com, REFUSED for anything else, both with AA and RA.
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mock = upstream.try_clone().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = mock.recv_from(&mut buf) {
+                let request = Message::parse_from(&buf[..size]).unwrap();
+                let nx = request.get_questions()[0].get_name().to_string() == "nx.example.com";
+                let rcode = if nx { RCode::NameError } else { RCode::Refused };
+                let mut header = response_header(request.get_header(), rcode);
+                header
+                    .set_qd_count(request.get_header().get_qd_count())
+                    .set_aa(true)
+                    .set_ra(true);
+                let soa = Answer::from_rdata(
+                    /* name= */ &Arc::new("example.com".parse().unwrap()),
+                    /* class= */ RecordClass::In,
+                    /* ttl= */ 3600,
+                    /* data= */
+                    RData::Soa {
+                        mname: "ns.example.com".parse().unwrap(),
+                        rname: "hostmaster.example.com".parse().unwrap(),
+                        serial: 1,
+                        refresh: 7200,
+                        retry: 900,
+                        expire: 1209600,
+                        minimum: 300,
+                    },
+                );
+                let response =
+                    Message::new(&header.into(), request.get_questions(), &Arc::from([]))
+                        .with_authorities(&Arc::from([soa]));
+                let _ = mock.send_to(&response.encode(), source);
+            }
+        });
+        let handler =
+            testing::query_handler(testing::forwarder(upstream.local_addr().unwrap(), 10.0));
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        let ask = |name: &str| -> Message {
+            let request = testing::query(name, None);
+            let header = Header::parse_from(request[..12].try_into().unwrap());
+            handler
+                .handle(&info, &header, &request, &Stats::default())
+                .unwrap()
+        };
+
+        let response = ask("nx.example.com");
+        let header = response.get_header();
+        assert_eq!(header.get_rcode(), &RCode::NameError);
+        assert!(header.get_aa());
+        assert!(header.get_ra());
+        assert!(response.get_answers().is_empty());
+        assert_eq!(
+            response.get_soa().unwrap().get_name().to_string(),
+            "example.com"
+        );
+
+        assert_eq!(
+            ask("www.example.com").get_header().get_rcode(),
+            &RCode::Refused
+        );
+    }
+
+    #[test]
+    fn truncated_answers_are_asked_again_over_tcp() {
+        // Over UDP, one address with TC set; over TCP, all of them, unless the name
+        // starts with "notcp", when the connection is closed unanswered.
+        let answer = |request: &Message, count: usize, tc: bool| -> Vec<u8> {
+            let name = request.get_questions()[0].get_name();
+            let answers: Vec<Answer> = (0..count)
+                .map(|index| Answer::a(name, 60, Ipv4Addr::new(192, 0, 2, index as u8)))
+                .collect();
+            let mut header = response_header(request.get_header(), RCode::NoError);
+            header
+                .set_qd_count(1)
+                .set_an_count(answers.len() as u16)
+                .set_tc(tc);
+            Message::new(&header.into(), request.get_questions(), &answers.into())
+                .encode()
+                .to_vec()
+        };
+        let (udp, tcp) = testing::udp_and_tcp();
+        let upstream = udp.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = udp.recv_from(&mut buf) {
+                let request = Message::parse_from(&buf[..size]).unwrap();
+                let _ = udp.send_to(&answer(&request, 1, true), source);
+            }
+        });
+        thread::spawn(move || {
+            for mut stream in tcp.incoming().flatten() {
+                let mut length = [0; 2];
+                stream.read_exact(&mut length).unwrap();
+                let mut request = vec![0; u16::from_be_bytes(length) as usize];
+                stream.read_exact(&mut request).unwrap();
+                let request = Message::parse_from(&request).unwrap();
+                if request.get_questions()[0]
+                    .get_name()
+                    .to_string()
+                    .starts_with("notcp")
+                {
+                    continue;
+                }
+                let response = answer(&request, 92, false);
+                assert!(response.len() >= 1500, "{}", response.len());
+                let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+                framed.extend_from_slice(&response);
+                stream.write_all(&framed).unwrap();
+            }
+        });
+        let handler = testing::query_handler(testing::forwarder(upstream, 10.0));
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Tcp,
+            load: Load::Normal,
+        };
+        let ask = |name: &str| -> Message {
+            let request = testing::query(name, None);
+            let header = Header::parse_from(request[..12].try_into().unwrap());
+            handler
+                .handle(&info, &header, &request, &Stats::default())
+                .unwrap()
+        };
+
+        let response = ask("www.example.com");
+        assert!(!response.get_header().get_tc());
+        assert_eq!(response.get_answers().len(), 92);
+
+        // Without an answer over TCP, the truncated one is passed on as it came.
+        let response = ask("notcp.example.com");
+        assert!(response.get_header().get_tc());
+        assert_eq!(response.get_answers().len(), 1);
+    }
+
+    #[test]
+    fn questions_of_a_request_are_sent_together_and_answered_in_order() {
+        // Holds the answer for first.example.com until the query for
+        // second.example.com comes, and answers that one first; lost.example.com goes
+        // unanswered.
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mock = upstream.try_clone().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let mut held = None;
+            while let Ok((size, source)) = mock.recv_from(&mut buf) {
+                let request = Message::parse_from(&buf[..size]).unwrap();
+                let question = &request.get_questions()[0];
+                let last = match question.get_name().to_string().as_str() {
+                    "first.example.com" => 1,
+                    "second.example.com" => 2,
+                    _ => continue,
+                };
+                let mut header = response_header(request.get_header(), RCode::NoError);
+                header.set_qd_count(1).set_an_count(1);
+                let answer = Answer::a(question.get_name(), 60, Ipv4Addr::new(192, 0, 2, last));
+                let response = Message::new(
+                    &header.into(),
+                    request.get_questions(),
+                    &Arc::from([answer]),
+                );
+                if last == 1 {
+                    held = Some((response, source));
+                    continue;
+                }
+                let _ = mock.send_to(&response.encode(), source);
+                if let Some((first, source)) = held.take() {
+                    let _ = mock.send_to(&first.encode(), source);
+                }
+            }
+        });
+        let mut resolver = testing::forwarder(upstream.local_addr().unwrap(), 10.0);
+        resolver.timeout = Duration::from_millis(500);
+        let questions: Arc<[Question]> = [
+            "first.example.com",
+            "lost.example.com",
+            "second.example.com",
+        ]
+        .iter()
+        .map(|name| {
+            Question::new(
+                &Arc::new(name.parse().unwrap()),
+                RecordType::A,
+                RecordClass::In,
+            )
+        })
+        .collect();
+
+        let start = Instant::now();
+        let resolution = resolver.resolve(&Header::default(), &questions);
+        let elapsed = start.elapsed();
+        assert!(elapsed < resolver.timeout * 2, "{elapsed:?}");
+        assert_eq!(resolution.rcode, RCode::NoError);
+        let addresses: Vec<Vec<u8>> = resolution
+            .answers
+            .iter()
+            .map(|answer| answer.get_data().to_vec())
+            .collect();
+        assert_eq!(addresses, [[192, 0, 2, 1], [192, 0, 2, 2]]);
+    }
+
+    #[test]
+    fn shutdown_answers_what_was_received_and_leaves_nothing_open() {
+        let directory = std::env::temp_dir().join(format!("shutdown-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let log = directory.join("queries.log");
+        let socket = directory.join("control.sock");
+        let mut server = testing::dummy_server();
+        server.housekeeping.query_log = Some(Arc::new(Mutex::new(
+            RotatingWriter::open(log.clone(), 1 << 20).unwrap(),
+        )));
+        server.control =
+            Some(ControlSocket::bind(socket.clone(), Arc::new(PinStore::default())).unwrap());
+
+        // A connection with a query received but not answered yet.
+        let address = server.listeners[0].stream_listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut state = LoopState::new(&server.randomness);
+        while state.connections.is_empty() {
+            server.run_once(&mut state).unwrap();
+        }
+        state.connections[0]
+            .queue
+            .push_back(testing::query("example.com", None));
+        let mut server_files: Vec<RawFd> = server
+            .listeners
+            .iter()
+            .flat_map(|listener| {
+                [
+                    listener.socket.as_raw_fd(),
+                    listener.stream_listener.as_raw_fd(),
+                ]
+            })
+            .collect();
+        server_files.push(server.control.as_ref().unwrap().fd());
+        server_files.push(state.connections[0].fd());
+        let server_files: Vec<String> = server_files
+            .into_iter()
+            .map(|fd| testing::open_file(fd).unwrap())
+            .collect();
+
+        let reports = server.shut_down(state);
+        let steps: Vec<String> = reports
+            .iter()
+            .map(|report| format!("{} {}", report.phase, report.name))
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                "stop intake connections",
+                "drain workers",
+                "drain connections",
+                "drain maintainer",
+                "flush writers artifacts",
+                "persist state handlers",
+                "close sockets control socket",
+                "close sockets listeners",
+            ]
+        );
+        assert!(reports.iter().all(|report| report.error.is_none()));
+
+        // The query was answered, and logged.
+        let mut length = [0; 2];
+        client.read_exact(&mut length).unwrap();
+        let mut response = vec![0; u16::from_be_bytes(length) as usize];
+        client.read_exact(&mut response).unwrap();
+        let response = Message::parse_from(&response).unwrap();
+        assert_eq!(response.get_header().get_id(), 0x1234);
+        assert!(fs::read_to_string(&log)
+            .unwrap()
+            .contains("IN    A -> NO_ERROR (0), 1 answer(s)"));
+        // Then the connection was closed.
+        assert_eq!(client.read(&mut length).unwrap(), 0);
+
+        assert!(!socket.exists());
+        let open = testing::open_files();
+        for file in &server_files {
+            assert!(!open.contains(file), "{} is still open", file);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
 
-        answers.into()
+    #[test]
+    fn messages_can_be_sent_between_threads() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Sync>() {}
+        assert_send::<Message>();
+        assert_sync::<Message>();
+        assert_send::<Header>();
+        assert_send::<Question>();
+        assert_send::<Answer>();
+        assert_send::<DummyDnsResolver>();
+        assert_sync::<DummyDnsResolver>();
     }
 }
@@ -0,0 +1,277 @@
+// Outgoing NOTIFY (RFC 1996): when a secondary zone takes a new version, the servers
+// given with --also-notify are told, so that they needn't wait for their refresh
+// timers. The NOTIFYs of a change don't go out at once: each target's is sent at a
+// random point of --transfer-spread-ms, so that a zone with many secondaries doesn't
+// bring them all back asking for its SOA, and for a transfer, in the same instant. A
+// NOTIFY that isn't answered is sent again, the wait doubling each time, for each zone
+// and target on its own: one target being down doesn't hold up or speed up the
+// others. After MAX_NOTIFY_ATTEMPTS the target is left to its refresh timer.
+
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::dns::message::{
+    Header, LabelSequence, Message, OpCode, Question, RecordClass, RecordType,
+};
+use super::random::Rng;
+
+// How long a NOTIFY waits for its response before it is sent again, the first time.
+const NOTIFY_RETRY: Duration = Duration::from_secs(1);
+
+// NOTIFYs sent to a target for one version of a zone, the first included.
+const MAX_NOTIFY_ATTEMPTS: u32 = 5;
+
+struct PendingNotify {
+    zone: Arc<LabelSequence>,
+    target: SocketAddr,
+    // Connected to the target, and nonblocking.
+    socket: UdpSocket,
+    id: u16,
+    attempts: u32,
+    next_send: Instant,
+    // How long the next NOTIFY waits for its response.
+    wait: Duration,
+}
+
+impl PendingNotify {
+    fn message(&self) -> Message {
+        let mut header = Header::default();
+        header
+            .set_id(self.id)
+            .set_opcode(OpCode::Notify)
+            .set_aa(true);
+        let question = Question::new(&self.zone, RecordType::Soa, RecordClass::In);
+        Message::new(&header.into(), &Arc::from([question]), &Arc::from([]))
+    }
+
+    // Whether a response to the NOTIFY has come, reading what's waiting.
+    fn answered(&self) -> bool {
+        let mut buf = [0; 512];
+        while let Ok(size) = self.socket.recv(&mut buf) {
+            let Ok(response) = Message::parse_from(&buf[..size]) else {
+                continue;
+            };
+            let header = response.get_header();
+            if header.get_id() == self.id
+                && header.get_qr()
+                && *header.get_opcode() == OpCode::Notify
+            {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+pub struct Notifier {
+    pub targets: Vec<SocketAddr>,
+    // The window over which the NOTIFYs of a change are spread.
+    pub window: Duration,
+    pub retry: Duration,
+    pub max_attempts: u32,
+    // Where the points in the window come from.
+    spread: Arc<dyn Rng>,
+    ids: Arc<dyn Rng>,
+    pending: Mutex<Vec<PendingNotify>>,
+}
+
+impl Notifier {
+    pub fn new(
+        targets: Vec<SocketAddr>,
+        window: Duration,
+        spread: Arc<dyn Rng>,
+        ids: Arc<dyn Rng>,
+    ) -> Notifier {
+        Notifier {
+            targets,
+            window,
+            retry: NOTIFY_RETRY,
+            max_attempts: MAX_NOTIFY_ATTEMPTS,
+            spread,
+            ids,
+            pending: Mutex::default(),
+        }
+    }
+
+    // Schedules the NOTIFYs of a new version of `zone`; those still pending for an
+    // older one are dropped.
+    pub fn notify(&self, zone: &Arc<LabelSequence>, now: Instant) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|notify| !notify.zone.eq_ignore_case(zone));
+        for &target in &self.targets {
+            let socket = match connected_socket(target) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    eprintln!("[NOTIFY] Can't notify {} of {}: {}", target, zone, err);
+                    continue;
+                }
+            };
+            pending.push(PendingNotify {
+                zone: Arc::clone(zone),
+                target,
+                socket,
+                id: self.ids.next_u64() as u16,
+                attempts: 0,
+                next_send: now + self.window.mul_f64(self.spread.fraction()),
+                wait: self.retry,
+            });
+        }
+    }
+
+    // Sends the NOTIFYs that are due, and forgets those that were answered.
+    pub fn poll(&self, now: Instant) {
+        self.pending.lock().unwrap().retain_mut(|notify| {
+            if notify.attempts > 0 && notify.answered() {
+                println!(
+                    "[NOTIFY] {} acknowledged {} after {} NOTIFY(s).",
+                    notify.target, notify.zone, notify.attempts
+                );
+                return false;
+            }
+            if notify.next_send > now {
+                return true;
+            }
+            if notify.attempts == self.max_attempts {
+                println!(
+                    "[NOTIFY] Giving up on {} for {}: {} NOTIFY(s) went unanswered.",
+                    notify.target, notify.zone, notify.attempts
+                );
+                return false;
+            }
+            if let Err(err) = notify.socket.send(&notify.message().encode()) {
+                eprintln!(
+                    "[NOTIFY] Failed to notify {} of {}: {}",
+                    notify.target, notify.zone, err
+                );
+            }
+            notify.attempts += 1;
+            notify.next_send = now + notify.wait;
+            notify.wait *= 2;
+            true
+        });
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|notify| notify.next_send)
+            .min()
+    }
+
+    // A line per NOTIFY not yet answered, for the control socket.
+    pub fn report(&self, now: Instant) -> Vec<String> {
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|notify| {
+                format!(
+                    "notify {} to {}: {} sent, next in {}ms",
+                    notify.zone,
+                    notify.target,
+                    notify.attempts,
+                    notify.next_send.saturating_duration_since(now).as_millis()
+                )
+            })
+            .collect()
+    }
+}
+
+fn connected_socket(target: SocketAddr) -> io::Result<UdpSocket> {
+    let local = if target.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(target)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use super::super::random::Randomness;
+    use super::*;
+
+    #[test]
+    fn unanswered_notifies_back_off_per_target() {
+        // One target acknowledges its NOTIFYs, the other never does.
+        let answering = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        silent.set_nonblocking(true).unwrap();
+        let responder = answering.try_clone().unwrap();
+        let acknowledged = Arc::new(AtomicUsize::new(0));
+        let count = Arc::clone(&acknowledged);
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = responder.recv_from(&mut buf) {
+                count.fetch_add(1, Ordering::SeqCst);
+                let request = Message::parse_from(&buf[..size]).unwrap();
+                let mut header = request.get_header().as_ref().clone();
+                header.set_qr(true);
+                let response =
+                    Message::new(&header.into(), request.get_questions(), &Arc::from([]));
+                let _ = responder.send_to(&response.encode(), source);
+            }
+        });
+
+        let randomness = Randomness::seeded(7);
+        let mut notifier = Notifier::new(
+            vec![
+                answering.local_addr().unwrap(),
+                silent.local_addr().unwrap(),
+            ],
+            Duration::from_millis(50),
+            randomness.stream("spread"),
+            randomness.stream("ids"),
+        );
+        notifier.retry = Duration::from_millis(40);
+        notifier.max_attempts = 4;
+        let zone = Arc::new("example.com".parse().unwrap());
+        let start = Instant::now();
+        notifier.notify(&zone, start);
+        let mut arrivals = Vec::new();
+        let mut buf = [0; 512];
+        while Instant::now() < start + Duration::from_millis(800) {
+            notifier.poll(Instant::now());
+            while let Ok(size) = silent.recv(&mut buf) {
+                let notify = Message::parse_from(&buf[..size]).unwrap();
+                assert_eq!(*notify.get_header().get_opcode(), OpCode::Notify);
+                assert_eq!(notify.get_questions()[0].get_type(), RecordType::Soa);
+                arrivals.push(Instant::now());
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+
+        // The silent target got every attempt, further and further apart (give or
+        // take the polling).
+        assert_eq!(arrivals.len(), 4);
+        let gaps: Vec<Duration> = arrivals.windows(2).map(|w| w[1] - w[0]).collect();
+        for (gap, wait) in gaps.iter().zip([40, 80, 160]) {
+            let wait = Duration::from_millis(wait);
+            assert!(
+                *gap + Duration::from_millis(5) >= wait && *gap < wait * 2,
+                "{gaps:?}"
+            );
+        }
+        // The answering one needed one, and nothing is left pending.
+        assert_eq!(acknowledged.load(Ordering::SeqCst), 1);
+        assert_eq!(notifier.next_due(), None);
+        assert!(notifier.report(Instant::now()).is_empty());
+    }
+}
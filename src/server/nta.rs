@@ -0,0 +1,285 @@
+// Negative trust anchors (--nta, RFC 7646): domains whose DNSSEC failures are let
+// through for a while, e.g. when a zone's signatures have expired and its operator
+// is still fixing them. The server doesn't validate answers itself but forwards to
+// an upstream that does, so a question at or under a negative trust anchor is
+// forwarded with Checking Disabled (CD, RFC 4035, 3.2.2): the upstream answers it
+// as it would one for an unsigned zone, instead of failing it as bogus. Each such
+// question is logged. An anchor lapses after its lifetime, an hour unless given,
+// and validation comes back on its own. The root and the top-level domains can only
+// be anchored with --nta-force, as that turns validation off for whole swathes of
+// the namespace.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::dns::message::LabelSequence;
+use super::housekeeping::parse_age;
+use super::policy::DomainSuffix;
+use super::signature_time::{Clock, SystemClock};
+
+// How long an anchor lasts when no lifetime is given.
+pub const DEFAULT_NTA_LIFETIME: Duration = Duration::from_secs(3600);
+
+// A --nta value: DOMAIN[=LIFETIME], e.g. example.net=30m.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NtaSpec {
+    pub domain: String,
+    pub lifetime: Duration,
+}
+
+impl NtaSpec {
+    pub fn parse(value: &str) -> Result<NtaSpec, String> {
+        let (domain, lifetime) = match value.split_once('=') {
+            Some((domain, lifetime)) => (domain, parse_lifetime(lifetime)?),
+            None => (value, DEFAULT_NTA_LIFETIME),
+        };
+        DomainSuffix::parse(domain)?;
+        Ok(NtaSpec {
+            domain: String::from(domain),
+            lifetime,
+        })
+    }
+}
+
+// Parses a lifetime in seconds, optionally with an s, m, h or d suffix.
+pub fn parse_lifetime(value: &str) -> Result<Duration, String> {
+    match parse_age(value)? {
+        lifetime if lifetime.is_zero() => Err(String::from(
+            "A negative trust anchor's lifetime must be positive.",
+        )),
+        lifetime => Ok(lifetime),
+    }
+}
+
+struct Anchor {
+    domain: DomainSuffix,
+    // In seconds since the epoch.
+    expires: u64,
+    // How many questions it has let through unvalidated.
+    uses: u64,
+}
+
+pub struct NegativeTrustAnchors {
+    anchors: Mutex<Vec<Anchor>>,
+    // Whether the root and the top-level domains may be anchored (--nta-force).
+    pub force: bool,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl NegativeTrustAnchors {
+    pub fn new(force: bool) -> NegativeTrustAnchors {
+        NegativeTrustAnchors {
+            anchors: Mutex::new(Vec::new()),
+            force,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    // Anchors `domain` for `lifetime`; anchoring it again renews it.
+    pub fn add(&self, domain: &str, lifetime: Duration) -> Result<(), String> {
+        let domain = DomainSuffix::parse(domain)?;
+        if domain.label_count() < 2 && !self.force {
+            return Err(format!(
+                "{} is {}; anchoring it needs --nta-force.",
+                domain,
+                if domain.is_root() {
+                    "the root"
+                } else {
+                    "a top-level domain"
+                }
+            ));
+        }
+        let expires = self.clock.now().seconds() + lifetime.as_secs();
+        let mut anchors = self.anchors.lock().unwrap();
+        anchors.retain(|anchor| anchor.domain != domain);
+        println!(
+            "[NTA] Not validating {} for {}s.",
+            domain,
+            lifetime.as_secs()
+        );
+        anchors.push(Anchor {
+            domain,
+            expires,
+            uses: 0,
+        });
+        Ok(())
+    }
+
+    pub fn remove(&self, domain: &str) -> Result<(), String> {
+        let domain = DomainSuffix::parse(domain)?;
+        let mut anchors = self.anchors.lock().unwrap();
+        let count = anchors.len();
+        anchors.retain(|anchor| anchor.domain != domain);
+        if anchors.len() == count {
+            return Err(format!("{} has no negative trust anchor.", domain));
+        }
+        println!("[NTA] Validating {} again: the anchor was removed.", domain);
+        Ok(())
+    }
+
+    // Drops the anchors whose lifetime is over.
+    fn expire(&self, now: u64) {
+        self.anchors.lock().unwrap().retain(|anchor| {
+            if anchor.expires > now {
+                return true;
+            }
+            println!(
+                "[NTA] Validating {} again: the anchor lapsed after {} use(s).",
+                anchor.domain, anchor.uses
+            );
+            false
+        });
+    }
+
+    // Whether `name` is at or under an anchor, i.e. is to go unvalidated; counts and
+    // logs the use of the closest such anchor.
+    pub fn covers(&self, name: &LabelSequence) -> bool {
+        self.expire(self.clock.now().seconds());
+        let mut anchors = self.anchors.lock().unwrap();
+        let Some(anchor) = anchors
+            .iter_mut()
+            .filter(|anchor| anchor.domain.matches(name))
+            .max_by_key(|anchor| anchor.domain.label_count())
+        else {
+            return false;
+        };
+        anchor.uses += 1;
+        println!(
+            "[NTA] Not validating {}: it is under the negative trust anchor {}.",
+            name, anchor.domain
+        );
+        true
+    }
+
+    // One line per anchor, e.g. "example.net. expires in 3540s, 2 use(s)", for the
+    // control socket.
+    pub fn list(&self) -> Vec<String> {
+        let now = self.clock.now().seconds();
+        self.expire(now);
+        self.anchors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|anchor| {
+                format!(
+                    "{} expires in {}s, {} use(s)",
+                    anchor.domain,
+                    anchor.expires - now,
+                    anchor.uses
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::super::dns::message::{Header, Question, RecordClass, RecordType};
+    use super::super::testing::{forwarder, mock_upstream, ManualClock};
+    use super::super::{ControlSocket, Lookup, PinStore, Resolve};
+    use super::*;
+
+    fn anchors_at(seconds: u64, force: bool) -> (NegativeTrustAnchors, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::at(seconds));
+        let mut anchors = NegativeTrustAnchors::new(force);
+        anchors.clock = Arc::clone(&clock) as Arc<dyn Clock>;
+        (anchors, clock)
+    }
+
+    fn name(name: &str) -> LabelSequence {
+        name.parse().unwrap()
+    }
+
+    #[test]
+    fn anchors_cover_their_subtree_until_they_lapse() {
+        let (anchors, clock) = anchors_at(1_000, false);
+        anchors
+            .add("Broken.Example", Duration::from_secs(60))
+            .unwrap();
+        assert!(anchors.covers(&name("broken.example")));
+        assert!(anchors.covers(&name("www.BROKEN.example")));
+        assert!(!anchors.covers(&name("notbroken.example")));
+        assert!(!anchors.covers(&name("example")));
+        clock.advance(30);
+        assert_eq!(anchors.list(), ["broken.example. expires in 30s, 2 use(s)"]);
+
+        // Anchoring again renews it.
+        anchors
+            .add("broken.example", Duration::from_secs(60))
+            .unwrap();
+        clock.advance(59);
+        assert!(anchors.covers(&name("broken.example")));
+        clock.advance(1);
+        assert!(!anchors.covers(&name("broken.example")));
+        assert!(anchors.list().is_empty());
+
+        anchors.add("broken.example", DEFAULT_NTA_LIFETIME).unwrap();
+        anchors.remove("broken.example.").unwrap();
+        assert!(!anchors.covers(&name("broken.example")));
+        assert!(anchors.remove("broken.example").is_err());
+    }
+
+    #[test]
+    fn the_root_and_top_level_domains_need_forcing() {
+        let (anchors, _) = anchors_at(1_000, false);
+        for domain in [".", "com", "org."] {
+            let err = anchors.add(domain, DEFAULT_NTA_LIFETIME).unwrap_err();
+            assert!(err.contains("needs --nta-force"), "{domain}: {err}");
+        }
+        let (anchors, _) = anchors_at(1_000, true);
+        anchors.add("com", DEFAULT_NTA_LIFETIME).unwrap();
+        assert!(anchors.covers(&name("example.com")));
+
+        assert_eq!(
+            NtaSpec::parse("example.net=30m").unwrap().lifetime,
+            Duration::from_secs(1800)
+        );
+        assert_eq!(
+            NtaSpec::parse("example.net").unwrap().lifetime,
+            DEFAULT_NTA_LIFETIME
+        );
+        assert!(NtaSpec::parse("example.net=0").is_err());
+        assert!(NtaSpec::parse("example..net").is_err());
+    }
+
+    #[test]
+    fn broken_zones_resolve_only_while_anchored() {
+        // The upstream validates, and fails bogus.example unless asked not to check.
+        let upstream = mock_upstream(&[[192, 0, 2, 1]], 60, Duration::ZERO);
+        let (anchors, clock) = anchors_at(1_000, false);
+        let anchors = Arc::new(anchors);
+        let mut resolver = forwarder(upstream.local_addr().unwrap(), 10.0);
+        resolver.negative_trust_anchors = Some(Arc::clone(&anchors));
+        let path = std::env::temp_dir().join(format!("control-{}-nta.sock", std::process::id()));
+        let mut control = ControlSocket::bind(path, Arc::new(PinStore::default())).unwrap();
+        control.ntas = Some(Arc::clone(&anchors));
+
+        let question = Question::new(
+            &Arc::new(name("bogus.example")),
+            RecordType::A,
+            RecordClass::In,
+        );
+        let lookup = || resolver.lookup(&Header::default(), &question).lookup;
+        assert!(matches!(lookup(), Lookup::Failed));
+
+        assert_eq!(control.execute("nta add bogus.example 5m"), "ok\n");
+        assert!(matches!(lookup(), Lookup::Found(answers) if answers.len() == 1));
+        assert_eq!(
+            control.execute("ntas"),
+            "bogus.example. expires in 300s, 1 use(s)\n"
+        );
+
+        clock.advance(300);
+        assert!(matches!(lookup(), Lookup::Failed));
+        assert_eq!(control.execute("ntas"), "");
+
+        assert!(control.execute("nta add example").starts_with("error: "));
+        assert!(control
+            .execute("nta remove bogus.example")
+            .starts_with("error: "));
+    }
+}
@@ -0,0 +1,201 @@
+// How busy the server is, judged by the backlog: the number of datagrams that were
+// waiting to be served in one iteration of the server loop.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Load {
+    Normal,
+    // Queries that need the network get an immediate SERVFAIL; local answers are served.
+    Overloaded,
+    // Queries that need the network are dropped; local answers are still served.
+    Saturated,
+}
+
+// Backlog marks for shedding load. The server becomes overloaded at `high_water`,
+// saturated at twice that, and only returns to normal once the backlog falls to
+// `low_water`, so that it doesn't flap around a single threshold.
+pub struct OverloadPolicy {
+    pub high_water: usize,
+    pub low_water: usize,
+}
+
+impl OverloadPolicy {
+    pub fn new(high_water: usize, low_water: usize) -> Result<OverloadPolicy, String> {
+        if low_water >= high_water {
+            return Err(format!(
+                "The low-water mark ({}) must be below the high-water mark ({}).",
+                low_water, high_water
+            ));
+        }
+        Ok(OverloadPolicy {
+            high_water,
+            low_water,
+        })
+    }
+
+    // The load after an iteration with `backlog` datagrams, given the load before it.
+    pub fn next(&self, current: Load, backlog: usize) -> Load {
+        if backlog >= 2 * self.high_water {
+            Load::Saturated
+        } else if backlog >= self.high_water {
+            Load::Overloaded
+        } else if backlog <= self.low_water || current == Load::Normal {
+            Load::Normal
+        } else {
+            Load::Overloaded
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::UdpSocket,
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::super::dns::message::{Answer, Header, Message, Question, RecordClass, RecordType};
+    use super::super::edns::request_option_codes;
+    use super::super::testing::{query, query_handler, Counter};
+    use super::super::{
+        DnsServer, Listener, ListenerSpec, Lookup, LoopState, Resolve, Resolved, StaticDnsResolver,
+        StaticRecords,
+    };
+    use super::*;
+
+    const UPSTREAM_DELAY: Duration = Duration::from_millis(50);
+
+    // Stands in for a slow upstream and counts the questions it was asked.
+    struct SlowUpstream {
+        asked: Counter,
+    }
+
+    impl Resolve for SlowUpstream {
+        fn lookup(&self, _header: &Header, _question: &Question) -> Resolved {
+            self.asked.increment();
+            thread::sleep(UPSTREAM_DELAY);
+            Lookup::NotFound.into()
+        }
+
+        fn provenance(&self, _question: &Question) -> String {
+            String::from("source=upstream")
+        }
+
+        fn answers_locally(&self, _question: &Question) -> bool {
+            false
+        }
+    }
+
+    fn server(asked: &Counter) -> DnsServer {
+        let mut records = StaticRecords::new();
+        records.add(Answer::new(
+            /* name= */ &Arc::new("printer.lan".parse().unwrap()),
+            /* type= */ RecordType::A,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */ &Arc::from([192, 0, 2, 7]),
+        ));
+        let spec = ListenerSpec::parse("test=127.0.0.1:0").unwrap();
+        DnsServer {
+            overload: OverloadPolicy::new(8, 2).unwrap(),
+            verify_encoding: true,
+            ..DnsServer::new(
+                vec![Listener::bind(&spec).unwrap()],
+                vec![Arc::new(query_handler(StaticDnsResolver {
+                    records: Arc::new(records),
+                    next: Box::new(SlowUpstream {
+                        asked: asked.clone(),
+                    }),
+                }))],
+            )
+        }
+    }
+
+    // Sends the queries, runs one loop iteration and returns the responses received.
+    fn exchange(server: &DnsServer, state: &mut LoopState, names: &[&str]) -> Vec<Vec<u8>> {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        for name in names {
+            client.send_to(&query(name, Some(&[])), address).unwrap();
+        }
+        server.run_once(state).unwrap();
+
+        client.set_nonblocking(true).unwrap();
+        let mut responses: Vec<Vec<u8>> = Vec::new();
+        let mut buf = [0; 512];
+        while let Ok(size) = client.recv(&mut buf) {
+            responses.push(buf[..size].to_vec());
+        }
+        responses
+    }
+
+    fn rcode(response: &[u8]) -> u8 {
+        response[3] & 0x0F
+    }
+
+    fn name(response: &[u8]) -> String {
+        Message::parse_from(response).unwrap().get_questions()[0]
+            .get_name()
+            .to_string()
+    }
+
+    #[test]
+    fn load_changes_with_hysteresis() {
+        let policy = OverloadPolicy::new(8, 2).unwrap();
+        assert_eq!(policy.next(Load::Normal, 7), Load::Normal);
+        assert_eq!(policy.next(Load::Normal, 8), Load::Overloaded);
+        assert_eq!(policy.next(Load::Normal, 16), Load::Saturated);
+        assert_eq!(policy.next(Load::Overloaded, 5), Load::Overloaded);
+        assert_eq!(policy.next(Load::Saturated, 5), Load::Overloaded);
+        assert_eq!(policy.next(Load::Overloaded, 2), Load::Normal);
+        assert!(OverloadPolicy::new(8, 8).is_err());
+    }
+
+    #[test]
+    fn overload_sheds_upstream_queries_but_serves_local_answers() {
+        let asked = Counter::default();
+        let server = server(&asked);
+        let mut state = LoopState::new(&server.randomness);
+
+        // Overloaded: local answers are served, the rest get a fast SERVFAIL with EDE.
+        let mut names: Vec<&str> = vec!["printer.lan"; 5];
+        names.extend(["example.com"; 5]);
+        let started = Instant::now();
+        let responses = exchange(&server, &mut state, &names);
+        assert!(started.elapsed() < UPSTREAM_DELAY);
+        assert_eq!(state.load, Load::Overloaded);
+        assert_eq!(asked.get(), 0);
+        assert_eq!(responses.len(), 10);
+        for response in &responses {
+            if name(response) == "printer.lan" {
+                assert_eq!(rcode(response), 0);
+            } else {
+                assert_eq!(rcode(response), 2);
+                assert_eq!(request_option_codes(response), Some(vec![15]));
+            }
+        }
+        assert_eq!(server.stats.shed_with_servfail.get(), 5);
+
+        // Saturated: upstream queries are dropped, local answers still served.
+        let mut names: Vec<&str> = vec!["printer.lan"; 4];
+        names.extend(["example.com"; 12]);
+        let responses = exchange(&server, &mut state, &names);
+        assert_eq!(state.load, Load::Saturated);
+        assert_eq!(responses.len(), 4);
+        assert!(responses.iter().all(|response| rcode(response) == 0));
+        assert_eq!(server.stats.shed_by_dropping.get(), 12);
+        assert_eq!(asked.get(), 0);
+
+        // Still overloaded above the low-water mark...
+        exchange(&server, &mut state, &["example.com"; 3]);
+        assert_eq!(state.load, Load::Overloaded);
+        assert_eq!(asked.get(), 0);
+
+        // ...and back to normal once the load subsides.
+        let responses = exchange(&server, &mut state, &["example.com"]);
+        assert_eq!(state.load, Load::Normal);
+        assert_eq!(asked.get(), 1);
+        // Answered, as NXDOMAIN: the upstream doesn't know the name.
+        assert_eq!(rcode(&responses[0]), 3);
+    }
+}
@@ -0,0 +1,282 @@
+// Keeps scheduled and bulk upstream queries from going out in synchronized bursts:
+// recurring tasks run at jittered intervals, and bulk work (warm-up, prefetch) is
+// queued and sent at a bounded rate. Queries forwarded for clients are never paced.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use super::random::Rng;
+use super::stats::ShardedCounter;
+
+// `interval` randomized by ±`jitter` (a fraction of it).
+pub fn jittered(interval: Duration, jitter: f64, rng: &dyn Rng) -> Duration {
+    interval.mul_f64(1.0 + jitter * (2.0 * rng.fraction() - 1.0))
+}
+
+// A recurring task for the maintenance scheduler, due every `interval` ± `jitter`,
+// so that tasks started together (e.g. after a restart) drift apart.
+pub struct JitteredInterval {
+    pub interval: Duration,
+    pub jitter: f64,
+    pub next_due: Instant,
+    rng: Arc<dyn Rng>,
+}
+
+impl JitteredInterval {
+    pub fn new(
+        interval: Duration,
+        jitter: f64,
+        now: Instant,
+        rng: Arc<dyn Rng>,
+    ) -> JitteredInterval {
+        JitteredInterval {
+            interval,
+            jitter,
+            next_due: now + jittered(interval, jitter, rng.as_ref()),
+            rng,
+        }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_due
+    }
+
+    pub fn reschedule(&mut self, now: Instant) {
+        self.next_due = now + jittered(self.interval, self.jitter, self.rng.as_ref());
+    }
+}
+
+// Allows `rate` operations per second on average, in bursts of at most `burst`.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    // When the next token will be available.
+    pub fn next_token_at(&self, now: Instant) -> Instant {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        let missing = 1.0 - (self.tokens + elapsed * self.rate);
+        if missing <= 0.0 {
+            now
+        } else {
+            now + Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+}
+
+// Upstream queries sent right away versus through a pacer, and the paced ones
+// still waiting.
+#[derive(Default)]
+pub struct PacingStats {
+    pub immediate: ShardedCounter,
+    pub paced: ShardedCounter,
+    pub backlog: AtomicUsize,
+}
+
+impl PacingStats {
+    pub fn set_backlog(&self, backlog: usize) {
+        self.backlog.store(backlog, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for PacingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "upstream queries: {} immediate, {} paced, {} waiting",
+            self.immediate.get(),
+            self.paced.get(),
+            self.backlog.load(Ordering::Relaxed)
+        )
+    }
+}
+
+// Bulk work waiting for its turn under a shared token bucket.
+pub struct Pacer<T> {
+    bucket: TokenBucket,
+    backlog: VecDeque<T>,
+}
+
+impl<T> Pacer<T> {
+    pub fn new(max_per_second: f64, now: Instant) -> Pacer<T> {
+        Pacer {
+            // No bursts: bulk work is spread evenly.
+            bucket: TokenBucket::new(max_per_second, 1.0, now),
+            backlog: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, task: T) {
+        self.backlog.push_back(task);
+    }
+
+    pub fn backlog(&self) -> usize {
+        self.backlog.len()
+    }
+
+    // The next task, if there is one and the rate allows it now.
+    pub fn next_ready(&mut self, now: Instant) -> Option<T> {
+        if self.backlog.is_empty() || !self.bucket.try_acquire(now) {
+            return None;
+        }
+        self.backlog.pop_front()
+    }
+
+    // When the next waiting task may go, if any is waiting.
+    pub fn next_due(&self, now: Instant) -> Option<Instant> {
+        if self.backlog.is_empty() {
+            return None;
+        }
+        Some(self.bucket.next_token_at(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::UdpSocket,
+        sync::{mpsc, Arc},
+        thread,
+    };
+
+    use super::super::dns::message::{Header, Message, Question, RecordClass, RecordType};
+    use super::super::random::Randomness;
+    use super::super::testing::forwarder;
+    use super::super::Resolve;
+    use super::*;
+
+    // Answers every query with an empty NOERROR, and reports when each arrived and
+    // for which name.
+    fn mock_upstream() -> (UdpSocket, mpsc::Receiver<(Instant, String)>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.try_clone().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = server.recv_from(&mut buf) {
+                let request = Message::parse_from(&buf[..size]).unwrap();
+                let name = request.get_questions()[0].get_name().to_string();
+                if sender.send((Instant::now(), name)).is_err() {
+                    return;
+                }
+                let mut response = buf[..size].to_vec();
+                response[2] |= 0x80;
+                let _ = server.send_to(&response, source);
+            }
+        });
+        (socket, receiver)
+    }
+
+    #[test]
+    fn jittered_intervals_stay_within_bounds_and_spread() {
+        let interval = Duration::from_secs(10);
+        let rng = Randomness::from_clock().stream("test");
+        let samples: Vec<Duration> = (0..200)
+            .map(|_| jittered(interval, 0.15, rng.as_ref()))
+            .collect();
+        assert!(samples
+            .iter()
+            .all(|&sample| sample >= Duration::from_millis(8500)
+                && sample <= Duration::from_millis(11500)));
+        let distinct: std::collections::HashSet<Duration> = samples.into_iter().collect();
+        assert!(distinct.len() > 100);
+    }
+
+    #[test]
+    fn bulk_queries_respect_the_rate_and_clients_skip_the_queue() {
+        const RATE: f64 = 100.0;
+        let (upstream, arrivals) = mock_upstream();
+        let resolver = forwarder(upstream.local_addr().unwrap(), RATE);
+        for index in 0..100 {
+            resolver.warm_up(&format!("bulk{}.example", index)).unwrap();
+        }
+        assert_eq!(resolver.pacing.backlog.load(Ordering::Relaxed), 100);
+
+        let started = Instant::now();
+        let mut interactive_latency: Option<Duration> = None;
+        while let Some(due) = resolver.next_due() {
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+            resolver.maintain();
+            let backlog = resolver.pacing.backlog.load(Ordering::Relaxed);
+            if interactive_latency.is_none() && backlog <= 50 {
+                let question = Question::new(
+                    &Arc::new("client.example".parse().unwrap()),
+                    RecordType::A,
+                    RecordClass::In,
+                );
+                let asked = Instant::now();
+                resolver.lookup(&Header::default(), &question);
+                interactive_latency = Some(asked.elapsed());
+                assert!(resolver.pacing.backlog.load(Ordering::Relaxed) > 0);
+            }
+        }
+        let elapsed = started.elapsed();
+
+        let received: Vec<(Instant, String)> = arrivals.try_iter().collect();
+        assert_eq!(received.len(), 101);
+        let bulk: Vec<Instant> = received
+            .iter()
+            .filter(|(_, name)| name.starts_with("bulk"))
+            .map(|(at, _)| *at)
+            .collect();
+        // The first goes right away, the other 99 one token apart at most.
+        let span = *bulk.last().unwrap() - bulk[0];
+        assert!(
+            span >= Duration::from_secs_f64(99.0 / RATE * 0.95),
+            "{:?}",
+            span
+        );
+        assert!(elapsed >= span);
+        // No window of a tenth of a second holds more than its share plus one.
+        for (index, &at) in bulk.iter().enumerate() {
+            let window = bulk[index..]
+                .iter()
+                .take_while(|&&later| later - at < Duration::from_millis(100))
+                .count();
+            assert!(window <= (RATE / 10.0) as usize + 1, "{} in 100ms", window);
+        }
+
+        assert!(interactive_latency.unwrap() < Duration::from_millis(50));
+        assert_eq!(resolver.pacing.immediate.get(), 1);
+        assert_eq!(resolver.pacing.paced.get(), 100);
+        assert_eq!(resolver.pacing.backlog.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,326 @@
+// Pinned RRsets: answers an operator fixes at runtime (see control.rs), e.g. a
+// known-good address while the upstream is misbehaving. A pin never expires; it is
+// served, with its TTL as given, ahead of every other source until it is unpinned.
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use super::dns::message::{
+    Answer, Header, LabelSequence, LabelSequenceParseError, Question, RecordClass, RecordType,
+};
+use super::legacy::{self, LegacyRdata};
+use super::policy::parse_record_type;
+use super::{Lookup, Resolve, Resolved};
+
+pub struct Pin {
+    pub name: Arc<LabelSequence>,
+    pub r#type: RecordType,
+    pub ttl: u32,
+    pub records: Vec<Answer>,
+    // The type and values as they were given, for listing.
+    pub text: String,
+}
+
+#[derive(Default)]
+pub struct PinStore {
+    pins: Mutex<Vec<Pin>>,
+}
+
+// Parses the values of a pinned RRset into RDATA, for the types that can be pinned.
+pub fn parse_rdata(r#type: RecordType, values: &[&str]) -> Result<Vec<Arc<[u8]>>, String> {
+    if values.is_empty() {
+        return Err(String::from("An RRset needs at least one value."));
+    }
+    if r#type == RecordType::Cname && values.len() > 1 {
+        return Err(String::from("A name can have only one CNAME."));
+    }
+    if legacy::deprecation(r#type).is_some() {
+        return LegacyRdata::parse(r#type, values)
+            .map(|records| records.iter().map(LegacyRdata::encode).collect());
+    }
+    values
+        .iter()
+        .map(|value| -> Result<Arc<[u8]>, String> {
+            match r#type {
+                RecordType::A => value
+                    .parse::<Ipv4Addr>()
+                    .map(|address| Arc::from(address.octets()))
+                    .map_err(|_| format!("'{}' is not an IPv4 address.", value)),
+                RecordType::Aaaa => value
+                    .parse::<Ipv6Addr>()
+                    .map(|address| Arc::from(address.octets()))
+                    .map_err(|_| format!("'{}' is not an IPv6 address.", value)),
+                RecordType::Ns | RecordType::Cname | RecordType::Ptr => value
+                    .parse::<LabelSequence>()
+                    .map(|name| name.encode())
+                    .map_err(|err: LabelSequenceParseError| err.message),
+                RecordType::Txt if value.len() <= 255 => {
+                    let mut data = vec![value.len() as u8];
+                    data.extend_from_slice(value.as_bytes());
+                    Ok(data.into())
+                }
+                RecordType::Txt => Err(format!("'{}' is longer than 255 bytes.", value)),
+                _ => Err(format!("{} records can't be pinned.", r#type)),
+            }
+        })
+        .collect()
+}
+
+impl PinStore {
+    // Pins the RRset `name` `type` to `values` (addresses, names or text, by type),
+    // replacing any pin for it.
+    pub fn pin(
+        &self,
+        name: &str,
+        type_name: &str,
+        ttl: &str,
+        values: &[&str],
+    ) -> Result<(), String> {
+        let name: Arc<LabelSequence> = Arc::new(
+            name.parse()
+                .map_err(|err: LabelSequenceParseError| err.message)?,
+        );
+        let r#type = parse_record_type(type_name)?;
+        let ttl: u32 = ttl
+            .parse()
+            .ok()
+            .filter(|&ttl| ttl <= i32::MAX as u32)
+            .ok_or_else(|| format!("'{}' is not a valid TTL.", ttl))?;
+        let records = parse_rdata(r#type, values)?
+            .iter()
+            .map(|data| {
+                Answer::new(
+                    /* name= */ &name,
+                    /* type= */ r#type,
+                    /* class= */ RecordClass::In,
+                    /* ttl= */ ttl,
+                    /* data= */ data,
+                )
+            })
+            .collect();
+        let mut pins = self.pins.lock().unwrap();
+        unpin_type(&mut pins, &name, r#type);
+        pins.push(Pin {
+            name,
+            r#type,
+            ttl,
+            records,
+            text: format!("{} {}", type_name.to_ascii_uppercase(), values.join(" ")),
+        });
+        Ok(())
+    }
+
+    // Removes the pin for `name` `type`; fails if there is none.
+    pub fn unpin(&self, name: &str, r#type: &str) -> Result<(), String> {
+        let name: LabelSequence = name
+            .parse()
+            .map_err(|err: LabelSequenceParseError| err.message)?;
+        let r#type = parse_record_type(r#type)?;
+        if !unpin_type(&mut self.pins.lock().unwrap(), &name, r#type) {
+            return Err(format!("{} TYPE{} isn't pinned.", name, r#type));
+        }
+        Ok(())
+    }
+
+    // The pinned records answering `question`, if it is pinned.
+    pub fn lookup(&self, question: &Question) -> Option<Vec<Answer>> {
+        self.pins
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|pin| {
+                pin.r#type == question.get_type() && pin.name.eq_ignore_case(question.get_name())
+            })
+            .map(|pin| pin.records.clone())
+    }
+
+    // Every pinned RRset, one per line.
+    pub fn list(&self) -> Vec<String> {
+        self.pins
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|pin| format!("{} {} {} ; pinned", pin.name, pin.ttl, pin.text))
+            .collect()
+    }
+}
+
+// Removes the pin for `name` `type` from `pins`; returns whether there was one.
+fn unpin_type(pins: &mut Vec<Pin>, name: &LabelSequence, r#type: RecordType) -> bool {
+    let before = pins.len();
+    pins.retain(|pin| !(pin.r#type == r#type && pin.name.eq_ignore_case(name)));
+    pins.len() != before
+}
+
+// Answers pinned questions from the pins, and passes the others on.
+pub struct PinnedResolver {
+    pub pins: Arc<PinStore>,
+    pub next: Box<dyn Resolve>,
+}
+
+impl Resolve for PinnedResolver {
+    fn lookup(&self, header: &Header, question: &Question) -> Resolved {
+        match self.pins.lookup(question) {
+            // Said here: the pin may be gone by the time the response is annotated.
+            Some(records) => Resolved {
+                provenance: Some(String::from("source=pinned")),
+                ..Lookup::Found(records).into()
+            },
+            None => self.next.lookup(header, question),
+        }
+    }
+
+    fn provenance(&self, question: &Question) -> String {
+        if self.pins.lookup(question).is_some() {
+            return String::from("source=pinned");
+        }
+        self.next.provenance(question)
+    }
+
+    fn answers_locally(&self, question: &Question) -> bool {
+        self.pins.lookup(question).is_some() || self.next.answers_locally(question)
+    }
+
+    fn prefetch(&self, header: &Header, questions: &[Question]) {
+        let unpinned: Vec<Question> = questions
+            .iter()
+            .filter(|question| self.pins.lookup(question).is_none())
+            .cloned()
+            .collect();
+        self.next.prefetch(header, &unpinned);
+    }
+
+    fn maintain(&self) {
+        self.next.maintain();
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        self.next.persist()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.next.next_due()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+        time::Duration,
+    };
+
+    use super::super::control::ControlSocket;
+    use super::super::stats::Stats;
+    use super::super::testing::{forwarder, mock_upstream};
+    use super::*;
+
+    fn addresses(resolver: &dyn Resolve, name: &str) -> Vec<Vec<u8>> {
+        let question = Question::new(
+            &Arc::new(name.parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        );
+        resolver
+            .lookup(&Header::default(), &question)
+            .into_answers()
+            .iter()
+            .map(|answer| answer.get_data().to_vec())
+            .collect()
+    }
+
+    fn control_socket(name: &str) -> ControlSocket {
+        let path =
+            std::env::temp_dir().join(format!("control-{}-{}.sock", std::process::id(), name));
+        ControlSocket::bind(path, Arc::new(PinStore::default())).unwrap()
+    }
+
+    // Sends a command over the socket as an operator would and returns the output.
+    fn send(control: &ControlSocket, command: &str) -> String {
+        let mut client = UnixStream::connect(&control.path).unwrap();
+        writeln!(client, "{}", command).unwrap();
+        control.serve(&Stats::default());
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn pins_override_the_upstream_until_unpinned() {
+        let upstream = mock_upstream(&[[192, 0, 2, 99]], 60, Duration::ZERO);
+        let control = control_socket("override");
+        let resolver = PinnedResolver {
+            pins: Arc::clone(&control.pins),
+            next: Box::new(forwarder(upstream.local_addr().unwrap(), 10.0)),
+        };
+        assert_eq!(addresses(&resolver, "example.com"), [[192, 0, 2, 99]]);
+
+        assert_eq!(
+            send(&control, "pin example.com A 300 1.2.3.4 5.6.7.8"),
+            "ok\n"
+        );
+        assert_eq!(
+            addresses(&resolver, "EXAMPLE.com"),
+            [[1, 2, 3, 4], [5, 6, 7, 8]]
+        );
+        let question = Question::new(
+            &Arc::new("example.com".parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        );
+        assert!(resolver.answers_locally(&question));
+        assert_eq!(
+            resolver
+                .lookup(&Header::default(), &question)
+                .provenance
+                .as_deref(),
+            Some("source=pinned")
+        );
+        // Other types of the name, and other names, still go upstream.
+        assert_eq!(addresses(&resolver, "www.example.com"), [[192, 0, 2, 99]]);
+
+        // Pinning again replaces the RRset.
+        send(&control, "pin example.com a 60 9.9.9.9");
+        assert_eq!(
+            send(&control, "pins"),
+            "example.com 60 A 9.9.9.9 ; pinned\n"
+        );
+
+        assert_eq!(send(&control, "unpin example.com A"), "ok\n");
+        assert_eq!(addresses(&resolver, "example.com"), [[192, 0, 2, 99]]);
+        assert_eq!(send(&control, "pins"), "");
+        assert!(send(&control, "unpin example.com A").starts_with("error: "));
+    }
+
+    #[test]
+    fn malformed_pins_are_rejected() {
+        let control = control_socket("malformed");
+        for command in [
+            "pin example.com A 300 1.2.3",
+            "pin example.com A 300",
+            "pin example.com A soon 1.2.3.4",
+            "pin example.com AAAA 300 1.2.3.4",
+            "pin example.com CNAME 300 a.example b.example",
+            "pin example.com SRV 300 0 0 53 ns.example",
+            "pin example..com A 300 1.2.3.4",
+            "pinned example.com",
+        ] {
+            let output = control.execute(command);
+            assert!(output.starts_with("error: "), "{}: {}", command, output);
+        }
+        assert_eq!(control.execute("pins"), "");
+        assert_eq!(
+            control.execute("pin example.com AAAA 300 2001:db8::1"),
+            "ok\n"
+        );
+        assert_eq!(control.execute("pin example.com TXT 300 hello"), "ok\n");
+        assert_eq!(control.pins.list().len(), 2);
+        // The counters come from the server, over the socket.
+        assert!(send(&control, "stats").starts_with("queries: 0, responses: 0, "));
+    }
+}
@@ -0,0 +1,201 @@
+use std::{fmt, net::IpAddr};
+
+use super::dns::message::{LabelSequence, Question, RecordClass, RecordType, RecordTypeParseError};
+use super::edns::{EDE_CENSORED, EDE_FILTERED, EDE_FORGED_ANSWER, EDE_NOT_READY, EDE_OTHER};
+
+// Parses a query type given either as a mnemonic ("AAAA") or in the generic
+// RFC 3597 form ("TYPE28"). Matching is case-insensitive.
+pub fn parse_record_type(value: &str) -> Result<RecordType, String> {
+    value
+        .parse()
+        .map_err(|err: RecordTypeParseError| err.message)
+}
+
+// A domain name kept as lowercase labels, used for label-aligned suffix matching.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DomainSuffix {
+    labels: Vec<String>,
+}
+
+impl DomainSuffix {
+    pub fn parse(value: &str) -> Result<DomainSuffix, String> {
+        let trimmed = value.trim().trim_end_matches('.');
+        if trimmed.is_empty() {
+            return Ok(DomainSuffix { labels: Vec::new() });
+        }
+        let labels: Vec<String> = trimmed.split('.').map(str::to_ascii_lowercase).collect();
+        if labels
+            .iter()
+            .any(|label| label.is_empty() || label.len() > 63)
+        {
+            return Err(format!("'{}' is not a valid domain name.", value));
+        }
+        Ok(DomainSuffix { labels })
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
+    }
+
+    // True when `name` equals this suffix or is a subdomain of it.
+    pub fn matches(&self, name: &LabelSequence) -> bool {
+        let name_labels = name.get_labels();
+        if name_labels.len() < self.labels.len() {
+            return false;
+        }
+        name_labels
+            .iter()
+            .rev()
+            .zip(self.labels.iter().rev())
+            .all(|(label, suffix_label)| label.get_content().eq_ignore_ascii_case(suffix_label))
+    }
+}
+
+impl fmt::Display for DomainSuffix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.", self.labels.join("."))
+    }
+}
+
+// What a policy did to an answer, for clients to be told with an Extended DNS Error
+// (RFC 8914): the info-code, and the rule that applied, for the EXTRA-TEXT.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolicyOutcome {
+    pub info_code: u16,
+    pub rule: String,
+}
+
+impl PolicyOutcome {
+    // The answer was blocked, e.g. by a policy zone.
+    pub fn filtered(rule: String) -> PolicyOutcome {
+        PolicyOutcome {
+            info_code: EDE_FILTERED,
+            rule,
+        }
+    }
+
+    // The answer was blocked because an outside authority requires it.
+    pub fn censored(rule: String) -> PolicyOutcome {
+        PolicyOutcome {
+            info_code: EDE_CENSORED,
+            rule,
+        }
+    }
+
+    // The answer was made up, e.g. a policy zone's local data or a redirect.
+    pub fn forged(rule: String) -> PolicyOutcome {
+        PolicyOutcome {
+            info_code: EDE_FORGED_ANSWER,
+            rule,
+        }
+    }
+
+    // The query was failed without asking the upstream, e.g. by the circuit breaker.
+    pub fn not_ready(rule: String) -> PolicyOutcome {
+        PolicyOutcome {
+            info_code: EDE_NOT_READY,
+            rule,
+        }
+    }
+
+    // The query was given up on, e.g. for the work it took (see budget.rs).
+    pub fn other(rule: String) -> PolicyOutcome {
+        PolicyOutcome {
+            info_code: EDE_OTHER,
+            rule,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PolicyVerdict {
+    Allow,
+    RefuseClass,
+    RefuseName,
+    RefuseType,
+}
+
+// A CIDR block of client addresses, e.g. 10.0.0.0/8.
+#[derive(Clone, Debug)]
+pub struct Subnet {
+    network: IpAddr,
+    prefix_length: u8,
+}
+
+impl Subnet {
+    pub fn parse(value: &str) -> Result<Subnet, String> {
+        let (address, prefix_length) = value
+            .split_once('/')
+            .ok_or_else(|| format!("'{}' is not a CIDR block like 10.0.0.0/8.", value))?;
+        let network: IpAddr = address
+            .parse()
+            .map_err(|_| format!("'{}' is not an IP address.", address))?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_length: u8 = prefix_length
+            .parse()
+            .ok()
+            .filter(|&length| length <= bits)
+            .ok_or_else(|| format!("'{}' is not a valid prefix length.", prefix_length))?;
+        Ok(Subnet {
+            network,
+            prefix_length,
+        })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let (network, address, bits) = match (self.network, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                (u32::from(network) as u128, u32::from(address) as u128, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                (u128::from(network), u128::from(address), 128)
+            }
+            _ => return false,
+        };
+        let shift = bits - self.prefix_length as u32;
+        shift == bits || network >> shift == address >> shift
+    }
+}
+
+// Allow-lists restricting which names and query types the server will serve.
+// Empty lists leave the corresponding dimension unrestricted.
+#[derive(Default)]
+pub struct QueryPolicy {
+    only_names: Vec<DomainSuffix>,
+    only_types: Vec<RecordType>,
+}
+
+impl QueryPolicy {
+    pub fn new(only_names: &[DomainSuffix], only_types: &[RecordType]) -> QueryPolicy {
+        QueryPolicy {
+            only_names: only_names.to_vec(),
+            only_types: only_types.to_vec(),
+        }
+    }
+
+    pub fn check(&self, questions: &[Question]) -> PolicyVerdict {
+        for question in questions {
+            // We only serve the Internet class; CHAOS and the rest would need a
+            // handler of their own.
+            if question.get_class() != RecordClass::In {
+                return PolicyVerdict::RefuseClass;
+            }
+            if !self.only_names.is_empty()
+                && !self
+                    .only_names
+                    .iter()
+                    .any(|suffix| suffix.matches(question.get_name()))
+            {
+                return PolicyVerdict::RefuseName;
+            }
+            if !self.only_types.is_empty() && !self.only_types.contains(&question.get_type()) {
+                return PolicyVerdict::RefuseType;
+            }
+        }
+        PolicyVerdict::Allow
+    }
+}
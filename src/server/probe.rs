@@ -0,0 +1,447 @@
+// Probing an upstream before trusting it (the probe subcommand). A battery of
+// queries, sent with the forwarder's own Query type, finds out what the upstream
+// supports: EDNS, and the largest query it still gets over UDP (a binary search with
+// padded queries, for paths that drop fragments), TCP, DO being honoured, cookies,
+// 0x20 casing, how it answers for a name that doesn't exist and for ANY, and how fast
+// it answers. Each check is a single query, with --timeout to answer it; a lost one
+// reads as no support. The round-trip times go through an UpstreamStateStore, as the
+// forwarder's do, and the report ends with the settings suggested for the upstream.
+
+use std::{
+    fmt,
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::dns::message::{
+    LabelSequence, LabelSequenceParseError, Message, RCode, RecordType, PADDING_OPTION,
+};
+use super::edns::UDP_PAYLOAD_SIZE;
+use super::query::{Query, ResponseMismatch, COOKIE_OPTION};
+use super::random::{Randomness, Rng};
+use super::split_brain::exchange_over_tcp;
+use super::upstream_state::UpstreamStateStore;
+use super::MAX_STRAY_RESPONSES;
+
+// The range the UDP size search covers: what every upstream must take (RFC 1035,
+// 4.2.1), to the most any sensible path carries unfragmented or not.
+const MIN_PROBED_SIZE: usize = 512;
+const MAX_PROBED_SIZE: usize = 4096;
+
+// The HINFO type, which RFC 8482 (4.2) answers ANY with.
+const HINFO: RecordType = RecordType::Unknown(13);
+
+// How the upstream answers for a name that doesn't exist.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Nonexistent {
+    NxDomain,
+    NoData,
+    // With records, as resolvers rewriting NXDOMAIN to an ad server do.
+    Answered,
+    Other(RCode),
+    Unanswered,
+}
+
+// How the upstream answers ANY.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnyPolicy {
+    // A single HINFO (RFC 8482, 4.2).
+    Minimal,
+    // With this many records.
+    Full(usize),
+    Empty,
+    Refused(RCode),
+    Unanswered,
+}
+
+#[derive(Debug)]
+pub struct ProbeReport {
+    pub upstream: SocketAddr,
+    pub edns: bool,
+    // The largest query answered over UDP, in bytes, or None if not even the smallest.
+    pub max_udp_size: Option<usize>,
+    pub tcp: bool,
+    pub dnssec: bool,
+    pub cookies: bool,
+    pub preserves_case: bool,
+    pub nonexistent: Nonexistent,
+    pub any: AnyPolicy,
+    // Of the timed queries answered, fastest first.
+    pub rtts: Vec<Duration>,
+    pub lost: usize,
+    pub srtt: Option<Duration>,
+}
+
+impl ProbeReport {
+    pub fn reachable(&self) -> bool {
+        !self.rtts.is_empty()
+    }
+
+    // The RTT `percent`% of the answered timed queries took at most.
+    fn percentile(&self, percent: usize) -> Option<Duration> {
+        let last = self.rtts.len().checked_sub(1)?;
+        Some(self.rtts[last * percent / 100])
+    }
+
+    // The UDP payload size to advertise to the upstream: ours, unless the upstream
+    // was seen to lose smaller queries.
+    pub fn suggested_udp_size(&self) -> Option<u16> {
+        self.edns.then(|| {
+            self.max_udp_size
+                .map_or(MIN_PROBED_SIZE as u16, |size| {
+                    (size as u16).min(UDP_PAYLOAD_SIZE)
+                })
+                .max(MIN_PROBED_SIZE as u16)
+        })
+    }
+
+    // How long to wait for an answer: four times the SRTT, but never less than the
+    // slowest answer seen.
+    pub fn suggested_timeout(&self) -> Option<Duration> {
+        Some((self.srtt? * 4).max(*self.rtts.last()?))
+    }
+
+    // The upstream with the settings suggested for it, as `--resolver` takes it, with
+    // the tuned options after it.
+    pub fn suggestion(&self) -> String {
+        let mut options = Vec::new();
+        match self.suggested_udp_size() {
+            Some(size) => options.push(format!("edns={}", size)),
+            None => options.push(String::from("edns=off")),
+        }
+        if let Some(timeout) = self.suggested_timeout() {
+            options.push(format!("timeout={}ms", timeout.as_millis().max(1)));
+        }
+        let flag = |name: &str, on: bool| format!("{}={}", name, if on { "on" } else { "off" });
+        options.push(flag("tcp", self.tcp));
+        options.push(flag("dnssec", self.dnssec));
+        options.push(flag("cookies", self.cookies));
+        options.push(flag("0x20", self.preserves_case));
+        format!("--resolver {}  # {}", self.upstream, options.join(" "))
+    }
+}
+
+impl fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |yes: bool| if yes { "yes" } else { "no" };
+        writeln!(f, "Probe of {}:", self.upstream)?;
+        writeln!(f, "  EDNS: {}", yes_no(self.edns))?;
+        match self.max_udp_size {
+            Some(size) => writeln!(f, "  Largest query answered over UDP: {} bytes", size)?,
+            None if self.edns => writeln!(f, "  Largest query answered over UDP: none")?,
+            None => {}
+        }
+        writeln!(f, "  TCP: {}", yes_no(self.tcp))?;
+        writeln!(f, "  DNSSEC (DO honoured): {}", yes_no(self.dnssec))?;
+        writeln!(f, "  Cookies: {}", yes_no(self.cookies))?;
+        writeln!(
+            f,
+            "  Case preserved (0x20): {}",
+            yes_no(self.preserves_case)
+        )?;
+        writeln!(f, "  Nonexistent names: {:?}", self.nonexistent)?;
+        writeln!(f, "  ANY: {:?}", self.any)?;
+        match (self.percentile(0), self.percentile(50), self.percentile(90)) {
+            (Some(min), Some(median), Some(p90)) => writeln!(
+                f,
+                "  Response times: min {:?}, median {:?}, p90 {:?}, max {:?} ({} lost)",
+                min,
+                median,
+                p90,
+                self.rtts.last().unwrap(),
+                self.lost
+            )?,
+            _ => writeln!(f, "  Response times: none answered ({} lost)", self.lost)?,
+        }
+        write!(f, "Suggested: {}", self.suggestion())
+    }
+}
+
+// A response to a probe query, and how it matches the query.
+struct Exchange {
+    response: Message,
+    rtt: Duration,
+    matched: Result<RCode, ResponseMismatch>,
+}
+
+pub struct Probe {
+    upstream: SocketAddr,
+    // A name the upstream can answer for; nonexistent ones are made up under it.
+    name: Arc<LabelSequence>,
+    // How long each query may take.
+    pub timeout: Duration,
+    // How many queries are timed.
+    pub samples: usize,
+    rng: Arc<dyn Rng>,
+}
+
+impl Probe {
+    pub fn new(upstream: SocketAddr, name: &str, randomness: &Randomness) -> Result<Probe, String> {
+        Ok(Probe {
+            upstream,
+            name: Arc::new(
+                name.parse()
+                    .map_err(|err: LabelSequenceParseError| err.message)?,
+            ),
+            timeout: Duration::from_secs(2),
+            samples: 20,
+            rng: randomness.stream("probe"),
+        })
+    }
+
+    fn query(&self, r#type: RecordType) -> Query {
+        let mut query = Query::new(&self.name, r#type);
+        query.set_rd(true);
+        query
+    }
+
+    // Sends `query` over UDP and waits for the response with its ID.
+    fn ask(&self, query: &Query) -> Option<Exchange> {
+        let local = match self.upstream {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(local).ok()?;
+        socket.connect(self.upstream).ok()?;
+        socket.set_read_timeout(Some(self.timeout)).ok()?;
+        let id = self.rng.next_u64() as u16;
+        let sent_at = Instant::now();
+        socket.send(&query.encode(id)).ok()?;
+        let mut buf = [0; 65535];
+        for _ in 0..=MAX_STRAY_RESPONSES {
+            let size = socket.recv(&mut buf).ok()?;
+            let Ok(response) = Message::parse_from(&buf[..size]) else {
+                continue;
+            };
+            let matched = query.matches_response(id, &response);
+            if matches!(
+                matched,
+                Err(ResponseMismatch::Id { .. } | ResponseMismatch::NotAResponse)
+            ) {
+                continue;
+            }
+            return Some(Exchange {
+                response,
+                rtt: sent_at.elapsed(),
+                matched,
+            });
+        }
+        None
+    }
+
+    fn edns(&self) -> bool {
+        let mut query = self.query(RecordType::A);
+        query.set_edns_size(UDP_PAYLOAD_SIZE);
+        self.ask(&query).is_some_and(|exchange| {
+            exchange.response.get_opt().is_some()
+                && exchange
+                    .matched
+                    .is_ok_and(|rcode| rcode != RCode::FormatError)
+        })
+    }
+
+    // Whether a query padded to `size` bytes is answered over UDP.
+    fn answers_query_of(&self, size: usize) -> bool {
+        let mut query = self.query(RecordType::A);
+        query.set_edns_size(MAX_PROBED_SIZE as u16);
+        let unpadded = {
+            let mut query = query.clone();
+            query.add_option(PADDING_OPTION, &[]);
+            query.encode(0).len()
+        };
+        query.add_option(PADDING_OPTION, &vec![0; size.saturating_sub(unpadded)]);
+        self.ask(&query).is_some()
+    }
+
+    fn max_udp_size(&self) -> Option<usize> {
+        if self.answers_query_of(MAX_PROBED_SIZE) {
+            return Some(MAX_PROBED_SIZE);
+        }
+        if !self.answers_query_of(MIN_PROBED_SIZE) {
+            return None;
+        }
+        let (mut answered, mut lost) = (MIN_PROBED_SIZE, MAX_PROBED_SIZE);
+        while lost - answered > 1 {
+            let size = (answered + lost) / 2;
+            match self.answers_query_of(size) {
+                true => answered = size,
+                false => lost = size,
+            }
+        }
+        Some(answered)
+    }
+
+    fn tcp(&self) -> bool {
+        let query = self.query(RecordType::A);
+        let id = self.rng.next_u64() as u16;
+        exchange_over_tcp(self.upstream, &query.encode(id), self.timeout)
+            .is_ok_and(|response| query.matches_response(id, &response).is_ok())
+    }
+
+    // Whether DO is copied into the response (RFC 3225, 3), as an upstream that
+    // serves DNSSEC records does.
+    fn dnssec(&self) -> bool {
+        let mut query = self.query(RecordType::A);
+        query.set_do_bit(true);
+        self.ask(&query)
+            .and_then(|exchange| exchange.response.get_opt())
+            .is_some_and(|opt| opt.get_do())
+    }
+
+    // Whether a server cookie comes back after our client cookie (RFC 7873, 5.2).
+    fn cookies(&self) -> bool {
+        let cookie = self.rng.next_u64().to_be_bytes();
+        let mut query = self.query(RecordType::A);
+        query.set_cookie(cookie);
+        let Some(opt) = self
+            .ask(&query)
+            .and_then(|exchange| exchange.response.get_opt())
+        else {
+            return false;
+        };
+        opt.get_options().iter().any(|(code, data)| {
+            *code == COOKIE_OPTION && (16..=40).contains(&data.len()) && data[..8] == cookie
+        })
+    }
+
+    fn preserves_case(&self) -> bool {
+        let mut query = self.query(RecordType::A);
+        query.set_mixed_case(self.rng.next_u64());
+        self.ask(&query)
+            .is_some_and(|exchange| exchange.matched.is_ok())
+    }
+
+    fn nonexistent(&self) -> Nonexistent {
+        let label = format!("probe-{:016x}", self.rng.next_u64());
+        let Ok(name) = format!("{}.{}", label, self.name).parse() else {
+            return Nonexistent::Unanswered;
+        };
+        let mut query = Query::new(&Arc::new(name), RecordType::A);
+        query.set_rd(true);
+        let Some(exchange) = self.ask(&query) else {
+            return Nonexistent::Unanswered;
+        };
+        match exchange.matched {
+            _ if !exchange.response.get_answers().is_empty() => Nonexistent::Answered,
+            Ok(RCode::NameError) => Nonexistent::NxDomain,
+            Ok(RCode::NoError) => Nonexistent::NoData,
+            Ok(rcode) => Nonexistent::Other(rcode),
+            Err(_) => Nonexistent::Unanswered,
+        }
+    }
+
+    fn any(&self) -> AnyPolicy {
+        let Some(exchange) = self.ask(&self.query(RecordType::Any)) else {
+            return AnyPolicy::Unanswered;
+        };
+        let answers = exchange.response.get_answers();
+        match exchange.matched {
+            Ok(RCode::NoError) if answers.is_empty() => AnyPolicy::Empty,
+            Ok(RCode::NoError) if answers.iter().all(|answer| answer.get_type() == HINFO) => {
+                AnyPolicy::Minimal
+            }
+            Ok(RCode::NoError) => AnyPolicy::Full(answers.len()),
+            Ok(rcode) => AnyPolicy::Refused(rcode),
+            Err(_) => AnyPolicy::Unanswered,
+        }
+    }
+
+    pub fn run(&self) -> ProbeReport {
+        let key = format!("udp/{}", self.upstream);
+        let mut state = UpstreamStateStore::in_memory();
+        let mut rtts = Vec::new();
+        for _ in 0..self.samples {
+            if let Some(exchange) = self.ask(&self.query(RecordType::A)) {
+                state.record_rtt(&key, exchange.rtt);
+                rtts.push(exchange.rtt);
+            }
+        }
+        rtts.sort();
+        let edns = self.edns();
+        ProbeReport {
+            upstream: self.upstream,
+            edns,
+            max_udp_size: if edns { self.max_udp_size() } else { None },
+            tcp: self.tcp(),
+            dnssec: edns && self.dnssec(),
+            cookies: edns && self.cookies(),
+            preserves_case: self.preserves_case(),
+            nonexistent: self.nonexistent(),
+            any: self.any(),
+            lost: self.samples - rtts.len(),
+            rtts,
+            srtt: state.get(&key).map(|state| state.srtt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{quirky_upstream, Quirks};
+    use super::*;
+
+    fn probe(quirks: Quirks) -> ProbeReport {
+        let mut probe = Probe::new(
+            quirky_upstream(quirks),
+            "example.com",
+            &Randomness::seeded(1),
+        )
+        .unwrap();
+        probe.timeout = Duration::from_millis(200);
+        probe.samples = 5;
+        probe.run()
+    }
+
+    #[test]
+    fn a_compliant_upstream_passes_every_check() {
+        let report = probe(Quirks::default());
+        assert!(report.edns && report.tcp && report.dnssec && report.cookies);
+        assert!(report.preserves_case);
+        assert_eq!(report.max_udp_size, Some(MAX_PROBED_SIZE));
+        assert_eq!(report.nonexistent, Nonexistent::NxDomain);
+        assert_eq!(report.any, AnyPolicy::Minimal);
+        assert_eq!((report.rtts.len(), report.lost), (5, 0));
+        assert!(report.srtt.is_some());
+        assert!(
+            report.suggestion().starts_with(&format!(
+                "--resolver {}  # edns=1232 timeout=",
+                report.upstream
+            )),
+            "{}",
+            report.suggestion()
+        );
+        assert!(report
+            .suggestion()
+            .ends_with("tcp=on dnssec=on cookies=on 0x20=on"));
+    }
+
+    #[test]
+    fn quirks_are_found_out() {
+        let report = probe(Quirks {
+            edns: false,
+            tcp: false,
+            preserves_case: false,
+            nxdomain_rewrite: Some([198, 51, 100, 1]),
+            refuses_any: true,
+            ..Quirks::default()
+        });
+        assert!(!report.edns && !report.tcp && !report.dnssec && !report.cookies);
+        assert!(!report.preserves_case);
+        assert_eq!(report.max_udp_size, None);
+        assert_eq!(report.nonexistent, Nonexistent::Answered);
+        assert_eq!(report.any, AnyPolicy::Refused(RCode::NotImplemented));
+        assert!(report.suggestion().contains("edns=off"));
+
+        let report = probe(Quirks {
+            max_udp_size: 1000,
+            do_bit: false,
+            cookies: false,
+            ..Quirks::default()
+        });
+        assert!(report.edns && report.tcp && !report.dnssec && !report.cookies);
+        assert_eq!(report.max_udp_size, Some(1000));
+        assert_eq!(report.suggested_udp_size(), Some(1000));
+        assert_eq!(report.nonexistent, Nonexistent::NxDomain);
+    }
+}
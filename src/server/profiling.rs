@@ -0,0 +1,177 @@
+// Allocation profiling, compiled in with `--features profiling`: a global allocator
+// that counts allocations and bytes, overall and per thread, so that what serving
+// one query allocates can be measured. Without the feature none of this exists.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+static LIVE_BYTES: AtomicU64 = AtomicU64::new(0);
+static PEAK_LIVE_BYTES: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // Const-initialized, so that reading it never allocates from within the allocator.
+    static THREAD_ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+struct CountingAllocator;
+
+impl CountingAllocator {
+    fn allocated(size: usize) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(size as u64, Ordering::Relaxed);
+        let live = LIVE_BYTES.fetch_add(size as u64, Ordering::Relaxed) + size as u64;
+        PEAK_LIVE_BYTES.fetch_max(live, Ordering::Relaxed);
+        // Fails only while the thread is being torn down.
+        let _ = THREAD_ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+    }
+
+    fn freed(size: usize) {
+        LIVE_BYTES.fetch_sub(size as u64, Ordering::Relaxed);
+    }
+}
+
+// SAFETY: all allocation is delegated to the system allocator unchanged.
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            CountingAllocator::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            CountingAllocator::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CountingAllocator::freed(layout.size());
+    }
+
+    // Counted as freeing the old block and allocating the new one.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            CountingAllocator::freed(layout.size());
+            CountingAllocator::allocated(new_size);
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Process-wide allocation totals since startup.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AllocationTotals {
+    pub allocations: u64,
+    pub allocated_bytes: u64,
+    pub live_bytes: u64,
+    pub peak_live_bytes: u64,
+}
+
+impl fmt::Display for AllocationTotals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "allocations: {}, allocated: {} bytes, live: {} bytes, peak: {} bytes",
+            self.allocations, self.allocated_bytes, self.live_bytes, self.peak_live_bytes
+        )
+    }
+}
+
+pub fn totals() -> AllocationTotals {
+    AllocationTotals {
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        live_bytes: LIVE_BYTES.load(Ordering::Relaxed),
+        peak_live_bytes: PEAK_LIVE_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+// Counts the allocations the current thread makes between start and finish.
+pub struct AllocationScope {
+    start: u64,
+}
+
+impl AllocationScope {
+    pub fn start() -> AllocationScope {
+        AllocationScope {
+            start: THREAD_ALLOCATIONS.with(Cell::get),
+        }
+    }
+
+    pub fn finish(self) -> u64 {
+        THREAD_ALLOCATIONS.with(Cell::get) - self.start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{hint::black_box, net::UdpSocket};
+
+    use super::super::testing::{dummy_server, query};
+    use super::super::LoopState;
+    use super::*;
+
+    #[test]
+    fn counters_see_a_known_allocation() {
+        let before = totals();
+        let scope = AllocationScope::start();
+        let buffer: Vec<u8> = black_box(Vec::with_capacity(4096));
+        assert_eq!(scope.finish(), 1);
+        let after = totals();
+        assert!(after.allocations > before.allocations);
+        assert!(after.allocated_bytes >= before.allocated_bytes + 4096);
+        assert!(after.peak_live_bytes >= 4096);
+        drop(buffer);
+
+        // Other threads' allocations don't count in this thread's scope.
+        let spawn_allocating = |count: usize| {
+            let scope = AllocationScope::start();
+            std::thread::spawn(move || {
+                for _ in 0..count {
+                    black_box(vec![0u8; 64]);
+                }
+            })
+            .join()
+            .unwrap();
+            scope.finish()
+        };
+        spawn_allocating(1);
+        assert_eq!(spawn_allocating(1), spawn_allocating(100));
+    }
+
+    #[test]
+    fn queries_are_sampled_into_the_stats() {
+        let server = dummy_server();
+        let mut state = LoopState::new(&server.randomness);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        for _ in 0..3 {
+            client
+                .send_to(&query("example.com", None), address)
+                .unwrap();
+        }
+        while server.stats.snapshot().responses_sent < 3 {
+            server.run_once(&mut state).unwrap();
+        }
+        let snapshot = server.stats.snapshot();
+        assert_eq!(snapshot.profiled_queries, 3);
+        assert!(snapshot.query_allocations >= 3);
+        assert!(snapshot.allocations.allocations >= snapshot.query_allocations);
+        assert!(snapshot.to_string().contains("allocations/query: "));
+    }
+}
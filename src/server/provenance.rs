@@ -0,0 +1,195 @@
+use std::sync::Arc;
+
+use super::dns::message::{Answer, LabelSequence, Question, RecordClass, RecordType};
+use super::edns::request_option_codes;
+
+// Owner of the annotation records; .invalid names never exist (RFC 6761, 6.4).
+const PROVENANCE_NAME: &str = "provenance.invalid";
+
+// Default EDNS option code clients send to ask for the annotation, from the range
+// reserved for local and experimental use (RFC 6891, 9).
+pub const DEFAULT_PROVENANCE_OPTION: u16 = 65001;
+
+// When responses are annotated with where their answers came from: for clients
+// that send an OPT record carrying `option_code`, or for everyone if `always`.
+pub struct ProvenancePolicy {
+    pub option_code: u16,
+    pub always: bool,
+}
+
+impl ProvenancePolicy {
+    pub fn requested(&self, request: &[u8]) -> bool {
+        self.always
+            || request_option_codes(request).is_some_and(|codes| codes.contains(&self.option_code))
+    }
+}
+
+// The annotation for one question: a TXT record such as "source=static" in the
+// additional section. Its TTL is 0, so nothing downstream caches it, and it is added
+// after resolution, so no resolver in the chain (a cache included) ever sees it.
+pub fn annotation(source: &str) -> Answer {
+    let name: LabelSequence = PROVENANCE_NAME
+        .parse()
+        .expect("the provenance name is a valid domain name");
+    let source = &source.as_bytes()[..source.len().min(255)];
+    let mut data: Vec<u8> = vec![source.len() as u8];
+    data.extend_from_slice(source);
+    Answer::new(
+        /* name= */ &Arc::new(name),
+        /* type= */ RecordType::Txt,
+        /* class= */ RecordClass::In,
+        /* ttl= */ 0,
+        /* data= */ &data.into(),
+    )
+}
+
+// Annotations for all questions of a request, in question order; `source` is given
+// each question's index as well.
+pub fn annotations(
+    questions: &[Question],
+    source: impl Fn(usize, &Question) -> String,
+) -> Arc<[Answer]> {
+    questions
+        .iter()
+        .enumerate()
+        .map(|(index, question)| annotation(&source(index, question)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::super::dns::message::{Header, RecordClass};
+    use super::super::listener::Transport;
+    use super::super::overload::Load;
+    use super::super::testing::{forwarder, query, query_handler};
+    use super::*;
+    use crate::server::{
+        DummyDnsResolver, HandleOpcode, QueryInfo, QueryOpcodeHandler, Resolve, StaticDnsResolver,
+        StaticRecords, Stats,
+    };
+
+    fn policy(always: bool) -> ProvenancePolicy {
+        ProvenancePolicy {
+            option_code: DEFAULT_PROVENANCE_OPTION,
+            always,
+        }
+    }
+
+    fn handler() -> QueryOpcodeHandler {
+        let mut records = StaticRecords::new();
+        records.add(Answer::new(
+            /* name= */ &Arc::new("printer.lan".parse().unwrap()),
+            /* type= */ RecordType::A,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */ &Arc::from([192, 0, 2, 7]),
+        ));
+        QueryOpcodeHandler {
+            provenance: policy(false),
+            ..query_handler(StaticDnsResolver {
+                records: Arc::new(records),
+                next: Box::new(DummyDnsResolver::default()),
+            })
+        }
+    }
+
+    // The provenance strings of the response to `request`, next to our OPT record.
+    fn sources(handler: &QueryOpcodeHandler, request: &[u8]) -> Vec<String> {
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        let header = Header::parse_from(request[..12].try_into().unwrap());
+        let response = handler
+            .handle(&info, &header, request, &Stats::default())
+            .unwrap();
+        assert_eq!(
+            response.get_header().get_ar_count() as usize,
+            response.get_additionals().len()
+        );
+        response
+            .get_additionals()
+            .iter()
+            .filter(|additional| additional.get_type() != RecordType::Opt)
+            .map(|additional| {
+                assert_eq!(additional.get_name().to_string(), PROVENANCE_NAME);
+                assert_eq!(additional.get_ttl(), 0);
+                String::from_utf8(additional.get_data()[1..].to_vec()).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn annotation_is_only_added_for_clients_that_opt_in() {
+        let option = DEFAULT_PROVENANCE_OPTION;
+        assert!(!policy(false).requested(&query("example.com", None)));
+        assert!(!policy(false).requested(&query("example.com", Some(&[10]))));
+        assert!(policy(false).requested(&query("example.com", Some(&[10, option]))));
+        assert!(policy(true).requested(&query("example.com", None)));
+
+        let handler = handler();
+        assert!(sources(&handler, &query("printer.lan", None)).is_empty());
+        assert!(sources(&handler, &query("printer.lan", Some(&[10]))).is_empty());
+    }
+
+    #[test]
+    fn annotation_names_the_resolver_that_answered() {
+        let handler = handler();
+        let option = DEFAULT_PROVENANCE_OPTION;
+        assert_eq!(
+            sources(&handler, &query("printer.lan", Some(&[option]))),
+            ["source=static"]
+        );
+        assert_eq!(
+            sources(&handler, &query("example.com", Some(&[option]))),
+            ["source=dummy"]
+        );
+    }
+
+    #[test]
+    fn forwarded_answers_name_the_upstream() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let resolver = forwarder(upstream.local_addr().unwrap(), 10.0);
+        let question = Question::new(
+            &Arc::new("example.com".parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        );
+        assert_eq!(
+            resolver.provenance(&question),
+            format!(
+                "source=upstream upstream={}",
+                upstream.local_addr().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn annotation_gives_way_to_the_size_budget() {
+        let handler = handler();
+        let request = query("printer.lan", Some(&[DEFAULT_PROVENANCE_OPTION]));
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        let header = Header::parse_from(request[..12].try_into().unwrap());
+        let response = handler
+            .handle(&info, &header, &request, &Stats::default())
+            .unwrap();
+        let budget = response.encode().len() - 1;
+
+        // The annotation goes; the OPT record stays.
+        let truncated = response.truncate_to(budget);
+        assert_eq!(truncated.get_additionals().len(), 1);
+        assert_eq!(truncated.get_header().get_ar_count(), 1);
+        assert_eq!(truncated.get_opt(), response.get_opt());
+        assert_eq!(truncated.get_answers().len(), response.get_answers().len());
+        assert!(!truncated.get_header().get_tc());
+    }
+}
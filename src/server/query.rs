@@ -0,0 +1,387 @@
+// Queries the server makes itself, rather than relays: the forwarder's queries
+// upstream, for clients and for paced bulk lookups alike. A Query is a single
+// question and the options to send it with; it builds the message, with counts
+// that match its sections, and tells whether a response answers it (RFC 5452,
+// 9.1): same ID, QR set, and the question echoed. The name must be echoed with
+// the casing sent if it was mixed (0x20, see set_mixed_case), and may differ in
+// case otherwise (RFC 4343).
+
+use std::{fmt, sync::Arc};
+
+use super::dns::message::{
+    Header, Label, LabelSequence, Message, OptRecord, Question, RCode, RecordClass, RecordType,
+};
+use super::edns::{extended_rcode, UDP_PAYLOAD_SIZE};
+
+// The EDNS COOKIE option (RFC 7873, 4).
+pub const COOKIE_OPTION: u16 = 10;
+
+#[derive(Clone, Debug)]
+pub struct Query {
+    // As sent, casing included.
+    question: Question,
+    rd: bool,
+    cd: bool,
+    // The UDP payload size to advertise; None to send no OPT record.
+    edns_size: Option<u16>,
+    do_bit: bool,
+    // The client cookie, sent without a server cookie.
+    cookie: Option<[u8; 8]>,
+    // Other EDNS options, e.g. edns-key-tag.
+    options: Vec<(u16, Vec<u8>)>,
+    mixed_case: bool,
+}
+
+// Why a response doesn't answer a query.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResponseMismatch {
+    Id { expected: u16, got: u16 },
+    NotAResponse,
+    // The response has no question, several, or another one.
+    Question { echoed: Option<String> },
+    // The question's name was echoed with other casing than the mixed one sent.
+    Casing { sent: String, echoed: String },
+}
+
+impl fmt::Display for ResponseMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Id { expected, got } => write!(f, "ID {} instead of {}", got, expected),
+            Self::NotAResponse => write!(f, "QR is not set"),
+            Self::Question { echoed: None } => write!(f, "no single question echoed"),
+            Self::Question {
+                echoed: Some(echoed),
+            } => write!(f, "another question echoed ({})", echoed),
+            Self::Casing { sent, echoed } => {
+                write!(f, "{} echoed as {}, casing lost", sent, echoed)
+            }
+        }
+    }
+}
+
+impl Query {
+    // An IN query for `name` and `type`, with RD clear and without EDNS.
+    pub fn new(name: &Arc<LabelSequence>, r#type: RecordType) -> Query {
+        Query::from_question(&Question::new(name, r#type, RecordClass::In))
+    }
+
+    pub fn from_question(question: &Question) -> Query {
+        Query {
+            question: question.clone(),
+            rd: false,
+            cd: false,
+            edns_size: None,
+            do_bit: false,
+            cookie: None,
+            options: Vec::new(),
+            mixed_case: false,
+        }
+    }
+
+    pub fn set_rd(&mut self, rd: bool) -> &'_ mut Self {
+        self.rd = rd;
+        self
+    }
+
+    // Asks a validating upstream not to validate the answer (CD, RFC 4035, 3.2.2).
+    pub fn set_cd(&mut self, cd: bool) -> &'_ mut Self {
+        self.cd = cd;
+        self
+    }
+
+    pub fn set_edns_size(&mut self, size: u16) -> &'_ mut Self {
+        self.edns_size = Some(size);
+        self
+    }
+
+    // Sets DO, with EDNS.
+    pub fn set_do_bit(&mut self, do_bit: bool) -> &'_ mut Self {
+        self.do_bit = do_bit;
+        self.edns_size.get_or_insert(UDP_PAYLOAD_SIZE);
+        self
+    }
+
+    // Sends a client cookie, with EDNS.
+    pub fn set_cookie(&mut self, cookie: [u8; 8]) -> &'_ mut Self {
+        self.cookie = Some(cookie);
+        self.edns_size.get_or_insert(UDP_PAYLOAD_SIZE);
+        self
+    }
+
+    // Adds an EDNS option, with EDNS.
+    pub fn add_option(&mut self, code: u16, data: &[u8]) -> &'_ mut Self {
+        self.options.push((code, data.to_vec()));
+        self.edns_size.get_or_insert(UDP_PAYLOAD_SIZE);
+        self
+    }
+
+    // Mixes the casing of the name's letters, one bit of `seed` each (0x20, as in
+    // draft-vixie-dnsext-dns0x20), for the response to echo.
+    pub fn set_mixed_case(&mut self, seed: u64) -> &'_ mut Self {
+        let mut bits = seed;
+        let labels: Vec<Label> = self
+            .question
+            .get_name()
+            .get_labels()
+            .iter()
+            .map(|label| {
+                let content: String = label
+                    .get_content()
+                    .chars()
+                    .map(|c| {
+                        if !c.is_ascii_alphabetic() {
+                            return c;
+                        }
+                        bits = bits.rotate_right(1);
+                        match bits >> 63 {
+                            1 => c.to_ascii_uppercase(),
+                            _ => c.to_ascii_lowercase(),
+                        }
+                    })
+                    .collect();
+                Label::new(&Arc::from(content))
+            })
+            .collect();
+        self.question = Question::new(
+            &Arc::new(LabelSequence::new(&labels.into())),
+            self.question.get_type(),
+            self.question.get_class(),
+        );
+        self.mixed_case = true;
+        self
+    }
+
+    pub fn to_message(&self, id: u16) -> Message {
+        let mut header = Header::default();
+        header
+            .set_id(id)
+            .set_rd(self.rd)
+            .set_cd(self.cd)
+            .set_qd_count(1);
+        let message = Message::new(
+            &Arc::new(header),
+            &Arc::from([self.question.clone()]),
+            &Arc::from([]),
+        );
+        let Some(size) = self.edns_size else {
+            return message;
+        };
+        let mut opt = OptRecord::new(size);
+        opt.set_do(self.do_bit);
+        if let Some(cookie) = &self.cookie {
+            opt.add_option(COOKIE_OPTION, cookie);
+        }
+        for (code, data) in &self.options {
+            opt.add_option(*code, data);
+        }
+        message.with_additionals(&Arc::from([opt.to_answer()]))
+    }
+
+    pub fn encode(&self, id: u16) -> Arc<[u8]> {
+        self.to_message(id).encode()
+    }
+
+    // Checks that `response` answers the query sent with `id`, and returns its
+    // RCODE, extended by its OPT record if it has one.
+    pub fn matches_response(&self, id: u16, response: &Message) -> Result<RCode, ResponseMismatch> {
+        let header = response.get_header();
+        if header.get_id() != id {
+            return Err(ResponseMismatch::Id {
+                expected: id,
+                got: header.get_id(),
+            });
+        }
+        if !header.get_qr() {
+            return Err(ResponseMismatch::NotAResponse);
+        }
+        let [echoed] = response.get_questions().as_ref() else {
+            return Err(ResponseMismatch::Question { echoed: None });
+        };
+        let sent = self.question.get_name();
+        if echoed.get_type() != self.question.get_type()
+            || echoed.get_class() != self.question.get_class()
+            || !echoed.get_name().eq_ignore_case(sent)
+        {
+            return Err(ResponseMismatch::Question {
+                echoed: Some(echoed.to_string()),
+            });
+        }
+        if self.mixed_case && echoed.get_name().to_string() != sent.to_string() {
+            return Err(ResponseMismatch::Casing {
+                sent: sent.to_string(),
+                echoed: echoed.get_name().to_string(),
+            });
+        }
+        Ok(extended_rcode(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, sync::mpsc, thread, time::Duration};
+
+    #[cfg(feature = "dnssec")]
+    use super::super::signature_time::SystemClock;
+    use super::super::testing::forwarder;
+    #[cfg(feature = "dnssec")]
+    use super::super::trust_anchors::{TrustAnchor, TrustAnchors};
+    use super::super::verify::parse_strict;
+    use super::super::wire_corpus::corpus_entry;
+    use super::super::{ForwardingDnsResolver, Lookup, Resolve};
+    use super::*;
+
+    fn name(name: &str) -> Arc<LabelSequence> {
+        Arc::new(name.parse().unwrap())
+    }
+
+    // The response an upstream would send to `request`, with the question echoed as
+    // `echoed`.
+    fn response(request: &Message, echoed: &str) -> Message {
+        let mut header = request.get_header().as_ref().clone();
+        header.set_qr(true).set_rcode(RCode::NameError);
+        let question = &request.get_questions()[0];
+        let question = Question::new(&name(echoed), question.get_type(), question.get_class());
+        Message::new(&Arc::new(header), &Arc::from([question]), &Arc::from([]))
+    }
+
+    #[test]
+    fn responses_must_echo_the_id_and_the_question() {
+        let mut query = Query::new(&name("www.example.com"), RecordType::A);
+        query.set_rd(true);
+        let request = query.to_message(0x1234);
+        let correct = response(&request, "www.example.com");
+        assert_eq!(
+            query.matches_response(0x1234, &correct),
+            Ok(RCode::NameError)
+        );
+        // Without 0x20, the casing may change.
+        let recased = response(&request, "WWW.Example.COM");
+        assert_eq!(
+            query.matches_response(0x1234, &recased),
+            Ok(RCode::NameError)
+        );
+
+        assert_eq!(
+            query.matches_response(0x4321, &correct),
+            Err(ResponseMismatch::Id {
+                expected: 0x4321,
+                got: 0x1234
+            })
+        );
+        assert_eq!(
+            query.matches_response(0x1234, &request),
+            Err(ResponseMismatch::NotAResponse)
+        );
+        assert_eq!(
+            query
+                .matches_response(0x1234, &response(&request, "evil.example.com"))
+                .unwrap_err()
+                .to_string(),
+            "another question echoed (;evil.example.com.    IN    A)"
+        );
+        let bare = Message::new(correct.get_header(), &Arc::from([]), &Arc::from([]));
+        assert_eq!(
+            query.matches_response(0x1234, &bare),
+            Err(ResponseMismatch::Question { echoed: None })
+        );
+    }
+
+    #[test]
+    fn mixed_case_queries_need_the_casing_echoed() {
+        let mut query = Query::new(&name("www.example.com"), RecordType::A);
+        query.set_mixed_case(0xA5A5_A5A5_A5A5_A5A5);
+        let sent = query.question.get_name().to_string();
+        assert!(sent.eq_ignore_ascii_case("www.example.com"));
+        assert_ne!(sent, "www.example.com");
+        let request = query.to_message(7);
+        assert!(query
+            .matches_response(7, &response(&request, &sent))
+            .is_ok());
+        assert_eq!(
+            query.matches_response(7, &response(&request, "www.example.com")),
+            Err(ResponseMismatch::Casing {
+                sent,
+                echoed: String::from("www.example.com")
+            })
+        );
+    }
+
+    // An upstream answering NOERROR with no records, and passing on every query it got.
+    fn recording_upstream() -> (UdpSocket, mpsc::Receiver<Vec<u8>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.try_clone().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = server.recv_from(&mut buf) {
+                let mut response = buf[..size].to_vec();
+                response[2] |= 0x80;
+                let _ = server.send_to(&response, source);
+                if sender.send(buf[..size].to_vec()).is_err() {
+                    return;
+                }
+            }
+        });
+        (socket, receiver)
+    }
+
+    #[test]
+    fn internal_queries_are_well_formed_and_match_the_corpus() {
+        let (upstream, sent) = recording_upstream();
+        let resolver = ForwardingDnsResolver {
+            #[cfg(feature = "dnssec")]
+            trust_anchors: Some(Arc::new(TrustAnchors::new(
+                vec![TrustAnchor {
+                    owner: String::new(),
+                    key_tag: 20326,
+                    algorithm: 8,
+                    digest_type: 2,
+                    digest: vec![0; 32],
+                    valid_from: None,
+                    valid_until: None,
+                }],
+                Arc::new(SystemClock),
+            ))),
+            ..forwarder(upstream.local_addr().unwrap(), 1000.0)
+        };
+        let mut header = Header::default();
+        header.set_id(0x1234).set_rd(true);
+
+        // A client's question, a root DNSKEY one (with edns-key-tag, given the trust
+        // anchors), and a warm-up.
+        let a = Question::new(&name("example.com"), RecordType::A, RecordClass::In);
+        assert!(matches!(
+            resolver.lookup(&header, &a).lookup,
+            Lookup::FoundNoData
+        ));
+        let mut captures = vec!["forwarder-a"];
+        #[cfg(feature = "dnssec")]
+        {
+            let dnskey = Question::new(&name(""), RecordType::Unknown(48), RecordClass::In);
+            assert!(matches!(
+                resolver.lookup(&header, &dnskey).lookup,
+                Lookup::FoundNoData
+            ));
+            captures.push("forwarder-key-tag");
+        }
+        resolver.warm_up("warm.example.com").unwrap();
+        resolver.maintain();
+        captures.push("forwarder-paced");
+
+        for capture in captures {
+            let query = sent.recv_timeout(Duration::from_secs(1)).unwrap();
+            let message = parse_strict(&query).unwrap_or_else(|err| panic!("{capture}: {err}"));
+            let header = message.get_header();
+            assert!(!header.get_qr(), "{capture}");
+            assert_eq!(header.get_qd_count(), 1, "{capture}");
+            assert_eq!(
+                header.get_ar_count() as usize,
+                message.get_additionals().len(),
+                "{capture}"
+            );
+            // The ID is random; the rest must be as captured.
+            assert_eq!(query[2..], corpus_entry(capture)[2..], "{capture}");
+        }
+        assert!(sent.try_recv().is_err());
+    }
+}
@@ -0,0 +1,233 @@
+// Concurrency quotas (--max-inflight-per-connection, --max-inflight-per-client), so
+// that one client pipelining expensive queries can't take every turn of the server
+// loop. Queries are admitted to the handlers at the start of an iteration and are
+// in flight until they are answered, later in it or by a worker (see workers.rs).
+//
+// A connection has at most its quota of queries in flight; the rest wait in its
+// queue for the next iterations. A connection whose queue outgrows QUEUE_FACTOR
+// times its quota is deemed abusive and closed. A client address has at most its
+// quota in flight over all its datagrams and connections: further queries on its
+// connections wait in their queues, and further datagrams are served as if the
+// server were overloaded (local answers, SERVFAIL for the rest).
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+// How many times its quota of queries a connection may have waiting.
+pub const QUEUE_FACTOR: usize = 4;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientUsage {
+    pub inflight: usize,
+    // Queries waiting on the client's connections.
+    pub queued: usize,
+}
+
+pub struct ClientQuotas {
+    pub per_connection: usize,
+    pub per_client: usize,
+    // Only clients with queries in flight or waiting.
+    usage: Mutex<HashMap<IpAddr, ClientUsage>>,
+}
+
+// A query admitted for `client`; it stops counting as in flight when dropped,
+// however its handling ends, on whichever thread (see workers.rs).
+pub struct Admission {
+    quotas: Arc<ClientQuotas>,
+    client: IpAddr,
+}
+
+impl ClientQuotas {
+    pub fn new(per_connection: usize, per_client: usize) -> Result<ClientQuotas, String> {
+        if per_connection == 0 || per_client == 0 {
+            return Err(String::from("In-flight quotas must be at least 1."));
+        }
+        Ok(ClientQuotas {
+            per_connection,
+            per_client,
+            usage: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Admits a query from `client` if it is under its quota.
+    pub fn admit(self: &Arc<Self>, client: IpAddr) -> Option<Admission> {
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(client).or_default();
+        if entry.inflight >= self.per_client {
+            return None;
+        }
+        entry.inflight += 1;
+        Some(Admission {
+            quotas: Arc::clone(self),
+            client,
+        })
+    }
+
+    // Records how many queries are waiting on a connection of `client`, `before` and
+    // now.
+    pub fn requeued(&self, client: IpAddr, before: usize, now: usize) {
+        self.update(client, |usage| usage.queued = usage.queued + now - before);
+    }
+
+    fn update(&self, client: IpAddr, change: impl FnOnce(&mut ClientUsage)) {
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(client).or_default();
+        change(entry);
+        if *entry == ClientUsage::default() {
+            usage.remove(&client);
+        }
+    }
+
+    // The clients with queries in flight or waiting, one per line, busiest first.
+    pub fn report(&self) -> Vec<String> {
+        let usage = self.usage.lock().unwrap();
+        let mut clients: Vec<(&IpAddr, &ClientUsage)> = usage.iter().collect();
+        clients.sort_by_key(|(client, usage)| {
+            (std::cmp::Reverse(usage.inflight + usage.queued), **client)
+        });
+        clients
+            .iter()
+            .map(|(client, usage)| {
+                format!(
+                    "{} inflight={} queued={}",
+                    client, usage.inflight, usage.queued
+                )
+            })
+            .collect()
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.quotas.update(self.client, |usage| usage.inflight -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpStream, UdpSocket},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use super::super::testing::{dummy_server, forwarder, mock_upstream, query, query_handler};
+    use super::super::LoopState;
+    use super::*;
+
+    fn connect(address: std::net::SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        stream
+    }
+
+    // Sends `count` queries on the connection at once.
+    fn pipeline(stream: &mut TcpStream, count: usize) {
+        let mut data: Vec<u8> = Vec::new();
+        for _ in 0..count {
+            let message = query("example.com", None);
+            data.extend_from_slice(&(message.len() as u16).to_be_bytes());
+            data.extend_from_slice(&message);
+        }
+        stream.write_all(&data).unwrap();
+    }
+
+    // Reads the responses that have arrived; None once the server closed the connection.
+    fn responses(stream: &mut TcpStream) -> Option<usize> {
+        let mut count = 0;
+        loop {
+            let mut length = [0; 2];
+            match stream.read(&mut length) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(_) => return Some(count),
+            }
+            let mut message = vec![0; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut message).unwrap();
+            count += 1;
+        }
+    }
+
+    #[test]
+    fn greedy_connections_are_throttled_then_closed() {
+        let mut server = dummy_server();
+        server.quotas = Arc::new(ClientQuotas::new(2, 64).unwrap());
+        let mut state = LoopState::new(&server.randomness);
+        let address = server.listeners[0].stream_listener.local_addr().unwrap();
+        let mut greedy = connect(address);
+        let mut modest = connect(address);
+        server.run_once(&mut state).unwrap();
+        assert_eq!(state.connections.len(), 2);
+
+        // The greedy connection pipelines six queries a turn and gets two answered;
+        // the modest one gets its single query answered every turn.
+        pipeline(&mut greedy, 6);
+        pipeline(&mut modest, 1);
+        server.run_once(&mut state).unwrap();
+        assert_eq!(responses(&mut greedy), Some(2));
+        assert_eq!(responses(&mut modest), Some(1));
+        assert_eq!(server.quotas.report(), ["127.0.0.1 inflight=0 queued=4"]);
+
+        pipeline(&mut greedy, 6);
+        pipeline(&mut modest, 1);
+        server.run_once(&mut state).unwrap();
+        assert_eq!(responses(&mut greedy), Some(2));
+        assert_eq!(responses(&mut modest), Some(1));
+        assert_eq!(server.quotas.report(), ["127.0.0.1 inflight=0 queued=8"]);
+
+        // Then it has more waiting than its queue allows, and is closed.
+        pipeline(&mut greedy, 6);
+        pipeline(&mut modest, 1);
+        server.run_once(&mut state).unwrap();
+        assert_eq!(responses(&mut greedy), None);
+        assert_eq!(responses(&mut modest), Some(1));
+        assert_eq!(state.connections.len(), 1);
+        assert_eq!(server.stats.abusive_connections.get(), 1);
+        assert!(server.quotas.report().is_empty());
+    }
+
+    #[test]
+    fn datagrams_over_the_client_quota_are_served_as_overloaded() {
+        let upstream = mock_upstream(&[[192, 0, 2, 1]], 60, Duration::ZERO);
+        let mut server = dummy_server();
+        server.handlers = vec![Arc::new(query_handler(forwarder(
+            upstream.local_addr().unwrap(),
+            10.0,
+        )))];
+        server.quotas = Arc::new(ClientQuotas::new(16, 2).unwrap());
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        let greedy = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let modest = UdpSocket::bind("127.0.0.2:0").unwrap();
+        for _ in 0..5 {
+            greedy
+                .send_to(&query("example.com", None), address)
+                .unwrap();
+        }
+        modest
+            .send_to(&query("example.com", None), address)
+            .unwrap();
+        server
+            .run_once(&mut LoopState::new(&server.randomness))
+            .unwrap();
+
+        let rcodes = |socket: &UdpSocket| -> Vec<u8> {
+            socket.set_nonblocking(true).unwrap();
+            let mut buf = [0; 512];
+            let mut rcodes: Vec<u8> = Vec::new();
+            while let Ok(size) = socket.recv(&mut buf) {
+                rcodes.push(buf[..size][3] & 0x0F);
+            }
+            rcodes
+        };
+        assert_eq!(rcodes(&greedy), [0, 0, 2, 2, 2]);
+        assert_eq!(rcodes(&modest), [0]);
+        assert_eq!(server.stats.over_client_quota.get(), 3);
+        assert!(server.quotas.report().is_empty());
+    }
+}
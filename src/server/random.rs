@@ -0,0 +1,208 @@
+// Where the server's randomness comes from, so that a run can be reproduced: every
+// randomized behaviour draws from a stream of the process's Randomness, seeded from
+// the clock or, with --random-seed, from the given seed. Each consumer (e.g. the
+// maintenance timer) has a stream of its own, derived from the seed and its name, so
+// that what one draws doesn't depend on how often the others did. None of it is
+// meant to be unpredictable to an attacker; what has to be, like the IDs of upstream
+// queries, draws from the system's randomness (SystemRng) instead, never seeded.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, Read},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Send + Sync, as the streams are drawn from by the resolvers, which the worker
+// threads share (see workers.rs).
+pub trait Rng: Send + Sync {
+    fn next_u64(&self) -> u64;
+
+    // A fraction in [0, 1).
+    fn fraction(&self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// splitmix64's output function: spreads a seed's bits, so that seeds close to each
+// other (0, 1, ...) start streams that aren't.
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+// xorshift64*.
+pub struct Xorshift {
+    state: AtomicU64,
+}
+
+impl Xorshift {
+    pub fn seeded(seed: u64) -> Xorshift {
+        Xorshift {
+            // The state must not be zero.
+            state: AtomicU64::new(mix(seed) | 1),
+        }
+    }
+}
+
+impl Rng for Xorshift {
+    fn next_u64(&self) -> u64 {
+        let step = |mut x: u64| {
+            x ^= x >> 12;
+            x ^= x << 25;
+            x ^= x >> 27;
+            x
+        };
+        // Never fails, as the closure always returns a state.
+        let x = self
+            .state
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(step(x)))
+            .unwrap();
+        step(x).wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+// The system's randomness, read from /dev/urandom.
+pub struct SystemRng {
+    source: Mutex<BufReader<File>>,
+}
+
+impl SystemRng {
+    pub fn open() -> io::Result<SystemRng> {
+        Ok(SystemRng {
+            source: Mutex::new(BufReader::new(File::open("/dev/urandom")?)),
+        })
+    }
+}
+
+impl Rng for SystemRng {
+    fn next_u64(&self) -> u64 {
+        let mut bytes = [0; 8];
+        self.source
+            .lock()
+            .unwrap()
+            .read_exact(&mut bytes)
+            .expect("Failed to read /dev/urandom");
+        u64::from_ne_bytes(bytes)
+    }
+}
+
+#[derive(Clone)]
+pub struct Randomness {
+    seed: u64,
+    // Whether the seed was given (--random-seed), rather than taken from the clock.
+    pub fixed: bool,
+}
+
+impl Randomness {
+    pub fn seeded(seed: u64) -> Randomness {
+        Randomness { seed, fixed: true }
+    }
+
+    pub fn from_clock() -> Randomness {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        Randomness { seed, fixed: false }
+    }
+
+    // The stream of the consumer called `name`; the same for the same seed and name.
+    pub fn stream(&self, name: &str) -> Arc<dyn Rng> {
+        // FNV-1a, to fold the name into the seed.
+        let folded = name.bytes().fold(0xCBF2_9CE4_8422_2325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+        });
+        Arc::new(Xorshift::seeded(mix(self.seed) ^ folded))
+    }
+
+    // The randomness of worker `index` (see workers.rs): a seed of its own, derived
+    // from this one, so that the workers don't all draw the same numbers.
+    pub fn for_worker(&self, index: usize) -> Randomness {
+        Randomness {
+            seed: mix(self.seed ^ (index as u64 + 1)),
+            fixed: self.fixed,
+        }
+    }
+}
+
+impl Default for Randomness {
+    fn default() -> Randomness {
+        Randomness::from_clock()
+    }
+}
+
+impl fmt::Display for Randomness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.fixed {
+            write!(f, "seed {} (--random-seed)", self.seed)
+        } else {
+            write!(f, "seed {} (from the clock)", self.seed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::super::housekeeping::Housekeeping;
+    use super::super::pacing::JitteredInterval;
+    use super::*;
+
+    // What a run draws: the maintenance timer's and the retention sweep's delays, the
+    // sweep rescheduled twice as often.
+    fn schedules(randomness: &Randomness) -> Vec<Duration> {
+        let start = Instant::now();
+        let mut maintenance = JitteredInterval::new(
+            Duration::from_secs(1),
+            0.15,
+            start,
+            randomness.stream("maintenance"),
+        );
+        let housekeeping = Housekeeping::with_randomness(randomness);
+        let mut due = Vec::new();
+        for step in 1..=20 {
+            let now = start + Duration::from_secs(step);
+            maintenance.reschedule(now);
+            due.push(maintenance.next_due - now);
+            for _ in 0..2 {
+                housekeeping.sweep.borrow_mut().reschedule(now);
+                due.push(housekeeping.sweep.borrow().next_due - now);
+            }
+        }
+        due
+    }
+
+    #[test]
+    fn runs_with_the_same_seed_draw_the_same() {
+        let once = schedules(&Randomness::seeded(42));
+        assert_eq!(once, schedules(&Randomness::seeded(42)));
+        assert_ne!(once, schedules(&Randomness::seeded(43)));
+    }
+
+    #[test]
+    fn streams_are_independent_of_each_other() {
+        let randomness = Randomness::seeded(7);
+        let alone: Vec<u64> = {
+            let stream = randomness.stream("maintenance");
+            (0..10).map(|_| stream.next_u64()).collect()
+        };
+        let maintenance = randomness.stream("maintenance");
+        let sweep = randomness.stream("housekeeping");
+        let interleaved: Vec<u64> = (0..10)
+            .map(|_| {
+                sweep.next_u64();
+                maintenance.next_u64()
+            })
+            .collect();
+        assert_eq!(alone, interleaved);
+        assert_ne!(alone[0], randomness.stream("housekeeping").next_u64());
+        assert!((0..1000).all(|_| (0.0..1.0).contains(&sweep.fraction())));
+    }
+}
@@ -0,0 +1,819 @@
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Mutex, MutexGuard, RwLock},
+    time::Instant,
+};
+
+use super::budget;
+use super::dns::message::{
+    Answer, Header, LabelSequence, LabelSequenceParseError, Question, RData, RecordClass,
+    RecordType,
+};
+use super::policy::parse_record_type;
+use super::transaction::{Transaction, ZoneDiff};
+use super::zone::{parse_data, parse_ttl};
+use super::zone_check::{validate, Diagnostic};
+use super::zone_file::split_fields_at;
+use super::{Lookup, Resolve, Resolved};
+
+// TTL of the MX records given with --mx.
+const MAIL_EXCHANGE_TTL: u32 = 300;
+
+// TTL of the SRV records given with --srv.
+const SERVICE_LOCATION_TTL: u32 = 300;
+
+// TTL of the records given with --record that don't have their own.
+const LOCAL_RECORD_TTL: u32 = 300;
+
+// Longest chain of local CNAMEs followed for a single question.
+const MAX_CNAME_CHAIN: usize = 8;
+
+// Transactions whose diffs are kept.
+const JOURNAL_SIZE: usize = 64;
+
+// Whether two records are the same but for their TTLs.
+pub(super) fn same_record(a: &Answer, b: &Answer) -> bool {
+    a.get_type() == b.get_type()
+        && a.get_class() == b.get_class()
+        && a.get_data() == b.get_data()
+        && a.get_name().eq_ignore_case(b.get_name())
+}
+
+// Locally defined records, answered by exact (case-insensitive) name and type match.
+// They are set up with `add`, and edited at runtime only through transactions
+// (see transaction.rs), which readers never see half-applied.
+#[derive(Default)]
+pub struct StaticRecords {
+    answers: RwLock<Vec<Answer>>,
+    // The diffs of the latest transactions committed, oldest first.
+    journal: Mutex<VecDeque<ZoneDiff>>,
+    // Held through a commit, so that transactions don't apply over each other's edits.
+    committing: Mutex<()>,
+}
+
+impl StaticRecords {
+    pub fn new() -> StaticRecords {
+        StaticRecords::default()
+    }
+
+    // Adds a record unless an identical one is already present.
+    pub fn add(&mut self, answer: Answer) -> &'_ mut Self {
+        let answers = self.answers.get_mut().unwrap();
+        if !answers
+            .iter()
+            .any(|existing| same_record(existing, &answer))
+        {
+            answers.push(answer);
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.answers.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.answers.read().unwrap().is_empty()
+    }
+
+    pub fn any(&self, predicate: impl Fn(&Answer) -> bool) -> bool {
+        self.answers.read().unwrap().iter().any(predicate)
+    }
+
+    // Checks the records for CNAME conflicts, alias targets and TTL mismatches.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        validate(&self.answers.read().unwrap(), None)
+    }
+
+    // Starts a transaction on the records; nothing changes until it is committed.
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    // Keeps other transactions from committing until the guard is dropped.
+    pub(super) fn lock_commits(&self) -> MutexGuard<'_, ()> {
+        self.committing.lock().unwrap()
+    }
+
+    // The records, as a transaction starts from.
+    pub(super) fn snapshot(&self) -> Vec<Answer> {
+        self.answers.read().unwrap().clone()
+    }
+
+    // Replaces the records with those of a committed transaction, and journals its
+    // diff.
+    pub(super) fn apply(&self, answers: Vec<Answer>, diff: ZoneDiff) {
+        *self.answers.write().unwrap() = answers;
+        let mut journal = self.journal.lock().unwrap();
+        if journal.len() == JOURNAL_SIZE {
+            journal.pop_front();
+        }
+        journal.push_back(diff);
+    }
+
+    // The diffs of the latest transactions committed, oldest first.
+    pub fn journal(&self) -> Vec<ZoneDiff> {
+        self.journal.lock().unwrap().iter().cloned().collect()
+    }
+
+    // Returns the records matching the question. When the name only has a CNAME,
+    // the CNAME is returned and its target is looked up locally in turn, as the
+    // query's work budget allows. A name with records of other types only is
+    // FoundNoData.
+    pub fn lookup(&self, question: &Question) -> Lookup {
+        // Read once, so that a transaction committed meanwhile is seen whole or not at all.
+        let records = self.answers.read().unwrap();
+        if !has_name(&records, question.get_name()) {
+            return Lookup::NotFound;
+        }
+        let mut answers: Vec<Answer> = Vec::new();
+        let mut name: Arc<LabelSequence> = Arc::clone(question.get_name());
+        for _ in 0..MAX_CNAME_CHAIN {
+            let exact = find(&records, &name, question.get_type(), question.get_class());
+            if !exact.is_empty() || question.get_type() == RecordType::Cname {
+                answers.extend(exact);
+                break;
+            }
+            let cname = match find(&records, &name, RecordType::Cname, question.get_class())
+                .into_iter()
+                .next()
+            {
+                Some(cname) => cname,
+                None => break,
+            };
+            let target = match cname.get_rdata() {
+                RData::Cname(target) => Some(target.clone()),
+                _ => None,
+            };
+            answers.push(cname);
+            match target {
+                Some(target) if !budget::follow(&target) => return Lookup::Failed,
+                Some(target) => name = Arc::new(target),
+                None => break,
+            }
+        }
+        if answers.is_empty() {
+            Lookup::FoundNoData
+        } else {
+            Lookup::Found(answers)
+        }
+    }
+}
+
+fn has_name(answers: &[Answer], name: &LabelSequence) -> bool {
+    answers
+        .iter()
+        .any(|answer| answer.get_name().eq_ignore_case(name))
+}
+
+// The RRset for the name, type and class. Its records all get the TTL of the
+// first one, should they differ (RFC 2181, 5.2).
+fn find(
+    answers: &[Answer],
+    name: &LabelSequence,
+    r#type: RecordType,
+    class: RecordClass,
+) -> Vec<Answer> {
+    let rrset: Vec<&Answer> = answers
+        .iter()
+        .filter(|answer| {
+            answer.get_type() == r#type
+                && answer.get_class() == class
+                && answer.get_name().eq_ignore_case(name)
+        })
+        .collect();
+    let ttl = rrset.first().map_or(0, |answer| answer.get_ttl());
+    rrset
+        .into_iter()
+        .map(|answer| {
+            Answer::from_rdata(
+                /* name= */ answer.get_name(),
+                /* class= */ answer.get_class(),
+                /* ttl= */ ttl,
+                /* data= */ answer.get_rdata().clone(),
+            )
+        })
+        .collect()
+}
+
+// A locally served MX record, given as "NAME=PREFERENCE:EXCHANGE",
+// e.g. "example.test=10:mail.example.test", to route a domain's mail locally.
+#[derive(Clone, Debug)]
+pub struct MailExchange {
+    name: String,
+    preference: u16,
+    exchange: String,
+}
+
+impl MailExchange {
+    pub fn parse(spec: &str) -> Result<MailExchange, String> {
+        let invalid = || {
+            format!(
+                "Mail exchange '{}' must look like NAME=PREFERENCE:EXCHANGE.",
+                spec
+            )
+        };
+        let (name, rest) = spec.split_once('=').ok_or_else(invalid)?;
+        let (preference, exchange) = rest.split_once(':').ok_or_else(invalid)?;
+        let preference: u16 = preference.trim().parse().map_err(|_| {
+            format!(
+                "MX preference '{}' is not a number up to 65535.",
+                preference
+            )
+        })?;
+        let (name, exchange) = (name.trim().to_string(), exchange.trim().to_string());
+        for name in [&name, &exchange] {
+            name.parse::<LabelSequence>()
+                .map_err(|err: LabelSequenceParseError| err.message)?;
+        }
+        Ok(MailExchange {
+            name,
+            preference,
+            exchange,
+        })
+    }
+
+    pub fn mx_record(&self) -> Result<Answer, String> {
+        let parse = |name: &str| -> Result<LabelSequence, String> {
+            name.parse()
+                .map_err(|err: LabelSequenceParseError| err.message)
+        };
+        Ok(Answer::from_rdata(
+            /* name= */ &Arc::new(parse(&self.name)?),
+            /* class= */ RecordClass::In,
+            /* ttl= */ MAIL_EXCHANGE_TTL,
+            /* data= */
+            RData::Mx {
+                preference: self.preference,
+                exchange: parse(&self.exchange)?,
+            },
+        ))
+    }
+}
+
+// A locally served SRV record (RFC 2782), given as
+// "NAME=PRIORITY:WEIGHT:PORT:TARGET", e.g. "_http._tcp.example.test=10:60:8080:web.example.test",
+// for service lookups without a DNS-SD registration.
+#[derive(Clone, Debug)]
+pub struct ServiceLocation {
+    name: String,
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+impl ServiceLocation {
+    pub fn parse(spec: &str) -> Result<ServiceLocation, String> {
+        let invalid = || {
+            format!(
+                "Service location '{}' must look like NAME=PRIORITY:WEIGHT:PORT:TARGET.",
+                spec
+            )
+        };
+        let (name, rest) = spec.split_once('=').ok_or_else(invalid)?;
+        let fields: Vec<&str> = rest.splitn(4, ':').map(str::trim).collect();
+        let [priority, weight, port, target] = fields.as_slice() else {
+            return Err(invalid());
+        };
+        let number = |field: &str, value: &str| -> Result<u16, String> {
+            value
+                .parse()
+                .map_err(|_| format!("SRV {} '{}' is not a number up to 65535.", field, value))
+        };
+        let (name, target) = (name.trim().to_string(), target.to_string());
+        // "." is the target of a service that is decidedly not available (RFC 2782).
+        for name in [&name, &target] {
+            name.parse::<LabelSequence>()
+                .map_err(|err: LabelSequenceParseError| err.message)?;
+        }
+        Ok(ServiceLocation {
+            name,
+            priority: number("priority", priority)?,
+            weight: number("weight", weight)?,
+            port: number("port", port)?,
+            target,
+        })
+    }
+
+    // The target is never compressed (RFC 2782); EncodeContext leaves it be.
+    pub fn srv_record(&self) -> Result<Answer, String> {
+        let parse = |name: &str| -> Result<LabelSequence, String> {
+            name.parse()
+                .map_err(|err: LabelSequenceParseError| err.message)
+        };
+        Ok(Answer::from_rdata(
+            /* name= */ &Arc::new(parse(&self.name)?),
+            /* class= */ RecordClass::In,
+            /* ttl= */ SERVICE_LOCATION_TTL,
+            /* data= */
+            RData::Srv {
+                priority: self.priority,
+                weight: self.weight,
+                port: self.port,
+                target: parse(&self.target)?,
+            },
+        ))
+    }
+}
+
+// A record given on the command line as "NAME TYPE VALUE [TTL]", its value written
+// as in a zone file, e.g. "dev.local A 127.0.0.1", "www.local CNAME dev.local" or
+// "local MX 10 mail.local 60". A TXT value is one string, quoted if it has spaces.
+// Names are taken as they are, there being no origin to be relative to.
+#[derive(Clone, Debug)]
+pub struct LocalRecord {
+    name: String,
+    r#type: RecordType,
+    // With the column each starts at, as parse_data takes them.
+    value: Vec<(usize, String)>,
+    ttl: u32,
+}
+
+impl LocalRecord {
+    pub fn parse(spec: &str) -> Result<LocalRecord, String> {
+        let invalid = |err: String| format!("Record '{}': {}", spec, err);
+        let mut fields = split_fields_at(spec);
+        if fields.len() < 3 {
+            return Err(invalid(String::from("expected NAME TYPE VALUE [TTL].")));
+        }
+        let value = fields.split_off(2);
+        let r#type = parse_record_type(&fields[1].1).map_err(invalid)?;
+        let mut record = LocalRecord {
+            name: fields[0].1.clone(),
+            r#type,
+            value,
+            ttl: LOCAL_RECORD_TTL,
+        };
+        // A field past those the type's value takes is the TTL.
+        let value_fields = match r#type {
+            RecordType::Mx => 2,
+            RecordType::Srv => 4,
+            RecordType::Soa => 7,
+            _ => 1,
+        };
+        if record.value.len() == value_fields + 1 {
+            let (_, ttl) = record.value.pop().unwrap_or_default();
+            record.ttl = parse_ttl(&ttl).map_err(invalid)?;
+        }
+        record.record().map_err(invalid)?;
+        Ok(record)
+    }
+
+    pub fn record(&self) -> Result<Answer, String> {
+        let name: LabelSequence = self
+            .name
+            .parse()
+            .map_err(|err: LabelSequenceParseError| err.message)?;
+        let data = parse_data(self.r#type, &self.value, "").map_err(|(_, err)| err)?;
+        Ok(Answer::from_rdata(
+            /* name= */ &Arc::new(name),
+            /* class= */ RecordClass::In,
+            /* ttl= */ self.ttl,
+            /* data= */ data,
+        ))
+    }
+}
+
+// Answers questions from the static records, authoritatively, and passes those
+// about names it doesn't have to the next resolver.
+pub struct StaticDnsResolver {
+    pub records: Arc<StaticRecords>,
+    pub next: Box<dyn Resolve>,
+}
+
+impl Resolve for StaticDnsResolver {
+    fn lookup(&self, header: &Header, question: &Question) -> Resolved {
+        let lookup = match self.records.lookup(question) {
+            Lookup::Found(answers) => {
+                println!(
+                    "[STATIC] Answering {} from {} local record(s).",
+                    question,
+                    answers.len()
+                );
+                Lookup::Found(answers)
+            }
+            Lookup::FoundNoData => {
+                println!(
+                    "[STATIC] Answering {} with no data; the name is local.",
+                    question
+                );
+                Lookup::FoundNoData
+            }
+            _ => return self.next.lookup(header, question),
+        };
+        let mut header = Header::default();
+        header.set_aa(true);
+        Resolved {
+            header: Some(Arc::new(header)),
+            ..lookup.into()
+        }
+    }
+
+    fn provenance(&self, question: &Question) -> String {
+        match self.records.lookup(question) {
+            Lookup::NotFound => self.next.provenance(question),
+            _ => String::from("source=static"),
+        }
+    }
+
+    fn answers_locally(&self, question: &Question) -> bool {
+        match self.records.lookup(question) {
+            Lookup::NotFound => self.next.answers_locally(question),
+            _ => true,
+        }
+    }
+
+    fn claims(&self, question: &Question) -> bool {
+        match self.records.lookup(question) {
+            Lookup::NotFound => self.next.claims(question),
+            _ => true,
+        }
+    }
+
+    fn maintain(&self) {
+        self.next.maintain();
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        self.next.persist()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.next.next_due()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dns::message::{Message, SvcParam};
+    use super::super::testing::Counter;
+    use super::*;
+
+    // Stands in for the upstream: has an address for every name and counts questions.
+    struct Upstream {
+        asked: Counter,
+    }
+
+    impl Resolve for Upstream {
+        fn lookup(&self, _header: &Header, question: &Question) -> Resolved {
+            self.asked.increment();
+            Lookup::Found(vec![record(
+                &question.get_name().to_string(),
+                question.get_type(),
+                &[203, 0, 113, 1],
+            )])
+            .into()
+        }
+
+        fn provenance(&self, _question: &Question) -> String {
+            String::from("source=upstream")
+        }
+
+        fn answers_locally(&self, _question: &Question) -> bool {
+            false
+        }
+    }
+
+    fn record(name: &str, r#type: RecordType, data: &[u8]) -> Answer {
+        Answer::new(
+            /* name= */ &Arc::new(name.parse().unwrap()),
+            /* type= */ r#type,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */ &Arc::from(data),
+        )
+    }
+
+    // Local data: printer.lan has an A record only.
+    fn resolver(asked: &Counter) -> StaticDnsResolver {
+        let mut records = StaticRecords::new();
+        records.add(record("printer.lan", RecordType::A, &[192, 0, 2, 7]));
+        StaticDnsResolver {
+            records: Arc::new(records),
+            next: Box::new(Upstream {
+                asked: asked.clone(),
+            }),
+        }
+    }
+
+    fn answers(resolver: &StaticDnsResolver, name: &str, r#type: RecordType) -> Vec<Vec<u8>> {
+        let question = Question::new(&Arc::new(name.parse().unwrap()), r#type, RecordClass::In);
+        resolver
+            .resolve(&Header::default(), &Arc::from([question]))
+            .answers
+            .iter()
+            .map(|answer| answer.get_data().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn local_nodata_is_not_filled_in_from_upstream() {
+        let asked = Counter::default();
+        let resolver = resolver(&asked);
+        let question = Question::new(
+            &Arc::new("Printer.lan".parse().unwrap()),
+            RecordType::Aaaa,
+            RecordClass::In,
+        );
+        assert!(matches!(
+            resolver.records.lookup(&question),
+            Lookup::FoundNoData
+        ));
+        assert!(answers(&resolver, "Printer.lan", RecordType::Aaaa).is_empty());
+        assert_eq!(asked.get(), 0);
+        assert_eq!(resolver.provenance(&question), "source=static");
+        assert!(resolver.answers_locally(&question));
+    }
+
+    #[test]
+    fn local_answers_are_not_merged_with_upstream_ones() {
+        let asked = Counter::default();
+        let resolver = resolver(&asked);
+        assert_eq!(
+            answers(&resolver, "printer.lan", RecordType::A),
+            [vec![192, 0, 2, 7]]
+        );
+        assert_eq!(asked.get(), 0);
+    }
+
+    #[test]
+    fn names_unknown_locally_go_upstream() {
+        let asked = Counter::default();
+        let resolver = resolver(&asked);
+        assert_eq!(
+            answers(&resolver, "example.com", RecordType::A),
+            [vec![203, 0, 113, 1]]
+        );
+        assert_eq!(asked.get(), 1);
+        let question = Question::new(
+            &Arc::new("example.com".parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        );
+        assert_eq!(resolver.provenance(&question), "source=upstream");
+    }
+
+    #[test]
+    fn command_line_records_override_upstream_and_chase_local_aliases() {
+        let mut records = StaticRecords::new();
+        for spec in [
+            "dev.local A 127.0.0.1",
+            "www.local CNAME dev.local 60",
+            "dev.local TXT \"a local override\"",
+            "local MX 10 mail.local 1h",
+        ] {
+            records.add(LocalRecord::parse(spec).unwrap().record().unwrap());
+        }
+        let asked = Counter::default();
+        let resolver = StaticDnsResolver {
+            records: Arc::new(records),
+            next: Box::new(Upstream {
+                asked: asked.clone(),
+            }),
+        };
+        let question = |name: &str, r#type| {
+            Question::new(&Arc::new(name.parse().unwrap()), r#type, RecordClass::In)
+        };
+        let resolve =
+            |question: Question| resolver.resolve(&Header::default(), &Arc::from([question]));
+
+        // The alias comes with its target's address, both authoritative.
+        let resolution = resolve(question("WWW.local", RecordType::A));
+        assert!(resolution.aa);
+        let answers: Vec<String> = resolution
+            .answers
+            .iter()
+            .map(|answer| {
+                format!(
+                    "{} {} {}",
+                    answer.get_type(),
+                    answer.get_ttl(),
+                    answer.get_rdata()
+                )
+            })
+            .collect();
+        assert_eq!(answers, ["CNAME 60 dev.local.", "A 300 127.0.0.1"]);
+        let resolution = resolve(question("local", RecordType::Mx));
+        assert_eq!(resolution.answers[0].get_ttl(), 3600);
+        assert_eq!(asked.get(), 0);
+
+        // Other names are forwarded, and not authoritative.
+        let resolution = resolve(question("example.com", RecordType::A));
+        assert!(!resolution.aa);
+        assert_eq!(asked.get(), 1);
+
+        for (spec, err) in [
+            (
+                "dev.local A",
+                "Record 'dev.local A': expected NAME TYPE VALUE [TTL].",
+            ),
+            (
+                "dev.local A 127.0.0.300",
+                "Record 'dev.local A 127.0.0.300': '127.0.0.300' is not an IPv4 address.",
+            ),
+            (
+                "dev.local TXT two words",
+                "Record 'dev.local TXT two words': 'words' is not a TTL.",
+            ),
+            (
+                "local MX mail.local",
+                "Record 'local MX mail.local': MX record needs a preference and an exchange.",
+            ),
+        ] {
+            assert_eq!(LocalRecord::parse(spec).unwrap_err(), err);
+        }
+    }
+
+    #[test]
+    fn mail_exchanges_keep_their_order_through_reencoding() {
+        let mut records = StaticRecords::new();
+        for spec in [
+            "example.test=20:backup.example.test",
+            "example.test = 10 : mail.example.test",
+        ] {
+            records.add(MailExchange::parse(spec).unwrap().mx_record().unwrap());
+        }
+        let resolver = StaticDnsResolver {
+            records: Arc::new(records),
+            next: Box::new(Upstream {
+                asked: Counter::default(),
+            }),
+        };
+        let question = Question::new(
+            &Arc::new("example.test".parse().unwrap()),
+            RecordType::Mx,
+            RecordClass::In,
+        );
+        let answers = resolver
+            .resolve(&Header::default(), &Arc::from([question.clone()]))
+            .answers;
+        let mut header = Header::default();
+        header.set_qr(true).set_qd_count(1);
+        let message = Message::new(&Arc::new(header), &Arc::from([question]), &answers);
+
+        let encoded = message.encode();
+        let reread = Message::parse_from(&encoded).unwrap();
+        let shown: Vec<String> = reread
+            .get_answers()
+            .iter()
+            .map(|answer| answer.to_string())
+            .collect();
+        assert_eq!(
+            shown,
+            [
+                "example.test.    300    IN    MX    20 backup.example.test.",
+                "example.test.    300    IN    MX    10 mail.example.test.",
+            ]
+        );
+        assert_eq!(reread.encode(), encoded);
+
+        for spec in [
+            "example.test",
+            "example.test=mail.example.test",
+            "example.test=65536:mail.example.test",
+            "example.test=10:mail..example.test",
+        ] {
+            assert!(MailExchange::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn srv_targets_are_written_uncompressed() {
+        let mut records = StaticRecords::new();
+        for spec in [
+            "_http._tcp.example.test=10:60:8080:web.example.test",
+            "_http._tcp.example.test = 20 : 0 : 80 : example.test",
+        ] {
+            records.add(ServiceLocation::parse(spec).unwrap().srv_record().unwrap());
+        }
+        let question = Question::new(
+            &Arc::new("_http._tcp.example.test".parse().unwrap()),
+            RecordType::Srv,
+            RecordClass::In,
+        );
+        let answers = records.lookup(&question).into_answers();
+        let mut header = Header::default();
+        header.set_qr(true).set_qd_count(1);
+        let message = Message::new(&Arc::new(header), &Arc::from([question]), &answers.into());
+
+        // The targets end in names written before them, but are spelled out in full.
+        let encoded = message.encode();
+        let target = b"\x03web\x07example\x04test\x00";
+        assert!(encoded.windows(target.len()).any(|window| window == target));
+        let reread = Message::parse_from(&encoded).unwrap();
+        let shown: Vec<String> = reread
+            .get_answers()
+            .iter()
+            .map(|answer| answer.to_string())
+            .collect();
+        assert_eq!(
+            shown,
+            [
+                "_http._tcp.example.test.    300    IN    SRV    10 60 8080 web.example.test.",
+                "_http._tcp.example.test.    300    IN    SRV    20 0 80 example.test.",
+            ]
+        );
+        assert_eq!(reread.encode(), encoded);
+
+        for spec in [
+            "_http._tcp.example.test=10:60:8080",
+            "_http._tcp.example.test=10:60:65536:web.example.test",
+            "_http._tcp.example.test=ten:60:8080:web.example.test",
+            "_http._tcp.example.test=10:60:8080:web..example.test",
+        ] {
+            assert!(ServiceLocation::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn caa_records_round_trip_and_display_as_dig_does() {
+        let data = b"\x00\x05issueletsencrypt.org";
+        let answer = record("example.com", RecordType::Caa, data);
+        assert!(matches!(
+            answer.get_rdata(),
+            RData::Caa { flags: 0, tag, value } if &**tag == "issue" && &**value == b"letsencrypt.org"
+        ));
+        assert_eq!(answer.get_data().to_vec(), data);
+        assert_eq!(
+            answer.to_string(),
+            "example.com.    300    IN    CAA    0 issue \"letsencrypt.org\""
+        );
+        let critical = record("example.com", RecordType::Caa, b"\x80\x05iodef\"a\\b\x07");
+        assert_eq!(
+            critical.get_rdata().to_string(),
+            "128 iodef \"\\\"a\\\\b\\007\""
+        );
+
+        // An empty tag or one cut short isn't CAA; it is kept as it came.
+        for data in [&b"\x00\x00x"[..], b"\x00\x09issue", b"\x00"] {
+            let answer = record("example.com", RecordType::Caa, data);
+            assert!(matches!(answer.get_rdata(), RData::Raw(257, _)));
+            assert_eq!(answer.get_data().to_vec(), data);
+        }
+
+        let long = RData::Caa {
+            flags: 0,
+            tag: "x".repeat(256).into(),
+            value: Arc::from(&b""[..]),
+        };
+        let err = long.try_encode().unwrap_err();
+        assert!(err.message.contains("256"), "{}", err.message);
+    }
+
+    #[test]
+    fn service_bindings_parse_in_both_modes() {
+        // AliasMode: priority 0, a target and no parameters.
+        let alias = record(
+            "example.com",
+            RecordType::Https,
+            b"\x00\x00\x03cdn\x03net\x00",
+        );
+        assert_eq!(alias.get_rdata().to_string(), "0 cdn.net.");
+        // Parameters given to an alias aren't understood; the RDATA is kept as it came.
+        let invalid = b"\x00\x00\x00\x00\x03\x00\x02\x01\xbb";
+        let answer = record("example.com", RecordType::Https, invalid);
+        assert!(matches!(answer.get_rdata(), RData::Raw(65, _)));
+        assert_eq!(answer.get_data().to_vec(), invalid);
+
+        // ServiceMode, with an unknown key and a port kept as they are.
+        let mut data: Vec<u8> = b"\x00\x01\x00".to_vec();
+        data.extend_from_slice(b"\x00\x01\x00\x06\x02h2\x02h3");
+        data.extend_from_slice(b"\x00\x03\x00\x02\x20\xfb");
+        data.extend_from_slice(b"\x00\x04\x00\x04\xc0\x00\x02\x01");
+        data.extend_from_slice(b"\xff\x00\x00\x02a\"");
+        let answer = record("_8443._https.example.com", RecordType::Svcb, &data);
+        assert_eq!(
+            answer.get_rdata().to_string(),
+            "1 . alpn=\"h2,h3\" port=8443 ipv4hint=192.0.2.1 key65280=\"a\\\"\""
+        );
+        assert_eq!(answer.get_data().to_vec(), data);
+
+        // Keys go on the wire in increasing order, however they were given.
+        let RData::Svcb {
+            https,
+            priority,
+            target,
+            mut params,
+        } = answer.get_rdata().clone()
+        else {
+            panic!("not parsed");
+        };
+        params.reverse();
+        assert_eq!(params[0], SvcParam::Other(65280, Arc::from(&b"a\""[..])));
+        let reordered = RData::Svcb {
+            https,
+            priority,
+            target,
+            params,
+        };
+        assert_eq!(reordered.encode().to_vec(), data);
+        // Out of order on the wire, the RDATA is kept as it came.
+        let mut unordered = b"\x00\x01\x00".to_vec();
+        unordered.extend_from_slice(&data[19..27]);
+        unordered.extend_from_slice(&data[3..13]);
+        let answer = record("example.com", RecordType::Svcb, &unordered);
+        assert!(matches!(answer.get_rdata(), RData::Raw(64, _)));
+    }
+}
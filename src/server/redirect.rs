@@ -0,0 +1,342 @@
+// NXDOMAIN redirection for domains we own: a query for a name that doesn't exist
+// under one of them is answered with a CNAME to a landing host instead, to catch
+// typos. Only names under the configured suffixes are ever redirected, never those
+// of domains we don't own.
+
+use std::{io, sync::Arc, time::Instant};
+
+use super::budget::{self, Work};
+use super::dns::message::{
+    Answer, Header, LabelSequence, LabelSequenceParseError, Question, RData, RecordType,
+};
+use super::policy::{DomainSuffix, PolicyOutcome};
+use super::records::StaticRecords;
+use super::stats::ShardedCounter;
+use super::{Lookup, Resolve, Resolved};
+
+// TTL of the synthesized CNAME, kept low so that a name created later is found
+// soon. Caches must not take the CNAME for the real state of the name.
+const REDIRECT_TTL: u32 = 30;
+
+// Types of the records that only exist in signed zones.
+const DNSSEC_TYPES: [RecordType; 4] = [
+    RecordType::Unknown(43),
+    RecordType::Unknown(46),
+    RecordType::Unknown(47),
+    RecordType::Unknown(48),
+];
+
+// A redirect given on the command line as "example.com=portal.example.com".
+#[derive(Clone, Debug)]
+pub struct NxdomainRedirect {
+    pub suffix: DomainSuffix,
+    pub landing: String,
+}
+
+impl NxdomainRedirect {
+    pub fn parse(spec: &str) -> Result<NxdomainRedirect, String> {
+        let (suffix, landing) = spec.split_once('=').ok_or_else(|| {
+            format!(
+                "NXDOMAIN redirect '{}' must look like DOMAIN=LANDING-NAME.",
+                spec
+            )
+        })?;
+        let suffix = DomainSuffix::parse(suffix)?;
+        if suffix.is_root() {
+            return Err(String::from(
+                "NXDOMAIN redirection applies to owned domains only, not to the root.",
+            ));
+        }
+        landing
+            .parse::<LabelSequence>()
+            .map_err(|err: LabelSequenceParseError| err.message)?;
+        Ok(NxdomainRedirect {
+            suffix,
+            landing: landing.to_string(),
+        })
+    }
+
+    pub fn landing_name(&self) -> Arc<LabelSequence> {
+        Arc::new(self.landing.parse().expect("validated when parsed"))
+    }
+
+    // Redirection breaks validation of a signed zone, as the synthesized CNAME can't
+    // be signed; checks that none of the local records under the domain are DNSSEC
+    // records.
+    pub fn check_unsigned(&self, records: &StaticRecords) -> Result<(), String> {
+        let signed = records.any(|record| {
+            DNSSEC_TYPES.contains(&record.get_type()) && self.suffix.matches(record.get_name())
+        });
+        if signed {
+            return Err(format!(
+                "Can't redirect NXDOMAIN under {}: it is signed with DNSSEC.",
+                self.suffix
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Answers questions about names under a redirected domain that the next resolver
+// says don't exist with a CNAME to the landing name, followed by the landing name's
+// records when they are known locally.
+pub struct NxdomainRedirectResolver {
+    pub redirects: Vec<NxdomainRedirect>,
+    pub next: Box<dyn Resolve>,
+    pub redirected: ShardedCounter,
+}
+
+impl NxdomainRedirectResolver {
+    fn redirect_for(&self, question: &Question) -> Option<&NxdomainRedirect> {
+        self.redirects.iter().find(|redirect| {
+            redirect.suffix.matches(question.get_name())
+                && !redirect.landing_name().eq_ignore_case(question.get_name())
+        })
+    }
+}
+
+impl Resolve for NxdomainRedirectResolver {
+    // The authority section of an NXDOMAIN would contradict the redirect, so none is
+    // passed on for names under a redirected domain. A redirected answer is ours, not
+    // the upstream's NXDOMAIN, and clients are told that the CNAME was made up, and by
+    // which redirect.
+    fn lookup(&self, header: &Header, question: &Question) -> Resolved {
+        let resolved = self.next.lookup(header, question);
+        let Some(redirect) = self.redirect_for(question) else {
+            return resolved;
+        };
+        if !matches!(resolved.lookup, Lookup::NotFound) {
+            return Resolved {
+                authorities: Arc::from([]),
+                ..resolved
+            };
+        }
+        let landing_name = redirect.landing_name();
+        if !budget::spend(Work::Rewrites, 1) || !budget::follow(&landing_name) {
+            return Lookup::Failed.into();
+        }
+        self.redirected.increment();
+        println!(
+            "[REDIRECT] {} doesn't exist; redirecting to {} ({} redirected so far).",
+            question,
+            redirect.landing,
+            self.redirected.get()
+        );
+        let mut answers: Vec<Answer> = vec![Answer::from_rdata(
+            /* name= */ question.get_name(),
+            /* class= */ question.get_class(),
+            /* ttl= */ REDIRECT_TTL,
+            /* data= */ RData::Cname(landing_name.as_ref().clone()),
+        )];
+        let landing = Question::new(&landing_name, question.get_type(), question.get_class());
+        if question.get_type() != RecordType::Cname && self.next.answers_locally(&landing) {
+            answers.extend(self.next.lookup(header, &landing).into_answers());
+        }
+        Resolved {
+            policy_outcome: Some(PolicyOutcome::forged(format!(
+                "nxdomain-redirect {}={}",
+                redirect.suffix, redirect.landing
+            ))),
+            provenance: resolved.provenance,
+            ..Lookup::Found(answers).into()
+        }
+    }
+
+    fn provenance(&self, question: &Question) -> String {
+        self.next.provenance(question)
+    }
+
+    fn answers_locally(&self, question: &Question) -> bool {
+        self.next.answers_locally(question)
+    }
+
+    fn prefetch(&self, header: &Header, questions: &[Question]) {
+        self.next.prefetch(header, questions);
+    }
+
+    fn maintain(&self) {
+        self.next.maintain();
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        self.next.persist()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.next.next_due()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dns::message::RecordClass;
+    use super::super::handlers::HandleOpcode;
+    use super::super::listener::{QueryInfo, Transport};
+    use super::super::overload::Load;
+    use super::super::stats::Stats;
+    use super::super::testing::{extended_error_in, query, query_handler};
+    use super::*;
+
+    // Stands in for the authoritative data: knows the names it was built with.
+    struct Authority {
+        records: StaticRecords,
+    }
+
+    impl Resolve for Authority {
+        fn lookup(&self, _header: &Header, question: &Question) -> Resolved {
+            self.records.lookup(question).into()
+        }
+
+        fn provenance(&self, _question: &Question) -> String {
+            String::from("source=test")
+        }
+
+        fn answers_locally(&self, _question: &Question) -> bool {
+            true
+        }
+    }
+
+    fn record(name: &str, r#type: RecordType, data: &[u8]) -> Answer {
+        Answer::new(
+            /* name= */ &Arc::new(name.parse().unwrap()),
+            /* type= */ r#type,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */ &Arc::from(data),
+        )
+    }
+
+    fn resolver(records: StaticRecords) -> NxdomainRedirectResolver {
+        NxdomainRedirectResolver {
+            redirects: vec![NxdomainRedirect::parse("example.com=portal.example.com").unwrap()],
+            next: Box::new(Authority { records }),
+            redirected: ShardedCounter::default(),
+        }
+    }
+
+    fn lookup(resolver: &NxdomainRedirectResolver, name: &str) -> Lookup {
+        let question = Question::new(
+            &Arc::new(name.parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        );
+        resolver.lookup(&Header::default(), &question).lookup
+    }
+
+    #[test]
+    fn missing_names_under_the_domain_are_redirected() {
+        let mut records = StaticRecords::new();
+        records
+            .add(record("www.example.com", RecordType::A, &[192, 0, 2, 1]))
+            .add(record(
+                "portal.example.com",
+                RecordType::A,
+                &[192, 0, 2, 80],
+            ))
+            .add(record("mail.example.com", RecordType::Txt, &[0]));
+        let resolver = resolver(records);
+
+        let Lookup::Found(answers) = lookup(&resolver, "wwww.EXAMPLE.com") else {
+            panic!("expected a redirect");
+        };
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].get_type(), RecordType::Cname);
+        assert_eq!(answers[0].get_ttl(), REDIRECT_TTL);
+        assert_eq!(
+            answers[0].get_data(),
+            "portal.example.com"
+                .parse::<LabelSequence>()
+                .unwrap()
+                .encode()
+        );
+        assert_eq!(answers[1].get_data().as_ref(), [192, 0, 2, 80]);
+        assert_eq!(resolver.redirected.get(), 1);
+
+        // Names that exist are answered as they are, with or without data.
+        assert!(
+            matches!(lookup(&resolver, "www.example.com"), Lookup::Found(answers) if answers.len() == 1)
+        );
+        assert!(matches!(
+            lookup(&resolver, "mail.example.com"),
+            Lookup::FoundNoData
+        ));
+        assert_eq!(resolver.redirected.get(), 1);
+    }
+
+    #[test]
+    fn edns_clients_are_told_the_redirect_is_forged() {
+        let mut records = StaticRecords::new();
+        records.add(record("www.example.com", RecordType::A, &[192, 0, 2, 1]));
+        let handler = query_handler(resolver(records));
+        let ede = |name: &str, options: Option<&[u16]>| {
+            let request = query(name, options);
+            let info = QueryInfo {
+                listener: "test",
+                client: "127.0.0.1:5353".parse().unwrap(),
+                transport: Transport::Udp,
+                load: Load::Normal,
+            };
+            let header = Header::parse_from(request[..12].try_into().unwrap());
+            let response = handler
+                .handle(&info, &header, &request, &Stats::default())
+                .unwrap();
+            extended_error_in(&response)
+        };
+
+        assert_eq!(
+            ede("wwww.example.com", Some(&[])),
+            Some((
+                4,
+                String::from("nxdomain-redirect example.com.=portal.example.com")
+            ))
+        );
+        assert_eq!(ede("wwww.example.com", None), None);
+        assert_eq!(ede("www.example.com", Some(&[])), None);
+    }
+
+    #[test]
+    fn names_outside_owned_domains_are_never_redirected() {
+        let resolver = resolver(StaticRecords::new());
+        for name in [
+            "nonexistent.example.net",
+            "example.com.evil.test",
+            "notexample.com",
+        ] {
+            assert!(
+                matches!(lookup(&resolver, name), Lookup::NotFound),
+                "{}",
+                name
+            );
+        }
+        // Nor is the landing name itself, which would loop.
+        assert!(matches!(
+            lookup(&resolver, "portal.example.com"),
+            Lookup::NotFound
+        ));
+        assert_eq!(resolver.redirected.get(), 0);
+    }
+
+    #[test]
+    fn signed_domains_refuse_redirection() {
+        let redirect = NxdomainRedirect::parse("example.com=portal.example.com").unwrap();
+        let mut records = StaticRecords::new();
+        records.add(record("www.example.com", RecordType::A, &[192, 0, 2, 1]));
+        records.add(record(
+            "example.org",
+            RecordType::Unknown(48),
+            &[1, 1, 3, 8],
+        ));
+        assert!(redirect.check_unsigned(&records).is_ok());
+
+        records.add(record(
+            "example.com",
+            RecordType::Unknown(48),
+            &[1, 1, 3, 8],
+        ));
+        let err = redirect.check_unsigned(&records).unwrap_err();
+        assert!(err.contains("signed"), "{}", err);
+
+        assert!(NxdomainRedirect::parse("example.com").is_err());
+        assert!(NxdomainRedirect::parse(".=portal.example.com").is_err());
+    }
+}
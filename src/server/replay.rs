@@ -0,0 +1,344 @@
+// Replay of a capture (--capture) against the configuration given on the command
+// line, for regression testing: "queries broke at 14:32" becomes a capture that can
+// be replayed after every change, or in CI against a golden one. The captured queries
+// are handled again, at their original pacing or as fast as possible, by the live
+// upstream or by a CapturedResolver answering from the captured responses; the
+// latter tests the local configuration (policy, records, RPZ) alone.
+//
+// A response diverges from the captured one if its RCODE or its answer RRsets differ;
+// TTLs, the order of the records and the casing of names don't count (see
+// verify::diff_rrsets), as they change from one run to the next.
+//
+// A capture has one exchange per line: when it happened, in Unix milliseconds, then
+// the query and the response as they were sent, in hex.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::dns::message::{Header, Message, Question, RCode, RecordClass, RecordType};
+use super::edns::extended_rcode;
+use super::listener::{QueryInfo, Transport};
+use super::overload::Load;
+use super::verify::diff_rrsets;
+use super::{HandleOpcode, Lookup, Resolve, Resolved, Stats};
+
+pub struct Exchange {
+    // Unix milliseconds.
+    pub at: u64,
+    pub query: Vec<u8>,
+    pub response: Message,
+}
+
+pub fn capture_line(at: SystemTime, query: &[u8], response: &[u8]) -> String {
+    let millis = at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    format!("{} {} {}", millis, to_hex(query), to_hex(response))
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(digits: &str) -> Result<Vec<u8>, String> {
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| {
+            digits
+                .get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("'{}' is not hex", digits))
+        })
+        .collect()
+}
+
+// Reads a capture; blank lines and #-comments are skipped.
+pub fn read_capture(path: &Path) -> Result<Vec<Exchange>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+    let mut exchanges: Vec<Exchange> = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let exchange = parse_exchange(line)
+            .map_err(|err| format!("{}:{}: {}.", path.display(), index + 1, err))?;
+        exchanges.push(exchange);
+    }
+    Ok(exchanges)
+}
+
+fn parse_exchange(line: &str) -> Result<Exchange, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [at, query, response] = fields[..] else {
+        return Err(String::from("expected a time, a query and a response"));
+    };
+    let at: u64 = at
+        .parse()
+        .map_err(|_| format!("'{}' is not a time in Unix milliseconds", at))?;
+    let query = from_hex(query)?;
+    Message::parse_from(&query).map_err(|err| format!("malformed query: {}", err))?;
+    let response = Message::parse_from(&from_hex(response)?)
+        .map_err(|err| format!("malformed response: {}", err))?;
+    Ok(Exchange {
+        at,
+        query,
+        response,
+    })
+}
+
+// The questions of the exchanges, lowercased, with their type and class.
+type QuestionKey = (String, RecordType, RecordClass);
+
+fn question_key(question: &Question) -> QuestionKey {
+    (
+        question.get_name().to_string().to_ascii_lowercase(),
+        question.get_type(),
+        question.get_class(),
+    )
+}
+
+// Answers every question as it was answered in a capture, in place of the upstream;
+// questions the capture doesn't have fail. Of a question captured more than once,
+// the last response is used.
+pub struct CapturedResolver {
+    responses: HashMap<QuestionKey, Message>,
+}
+
+impl CapturedResolver {
+    pub fn new(exchanges: &[Exchange]) -> CapturedResolver {
+        let mut responses: HashMap<QuestionKey, Message> = HashMap::new();
+        for exchange in exchanges {
+            if let [question] = exchange.response.get_questions().as_ref() {
+                responses.insert(question_key(question), exchange.response.clone());
+            }
+        }
+        CapturedResolver { responses }
+    }
+}
+
+impl Resolve for CapturedResolver {
+    fn lookup(&self, _header: &Header, question: &Question) -> Resolved {
+        let Some(response) = self.responses.get(&question_key(question)) else {
+            return Lookup::Failed.into();
+        };
+        let answers = response.get_answers();
+        // As the forwarder reads the upstream's responses.
+        let lookup = match response.get_header().get_rcode() {
+            _ if !answers.is_empty() => Lookup::Found(answers.to_vec()),
+            RCode::NoError => Lookup::FoundNoData,
+            RCode::ServerError => Lookup::Failed,
+            _ => Lookup::NotFound,
+        };
+        Resolved {
+            header: Some(Arc::clone(response.get_header())),
+            authorities: response.get_authorities().clone(),
+            ..lookup.into()
+        }
+    }
+
+    fn provenance(&self, _question: &Question) -> String {
+        String::from("source=capture")
+    }
+
+    fn answers_locally(&self, _question: &Question) -> bool {
+        true
+    }
+}
+
+// A response that diverged from the captured one.
+pub struct Divergence {
+    // The question's name and type, and the captured RCODE.
+    pub group: String,
+    pub differences: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct ReplayReport {
+    pub replayed: usize,
+    pub divergences: Vec<Divergence>,
+}
+
+impl ReplayReport {
+    // The divergences grouped by name, type and RCODE, one group per line, the
+    // largest first, with the first difference of the group as an example.
+    pub fn summary(&self) -> Vec<String> {
+        let mut groups: Vec<(&str, usize, &str)> = Vec::new();
+        for divergence in &self.divergences {
+            match groups
+                .iter_mut()
+                .find(|(group, _, _)| *group == divergence.group)
+            {
+                Some((_, count, _)) => *count += 1,
+                None => groups.push((
+                    &divergence.group,
+                    1,
+                    divergence.differences.first().map_or("", String::as_str),
+                )),
+            }
+        }
+        groups.sort_by_key(|(group, count, _)| (std::cmp::Reverse(*count), *group));
+        groups
+            .iter()
+            .map(|(group, count, example)| {
+                format!("{}: {} divergence(s), e.g. {}", group, count, example)
+            })
+            .collect()
+    }
+}
+
+// Has `handler` answer the captured queries again, and compares its responses with
+// the captured ones. With `original_pacing`, the queries are spaced as they were
+// captured; otherwise they are handled back to back.
+pub fn replay(
+    handler: &dyn HandleOpcode,
+    exchanges: &[Exchange],
+    original_pacing: bool,
+) -> ReplayReport {
+    let info = QueryInfo {
+        listener: "replay",
+        client: "127.0.0.1:0".parse().expect("a valid address"),
+        transport: Transport::Udp,
+        load: Load::Normal,
+    };
+    let stats = Stats::default();
+    let started = Instant::now();
+    let first = exchanges.first().map_or(0, |exchange| exchange.at);
+    let mut report = ReplayReport::default();
+    for exchange in exchanges {
+        if original_pacing {
+            let due = Duration::from_millis(exchange.at.saturating_sub(first));
+            thread::sleep(due.saturating_sub(started.elapsed()));
+        }
+        let header = Header::parse_from(
+            exchange.query[..12]
+                .try_into()
+                .expect("captured queries parse"),
+        );
+        let response = handler.handle(&info, &header, &exchange.query, &stats);
+        report.replayed += 1;
+        let differences = compare(&exchange.response, response.as_ref());
+        if differences.is_empty() {
+            continue;
+        }
+        let captured = &exchange.response;
+        let question = captured
+            .get_questions()
+            .first()
+            .map_or(String::from("(no question)"), |question| {
+                format!("{} {}", question.get_name(), question.get_type())
+            });
+        report.divergences.push(Divergence {
+            group: format!("{} {}", question, extended_rcode(captured)),
+            differences,
+        });
+    }
+    report
+}
+
+fn compare(captured: &Message, replayed: Option<&Message>) -> Vec<String> {
+    let Some(replayed) = replayed else {
+        return vec![String::from("the query was dropped")];
+    };
+    let mut differences: Vec<String> = Vec::new();
+    let (expected, actual) = (extended_rcode(captured), extended_rcode(replayed));
+    if expected.to_string() != actual.to_string() {
+        differences.push(format!("rcode: expected {}, got {}", expected, actual));
+    }
+    differences.extend(diff_rrsets(captured.get_answers(), replayed.get_answers()));
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, path::PathBuf, sync::Mutex};
+
+    use super::super::testing::{dummy_server, forwarder, mock_upstream, query, query_handler};
+    use super::super::{LoopState, RotatingWriter};
+    use super::*;
+
+    fn fixture() -> Vec<Exchange> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("replay")
+            .join("example.capture");
+        read_capture(&path).unwrap()
+    }
+
+    #[test]
+    fn replaying_against_the_captured_answers_matches() {
+        let exchanges = fixture();
+        assert_eq!(exchanges.len(), 3);
+        let handler = query_handler(CapturedResolver::new(&exchanges));
+        let report = replay(&handler, &exchanges, false);
+        assert_eq!(report.replayed, 3);
+        assert!(report.summary().is_empty(), "{:?}", report.summary());
+
+        // At the original pacing, the replay takes as long as the capture did.
+        let started = Instant::now();
+        let report = replay(&handler, &exchanges[..2], true);
+        assert!(report.divergences.is_empty());
+        assert!(started.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn divergences_from_the_capture_are_grouped() {
+        let exchanges = fixture();
+        // An upstream that now answers every question with the one address.
+        let upstream = mock_upstream(&[[192, 0, 2, 1]], 60, Duration::ZERO);
+        let handler = query_handler(forwarder(upstream.local_addr().unwrap(), 10.0));
+        let mut report = replay(&handler, &exchanges, false);
+        assert_eq!(report.replayed, 3);
+        assert_eq!(report.divergences.len(), 2);
+        assert_eq!(
+            report.summary(),
+            [
+                "example.com A NO_ERROR (0): 1 divergence(s), e.g. answer section: expected 2 record(s), got 1",
+                "www.example.com AAAA NO_ERROR (0): 1 divergence(s), e.g. answer section: expected 0 record(s), got 1",
+            ]
+        );
+
+        // Twice the queries, twice the divergences in the same groups.
+        let again = replay(&handler, &exchanges, false);
+        report.divergences.extend(again.divergences);
+        assert_eq!(report.summary().len(), 2);
+        assert!(report.summary()[0].contains(": 2 divergence(s)"));
+    }
+
+    #[test]
+    fn the_server_captures_what_it_sends() {
+        let path: PathBuf =
+            std::env::temp_dir().join(format!("replay-{}-server.capture", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut server = dummy_server();
+        server.housekeeping.capture = Some(Arc::new(Mutex::new(
+            RotatingWriter::open(path.clone(), 1 << 20).unwrap(),
+        )));
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for name in ["example.com", "example.org"] {
+            client.send_to(&query(name, None), address).unwrap();
+        }
+        server
+            .run_once(&mut LoopState::new(&server.randomness))
+            .unwrap();
+        server.housekeeping.maintain(Instant::now());
+
+        let exchanges = read_capture(&path).unwrap();
+        assert_eq!(exchanges.len(), 2);
+        assert_eq!(exchanges[0].query, query("example.com", None));
+        assert_eq!(exchanges[1].response.get_answers().len(), 1);
+        // Replayed against the same configuration, nothing diverges.
+        let report = replay(server.handlers[0].as_ref(), &exchanges, false);
+        assert!(report.divergences.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
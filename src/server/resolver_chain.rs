@@ -0,0 +1,198 @@
+// Sources of answers composed in order of precedence. Each stage of a chain either
+// claims a question, and then has the last word on it, whether an answer (Found) or a
+// negative one (FoundNoData, or NotFound with the NXDOMAIN or REFUSED its header
+// says), or passes it on to the next stage. The first stage to claim a question is
+// the only one asked about it, for its answers as for its header, authorities and
+// provenance; the stages after it are never consulted. A question no stage claims
+// is NotFound.
+//
+// Stages that wrap another resolver pass on what they don't know to Fallthrough,
+// which claims nothing, to hand it back to the chain.
+
+use std::{io, time::Instant};
+
+use super::dns::message::{Header, Question};
+use super::{Lookup, Resolve, Resolved};
+
+pub struct ResolverChain {
+    pub stages: Vec<Box<dyn Resolve>>,
+}
+
+impl ResolverChain {
+    // The stage that claims `question`.
+    fn stage(&self, question: &Question) -> Option<&dyn Resolve> {
+        self.stages
+            .iter()
+            .find(|stage| stage.claims(question))
+            .map(|stage| stage.as_ref())
+    }
+}
+
+impl Resolve for ResolverChain {
+    fn lookup(&self, header: &Header, question: &Question) -> Resolved {
+        match self.stage(question) {
+            Some(stage) => stage.lookup(header, question),
+            None => Lookup::NotFound.into(),
+        }
+    }
+
+    fn claims(&self, question: &Question) -> bool {
+        self.stage(question).is_some()
+    }
+
+    fn provenance(&self, question: &Question) -> String {
+        match self.stage(question) {
+            Some(stage) => stage.provenance(question),
+            None => String::from("source=none"),
+        }
+    }
+
+    fn answers_locally(&self, question: &Question) -> bool {
+        self.stage(question)
+            .map_or(true, |stage| stage.answers_locally(question))
+    }
+
+    // Each stage is prefetched the questions it claims.
+    fn prefetch(&self, header: &Header, questions: &[Question]) {
+        let mut claimed: Vec<Vec<Question>> = vec![Vec::new(); self.stages.len()];
+        for question in questions {
+            if let Some(index) = self.stages.iter().position(|stage| stage.claims(question)) {
+                claimed[index].push(question.clone());
+            }
+        }
+        for (stage, questions) in self.stages.iter().zip(claimed) {
+            stage.prefetch(header, &questions);
+        }
+    }
+
+    fn maintain(&self) {
+        for stage in &self.stages {
+            stage.maintain();
+        }
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        for stage in &self.stages {
+            stage.persist()?;
+        }
+        Ok(())
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.stages
+            .iter()
+            .filter_map(|stage| stage.next_due())
+            .min()
+    }
+}
+
+// The end of a stage that wraps another resolver: claims nothing, so that what the
+// stage doesn't know goes on down the chain.
+pub struct Fallthrough;
+
+impl Resolve for Fallthrough {
+    fn lookup(&self, _header: &Header, _question: &Question) -> Resolved {
+        Lookup::NotFound.into()
+    }
+
+    fn claims(&self, _question: &Question) -> bool {
+        false
+    }
+
+    fn provenance(&self, _question: &Question) -> String {
+        String::from("source=none")
+    }
+
+    fn answers_locally(&self, _question: &Question) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::super::dns::message::{Answer, RData, RecordClass, RecordType};
+    use super::super::records::{LocalRecord, StaticDnsResolver, StaticRecords};
+    use super::super::testing::Counter;
+    use super::*;
+
+    // Answers every question, counting those it's asked.
+    struct Counting {
+        asked: Counter,
+    }
+
+    impl Resolve for Counting {
+        fn lookup(&self, _header: &Header, question: &Question) -> Resolved {
+            self.asked.increment();
+            Lookup::Found(vec![Answer::a(
+                question.get_name(),
+                60,
+                [203, 0, 113, 1].into(),
+            )])
+            .into()
+        }
+
+        fn provenance(&self, _question: &Question) -> String {
+            String::from("source=counting")
+        }
+
+        fn answers_locally(&self, _question: &Question) -> bool {
+            false
+        }
+    }
+
+    fn stage(records: &[&str]) -> Box<dyn Resolve> {
+        let mut local = StaticRecords::new();
+        for spec in records {
+            local.add(LocalRecord::parse(spec).unwrap().record().unwrap());
+        }
+        Box::new(StaticDnsResolver {
+            records: Arc::new(local),
+            next: Box::new(Fallthrough),
+        })
+    }
+
+    #[test]
+    fn the_first_stage_to_claim_a_question_answers_it() {
+        let asked = Counter::default();
+        let chain = ResolverChain {
+            stages: vec![
+                stage(&["printer.lan A 192.0.2.7"]),
+                stage(&["dev.local A 127.0.0.1", "dev.local TXT dev"]),
+                Box::new(Counting {
+                    asked: asked.clone(),
+                }),
+            ],
+        };
+        let question = |name: &str, r#type| {
+            Question::new(&Arc::new(name.parse().unwrap()), r#type, RecordClass::In)
+        };
+        let header = Header::default();
+
+        // The middle stage answers, NODATA included; the last is never consulted.
+        let dev = question("dev.local", RecordType::A);
+        let resolved = chain.lookup(&header, &dev);
+        assert!(resolved.header.as_ref().unwrap().get_aa());
+        let answers = resolved.into_answers();
+        assert!(matches!(
+            answers[0].get_rdata(),
+            RData::A(address) if address.octets() == [127, 0, 0, 1]
+        ));
+        assert_eq!(chain.provenance(&dev), "source=static");
+        let nodata = question("dev.local", RecordType::Aaaa);
+        assert!(matches!(
+            chain.lookup(&header, &nodata).lookup,
+            Lookup::FoundNoData
+        ));
+        assert_eq!(asked.get(), 0);
+
+        // What neither local stage claims falls through to the last.
+        let other = question("example.com", RecordType::A);
+        let resolved = chain.lookup(&header, &other);
+        assert!(resolved.header.is_none());
+        assert_eq!(resolved.into_answers().len(), 1);
+        assert_eq!(chain.provenance(&other), "source=counting");
+        assert_eq!(asked.get(), 1);
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+};
+
+use super::dns::message::{Answer, LabelSequence, LabelSequenceParseError, RData, RecordClass};
+
+const REVERSE_TTL: u32 = 3600;
+
+// A classless in-addr.arpa delegation (RFC 2317) of a block smaller than a /24,
+// given as "192.0.2.0/25". PTRs for the block live in a child zone named after
+// the block ("0/25.2.0.192.in-addr.arpa") and the parent zone carries a CNAME
+// per address pointing into it.
+#[derive(Clone, Debug)]
+pub struct ClasslessDelegation {
+    network: Ipv4Addr,
+    prefix_length: u8,
+}
+
+impl ClasslessDelegation {
+    pub fn parse(value: &str) -> Result<ClasslessDelegation, String> {
+        let (address, prefix_length) = value
+            .split_once('/')
+            .ok_or_else(|| format!("Delegation '{}' must look like 192.0.2.0/25.", value))?;
+        let network: Ipv4Addr = address
+            .parse()
+            .map_err(|_| format!("'{}' is not an IPv4 address.", address))?;
+        let prefix_length: u8 = prefix_length
+            .parse()
+            .ok()
+            .filter(|length| (25..=31).contains(length))
+            .ok_or_else(|| {
+                format!(
+                    "Classless delegation prefix must be from 25 to 31, but it is '{}'.",
+                    prefix_length
+                )
+            })?;
+        let delegation = ClasslessDelegation {
+            network,
+            prefix_length,
+        };
+        if u32::from(network) & !delegation.mask() != 0 {
+            return Err(format!(
+                "{} is not the first address of a /{} block.",
+                network, prefix_length
+            ));
+        }
+        Ok(delegation)
+    }
+
+    fn mask(&self) -> u32 {
+        u32::MAX << (32 - self.prefix_length)
+    }
+
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        u32::from(address) & self.mask() == u32::from(self.network)
+    }
+
+    // "0/25.2.0.192.in-addr.arpa" for 192.0.2.0/25.
+    pub fn child_zone(&self) -> String {
+        let [a, b, c, d] = self.network.octets();
+        format!(
+            "{}/{}.{}.{}.{}.in-addr.arpa",
+            d, self.prefix_length, c, b, a
+        )
+    }
+
+    // Where the PTR for `address` lives: "10.0/25.2.0.192.in-addr.arpa".
+    pub fn child_name(&self, address: Ipv4Addr) -> Result<LabelSequence, String> {
+        format!("{}.{}", address.octets()[3], self.child_zone())
+            .parse()
+            .map_err(|err: LabelSequenceParseError| err.message)
+    }
+
+    // The parent-zone CNAME for every address of the block.
+    pub fn glue_records(&self) -> Result<Vec<Answer>, String> {
+        let size: u32 = 1 << (32 - self.prefix_length);
+        (0..size)
+            .map(|offset| {
+                let address = Ipv4Addr::from(u32::from(self.network) + offset);
+                Ok(Answer::from_rdata(
+                    /* name= */ &Arc::new(LabelSequence::from_reverse_ipv4(address)),
+                    /* class= */ RecordClass::In,
+                    /* ttl= */ REVERSE_TTL,
+                    /* data= */ RData::Cname(self.child_name(address)?),
+                ))
+            })
+            .collect()
+    }
+}
+
+// A locally served PTR record, given as "192.0.2.10=host.example.com".
+#[derive(Clone, Debug)]
+pub struct ReverseMapping {
+    address: IpAddr,
+    target: String,
+}
+
+impl ReverseMapping {
+    pub fn parse(value: &str) -> Result<ReverseMapping, String> {
+        let (address, target) = value
+            .split_once('=')
+            .ok_or_else(|| format!("Reverse mapping '{}' must look like ADDRESS=NAME.", value))?;
+        let address: IpAddr = address
+            .trim()
+            .parse()
+            .map_err(|_| format!("'{}' is not an IP address.", address))?;
+        let target = target.trim().to_string();
+        target
+            .parse::<LabelSequence>()
+            .map_err(|err: LabelSequenceParseError| err.message)?;
+        Ok(ReverseMapping { address, target })
+    }
+
+    // The PTR record for the address, placed in the child zone of the first
+    // delegation covering it, or at the plain in-addr.arpa/ip6.arpa name otherwise.
+    pub fn ptr_record(&self, delegations: &[ClasslessDelegation]) -> Result<Answer, String> {
+        let name = match self.address {
+            IpAddr::V4(address) => match delegations
+                .iter()
+                .find(|delegation| delegation.contains(address))
+            {
+                Some(delegation) => delegation.child_name(address)?,
+                None => LabelSequence::from_reverse_ipv4(address),
+            },
+            IpAddr::V6(address) => LabelSequence::from_reverse_ipv6(address),
+        };
+        let target: LabelSequence = self
+            .target
+            .parse()
+            .map_err(|err: LabelSequenceParseError| err.message)?;
+        Ok(Answer::from_rdata(
+            /* name= */ &Arc::new(name),
+            /* class= */ RecordClass::In,
+            /* ttl= */ REVERSE_TTL,
+            /* data= */ RData::Ptr(target),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::super::dns::message::{Header, Message, Question, RecordType};
+    use super::super::records::StaticRecords;
+    use super::*;
+
+    // Encodes `name` as the question of a message and reads it back.
+    fn reread(name: &LabelSequence) -> LabelSequence {
+        let question = Question::new(&Arc::new(name.clone()), RecordType::Ptr, RecordClass::In);
+        let mut header = Header::default();
+        header.set_qd_count(1);
+        let message = Message::new(&Arc::new(header), &Arc::from([question]), &Arc::from([]));
+        let reread = Message::parse_from(&message.encode()).unwrap();
+        reread.get_questions()[0].get_name().as_ref().clone()
+    }
+
+    #[test]
+    fn reverse_names_round_trip() {
+        let address = Ipv4Addr::new(192, 0, 2, 10);
+        let name = LabelSequence::from_reverse_ipv4(address);
+        assert_eq!(name.to_string(), "10.2.0.192.in-addr.arpa");
+        for name in [
+            name.clone(),
+            reread(&name),
+            name.to_string().parse().unwrap(),
+        ] {
+            assert_eq!(name.to_reverse_ipv4(), Some(address));
+        }
+
+        // The longest reverse names: 32 nibble labels under ip6.arpa, 34 labels in all.
+        let address: Ipv6Addr = "2001:db8:ffff::abcd:1".parse().unwrap();
+        let name = LabelSequence::from_reverse_ipv6(address);
+        assert_eq!(name.get_labels().len(), 34);
+        assert_eq!(
+            name.to_string(),
+            "1.0.0.0.d.c.b.a.0.0.0.0.0.0.0.0.0.0.0.0.f.f.f.f.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+        assert_eq!(name.encode().len(), 74);
+        let upper: LabelSequence = name.to_string().to_ascii_uppercase().parse().unwrap();
+        for name in [name.clone(), reread(&name), upper] {
+            assert_eq!(name.to_reverse_ipv6(), Some(address));
+        }
+
+        for name in [
+            "10.2.0.192.in-addr.arpa.example",
+            "2.0.192.in-addr.arpa",
+            "010.2.0.192.in-addr.arpa",
+            "256.2.0.192.in-addr.arpa",
+            "10.2.0.192.ip6.arpa",
+        ] {
+            let name: LabelSequence = name.parse().unwrap();
+            assert_eq!(name.to_reverse_ipv4(), None, "{}", name);
+        }
+        let short = name.to_string().replacen("1.0.", "10.", 1);
+        let short: LabelSequence = short.parse().unwrap();
+        assert_eq!(short.to_reverse_ipv6(), None);
+    }
+
+    #[test]
+    fn static_records_answer_ptr_queries() {
+        let mut records = StaticRecords::new();
+        for mapping in [
+            "192.0.2.10=host.example.com",
+            "2001:db8::1=host6.example.com.",
+        ] {
+            records.add(
+                ReverseMapping::parse(mapping)
+                    .unwrap()
+                    .ptr_record(&[])
+                    .unwrap(),
+            );
+        }
+        for (name, shown) in [
+            (
+                "10.2.0.192.IN-ADDR.ARPA",
+                "10.2.0.192.in-addr.arpa.    3600    IN    PTR    host.example.com.",
+            ),
+            (
+                "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa",
+                "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa.    \
+                 3600    IN    PTR    host6.example.com.",
+            ),
+        ] {
+            let question = Question::new(
+                &Arc::new(name.parse().unwrap()),
+                RecordType::Ptr,
+                RecordClass::In,
+            );
+            let answers = records.lookup(&question).into_answers();
+            let shown_answers: Vec<String> = answers.iter().map(ToString::to_string).collect();
+            assert_eq!(shown_answers, [shown], "{}", name);
+        }
+
+        // A /25 delegation moves the PTR into the child zone.
+        let delegation = ClasslessDelegation::parse("192.0.2.0/25").unwrap();
+        let answer = ReverseMapping::parse("192.0.2.10=host.example.com")
+            .unwrap()
+            .ptr_record(&[delegation])
+            .unwrap();
+        assert_eq!(
+            answer.get_name().to_string(),
+            "10.0/25.2.0.192.in-addr.arpa"
+        );
+        assert!(ReverseMapping::parse("192.0.2.300=host.example.com").is_err());
+    }
+}
@@ -0,0 +1,1170 @@
+// Response Policy Zones (--rpz, draft-vixie-dnsop-dns-rpz): firewall-style policies
+// shipped as zone files, e.g. by threat-intelligence feeds. Each record names a
+// trigger by its owner and encodes what to do about it in its data:
+//
+//     bad.example         CNAME .               NXDOMAIN
+//     *.bad.example       CNAME .               NXDOMAIN for the names under it
+//     empty.example       CNAME *.              NODATA
+//     ok.bad.example      CNAME rpz-passthru.   answer as usual
+//     quiet.example       CNAME rpz-drop.       no response at all
+//     big.example         CNAME rpz-tcp-only.   TC over UDP, answer over TCP
+//     walled.example      A 192.0.2.1           answer with these records instead
+//     24.0.2.0.192.rpz-ip CNAME .               any of the above, for answers with
+//                                               addresses in 192.0.2.0/24
+//
+// Owners are relative to the zone's name ($ORIGIN, or the owner of its SOA). QNAME
+// triggers apply before resolution, so that a blocked name never reaches the
+// upstream; IP triggers (rpz-ip) apply to the addresses in the answers, after it.
+// Zones are tried in the order given, and the first that has a trigger for a query
+// decides; within a zone, an exact name beats a wildcard and a longer prefix a
+// shorter one. NSDNAME, NSIP and client IP triggers aren't supported yet, and are
+// skipped when loading. Zone files can $INCLUDE others and $GENERATE records (see
+// zone_file.rs), and are reloaded when they, or the files they include, change.
+//
+// A zone can apply only at certain times, given by a $SCHEDULE line (see
+// src/server/schedule.rs); outside of them its triggers are skipped:
+//
+//     $SCHEDULE mon-fri 20:00-07:00 Europe/Berlin
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use super::dns::message::{
+    Answer, Header, LabelSequence, LabelSequenceParseError, Message, Question, RCode, RData,
+    RecordClass, RecordType,
+};
+use super::handlers::response_header;
+use super::listener::Transport;
+use super::pins::parse_rdata;
+use super::policy::{parse_record_type, PolicyOutcome};
+use super::schedule::Schedule;
+use super::signature_time::{Clock, SystemClock};
+use super::stats::ShardedCounter;
+use super::support::{Json, ToJson};
+use super::zone_file::{self, split_fields, ZoneEvent, ZoneSource};
+
+// TTL of records that give none, when the zone has no $TTL either.
+const DEFAULT_TTL: u32 = 300;
+
+// A record as written: its type, TTL and the fields of its data.
+type RecordFields = (RecordType, u32, Vec<String>);
+
+#[derive(Clone, Debug)]
+pub enum RpzAction {
+    NxDomain,
+    NoData,
+    Passthru,
+    Drop,
+    TcpOnly,
+    // The records to answer with, owned by the trigger.
+    LocalData(Vec<Answer>),
+}
+
+impl fmt::Display for RpzAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpzAction::NxDomain => write!(f, "NXDOMAIN"),
+            RpzAction::NoData => write!(f, "NODATA"),
+            RpzAction::Passthru => write!(f, "PASSTHRU"),
+            RpzAction::Drop => write!(f, "DROP"),
+            RpzAction::TcpOnly => write!(f, "TCP-Only"),
+            RpzAction::LocalData(_) => write!(f, "Local-Data"),
+        }
+    }
+}
+
+// An IP trigger: the answers with an address in the prefix.
+#[derive(Clone, Debug)]
+struct AddressTrigger {
+    network: IpAddr,
+    length: u8,
+    action: RpzAction,
+}
+
+impl AddressTrigger {
+    fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.length as u32).unwrap_or(0);
+                u32::from(network) == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.length as u32).unwrap_or(0);
+                u128::from(network) == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// The triggers of one policy zone, as read from its file.
+#[derive(Debug)]
+pub struct PolicyZone {
+    pub name: Arc<LabelSequence>,
+    // Added to the additional section of rewritten responses, as BIND does, so that
+    // a client can tell which zone rewrote them.
+    soa: Option<Answer>,
+    // QNAME triggers by owner relative to the zone, lowercase, e.g. "*.bad.example".
+    names: HashMap<String, RpzAction>,
+    addresses: Vec<AddressTrigger>,
+    // Records of the trigger types that aren't supported.
+    pub skipped: usize,
+    // When the zone applies, if not always.
+    pub schedule: Option<Schedule>,
+}
+
+// Makes `name` absolute (lowercase, without the trailing dot).
+pub(super) fn absolute(name: &str, origin: Option<&str>) -> Result<String, String> {
+    let name = name.to_ascii_lowercase();
+    if name == "@" {
+        return origin
+            .map(str::to_string)
+            .ok_or_else(|| String::from("'@' needs an $ORIGIN."));
+    }
+    if let Some(name) = name.strip_suffix('.') {
+        return Ok(name.to_string());
+    }
+    match origin {
+        Some("") => Ok(name),
+        Some(origin) => Ok(format!("{}.{}", name, origin)),
+        None => Err(format!("'{}' is relative, but there is no $ORIGIN.", name)),
+    }
+}
+
+fn parse_name(name: &str) -> Result<LabelSequence, String> {
+    name.parse()
+        .map_err(|err: LabelSequenceParseError| err.message)
+}
+
+// Reads the prefix of an rpz-ip trigger: the prefix length, then the address with
+// its labels reversed, e.g. "24.0.2.0.198" or "48.zz.db8.2001" (zz stands for ::).
+fn parse_prefix(trigger: &str) -> Result<(IpAddr, u8), String> {
+    let invalid = || format!("'{}' is not an rpz-ip prefix.", trigger);
+    let mut labels: Vec<&str> = trigger.split('.').collect();
+    let length: u8 = labels.remove(0).parse().map_err(|_| invalid())?;
+    labels.reverse();
+    let network: IpAddr =
+        if labels.len() == 4 && labels.iter().all(|label| label.parse::<u8>().is_ok()) {
+            if length > 32 {
+                return Err(invalid());
+            }
+            IpAddr::V4(
+                labels
+                    .join(".")
+                    .parse::<Ipv4Addr>()
+                    .map_err(|_| invalid())?,
+            )
+        } else {
+            let mut text = labels
+                .iter()
+                .map(|label| if *label == "zz" { "" } else { label })
+                .collect::<Vec<&str>>()
+                .join(":");
+            if text.starts_with(':') {
+                text.insert(0, ':');
+            }
+            if text.ends_with(':') {
+                text.push(':');
+            }
+            if length > 128 {
+                return Err(invalid());
+            }
+            IpAddr::V6(text.parse::<Ipv6Addr>().map_err(|_| invalid())?)
+        };
+    let canonical = AddressTrigger {
+        network,
+        length,
+        action: RpzAction::Drop,
+    };
+    if !canonical.contains(network) {
+        return Err(format!(
+            "'{}' has bits set past its prefix length.",
+            trigger
+        ));
+    }
+    Ok((network, length))
+}
+
+// The SOA of the zone, from its presentation form.
+fn soa_record(name: &Arc<LabelSequence>, ttl: u32, values: &[String]) -> Result<Answer, String> {
+    let [mname, rname, numbers @ ..] = values else {
+        return Err(String::from("An SOA needs names and five numbers."));
+    };
+    if numbers.len() != 5 {
+        return Err(String::from("An SOA needs names and five numbers."));
+    }
+    let mut parsed: Vec<u32> = Vec::new();
+    for number in numbers {
+        let number: u32 = number
+            .parse()
+            .map_err(|_| format!("'{}' is not a number.", number))?;
+        parsed.push(number);
+    }
+    Ok(Answer::from_rdata(
+        /* name= */ name,
+        /* class= */ RecordClass::In,
+        /* ttl= */ ttl,
+        /* data= */
+        RData::Soa {
+            mname: parse_name(mname)?,
+            rname: parse_name(rname)?,
+            serial: parsed[0],
+            refresh: parsed[1],
+            retry: parsed[2],
+            expire: parsed[3],
+            minimum: parsed[4],
+        },
+    ))
+}
+
+// The action a trigger's records encode.
+fn action(owner: &str, records: Vec<RecordFields>) -> Result<RpzAction, String> {
+    let special = |values: &[String]| -> Option<RpzAction> {
+        match values {
+            [target] => match target.to_ascii_lowercase().as_str() {
+                "." => Some(RpzAction::NxDomain),
+                "*." => Some(RpzAction::NoData),
+                "rpz-passthru." => Some(RpzAction::Passthru),
+                "rpz-drop." => Some(RpzAction::Drop),
+                "rpz-tcp-only." => Some(RpzAction::TcpOnly),
+                _ => None,
+            },
+            _ => None,
+        }
+    };
+    if let [(RecordType::Cname, _, values)] = records.as_slice() {
+        if let Some(action) = special(values) {
+            return Ok(action);
+        }
+    }
+    let name: Arc<LabelSequence> = Arc::new(parse_name(owner.trim_start_matches("*."))?);
+    let mut answers: Vec<Answer> = Vec::new();
+    for (r#type, ttl, values) in &records {
+        if *r#type == RecordType::Cname && special(values).is_some() {
+            return Err(format!(
+                "{}: a policy action can't be mixed with other records.",
+                owner
+            ));
+        }
+        let values: Vec<&str> = values.iter().map(String::as_str).collect();
+        for data in parse_rdata(*r#type, &values)? {
+            answers.push(Answer::new(
+                /* name= */ &name,
+                /* type= */ *r#type,
+                /* class= */ RecordClass::In,
+                /* ttl= */ *ttl,
+                /* data= */ &data,
+            ));
+        }
+    }
+    Ok(RpzAction::LocalData(answers))
+}
+
+impl PolicyZone {
+    #[cfg(test)]
+    pub fn parse(source: &str) -> Result<PolicyZone, String> {
+        PolicyZone::from_source(zone_file::from_text(source)?)
+    }
+
+    // The zone of a file as read, with its includes and generated records.
+    pub fn from_source(source: ZoneSource) -> Result<PolicyZone, String> {
+        let mut origin: Option<String> = None;
+        let mut default_ttl: Option<u32> = None;
+        let mut owner: Option<String> = None;
+        let mut soa: Option<(u32, Vec<String>)> = None;
+        let mut schedule: Option<Schedule> = None;
+        // The records of each trigger owner, in order of appearance.
+        let mut triggers: Vec<(String, Vec<RecordFields>)> = Vec::new();
+        // The origin and owner of each file including the one being read.
+        let mut including: Vec<(Option<String>, Option<String>)> = Vec::new();
+        for event in source.events {
+            let (location, line) = match event {
+                ZoneEvent::Line { location, text } => (location, text),
+                ZoneEvent::Include {
+                    location,
+                    origin: given,
+                } => {
+                    including.push((origin.clone(), owner.clone()));
+                    if let Some(given) = given {
+                        let given = absolute(&given, origin.as_deref())
+                            .map_err(|err| format!("{}: {}", location, err))?;
+                        origin = Some(given);
+                    }
+                    continue;
+                }
+                ZoneEvent::EndInclude => {
+                    // An origin first set by the included file's SOA stays.
+                    if let Some((outer, outer_owner)) = including.pop() {
+                        origin = outer.or(origin);
+                        owner = outer_owner;
+                    }
+                    continue;
+                }
+            };
+            let at = |err: String| format!("{}: {}", location, err);
+            let mut fields = split_fields(&line);
+            if fields.is_empty() {
+                continue;
+            }
+            match fields[0].to_ascii_uppercase().as_str() {
+                "$ORIGIN" => {
+                    let name = fields
+                        .get(1)
+                        .ok_or_else(|| at("$ORIGIN needs a name.".into()))?;
+                    origin = Some(absolute(name, origin.as_deref()).map_err(at)?);
+                    continue;
+                }
+                "$TTL" => {
+                    let ttl = fields.get(1).and_then(|ttl| ttl.parse().ok());
+                    default_ttl = Some(ttl.ok_or_else(|| at("$TTL needs a number.".into()))?);
+                    continue;
+                }
+                "$SCHEDULE" => {
+                    schedule = Some(Schedule::parse(&fields[1..].join(" ")).map_err(at)?);
+                    continue;
+                }
+                _ => {}
+            }
+            if !line.starts_with(char::is_whitespace) {
+                owner = Some(fields.remove(0));
+            }
+            let Some(name) = owner.clone() else {
+                return Err(at(String::from("The first record needs an owner.")));
+            };
+            // [TTL] [CLASS] TYPE DATA..., TTL and class in either order.
+            let mut ttl: Option<u32> = None;
+            while let Some(field) = fields.first() {
+                if let Ok(value) = field.parse() {
+                    ttl = Some(value);
+                } else if field.parse::<RecordClass>().ok() != Some(RecordClass::In) {
+                    break;
+                }
+                fields.remove(0);
+            }
+            if fields.is_empty() {
+                return Err(at(format!("{} has no type.", name)));
+            }
+            let r#type = parse_record_type(&fields.remove(0)).map_err(at)?;
+            let ttl = ttl.or(default_ttl).unwrap_or(DEFAULT_TTL);
+            if r#type == RecordType::Soa && origin.is_none() {
+                origin = Some(absolute(&name, None).map_err(at)?);
+            }
+            let name = absolute(&name, origin.as_deref()).map_err(at)?;
+            let origin = origin
+                .as_deref()
+                .ok_or_else(|| at(String::from("The zone needs an $ORIGIN or an SOA first.")))?;
+            let relative = if name == origin {
+                ""
+            } else if origin.is_empty() {
+                name.as_str()
+            } else {
+                name.strip_suffix(&format!(".{}", origin))
+                    .ok_or_else(|| at(format!("{} is outside of {}.", name, origin)))?
+            };
+            match r#type {
+                RecordType::Soa if relative.is_empty() => soa = Some((ttl, fields)),
+                // The apex's NS records, and anything else there, trigger nothing.
+                _ if relative.is_empty() => {}
+                _ => match triggers.iter_mut().find(|(owner, _)| owner == relative) {
+                    Some((_, records)) => records.push((r#type, ttl, fields)),
+                    None => triggers.push((relative.to_string(), vec![(r#type, ttl, fields)])),
+                },
+            }
+        }
+        let origin = origin.ok_or_else(|| String::from("The zone needs an $ORIGIN or an SOA."))?;
+        let name: Arc<LabelSequence> = Arc::new(parse_name(&origin)?);
+        let soa = soa
+            .map(|(ttl, values)| soa_record(&name, ttl, &values))
+            .transpose()?;
+
+        let mut zone = PolicyZone {
+            name,
+            soa,
+            names: HashMap::new(),
+            addresses: Vec::new(),
+            skipped: 0,
+            schedule,
+        };
+        for (owner, records) in triggers {
+            let kind = owner.rsplit('.').next().unwrap_or_default();
+            match kind {
+                "rpz-nsdname" | "rpz-nsip" | "rpz-client-ip" => zone.skipped += records.len(),
+                "rpz-ip" => {
+                    let prefix = owner.strip_suffix(".rpz-ip").unwrap_or_default();
+                    let (network, length) = parse_prefix(prefix)?;
+                    zone.addresses.push(AddressTrigger {
+                        network,
+                        length,
+                        action: action(&owner, records)?,
+                    });
+                }
+                _ => {
+                    let action = action(&owner, records)?;
+                    zone.names.insert(owner, action);
+                }
+            }
+        }
+        // Longest prefixes first, so that the first match is the most specific.
+        zone.addresses
+            .sort_by_key(|trigger| std::cmp::Reverse(trigger.length));
+        Ok(zone)
+    }
+
+    // The QNAME trigger for `name` and its action: the exact name, or else the
+    // closest wildcard above it.
+    fn find_name(&self, name: &LabelSequence) -> Option<(String, &RpzAction)> {
+        let name = name.to_string().to_ascii_lowercase();
+        if let Some(action) = self.names.get(&name) {
+            return Some((name, action));
+        }
+        let mut rest = name.as_str();
+        while let Some((_, parent)) = rest.split_once('.') {
+            let wildcard = format!("*.{}", parent);
+            if let Some(action) = self.names.get(&wildcard) {
+                return Some((wildcard, action));
+            }
+            rest = parent;
+        }
+        None
+    }
+
+    // The IP trigger for the first answer address it covers, most specific first.
+    fn find_address(&self, answers: &[Answer]) -> Option<(String, &RpzAction)> {
+        let addresses: Vec<IpAddr> = answers
+            .iter()
+            .filter_map(|answer| match answer.get_type() {
+                RecordType::A => <[u8; 4]>::try_from(answer.get_data().as_ref())
+                    .ok()
+                    .map(IpAddr::from),
+                RecordType::Aaaa => <[u8; 16]>::try_from(answer.get_data().as_ref())
+                    .ok()
+                    .map(IpAddr::from),
+                _ => None,
+            })
+            .collect();
+        self.addresses.iter().find_map(|trigger| {
+            addresses
+                .iter()
+                .find(|&&address| trigger.contains(address))
+                .map(|_| {
+                    (
+                        format!("{}/{}", trigger.network, trigger.length),
+                        &trigger.action,
+                    )
+                })
+        })
+    }
+}
+
+// A trigger that matched a query, and what to do about it.
+#[derive(Debug)]
+pub struct RpzHit {
+    pub zone: Arc<LabelSequence>,
+    pub trigger: String,
+    pub action: RpzAction,
+    soa: Option<Answer>,
+    censored: bool,
+}
+
+// What becomes of a query a trigger matched.
+pub enum Rewrite {
+    // Answered as if no trigger had matched.
+    Pass,
+    Drop,
+    Respond(Message),
+}
+
+impl RpzHit {
+    // The provenance annotation of a rewritten response.
+    pub fn trace(&self) -> String {
+        format!(
+            "source=rpz zone={} trigger={} action={}",
+            self.zone, self.trigger, self.action
+        )
+    }
+
+    // What clients are told about the rewrite: blocks are Filtered (or Censored),
+    // local data is Forged. None if the query is answered as usual, dropped, or
+    // sent to TCP.
+    pub fn outcome(&self) -> Option<PolicyOutcome> {
+        let rule = format!("rpz {} in {}", self.trigger, self.zone);
+        match self.action {
+            RpzAction::NxDomain | RpzAction::NoData if self.censored => {
+                Some(PolicyOutcome::censored(rule))
+            }
+            RpzAction::NxDomain | RpzAction::NoData => Some(PolicyOutcome::filtered(rule)),
+            RpzAction::LocalData(_) => Some(PolicyOutcome::forged(rule)),
+            RpzAction::Passthru | RpzAction::Drop | RpzAction::TcpOnly => None,
+        }
+    }
+
+    // Rewrites the response to `request`, for its first question.
+    pub fn rewrite(&self, request: &Message, transport: Transport) -> Rewrite {
+        let question = &request.get_questions()[0];
+        let mut header: Header = response_header(request.get_header(), RCode::NoError);
+        header.set_qd_count(request.get_header().get_qd_count());
+        let answers: Vec<Answer> = match &self.action {
+            RpzAction::Passthru => return Rewrite::Pass,
+            RpzAction::TcpOnly if transport == Transport::Tcp => return Rewrite::Pass,
+            RpzAction::Drop => return Rewrite::Drop,
+            // Tells the client to retry over TCP, which spoofed sources can't.
+            RpzAction::TcpOnly => {
+                header.set_tc(true);
+                return Rewrite::Respond(Message::new(
+                    &header.into(),
+                    request.get_questions(),
+                    &Arc::from([]),
+                ));
+            }
+            RpzAction::NxDomain => {
+                header.set_rcode(RCode::NameError);
+                Vec::new()
+            }
+            RpzAction::NoData => Vec::new(),
+            RpzAction::LocalData(records) => local_answers(question, records),
+        };
+        header.set_an_count(answers.len() as u16);
+        let response = Message::new(&header.into(), request.get_questions(), &answers.into());
+        Rewrite::Respond(match &self.soa {
+            Some(soa) => response.with_additionals(&Arc::from([soa.clone()])),
+            None => response,
+        })
+    }
+}
+
+// The local data answering `question`: the records of its type, or else a CNAME,
+// owned by the question's name.
+fn local_answers(question: &Question, records: &[Answer]) -> Vec<Answer> {
+    let of_type = |r#type: RecordType| -> Vec<Answer> {
+        records
+            .iter()
+            .filter(|record| record.get_type() == r#type || question.get_type() == RecordType::Any)
+            .map(|record| record.with_name(question.get_name()))
+            .collect()
+    };
+    let answers = of_type(question.get_type());
+    if answers.is_empty() {
+        of_type(RecordType::Cname)
+    } else {
+        answers
+    }
+}
+
+// A policy zone loaded from a file, reloaded when the file changes.
+struct LoadedZone {
+    path: PathBuf,
+    // The text of the file and of those it includes.
+    source: Mutex<String>,
+    zone: Mutex<Arc<PolicyZone>>,
+    // Queries its triggers matched, over reloads.
+    hits: ShardedCounter,
+}
+
+// The policy zones given with --rpz, in order.
+pub struct ResponsePolicy {
+    zones: Vec<LoadedZone>,
+    // Whether blocks are reported to clients as Censored rather than Filtered.
+    pub censored: bool,
+    // Tells whether scheduled zones apply.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl ResponsePolicy {
+    pub fn load(paths: &[PathBuf]) -> Result<ResponsePolicy, String> {
+        let mut zones: Vec<LoadedZone> = Vec::new();
+        for path in paths {
+            let in_file = |err: String| format!("{}: {}", path.display(), err);
+            let source = zone_file::read(path).map_err(in_file)?;
+            let text = source.text.clone();
+            let zone = PolicyZone::from_source(source).map_err(in_file)?;
+            zones.push(LoadedZone {
+                path: path.clone(),
+                source: Mutex::new(text),
+                zone: Mutex::new(Arc::new(zone)),
+                hits: ShardedCounter::default(),
+            });
+        }
+        Ok(ResponsePolicy {
+            zones,
+            censored: false,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    // Rereads the zone files that changed, and returns how many were reloaded. A
+    // file that can't be read or no longer parses is reported and its zone kept.
+    pub fn reload(&self) -> usize {
+        let mut reloaded = 0;
+        for loaded in &self.zones {
+            let source = match zone_file::read(&loaded.path) {
+                Ok(source) => source,
+                Err(err) => {
+                    println!(
+                        "[RPZ] {}: {}; keeping the loaded zone.",
+                        loaded.path.display(),
+                        err
+                    );
+                    continue;
+                }
+            };
+            if source.text == *loaded.source.lock().unwrap() {
+                continue;
+            }
+            *loaded.source.lock().unwrap() = source.text.clone();
+            let parsed = PolicyZone::from_source(source);
+            match parsed {
+                Ok(zone) => {
+                    println!(
+                        "[RPZ] Reloaded {} from {}.",
+                        zone.name,
+                        loaded.path.display()
+                    );
+                    *loaded.zone.lock().unwrap() = Arc::new(zone);
+                    reloaded += 1;
+                }
+                Err(err) => println!(
+                    "[RPZ] {}: {}; keeping the loaded zone.",
+                    loaded.path.display(),
+                    err
+                ),
+            }
+        }
+        reloaded
+    }
+
+    // Looks for the first zone in effect with a trigger, by `find`, and counts the hit.
+    fn check(
+        &self,
+        subject: &dyn fmt::Display,
+        find: impl Fn(&PolicyZone) -> Option<(String, &RpzAction)>,
+    ) -> Option<RpzHit> {
+        self.zones.iter().find_map(|loaded| {
+            let zone = Arc::clone(&loaded.zone.lock().unwrap());
+            if let Some(schedule) = &zone.schedule {
+                if !schedule.is_active(self.clock.as_ref()) {
+                    return None;
+                }
+            }
+            let (trigger, action) = find(&zone)?;
+            loaded.hits.increment();
+            println!(
+                "[RPZ] {} matched {} in {}: {} ({} hit(s) in the zone).",
+                subject,
+                trigger,
+                zone.name,
+                action,
+                loaded.hits.get()
+            );
+            Some(RpzHit {
+                zone: Arc::clone(&zone.name),
+                trigger,
+                action: action.clone(),
+                soa: zone.soa.clone(),
+                censored: self.censored,
+            })
+        })
+    }
+
+    // The QNAME trigger for the request's first question; applied before resolution.
+    pub fn check_qname(&self, questions: &[Question]) -> Option<RpzHit> {
+        let question = questions.first()?;
+        self.check(question, |zone| zone.find_name(question.get_name()))
+    }
+
+    // The IP trigger for the addresses in the answers; applied after resolution.
+    pub fn check_addresses(&self, questions: &[Question], answers: &[Answer]) -> Option<RpzHit> {
+        let question = questions.first()?;
+        self.check(question, |zone| zone.find_address(answers))
+    }
+
+    // Every zone, one per line, with its triggers and hits.
+    pub fn report(&self) -> Vec<String> {
+        self.zones
+            .iter()
+            .map(|loaded| {
+                let zone = loaded.zone.lock().unwrap();
+                format!(
+                    "{} qname={} ip={} skipped={} hits={} ({})",
+                    zone.name,
+                    zone.names.len(),
+                    zone.addresses.len(),
+                    zone.skipped,
+                    loaded.hits.get(),
+                    loaded.path.display()
+                )
+            })
+            .collect()
+    }
+
+    // The scheduled zones, one per line, with whether they apply and until when.
+    pub fn schedules(&self) -> Vec<String> {
+        self.zones
+            .iter()
+            .filter_map(|loaded| {
+                let zone = loaded.zone.lock().unwrap();
+                let schedule = zone.schedule.as_ref()?;
+                Some(format!(
+                    "rpz {} \"{}\": {}",
+                    zone.name,
+                    schedule,
+                    schedule.status(self.clock.as_ref())
+                ))
+            })
+            .collect()
+    }
+}
+
+// The zones, for support bundles.
+impl ToJson for ResponsePolicy {
+    fn to_json(&self) -> Json {
+        Json::Array(
+            self.zones
+                .iter()
+                .map(|loaded| {
+                    let zone = loaded.zone.lock().unwrap();
+                    let serial = zone.soa.as_ref().and_then(|soa| match soa.get_rdata() {
+                        RData::Soa { serial, .. } => Some(u64::from(*serial)),
+                        _ => None,
+                    });
+                    Json::object([
+                        ("name", Json::from(zone.name.to_string())),
+                        ("path", Json::from(loaded.path.display().to_string())),
+                        ("serial", Json::from(serial)),
+                        ("qname_triggers", Json::from(zone.names.len() as u64)),
+                        ("ip_triggers", Json::from(zone.addresses.len() as u64)),
+                        ("skipped", Json::from(zone.skipped as u64)),
+                        ("hits", Json::from(loaded.hits.get())),
+                        (
+                            "schedule",
+                            Json::from(zone.schedule.as_ref().map(ToString::to_string)),
+                        ),
+                    ])
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::super::handlers::{HandleOpcode, QueryOpcodeHandler};
+    use super::super::listener::QueryInfo;
+    use super::super::overload::Load;
+    use super::super::provenance::DEFAULT_PROVENANCE_OPTION;
+    use super::super::records::{StaticDnsResolver, StaticRecords};
+    use super::super::stats::Stats;
+    use super::super::testing::{extended_error_in, query, query_handler};
+    use super::super::DummyDnsResolver;
+    use super::*;
+
+    // A feed exercising every trigger and action.
+    const FEED: &str = r#"
+$TTL 300
+$ORIGIN rpz.example.
+@           SOA localhost. hostmaster.localhost. ( 2026101601 3600 600
+                86400 60 )      ; serial refresh retry expire minimum
+            NS  localhost.
+
+; QNAME triggers
+blocked.test        CNAME .
+*.blocked.test      CNAME .
+ok.blocked.test     CNAME rpz-passthru.
+empty.test          CNAME *.
+quiet.test          CNAME rpz-drop.
+big.test            CNAME rpz-tcp-only.
+walled.test         A     192.0.2.80
+                    A     192.0.2.81
+                    TXT   "walled garden; keep out"
+alias.test     60   CNAME garden.example.
+
+; IP triggers
+24.0.100.51.198.rpz-ip  CNAME .
+32.5.113.0.203.rpz-ip   A 192.0.2.80
+48.zz.db8.2001.rpz-ip   CNAME *.
+
+; Not supported yet
+ns.evil.test.rpz-nsdname  CNAME .
+"#;
+
+    // A zone file of its own for each test, as they run in parallel.
+    fn zone_file(name: &str, source: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rpz-{}-{}.zone", std::process::id(), name));
+        fs::write(&path, source).unwrap();
+        path
+    }
+
+    fn record(name: &str, r#type: RecordType, data: &[u8]) -> Answer {
+        Answer::new(
+            /* name= */ &Arc::new(name.parse().unwrap()),
+            /* type= */ r#type,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */ &Arc::from(data),
+        )
+    }
+
+    // Resolves a few names to addresses the IP triggers cover, and the rest to
+    // 8.8.8.8.
+    fn handler(rpz: ResponsePolicy) -> QueryOpcodeHandler {
+        let mut records = StaticRecords::new();
+        records
+            .add(record("hosted.test", RecordType::A, &[198, 51, 100, 7]))
+            .add(record("ok.blocked.test", RecordType::A, &[198, 51, 100, 9]))
+            .add(record("cdn.test", RecordType::A, &[203, 0, 113, 5]))
+            .add(record(
+                "v6.test",
+                RecordType::Aaaa,
+                &"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets(),
+            ));
+        QueryOpcodeHandler {
+            rpz: Some(Arc::new(rpz)),
+            ..query_handler(StaticDnsResolver {
+                records: Arc::new(records),
+                next: Box::new(DummyDnsResolver::default()),
+            })
+        }
+    }
+
+    // The response to a query for `name` `type`, over `transport`.
+    fn ask(
+        handler: &QueryOpcodeHandler,
+        name: &str,
+        r#type: RecordType,
+        transport: Transport,
+    ) -> Option<Message> {
+        let mut request = query(name, None);
+        let at = request.len() - 4;
+        request[at..at + 2].copy_from_slice(&u16::from(r#type).to_be_bytes());
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport,
+            load: Load::Normal,
+        };
+        let header = Header::parse_from(request[..12].try_into().unwrap());
+        handler.handle(&info, &header, &request, &Stats::default())
+    }
+
+    // The RCODE, the answers as "TYPE DATA", and whether the zone's SOA came along.
+    fn outcome(response: &Message) -> (u16, Vec<String>, bool) {
+        let answers = response
+            .get_answers()
+            .iter()
+            .map(|answer| {
+                assert_eq!(
+                    answer.get_name().to_string(),
+                    response.get_questions()[0].get_name().to_string()
+                );
+                let data = match answer.get_type() {
+                    RecordType::A => {
+                        Ipv4Addr::from(<[u8; 4]>::try_from(answer.get_data().as_ref()).unwrap())
+                            .to_string()
+                    }
+                    RecordType::Cname => LabelSequence::decode_uncompressed(&answer.get_data())
+                        .unwrap()
+                        .0
+                        .to_string(),
+                    _ => String::from_utf8_lossy(&answer.get_data()[1..]).to_string(),
+                };
+                format!("{} {}", answer.get_type(), data)
+            })
+            .collect();
+        let soa = response.get_additionals().iter().any(|additional| {
+            additional.get_type() == RecordType::Soa
+                && additional.get_name().to_string() == "rpz.example"
+        });
+        (u16::from(response.get_header().get_rcode()), answers, soa)
+    }
+
+    #[test]
+    fn triggers_rewrite_the_responses_clients_see() {
+        let path = zone_file("feed", FEED);
+        let handler = handler(ResponsePolicy::load(std::slice::from_ref(&path)).unwrap());
+        let udp = |name: &str, r#type: RecordType| {
+            outcome(&ask(&handler, name, r#type, Transport::Udp).unwrap())
+        };
+
+        // QNAME triggers, exact names ahead of wildcards.
+        assert_eq!(udp("blocked.test", RecordType::A), (3, vec![], true));
+        assert_eq!(udp("www.BLOCKED.test", RecordType::A), (3, vec![], true));
+        assert_eq!(
+            udp("ok.blocked.test", RecordType::A),
+            (0, vec![String::from("A 198.51.100.9")], false)
+        );
+        assert_eq!(udp("empty.test", RecordType::A), (0, vec![], true));
+        assert!(ask(&handler, "quiet.test", RecordType::A, Transport::Udp).is_none());
+        let big = ask(&handler, "big.test", RecordType::A, Transport::Udp).unwrap();
+        assert!(big.get_header().get_tc());
+        assert!(big.get_answers().is_empty());
+        assert_eq!(
+            outcome(&ask(&handler, "big.test", RecordType::A, Transport::Tcp).unwrap()),
+            (0, vec![String::from("A 8.8.8.8")], false)
+        );
+        assert_eq!(
+            udp("walled.test", RecordType::A),
+            (
+                0,
+                vec![String::from("A 192.0.2.80"), String::from("A 192.0.2.81")],
+                true
+            )
+        );
+        assert_eq!(
+            udp("walled.test", RecordType::Txt),
+            (0, vec![String::from("TXT walled garden; keep out")], true)
+        );
+        assert_eq!(udp("walled.test", RecordType::Aaaa), (0, vec![], true));
+        let alias = ask(&handler, "alias.test", RecordType::A, Transport::Udp).unwrap();
+        assert_eq!(
+            outcome(&alias),
+            (0, vec![String::from("CNAME garden.example")], true)
+        );
+        assert_eq!(alias.get_answers()[0].get_ttl(), 60);
+
+        // IP triggers, on the addresses the resolver came back with.
+        assert_eq!(udp("hosted.test", RecordType::A), (3, vec![], true));
+        assert_eq!(
+            udp("cdn.test", RecordType::A),
+            (0, vec![String::from("A 192.0.2.80")], true)
+        );
+        assert_eq!(udp("v6.test", RecordType::Aaaa), (0, vec![], true));
+        assert_eq!(
+            udp("example.com", RecordType::A),
+            (0, vec![String::from("A 8.8.8.8")], false)
+        );
+
+        // The decision trace names the zone and the trigger.
+        let request = query("walled.test", Some(&[DEFAULT_PROVENANCE_OPTION]));
+        let header = Header::parse_from(request[..12].try_into().unwrap());
+        let info = QueryInfo {
+            listener: "test",
+            client: "127.0.0.1:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        let response = handler
+            .handle(&info, &header, &request, &Stats::default())
+            .unwrap();
+        assert!(response.get_additionals().iter().any(|additional| {
+            additional.get_data()[1..]
+                == *b"source=rpz zone=rpz.example trigger=walled.test action=Local-Data"
+        }));
+
+        assert_eq!(
+            handler.rpz.as_ref().unwrap().report(),
+            [format!(
+                "rpz.example qname=8 ip=3 skipped=1 hits=15 ({})",
+                path.display()
+            )]
+        );
+    }
+
+    #[test]
+    fn edns_clients_are_told_which_rule_shaped_the_answer() {
+        let path = zone_file(
+            "ede",
+            &format!(
+                "{}{}\n",
+                FEED, "a-rather-long-label-for-a-blocked-host.in-a-long-domain.test CNAME ."
+            ),
+        );
+        let mut rpz = ResponsePolicy::load(std::slice::from_ref(&path)).unwrap();
+        let ede = |handler: &QueryOpcodeHandler, name: &str, options: Option<&[u16]>| {
+            let request = query(name, options);
+            let header = Header::parse_from(request[..12].try_into().unwrap());
+            let info = QueryInfo {
+                listener: "test",
+                client: "127.0.0.1:5353".parse().unwrap(),
+                transport: Transport::Udp,
+                load: Load::Normal,
+            };
+            let response = handler
+                .handle(&info, &header, &request, &Stats::default())
+                .unwrap();
+            extended_error_in(&response)
+        };
+        let edns: Option<&[u16]> = Some(&[]);
+
+        {
+            let handler = handler(ResponsePolicy::load(std::slice::from_ref(&path)).unwrap());
+            // Blocks are Filtered, local data Forged; the EXTRA-TEXT names the trigger.
+            assert_eq!(
+                ede(&handler, "www.blocked.test", edns),
+                Some((17, String::from("rpz *.blocked.test in rpz.example")))
+            );
+            assert_eq!(
+                ede(&handler, "walled.test", edns),
+                Some((4, String::from("rpz walled.test in rpz.example")))
+            );
+            assert_eq!(
+                ede(&handler, "hosted.test", edns),
+                Some((17, String::from("rpz 198.51.100.0/24 in rpz.example")))
+            );
+            // The EXTRA-TEXT is cut to 64 bytes.
+            let (_, text) = ede(
+                &handler,
+                "a-rather-long-label-for-a-blocked-host.in-a-long-domain.test",
+                edns,
+            )
+            .unwrap();
+            assert_eq!(
+                text,
+                "rpz a-rather-long-label-for-a-blocked-host.in-a-long-domain.test"
+            );
+            // Nothing for answers no policy touched, nor for clients without EDNS.
+            assert_eq!(ede(&handler, "ok.blocked.test", edns), None);
+            assert_eq!(ede(&handler, "blocked.test", None), None);
+        }
+
+        rpz.censored = true;
+        let handler = handler(rpz);
+        assert_eq!(
+            ede(&handler, "blocked.test", edns),
+            Some((16, String::from("rpz blocked.test in rpz.example")))
+        );
+        assert_eq!(ede(&handler, "walled.test", edns).unwrap().0, 4);
+    }
+
+    #[test]
+    fn zones_apply_in_order_and_are_reloaded_when_changed() {
+        let local = zone_file(
+            "local",
+            "local.rpz. 300 SOA ns.local. admin.local. 1 3600 600 86400 60\n\
+             blocked.test.local.rpz. CNAME rpz-passthru.\n",
+        );
+        let feed = zone_file("ordered-feed", FEED);
+        let handler = handler(ResponsePolicy::load(&[local.clone(), feed]).unwrap());
+        let rcode = |name: &str| {
+            ask(&handler, name, RecordType::A, Transport::Udp)
+                .map(|response| u16::from(response.get_header().get_rcode()))
+        };
+        // The local zone lets the name through, ahead of the feed.
+        assert_eq!(rcode("blocked.test"), Some(0));
+        assert_eq!(rcode("www.blocked.test"), Some(3));
+
+        fs::write(
+            &local,
+            "$ORIGIN local.rpz.\n@ SOA ns.local. admin.local. 2 3600 600 86400 60\n\
+             blocked.test CNAME rpz-drop.\n",
+        )
+        .unwrap();
+        handler.maintain();
+        assert_eq!(rcode("blocked.test"), None);
+
+        // A broken file leaves the loaded zone in place.
+        fs::write(&local, "blocked.test CNAME rpz-drop.\n").unwrap();
+        handler.maintain();
+        assert_eq!(rcode("blocked.test"), None);
+        let report = handler.rpz.as_ref().unwrap().report();
+        assert!(report[0].starts_with("local.rpz qname=1 ip=0 skipped=0 hits=3 "));
+        assert!(report[1].starts_with("rpz.example qname=8 ip=3 skipped=1 hits=1 "));
+    }
+
+    #[test]
+    fn included_and_generated_triggers_are_loaded() {
+        let ads = zone_file(
+            "ads",
+            "$ORIGIN ads.rpz.example.\n$GENERATE 1-3 tracker-$ CNAME .\n",
+        );
+        let path = zone_file(
+            "including",
+            &format!(
+                "$ORIGIN rpz.example.\n$INCLUDE {}\nwalled.test A 192.0.2.80\n\
+                 $GENERATE 10-11 host-${{0,2,x}}.test A 192.0.2.$\n",
+                ads.file_name().unwrap().to_str().unwrap()
+            ),
+        );
+        let policy = ResponsePolicy::load(&[path]).unwrap();
+        let zone = Arc::clone(&policy.zones[0].zone.lock().unwrap());
+        let mut names: Vec<(&str, String)> = zone
+            .names
+            .iter()
+            .map(|(name, action)| (name.as_str(), action.to_string()))
+            .collect();
+        names.sort();
+        // Back from the include, the including file's origin is restored.
+        assert_eq!(
+            names,
+            [
+                ("host-0a.test", String::from("Local-Data")),
+                ("host-0b.test", String::from("Local-Data")),
+                ("tracker-1", String::from("NXDOMAIN")),
+                ("tracker-2", String::from("NXDOMAIN")),
+                ("tracker-3", String::from("NXDOMAIN")),
+                ("walled.test", String::from("Local-Data")),
+            ]
+        );
+        let Some(RpzAction::LocalData(answers)) = zone.names.get("host-0b.test") else {
+            panic!("host-0b.test has no local data");
+        };
+        assert_eq!(answers[0].get_data().as_ref(), [192, 0, 2, 11]);
+    }
+
+    #[test]
+    fn malformed_policy_zones_are_rejected() {
+        for source in [
+            "blocked.test CNAME .\n",
+            "$ORIGIN rpz.example.\nblocked.other. CNAME .\n",
+            "$ORIGIN rpz.example.\n1.2.3.4.24.rpz-ip CNAME .\n",
+            "$ORIGIN rpz.example.\n24.1.2.0.192.rpz-ip CNAME .\n",
+            "$ORIGIN rpz.example.\n33.1.2.0.192.rpz-ip CNAME .\n",
+            "$ORIGIN rpz.example.\nmixed.test CNAME .\nmixed.test A 192.0.2.1\n",
+            "$ORIGIN rpz.example.\nwalled.test A 192.0.2\n",
+            "$ORIGIN rpz.example.\nwalled.test 300 IN\n",
+            "$SCHEDULE nightly 20:00-07:00\n$ORIGIN rpz.example.\nblocked.test CNAME .\n",
+        ] {
+            assert!(PolicyZone::parse(source).is_err(), "{}", source);
+        }
+        let zone = PolicyZone::parse(
+            "$ORIGIN rpz.example.\n128.1.zz.db8.2001.rpz-ip CNAME .\n\
+             64.zz.2001.rpz-ip CNAME .\n32.1.2.0.192.rpz-ip CNAME .\n",
+        )
+        .unwrap();
+        let networks: Vec<String> = zone
+            .addresses
+            .iter()
+            .map(|trigger| format!("{}/{}", trigger.network, trigger.length))
+            .collect();
+        assert_eq!(networks, ["2001:db8::1/128", "2001::/64", "192.0.2.1/32"]);
+    }
+
+    #[test]
+    #[cfg(feature = "time-zones")]
+    fn scheduled_zones_apply_from_the_configured_minute() {
+        use super::super::testing::ManualClock;
+
+        let path = zone_file(
+            "scheduled",
+            "$SCHEDULE daily 20:00-07:00 Europe/Berlin\n$ORIGIN kids.rpz.\nblocked.test CNAME .\n",
+        );
+        // 2026-10-24, a Saturday, 00:00 UTC; Berlin is on CEST until the next night.
+        let saturday: u64 = 1_792_800_000;
+        let clock = Arc::new(ManualClock::at(saturday + 18 * 3600 - 1));
+        let mut rpz = ResponsePolicy::load(std::slice::from_ref(&path)).unwrap();
+        rpz.clock = clock.clone();
+        let handler = handler(rpz);
+        let rcode =
+            || outcome(&ask(&handler, "blocked.test", RecordType::A, Transport::Udp).unwrap()).0;
+
+        // 19:59:59 CEST, then 20:00.
+        assert_eq!(rcode(), 0);
+        clock.advance(1);
+        assert_eq!(rcode(), 3);
+        assert_eq!(
+            handler.rpz.as_ref().unwrap().schedules(),
+            ["rpz kids.rpz \"daily 20:00-07:00 Europe/Berlin\": active until 2026-10-25 07:00 CET"]
+        );
+
+        // The clocks go back an hour that night, so 07:00 CET is 06:00 UTC.
+        clock.set(saturday + 86_400 + 6 * 3600 - 1);
+        assert_eq!(rcode(), 3);
+        clock.advance(1);
+        assert_eq!(rcode(), 0);
+    }
+}
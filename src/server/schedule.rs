@@ -0,0 +1,286 @@
+// When a scheduled policy rule is in effect, e.g. a blocklist that only applies on
+// school nights. A schedule is written as days, time ranges and a time zone:
+//
+//     mon-fri 20:00-07:00 Europe/Berlin
+//     sat,sun 00:00-09:00,22:00-24:00
+//     daily 12:00-13:00 America/New_York
+//
+// The days are mon..sun, ranges and comma-separated lists of them, or daily; the
+// time ranges are HH:MM-HH:MM, comma-separated; the time zone is a tz database name,
+// UTC if left out (and the only one without the time-zones feature). A range that ends at or before its start runs past midnight, and
+// belongs to the day it starts on. Times are on the local wall clock, so a range
+// keeps its hours across a DST change; one starting in the hour a change skips
+// starts when the clock jumps past it.
+
+use std::fmt;
+
+#[cfg(not(feature = "time-zones"))]
+use chrono::Utc as Tz;
+use chrono::{DateTime, Datelike, Timelike};
+#[cfg(feature = "time-zones")]
+use chrono_tz::Tz;
+
+use super::signature_time::Clock;
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+// How far ahead next_flip looks: a schedule flips at least weekly, if at all.
+const FLIP_HORIZON_MINUTES: i64 = 8 * MINUTES_PER_DAY as i64;
+
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    // Bit 0 is Monday.
+    days: u8,
+    // Minutes since local midnight, start inclusive, end exclusive; end is up to 24:00.
+    ranges: Vec<(u16, u16)>,
+    tz: Tz,
+    source: String,
+}
+
+fn parse_day(day: &str) -> Result<usize, String> {
+    DAY_NAMES
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(day))
+        .ok_or_else(|| format!("{} is not a day (mon..sun).", day))
+}
+
+fn parse_days(days: &str) -> Result<u8, String> {
+    if days.eq_ignore_ascii_case("daily") {
+        return Ok(0x7F);
+    }
+    let mut mask: u8 = 0;
+    for part in days.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+            None => (parse_day(part)?, parse_day(part)?),
+        };
+        // A range may wrap around the week, e.g. fri-mon.
+        let mut day = first;
+        loop {
+            mask |= 1 << day;
+            if day == last {
+                break;
+            }
+            day = (day + 1) % 7;
+        }
+    }
+    Ok(mask)
+}
+
+fn parse_time(time: &str) -> Result<u16, String> {
+    let invalid = || format!("{} is not a time (HH:MM).", time);
+    let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+    let hours: u16 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u16 = minutes.parse().map_err(|_| invalid())?;
+    if minutes > 59 || hours * 60 + minutes > MINUTES_PER_DAY {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+#[cfg(feature = "time-zones")]
+fn time_zone(name: &str) -> Result<Tz, String> {
+    name.parse()
+        .map_err(|_| format!("{} is not a known time zone.", name))
+}
+
+#[cfg(not(feature = "time-zones"))]
+fn time_zone(name: &str) -> Result<Tz, String> {
+    if name != "UTC" {
+        return Err(format!(
+            "{name} needs the 'time-zones' feature, and this build was compiled without it \
+             (rebuild with --features time-zones)"
+        ));
+    }
+    Ok(Tz)
+}
+
+impl Schedule {
+    pub fn parse(source: &str) -> Result<Schedule, String> {
+        let fields: Vec<&str> = source.split_whitespace().collect();
+        let (days, ranges, tz) = match fields.as_slice() {
+            [days, ranges] => (days, ranges, time_zone("UTC")?),
+            [days, ranges, tz] => (days, ranges, time_zone(tz)?),
+            _ => {
+                return Err(format!(
+                    "{:?} is not a schedule (DAYS HH:MM-HH:MM[,...] [TIME-ZONE]).",
+                    source
+                ))
+            }
+        };
+        let ranges = ranges
+            .split(',')
+            .map(|range| {
+                let (start, end) = range
+                    .split_once('-')
+                    .ok_or_else(|| format!("{} is not a time range (HH:MM-HH:MM).", range))?;
+                let (start, end) = (parse_time(start)?, parse_time(end)?);
+                if start == MINUTES_PER_DAY {
+                    return Err(format!("{} starts at 24:00.", range));
+                }
+                // An overnight range ending at midnight is the same as one ending at 24:00.
+                Ok((start, if end == 0 { MINUTES_PER_DAY } else { end }))
+            })
+            .collect::<Result<Vec<(u16, u16)>, String>>()?;
+        Ok(Schedule {
+            days: parse_days(days)?,
+            ranges,
+            tz,
+            source: fields.join(" "),
+        })
+    }
+
+    fn on(&self, weekday: usize) -> bool {
+        self.days & (1 << weekday) != 0
+    }
+
+    // Whether the schedule is in effect at `seconds` since the epoch.
+    pub fn is_active_at(&self, seconds: i64) -> bool {
+        let Some(time) = DateTime::from_timestamp(seconds, 0) else {
+            return false;
+        };
+        let local = time.with_timezone(&self.tz);
+        let minute = (local.hour() * 60 + local.minute()) as u16;
+        let today = local.weekday().num_days_from_monday() as usize;
+        let yesterday = (today + 6) % 7;
+        self.ranges.iter().any(|&(start, end)| {
+            if start < end {
+                self.on(today) && start <= minute && minute < end
+            } else {
+                (self.on(today) && minute >= start) || (self.on(yesterday) && minute < end)
+            }
+        })
+    }
+
+    pub fn is_active(&self, clock: &dyn Clock) -> bool {
+        self.is_active_at(clock.now().seconds() as i64)
+    }
+
+    // The first minute after `seconds` at which the schedule turns on or off, if it
+    // does within a week.
+    pub fn next_flip(&self, seconds: i64) -> Option<i64> {
+        let active = self.is_active_at(seconds);
+        let next_minute = seconds.div_euclid(60) * 60 + 60;
+        (0..FLIP_HORIZON_MINUTES)
+            .map(|minute| next_minute + minute * 60)
+            .find(|&at| self.is_active_at(at) != active)
+    }
+
+    // E.g. "active until 2026-10-16 07:00 CEST", for the control socket.
+    pub fn status(&self, clock: &dyn Clock) -> String {
+        let now = clock.now().seconds() as i64;
+        let state = if self.is_active_at(now) {
+            "active"
+        } else {
+            "inactive"
+        };
+        match self
+            .next_flip(now)
+            .and_then(|at| DateTime::from_timestamp(at, 0))
+        {
+            Some(at) => format!(
+                "{} until {}",
+                state,
+                at.with_timezone(&self.tz).format("%Y-%m-%d %H:%M %Z")
+            ),
+            None => String::from(state),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2026-10-16 (a Friday) 00:00 UTC.
+    const FRIDAY: i64 = 1_792_108_800;
+
+    fn at(day: i64, hour: i64, minute: i64) -> i64 {
+        FRIDAY + day * 86_400 + hour * 3600 + minute * 60
+    }
+
+    #[test]
+    fn parses_days_ranges_and_time_zones() {
+        let schedule = Schedule::parse("fri-mon  08:00-09:30,22:00-24:00").unwrap();
+        assert_eq!(schedule.to_string(), "fri-mon 08:00-09:30,22:00-24:00");
+        assert_eq!(schedule.days, 0b111_0001);
+        assert_eq!(schedule.ranges, [(480, 570), (1320, 1440)]);
+        assert!(schedule.is_active_at(at(0, 8, 0)));
+        assert!(!schedule.is_active_at(at(0, 9, 30)));
+        assert!(schedule.is_active_at(at(3, 23, 59)));
+        assert!(!schedule.is_active_at(at(4, 8, 0)));
+
+        for (source, error) in [
+            ("mon-fri", "is not a schedule"),
+            ("someday 08:00-09:00", "someday is not a day"),
+            ("daily 0800", "0800 is not a time range"),
+            ("daily 8-9", "8 is not a time"),
+            ("daily 08:00-25:00", "25:00 is not a time"),
+            ("daily 24:00-01:00", "starts at 24:00"),
+            (
+                "daily 08:00-09:00 Mars/Olympus",
+                if cfg!(feature = "time-zones") {
+                    "Mars/Olympus is not a known time zone"
+                } else {
+                    "needs the 'time-zones' feature"
+                },
+            ),
+        ] {
+            let err = Schedule::parse(source).unwrap_err();
+            assert!(err.contains(error), "{source}: {err}");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "time-zones")]
+    fn overnight_ranges_flip_at_the_local_minute() {
+        use super::super::testing::ManualClock;
+
+        // Friday night in Berlin (CEST, UTC+2) runs into Saturday morning.
+        let schedule = Schedule::parse("fri 20:00-07:00 Europe/Berlin").unwrap();
+        let clock = ManualClock::at(at(0, 17, 59) as u64);
+        assert!(!schedule.is_active(&clock));
+        assert_eq!(schedule.next_flip(at(0, 17, 59)), Some(at(0, 18, 0)));
+        clock.advance(60);
+        assert!(schedule.is_active(&clock));
+        assert_eq!(
+            schedule.status(&clock),
+            "active until 2026-10-17 07:00 CEST"
+        );
+        clock.set(at(1, 4, 59) as u64);
+        assert!(schedule.is_active(&clock));
+        clock.advance(60);
+        assert!(!schedule.is_active(&clock));
+        // Saturday night isn't a Friday night.
+        assert!(!schedule.is_active_at(at(1, 20, 0)));
+        assert_eq!(
+            schedule.status(&clock),
+            "inactive until 2026-10-23 20:00 CEST"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "time-zones")]
+    fn ranges_keep_their_local_hours_across_dst_changes() {
+        let schedule = Schedule::parse("daily 01:00-07:00 Europe/Berlin").unwrap();
+        // Before the change on 2026-10-25 it ends at 05:00 UTC; after it, at 06:00.
+        assert_eq!(schedule.next_flip(at(8, 4, 0)), Some(at(8, 5, 0)));
+        assert_eq!(schedule.next_flip(at(9, 4, 0)), Some(at(9, 6, 0)));
+        // That night lasts an hour longer: 01:00 CEST is 23:00 UTC.
+        assert_eq!(schedule.next_flip(at(8, 12, 0)), Some(at(8, 23, 0)));
+
+        // On 2026-03-29 the clocks skip 02:00-03:00: a range starting at 02:30
+        // starts when they jump to 03:00 CEST, i.e. 01:00 UTC.
+        let schedule = Schedule::parse("daily 02:30-04:00 Europe/Berlin").unwrap();
+        let march_29 = 1_774_742_400;
+        assert_eq!(schedule.next_flip(march_29), Some(march_29 + 3600));
+    }
+}
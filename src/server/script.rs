@@ -0,0 +1,985 @@
+// Per-query policy scripts (--policy-script), for policies the static options can't
+// express. A script is a list of rules, one per line, tried in order: the first whose
+// condition holds decides what happens to the query, and a query no rule matches is
+// allowed. For example:
+//
+//     # Guests get no internal names outside business hours.
+//     if client in 10.20.0.0/16 and qname under corp and ( hour < 8 or hour >= 18 ) then refuse
+//     if qtype == ANY then drop
+//     if qname == old.example.com then rewrite-to(new.example.com)
+//     if listener == office then forward-to(default)
+//
+// Conditions test the fields client (==, != an address; in a CIDR block), qname (==,
+// != a name; under a domain), qtype (==, != a type), listener and transport (==, !=;
+// transport is udp or tcp), and hour and minute of the local time (==, !=, <, <=, >,
+// >=), joined with and, or, not and parentheses, all separated by spaces. The actions
+// are allow, refuse, nxdomain, drop, forward-to(TAG) and rewrite-to(NAME).
+//
+// Scripts are compiled once, and again whenever the file changes. They can't do IO,
+// and an evaluation taking more than a bounded number of steps is cut off; a query
+// whose evaluation fails is allowed, or refused when failing closed.
+//
+// The whole script, forward-to rules included, can be limited to a schedule
+// (--policy-script-schedule, see src/server/schedule.rs); outside of it, queries are
+// handled as if there were no script.
+
+use std::{
+    fmt, fs, io,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::dns::message::{
+    Answer, Header, LabelSequence, LabelSequenceParseError, Message, OpCode, Question, RCode,
+    RData, RecordType,
+};
+use super::handlers::{echo_qname_casing, response_header, HandleOpcode, QueryOpcodeHandler};
+use super::listener::{QueryInfo, Transport};
+use super::policy::{parse_record_type, DomainSuffix, Subnet};
+use super::schedule::Schedule;
+use super::signature_time::{Clock, SystemClock};
+use super::stats::Stats;
+use super::Resolve;
+
+// The tag of the resolver the server forwards to anyway; always known to forward-to.
+pub const DEFAULT_FORWARDER: &str = "default";
+
+// How deeply parentheses and nots may nest, so that neither compiling nor evaluating
+// a script can run out of stack.
+const MAX_NESTING: usize = 32;
+
+// TTL of the CNAME synthesized by rewrite-to: none, as the script may decide
+// differently for the next query (another client, another time of day).
+const REWRITE_TTL: u32 = 0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScriptAction {
+    Allow,
+    Refuse,
+    NxDomain,
+    Drop,
+    ForwardTo(String),
+    RewriteTo(String),
+}
+
+impl fmt::Display for ScriptAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptAction::Allow => write!(f, "allow"),
+            ScriptAction::Refuse => write!(f, "refuse"),
+            ScriptAction::NxDomain => write!(f, "nxdomain"),
+            ScriptAction::Drop => write!(f, "drop"),
+            ScriptAction::ForwardTo(tag) => write!(f, "forward-to({})", tag),
+            ScriptAction::RewriteTo(name) => write!(f, "rewrite-to({})", name),
+        }
+    }
+}
+
+// What a script can see of a query.
+pub struct ScriptContext<'a> {
+    pub client: IpAddr,
+    pub question: &'a Question,
+    pub listener: &'a str,
+    pub transport: Transport,
+    pub hour: u8,
+    pub minute: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Comparison {
+    Equal,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    fn holds(self, value: u8, operand: u8) -> bool {
+        match self {
+            Comparison::Equal => value == operand,
+            Comparison::Less => value < operand,
+            Comparison::LessOrEqual => value <= operand,
+            Comparison::Greater => value > operand,
+            Comparison::GreaterOrEqual => value >= operand,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Condition {
+    ClientIs(IpAddr),
+    ClientIn(Subnet),
+    NameIs(LabelSequence),
+    NameUnder(DomainSuffix),
+    TypeIs(RecordType),
+    ListenerIs(String),
+    TransportIs(Transport),
+    Hour(Comparison, u8),
+    Minute(Comparison, u8),
+}
+
+impl Condition {
+    fn holds(&self, context: &ScriptContext) -> bool {
+        match self {
+            Condition::ClientIs(address) => context.client.to_canonical() == *address,
+            Condition::ClientIn(subnet) => subnet.contains(context.client),
+            Condition::NameIs(name) => name.eq_ignore_case(context.question.get_name()),
+            Condition::NameUnder(suffix) => suffix.matches(context.question.get_name()),
+            Condition::TypeIs(r#type) => context.question.get_type() == *r#type,
+            Condition::ListenerIs(tag) => context.listener == tag,
+            Condition::TransportIs(transport) => context.transport == *transport,
+            Condition::Hour(comparison, operand) => comparison.holds(context.hour, *operand),
+            Condition::Minute(comparison, operand) => comparison.holds(context.minute, *operand),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Expression {
+    Test(Condition),
+    Not(Box<Expression>),
+    All(Vec<Expression>),
+    Any(Vec<Expression>),
+}
+
+impl Expression {
+    // Every node visited costs a step; fails once `steps` runs out.
+    fn evaluate(&self, context: &ScriptContext, steps: &mut u32) -> Result<bool, String> {
+        *steps = steps
+            .checked_sub(1)
+            .ok_or("the script ran out of evaluation steps")?;
+        match self {
+            Expression::Test(condition) => Ok(condition.holds(context)),
+            Expression::Not(inner) => Ok(!inner.evaluate(context, steps)?),
+            Expression::All(operands) => {
+                for operand in operands {
+                    if !operand.evaluate(context, steps)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Expression::Any(operands) => {
+                for operand in operands {
+                    if operand.evaluate(context, steps)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    condition: Option<Expression>,
+    action: ScriptAction,
+}
+
+// Splits a line into words, with parentheses as words of their own.
+fn tokenize(line: &str) -> Vec<&str> {
+    let mut tokens: Vec<&str> = Vec::new();
+    for word in line.split_whitespace() {
+        let mut rest = word;
+        while let Some(index) = rest.find(['(', ')']) {
+            if index > 0 {
+                tokens.push(&rest[..index]);
+            }
+            tokens.push(&rest[index..index + 1]);
+            rest = &rest[index + 1..];
+        }
+        if !rest.is_empty() {
+            tokens.push(rest);
+        }
+    }
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    position: usize,
+    forward_tags: &'a [String],
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        let token = self.peek().ok_or("unexpected end of line")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next() {
+            Ok(token) if token == expected => Ok(()),
+            Ok(token) => Err(format!("expected '{}', found '{}'", expected, token)),
+            Err(_) => Err(format!("expected '{}' at the end of the line", expected)),
+        }
+    }
+
+    fn rule(&mut self) -> Result<Rule, String> {
+        let condition = if self.peek() == Some("if") {
+            self.position += 1;
+            let condition = self.any(0)?;
+            self.expect("then")?;
+            Some(condition)
+        } else {
+            None
+        };
+        let action = self.action()?;
+        if let Some(token) = self.peek() {
+            return Err(format!("unexpected '{}' after the action", token));
+        }
+        Ok(Rule { condition, action })
+    }
+
+    fn action(&mut self) -> Result<ScriptAction, String> {
+        let action = self.next()?;
+        let simple = match action {
+            "allow" => Some(ScriptAction::Allow),
+            "refuse" => Some(ScriptAction::Refuse),
+            "nxdomain" => Some(ScriptAction::NxDomain),
+            "drop" => Some(ScriptAction::Drop),
+            _ => None,
+        };
+        if let Some(simple) = simple {
+            return Ok(simple);
+        }
+        if action != "forward-to" && action != "rewrite-to" {
+            return Err(format!("unknown action '{}'", action));
+        }
+        self.expect("(")?;
+        let argument = self.next()?;
+        self.expect(")")?;
+        if action == "forward-to" {
+            if !self.forward_tags.iter().any(|tag| tag == argument) {
+                return Err(format!("no resolver is tagged '{}'", argument));
+            }
+            return Ok(ScriptAction::ForwardTo(argument.to_string()));
+        }
+        argument
+            .parse::<LabelSequence>()
+            .map_err(|err| err.message)?;
+        Ok(ScriptAction::RewriteTo(argument.to_string()))
+    }
+
+    fn any(&mut self, depth: usize) -> Result<Expression, String> {
+        let mut operands = vec![self.all(depth)?];
+        while self.peek() == Some("or") {
+            self.position += 1;
+            operands.push(self.all(depth)?);
+        }
+        Ok(match operands.len() {
+            1 => operands.pop().expect("one operand"),
+            _ => Expression::Any(operands),
+        })
+    }
+
+    fn all(&mut self, depth: usize) -> Result<Expression, String> {
+        let mut operands = vec![self.unary(depth)?];
+        while self.peek() == Some("and") {
+            self.position += 1;
+            operands.push(self.unary(depth)?);
+        }
+        Ok(match operands.len() {
+            1 => operands.pop().expect("one operand"),
+            _ => Expression::All(operands),
+        })
+    }
+
+    fn unary(&mut self, depth: usize) -> Result<Expression, String> {
+        if depth > MAX_NESTING {
+            return Err(format!("nested more than {} deep", MAX_NESTING));
+        }
+        match self.peek() {
+            Some("not") => {
+                self.position += 1;
+                Ok(Expression::Not(Box::new(self.unary(depth + 1)?)))
+            }
+            Some("(") => {
+                self.position += 1;
+                let inner = self.any(depth + 1)?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            _ => self.comparison(),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expression, String> {
+        let field = self.next()?;
+        let operator = self.next()?;
+        let operand = self.next()?;
+        let (comparison, negated) = match operator {
+            "==" => (Comparison::Equal, false),
+            "!=" => (Comparison::Equal, true),
+            "<" => (Comparison::Less, false),
+            "<=" => (Comparison::LessOrEqual, false),
+            ">" => (Comparison::Greater, false),
+            ">=" => (Comparison::GreaterOrEqual, false),
+            "in" | "under" => (Comparison::Equal, false),
+            _ => return Err(format!("unknown operator '{}'", operator)),
+        };
+        let equality = matches!(operator, "==" | "!=");
+        let condition = match (field, operator) {
+            ("client", "in") => Condition::ClientIn(Subnet::parse(operand)?),
+            ("client", _) if equality => Condition::ClientIs(
+                operand
+                    .parse::<IpAddr>()
+                    .map_err(|_| format!("'{}' is not an IP address", operand))?
+                    .to_canonical(),
+            ),
+            ("qname", "under") => Condition::NameUnder(DomainSuffix::parse(operand)?),
+            ("qname", _) if equality => Condition::NameIs(
+                operand
+                    .parse()
+                    .map_err(|err: LabelSequenceParseError| err.message)?,
+            ),
+            ("qtype", _) if equality => Condition::TypeIs(parse_record_type(operand)?),
+            ("listener", _) if equality => Condition::ListenerIs(operand.to_string()),
+            ("transport", _) if equality => Condition::TransportIs(match operand {
+                "udp" => Transport::Udp,
+                "tcp" => Transport::Tcp,
+                _ => return Err(format!("unknown transport '{}'", operand)),
+            }),
+            ("hour" | "minute", "in" | "under") => {
+                return Err(format!("'{}' can't be applied to {}", operator, field))
+            }
+            ("hour" | "minute", _) => {
+                let limit = if field == "hour" { 23 } else { 59 };
+                let operand: u8 = operand
+                    .parse()
+                    .ok()
+                    .filter(|&value| value <= limit)
+                    .ok_or_else(|| format!("{} must be from 0 to {}", field, limit))?;
+                if field == "hour" {
+                    Condition::Hour(comparison, operand)
+                } else {
+                    Condition::Minute(comparison, operand)
+                }
+            }
+            ("client" | "qname" | "qtype" | "listener" | "transport", _) => {
+                return Err(format!("'{}' can't be applied to {}", operator, field))
+            }
+            _ => return Err(format!("unknown field '{}'", field)),
+        };
+        let test = Expression::Test(condition);
+        Ok(if negated {
+            Expression::Not(Box::new(test))
+        } else {
+            test
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct PolicyScript {
+    rules: Vec<Rule>,
+}
+
+impl PolicyScript {
+    // Compiles a script; forward-to may only name one of `forward_tags`.
+    pub fn compile(source: &str, forward_tags: &[String]) -> Result<PolicyScript, String> {
+        let mut rules: Vec<Rule> = Vec::new();
+        for (index, line) in source.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let tokens = tokenize(line);
+            if tokens.is_empty() {
+                continue;
+            }
+            let mut parser = Parser {
+                tokens,
+                position: 0,
+                forward_tags,
+            };
+            rules.push(
+                parser
+                    .rule()
+                    .map_err(|err| format!("line {}: {}", index + 1, err))?,
+            );
+        }
+        Ok(PolicyScript { rules })
+    }
+
+    // The action for a query, or an error if it takes more than `max_steps` steps
+    // (a rule and every part of its condition cost one each) to decide.
+    pub fn evaluate(
+        &self,
+        context: &ScriptContext,
+        max_steps: u32,
+    ) -> Result<ScriptAction, String> {
+        let mut steps = max_steps;
+        for rule in &self.rules {
+            steps = steps
+                .checked_sub(1)
+                .ok_or("the script ran out of evaluation steps")?;
+            let matched = match &rule.condition {
+                Some(condition) => condition.evaluate(context, &mut steps)?,
+                None => true,
+            };
+            if matched {
+                return Ok(rule.action.clone());
+            }
+        }
+        Ok(ScriptAction::Allow)
+    }
+}
+
+// A policy script loaded from a file, recompiled when the file changes.
+pub struct ScriptedPolicy {
+    pub path: PathBuf,
+    pub forward_tags: Vec<String>,
+    pub max_steps: u32,
+    // Whether a query whose evaluation fails is refused rather than allowed.
+    pub fail_closed: bool,
+    // When the script applies, if not always.
+    pub schedule: Option<Schedule>,
+    pub clock: Arc<dyn Clock>,
+    source: Mutex<String>,
+    script: Mutex<Arc<PolicyScript>>,
+}
+
+impl ScriptedPolicy {
+    pub fn load(
+        path: PathBuf,
+        forward_tags: Vec<String>,
+        max_steps: u32,
+        fail_closed: bool,
+    ) -> Result<ScriptedPolicy, String> {
+        let source = fs::read_to_string(&path)
+            .map_err(|err| format!("Can't read {}: {}", path.display(), err))?;
+        let script = PolicyScript::compile(&source, &forward_tags)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(ScriptedPolicy {
+            path,
+            forward_tags,
+            max_steps,
+            fail_closed,
+            schedule: None,
+            clock: Arc::new(SystemClock),
+            source: Mutex::new(source),
+            script: Mutex::new(Arc::new(script)),
+        })
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.script.lock().unwrap().rules.len()
+    }
+
+    // Whether the script applies now, by its schedule.
+    pub fn in_effect(&self) -> bool {
+        self.schedule
+            .as_ref()
+            .map_or(true, |schedule| schedule.is_active(self.clock.as_ref()))
+    }
+
+    // Recompiles the script if the file changed, and returns whether it did. A script
+    // that can't be read or no longer compiles is reported and the old one kept.
+    pub fn reload(&self) -> bool {
+        let source = match fs::read_to_string(&self.path) {
+            Ok(source) => source,
+            Err(err) => {
+                println!(
+                    "[SCRIPT] Can't read {}, keeping the loaded script: {}",
+                    self.path.display(),
+                    err
+                );
+                return false;
+            }
+        };
+        if source == *self.source.lock().unwrap() {
+            return false;
+        }
+        let compiled = PolicyScript::compile(&source, &self.forward_tags);
+        *self.source.lock().unwrap() = source;
+        match compiled {
+            Ok(script) => {
+                println!(
+                    "[SCRIPT] Reloaded {} ({} rule(s)).",
+                    self.path.display(),
+                    script.rules.len()
+                );
+                *self.script.lock().unwrap() = Arc::new(script);
+                true
+            }
+            Err(err) => {
+                println!(
+                    "[SCRIPT] {}: {}; keeping the loaded script.",
+                    self.path.display(),
+                    err
+                );
+                false
+            }
+        }
+    }
+
+    // The action for a query, timing the evaluation into the stats. An evaluation
+    // that fails allows the query, or refuses it when failing closed.
+    pub fn decide(&self, context: &ScriptContext, stats: &Stats) -> ScriptAction {
+        let script = Arc::clone(&self.script.lock().unwrap());
+        let started = Instant::now();
+        let result = script.evaluate(context, self.max_steps);
+        stats.record_script_evaluation(started.elapsed(), result.is_err());
+        result.unwrap_or_else(|err| {
+            let fallback = if self.fail_closed {
+                ScriptAction::Refuse
+            } else {
+                ScriptAction::Allow
+            };
+            println!(
+                "[SCRIPT] Evaluation failed for {}: {}; falling back to {}.",
+                context.question, err, fallback
+            );
+            fallback
+        })
+    }
+}
+
+// The current local time of day, as (hour, minute); UTC if the local time zone
+// can't be determined.
+fn local_time_of_day() -> (u8, u8) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs()) as libc::time_t;
+    // SAFETY: an all-zero `tm` is valid (its pointer field may be null).
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers refer to valid, exclusively borrowed values.
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return (((now / 3600) % 24) as u8, ((now / 60) % 60) as u8);
+    }
+    (tm.tm_hour as u8, tm.tm_min as u8)
+}
+
+// Standard queries, judged by a policy script before the query handler sees them.
+// Queries the script allows are handed to `inner` as they are.
+pub struct ScriptedQueryHandler {
+    pub policy: ScriptedPolicy,
+    pub inner: QueryOpcodeHandler,
+    // The resolvers forward-to can name besides DEFAULT_FORWARDER, which is the
+    // inner handler's.
+    pub forwarders: Vec<(String, Box<dyn Resolve>)>,
+}
+
+impl ScriptedQueryHandler {
+    fn forwarder(&self, tag: &str) -> &dyn Resolve {
+        self.forwarders
+            .iter()
+            .find(|(forwarder_tag, _)| forwarder_tag == tag)
+            .map_or(self.inner.resolver.as_ref(), |(_, resolver)| {
+                resolver.as_ref()
+            })
+    }
+
+    // A CNAME from the question's name to `target`, followed by what the resolver
+    // has for `target`, if anything.
+    fn rewrite(&self, header: &Header, question: &Question, target: &str) -> Vec<Answer> {
+        let target: Arc<LabelSequence> = Arc::new(target.parse().expect("validated when compiled"));
+        let mut answers: Vec<Answer> = vec![Answer::from_rdata(
+            /* name= */ question.get_name(),
+            /* class= */ question.get_class(),
+            /* ttl= */ REWRITE_TTL,
+            /* data= */ RData::Cname(target.as_ref().clone()),
+        )];
+        if question.get_type() != RecordType::Cname {
+            let rewritten = Question::new(&target, question.get_type(), question.get_class());
+            let resolved = self.inner.resolver.resolve(header, &Arc::from([rewritten]));
+            answers.extend(resolved.answers.iter().cloned());
+        }
+        answers
+    }
+}
+
+impl HandleOpcode for ScriptedQueryHandler {
+    fn opcode(&self) -> OpCode {
+        self.inner.opcode()
+    }
+
+    fn handle(
+        &self,
+        info: &QueryInfo,
+        header: &Header,
+        data: &[u8],
+        stats: &Stats,
+    ) -> Option<Message> {
+        let Ok(request) = Message::parse_from(data) else {
+            return self.inner.handle(info, header, data, stats);
+        };
+        // Scripts see the first question; there is hardly ever more than one.
+        let Some(question) = request.get_questions().first() else {
+            return self.inner.handle(info, header, data, stats);
+        };
+        if !self.policy.in_effect() {
+            return self.inner.handle(info, header, data, stats);
+        }
+        let (hour, minute) = local_time_of_day();
+        let context = ScriptContext {
+            client: info.client.ip(),
+            question,
+            listener: info.listener,
+            transport: info.transport,
+            hour,
+            minute,
+        };
+        let action = self.policy.decide(&context, stats);
+        if action != ScriptAction::Allow {
+            println!(
+                "[{}] Policy script: {} for {} from {}.",
+                info.listener, action, question, info.client
+            );
+        }
+        let (rcode, answers): (RCode, Arc<[Answer]>) = match action {
+            ScriptAction::Allow => return self.inner.handle(info, header, data, stats),
+            ScriptAction::Drop => return None,
+            ScriptAction::Refuse => (RCode::Refused, Arc::from([])),
+            ScriptAction::NxDomain => (RCode::NameError, Arc::from([])),
+            ScriptAction::ForwardTo(tag) => {
+                let resolved = self
+                    .forwarder(&tag)
+                    .resolve(request.get_header(), request.get_questions());
+                (
+                    resolved.rcode,
+                    echo_qname_casing(request.get_questions(), &resolved.answers),
+                )
+            }
+            ScriptAction::RewriteTo(target) => (
+                RCode::NoError,
+                self.rewrite(request.get_header(), question, &target).into(),
+            ),
+        };
+        let mut header = response_header(request.get_header(), rcode);
+        header
+            .set_qd_count(request.get_header().get_qd_count())
+            .set_an_count(answers.len() as u16);
+        Some(Message::new(
+            &header.into(),
+            request.get_questions(),
+            &answers,
+        ))
+    }
+
+    fn maintain(&self) {
+        self.policy.reload();
+        self.inner.maintain();
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        self.inner.persist()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.inner.next_due()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dns::message::RecordClass;
+    use super::super::overload::Load;
+    use super::super::records::{StaticDnsResolver, StaticRecords};
+    use super::super::testing::{query, query_handler};
+    use super::super::DummyDnsResolver;
+    use super::*;
+
+    fn question(name: &str, r#type: RecordType) -> Question {
+        Question::new(&Arc::new(name.parse().unwrap()), r#type, RecordClass::In)
+    }
+
+    fn context<'a>(client: &str, question: &'a Question, hour: u8) -> ScriptContext<'a> {
+        ScriptContext {
+            client: client.parse().unwrap(),
+            question,
+            listener: "guest",
+            transport: Transport::Udp,
+            hour,
+            minute: 30,
+        }
+    }
+
+    fn tags() -> Vec<String> {
+        vec![String::from(DEFAULT_FORWARDER), String::from("office")]
+    }
+
+    // A script file of its own for each test, as they run in parallel.
+    fn script_file(name: &str, source: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("policy-{}-{}.script", std::process::id(), name));
+        fs::write(&path, source).unwrap();
+        path
+    }
+
+    fn record(name: &str, data: [u8; 4]) -> Answer {
+        Answer::new(
+            /* name= */ &Arc::new(name.parse().unwrap()),
+            /* type= */ RecordType::A,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */ &Arc::from(data),
+        )
+    }
+
+    fn handler(path: PathBuf, fail_closed: bool) -> ScriptedQueryHandler {
+        let mut records = StaticRecords::new();
+        records.add(record("new.example.com", [192, 0, 2, 1]));
+        let mut office = StaticRecords::new();
+        office.add(record("intranet.corp", [10, 0, 0, 1]));
+        ScriptedQueryHandler {
+            policy: ScriptedPolicy::load(path, tags(), 1000, fail_closed).unwrap(),
+            inner: query_handler(StaticDnsResolver {
+                records: Arc::new(records),
+                next: Box::new(DummyDnsResolver::default()),
+            }),
+            forwarders: vec![(
+                String::from("office"),
+                Box::new(StaticDnsResolver {
+                    records: Arc::new(office),
+                    next: Box::new(DummyDnsResolver::default()),
+                }),
+            )],
+        }
+    }
+
+    fn handle(handler: &ScriptedQueryHandler, name: &str, stats: &Stats) -> Option<Message> {
+        let request = query(name, None);
+        let info = QueryInfo {
+            listener: "test",
+            client: "10.20.1.2:5353".parse().unwrap(),
+            transport: Transport::Udp,
+            load: Load::Normal,
+        };
+        let header = Header::parse_from(request[..12].try_into().unwrap());
+        handler.handle(&info, &header, &request, stats)
+    }
+
+    #[test]
+    fn rules_are_tried_in_order_over_every_field() {
+        let script = PolicyScript::compile(
+            "# Guests get no internal names outside business hours.\n\
+             if client in 10.20.0.0/16 and qname under corp and ( hour < 8 or hour >= 18 ) then refuse\n\
+             \n\
+             if qtype == ANY or qtype == TYPE99 then drop  # no amplification\n\
+             if qname == OLD.example.com then rewrite-to(new.example.com)\n\
+             if listener != guest and not transport == tcp then forward-to(office)\n\
+             if client == ::ffff:192.0.2.9 and minute >= 30 then nxdomain\n",
+            &tags(),
+        )
+        .unwrap();
+        let intranet = question("intranet.CORP", RecordType::A);
+        let evaluate = |context: &ScriptContext| script.evaluate(context, 100).unwrap();
+        assert_eq!(
+            evaluate(&context("10.20.3.4", &intranet, 22)),
+            ScriptAction::Refuse
+        );
+        assert_eq!(
+            evaluate(&context("10.20.3.4", &intranet, 9)),
+            ScriptAction::Allow
+        );
+        assert_eq!(
+            evaluate(&context("10.30.3.4", &intranet, 22)),
+            ScriptAction::Allow
+        );
+        assert_eq!(
+            evaluate(&context(
+                "10.20.3.4",
+                &question("example.com", RecordType::Any),
+                9
+            )),
+            ScriptAction::Drop
+        );
+        assert_eq!(
+            evaluate(&context(
+                "10.20.3.4",
+                &question("old.example.com", RecordType::A),
+                9
+            )),
+            ScriptAction::RewriteTo(String::from("new.example.com"))
+        );
+        let mut office = context("10.20.3.4", &intranet, 9);
+        office.listener = "office";
+        assert_eq!(
+            evaluate(&office),
+            ScriptAction::ForwardTo(String::from("office"))
+        );
+        office.transport = Transport::Tcp;
+        assert_eq!(evaluate(&office), ScriptAction::Allow);
+        assert_eq!(
+            evaluate(&context("192.0.2.9", &intranet, 9)),
+            ScriptAction::NxDomain
+        );
+
+        for (source, error) in [
+            ("if qname under corp refuse", "line 1: expected 'then'"),
+            ("allow\nif hour < 24 then drop", "line 2: hour must be"),
+            ("if qtype < A then drop", "line 1: '<' can't be applied"),
+            ("forward-to(lab)", "line 1: no resolver is tagged 'lab'"),
+            ("if color == red then drop", "line 1: unknown field"),
+            ("refuse now", "line 1: unexpected 'now'"),
+        ] {
+            let err = PolicyScript::compile(source, &tags()).unwrap_err();
+            assert!(err.starts_with(error), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn every_action_shapes_the_response() {
+        let path = script_file(
+            "actions",
+            "if qname == refused.example then refuse\n\
+             if qname == missing.example then nxdomain\n\
+             if qname == dropped.example then drop\n\
+             if qname under corp then forward-to(office)\n\
+             if qname == old.example.com then rewrite-to(new.example.com)\n",
+        );
+        let handler = handler(path.clone(), false);
+        let stats = Stats::default();
+        let rcode = |name: &str| {
+            let response = handle(&handler, name, &stats).unwrap();
+            response.get_header().get_rcode().clone()
+        };
+        assert_eq!(rcode("refused.example"), RCode::Refused);
+        assert_eq!(rcode("missing.example"), RCode::NameError);
+        assert!(handle(&handler, "dropped.example", &stats).is_none());
+
+        let forwarded = handle(&handler, "Intranet.corp", &stats).unwrap();
+        assert_eq!(forwarded.get_answers().len(), 1);
+        assert_eq!(
+            forwarded.get_answers()[0].get_data().as_ref(),
+            [10, 0, 0, 1]
+        );
+        assert_eq!(
+            forwarded.get_answers()[0].get_name().to_string(),
+            "Intranet.corp"
+        );
+
+        let rewritten = handle(&handler, "old.example.com", &stats).unwrap();
+        let answers = rewritten.get_answers();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(
+            (answers[0].get_type(), answers[0].get_ttl()),
+            (RecordType::Cname, 0)
+        );
+        assert_eq!(answers[1].get_name().to_string(), "new.example.com");
+        assert_eq!(answers[1].get_data().as_ref(), [192, 0, 2, 1]);
+
+        // Allowed queries go through the usual resolvers.
+        let allowed = handle(&handler, "www.example.org", &stats).unwrap();
+        assert_eq!(*allowed.get_header().get_rcode(), RCode::NoError);
+        assert_eq!(allowed.get_answers().len(), 1);
+
+        assert_eq!(stats.snapshot().script_evaluations, 6);
+        assert_eq!(stats.snapshot().script_errors, 0);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn changed_scripts_are_reloaded() {
+        let path = script_file("reload", "if qname under example then refuse\n");
+        let policy = ScriptedPolicy::load(path.clone(), tags(), 100, false).unwrap();
+        let stats = Stats::default();
+        let name = question("www.example", RecordType::A);
+        let decide = || policy.decide(&context("192.0.2.1", &name, 12), &stats);
+        assert_eq!(decide(), ScriptAction::Refuse);
+        assert!(!policy.reload());
+
+        fs::write(&path, "if qname under example then nxdomain\n").unwrap();
+        assert!(policy.reload());
+        assert_eq!(decide(), ScriptAction::NxDomain);
+
+        // A script that no longer compiles leaves the last good one in force.
+        fs::write(&path, "if qname under example then explode\n").unwrap();
+        assert!(!policy.reload());
+        assert_eq!(decide(), ScriptAction::NxDomain);
+        assert!(!policy.reload());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn step_bound_cuts_off_pathological_scripts() {
+        let source = "if qtype == TXT and ( qname under a.example or not hour < 12 ) then drop\n"
+            .repeat(50_000);
+        let script = PolicyScript::compile(&source, &tags()).unwrap();
+        let name = question("www.example", RecordType::A);
+        let started = Instant::now();
+        let err = script
+            .evaluate(&context("192.0.2.1", &name, 12), 10_000)
+            .unwrap_err();
+        assert!(err.contains("steps"), "{}", err);
+        assert!(started.elapsed() < std::time::Duration::from_millis(100));
+        // The same script within a large enough bound decides.
+        assert_eq!(
+            script.evaluate(&context("192.0.2.1", &name, 12), u32::MAX),
+            Ok(ScriptAction::Allow)
+        );
+
+        let nested = format!(
+            "if {}hour < 1{} then drop",
+            "( ".repeat(100),
+            " )".repeat(100)
+        );
+        let err = PolicyScript::compile(&nested, &tags()).unwrap_err();
+        assert!(err.contains("nested"), "{}", err);
+    }
+
+    #[test]
+    fn failed_evaluations_fail_open_or_closed() {
+        let path = script_file(
+            "fail",
+            &"if qname == never.example then refuse\n".repeat(1000),
+        );
+        let name = question("www.example", RecordType::A);
+        for (fail_closed, expected) in [(false, ScriptAction::Allow), (true, ScriptAction::Refuse)]
+        {
+            let policy = ScriptedPolicy::load(path.clone(), tags(), 100, fail_closed).unwrap();
+            let stats = Stats::default();
+            assert_eq!(
+                policy.decide(&context("192.0.2.1", &name, 12), &stats),
+                expected
+            );
+            assert_eq!(stats.snapshot().script_errors, 1);
+        }
+
+        let handler = handler(path.clone(), true);
+        let stats = Stats::default();
+        let response = handle(&handler, "www.example", &stats).unwrap();
+        assert_eq!(*response.get_header().get_rcode(), RCode::Refused);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "time-zones")]
+    fn scheduled_scripts_only_apply_inside_their_schedule() {
+        use super::super::testing::ManualClock;
+
+        let path = script_file("scheduled", "if qname under example then refuse\n");
+        let mut handler = handler(path.clone(), false);
+        // 2026-10-16, a Friday, 08:59:59 in Berlin (CEST).
+        let clock = Arc::new(ManualClock::at(1_792_108_800 + 7 * 3600 - 1));
+        handler.policy.schedule =
+            Some(Schedule::parse("mon-fri 09:00-17:00 Europe/Berlin").unwrap());
+        handler.policy.clock = clock.clone();
+        let stats = Stats::default();
+        let rcode = |handler: &ScriptedQueryHandler| {
+            u16::from(
+                handle(handler, "www.example", &stats)
+                    .unwrap()
+                    .get_header()
+                    .get_rcode(),
+            )
+        };
+
+        assert_eq!(rcode(&handler), 0);
+        clock.advance(1);
+        assert_eq!(rcode(&handler), 5);
+        clock.advance(8 * 3600);
+        assert_eq!(rcode(&handler), 0);
+        // Only the queries inside the schedule were evaluated.
+        assert_eq!(stats.snapshot().script_evaluations, 1);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,359 @@
+// Server-side search lists (--search-list), for clients that send unqualified names
+// and can't be given a search domain of their own, e.g. an appliance asking for
+// "printer". For a client in the list's blocks, the question is tried with each of
+// the list's suffixes appended, as a stub resolver would, and with the name as it
+// is: first when it has at least `ndots` dots, last otherwise (resolv.conf(5)). The
+// first candidate with records wins, and its records are handed back under the name
+// the client asked for; the other clients are answered as before. Each candidate
+// is an ordinary lookup of the resolvers below, and the search gives up trying more
+// of them once SEARCH_BUDGET has gone by, as the client would have by then.
+
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::budget;
+use super::dns::message::{Answer, Header, LabelSequence, Question};
+use super::policy::Subnet;
+use super::{Lookup, Resolve, Resolved};
+
+// What ndots is unless given, as in resolv.conf.
+pub const DEFAULT_NDOTS: usize = 1;
+
+// How long the candidates are tried for; about a stub resolver's first timeout.
+const SEARCH_BUDGET: Duration = Duration::from_secs(2);
+
+// A --search-list value: CIDR[,CIDR...]=SUFFIX[,SUFFIX...][:NDOTS], e.g.
+// 192.0.2.0/24=lan,corp.example:2.
+#[derive(Clone, Debug)]
+pub struct SearchList {
+    pub clients: Vec<Subnet>,
+    // Kept as text, and parsed into names when searched.
+    pub suffixes: Vec<String>,
+    pub ndots: usize,
+}
+
+impl SearchList {
+    pub fn parse(value: &str) -> Result<SearchList, String> {
+        let (clients, rest) = value.split_once('=').ok_or_else(|| {
+            format!(
+                "Search list '{}' must look like CIDR=SUFFIX[,SUFFIX...][:NDOTS].",
+                value
+            )
+        })?;
+        let (suffixes, ndots) = match rest.rsplit_once(':') {
+            Some((suffixes, ndots)) => (
+                suffixes,
+                ndots
+                    .parse()
+                    .map_err(|_| format!("'{}' is not a number of dots.", ndots))?,
+            ),
+            None => (rest, DEFAULT_NDOTS),
+        };
+        let suffixes: Vec<String> = suffixes
+            .split(',')
+            .map(|suffix| suffix.trim().trim_end_matches('.').to_string())
+            .collect();
+        for suffix in &suffixes {
+            if suffix.is_empty() {
+                return Err(format!("Search list '{}' has an empty suffix.", value));
+            }
+            suffix.parse::<LabelSequence>().map_err(|err| err.message)?;
+        }
+        Ok(SearchList {
+            clients: clients
+                .split(',')
+                .map(|block| Subnet::parse(block.trim()))
+                .collect::<Result<_, _>>()?,
+            suffixes,
+            ndots,
+        })
+    }
+
+    pub fn applies_to(&self, client: IpAddr) -> bool {
+        self.clients.iter().any(|block| block.contains(client))
+    }
+
+    // The names to try for `name`, in order.
+    pub fn candidates(&self, name: &LabelSequence) -> Vec<LabelSequence> {
+        let expanded = self
+            .suffixes
+            .iter()
+            .filter_map(|suffix| format!("{}.{}", name, suffix).parse().ok());
+        let dots = name.get_labels().len().saturating_sub(1);
+        if dots >= self.ndots {
+            std::iter::once(name.clone()).chain(expanded).collect()
+        } else {
+            expanded.chain(std::iter::once(name.clone())).collect()
+        }
+    }
+
+    // Looks `question` up under each candidate name in turn, until one has records;
+    // those owned by the candidate are renamed back to the question's name. Without
+    // a hit, the answer is that of the last candidate tried, NODATA over NXDOMAIN.
+    // The authorities of another name's answers don't go with them.
+    pub fn lookup(&self, resolver: &dyn Resolve, header: &Header, question: &Question) -> Resolved {
+        let name = question.get_name();
+        if name.get_labels().is_empty() {
+            return resolver.lookup(header, question);
+        }
+        let started = Instant::now();
+        let mut outcome = Lookup::NotFound;
+        for candidate in self.candidates(name) {
+            if started.elapsed() >= SEARCH_BUDGET {
+                println!(
+                    "[SEARCH] Gave up on {} after {:?}, before trying {}.",
+                    name,
+                    started.elapsed(),
+                    candidate
+                );
+                break;
+            }
+            // Expanded names are charged to the query's work budget.
+            let expanded = !candidate.eq_ignore_case(name);
+            if expanded && !budget::follow(&candidate) {
+                return Lookup::Failed.into();
+            }
+            let asked = Question::new(
+                &Arc::new(candidate.clone()),
+                question.get_type(),
+                question.get_class(),
+            );
+            let resolved = resolver.lookup(header, &asked);
+            match &resolved.lookup {
+                Lookup::Found(records) => {
+                    println!("[SEARCH] Answered {} as {}.", name, candidate);
+                    let lookup = Lookup::Found(
+                        records
+                            .iter()
+                            .map(|record| owned_by(record, &candidate, name))
+                            .collect(),
+                    );
+                    return Resolved {
+                        lookup,
+                        authorities: if expanded {
+                            Arc::from([])
+                        } else {
+                            resolved.authorities
+                        },
+                        encoded_answers: None,
+                        ..resolved
+                    };
+                }
+                Lookup::Failed => return resolved,
+                Lookup::FoundNoData => outcome = Lookup::FoundNoData,
+                Lookup::NotFound => {}
+            }
+        }
+        outcome.into()
+    }
+}
+
+// `record` renamed to `name` if `candidate` owns it.
+fn owned_by(record: &Answer, candidate: &LabelSequence, name: &Arc<LabelSequence>) -> Answer {
+    if record
+        .get_name()
+        .to_string()
+        .eq_ignore_ascii_case(&candidate.to_string())
+    {
+        record.with_name(name)
+    } else {
+        record.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::super::dns::message::{RecordClass, RecordType};
+    use super::super::handlers::HandleOpcode;
+    use super::super::listener::{QueryInfo, Transport};
+    use super::super::overload::Load;
+    use super::super::records::{StaticDnsResolver, StaticRecords};
+    use super::super::testing::{query, query_handler};
+    use super::super::{QueryOpcodeHandler, Stats};
+    use super::*;
+
+    // Local data: printer.lan, and nas.corp.example as a CNAME to files.corp.example.
+    struct Recording {
+        records: StaticDnsResolver,
+        asked: Mutex<Vec<String>>,
+    }
+
+    impl Resolve for Recording {
+        fn lookup(&self, header: &Header, question: &Question) -> Resolved {
+            self.asked
+                .lock()
+                .unwrap()
+                .push(question.get_name().to_string());
+            self.records.lookup(header, question)
+        }
+
+        fn provenance(&self, _question: &Question) -> String {
+            String::from("source=static")
+        }
+
+        fn answers_locally(&self, _question: &Question) -> bool {
+            true
+        }
+    }
+
+    struct Nowhere;
+
+    impl Resolve for Nowhere {
+        fn lookup(&self, _header: &Header, _question: &Question) -> Resolved {
+            Lookup::NotFound.into()
+        }
+
+        fn provenance(&self, _question: &Question) -> String {
+            String::from("source=nowhere")
+        }
+
+        fn answers_locally(&self, _question: &Question) -> bool {
+            true
+        }
+    }
+
+    fn record(name: &str, r#type: RecordType, data: &[u8]) -> Answer {
+        Answer::new(
+            /* name= */ &Arc::new(name.parse().unwrap()),
+            /* type= */ r#type,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */ &Arc::from(data),
+        )
+    }
+
+    fn resolver() -> Recording {
+        let mut records = StaticRecords::new();
+        records.add(record("printer.lan", RecordType::A, &[192, 0, 2, 7]));
+        records.add(record(
+            "nas.corp.example",
+            RecordType::Cname,
+            b"\x05files\x04corp\x07example\x00",
+        ));
+        records.add(record("files.corp.example", RecordType::A, &[192, 0, 2, 8]));
+        Recording {
+            records: StaticDnsResolver {
+                records: Arc::new(records),
+                next: Box::new(Nowhere),
+            },
+            asked: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn question(name: &str) -> Question {
+        Question::new(
+            &Arc::new(name.parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        )
+    }
+
+    fn shown(resolved: Resolved) -> Vec<String> {
+        resolved
+            .into_answers()
+            .iter()
+            .map(|answer| answer.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn unqualified_names_are_searched_and_answered_under_their_own_name() {
+        let list = SearchList::parse("192.0.2.0/24=lan,corp.example").unwrap();
+        assert!(list.applies_to("192.0.2.5".parse().unwrap()));
+        assert!(!list.applies_to("198.51.100.5".parse().unwrap()));
+
+        let resolver = resolver();
+        let answers = list.lookup(&resolver, &Header::default(), &question("printer"));
+        assert_eq!(shown(answers), ["printer.    300    IN    A    192.0.2.7"]);
+        assert_eq!(*resolver.asked.lock().unwrap(), ["printer.lan"]);
+
+        // The second suffix; the CNAME's target keeps its name.
+        resolver.asked.lock().unwrap().clear();
+        let answers = list.lookup(&resolver, &Header::default(), &question("nas"));
+        assert_eq!(
+            shown(answers),
+            [
+                "nas.    300    IN    CNAME    files.corp.example.",
+                "files.corp.example.    300    IN    A    192.0.2.8",
+            ]
+        );
+        assert_eq!(
+            *resolver.asked.lock().unwrap(),
+            ["nas.lan", "nas.corp.example"]
+        );
+
+        resolver.asked.lock().unwrap().clear();
+        let answers = list.lookup(&resolver, &Header::default(), &question("scanner"));
+        assert!(matches!(answers.lookup, Lookup::NotFound));
+        assert_eq!(
+            *resolver.asked.lock().unwrap(),
+            ["scanner.lan", "scanner.corp.example", "scanner"]
+        );
+    }
+
+    #[test]
+    fn names_with_ndots_dots_are_tried_as_they_are_first() {
+        let list = SearchList::parse("192.0.2.0/24,2001:db8::/32=lan,corp.example:2").unwrap();
+        let name = |name: &str| -> LabelSequence { name.parse().unwrap() };
+        let candidates = |value: &str| -> Vec<String> {
+            list.candidates(&name(value))
+                .iter()
+                .map(LabelSequence::to_string)
+                .collect()
+        };
+        assert_eq!(
+            candidates("www.example"),
+            ["www.example.lan", "www.example.corp.example", "www.example"]
+        );
+        assert_eq!(
+            candidates("www.example.com"),
+            [
+                "www.example.com",
+                "www.example.com.lan",
+                "www.example.com.corp.example"
+            ]
+        );
+        assert!(list.applies_to("2001:db8::1".parse().unwrap()));
+
+        for value in ["lan", "192.0.2.0/24=", "192.0.2.0/24=lan:x", "10.0.0.0=lan"] {
+            assert!(SearchList::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn other_clients_are_answered_as_before() {
+        let handler = QueryOpcodeHandler {
+            search: vec![SearchList::parse("192.0.2.0/24=lan").unwrap()],
+            ..query_handler(resolver())
+        };
+        let ask = |client: &str| -> Vec<String> {
+            let request = query("printer", None);
+            let info = QueryInfo {
+                listener: "test",
+                client: client.parse().unwrap(),
+                transport: Transport::Udp,
+                load: Load::Normal,
+            };
+            let header = Header::parse_from(request[..12].try_into().unwrap());
+            let response = handler
+                .handle(&info, &header, &request, &Stats::default())
+                .unwrap();
+            response
+                .get_answers()
+                .iter()
+                .map(|answer| answer.to_string())
+                .collect()
+        };
+        assert_eq!(
+            ask("192.0.2.20:5353"),
+            ["printer.    300    IN    A    192.0.2.7"]
+        );
+        assert!(ask("198.51.100.20:5353").is_empty());
+        assert!(ask("[2001:db8::20]:5353").is_empty());
+    }
+}
@@ -0,0 +1,788 @@
+// Secondary zones (--secondary ZONE=PRIMARY): zones copied from their primary server
+// by zone transfer (AXFR, RFC 5936) and answered from the copy, for every name under
+// them: a name the copy doesn't have is NXDOMAIN, and a zone that hasn't been
+// transferred yet, or has expired, is SERVFAIL. Each zone's SOA is asked of the
+// primary every REFRESH of the zone's SOA (RETRY after a failure), and the zone is
+// transferred again when the serial went up (RFC 1982 serial arithmetic); after
+// EXPIRE without reaching the primary, the copy is dropped.
+//
+// So that many zones don't make for bursts of control traffic:
+// - the first refresh of each zone after startup is at a random point of
+//   --transfer-spread-ms;
+// - each zone's timers are shortened by a share of --refresh-jitter drawn for it
+//   once, so that zones with the same REFRESH, started together, drift apart and
+//   stay apart;
+// - at most --max-concurrent-transfers transfers are in flight at once; the zones
+//   waiting for one queue, first come first served;
+// - the --also-notify servers are told of each new version (see notify.rs).
+//
+// SOA queries and transfers are made on threads of their own, for the server loop not
+// to wait on them, and their results taken in by its maintenance. The workers
+// answer from the copies too, through the resolvers they share with it.
+// Nothing is kept across restarts: the zones are transferred afresh every time.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::client::{Client, UpstreamSpec};
+use super::dns::message::{
+    Answer, Header, LabelSequence, LabelSequenceParseError, Message, Question, RCode, RData,
+    RecordType,
+};
+use super::notify::Notifier;
+use super::policy::DomainSuffix;
+use super::query::Query;
+use super::random::Rng;
+use super::records::StaticRecords;
+use super::{Lookup, Resolve, Resolved};
+
+// The type of a zone transfer query (RFC 5936).
+const AXFR: RecordType = RecordType::Unknown(252);
+
+// How long an SOA query, and each read of a transfer, may take.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often the maintenance looks for the results of the work in flight.
+const RESULT_POLL: Duration = Duration::from_millis(20);
+
+// How long a zone not transferred yet, whose SOA says nothing, waits to try again.
+const FIRST_RETRY: Duration = Duration::from_secs(60);
+
+// A secondary zone given on the command line as "example.com=192.0.2.53:53".
+#[derive(Clone, Debug)]
+pub struct SecondarySpec {
+    pub zone: DomainSuffix,
+    pub primary: SocketAddr,
+}
+
+impl SecondarySpec {
+    pub fn parse(spec: &str) -> Result<SecondarySpec, String> {
+        let (zone, primary) = spec.split_once('=').ok_or_else(|| {
+            format!(
+                "Secondary zone '{}' must look like ZONE=PRIMARY-IP:PORT.",
+                spec
+            )
+        })?;
+        let primary = primary
+            .parse()
+            .map_err(|_| format!("'{}' is not an IP:port.", primary))?;
+        Ok(SecondarySpec {
+            zone: DomainSuffix::parse(zone)?,
+            primary,
+        })
+    }
+}
+
+// Whether `serial` is newer than `ours`, in serial number arithmetic (RFC 1982).
+fn serial_newer(serial: u32, ours: u32) -> bool {
+    serial != ours && serial.wrapping_sub(ours) < 1 << 31
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    Idle,
+    // Asking the primary for the zone's SOA.
+    Checking,
+    // Waiting for a transfer slot.
+    Queued,
+    Transferring,
+}
+
+struct Zone {
+    suffix: DomainSuffix,
+    name: Arc<LabelSequence>,
+    primary: SocketAddr,
+    // The share of --refresh-jitter taken off this zone's timers, drawn once.
+    share: f64,
+    phase: Phase,
+    // When the zone is next refreshed, while idle.
+    next_refresh: Instant,
+    // The copy, and its SOA; None until the first transfer, and once it expires.
+    copy: Option<(Arc<StaticRecords>, Answer)>,
+    // When the primary last answered, for EXPIRE.
+    reached: Option<Instant>,
+}
+
+impl Zone {
+    // The copy's serial, REFRESH, RETRY and EXPIRE.
+    fn soa(&self) -> Option<(u32, Duration, Duration, Duration)> {
+        match self.copy.as_ref()?.1.get_rdata() {
+            RData::Soa {
+                serial,
+                refresh,
+                retry,
+                expire,
+                ..
+            } => Some((
+                *serial,
+                Duration::from_secs(*refresh as u64),
+                Duration::from_secs(*retry as u64),
+                Duration::from_secs(*expire as u64),
+            )),
+            _ => None,
+        }
+    }
+
+    fn serial(&self) -> Option<u32> {
+        self.soa().map(|(serial, ..)| serial)
+    }
+}
+
+// What a thread came back with.
+enum Outcome {
+    Serial(Result<u32, String>),
+    // The messages of the transfer, as received.
+    Transfer(Result<Vec<Vec<u8>>, String>),
+}
+
+pub struct SecondaryZones {
+    pub timeout: Duration,
+    pub max_transfers: usize,
+    // The most a zone's timers are shortened by, as a fraction of them.
+    pub jitter: f64,
+    pub notifier: Option<Notifier>,
+    // Locked before the queue when both are.
+    zones: Mutex<Vec<Zone>>,
+    // Zones waiting for a transfer slot, by index, first come first served.
+    queue: Mutex<VecDeque<usize>>,
+    // Transfers in flight, and the most there were at once.
+    active: AtomicUsize,
+    peak: AtomicUsize,
+    ids: Arc<dyn Rng>,
+    sender: Sender<(usize, Outcome)>,
+    results: Mutex<Receiver<(usize, Outcome)>>,
+}
+
+impl SecondaryZones {
+    // The zones of `specs`, first refreshed at points of `spread` from `now` drawn
+    // from `shares`.
+    pub fn new(
+        specs: &[SecondarySpec],
+        max_transfers: usize,
+        jitter: f64,
+        spread: Duration,
+        shares: &dyn Rng,
+        ids: Arc<dyn Rng>,
+        now: Instant,
+    ) -> Result<SecondaryZones, String> {
+        let mut zones = Vec::new();
+        for spec in specs {
+            let name: LabelSequence = spec
+                .zone
+                .to_string()
+                .parse()
+                .map_err(|err: LabelSequenceParseError| err.message)?;
+            let share = shares.fraction();
+            zones.push(Zone {
+                suffix: spec.zone.clone(),
+                name: Arc::new(name),
+                primary: spec.primary,
+                share,
+                phase: Phase::Idle,
+                next_refresh: now + spread.mul_f64(share),
+                copy: None,
+                reached: None,
+            });
+        }
+        let (sender, results) = mpsc::channel();
+        Ok(SecondaryZones {
+            timeout: TRANSFER_TIMEOUT,
+            max_transfers: max_transfers.max(1),
+            jitter,
+            notifier: None,
+            zones: Mutex::new(zones),
+            queue: Mutex::default(),
+            active: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            ids,
+            sender,
+            results: Mutex::new(results),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.zones.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn jittered(&self, interval: Duration, share: f64) -> Duration {
+        interval.mul_f64(1.0 - self.jitter * share)
+    }
+
+    // Takes in what the threads came back with, starts the refreshes and transfers
+    // that are due, and sends the NOTIFYs that are.
+    pub fn maintain(&self, now: Instant) {
+        loop {
+            let Ok((index, outcome)) = self.results.lock().unwrap().try_recv() else {
+                break;
+            };
+            match outcome {
+                Outcome::Serial(serial) => self.checked(index, serial, now),
+                Outcome::Transfer(messages) => {
+                    self.active.fetch_sub(1, Ordering::Relaxed);
+                    self.transferred(index, messages, now);
+                }
+            }
+        }
+        for (index, zone) in self.zones.lock().unwrap().iter_mut().enumerate() {
+            if let (Some((_, _, _, expire)), Some(reached)) = (zone.soa(), zone.reached) {
+                if now >= reached + expire {
+                    println!(
+                        "[SECONDARY] {} expired: {} wasn't reached for {:?}.",
+                        zone.name, zone.primary, expire
+                    );
+                    zone.copy = None;
+                }
+            }
+            if zone.phase == Phase::Idle && zone.next_refresh <= now {
+                zone.phase = Phase::Checking;
+                self.check(index, zone);
+            }
+        }
+        self.start_transfers();
+        if let Some(notifier) = &self.notifier {
+            notifier.poll(now);
+        }
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        let zones = self.zones.lock().unwrap();
+        let busy = zones
+            .iter()
+            .any(|zone| matches!(zone.phase, Phase::Checking | Phase::Transferring));
+        zones
+            .iter()
+            .filter(|zone| zone.phase == Phase::Idle)
+            .map(|zone| zone.next_refresh)
+            .chain(busy.then(|| Instant::now() + RESULT_POLL))
+            .chain(self.notifier.as_ref().and_then(Notifier::next_due))
+            .min()
+    }
+
+    // Asks the zone's primary for its SOA, on a thread of its own.
+    fn check(&self, index: usize, zone: &Zone) {
+        let sender = self.sender.clone();
+        let name = zone.name.to_string();
+        let mut upstream = UpstreamSpec::new(zone.primary);
+        upstream.set_timeout(self.timeout);
+        thread::spawn(move || {
+            let serial = Client::new(upstream)
+                .query(&name, RecordType::Soa)
+                .map_err(|err| err.to_string())
+                .and_then(|resolution| {
+                    resolution
+                        .answers
+                        .iter()
+                        .find_map(|answer| match answer.get_rdata() {
+                            RData::Soa { serial, .. } => Some(*serial),
+                            _ => None,
+                        })
+                        .ok_or_else(|| String::from("no SOA in the answer"))
+                });
+            let _ = sender.send((index, Outcome::Serial(serial)));
+        });
+    }
+
+    fn checked(&self, index: usize, serial: Result<u32, String>, now: Instant) {
+        let mut zones = self.zones.lock().unwrap();
+        let zone = &mut zones[index];
+        match (serial, zone.soa()) {
+            (Ok(serial), Some((ours, refresh, _, _))) if !serial_newer(serial, ours) => {
+                zone.reached = Some(now);
+                zone.phase = Phase::Idle;
+                zone.next_refresh = now + self.jittered(refresh, zone.share);
+            }
+            (Ok(serial), _) => {
+                zone.reached = Some(now);
+                zone.phase = Phase::Queued;
+                println!(
+                    "[SECONDARY] {} has {} at serial {}; queueing a transfer.",
+                    zone.primary, zone.name, serial
+                );
+                self.queue.lock().unwrap().push_back(index);
+            }
+            (Err(err), soa) => {
+                let retry = soa.map_or(FIRST_RETRY, |(_, _, retry, _)| retry);
+                let retry = self.jittered(retry, zone.share);
+                println!(
+                    "[SECONDARY] Failed to ask {} for the SOA of {}: {}; retrying in {:?}.",
+                    zone.primary, zone.name, err, retry
+                );
+                zone.phase = Phase::Idle;
+                zone.next_refresh = now + retry;
+            }
+        }
+    }
+
+    // Starts transfers from the queue while there are slots for them.
+    fn start_transfers(&self) {
+        while self.active.load(Ordering::Relaxed) < self.max_transfers {
+            let Some(index) = self.queue.lock().unwrap().pop_front() else {
+                break;
+            };
+            let mut zones = self.zones.lock().unwrap();
+            let zone = &mut zones[index];
+            zone.phase = Phase::Transferring;
+            let active = self.active.fetch_add(1, Ordering::Relaxed) + 1;
+            self.peak.fetch_max(active, Ordering::Relaxed);
+            println!(
+                "[SECONDARY] Transferring {} from {} ({} in flight, {} queued).",
+                zone.name,
+                zone.primary,
+                active,
+                self.queue.lock().unwrap().len()
+            );
+            let sender = self.sender.clone();
+            let (primary, name, timeout) = (zone.primary, zone.name.to_string(), self.timeout);
+            let id = self.ids.next_u64() as u16;
+            thread::spawn(move || {
+                let messages = transfer(primary, &name, id, timeout).map_err(|err| err.to_string());
+                let _ = sender.send((index, Outcome::Transfer(messages)));
+            });
+        }
+    }
+
+    fn transferred(&self, index: usize, messages: Result<Vec<Vec<u8>>, String>, now: Instant) {
+        let mut zones = self.zones.lock().unwrap();
+        let zone = &mut zones[index];
+        zone.phase = Phase::Idle;
+        match messages.and_then(|messages| read_transfer(&zone.suffix, &messages)) {
+            Ok((records, soa)) => {
+                zone.copy = Some((Arc::new(records), soa));
+                zone.reached = Some(now);
+                let (serial, refresh, _, _) = zone.soa().expect("the copy has its SOA");
+                zone.next_refresh = now + self.jittered(refresh, zone.share);
+                println!(
+                    "[SECONDARY] Transferred {} at serial {} from {}.",
+                    zone.name, serial, zone.primary
+                );
+                if let Some(notifier) = &self.notifier {
+                    notifier.notify(&zone.name, now);
+                }
+            }
+            Err(err) => {
+                let retry = zone.soa().map_or(FIRST_RETRY, |(_, _, retry, _)| retry);
+                let retry = self.jittered(retry, zone.share);
+                println!(
+                    "[SECONDARY] Failed to transfer {} from {}: {}; retrying in {:?}.",
+                    zone.name, zone.primary, err, retry
+                );
+                zone.next_refresh = now + retry;
+            }
+        }
+    }
+
+    // The answer from the copy of the zone `question` is under, if there's one.
+    pub fn lookup(&self, question: &Question) -> Option<Lookup> {
+        let zones = self.zones.lock().unwrap();
+        let zone = closest(&zones, question.get_name())?;
+        Some(match &zone.copy {
+            Some((records, _)) => records.lookup(question),
+            None => Lookup::Failed,
+        })
+    }
+
+    // The SOA of the zone `question` is under, for negative answers from its copy.
+    fn soa_for(&self, question: &Question) -> Option<Answer> {
+        let zones = self.zones.lock().unwrap();
+        let (records, soa) = closest(&zones, question.get_name())?.copy.as_ref()?;
+        match records.lookup(question) {
+            Lookup::FoundNoData | Lookup::NotFound => Some(soa.clone()),
+            _ => None,
+        }
+    }
+
+    fn provenance(&self, question: &Question) -> Option<String> {
+        let zones = self.zones.lock().unwrap();
+        let zone = closest(&zones, question.get_name())?;
+        Some(match zone.serial() {
+            Some(serial) => format!("source=secondary zone={} serial={}", zone.name, serial),
+            None => format!("source=secondary zone={} serial=none", zone.name),
+        })
+    }
+
+    // A line per zone, then the transfers and the NOTIFYs pending, for the control
+    // socket.
+    pub fn report(&self, now: Instant) -> Vec<String> {
+        let zones = self.zones.lock().unwrap();
+        let mut lines: Vec<String> = zones
+            .iter()
+            .map(|zone| {
+                let serial = zone
+                    .serial()
+                    .map_or_else(|| String::from("none"), |serial| serial.to_string());
+                let phase = match zone.phase {
+                    Phase::Idle => format!(
+                        "next refresh in {}s",
+                        zone.next_refresh.saturating_duration_since(now).as_secs()
+                    ),
+                    Phase::Checking => String::from("asking for the SOA"),
+                    Phase::Queued => String::from("queued for a transfer"),
+                    Phase::Transferring => String::from("transferring"),
+                };
+                format!(
+                    "{} from {}: serial {}, {}",
+                    zone.name, zone.primary, serial, phase
+                )
+            })
+            .collect();
+        lines.push(format!(
+            "transfers: {} in flight (at most {}), {} queued, {} at once at the most",
+            self.active.load(Ordering::Relaxed),
+            self.max_transfers,
+            self.queue.lock().unwrap().len(),
+            self.peak.load(Ordering::Relaxed)
+        ));
+        if let Some(notifier) = &self.notifier {
+            lines.extend(notifier.report(now));
+        }
+        lines
+    }
+}
+
+// The zone that `name` is under, the deepest if several are.
+fn closest<'a>(zones: &'a [Zone], name: &LabelSequence) -> Option<&'a Zone> {
+    zones
+        .iter()
+        .filter(|zone| zone.suffix.matches(name))
+        .max_by_key(|zone| zone.suffix.label_count())
+}
+
+// Transfers the zone `name` from `primary` over TCP, up to the SOA that closes it.
+fn transfer(
+    primary: SocketAddr,
+    name: &str,
+    id: u16,
+    timeout: Duration,
+) -> io::Result<Vec<Vec<u8>>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let name: LabelSequence = name
+        .parse()
+        .map_err(|err: LabelSequenceParseError| invalid(err.message))?;
+    let request = Query::new(&Arc::new(name), AXFR).encode(id);
+    let mut stream = TcpStream::connect_timeout(&primary, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut framed = (request.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(&request);
+    stream.write_all(&framed)?;
+    let mut messages = Vec::new();
+    let mut soas = 0;
+    while soas < 2 {
+        let mut length = [0; 2];
+        stream.read_exact(&mut length)?;
+        let mut data = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut data)?;
+        let message = Message::parse_from(&data).map_err(|err| invalid(err.to_string()))?;
+        let header = message.get_header();
+        if header.get_id() != id || !header.get_qr() {
+            return Err(invalid(String::from("a message that isn't the response")));
+        }
+        if *header.get_rcode() != RCode::NoError {
+            return Err(invalid(format!(
+                "the primary answered {}",
+                header.get_rcode()
+            )));
+        }
+        soas += message
+            .get_answers()
+            .iter()
+            .filter(|answer| answer.get_type() == RecordType::Soa)
+            .count();
+        messages.push(data);
+    }
+    Ok(messages)
+}
+
+// The records of a transfer of `zone`, and its SOA.
+fn read_transfer(
+    zone: &DomainSuffix,
+    messages: &[Vec<u8>],
+) -> Result<(StaticRecords, Answer), String> {
+    let mut answers: Vec<Answer> = Vec::new();
+    for data in messages {
+        let message = Message::parse_from(data).map_err(|err| err.to_string())?;
+        answers.extend(message.get_answers().iter().cloned());
+    }
+    let soa = match answers.first() {
+        Some(soa) if soa.get_type() == RecordType::Soa => soa.clone(),
+        _ => return Err(String::from("the transfer doesn't start with the SOA")),
+    };
+    // The SOA closes the transfer again.
+    answers.pop();
+    let mut records = StaticRecords::new();
+    for answer in answers {
+        if !zone.matches(answer.get_name()) {
+            return Err(format!("{} is outside of the zone", answer.get_name()));
+        }
+        records.add(answer);
+    }
+    Ok((records, soa))
+}
+
+// Answers the names under the secondary zones from their copies, and passes the
+// others to the next resolver.
+pub struct SecondaryResolver {
+    pub zones: Arc<SecondaryZones>,
+    pub next: Box<dyn Resolve>,
+}
+
+impl Resolve for SecondaryResolver {
+    fn lookup(&self, header: &Header, question: &Question) -> Resolved {
+        let lookup = match self.zones.lookup(question) {
+            Some(Lookup::Failed) => {
+                println!(
+                    "[SECONDARY] Failing {}: its zone hasn't been transferred.",
+                    question
+                );
+                Lookup::Failed
+            }
+            Some(lookup) => lookup,
+            None => return self.next.lookup(header, question),
+        };
+        // Said here, as the zone may be transferred anew before the response goes.
+        Resolved {
+            authorities: self.zones.soa_for(question).into_iter().collect(),
+            provenance: self.zones.provenance(question),
+            ..lookup.into()
+        }
+    }
+
+    fn provenance(&self, question: &Question) -> String {
+        self.zones
+            .provenance(question)
+            .unwrap_or_else(|| self.next.provenance(question))
+    }
+
+    fn answers_locally(&self, question: &Question) -> bool {
+        self.zones.lookup(question).is_some() || self.next.answers_locally(question)
+    }
+
+    fn prefetch(&self, header: &Header, questions: &[Question]) {
+        let outside: Vec<Question> = questions
+            .iter()
+            .filter(|question| self.zones.lookup(question).is_none())
+            .cloned()
+            .collect();
+        self.next.prefetch(header, &outside);
+    }
+
+    fn maintain(&self) {
+        self.zones.maintain(Instant::now());
+        self.next.maintain();
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        self.next.persist()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        [self.zones.next_due(), self.next.next_due()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use super::super::dns::message::RecordClass;
+    use super::super::handlers::response_header;
+    use super::super::random::Randomness;
+    use super::super::testing::udp_and_tcp;
+    use super::*;
+
+    fn soa(name: &Arc<LabelSequence>, serial: u32) -> Answer {
+        Answer::from_rdata(
+            /* name= */ name,
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */
+            RData::Soa {
+                mname: "ns.test".parse().unwrap(),
+                rname: "admin.test".parse().unwrap(),
+                serial,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 60,
+            },
+        )
+    }
+
+    // A primary for every zone, at serial 7 with a www A record, that notes when each
+    // SOA query came, takes `hold` over each transfer, and counts the transfers in
+    // flight at once.
+    struct Primary {
+        address: SocketAddr,
+        asked: Arc<Mutex<Vec<Instant>>>,
+        transfers: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
+    }
+
+    fn primary(hold: Duration) -> Primary {
+        let (socket, listener) = udp_and_tcp();
+        let address = socket.local_addr().unwrap();
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let times = Arc::clone(&asked);
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                times.lock().unwrap().push(Instant::now());
+                let request = Message::parse_from(&buf[..size]).unwrap();
+                let name = request.get_questions()[0].get_name();
+                let response = Message::new(
+                    &response_header(request.get_header(), RCode::NoError).into(),
+                    request.get_questions(),
+                    &Arc::from([soa(name, 7)]),
+                );
+                let _ = socket.send_to(&response.encode(), source);
+            }
+        });
+        let transfers = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (active, most) = (Arc::new(AtomicUsize::new(0)), Arc::clone(&peak));
+        let count = Arc::clone(&transfers);
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let (active, most, count) =
+                    (Arc::clone(&active), Arc::clone(&most), Arc::clone(&count));
+                thread::spawn(move || {
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    count.fetch_add(1, Ordering::SeqCst);
+                    let mut length = [0; 2];
+                    stream.read_exact(&mut length).unwrap();
+                    let mut request = vec![0; u16::from_be_bytes(length) as usize];
+                    stream.read_exact(&mut request).unwrap();
+                    let request = Message::parse_from(&request).unwrap();
+                    let zone = request.get_questions()[0].get_name();
+                    let www: LabelSequence = format!("www.{}", zone).parse().unwrap();
+                    let header: Arc<Header> =
+                        response_header(request.get_header(), RCode::NoError).into();
+                    thread::sleep(hold);
+                    // Done before the client can see the transfer end and start the
+                    // next one.
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    // The closing SOA comes in a message of its own.
+                    let first = [
+                        soa(zone, 7),
+                        Answer::a(&Arc::new(www), 300, Ipv4Addr::new(192, 0, 2, 80)),
+                    ];
+                    for answers in [&first[..], &[soa(zone, 7)]] {
+                        let response =
+                            Message::new(&header, request.get_questions(), &Arc::from(answers));
+                        let data = response.encode();
+                        let mut framed = (data.len() as u16).to_be_bytes().to_vec();
+                        framed.extend_from_slice(&data);
+                        stream.write_all(&framed).unwrap();
+                    }
+                });
+            }
+        });
+        Primary {
+            address,
+            asked,
+            transfers,
+            peak,
+        }
+    }
+
+    fn question(name: &str) -> Question {
+        Question::new(
+            &Arc::new(name.parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        )
+    }
+
+    #[test]
+    fn refreshes_are_spread_and_transfers_capped() {
+        let primary = primary(Duration::from_millis(150));
+        let specs: Vec<SecondarySpec> = (0..10)
+            .map(|n| SecondarySpec::parse(&format!("zone{n}.test={}", primary.address)).unwrap())
+            .collect();
+        let randomness = Randomness::seeded(3);
+        let start = Instant::now();
+        let zones = SecondaryZones::new(
+            &specs,
+            2,
+            0.1,
+            Duration::from_millis(400),
+            randomness.stream("shares").as_ref(),
+            randomness.stream("ids"),
+            start,
+        )
+        .unwrap();
+        assert!(matches!(
+            zones.lookup(&question("www.zone3.test")),
+            Some(Lookup::Failed)
+        ));
+
+        let transferred = || {
+            zones
+                .zones
+                .lock()
+                .unwrap()
+                .iter()
+                .all(|zone| zone.serial() == Some(7) && zone.phase == Phase::Idle)
+        };
+        while !transferred() && start.elapsed() < Duration::from_secs(5) {
+            zones.maintain(Instant::now());
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(transferred(), "{:#?}", zones.report(Instant::now()));
+
+        // The SOA queries came over most of the window, not in a burst.
+        let asked = primary.asked.lock().unwrap().clone();
+        assert_eq!(asked.len(), 10);
+        let first = *asked.iter().min().unwrap();
+        let last = *asked.iter().max().unwrap();
+        assert!(
+            last - first >= Duration::from_millis(200),
+            "{:?}",
+            last - first
+        );
+        // Two transfers at most were in flight at once, and they did overlap.
+        assert_eq!(primary.transfers.load(Ordering::SeqCst), 10);
+        assert_eq!(primary.peak.load(Ordering::SeqCst), 2);
+        assert_eq!(zones.peak.load(Ordering::SeqCst), 2);
+
+        // The next refreshes are an hour out, less each zone's share of the jitter.
+        let now = Instant::now();
+        for zone in zones.zones.lock().unwrap().iter() {
+            let wait = zone.next_refresh - now;
+            assert!(wait > Duration::from_secs(3240) && wait <= Duration::from_secs(3600));
+        }
+
+        let www = zones.lookup(&question("www.zone3.test")).unwrap();
+        assert_eq!(www.into_answers().len(), 1);
+        assert!(matches!(
+            zones.lookup(&question("nowhere.zone3.test")),
+            Some(Lookup::NotFound)
+        ));
+        assert!(zones.soa_for(&question("nowhere.zone3.test")).is_some());
+        assert!(zones.lookup(&question("www.other.test")).is_none());
+    }
+}
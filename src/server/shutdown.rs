@@ -0,0 +1,216 @@
+// Orderly shutdown, on SIGTERM or SIGINT. The server loop stops before its next
+// iteration, and the teardown steps registered with a Shutdown run in the order of
+// their phases: intake stops, the queries already received are answered, the files
+// being written are flushed, state is persisted, and only then are the sockets
+// closed, so that no step acts on what an earlier one tore down (e.g. no response
+// is logged after the query log was flushed). Steps of a phase run in the order
+// they were registered.
+//
+// Everything runs on the server's one thread, so a step can't be cut short from
+// outside: each is handed its deadline to keep to where it can (draining stops
+// serving at it), and one that takes longer than its timeout is logged as lagging.
+// A step that fails is logged too, and the steps after it run anyway.
+
+use std::{
+    fmt, io,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_terminate(_: libc::c_int) {
+    // A second signal ends the process at once, should the shutdown hang.
+    if TERMINATE.swap(true, Ordering::Relaxed) {
+        // SAFETY: _exit is async-signal-safe.
+        unsafe { libc::_exit(1) };
+    }
+}
+
+// Makes SIGTERM and SIGINT ask the server loop to shut down instead of ending the
+// process where it is.
+pub fn install_termination_handler() {
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        // SAFETY: the handler only stores to an atomic and calls _exit, both of which
+        // are async-signal-safe.
+        unsafe {
+            libc::signal(
+                signal,
+                on_terminate as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+    }
+}
+
+// Whether SIGTERM or SIGINT arrived.
+pub fn termination_requested() -> bool {
+    TERMINATE.load(Ordering::Relaxed)
+}
+
+// The phases of a shutdown, in the order they run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    StopIntake,
+    Drain,
+    FlushWriters,
+    PersistState,
+    CloseSockets,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Phase::StopIntake => "stop intake",
+            Phase::Drain => "drain",
+            Phase::FlushWriters => "flush writers",
+            Phase::PersistState => "persist state",
+            Phase::CloseSockets => "close sockets",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+type Teardown<T> = Box<dyn FnOnce(&mut T, Instant) -> io::Result<()>>;
+
+struct Step<T> {
+    phase: Phase,
+    name: String,
+    timeout: Duration,
+    teardown: Teardown<T>,
+}
+
+// How a step went.
+#[derive(Debug)]
+pub struct StepReport {
+    pub phase: Phase,
+    pub name: String,
+    pub took: Duration,
+    // Whether it took longer than its timeout.
+    pub lagged: bool,
+    pub error: Option<String>,
+}
+
+// The teardown steps of what is being shut down, a T.
+pub struct Shutdown<T> {
+    steps: Vec<Step<T>>,
+}
+
+impl<T> Default for Shutdown<T> {
+    fn default() -> Shutdown<T> {
+        Shutdown { steps: Vec::new() }
+    }
+}
+
+impl<T> Shutdown<T> {
+    // Registers the step `name` of `phase`, which is given until `timeout` after it
+    // starts (its deadline) to tear its part of the T down.
+    pub fn register(
+        &mut self,
+        phase: Phase,
+        name: &str,
+        timeout: Duration,
+        teardown: impl FnOnce(&mut T, Instant) -> io::Result<()> + 'static,
+    ) -> &'_ mut Self {
+        self.steps.push(Step {
+            phase,
+            name: String::from(name),
+            timeout,
+            teardown: Box::new(teardown),
+        });
+        self
+    }
+
+    // Runs the steps on `target`, phase by phase, and reports how each went.
+    pub fn run(mut self, target: &mut T) -> Vec<StepReport> {
+        // Stable, so that a phase's steps keep the order they were registered in.
+        self.steps.sort_by_key(|step| step.phase);
+        let mut reports = Vec::new();
+        for step in self.steps {
+            let started = Instant::now();
+            let result = (step.teardown)(target, started + step.timeout);
+            let took = started.elapsed();
+            let report = StepReport {
+                phase: step.phase,
+                name: step.name,
+                took,
+                lagged: took > step.timeout,
+                error: result.err().map(|err| err.to_string()),
+            };
+            if report.lagged {
+                println!(
+                    "[SHUTDOWN] {} ({}) lagged: took {:?}, over its {:?}.",
+                    report.name, report.phase, took, step.timeout
+                );
+            }
+            if let Some(error) = &report.error {
+                eprintln!(
+                    "[SHUTDOWN] {} ({}) failed: {}",
+                    report.name, report.phase, error
+                );
+            }
+            reports.push(report);
+        }
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn steps_run_by_phase_and_laggards_and_failures_are_reported() {
+        let mut shutdown: Shutdown<Vec<&str>> = Shutdown::default();
+        shutdown
+            .register(
+                Phase::CloseSockets,
+                "listeners",
+                Duration::from_secs(1),
+                |done, _| {
+                    done.push("listeners");
+                    Ok(())
+                },
+            )
+            .register(
+                Phase::FlushWriters,
+                "query log",
+                Duration::from_secs(1),
+                |done, _| {
+                    done.push("query log");
+                    Err(io::Error::other("disk full"))
+                },
+            )
+            .register(
+                Phase::Drain,
+                "connections",
+                Duration::from_millis(10),
+                |done, deadline| {
+                    // Past its deadline, as a step that can't keep to it would be.
+                    thread::sleep(deadline.saturating_duration_since(Instant::now()) * 2);
+                    done.push("connections");
+                    Ok(())
+                },
+            )
+            .register(
+                Phase::FlushWriters,
+                "capture",
+                Duration::from_secs(1),
+                |done, _| {
+                    done.push("capture");
+                    Ok(())
+                },
+            );
+        let mut done = Vec::new();
+        let reports = shutdown.run(&mut done);
+        assert_eq!(done, ["connections", "query log", "capture", "listeners"]);
+        let names: Vec<&str> = reports.iter().map(|report| report.name.as_str()).collect();
+        assert_eq!(names, done);
+        assert!(reports[0].lagged);
+        assert!(reports[1..].iter().all(|report| !report.lagged));
+        assert_eq!(reports[1].error.as_deref(), Some("disk full"));
+        assert!(reports[2].error.is_none());
+        assert!(!termination_requested());
+    }
+}
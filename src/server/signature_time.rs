@@ -0,0 +1,125 @@
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Largest value of the 48-bit Time Signed field of TSIG (RFC 8945, 4.2).
+const MAX_SECONDS: u64 = (1 << 48) - 1;
+
+// A point in time as used by transaction signatures: seconds since the epoch.
+// TSIG carries it as a 48-bit field, which lasts well past 2106 where the 32-bit
+// fields of SIG(0) (RFC 2931) wrap around; those are read relative to a reference
+// time with serial number arithmetic (RFC 1982) instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SignatureTime(u64);
+
+impl SignatureTime {
+    pub fn from_seconds(seconds: u64) -> Result<SignatureTime, String> {
+        if seconds > MAX_SECONDS {
+            return Err(format!(
+                "{} seconds does not fit the 48-bit time field.",
+                seconds
+            ));
+        }
+        Ok(SignatureTime(seconds))
+    }
+
+    pub fn from_system_time(time: SystemTime) -> SignatureTime {
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        SignatureTime(seconds.min(MAX_SECONDS))
+    }
+
+    pub fn seconds(&self) -> u64 {
+        self.0
+    }
+
+    // The 48-bit big-endian wire form.
+    pub fn encode(&self) -> [u8; 6] {
+        let bytes = self.0.to_be_bytes();
+        [bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]]
+    }
+
+    pub fn decode(data: &[u8; 6]) -> SignatureTime {
+        let mut bytes = [0; 8];
+        bytes[2..].copy_from_slice(data);
+        SignatureTime(u64::from_be_bytes(bytes))
+    }
+
+    // The low 32 bits, as carried in the inception and expiration fields of SIG(0).
+    pub fn to_serial32(self) -> u32 {
+        self.0 as u32
+    }
+
+    // Reads a 32-bit SIG(0) time as the value closest to `reference` that has those
+    // low 32 bits, so that times keep working across the 2106 wrap-around.
+    pub fn from_serial32(value: u32, reference: SignatureTime) -> SignatureTime {
+        let offset = value.wrapping_sub(reference.to_serial32()) as i32 as i64;
+        let seconds = (reference.0 as i64 + offset).clamp(0, MAX_SECONDS as i64);
+        SignatureTime(seconds as u64)
+    }
+
+    // Seconds from `self` to `other`, negative if `other` is earlier.
+    pub fn offset_to(&self, other: SignatureTime) -> i64 {
+        other.0 as i64 - self.0 as i64
+    }
+
+    pub fn add_offset(&self, offset: i64) -> SignatureTime {
+        SignatureTime((self.0 as i64 + offset).clamp(0, MAX_SECONDS as i64) as u64)
+    }
+}
+
+impl fmt::Display for SignatureTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Where the current time comes from; injectable so that tests can control it.
+// Send + Sync, for the resolvers that read it to be shared by the worker threads.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SignatureTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SignatureTime {
+        SignatureTime::from_system_time(SystemTime::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: u64) -> SignatureTime {
+        SignatureTime::from_seconds(seconds).unwrap()
+    }
+
+    // 2106-02-07T06:28:16Z, where 32-bit times wrap.
+    const WRAP: u64 = 1 << 32;
+
+    #[test]
+    fn time_signed_uses_all_48_bits() {
+        for seconds in [0, 1_700_000_000, WRAP - 1, WRAP, WRAP + 1, MAX_SECONDS] {
+            assert_eq!(SignatureTime::decode(&at(seconds).encode()), at(seconds));
+        }
+        assert_eq!(at(WRAP).encode(), [0x00, 0x01, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(at(MAX_SECONDS).encode(), [0xFF; 6]);
+        assert!(SignatureTime::from_seconds(MAX_SECONDS + 1).is_err());
+    }
+
+    #[test]
+    fn serial_times_are_read_relative_to_now() {
+        let before_wrap = at(WRAP - 10);
+        assert_eq!(SignatureTime::from_serial32(5, before_wrap), at(WRAP + 5));
+        assert_eq!(
+            SignatureTime::from_serial32((WRAP - 20) as u32, at(WRAP + 5)),
+            at(WRAP - 20)
+        );
+        assert_eq!(at(WRAP + 5).to_serial32(), 5);
+    }
+}
@@ -0,0 +1,168 @@
+// The time checks of transaction signatures: TSIG's Time Signed and fudge (RFC
+// 8945), SIG(0)'s validity period (RFC 2931), the BADTIME error and the client's
+// retry with the server's clock. Nothing verifies or makes TSIG or SIG(0) records
+// yet, so the module is only built for its tests until something does.
+
+use std::time::Duration;
+
+use super::signature_time::{Clock, SignatureTime};
+
+// The TSIG error code for a signature outside its time window (RFC 8945, 5.2.3).
+pub const BADTIME: u16 = 18;
+
+// A signature made outside the acceptable window. Carries our current time, which
+// a BADTIME response must include so that the client can correct its clock.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BadTime {
+    pub server_time: SignatureTime,
+}
+
+// Checks a TSIG's Time Signed against our clock (RFC 8945, 5.2.3): it must be within
+// the signer's fudge, widened by `skew_tolerance`, the clock skew we accept on top.
+pub fn check_time_signed(
+    time_signed: SignatureTime,
+    fudge: u16,
+    skew_tolerance: Duration,
+    clock: &dyn Clock,
+) -> Result<(), BadTime> {
+    let now = clock.now();
+    let allowed = fudge as u64 + skew_tolerance.as_secs();
+    if now.offset_to(time_signed).unsigned_abs() > allowed {
+        return Err(BadTime { server_time: now });
+    }
+    Ok(())
+}
+
+// Checks a SIG(0)'s inception and expiration (RFC 2931, 3) against our clock,
+// accepting `skew_tolerance` of clock skew at either end.
+pub fn check_validity_period(
+    inception: u32,
+    expiration: u32,
+    skew_tolerance: Duration,
+    clock: &dyn Clock,
+) -> Result<(), BadTime> {
+    let now = clock.now();
+    let tolerance = skew_tolerance.as_secs() as i64;
+    let inception = SignatureTime::from_serial32(inception, now);
+    let expiration = SignatureTime::from_serial32(expiration, now);
+    if now.add_offset(tolerance) < inception || now.add_offset(-tolerance) > expiration {
+        return Err(BadTime { server_time: now });
+    }
+    Ok(())
+}
+
+// The time fields of a TSIG record's RDATA (RFC 8945, 4.2) following the algorithm
+// name and the MAC, for building error responses.
+pub struct TsigTimeFields {
+    pub time_signed: SignatureTime,
+    pub fudge: u16,
+    pub error: u16,
+    pub other_data: Vec<u8>,
+}
+
+impl TsigTimeFields {
+    // The fields of the TSIG in a BADTIME response: the request's Time Signed and
+    // fudge, the BADTIME error and our current time as Other Data (RFC 8945, 5.2.3).
+    pub fn badtime(time_signed: SignatureTime, fudge: u16, error: &BadTime) -> TsigTimeFields {
+        TsigTimeFields {
+            time_signed,
+            fudge,
+            error: BADTIME,
+            other_data: error.server_time.encode().to_vec(),
+        }
+    }
+
+    // The server's time out of a BADTIME response, if that's what these fields are.
+    pub fn server_time(&self) -> Option<SignatureTime> {
+        let data: &[u8; 6] = self.other_data.as_slice().try_into().ok()?;
+        (self.error == BADTIME).then(|| SignatureTime::decode(data))
+    }
+}
+
+// Client side: signs with our clock and, if the server answers BADTIME, adopts the
+// server's clock offset and signs once more. `attempt` signs and sends the request
+// with the given time and returns the server's verdict. Also returns the offset
+// used, to be reused for later requests to the same server.
+pub fn retry_on_badtime<T>(
+    clock: &dyn Clock,
+    mut attempt: impl FnMut(SignatureTime) -> Result<T, BadTime>,
+) -> (Result<T, BadTime>, i64) {
+    let now = clock.now();
+    match attempt(now) {
+        Err(BadTime { server_time }) => {
+            let offset = now.offset_to(server_time);
+            (attempt(clock.now().add_offset(offset)), offset)
+        }
+        result => (result, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::ManualClock;
+    use super::*;
+
+    fn at(seconds: u64) -> SignatureTime {
+        SignatureTime::from_seconds(seconds).unwrap()
+    }
+
+    // 2106-02-07T06:28:16Z, where 32-bit times wrap.
+    const WRAP: u64 = 1 << 32;
+
+    #[test]
+    fn time_signed_must_be_within_fudge_and_tolerance() {
+        let clock = ManualClock::at(1_700_000_000);
+        let check = |signed: u64, tolerance: u64| {
+            check_time_signed(at(signed), 300, Duration::from_secs(tolerance), &clock)
+        };
+        assert!(check(1_700_000_300, 0).is_ok());
+        assert!(check(1_699_999_700, 0).is_ok());
+        assert!(check(1_700_000_301, 0).is_err());
+        assert!(check(1_699_999_699, 0).is_err());
+        assert!(check(1_700_000_330, 30).is_ok());
+        assert!(check(1_700_000_331, 30).is_err());
+        assert_eq!(
+            check(1_800_000_000, 0),
+            Err(BadTime {
+                server_time: at(1_700_000_000)
+            })
+        );
+    }
+
+    #[test]
+    fn validity_period_is_checked_across_the_wrap() {
+        let clock = ManualClock::at(WRAP + 100);
+        let (inception, expiration) = ((WRAP - 100) as u32, (WRAP + 200) as u32);
+        assert!(check_validity_period(inception, expiration, Duration::ZERO, &clock).is_ok());
+        clock.set(WRAP + 201);
+        assert!(check_validity_period(inception, expiration, Duration::ZERO, &clock).is_err());
+        assert!(
+            check_validity_period(inception, expiration, Duration::from_secs(1), &clock).is_ok()
+        );
+    }
+
+    #[test]
+    fn badtime_response_carries_our_time() {
+        let clock = ManualClock::at(1_700_000_000);
+        let error = check_time_signed(at(1_600_000_000), 300, Duration::ZERO, &clock).unwrap_err();
+        let fields = TsigTimeFields::badtime(at(1_600_000_000), 300, &error);
+        assert_eq!(fields.error, BADTIME);
+        assert_eq!(fields.time_signed, at(1_600_000_000));
+        assert_eq!(fields.fudge, 300);
+        assert_eq!(fields.server_time(), Some(at(1_700_000_000)));
+    }
+
+    #[test]
+    fn client_adopts_the_server_clock_and_retries() {
+        let server = ManualClock::at(1_700_000_000);
+        let client = ManualClock::at(1_699_990_000);
+        let mut attempts: Vec<SignatureTime> = Vec::new();
+        let (result, offset) = retry_on_badtime(&client, |time_signed| {
+            attempts.push(time_signed);
+            check_time_signed(time_signed, 300, Duration::ZERO, &server)
+        });
+        assert!(result.is_ok());
+        assert_eq!(offset, 10_000);
+        assert_eq!(attempts, [at(1_699_990_000), at(1_700_000_000)]);
+    }
+}
@@ -0,0 +1,452 @@
+// Latency service level objectives (--slo), e.g. forwarded:p95<50ms: the share of
+// the queries of a class (forwarded, authoritative, cache-hit or overall) that has to
+// be answered within a threshold, over a sliding window (--slo-window). The window
+// is kept in SLICES slices, each counting the queries answered within each SLO's
+// threshold, and their latencies in log-scale buckets (four to a power of two, as
+// an HDR histogram would) to estimate the percentile with; slices older than the
+// window are dropped, so a percentile reflects the last window only. Compliance
+// itself is exact, not estimated: the percentile is under the threshold exactly when
+// that share of the queries was. Each query is evaluated against the SLOs of its
+// class as it's recorded; a breach is logged as an [SLO] event when it starts, and
+// again once per window for as long as it lasts, and the recovery when it ends.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Slices per window.
+const SLICES: u32 = 10;
+// Latency buckets: bucket i holds latencies under 2^((i + 1) / 4) microseconds.
+const BUCKETS: usize = 4 * 32;
+
+// What a query was answered from, as far as latency goes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueryClass {
+    // Answered from the cache (--cache).
+    CacheHit,
+    // Asked of the upstream.
+    Forwarded,
+    // Answered from the local records, pins and the like.
+    Authoritative,
+    // Every query, whatever it was answered from.
+    Overall,
+}
+
+impl QueryClass {
+    fn label(&self) -> &'static str {
+        match self {
+            QueryClass::CacheHit => "cache-hit",
+            QueryClass::Forwarded => "forwarded",
+            QueryClass::Authoritative => "authoritative",
+            QueryClass::Overall => "overall",
+        }
+    }
+
+    // Whether a query of class `class` counts towards an SLO on this one.
+    fn covers(&self, class: QueryClass) -> bool {
+        *self == QueryClass::Overall || *self == class
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SloSpec {
+    pub class: QueryClass,
+    // The share within the threshold, in hundredths of a percent (p99.9 is 9990).
+    pub target: u32,
+    pub threshold: Duration,
+}
+
+impl SloSpec {
+    // Parses CLASS:pPERCENTILE<THRESHOLD, e.g. forwarded:p95<50ms, with the threshold
+    // in us, ms or s.
+    pub fn parse(value: &str) -> Result<SloSpec, String> {
+        let usage = || {
+            format!(
+                "SLO '{}' must look like CLASS:pPERCENTILE<THRESHOLD (e.g. forwarded:p95<50ms).",
+                value
+            )
+        };
+        let (class, objective) = value.split_once(':').ok_or_else(usage)?;
+        let (percentile, threshold) = objective
+            .strip_prefix('p')
+            .and_then(|objective| objective.split_once('<'))
+            .ok_or_else(usage)?;
+        let class = [
+            QueryClass::CacheHit,
+            QueryClass::Forwarded,
+            QueryClass::Authoritative,
+            QueryClass::Overall,
+        ]
+        .into_iter()
+        .find(|known| known.label() == class.trim())
+        .ok_or_else(|| {
+            format!(
+                "'{}' is not a query class (cache-hit, forwarded, authoritative or overall).",
+                class
+            )
+        })?;
+        let target = match percentile.trim().split_once('.') {
+            Some((whole, fraction)) if !fraction.is_empty() && fraction.len() <= 2 => whole
+                .parse::<u32>()
+                .ok()
+                .zip(format!("{:0<2}", fraction).parse::<u32>().ok())
+                .map(|(whole, fraction)| whole * 100 + fraction),
+            Some(_) => None,
+            None => percentile
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .map(|whole| whole * 100),
+        }
+        .filter(|target| (1..10000).contains(target))
+        .ok_or_else(|| format!("'p{}' is not a percentile (e.g. p95, p99.9).", percentile))?;
+        let threshold = threshold.trim();
+        let (digits, unit) = if let Some(digits) = threshold.strip_suffix("us") {
+            (digits, Duration::from_micros(1))
+        } else if let Some(digits) = threshold.strip_suffix("ms") {
+            (digits, Duration::from_millis(1))
+        } else if let Some(digits) = threshold.strip_suffix('s') {
+            (digits, Duration::from_secs(1))
+        } else {
+            (threshold, Duration::from_millis(1))
+        };
+        let threshold = digits
+            .parse::<u32>()
+            .ok()
+            .filter(|&count| count > 0)
+            .map(|count| unit * count)
+            .ok_or_else(|| format!("'{}' is not a latency (e.g. 1ms, 500us).", threshold))?;
+        Ok(SloSpec {
+            class,
+            target,
+            threshold,
+        })
+    }
+}
+
+impl fmt::Display for SloSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} p{}", self.class.label(), self.target / 100)?;
+        if self.target % 100 > 0 {
+            write!(
+                f,
+                ".{}",
+                format!("{:02}", self.target % 100).trim_end_matches('0')
+            )?;
+        }
+        write!(f, " < {:?}", self.threshold)
+    }
+}
+
+// Where an SLO stands over the last window.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SloStatus {
+    pub slo: String,
+    pub samples: u64,
+    // The share of them within the threshold, in hundredths of a percent; all of
+    // them if there were none.
+    pub within: u32,
+    // The percentile, estimated from the buckets.
+    pub estimate: Option<Duration>,
+    pub met: bool,
+}
+
+impl fmt::Display for SloStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}, {}.{:02}% of {} within",
+            self.slo,
+            if self.met { "met" } else { "breached" },
+            self.within / 100,
+            self.within % 100,
+            self.samples
+        )?;
+        if let Some(estimate) = self.estimate {
+            write!(f, ", ~{:?}", estimate)?;
+        }
+        Ok(())
+    }
+}
+
+struct Slice {
+    // Which slice of time since the tracker started.
+    index: u64,
+    samples: u64,
+    within: u64,
+    buckets: [u64; BUCKETS],
+}
+
+struct Tracked {
+    spec: SloSpec,
+    slices: VecDeque<Slice>,
+    breached: bool,
+    // When the ongoing breach was last logged.
+    logged_at: Option<Instant>,
+}
+
+pub struct SloTracker {
+    window: Duration,
+    started: Instant,
+    // Whether there are SLOs at all, so that queries go untimed without the lock.
+    enabled: bool,
+    slos: Mutex<Vec<Tracked>>,
+}
+
+impl Default for SloTracker {
+    fn default() -> Self {
+        SloTracker::new(&[], Duration::from_secs(60), Instant::now())
+    }
+}
+
+// The bucket of `latency`.
+fn bucket(latency: Duration) -> usize {
+    let micros = latency.as_micros().max(1) as f64;
+    ((micros.log2() * 4.0) as usize).min(BUCKETS - 1)
+}
+
+// The upper bound of the latencies in `bucket`.
+fn bucket_bound(bucket: usize) -> Duration {
+    Duration::from_secs_f64(2f64.powf((bucket + 1) as f64 / 4.0) / 1_000_000.0)
+}
+
+impl SloTracker {
+    pub fn new(specs: &[SloSpec], window: Duration, now: Instant) -> SloTracker {
+        SloTracker {
+            window,
+            started: now,
+            enabled: !specs.is_empty(),
+            slos: Mutex::new(
+                specs
+                    .iter()
+                    .map(|spec| Tracked {
+                        spec: spec.clone(),
+                        slices: VecDeque::new(),
+                        breached: false,
+                        logged_at: None,
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.enabled
+    }
+
+    // The slice `now` falls in.
+    fn slice_index(&self, now: Instant) -> u64 {
+        let slice = (self.window / SLICES).max(Duration::from_millis(1));
+        (now.saturating_duration_since(self.started).as_nanos() / slice.as_nanos()) as u64
+    }
+
+    // Records that a query of `class` took `latency`, and logs the SLO events.
+    pub fn record(&self, class: QueryClass, latency: Duration) {
+        for event in self.record_at(class, latency, Instant::now()) {
+            println!("{}", event);
+        }
+    }
+
+    // Records that a query of `class` took `latency` at `now`, and returns the SLO
+    // events to log.
+    pub fn record_at(&self, class: QueryClass, latency: Duration, now: Instant) -> Vec<String> {
+        let index = self.slice_index(now);
+        let mut events = Vec::new();
+        let mut slos = self.slos.lock().unwrap();
+        for tracked in slos
+            .iter_mut()
+            .filter(|tracked| tracked.spec.class.covers(class))
+        {
+            while tracked
+                .slices
+                .front()
+                .is_some_and(|slice| slice.index + SLICES as u64 <= index)
+            {
+                tracked.slices.pop_front();
+            }
+            if tracked
+                .slices
+                .back()
+                .map_or(true, |slice| slice.index != index)
+            {
+                tracked.slices.push_back(Slice {
+                    index,
+                    samples: 0,
+                    within: 0,
+                    buckets: [0; BUCKETS],
+                });
+            }
+            let slice = tracked.slices.back_mut().expect("just pushed");
+            slice.samples += 1;
+            slice.within += (latency <= tracked.spec.threshold) as u64;
+            slice.buckets[bucket(latency)] += 1;
+
+            let status = self.status(tracked, index);
+            let due = tracked
+                .logged_at
+                .map_or(true, |at| now.saturating_duration_since(at) >= self.window);
+            if !status.met && (!tracked.breached || due) {
+                events.push(format!(
+                    "[SLO] event=breach slo=\"{}\" window={:?} samples={} within={}.{:02}% \
+                     estimate={:?}",
+                    tracked.spec,
+                    self.window,
+                    status.samples,
+                    status.within / 100,
+                    status.within % 100,
+                    status.estimate.unwrap_or_default()
+                ));
+                tracked.breached = true;
+                tracked.logged_at = Some(now);
+            } else if status.met && tracked.breached {
+                events.push(format!(
+                    "[SLO] event=recovered slo=\"{}\" window={:?} samples={} within={}.{:02}%",
+                    tracked.spec,
+                    self.window,
+                    status.samples,
+                    status.within / 100,
+                    status.within % 100
+                ));
+                tracked.breached = false;
+                tracked.logged_at = None;
+            }
+        }
+        events
+    }
+
+    // Where `tracked` stands over the window ending in slice `index`.
+    fn status(&self, tracked: &Tracked, index: u64) -> SloStatus {
+        let live = || {
+            tracked
+                .slices
+                .iter()
+                .filter(move |slice| slice.index + SLICES as u64 > index)
+        };
+        let samples: u64 = live().map(|slice| slice.samples).sum();
+        let within: u64 = live().map(|slice| slice.within).sum();
+        // The nearest rank of the percentile among the samples.
+        let rank = (samples * tracked.spec.target as u64).div_ceil(10000);
+        let mut seen = 0;
+        let estimate = (samples > 0)
+            .then(|| {
+                (0..BUCKETS).find(|&bucket| {
+                    seen += live().map(|slice| slice.buckets[bucket]).sum::<u64>();
+                    seen >= rank
+                })
+            })
+            .flatten()
+            .map(bucket_bound);
+        SloStatus {
+            slo: tracked.spec.to_string(),
+            samples,
+            within: (within * 10000)
+                .checked_div(samples)
+                .map_or(10000, |share| share as u32),
+            estimate,
+            met: within * 10000 >= samples * tracked.spec.target as u64,
+        }
+    }
+
+    // Where each SLO stands over the window up to `now`.
+    pub fn statuses(&self, now: Instant) -> Vec<SloStatus> {
+        let index = self.slice_index(now);
+        self.slos
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|tracked| self.status(tracked, index))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(count: u64) -> Duration {
+        Duration::from_millis(count)
+    }
+
+    #[test]
+    fn compliance_flips_as_the_percentile_crosses_the_threshold() {
+        assert_eq!(
+            SloSpec::parse("cache-hit:p99.9<1ms").unwrap().to_string(),
+            "cache-hit p99.9 < 1ms"
+        );
+        assert!(SloSpec::parse("forwarded:p100<50ms").is_err());
+        assert!(SloSpec::parse("cached:p95<50ms").is_err());
+        let started = Instant::now();
+        let tracker = SloTracker::new(
+            &[
+                SloSpec::parse("forwarded:p95<50ms").unwrap(),
+                SloSpec::parse("authoritative:p99<1ms").unwrap(),
+                SloSpec::parse("overall:p50<100ms").unwrap(),
+            ],
+            Duration::from_secs(60),
+            started,
+        );
+        let at = |seconds: u64| started + Duration::from_secs(seconds);
+
+        // 95 of 100 forwarded queries within 50ms: p95 is under it, just.
+        let mut events = Vec::new();
+        for index in 0..100 {
+            let latency = if index % 20 == 19 {
+                millis(80)
+            } else {
+                millis(20)
+            };
+            events.extend(tracker.record_at(QueryClass::Forwarded, latency, at(1)));
+        }
+        assert!(events.is_empty(), "{:?}", events);
+        let statuses = tracker.statuses(at(1));
+        assert_eq!(statuses[0].within, 9500);
+        assert!(statuses[0].met);
+        assert!(
+            statuses[0].estimate.unwrap() < millis(50),
+            "{:?}",
+            statuses[0]
+        );
+        // Authoritative queries have none yet, and forwarded ones don't count for them.
+        assert_eq!(statuses[1].samples, 0);
+        assert!(statuses[1].met);
+        // Every query counts for the overall SLO.
+        assert_eq!(statuses[2].samples, 100);
+        assert!(statuses[2].met);
+
+        // One slower query breaches the forwarded SLO; more, in the same window, log
+        // nothing more.
+        let events = tracker.record_at(QueryClass::Forwarded, millis(80), at(2));
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert!(
+            events[0].starts_with("[SLO] event=breach slo=\"forwarded p95 < 50ms\" window=60s samples=101 within=94.05%"),
+            "{}",
+            events[0]
+        );
+        for _ in 0..50 {
+            assert!(tracker
+                .record_at(QueryClass::Forwarded, millis(80), at(3))
+                .is_empty());
+        }
+        // A window later, the ongoing breach is logged again.
+        let events = tracker.record_at(QueryClass::Forwarded, millis(80), at(45));
+        assert!(events.is_empty());
+        let events = tracker.record_at(QueryClass::Forwarded, millis(80), at(62));
+        assert_eq!(events.len(), 1, "{:?}", events);
+
+        // Once the slow queries are out of the window, fast ones bring it back.
+        let mut events = Vec::new();
+        for _ in 0..20 {
+            events.extend(tracker.record_at(QueryClass::Forwarded, millis(5), at(125)));
+        }
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert!(
+            events[0].starts_with("[SLO] event=recovered slo=\"forwarded p95 < 50ms\""),
+            "{}",
+            events[0]
+        );
+        assert_eq!(tracker.statuses(at(125))[0].samples, 20);
+    }
+}
@@ -0,0 +1,287 @@
+// Split-brain detection (--check-udp-tcp-consistency). Some load balancers front
+// different resolver pools on 53/udp and 53/tcp, so that the answers a client gets
+// change once a truncated response sends it over TCP. When the upstream truncates a
+// response, the question is asked again of the same upstream over TCP, and the UDP
+// answer is compared with the TCP one: records may be missing from the UDP answer,
+// as it was truncated, but every record it has should be in the TCP answer, and the
+// RCODEs should agree. Anything else is a divergence; it is counted, per upstream
+// too, and logged with the differences (see verify::diff_rrsets). This is telemetry
+// only: the client is served what it would have been without it.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::Mutex,
+    time::Duration,
+};
+
+use super::dns::message::{Answer, Message};
+use super::stats::ShardedCounter;
+use super::verify::{diff_rrsets, normalized_rrsets};
+
+#[derive(Default)]
+pub struct SplitBrainStats {
+    pub checked: ShardedCounter,
+    pub diverged: ShardedCounter,
+}
+
+impl fmt::Display for SplitBrainStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "truncated answers checked over TCP: {}, divergences: {}",
+            self.checked.get(),
+            self.diverged.get()
+        )
+    }
+}
+
+// How an upstream's UDP and TCP answers have compared.
+#[derive(Default)]
+struct Divergence {
+    checked: u64,
+    diverged: u64,
+}
+
+// Sends `request` to `upstream` over TCP (RFC 1035, 4.2.2) and reads its response.
+pub fn exchange_over_tcp(
+    upstream: SocketAddr,
+    request: &[u8],
+    timeout: Duration,
+) -> io::Result<Message> {
+    let mut stream = TcpStream::connect_timeout(&upstream, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut framed = (request.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(request);
+    stream.write_all(&framed)?;
+    let mut length = [0; 2];
+    stream.read_exact(&mut length)?;
+    let mut response = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut response)?;
+    Message::parse_from(&response)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+}
+
+pub struct SplitBrainCheck {
+    // How long the TCP exchange may take.
+    pub timeout: Duration,
+    pub stats: SplitBrainStats,
+    // By upstream, keyed as in the UpstreamStateStore.
+    upstreams: Mutex<BTreeMap<String, Divergence>>,
+}
+
+impl SplitBrainCheck {
+    pub fn new(timeout: Duration) -> SplitBrainCheck {
+        SplitBrainCheck {
+            timeout,
+            stats: Default::default(),
+            upstreams: Default::default(),
+        }
+    }
+
+    // How the truncated UDP answer differs from the TCP one, beyond what truncation
+    // explains; empty if it doesn't.
+    pub fn divergences(truncated: &Message, full: &Message) -> Vec<String> {
+        let udp_rcode = truncated.get_header().get_rcode();
+        let tcp_rcode = full.get_header().get_rcode();
+        if udp_rcode != tcp_rcode {
+            return vec![format!(
+                "answered {} over UDP, {} over TCP",
+                udp_rcode, tcp_rcode
+            )];
+        }
+        let encoded = |records: &[Answer]| -> Vec<_> {
+            normalized_rrsets(records)
+                .iter()
+                .map(Answer::encode)
+                .collect()
+        };
+        let over_tcp = encoded(full.get_answers());
+        let consistent = encoded(truncated.get_answers())
+            .iter()
+            .all(|record| over_tcp.contains(record));
+        if consistent {
+            return Vec::new();
+        }
+        diff_rrsets(truncated.get_answers(), full.get_answers())
+    }
+
+    // Asks `request`, which `upstream` (known as `key`) answered with the truncated
+    // `response`, again over TCP, and records how the answers compare.
+    pub fn check(&self, key: &str, upstream: SocketAddr, request: &Message, response: &Message) {
+        match exchange_over_tcp(upstream, &request.encode(), self.timeout) {
+            Ok(full) => self.compare(key, upstream, request, response, &full),
+            Err(err) => println!(
+                "[SPLIT-BRAIN] Couldn't ask {} over TCP to compare: {}",
+                upstream, err
+            ),
+        }
+    }
+
+    // Records how the truncated `response` compares with `full`, the answer to
+    // `request` over TCP, e.g. the one the forwarder fell back to.
+    pub fn compare(
+        &self,
+        key: &str,
+        upstream: SocketAddr,
+        request: &Message,
+        response: &Message,
+        full: &Message,
+    ) {
+        self.stats.checked.increment();
+        let differences = SplitBrainCheck::divergences(response, full);
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let divergence = upstreams.entry(String::from(key)).or_default();
+        divergence.checked += 1;
+        if differences.is_empty() {
+            return;
+        }
+        divergence.diverged += 1;
+        self.stats.diverged.increment();
+        println!(
+            "[SPLIT-BRAIN] ALERT: {} answers {} differently over UDP and TCP ({}):\n  {}",
+            upstream,
+            request
+                .get_questions()
+                .first()
+                .map_or(String::new(), |question| question.to_string()),
+            self.stats,
+            differences.join("\n  ")
+        );
+    }
+
+    // One line per upstream checked, e.g. "udp/192.0.2.53:53 udp/tcp divergence 25%
+    // (1 of 4)", for the control socket.
+    pub fn report(&self) -> Vec<String> {
+        self.upstreams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, divergence)| {
+                format!(
+                    "{} udp/tcp divergence {}% ({} of {})",
+                    key,
+                    divergence.diverged * 100 / divergence.checked,
+                    divergence.diverged,
+                    divergence.checked
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::super::dns::message::{Header, Question, RCode, RecordClass, RecordType};
+    use super::super::testing::{forwarder, udp_and_tcp};
+    use super::super::{ControlSocket, Lookup, PinStore, Resolve};
+    use super::*;
+
+    // The records of an answer to the query `request`, for `addresses`.
+    fn answer(request: &[u8], addresses: &[[u8; 4]], truncated: bool) -> Vec<u8> {
+        let mut response = request.to_vec();
+        response[2] |= if truncated { 0x82 } else { 0x80 };
+        response[7] = addresses.len() as u8;
+        for address in addresses {
+            response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+            response.extend_from_slice(address);
+        }
+        response
+    }
+
+    // An upstream that truncates every answer over UDP to its first address, and
+    // over TCP answers names starting with "split" from another pool of resolvers.
+    fn split_upstream() -> SocketAddr {
+        let (udp, tcp) = udp_and_tcp();
+        let address = udp.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = udp.recv_from(&mut buf) {
+                let _ = udp.send_to(&answer(&buf[..size], &[[192, 0, 2, 1]], true), source);
+            }
+        });
+        thread::spawn(move || {
+            for mut stream in tcp.incoming().flatten() {
+                let mut length = [0; 2];
+                stream.read_exact(&mut length).unwrap();
+                let mut request = vec![0; u16::from_be_bytes(length) as usize];
+                stream.read_exact(&mut request).unwrap();
+                let addresses: &[[u8; 4]] = if request[13..].starts_with(b"split") {
+                    &[[198, 51, 100, 1], [198, 51, 100, 2]]
+                } else {
+                    &[[192, 0, 2, 2], [192, 0, 2, 1]]
+                };
+                let response = answer(&request, addresses, false);
+                let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+                framed.extend_from_slice(&response);
+                stream.write_all(&framed).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn answers_differing_beyond_truncation_are_detected() {
+        let upstream = split_upstream();
+        let check = Arc::new(SplitBrainCheck::new(Duration::from_secs(5)));
+        let mut resolver = forwarder(upstream, 10.0);
+        resolver.split_brain = Some(Arc::clone(&check));
+        // Without the forwarder's own TCP fallback, which would serve the TCP answer.
+        resolver.tcp_fallback = None;
+        let lookup = |name: &str| {
+            let question = Question::new(
+                &Arc::new(name.parse().unwrap()),
+                RecordType::A,
+                RecordClass::In,
+            );
+            resolver.lookup(&Header::default(), &question).lookup
+        };
+
+        // Truncation only drops records: consistent.
+        for name in ["www.example.com", "mail.example.com", "ftp.example.com"] {
+            assert!(matches!(lookup(name), Lookup::Found(answers) if answers.len() == 1));
+        }
+        assert_eq!(check.stats.diverged.get(), 0);
+        // The client still gets the UDP answer.
+        let Lookup::Found(answers) = lookup("split.example.com") else {
+            panic!("no answer");
+        };
+        assert_eq!(answers[0].get_data().as_ref(), [192, 0, 2, 1]);
+        assert_eq!(check.stats.checked.get(), 4);
+        assert_eq!(check.stats.diverged.get(), 1);
+
+        let path =
+            std::env::temp_dir().join(format!("control-{}-split-brain.sock", std::process::id()));
+        let mut control = ControlSocket::bind(path, Arc::new(PinStore::default())).unwrap();
+        control.split_brain = Some(Arc::clone(&check));
+        assert_eq!(
+            control.execute("divergence"),
+            format!("udp/{} udp/tcp divergence 25% (1 of 4)\n", upstream)
+        );
+    }
+
+    #[test]
+    fn rcodes_must_agree() {
+        let request =
+            Message::parse_from(&[0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, b'x', 0, 0, 1, 0, 1])
+                .unwrap();
+        let mut header = request.get_header().as_ref().clone();
+        header.set_qr(true).set_tc(true);
+        let truncated = Message::new(
+            &Arc::new(header.clone()),
+            request.get_questions(),
+            &Arc::from([]),
+        );
+        header.set_tc(false).set_rcode(RCode::NameError);
+        let full = Message::new(&Arc::new(header), request.get_questions(), &Arc::from([]));
+        let differences = SplitBrainCheck::divergences(&truncated, &full);
+        assert_eq!(differences.len(), 1);
+        assert!(differences[0].contains("NAME_ERROR"), "{:?}", differences);
+        assert!(SplitBrainCheck::divergences(&truncated, &truncated).is_empty());
+    }
+}
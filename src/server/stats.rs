@@ -0,0 +1,622 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use super::anomaly::AnomalyReason;
+use super::bad_packets::BadPackets;
+use super::blocklist::Blocklist;
+use super::breaker::{BreakerStatus, CircuitBreaker};
+use super::edns::UDP_PAYLOAD_SIZE;
+use super::expiry::{ExpiryCounters, ExpiryCounts};
+use super::slo::{SloStatus, SloTracker};
+use super::support::{Json, ToJson};
+use super::truncation::{TruncationCounts, TruncationTracker};
+use super::upstreams::{UpstreamCounts, UpstreamSet};
+
+// Number of shards per counter. Threads are spread over the shards round-robin,
+// so increments from different threads rarely touch the same cache line.
+const SHARD_COUNT: usize = 16;
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD_INDEX: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARD_COUNT;
+}
+
+// Padded to a cache line so that neighbouring shards don't false-share.
+#[derive(Default)]
+#[repr(align(64))]
+struct Shard(AtomicU64);
+
+// A monotonically increasing counter for the hot path: each thread increments
+// its own shard and readers sum all shards.
+#[derive(Default)]
+pub struct ShardedCounter {
+    shards: [Shard; SHARD_COUNT],
+}
+
+impl ShardedCounter {
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        let index = SHARD_INDEX.with(|index| *index);
+        self.shards[index].0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| shard.0.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+// Counters kept separately for every listener.
+#[derive(Default)]
+pub struct ListenerStats {
+    pub tag: String,
+    pub queries_received: ShardedCounter,
+    pub responses_sent: ShardedCounter,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    pub queries_received: ShardedCounter,
+    pub responses_sent: ShardedCounter,
+    pub refused_by_class: ShardedCounter,
+    pub refused_by_name: ShardedCounter,
+    pub refused_by_type: ShardedCounter,
+    pub truncated: ShardedCounter,
+    // Queries shed under overload, answered with SERVFAIL or dropped.
+    pub shed_with_servfail: ShardedCounter,
+    pub shed_by_dropping: ShardedCounter,
+    // Datagrams from clients over their in-flight quota, served as if overloaded, and
+    // connections closed for queueing too many queries (see quota.rs).
+    pub over_client_quota: ShardedCounter,
+    pub abusive_connections: ShardedCounter,
+    // Responses over the size budget squeezed by each minimization step, TC or not.
+    pub minimized_additionals: ShardedCounter,
+    pub minimized_authorities: ShardedCounter,
+    pub minimized_addresses: ShardedCounter,
+    // Responses whose encoding didn't read back as the message, answered SERVFAIL.
+    pub encoding_failures: ShardedCounter,
+    // Responses the socket wouldn't send (e.g. its buffer was full, or the client's
+    // address was unusable), which the client never gets.
+    pub send_failures: ShardedCounter,
+    // Queries given up on for going over their work budget (see budget.rs).
+    pub over_work_budget: ShardedCounter,
+    // Answer RRsets not on the CNAME chain from the question's name, placed after it
+    // (see chain.rs).
+    pub off_chain_rrsets: ShardedCounter,
+    // Address records removed by --filter-aaaa or --filter-a, queries for the filtered
+    // type answered NODATA instead, and HTTPS/SVCB records stripped of address hints.
+    pub filtered_records: ShardedCounter,
+    pub filtered_to_nodata: ShardedCounter,
+    pub filtered_hints: ShardedCounter,
+    // Heap allocations made while answering queries, and how many were measured.
+    #[cfg(feature = "profiling")]
+    pub query_allocations: ShardedCounter,
+    #[cfg(feature = "profiling")]
+    pub profiled_queries: ShardedCounter,
+    // Policy script evaluations, the time they took, and how many failed.
+    #[cfg(feature = "scripting")]
+    pub script_evaluations: ShardedCounter,
+    #[cfg(feature = "scripting")]
+    pub script_nanos: ShardedCounter,
+    #[cfg(feature = "scripting")]
+    pub script_errors: ShardedCounter,
+    // Anomalies reported by the query pattern heuristics, by reason.
+    pub anomalies: [ShardedCounter; AnomalyReason::ALL.len()],
+    // Query latencies, against the SLOs (see slo.rs).
+    pub slos: SloTracker,
+    // The latest requests that didn't parse (see bad_packets.rs).
+    pub bad_packets: BadPackets,
+    // The upstream's circuit breaker, if there is one (see breaker.rs).
+    pub breaker: Option<Arc<CircuitBreaker>>,
+    // The blocklist, if there is one, which counts the queries it blocks (see
+    // blocklist.rs).
+    pub blocklist: Option<Arc<Blocklist>>,
+    // How the cache's entries expired, if there is a cache (see expiry.rs).
+    pub cache: Option<Arc<ExpiryCounters>>,
+    // The upstreams, with how each fared (see upstreams.rs).
+    pub upstreams: Option<Arc<UpstreamSet>>,
+    pub listeners: Vec<ListenerStats>,
+    // How truncated responses fare, per listener (see truncation.rs).
+    pub truncation: TruncationTracker,
+}
+
+impl Stats {
+    pub fn for_listeners(tags: &[String]) -> Stats {
+        Stats {
+            listeners: tags
+                .iter()
+                .map(|tag| ListenerStats {
+                    tag: tag.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            truncation: TruncationTracker::new(tags, UDP_PAYLOAD_SIZE as usize),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    pub fn record_query_allocations(&self, allocations: u64) {
+        self.query_allocations.add(allocations);
+        self.profiled_queries.increment();
+    }
+
+    #[cfg(feature = "scripting")]
+    pub fn record_script_evaluation(&self, elapsed: std::time::Duration, failed: bool) {
+        self.script_evaluations.increment();
+        self.script_nanos.add(elapsed.as_nanos() as u64);
+        if failed {
+            self.script_errors.increment();
+        }
+    }
+
+    pub fn record_anomaly(&self, reason: AnomalyReason) {
+        self.anomalies[reason as usize].increment();
+    }
+
+    pub fn listener(&self, tag: &str) -> Option<&ListenerStats> {
+        self.listeners.iter().find(|listener| listener.tag == tag)
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let truncation = self.truncation.counts(std::time::Instant::now());
+        StatsSnapshot {
+            queries_received: self.queries_received.get(),
+            responses_sent: self.responses_sent.get(),
+            refused_by_class: self.refused_by_class.get(),
+            refused_by_name: self.refused_by_name.get(),
+            refused_by_type: self.refused_by_type.get(),
+            truncated: self.truncated.get(),
+            shed_with_servfail: self.shed_with_servfail.get(),
+            shed_by_dropping: self.shed_by_dropping.get(),
+            over_client_quota: self.over_client_quota.get(),
+            abusive_connections: self.abusive_connections.get(),
+            minimized_additionals: self.minimized_additionals.get(),
+            minimized_authorities: self.minimized_authorities.get(),
+            minimized_addresses: self.minimized_addresses.get(),
+            encoding_failures: self.encoding_failures.get(),
+            send_failures: self.send_failures.get(),
+            over_work_budget: self.over_work_budget.get(),
+            off_chain_rrsets: self.off_chain_rrsets.get(),
+            filtered_records: self.filtered_records.get(),
+            filtered_to_nodata: self.filtered_to_nodata.get(),
+            filtered_hints: self.filtered_hints.get(),
+            #[cfg(feature = "profiling")]
+            query_allocations: self.query_allocations.get(),
+            #[cfg(feature = "profiling")]
+            profiled_queries: self.profiled_queries.get(),
+            #[cfg(feature = "profiling")]
+            allocations: super::profiling::totals(),
+            #[cfg(feature = "scripting")]
+            script_evaluations: self.script_evaluations.get(),
+            #[cfg(feature = "scripting")]
+            script_nanos: self.script_nanos.get(),
+            #[cfg(feature = "scripting")]
+            script_errors: self.script_errors.get(),
+            anomalies: AnomalyReason::ALL
+                .iter()
+                .map(|&reason| (reason.label(), self.anomalies[reason as usize].get()))
+                .filter(|(_, count)| *count > 0)
+                .collect(),
+            slos: self.slos.statuses(std::time::Instant::now()),
+            breaker: self
+                .breaker
+                .as_ref()
+                .map(|breaker| breaker.status(std::time::Instant::now())),
+            blocked: self
+                .blocklist
+                .as_ref()
+                .map(|blocklist| blocklist.blocked.get()),
+            cache: self.cache.as_ref().map(|cache| cache.counts()),
+            upstreams: self
+                .upstreams
+                .as_ref()
+                .map(|upstreams| upstreams.counts(std::time::Instant::now()))
+                .unwrap_or_default(),
+            listeners: self
+                .listeners
+                .iter()
+                .map(|listener| ListenerSnapshot {
+                    tag: listener.tag.clone(),
+                    queries_received: listener.queries_received.get(),
+                    responses_sent: listener.responses_sent.get(),
+                    truncation: truncation
+                        .iter()
+                        .find(|(tag, _)| *tag == listener.tag)
+                        .map(|(_, counts)| counts.clone())
+                        .unwrap_or_default(),
+                })
+                .collect(),
+        }
+    }
+}
+
+// A point-in-time copy of the counters.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StatsSnapshot {
+    pub queries_received: u64,
+    pub responses_sent: u64,
+    pub refused_by_class: u64,
+    pub refused_by_name: u64,
+    pub refused_by_type: u64,
+    pub truncated: u64,
+    pub shed_with_servfail: u64,
+    pub shed_by_dropping: u64,
+    pub over_client_quota: u64,
+    pub abusive_connections: u64,
+    pub minimized_additionals: u64,
+    pub minimized_authorities: u64,
+    pub minimized_addresses: u64,
+    pub encoding_failures: u64,
+    pub send_failures: u64,
+    pub over_work_budget: u64,
+    pub off_chain_rrsets: u64,
+    pub filtered_records: u64,
+    pub filtered_to_nodata: u64,
+    pub filtered_hints: u64,
+    #[cfg(feature = "profiling")]
+    pub query_allocations: u64,
+    #[cfg(feature = "profiling")]
+    pub profiled_queries: u64,
+    #[cfg(feature = "profiling")]
+    pub allocations: super::profiling::AllocationTotals,
+    #[cfg(feature = "scripting")]
+    pub script_evaluations: u64,
+    #[cfg(feature = "scripting")]
+    pub script_nanos: u64,
+    #[cfg(feature = "scripting")]
+    pub script_errors: u64,
+    // Only the reasons that occurred.
+    pub anomalies: Vec<(&'static str, u64)>,
+    // Where each SLO stands over its window.
+    pub slos: Vec<SloStatus>,
+    pub breaker: Option<BreakerStatus>,
+    // Queries blocked, with a blocklist.
+    pub blocked: Option<u64>,
+    pub cache: Option<ExpiryCounts>,
+    pub upstreams: Vec<UpstreamCounts>,
+    pub listeners: Vec<ListenerSnapshot>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ListenerSnapshot {
+    pub tag: String,
+    pub queries_received: u64,
+    pub responses_sent: u64,
+    pub truncation: TruncationCounts,
+}
+
+impl ToJson for StatsSnapshot {
+    fn to_json(&self) -> Json {
+        let counters = [
+            ("queries_received", self.queries_received),
+            ("responses_sent", self.responses_sent),
+            ("refused_by_class", self.refused_by_class),
+            ("refused_by_name", self.refused_by_name),
+            ("refused_by_type", self.refused_by_type),
+            ("truncated", self.truncated),
+            ("shed_with_servfail", self.shed_with_servfail),
+            ("shed_by_dropping", self.shed_by_dropping),
+            ("over_client_quota", self.over_client_quota),
+            ("abusive_connections", self.abusive_connections),
+            ("minimized_additionals", self.minimized_additionals),
+            ("minimized_authorities", self.minimized_authorities),
+            ("minimized_addresses", self.minimized_addresses),
+            ("encoding_failures", self.encoding_failures),
+            ("send_failures", self.send_failures),
+            ("over_work_budget", self.over_work_budget),
+            ("off_chain_rrsets", self.off_chain_rrsets),
+            ("filtered_records", self.filtered_records),
+            ("filtered_to_nodata", self.filtered_to_nodata),
+            ("filtered_hints", self.filtered_hints),
+        ];
+        #[cfg(feature = "profiling")]
+        let counters = counters.into_iter().chain([
+            ("query_allocations", self.query_allocations),
+            ("profiled_queries", self.profiled_queries),
+            ("allocations", self.allocations.allocations),
+            ("allocated_bytes", self.allocations.allocated_bytes),
+            ("live_bytes", self.allocations.live_bytes),
+            ("peak_live_bytes", self.allocations.peak_live_bytes),
+        ]);
+        #[cfg(feature = "scripting")]
+        let counters = counters.into_iter().chain([
+            ("script_evaluations", self.script_evaluations),
+            ("script_nanos", self.script_nanos),
+            ("script_errors", self.script_errors),
+        ]);
+        let mut json: Vec<(&str, Json)> = counters
+            .into_iter()
+            .map(|(name, count)| (name, Json::from(count)))
+            .collect();
+        json.push((
+            "anomalies",
+            Json::object(
+                self.anomalies
+                    .iter()
+                    .map(|&(reason, count)| (reason, Json::from(count))),
+            ),
+        ));
+        json.push((
+            "slos",
+            Json::Array(
+                self.slos
+                    .iter()
+                    .map(|status| {
+                        Json::object([
+                            ("slo", Json::from(status.slo.as_str())),
+                            ("samples", Json::from(status.samples)),
+                            // In hundredths of a percent.
+                            ("within", Json::from(status.within as u64)),
+                            (
+                                "estimate_micros",
+                                status.estimate.map_or(Json::Null, |estimate| {
+                                    Json::from(estimate.as_micros() as u64)
+                                }),
+                            ),
+                            ("met", Json::Bool(status.met)),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ));
+        if let Some(blocked) = self.blocked {
+            json.push(("blocked", Json::from(blocked)));
+        }
+        if let Some(cache) = &self.cache {
+            json.push((
+                "cache",
+                Json::object([
+                    ("lazily_expired", Json::from(cache.lazily_expired)),
+                    ("swept", Json::from(cache.swept)),
+                ]),
+            ));
+        }
+        if !self.upstreams.is_empty() {
+            json.push((
+                "upstreams",
+                Json::Array(
+                    self.upstreams
+                        .iter()
+                        .map(|counts| {
+                            Json::object([
+                                ("upstream", Json::from(counts.upstream.to_string())),
+                                ("successes", Json::from(counts.successes)),
+                                ("failures", Json::from(counts.failures)),
+                                ("skipped", Json::from(counts.skipped)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ));
+        }
+        if let Some(breaker) = &self.breaker {
+            json.push((
+                "breaker",
+                Json::object([
+                    ("state", Json::from(breaker.state)),
+                    (
+                        "next_probe_millis",
+                        Json::from(
+                            breaker
+                                .next_probe
+                                .map(|next_probe| next_probe.as_millis() as u64),
+                        ),
+                    ),
+                    ("opened", Json::from(breaker.counts.opened)),
+                    ("closed", Json::from(breaker.counts.closed)),
+                    ("rejected", Json::from(breaker.counts.rejected)),
+                    ("probes", Json::from(breaker.counts.probes)),
+                ]),
+            ));
+        }
+        json.push((
+            "listeners",
+            Json::Array(
+                self.listeners
+                    .iter()
+                    .map(|listener| {
+                        Json::object([
+                            ("tag", Json::from(listener.tag.as_str())),
+                            ("queries_received", Json::from(listener.queries_received)),
+                            ("responses_sent", Json::from(listener.responses_sent)),
+                            ("udp_responses", Json::from(listener.truncation.responses)),
+                            ("truncated", Json::from(listener.truncation.truncated)),
+                            ("tcp_retries", Json::from(listener.truncation.retried)),
+                            (
+                                "never_retried",
+                                Json::from(listener.truncation.never_retried),
+                            ),
+                            (
+                                "p99_udp_size",
+                                Json::from(listener.truncation.p99_size().map(|size| size as u64)),
+                            ),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ));
+        Json::object(json)
+    }
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "queries: {}, responses: {}, refused (class): {}, refused (name): {}, \
+             refused (type): {}, truncated: {}, shed (servfail): {}, shed (dropped): {}, \
+             over client quota: {}, abusive connections: {}, minimized (additionals): {}, \
+             minimized (authorities): {}, minimized (addresses): {}, \
+             encoding failures: {}, send failures: {}, over work budget: {}, off-chain rrsets: {}, \
+             filtered (records): {}, filtered (nodata): {}, filtered (hints): {}",
+            self.queries_received,
+            self.responses_sent,
+            self.refused_by_class,
+            self.refused_by_name,
+            self.refused_by_type,
+            self.truncated,
+            self.shed_with_servfail,
+            self.shed_by_dropping,
+            self.over_client_quota,
+            self.abusive_connections,
+            self.minimized_additionals,
+            self.minimized_authorities,
+            self.minimized_addresses,
+            self.encoding_failures,
+            self.send_failures,
+            self.over_work_budget,
+            self.off_chain_rrsets,
+            self.filtered_records,
+            self.filtered_to_nodata,
+            self.filtered_hints
+        )?;
+        #[cfg(feature = "profiling")]
+        write!(
+            f,
+            ", allocations/query: {:.1}, {}",
+            self.query_allocations as f64 / self.profiled_queries.max(1) as f64,
+            self.allocations
+        )?;
+        #[cfg(feature = "scripting")]
+        write!(
+            f,
+            ", script evaluations: {} ({:.1} µs each, {} failed)",
+            self.script_evaluations,
+            self.script_nanos as f64 / 1000.0 / self.script_evaluations.max(1) as f64,
+            self.script_errors
+        )?;
+        for (reason, count) in &self.anomalies {
+            write!(f, ", anomalies ({}): {}", reason, count)?;
+        }
+        for status in &self.slos {
+            write!(f, ", slo ({})", status)?;
+        }
+        if let Some(blocked) = self.blocked {
+            write!(f, ", blocked: {}", blocked)?;
+        }
+        if let Some(cache) = &self.cache {
+            write!(f, ", cache: {}", cache)?;
+        }
+        for counts in &self.upstreams {
+            write!(f, ", upstream {}", counts)?;
+        }
+        if let Some(breaker) = &self.breaker {
+            write!(f, ", breaker: {}", breaker)?;
+        }
+        for listener in &self.listeners {
+            write!(
+                f,
+                "; [{}] queries: {}, responses: {}, truncated: {}, retried over TCP: {}, \
+                 never retried: {}",
+                listener.tag,
+                listener.queries_received,
+                listener.responses_sent,
+                listener.truncation.truncated,
+                listener.truncation.retried,
+                listener.truncation.never_retried
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    const THREADS: usize = 8;
+
+    #[test]
+    fn increments_from_every_thread_are_counted() {
+        const INCREMENTS: u64 = 100_000;
+        let stats = Stats::default();
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for _ in 0..INCREMENTS {
+                        stats.queries_received.increment();
+                    }
+                    stats.responses_sent.add(INCREMENTS);
+                });
+            }
+        });
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.queries_received, THREADS as u64 * INCREMENTS);
+        assert_eq!(snapshot.responses_sent, THREADS as u64 * INCREMENTS);
+    }
+
+    // Increments per second from `threads` threads at once, into one counter.
+    fn contended_throughput(
+        threads: usize,
+        increment: impl Fn() + Sync,
+        duration: Duration,
+    ) -> f64 {
+        let total = AtomicU64::new(0);
+        let started = Instant::now();
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    let mut done = 0;
+                    while started.elapsed() < duration {
+                        for _ in 0..1024 {
+                            increment();
+                        }
+                        done += 1024;
+                    }
+                    total.fetch_add(done, Ordering::Relaxed);
+                });
+            }
+        });
+        total.load(Ordering::Relaxed) as f64 / started.elapsed().as_secs_f64()
+    }
+
+    // A timing run rather than a test, ignored by default; with optimizations, on a
+    // machine with several cores (with one, nothing contends):
+    //
+    //     cargo test --release --lib sharded_counters_scale -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn sharded_counters_scale_under_contention() {
+        let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+        let threads = cores.max(2);
+        let duration = Duration::from_secs(2);
+        let single = AtomicU64::new(0);
+        let sharded = ShardedCounter::default();
+        let single_rate = contended_throughput(
+            threads,
+            || {
+                single.fetch_add(1, Ordering::Relaxed);
+            },
+            duration,
+        );
+        let sharded_rate = contended_throughput(threads, || sharded.increment(), duration);
+        println!(
+            "{} threads on {} cores: one atomic {:.0}/s, sharded {:.0}/s ({:.1}x)",
+            threads,
+            cores,
+            single_rate,
+            sharded_rate,
+            sharded_rate / single_rate
+        );
+        if cores > 1 {
+            assert!(sharded_rate > single_rate, "sharding doesn't pay off");
+        }
+    }
+}
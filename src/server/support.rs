@@ -0,0 +1,385 @@
+// Support bundles: a directory with what a bug report needs, written by the control
+// socket's `support-bundle DIR` command, or when the server panics, with
+// --dump-state-on-crash DIR. Each file holds a JSON object:
+//
+//     version.json    the build: version, features, target
+//     config.json     the effective settings, with where each came from
+//     stats.json      the counters
+//     zones.json      the response policy zones, with their serials and triggers
+//     overrides.json  pinned RRsets, negative trust anchors, schedules and clients
+//     ttl.json        the TTL honesty report, empty unless names are sampled
+//     panic.json      on a crash only: the panic message and where it happened
+//
+// The JSON is canonical: object keys are sorted, so the same state always gives the
+// same bytes, and bundles diff cleanly. Secrets are left out by type, not by value:
+// a setting declared with hide_env_values (see CliArgs::effective_config) is written
+// as "[redacted]" whatever it's set to.
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io, panic,
+    path::{Path, PathBuf},
+};
+
+// What a secret is written as.
+pub const REDACTED: &str = "[redacted]";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+impl Json {
+    pub fn object<'a>(entries: impl IntoIterator<Item = (&'a str, Json)>) -> Json {
+        Json::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| (String::from(key), value))
+                .collect(),
+        )
+    }
+
+    pub fn strings(values: impl IntoIterator<Item = impl Into<String>>) -> Json {
+        Json::Array(
+            values
+                .into_iter()
+                .map(|value| Json::String(value.into()))
+                .collect(),
+        )
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        let pad = "  ".repeat(indent + 1);
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(value) => write!(f, "{}", value),
+            Json::String(value) => write_string(f, value),
+            Json::Array(values) if values.is_empty() => write!(f, "[]"),
+            Json::Array(values) => {
+                writeln!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    write!(f, "{}", pad)?;
+                    value.write(f, indent + 1)?;
+                    writeln!(f, "{}", if index + 1 < values.len() { "," } else { "" })?;
+                }
+                write!(f, "{}]", "  ".repeat(indent))
+            }
+            Json::Object(entries) if entries.is_empty() => write!(f, "{{}}"),
+            Json::Object(entries) => {
+                writeln!(f, "{{")?;
+                for (index, (key, value)) in entries.iter().enumerate() {
+                    write!(f, "{}", pad)?;
+                    write_string(f, key)?;
+                    write!(f, ": ")?;
+                    value.write(f, indent + 1)?;
+                    writeln!(f, "{}", if index + 1 < entries.len() { "," } else { "" })?;
+                }
+                write!(f, "{}}}", "  ".repeat(indent))
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Json {
+        Json::Bool(value)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(value: u64) -> Json {
+        Json::Number(value)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Json {
+        Json::String(String::from(value))
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Json {
+        Json::String(value)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Json {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+// State that goes into support bundles.
+pub trait ToJson {
+    fn to_json(&self) -> Json;
+}
+
+// The build, for version.json.
+pub fn build_info() -> Json {
+    let features = [
+        ("dnssec", cfg!(feature = "dnssec")),
+        ("mdns", cfg!(feature = "mdns")),
+        ("profiling", cfg!(feature = "profiling")),
+        ("scripting", cfg!(feature = "scripting")),
+        ("time-zones", cfg!(feature = "time-zones")),
+    ];
+    Json::object([
+        ("name", Json::from(env!("CARGO_PKG_NAME"))),
+        ("version", Json::from(env!("CARGO_PKG_VERSION"))),
+        (
+            "features",
+            Json::strings(
+                features
+                    .iter()
+                    .filter(|(_, enabled)| *enabled)
+                    .map(|(feature, _)| *feature),
+            ),
+        ),
+        (
+            "target",
+            Json::from(format!(
+                "{}-{}",
+                std::env::consts::ARCH,
+                std::env::consts::OS
+            )),
+        ),
+        ("debug", Json::from(cfg!(debug_assertions))),
+    ])
+}
+
+pub struct SupportBundle {
+    files: BTreeMap<&'static str, Json>,
+}
+
+impl SupportBundle {
+    // A bundle with version.json and `config` as config.json.
+    pub fn new(config: &Json) -> SupportBundle {
+        let mut bundle = SupportBundle {
+            files: BTreeMap::new(),
+        };
+        bundle
+            .add("version.json", build_info())
+            .add("config.json", config.clone());
+        bundle
+    }
+
+    pub fn add(&mut self, name: &'static str, contents: Json) -> &'_ mut Self {
+        self.files.insert(name, contents);
+        self
+    }
+
+    // Writes the files into `dir`, creating it if need be, and returns their count.
+    pub fn write(&self, dir: &Path) -> io::Result<usize> {
+        fs::create_dir_all(dir)?;
+        for (name, contents) in &self.files {
+            fs::write(dir.join(name), format!("{}\n", contents))?;
+        }
+        Ok(self.files.len())
+    }
+}
+
+// Has a panic write a bundle of the build, `config` and the panic into `dir`, before
+// the usual message. The rest of the state is out of reach of a panic hook, which
+// must be Send and Sync.
+pub fn dump_state_on_crash(dir: PathBuf, config: Json) {
+    let report_panic = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| String::from(*message))
+            .or_else(|| payload.downcast_ref::<String>().cloned());
+        let mut bundle = SupportBundle::new(&config);
+        bundle.add(
+            "panic.json",
+            Json::object([
+                ("message", Json::from(message)),
+                (
+                    "location",
+                    Json::from(info.location().map(|location| location.to_string())),
+                ),
+            ]),
+        );
+        match bundle.write(&dir) {
+            Ok(_) => eprintln!("[CRASH] Wrote a support bundle to {}.", dir.display()),
+            Err(e) => eprintln!(
+                "[CRASH] Failed to write a support bundle to {}: {}",
+                dir.display(),
+                e
+            ),
+        }
+        report_panic(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, os::unix::net::UnixStream, sync::Arc, time::Duration};
+
+    use super::super::control::ControlSocket;
+    use super::super::rpz::ResponsePolicy;
+    use super::super::stats::Stats;
+    use super::super::{NegativeTrustAnchors, PinStore};
+    use super::*;
+    use crate::cli::effective_config;
+
+    fn field<'a>(json: &'a Json, key: &str) -> Option<&'a Json> {
+        match json {
+            Json::Object(entries) => entries.get(key),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn json_is_canonical_and_escaped() {
+        let json = Json::object([
+            ("b", Json::strings(["say \"hi\"\n", "tab\there"])),
+            ("a", Json::from(Some(7u64))),
+            ("c", Json::object([])),
+            ("d", Json::from(None::<bool>)),
+            ("e", Json::Array(Vec::new())),
+        ]);
+        assert_eq!(
+            json.to_string(),
+            "{\n  \"a\": 7,\n  \"b\": [\n    \"say \\\"hi\\\"\\n\",\n    \"tab\\there\"\n  ],\n  \
+             \"c\": {},\n  \"d\": null,\n  \"e\": []\n}"
+        );
+        assert_eq!(Json::from("\u{1}").to_string(), "\"\\u0001\"");
+    }
+
+    #[test]
+    fn bundles_have_every_file_and_no_secrets() {
+        let dir = std::env::temp_dir().join(format!("support-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let zone = dir.with_extension("rpz");
+        fs::write(
+            &zone,
+            "$ORIGIN rpz.example.\n\
+             @ 300 SOA localhost. hostmaster.localhost. 2026101601 3600 600 86400 60\n\
+             blocked.test CNAME .\n",
+        )
+        .unwrap();
+
+        let socket = dir.with_extension("sock");
+        let mut control = ControlSocket::bind(socket, Arc::new(PinStore::default())).unwrap();
+        control.rpz = Some(Arc::new(
+            ResponsePolicy::load(std::slice::from_ref(&zone)).unwrap(),
+        ));
+        let ntas = Arc::new(NegativeTrustAnchors::new(false));
+        ntas.add("broken.example", Duration::from_secs(60)).unwrap();
+        control.ntas = Some(ntas);
+        control.execute("pin pinned.example A 300 192.0.2.1");
+        // A secret option given on the command line, beside an ordinary one.
+        let command = clap::Command::new("test")
+            .arg(clap::Arg::new("resolver").long("resolver"))
+            .arg(
+                clap::Arg::new("tsig-secret")
+                    .long("tsig-secret")
+                    .env("TEST_TSIG_SECRET")
+                    .hide_env_values(true),
+            );
+        let matches = command.clone().get_matches_from([
+            "test",
+            "--resolver",
+            "192.0.2.53:53",
+            "--tsig-secret",
+            "c2VjcmV0LWtleQ==",
+        ]);
+        control.config = effective_config(&command, &matches);
+
+        let mut client = UnixStream::connect(&control.path).unwrap();
+        writeln!(client, "support-bundle {}", dir.display()).unwrap();
+        let stats = Stats::default();
+        stats.queries_received.increment();
+        control.serve(&stats);
+
+        let bundle = control.support_bundle(&stats);
+        for (name, keys) in [
+            (
+                "version.json",
+                &["debug", "features", "name", "target", "version"][..],
+            ),
+            ("config.json", &["resolver", "tsig-secret"]),
+            (
+                "stats.json",
+                &["anomalies", "listeners", "queries_received"],
+            ),
+            ("zones.json", &["rpz"]),
+            (
+                "overrides.json",
+                &["clients", "negative_trust_anchors", "pins", "schedules"],
+            ),
+        ] {
+            let written = fs::read_to_string(dir.join(name)).unwrap();
+            assert!(
+                !written.contains("c2VjcmV0LWtleQ=="),
+                "{name} leaks the secret"
+            );
+            assert!(
+                written.starts_with("{\n") && written.ends_with("}\n"),
+                "{name}"
+            );
+            for key in keys {
+                // A key of the top-level object.
+                assert!(
+                    written.contains(&format!("\n  \"{key}\": ")),
+                    "{name} has no {key}"
+                );
+                assert!(
+                    field(&bundle.files[name], key).is_some(),
+                    "{name} has no {key}"
+                );
+            }
+        }
+        assert_eq!(
+            field(&bundle.files["config.json"], "tsig-secret"),
+            Some(&Json::object([
+                ("source", Json::from("command-line")),
+                ("values", Json::strings([REDACTED])),
+            ]))
+        );
+        let stats = &bundle.files["stats.json"];
+        assert_eq!(field(stats, "queries_received"), Some(&Json::Number(1)));
+        let Some(Json::Array(zones)) = field(&bundle.files["zones.json"], "rpz") else {
+            panic!("zones.json lists no policy zones");
+        };
+        assert_eq!(field(&zones[0], "serial"), Some(&Json::Number(2026101601)));
+        assert_eq!(field(&zones[0], "qname_triggers"), Some(&Json::Number(1)));
+        let overrides = &bundle.files["overrides.json"];
+        assert_eq!(
+            field(overrides, "pins"),
+            Some(&Json::strings(["pinned.example 300 A 192.0.2.1 ; pinned"]))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&zone).unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+// Helpers shared by the unit tests.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, UdpSocket},
+    os::fd::RawFd,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::dns::message::{
+    Answer, LabelSequence, Message, OptRecord, Question, RCode, RecordClass, RecordType,
+};
+use super::edns::UDP_PAYLOAD_SIZE;
+use super::query::COOKIE_OPTION;
+use super::random::Xorshift;
+use super::signature_time::{Clock, SignatureTime};
+use super::{
+    DnsServer, DummyDnsResolver, ForwardingDnsResolver, Listener, ListenerSpec, Pacer,
+    QueryOpcodeHandler, Resolve, UpstreamSet, UpstreamStateStore,
+};
+
+// The query handler of most tests: QueryOpcodeHandler::new's defaults in front of
+// `resolver`. Tests about a field set it on the result, e.g.
+// `QueryOpcodeHandler { rpz, ..query_handler(resolver) }`.
+pub fn query_handler(resolver: impl Resolve + 'static) -> QueryOpcodeHandler {
+    QueryOpcodeHandler::new(Arc::new(resolver))
+}
+
+// A server with a single listener ("test", on an ephemeral port) that answers
+// queries with the dummy resolver and never judges itself overloaded.
+pub fn dummy_server() -> DnsServer {
+    let spec = ListenerSpec::parse("test=127.0.0.1:0").unwrap();
+    DnsServer {
+        verify_encoding: true,
+        ..DnsServer::new(
+            vec![Listener::bind(&spec).unwrap()],
+            vec![Arc::new(query_handler(DummyDnsResolver::default()))],
+        )
+    }
+}
+
+// What the descriptor `fd` of this process refers to, as Linux shows it (e.g.
+// "socket:[4026]"); None if it isn't open. Unlike the number, which the next file
+// opened by any test may get, it names the file itself, so that leaks can be
+// checked for while other tests run.
+pub fn open_file(fd: RawFd) -> Option<String> {
+    fs::read_link(format!("/proc/self/fd/{}", fd))
+        .ok()
+        .map(|target| target.to_string_lossy().into_owned())
+}
+
+// Everything this process has open, as open_file shows it.
+pub fn open_files() -> HashSet<String> {
+    fs::read_dir("/proc/self/fd")
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter_map(|entry| fs::read_link(entry.path()).ok())
+                .map(|target| target.to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// A query for `name` IN A with RD set, with an OPT record carrying the given
+// EDNS option codes (each with two bytes of dummy data) if `options` is given.
+pub fn query(name: &str, options: Option<&[u16]>) -> Vec<u8> {
+    let mut data: Vec<u8> = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+    data.extend_from_slice(&(options.is_some() as u16).to_be_bytes());
+    data.extend_from_slice(&name.parse::<LabelSequence>().unwrap().encode());
+    data.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+    if let Some(options) = options {
+        let mut rdata: Vec<u8> = Vec::new();
+        for code in options {
+            rdata.extend_from_slice(&code.to_be_bytes());
+            rdata.extend_from_slice(&[0x00, 0x02, 0xAB, 0xCD]);
+        }
+        data.extend_from_slice(&[0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        data.extend_from_slice(&rdata);
+    }
+    data
+}
+
+// The info-code and EXTRA-TEXT of the Extended DNS Error in a response, if any.
+pub fn extended_error_in(response: &Message) -> Option<(u16, String)> {
+    let opt = response.get_opt()?;
+    opt.get_options()
+        .iter()
+        .find(|(code, _)| *code == 15)
+        .map(|(_, data)| {
+            (
+                u16::from_be_bytes([data[0], data[1]]),
+                String::from_utf8(data[2..].to_vec()).unwrap(),
+            )
+        })
+}
+
+// A forwarder to `upstream`, pacing bulk queries at `max_qps`.
+pub fn forwarder(upstream: SocketAddr, max_qps: f64) -> ForwardingDnsResolver {
+    ForwardingDnsResolver {
+        upstreams: Arc::new(UpstreamSet::single(upstream)),
+        upstream_state: UpstreamStateStore::in_memory().into(),
+        #[cfg(feature = "dnssec")]
+        trust_anchors: None,
+        negative_trust_anchors: None,
+        pacer: Mutex::new(Pacer::new(max_qps, Instant::now())),
+        pacing: Default::default(),
+        prefetched: Default::default(),
+        split_brain: None,
+        tcp_fallback: Some(Duration::from_secs(2)),
+        ttl_honesty: None,
+        timeout: Duration::from_secs(2),
+        retries: 0,
+        ids: Arc::new(Xorshift::seeded(0)),
+        breaker: None,
+    }
+}
+
+// An upstream answering every question with A records for `addresses`, after
+// `delay`; questions for names starting with "down" get SERVFAIL instead, as do
+// those for names starting with "bogus" unless they have CD set, as from a
+// validating resolver to a zone with broken signatures. Answers for names starting
+// with "signed" have AD set, as if validated.
+pub fn mock_upstream(addresses: &[[u8; 4]], ttl: u32, delay: Duration) -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.try_clone().unwrap();
+    let addresses = addresses.to_vec();
+    thread::spawn(move || {
+        let mut buf = [0; 512];
+        while let Ok((size, source)) = server.recv_from(&mut buf) {
+            thread::sleep(delay);
+            let mut response = buf[..size].to_vec();
+            response[2] |= 0x80;
+            let bogus = response[13..].starts_with(b"bogus") && response[3] & 0x10 == 0;
+            if response[13..].starts_with(b"down") || bogus {
+                response[3] = (response[3] & 0xF0) | 2;
+            } else {
+                if response[13..].starts_with(b"signed") {
+                    response[3] |= 0x20;
+                }
+                response[7] = addresses.len() as u8;
+                for address in &addresses {
+                    response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+                    response.extend_from_slice(&ttl.to_be_bytes());
+                    response.extend_from_slice(&[0, 4]);
+                    response.extend_from_slice(address);
+                }
+            }
+            let _ = server.send_to(&response, source);
+        }
+    });
+    socket
+}
+
+// A count the mock resolvers keep of what they were asked, shared with the test
+// that reads it, from whichever thread looks up.
+#[derive(Clone, Default)]
+pub struct Counter(Arc<AtomicUsize>);
+
+impl Counter {
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// A clock that only moves when told to, in seconds since the epoch.
+pub struct ManualClock(AtomicU64);
+
+impl ManualClock {
+    pub fn at(seconds: u64) -> ManualClock {
+        ManualClock(AtomicU64::new(seconds))
+    }
+
+    pub fn set(&self, seconds: u64) {
+        self.0.store(seconds, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.0.fetch_add(seconds, Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SignatureTime {
+        SignatureTime::from_seconds(self.0.load(Ordering::Relaxed)).unwrap()
+    }
+}
+
+// What a mock upstream (quirky_upstream) gets wrong, or doesn't do at all. The
+// default is an upstream that gets everything right.
+#[derive(Clone)]
+pub struct Quirks {
+    pub edns: bool,
+    // Queries larger than this go unanswered, as if their fragments were dropped.
+    pub max_udp_size: usize,
+    pub tcp: bool,
+    // Whether DO is copied into the response (RFC 3225, 3).
+    pub do_bit: bool,
+    pub cookies: bool,
+    pub preserves_case: bool,
+    // Nonexistent names are answered with this address instead of NXDOMAIN.
+    pub nxdomain_rewrite: Option<[u8; 4]>,
+    // ANY is answered NOTIMP instead of with a single HINFO (RFC 8482, 4.2).
+    pub refuses_any: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            edns: true,
+            max_udp_size: 4096,
+            tcp: true,
+            do_bit: true,
+            cookies: true,
+            preserves_case: true,
+            nxdomain_rewrite: None,
+            refuses_any: false,
+        }
+    }
+}
+
+// The mock's response to `request`: example.com has an A record (192.0.2.1), and no
+// other name exists.
+fn quirky_response(quirks: &Quirks, request: &[u8]) -> Option<Vec<u8>> {
+    let request = Message::parse_from(request).ok()?;
+    let question = request.get_questions().first()?.clone();
+    let mut header = request.get_header().as_ref().clone();
+    header.set_qr(true);
+    let mut answers = Vec::new();
+    let mut additionals = Vec::new();
+    let echoed = match quirks.preserves_case {
+        true => question.get_name().clone(),
+        false => Arc::new(
+            question
+                .get_name()
+                .to_string()
+                .to_ascii_lowercase()
+                .parse()
+                .unwrap(),
+        ),
+    };
+    let name: LabelSequence = "example.com".parse().unwrap();
+    let exists = question.get_name().eq_ignore_case(&name);
+    match request.get_opt() {
+        Some(_) if !quirks.edns => {
+            header.set_rcode(RCode::FormatError);
+        }
+        Some(opt) => {
+            let mut response_opt = OptRecord::new(UDP_PAYLOAD_SIZE);
+            response_opt.set_do(quirks.do_bit && opt.get_do());
+            if let Some((_, cookie)) = opt
+                .get_options()
+                .into_iter()
+                .find(|(code, _)| *code == COOKIE_OPTION)
+                .filter(|_| quirks.cookies)
+            {
+                let mut cookies = cookie[..8].to_vec();
+                cookies.extend_from_slice(b"servercookie");
+                response_opt.add_option(COOKIE_OPTION, &cookies);
+            }
+            additionals.push(response_opt.to_answer());
+        }
+        None => {}
+    }
+    if header.get_rcode() == &RCode::NoError {
+        match (question.get_type(), exists, quirks.nxdomain_rewrite) {
+            (RecordType::Any, true, _) if quirks.refuses_any => {
+                header.set_rcode(RCode::NotImplemented);
+            }
+            (RecordType::Any, true, _) => answers.push(Answer::new(
+                /* name= */ &echoed,
+                /* type= */ RecordType::Unknown(13),
+                /* class= */ RecordClass::In,
+                /* ttl= */ 3600,
+                /* data= */ &Arc::from(&b"\x07RFC8482\x00"[..]),
+            )),
+            (_, true, _) => answers.push(Answer::a(&echoed, 300, Ipv4Addr::new(192, 0, 2, 1))),
+            (_, false, Some(address)) => {
+                answers.push(Answer::a(&echoed, 300, Ipv4Addr::from(address)))
+            }
+            (_, false, None) => {
+                header.set_rcode(RCode::NameError);
+            }
+        }
+    }
+    header
+        .set_an_count(answers.len() as u16)
+        .set_ar_count(additionals.len() as u16);
+    let response = Message::new(
+        &Arc::new(header),
+        &Arc::from([Question::new(
+            &echoed,
+            question.get_type(),
+            question.get_class(),
+        )]),
+        &answers.into(),
+    )
+    .with_additionals(&additionals.into());
+    Some(response.encode().to_vec())
+}
+
+// A UDP socket and a TCP listener on the same ephemeral port of the loopback. The
+// port the system picked for UDP may be taken for TCP; then pick another, as
+// Listener::bind does.
+pub fn udp_and_tcp() -> (UdpSocket, TcpListener) {
+    loop {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        match TcpListener::bind(socket.local_addr().unwrap()) {
+            Ok(listener) => return (socket, listener),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => panic!("{e}"),
+        }
+    }
+}
+
+// An upstream with `quirks`, over UDP and, unless they say otherwise, over TCP on the
+// same port.
+pub fn quirky_upstream(quirks: Quirks) -> SocketAddr {
+    let (socket, listener) = udp_and_tcp();
+    let address = socket.local_addr().unwrap();
+    if quirks.tcp {
+        let quirks = quirks.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut length = [0; 2];
+                if stream.read_exact(&mut length).is_err() {
+                    continue;
+                }
+                let mut request = vec![0; u16::from_be_bytes(length) as usize];
+                if stream.read_exact(&mut request).is_err() {
+                    continue;
+                }
+                if let Some(response) = quirky_response(&quirks, &request) {
+                    let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+                    framed.extend_from_slice(&response);
+                    let _ = stream.write_all(&framed);
+                }
+            }
+        });
+    }
+    thread::spawn(move || {
+        let mut buf = [0; 65535];
+        while let Ok((size, source)) = socket.recv_from(&mut buf) {
+            if size > quirks.max_udp_size {
+                continue;
+            }
+            if let Some(response) = quirky_response(&quirks, &buf[..size]) {
+                let _ = socket.send_to(&response, source);
+            }
+        }
+    });
+    address
+}
@@ -0,0 +1,531 @@
+// Transactions on the local records (StaticRecords::begin): edits are staged, and on
+// commit applied to a copy of the records, checked with the zone validation rules
+// (zone_check.rs) and swapped in whole, so that lookups see the records as they were
+// before the transaction until it is committed, and never a part of it. A commit
+// that would add validation errors, or whose edits don't apply, changes nothing.
+//
+// Each commit that changes records bumps, once, the serial of every SOA whose zone
+// has a changed name, unless the transaction edits that SOA itself, and journals a
+// diff of the records (StaticRecords::journal).
+
+use std::{fmt, sync::Arc};
+
+use super::dns::message::{
+    Answer, LabelSequence, LabelSequenceParseError, RData, RecordClass, RecordType,
+};
+use super::pins::parse_rdata;
+use super::policy::{parse_record_type, DomainSuffix};
+use super::records::{same_record, StaticRecords};
+use super::zone_check::{validate, Diagnostic, Severity};
+
+enum Edit {
+    Add(Answer),
+    Remove(Answer),
+    RemoveRrset(Arc<LabelSequence>, RecordType),
+    ReplaceRrset(Arc<LabelSequence>, RecordType, Vec<Answer>),
+}
+
+#[derive(Debug)]
+pub enum TransactionError {
+    // A record or RRset to remove isn't there.
+    Missing(String),
+    // A replacement record doesn't belong to the RRset it replaces.
+    Mismatch(String),
+    // The validation errors the transaction would add.
+    Invalid(Vec<Diagnostic>),
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::Missing(what) => write!(f, "{} isn't there", what),
+            TransactionError::Mismatch(what) => write!(f, "{}", what),
+            TransactionError::Invalid(diagnostics) => {
+                let diagnostics: Vec<String> = diagnostics
+                    .iter()
+                    .map(|diagnostic| diagnostic.to_string())
+                    .collect();
+                write!(f, "{}", diagnostics.join("; "))
+            }
+        }
+    }
+}
+
+// What a committed transaction changed.
+#[derive(Clone, Debug)]
+pub struct ZoneDiff {
+    pub removed: Vec<Answer>,
+    pub added: Vec<Answer>,
+    // The zones whose SOA serial was bumped, and their new serials.
+    pub serials: Vec<(Arc<LabelSequence>, u32)>,
+}
+
+impl ZoneDiff {
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty()
+    }
+
+    // The diff as lines of text: the serials, then the records removed and added.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![self.to_string()];
+        lines.extend(self.removed.iter().map(|answer| format!("- {}", answer)));
+        lines.extend(self.added.iter().map(|answer| format!("+ {}", answer)));
+        lines
+    }
+}
+
+impl fmt::Display for ZoneDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} record(s) removed, {} added",
+            self.removed.len(),
+            self.added.len()
+        )?;
+        for (apex, serial) in &self.serials {
+            write!(f, ", {}. serial {}", apex, serial)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Transaction<'a> {
+    records: &'a StaticRecords,
+    edits: Vec<Edit>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(super) fn new(records: &'a StaticRecords) -> Transaction<'a> {
+        Transaction {
+            records,
+            edits: Vec::new(),
+        }
+    }
+
+    // Adds a record, or changes its TTL if it is there already.
+    pub fn add_record(&mut self, answer: Answer) -> &'_ mut Self {
+        self.edits.push(Edit::Add(answer));
+        self
+    }
+
+    // Removes a record, whatever its TTL.
+    pub fn remove_record(&mut self, answer: Answer) -> &'_ mut Self {
+        self.edits.push(Edit::Remove(answer));
+        self
+    }
+
+    pub fn remove_rrset(&mut self, name: &Arc<LabelSequence>, r#type: RecordType) -> &'_ mut Self {
+        self.edits.push(Edit::RemoveRrset(Arc::clone(name), r#type));
+        self
+    }
+
+    // Replaces the RRset `name` `type` with `answers`, which may be empty.
+    pub fn replace_rrset(
+        &mut self,
+        name: &Arc<LabelSequence>,
+        r#type: RecordType,
+        answers: Vec<Answer>,
+    ) -> &'_ mut Self {
+        self.edits
+            .push(Edit::ReplaceRrset(Arc::clone(name), r#type, answers));
+        self
+    }
+
+    // Stages an edit given as words, as the control socket takes them:
+    //     add NAME TYPE TTL VALUE...
+    //     remove NAME TYPE VALUE...
+    //     delete NAME TYPE
+    //     replace NAME TYPE TTL VALUE...
+    pub fn edit(&mut self, words: &[&str]) -> Result<(), String> {
+        let parse_name = |name: &str| -> Result<Arc<LabelSequence>, String> {
+            name.parse()
+                .map(Arc::new)
+                .map_err(|err: LabelSequenceParseError| err.message)
+        };
+        let parse_ttl = |ttl: &str| -> Result<u32, String> {
+            ttl.parse()
+                .ok()
+                .filter(|&ttl| ttl <= i32::MAX as u32)
+                .ok_or_else(|| format!("'{}' is not a valid TTL.", ttl))
+        };
+        let records = |name: &Arc<LabelSequence>, r#type, ttl, values: &[&str]| {
+            parse_rdata(r#type, values).map(|data| {
+                data.iter()
+                    .map(|data| {
+                        Answer::new(
+                            /* name= */ name,
+                            /* type= */ r#type,
+                            /* class= */ RecordClass::In,
+                            /* ttl= */ ttl,
+                            /* data= */ data,
+                        )
+                    })
+                    .collect::<Vec<Answer>>()
+            })
+        };
+        match words {
+            ["add", name, r#type, ttl, values @ ..] => {
+                let name = parse_name(name)?;
+                for answer in records(&name, parse_record_type(r#type)?, parse_ttl(ttl)?, values)? {
+                    self.add_record(answer);
+                }
+            }
+            ["remove", name, r#type, values @ ..] => {
+                let name = parse_name(name)?;
+                for answer in records(&name, parse_record_type(r#type)?, 0, values)? {
+                    self.remove_record(answer);
+                }
+            }
+            ["delete", name, r#type] => {
+                self.remove_rrset(&parse_name(name)?, parse_record_type(r#type)?);
+            }
+            ["replace", name, r#type, ttl, values @ ..] => {
+                let name = parse_name(name)?;
+                let r#type = parse_record_type(r#type)?;
+                let answers = records(&name, r#type, parse_ttl(ttl)?, values)?;
+                self.replace_rrset(&name, r#type, answers);
+            }
+            _ => {
+                return Err(String::from(
+                    "An edit is add NAME TYPE TTL VALUE..., remove NAME TYPE VALUE..., \
+                     delete NAME TYPE or replace NAME TYPE TTL VALUE....",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    // Applies the edits, in order, all or none.
+    pub fn commit(self) -> Result<ZoneDiff, TransactionError> {
+        let _committing = self.records.lock_commits();
+        let before = self.records.snapshot();
+        let mut after = before.clone();
+        for edit in &self.edits {
+            apply(&mut after, edit)?;
+        }
+
+        let mut diff = ZoneDiff {
+            removed: missing_from(&before, &after),
+            added: missing_from(&after, &before),
+            serials: Vec::new(),
+        };
+        if diff.is_empty() {
+            return Ok(diff);
+        }
+
+        let errors = |records: &[Answer]| -> Vec<Diagnostic> {
+            validate(records, None)
+                .into_iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                .collect()
+        };
+        let existing = errors(&before);
+        let added: Vec<Diagnostic> = errors(&after)
+            .into_iter()
+            .filter(|diagnostic| !existing.contains(diagnostic))
+            .collect();
+        if !added.is_empty() {
+            return Err(TransactionError::Invalid(added));
+        }
+
+        bump_serials(&mut after, &mut diff);
+        println!("[STATIC] Committed a transaction: {}.", diff);
+        self.records.apply(after, diff.clone());
+        Ok(diff)
+    }
+}
+
+fn apply(records: &mut Vec<Answer>, edit: &Edit) -> Result<(), TransactionError> {
+    let in_rrset = |answer: &Answer, name: &LabelSequence, r#type: RecordType| {
+        answer.get_type() == r#type && answer.get_name().eq_ignore_case(name)
+    };
+    match edit {
+        Edit::Add(answer) => {
+            records.retain(|existing| !same_record(existing, answer));
+            records.push(answer.clone());
+        }
+        Edit::Remove(answer) => {
+            let before = records.len();
+            records.retain(|existing| !same_record(existing, answer));
+            if records.len() == before {
+                return Err(TransactionError::Missing(format!(
+                    "{}. {} {}",
+                    answer.get_name(),
+                    answer.get_type(),
+                    answer.get_rdata()
+                )));
+            }
+        }
+        Edit::RemoveRrset(name, r#type) => {
+            let before = records.len();
+            records.retain(|existing| !in_rrset(existing, name, *r#type));
+            if records.len() == before {
+                return Err(TransactionError::Missing(format!("{}. {}", name, r#type)));
+            }
+        }
+        Edit::ReplaceRrset(name, r#type, answers) => {
+            if let Some(stray) = answers
+                .iter()
+                .find(|answer| !in_rrset(answer, name, *r#type))
+            {
+                return Err(TransactionError::Mismatch(format!(
+                    "{}. {} doesn't belong to the RRset {}. {}",
+                    stray.get_name(),
+                    stray.get_type(),
+                    name,
+                    r#type
+                )));
+            }
+            records.retain(|existing| !in_rrset(existing, name, *r#type));
+            for answer in answers {
+                records.retain(|existing| !same_record(existing, answer));
+                records.push(answer.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+// The records of `records` that aren't in `other`, or are with another TTL.
+fn missing_from(records: &[Answer], other: &[Answer]) -> Vec<Answer> {
+    records
+        .iter()
+        .filter(|answer| {
+            !other.iter().any(|candidate| {
+                same_record(candidate, answer) && candidate.get_ttl() == answer.get_ttl()
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+// Bumps the serial of each SOA whose zone has a name in `diff`, and notes it there.
+fn bump_serials(records: &mut [Answer], diff: &mut ZoneDiff) {
+    let changed: Vec<&Answer> = diff.removed.iter().chain(diff.added.iter()).collect();
+    for record in records.iter_mut() {
+        let RData::Soa {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        } = record.get_rdata()
+        else {
+            continue;
+        };
+        let apex = Arc::clone(record.get_name());
+        let Ok(zone) = DomainSuffix::parse(&apex.to_string()) else {
+            continue;
+        };
+        let edited_soa = changed.iter().any(|answer| {
+            answer.get_type() == RecordType::Soa && answer.get_name().eq_ignore_case(&apex)
+        });
+        if edited_soa || !changed.iter().any(|answer| zone.matches(answer.get_name())) {
+            continue;
+        }
+        // Serial number arithmetic (RFC 1982) wraps around.
+        let serial = serial.wrapping_add(1);
+        let bumped = Answer::from_rdata(
+            /* name= */ &apex,
+            /* class= */ record.get_class(),
+            /* ttl= */ record.get_ttl(),
+            /* data= */
+            RData::Soa {
+                mname: mname.clone(),
+                rname: rname.clone(),
+                serial,
+                refresh: *refresh,
+                retry: *retry,
+                expire: *expire,
+                minimum: *minimum,
+            },
+        );
+        *record = bumped;
+        diff.serials.push((apex, serial));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::super::dns::message::Question;
+    use super::super::pins::PinStore;
+    use super::super::{ControlSocket, Lookup};
+    use super::*;
+
+    fn name(name: &str) -> Arc<LabelSequence> {
+        Arc::new(name.parse().unwrap())
+    }
+
+    fn a(owner: &str, last: u8) -> Answer {
+        Answer::a(&name(owner), 300, Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    fn cname(owner: &str, target: &str) -> Answer {
+        Answer::from_rdata(
+            /* name= */ &name(owner),
+            /* class= */ RecordClass::In,
+            /* ttl= */ 300,
+            /* data= */ RData::Cname(target.parse().unwrap()),
+        )
+    }
+
+    // example.test, with its SOA (serial 7), NS and an address for www.
+    fn zone() -> StaticRecords {
+        let mut records = StaticRecords::new();
+        records
+            .add(Answer::from_rdata(
+                /* name= */ &name("example.test"),
+                /* class= */ RecordClass::In,
+                /* ttl= */ 3600,
+                /* data= */
+                RData::Soa {
+                    mname: "ns.example.test".parse().unwrap(),
+                    rname: "hostmaster.example.test".parse().unwrap(),
+                    serial: 7,
+                    refresh: 3600,
+                    retry: 600,
+                    expire: 86400,
+                    minimum: 300,
+                },
+            ))
+            .add(Answer::from_rdata(
+                /* name= */ &name("example.test"),
+                /* class= */ RecordClass::In,
+                /* ttl= */ 3600,
+                /* data= */ RData::Ns("ns.example.test".parse().unwrap()),
+            ))
+            .add(a("www.example.test", 1));
+        records
+    }
+
+    fn addresses(records: &StaticRecords, owner: &str) -> Vec<String> {
+        let question = Question::new(&name(owner), RecordType::A, RecordClass::In);
+        match records.lookup(&question) {
+            Lookup::Found(answers) => answers
+                .iter()
+                .map(|answer| answer.get_rdata().to_string())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn serial(records: &StaticRecords) -> u32 {
+        let question = Question::new(&name("example.test"), RecordType::Soa, RecordClass::In);
+        match records.lookup(&question) {
+            Lookup::Found(answers) => match answers[0].get_rdata() {
+                RData::Soa { serial, .. } => *serial,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn commits_apply_every_edit_at_once_and_bump_the_serial_once() {
+        let records = zone();
+        let mut transaction = records.begin();
+        transaction
+            .add_record(a("www.example.test", 2))
+            .remove_record(a("www.example.test", 1))
+            .replace_rrset(
+                &name("mail.example.test"),
+                RecordType::A,
+                vec![a("mail.example.test", 25)],
+            );
+        transaction
+            .edit(&[
+                "add",
+                "api.example.test",
+                "A",
+                "60",
+                "192.0.2.80",
+                "192.0.2.81",
+            ])
+            .unwrap();
+        // Readers see the records as they were until the commit.
+        assert_eq!(addresses(&records, "www.example.test"), ["192.0.2.1"]);
+        assert!(addresses(&records, "mail.example.test").is_empty());
+
+        let diff = transaction.commit().unwrap();
+        assert_eq!(addresses(&records, "www.example.test"), ["192.0.2.2"]);
+        assert_eq!(addresses(&records, "mail.example.test"), ["192.0.2.25"]);
+        assert_eq!(
+            addresses(&records, "api.example.test"),
+            ["192.0.2.80", "192.0.2.81"]
+        );
+        assert_eq!(serial(&records), 8);
+        assert_eq!(
+            diff.to_string(),
+            "1 record(s) removed, 4 added, example.test. serial 8"
+        );
+        assert_eq!(records.journal().len(), 1);
+
+        // Nothing to change, nothing to bump.
+        let mut transaction = records.begin();
+        transaction.add_record(a("www.example.test", 2));
+        assert!(transaction.commit().unwrap().is_empty());
+        assert_eq!(serial(&records), 8);
+        assert_eq!(records.journal().len(), 1);
+    }
+
+    #[test]
+    fn failed_commits_leave_the_records_untouched() {
+        let records = zone();
+        // A CNAME next to an address is a validation error.
+        let mut transaction = records.begin();
+        transaction
+            .remove_rrset(&name("www.example.test"), RecordType::A)
+            .add_record(a("ftp.example.test", 21))
+            .add_record(cname("ftp.example.test", "www.example.test"));
+        let err = transaction.commit().unwrap_err();
+        assert!(matches!(err, TransactionError::Invalid(_)), "{err}");
+
+        // So is removing what isn't there, even after edits that apply.
+        let mut transaction = records.begin();
+        transaction
+            .add_record(a("www.example.test", 2))
+            .remove_record(a("gone.example.test", 1));
+        let err = transaction.commit().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "gone.example.test. A 192.0.2.1 isn't there"
+        );
+
+        assert_eq!(addresses(&records, "www.example.test"), ["192.0.2.1"]);
+        assert!(addresses(&records, "ftp.example.test").is_empty());
+        assert_eq!(serial(&records), 7);
+        assert!(records.journal().is_empty());
+    }
+
+    #[test]
+    fn the_txn_command_batches_edits() {
+        let path = std::env::temp_dir().join(format!("control-{}-txn.sock", std::process::id()));
+        let mut control = ControlSocket::bind(path, Arc::new(PinStore::default())).unwrap();
+        let records = Arc::new(zone());
+        control.records = Some(Arc::clone(&records));
+
+        assert_eq!(
+            control.execute(
+                "txn replace www.example.test A 60 192.0.2.3; add mail.example.test A 300 192.0.2.25"
+            ),
+            "1 record(s) removed, 2 added, example.test. serial 8\n\
+             - www.example.test.    300    IN    A    192.0.2.1\n\
+             + www.example.test.    60    IN    A    192.0.2.3\n\
+             + mail.example.test.    300    IN    A    192.0.2.25\n"
+        );
+        let output = control.execute("txn delete www.example.test A; delete www.example.test A");
+        assert!(
+            output.starts_with("error: Nothing was changed: "),
+            "{output}"
+        );
+        assert!(control
+            .execute("txn add www.example.test A")
+            .starts_with("error: "));
+        assert_eq!(addresses(&records, "www.example.test"), ["192.0.2.3"]);
+        assert_eq!(control.execute("journal").lines().count(), 4);
+    }
+}
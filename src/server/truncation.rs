@@ -0,0 +1,425 @@
+// How truncation goes for clients, per listener: how many UDP responses needed TC,
+// how many of the clients that got one came back over TCP for the same question
+// within RETRY_WINDOW, and how big the responses were next to the size budget they
+// had to fit. A truncated response is kept in a table by client and question until
+// the client retries or the window runs out, when it counts as never retried; the
+// table holds at most MAX_AWAITING, the oldest making room without being counted
+// either way.
+//
+// From those, every ADVICE_INTERVAL the advisor suggests the settings to change,
+// e.g. "38% of truncated clients never retried over TCP", for which squeezing
+// responses harder (--max-addresses-per-rrset) would save them the answer, or a p99
+// response size well under --max-udp-size, which could then be lowered.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::edns::UDP_PAYLOAD_SIZE;
+
+// How long after a TC a query over TCP for the same question counts as its retry.
+const RETRY_WINDOW: Duration = Duration::from_secs(5);
+
+// Most truncated responses awaiting a retry.
+const MAX_AWAITING: usize = 4096;
+
+// How often the advisor logs.
+const ADVICE_INTERVAL: Duration = Duration::from_secs(600);
+
+// Truncated responses seen through, retried or not, before any advice on them, and
+// UDP responses before any on their sizes.
+const MIN_TRUNCATIONS: u64 = 20;
+const MIN_RESPONSES: u64 = 100;
+
+// Advice is given when at least this share of truncated clients never retry, when at
+// least this share of responses are truncated, and when the p99 response size is at
+// most this share of --max-udp-size.
+const NEVER_RETRIED_SHARE: f64 = 0.2;
+const TRUNCATED_SHARE: f64 = 0.05;
+const P99_SHARE: f64 = 0.5;
+
+// Response sizes are counted in buckets of SIZE_BUCKET bytes, the last one for any
+// larger.
+const SIZE_BUCKET: usize = 64;
+const SIZE_BUCKETS: usize = 64;
+
+// Response sizes as percentages of their budget, just below and above it: up to
+// each of these, and over the last.
+const BUDGET_BANDS: [usize; 5] = [50, 90, 100, 110, 150];
+
+// A client, the question's name lowercased, and its type.
+type RetryKey = (IpAddr, String, u16);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TruncationCounts {
+    pub responses: u64,
+    pub truncated: u64,
+    pub retried: u64,
+    pub never_retried: u64,
+    // Responses by size next to their budget, in BUDGET_BANDS.
+    pub bands: [u64; BUDGET_BANDS.len() + 1],
+    sizes: [u64; SIZE_BUCKETS],
+}
+
+impl Default for TruncationCounts {
+    fn default() -> Self {
+        TruncationCounts {
+            responses: 0,
+            truncated: 0,
+            retried: 0,
+            never_retried: 0,
+            bands: [0; BUDGET_BANDS.len() + 1],
+            sizes: [0; SIZE_BUCKETS],
+        }
+    }
+}
+
+impl TruncationCounts {
+    // The size in bytes that 99% of the responses are at most, to SIZE_BUCKET.
+    pub fn p99_size(&self) -> Option<usize> {
+        if self.responses == 0 {
+            return None;
+        }
+        let target = (self.responses as f64 * 0.99).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.sizes.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Some((bucket + 1) * SIZE_BUCKET);
+            }
+        }
+        Some(SIZE_BUCKETS * SIZE_BUCKET)
+    }
+
+    // The share of the truncated responses seen through that were never retried.
+    pub fn never_retried_share(&self) -> Option<f64> {
+        let resolved = self.retried + self.never_retried;
+        (resolved > 0).then(|| self.never_retried as f64 / resolved as f64)
+    }
+
+    // What to change, by these counts, with --max-udp-size at `max_udp_size`.
+    pub fn advice(&self, max_udp_size: usize) -> Vec<String> {
+        let mut advice = Vec::new();
+        if let Some(share) = self.never_retried_share() {
+            if self.retried + self.never_retried >= MIN_TRUNCATIONS && share >= NEVER_RETRIED_SHARE
+            {
+                advice.push(format!(
+                    "{:.0}% of truncated clients never retried over TCP; consider a lower \
+                     --max-addresses-per-rrset, so that fewer responses need TC",
+                    share * 100.0
+                ));
+            }
+        }
+        if self.responses < MIN_RESPONSES {
+            return advice;
+        }
+        let truncated = self.truncated as f64 / self.responses as f64;
+        if truncated >= TRUNCATED_SHARE {
+            let just_over = self.bands[3];
+            advice.push(format!(
+                "{:.0}% of UDP responses were truncated, {} of them by at most 10% of their \
+                 budget; consider a higher --max-udp-size than {}",
+                truncated * 100.0,
+                just_over,
+                max_udp_size
+            ));
+        }
+        if let Some(p99) = self.p99_size() {
+            if max_udp_size > 512 && p99 as f64 <= max_udp_size as f64 * P99_SHARE {
+                advice.push(format!(
+                    "p99 response size is {}B; --max-udp-size {} could be lowered",
+                    p99, max_udp_size
+                ));
+            }
+        }
+        advice
+    }
+}
+
+impl fmt::Display for TruncationCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} UDP responses, {} truncated, {} retried over TCP, {} never retried",
+            self.responses, self.truncated, self.retried, self.never_retried
+        )?;
+        if let Some(p99) = self.p99_size() {
+            write!(f, ", p99 size {}B", p99)?;
+        }
+        write!(f, ", by budget:")?;
+        for (band, count) in self.bands.iter().enumerate() {
+            match BUDGET_BANDS.get(band) {
+                Some(percent) => write!(f, " <={}%: {}", percent, count)?,
+                None => write!(f, " >{}%: {}", BUDGET_BANDS[band - 1], count)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Awaiting {
+    truncated_at: Instant,
+    listener: usize,
+}
+
+#[derive(Default)]
+struct TrackerState {
+    listeners: Vec<TruncationCounts>,
+    awaiting: HashMap<RetryKey, Awaiting>,
+    // The keys in the order they were truncated; a key truncated again is in it twice,
+    // and only its latest entry is current.
+    order: VecDeque<(Instant, RetryKey)>,
+    advised_at: Option<Instant>,
+}
+
+impl TrackerState {
+    // Counts the truncated responses whose window ran out by `now` as never retried.
+    fn sweep(&mut self, now: Instant) {
+        while let Some((truncated_at, _)) = self.order.front() {
+            if now.saturating_duration_since(*truncated_at) <= RETRY_WINDOW {
+                break;
+            }
+            let (truncated_at, key) = self.order.pop_front().expect("the order isn't empty");
+            if self
+                .awaiting
+                .get(&key)
+                .is_some_and(|awaiting| awaiting.truncated_at == truncated_at)
+            {
+                let awaiting = self.awaiting.remove(&key).expect("it's awaited");
+                self.listeners[awaiting.listener].never_retried += 1;
+            }
+        }
+    }
+}
+
+pub struct TruncationTracker {
+    // --max-udp-size, for the advice.
+    pub max_udp_size: usize,
+    tags: Vec<String>,
+    state: Mutex<TrackerState>,
+}
+
+impl Default for TruncationTracker {
+    fn default() -> Self {
+        TruncationTracker::new(&[], UDP_PAYLOAD_SIZE as usize)
+    }
+}
+
+impl TruncationTracker {
+    pub fn new(tags: &[String], max_udp_size: usize) -> TruncationTracker {
+        TruncationTracker {
+            max_udp_size,
+            tags: tags.to_vec(),
+            state: Mutex::new(TrackerState {
+                listeners: vec![TruncationCounts::default(); tags.len()],
+                ..Default::default()
+            }),
+        }
+    }
+
+    // Records a UDP response to `client` on `listener` for a question of `name` and
+    // `type`, `size` bytes before it was made to fit `budget`, and whether it was
+    // truncated.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record_udp(
+        &self,
+        listener: &str,
+        client: IpAddr,
+        name: &str,
+        r#type: u16,
+        size: usize,
+        budget: usize,
+        truncated: bool,
+        now: Instant,
+    ) {
+        let Some(index) = self.tags.iter().position(|tag| tag == listener) else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        state.sweep(now);
+        let counts = &mut state.listeners[index];
+        counts.responses += 1;
+        counts.sizes[(size / SIZE_BUCKET).min(SIZE_BUCKETS - 1)] += 1;
+        let percent = size * 100 / budget.max(1);
+        let band = BUDGET_BANDS
+            .iter()
+            .position(|&bound| percent <= bound)
+            .unwrap_or(BUDGET_BANDS.len());
+        counts.bands[band] += 1;
+        if !truncated {
+            return;
+        }
+        counts.truncated += 1;
+        let key = (client, name.to_ascii_lowercase(), r#type);
+        if state.order.len() >= MAX_AWAITING {
+            if let Some((truncated_at, oldest)) = state.order.pop_front() {
+                if state
+                    .awaiting
+                    .get(&oldest)
+                    .is_some_and(|awaiting| awaiting.truncated_at == truncated_at)
+                {
+                    state.awaiting.remove(&oldest);
+                }
+            }
+        }
+        state.order.push_back((now, key.clone()));
+        state.awaiting.insert(
+            key,
+            Awaiting {
+                truncated_at: now,
+                listener: index,
+            },
+        );
+    }
+
+    // Records a query from `client` over TCP, which is the retry of a truncated
+    // response if one for the question is awaited.
+    pub fn record_tcp(&self, client: IpAddr, name: &str, r#type: u16, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.sweep(now);
+        let key = (client, name.to_ascii_lowercase(), r#type);
+        if let Some(awaiting) = state.awaiting.remove(&key) {
+            state.listeners[awaiting.listener].retried += 1;
+        }
+    }
+
+    // The counts of each listener, by tag, as of `now`.
+    pub fn counts(&self, now: Instant) -> Vec<(String, TruncationCounts)> {
+        let mut state = self.state.lock().unwrap();
+        state.sweep(now);
+        self.tags
+            .iter()
+            .cloned()
+            .zip(state.listeners.clone())
+            .collect()
+    }
+
+    // The advice for each listener, tagged, if ADVICE_INTERVAL has passed since it was
+    // last given.
+    pub fn advise(&self, now: Instant) -> Vec<String> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.advised_at.is_some_and(|advised_at| {
+                now.saturating_duration_since(advised_at) < ADVICE_INTERVAL
+            }) {
+                return Vec::new();
+            }
+            state.advised_at = Some(now);
+        }
+        self.counts(now)
+            .into_iter()
+            .flat_map(|(tag, counts)| {
+                counts
+                    .advice(self.max_udp_size)
+                    .into_iter()
+                    .map(move |advice| format!("[{}] {}", tag, advice))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn client(index: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, index))
+    }
+
+    #[test]
+    fn retries_over_tcp_are_matched_to_their_truncation() {
+        let tracker = TruncationTracker::new(&[String::from("udp"), String::from("other")], 1232);
+        let start = Instant::now();
+        // 30 clients get a TC; 18 of them come back over TCP in the window, in any
+        // case, 2 only after it, and the rest never.
+        for index in 0..30 {
+            tracker.record_udp(
+                "udp",
+                client(index),
+                "big.example",
+                1,
+                1400,
+                1232,
+                true,
+                start,
+            );
+        }
+        for index in 0..18 {
+            let later = start + Duration::from_secs(1);
+            tracker.record_tcp(client(index), "BIG.example", 1, later);
+        }
+        // Another client's, or another question's, TCP query isn't a retry.
+        tracker.record_tcp(client(99), "big.example", 1, start);
+        tracker.record_tcp(client(20), "big.example", 28, start);
+        let late = start + RETRY_WINDOW + Duration::from_secs(1);
+        for index in 18..20 {
+            tracker.record_tcp(client(index), "big.example", 1, late);
+        }
+
+        let counts = tracker.counts(late);
+        assert_eq!(counts[1].1, TruncationCounts::default());
+        let (tag, counts) = &counts[0];
+        assert_eq!(tag, "udp");
+        assert_eq!(
+            (counts.truncated, counts.retried, counts.never_retried),
+            (30, 18, 12)
+        );
+        assert_eq!(counts.never_retried_share(), Some(0.4));
+        // 1400 is 113% of 1232.
+        assert_eq!(counts.bands, [0, 0, 0, 0, 30, 0]);
+
+        // Given once per interval.
+        let advice = tracker.advise(late);
+        assert_eq!(advice.len(), 1, "{advice:?}");
+        assert!(
+            advice[0].starts_with("[udp] 40% of truncated clients never retried over TCP"),
+            "{advice:?}"
+        );
+        assert!(tracker.advise(late + Duration::from_secs(1)).is_empty());
+        assert_eq!(tracker.advise(late + ADVICE_INTERVAL), advice);
+    }
+
+    #[test]
+    fn advice_needs_enough_responses() {
+        let mut counts = TruncationCounts::default();
+        let tracker = TruncationTracker::new(&[String::from("udp")], 4096);
+        let now = Instant::now();
+        for index in 0..99 {
+            tracker.record_udp("udp", client(index), "a.example", 1, 650, 4096, false, now);
+        }
+        // Few clients retried, but too few were truncated to tell.
+        counts.retried = 5;
+        counts.never_retried = 5;
+        assert!(counts.advice(4096).is_empty());
+        assert!(tracker.counts(now)[0].1.advice(4096).is_empty());
+
+        // With a hundred responses, the small ones show the budget is too big, as long
+        // as it's over 512 bytes.
+        tracker.record_udp("udp", client(100), "a.example", 1, 650, 4096, false, now);
+        let counts = &tracker.counts(now)[0].1;
+        assert_eq!(counts.p99_size(), Some(704));
+        assert_eq!(
+            counts.advice(4096),
+            ["p99 response size is 704B; --max-udp-size 4096 could be lowered"]
+        );
+        assert!(counts.advice(1232).is_empty());
+
+        // Truncating one in ten responses, nine of them just over the budget.
+        let mut counts = counts.clone();
+        counts.truncated = 10;
+        counts.bands[3] = 9;
+        assert_eq!(
+            counts.advice(1232),
+            [
+                "10% of UDP responses were truncated, 9 of them by at most 10% of their \
+              budget; consider a higher --max-udp-size than 1232"
+            ]
+        );
+    }
+}
@@ -0,0 +1,388 @@
+use std::{fs, path::Path, sync::Arc};
+
+use super::dns::message::{LabelSequence, Question, RecordType};
+use super::signature_time::{Clock, SignatureTime};
+
+// The EDNS option carrying the key tags of the trust anchors in use (RFC 8145, 4.1).
+pub const EDNS_KEY_TAG: u16 = 14;
+
+const DNSKEY: RecordType = RecordType::Unknown(48);
+
+// Leftmost labels of the sentinel query names (RFC 8509, 3), followed by a 5-digit
+// key tag; the "kskroll-sentinel-" forms are those of the drafts, still sent by
+// some measurement tools.
+const IS_TA_PREFIXES: [&str; 2] = ["root-key-sentinel-is-ta-", "kskroll-sentinel-is-ta-"];
+const NOT_TA_PREFIXES: [&str; 2] = ["root-key-sentinel-not-ta-", "kskroll-sentinel-not-ta-"];
+
+// A DS-style trust anchor, optionally only valid within a window (as in the IANA
+// root-anchors.xml format).
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrustAnchor {
+    // Lowercased, without the trailing dot; empty for the root.
+    pub owner: String,
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    pub digest: Vec<u8>,
+    pub valid_from: Option<SignatureTime>,
+    pub valid_until: Option<SignatureTime>,
+}
+
+impl TrustAnchor {
+    pub fn is_active(&self, now: SignatureTime) -> bool {
+        self.valid_from.map_or(true, |from| from <= now)
+            && self.valid_until.map_or(true, |until| now < until)
+    }
+}
+
+pub struct TrustAnchors {
+    anchors: Vec<TrustAnchor>,
+    clock: Arc<dyn Clock>,
+}
+
+impl TrustAnchors {
+    pub fn new(anchors: Vec<TrustAnchor>, clock: Arc<dyn Clock>) -> TrustAnchors {
+        TrustAnchors { anchors, clock }
+    }
+
+    // Loads anchors from a file of DS records or in the IANA XML format.
+    pub fn load(path: &Path, clock: Arc<dyn Clock>) -> Result<TrustAnchors, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        Ok(TrustAnchors::new(parse_trust_anchors(&content)?, clock))
+    }
+
+    pub fn len(&self) -> usize {
+        self.anchors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.anchors.is_empty()
+    }
+
+    // Key tags of the root anchors that are valid now, in file order.
+    pub fn active_root_key_tags(&self) -> Vec<u16> {
+        let now = self.clock.now();
+        let mut tags: Vec<u16> = Vec::new();
+        for anchor in &self.anchors {
+            if anchor.owner.is_empty() && anchor.is_active(now) && !tags.contains(&anchor.key_tag) {
+                tags.push(anchor.key_tag);
+            }
+        }
+        tags
+    }
+
+    // Whether the question is an RFC 8509 sentinel query that must fail: an is-ta
+    // name for a key tag we don't trust, or a not-ta name for one we do. Other
+    // names, sentinel or not, are resolved as usual.
+    pub fn sentinel_fails(&self, question: &Question) -> bool {
+        let Some(label) = question.get_name().get_labels().first() else {
+            return false;
+        };
+        let label = label.get_content().to_ascii_lowercase();
+        let key_tag = |prefixes: [&str; 2]| {
+            prefixes
+                .iter()
+                .find_map(|prefix| label.strip_prefix(prefix))
+                .filter(|digits| digits.len() == 5 && digits.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|digits| digits.parse::<u16>().ok())
+        };
+        let trusted = self.active_root_key_tags();
+        if let Some(tag) = key_tag(IS_TA_PREFIXES) {
+            return !trusted.contains(&tag);
+        }
+        if let Some(tag) = key_tag(NOT_TA_PREFIXES) {
+            return trusted.contains(&tag);
+        }
+        false
+    }
+
+    // The edns-key-tag option data to send with `question`, if any: only root
+    // DNSKEY queries carry it (RFC 8145, 4.2).
+    pub fn key_tag_option(&self, question: &Question) -> Option<Vec<u8>> {
+        if question.get_type() != DNSKEY || !question.get_name().get_labels().is_empty() {
+            return None;
+        }
+        let tags = self.active_root_key_tags();
+        if tags.is_empty() {
+            return None;
+        }
+        Some(tags.iter().flat_map(|tag| tag.to_be_bytes()).collect())
+    }
+}
+
+fn owner(name: &str) -> Result<String, String> {
+    let name: LabelSequence = name
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid owner name.", name))?;
+    Ok(name.to_string().to_ascii_lowercase())
+}
+
+fn hex(digits: &str) -> Result<Vec<u8>, String> {
+    if digits.len() % 2 != 0 {
+        return Err(format!("'{}' is not a valid hex digest.", digits));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&digits[index..index + 2], 16)
+                .map_err(|_| format!("'{}' is not a valid hex digest.", digits))
+        })
+        .collect()
+}
+
+fn number<T: std::str::FromStr>(value: &str, what: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid {}.", value, what))
+}
+
+// Parses anchors as DS records ("owner [TTL] [IN] DS tag algorithm digest-type
+// digest", ';' starting comments) or, if the content looks like XML, in the IANA
+// root-anchors.xml format with its validFrom/validUntil windows (RFC 7958).
+pub fn parse_trust_anchors(content: &str) -> Result<Vec<TrustAnchor>, String> {
+    if content.trim_start().starts_with('<') {
+        return parse_xml(content);
+    }
+    let mut anchors: Vec<TrustAnchor> = Vec::new();
+    for line in content.lines() {
+        let line = line.split(';').next().unwrap_or_default();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        let Some(ds) = fields
+            .iter()
+            .position(|field| field.eq_ignore_ascii_case("DS"))
+        else {
+            return Err(format!("'{}' is not a DS record.", line.trim()));
+        };
+        if fields.len() < ds + 5 {
+            return Err(format!("DS record '{}' is incomplete.", line.trim()));
+        }
+        anchors.push(TrustAnchor {
+            owner: owner(fields[0])?,
+            key_tag: number(fields[ds + 1], "key tag")?,
+            algorithm: number(fields[ds + 2], "algorithm")?,
+            digest_type: number(fields[ds + 3], "digest type")?,
+            digest: hex(&fields[ds + 4..].concat())?,
+            valid_from: None,
+            valid_until: None,
+        });
+    }
+    Ok(anchors)
+}
+
+// The text of the first <tag> element in `xml`.
+fn element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(xml[start..end].trim())
+}
+
+// The value of the attribute `name` in the opening tag `tag`.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let start = tag.find(&format!("{}=\"", name))? + name.len() + 2;
+    let end = start + tag[start..].find('"')?;
+    Some(&tag[start..end])
+}
+
+fn parse_xml(content: &str) -> Result<Vec<TrustAnchor>, String> {
+    let zone = owner(element(content, "Zone").unwrap_or("."))?;
+    let mut anchors: Vec<TrustAnchor> = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find("<KeyDigest") {
+        let end = rest[start..]
+            .find("</KeyDigest>")
+            .ok_or("A KeyDigest element is not closed.")?
+            + start;
+        let digest = &rest[start..end];
+        let opening = &digest[..digest.find('>').unwrap_or(digest.len())];
+        let field = |tag: &str| {
+            element(digest, tag).ok_or_else(|| format!("A KeyDigest has no {} element.", tag))
+        };
+        let time = |name: &str| attribute(opening, name).map(parse_timestamp).transpose();
+        anchors.push(TrustAnchor {
+            owner: zone.clone(),
+            key_tag: number(field("KeyTag")?, "key tag")?,
+            algorithm: number(field("Algorithm")?, "algorithm")?,
+            digest_type: number(field("DigestType")?, "digest type")?,
+            digest: hex(field("Digest")?)?,
+            valid_from: time("validFrom")?,
+            valid_until: time("validUntil")?,
+        });
+        rest = &rest[end..];
+    }
+    Ok(anchors)
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date (Howard Hinnant's algorithm).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Parses an xsd:dateTime such as "2017-02-02T00:00:00+00:00" (or with "Z").
+fn parse_timestamp(value: &str) -> Result<SignatureTime, String> {
+    let invalid = || format!("'{}' is not a valid timestamp.", value);
+    let (date, time) = value.split_once('T').ok_or_else(invalid)?;
+    let (time, offset) = match time.find(['+', '-', 'Z']) {
+        Some(index) => time.split_at(index),
+        None => (time, ""),
+    };
+    let parts = |text: &str, separator: char| -> Result<Vec<i64>, String> {
+        text.split(separator)
+            .map(|part| {
+                part.split('.')
+                    .next()
+                    .unwrap_or(part)
+                    .parse()
+                    .map_err(|_| invalid())
+            })
+            .collect()
+    };
+    let (date, time) = (parts(date, '-')?, parts(time, ':')?);
+    if date.len() != 3 || time.len() != 3 {
+        return Err(invalid());
+    }
+    let offset_seconds = match offset {
+        "" | "Z" => 0,
+        _ => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let hours_minutes = parts(&offset[1..], ':')?;
+            sign * (hours_minutes[0] * 3600 + hours_minutes.get(1).unwrap_or(&0) * 60)
+        }
+    };
+    let seconds = days_from_civil(date[0], date[1], date[2]) * 86_400
+        + time[0] * 3600
+        + time[1] * 60
+        + time[2]
+        - offset_seconds;
+    SignatureTime::from_seconds(u64::try_from(seconds).map_err(|_| invalid())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::super::dns::message::RecordClass;
+    use super::super::testing::ManualClock;
+    use super::*;
+
+    // 2017-02-02T00:00:00Z and 2019-01-11T00:00:00Z.
+    const NEW_KEY_VALID_FROM: u64 = 1_485_993_600;
+    const OLD_KEY_VALID_UNTIL: u64 = 1_547_164_800;
+
+    // The root KSK rollover: KSK-2010 (19036) valid until early 2019, KSK-2017
+    // (20326) from early 2017.
+    const ROOT_ANCHORS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<TrustAnchor id="380DC50D-484E-40D0-A3AE-68F2B18F61C7" source="http://data.iana.org/root-anchors/root-anchors.xml">
+<Zone>.</Zone>
+<KeyDigest id="Kjqmt7v" validFrom="2010-07-15T00:00:00+00:00" validUntil="2019-01-11T00:00:00+00:00">
+<KeyTag>19036</KeyTag>
+<Algorithm>8</Algorithm>
+<DigestType>2</DigestType>
+<Digest>49AAC11D7B6F6446702E54A1607371607A1A41855200FD2CE1CDDE32F24E8FB5</Digest>
+</KeyDigest>
+<KeyDigest id="Klajeyz" validFrom="2017-02-02T00:00:00+00:00">
+<KeyTag>20326</KeyTag>
+<Algorithm>8</Algorithm>
+<DigestType>2</DigestType>
+<Digest>E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D</Digest>
+</KeyDigest>
+</TrustAnchor>
+"#;
+
+    fn sentinel(name: &str) -> Question {
+        Question::new(
+            &Arc::new(name.parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        )
+    }
+
+    fn root_dnskey() -> Question {
+        Question::new(
+            &Arc::new(LabelSequence::new(&Arc::from([]))),
+            DNSKEY,
+            RecordClass::In,
+        )
+    }
+
+    #[test]
+    fn both_formats_parse() {
+        let anchors = parse_trust_anchors(ROOT_ANCHORS).unwrap();
+        assert_eq!(anchors.len(), 2);
+        assert_eq!(anchors[0].owner, "");
+        assert_eq!(anchors[0].key_tag, 19036);
+        assert_eq!(
+            anchors[0].valid_until,
+            Some(SignatureTime::from_seconds(OLD_KEY_VALID_UNTIL).unwrap())
+        );
+        assert_eq!(
+            anchors[1].valid_from,
+            Some(SignatureTime::from_seconds(NEW_KEY_VALID_FROM).unwrap())
+        );
+        assert_eq!(anchors[1].valid_until, None);
+
+        let ds = parse_trust_anchors(
+            "; KSK-2017\n. 172800 IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D084 \
+             58E880409BBC683457104237C7F8EC8D\n",
+        )
+        .unwrap();
+        assert_eq!(
+            ds,
+            [TrustAnchor {
+                valid_from: None,
+                ..anchors[1].clone()
+            }]
+        );
+    }
+
+    #[test]
+    fn sentinels_and_key_tags_follow_the_validity_windows() {
+        let clock = Arc::new(ManualClock::at(NEW_KEY_VALID_FROM - 1));
+        let anchors = TrustAnchors::new(parse_trust_anchors(ROOT_ANCHORS).unwrap(), clock.clone());
+        let is_ta_old = sentinel("root-key-sentinel-is-ta-19036.example.com");
+        let is_ta_new = sentinel("Root-Key-Sentinel-Is-TA-20326.example.com");
+        let not_ta_old = sentinel("root-key-sentinel-not-ta-19036.example.com");
+        let not_ta_new = sentinel("kskroll-sentinel-not-ta-20326.example.com");
+        let verdicts = |anchors: &TrustAnchors| {
+            [&is_ta_old, &is_ta_new, &not_ta_old, &not_ta_new]
+                .map(|question| anchors.sentinel_fails(question))
+        };
+
+        // Before KSK-2017 is valid: only KSK-2010 is trusted.
+        assert_eq!(verdicts(&anchors), [false, true, true, false]);
+        assert_eq!(
+            anchors.key_tag_option(&root_dnskey()),
+            Some(vec![0x4A, 0x5C])
+        );
+
+        // During the rollover both are.
+        clock.set(NEW_KEY_VALID_FROM);
+        assert_eq!(verdicts(&anchors), [false, false, true, true]);
+        assert_eq!(
+            anchors.key_tag_option(&root_dnskey()),
+            Some(vec![0x4A, 0x5C, 0x4F, 0x66])
+        );
+
+        // After KSK-2010 is revoked, only KSK-2017 is.
+        clock.set(OLD_KEY_VALID_UNTIL);
+        assert_eq!(verdicts(&anchors), [true, false, false, true]);
+        assert_eq!(
+            anchors.key_tag_option(&root_dnskey()),
+            Some(vec![0x4F, 0x66])
+        );
+
+        // Other names and queries are left alone.
+        assert!(!anchors.sentinel_fails(&sentinel("root-key-sentinel-is-ta-1903.example.com")));
+        assert!(!anchors.sentinel_fails(&sentinel("www.example.com")));
+        assert_eq!(anchors.key_tag_option(&sentinel("example.com")), None);
+    }
+}
@@ -0,0 +1,311 @@
+// TTL honesty (--ttl-honesty-sample): whether the TTLs upstreams advertise match how
+// often their data actually changes, to tune TTL clamps by. It asks nothing of its
+// own: it looks at the answers the forwarder already gets, for clients and for paced
+// lookups like warm-up, and keeps a bounded ring of observations (when, the answer's
+// RRsets hashed without TTLs, the smallest TTL advertised) for a sample of hot names,
+// those answered HOT_AFTER times, up to the size of the sample. Data that changed
+// between two observations changed once, as far as we can tell, so the change
+// interval is the span of the ring over the changes seen in it, and a name's honesty
+// ratio is that interval over its TTL: under 1 the TTL over-claims (caches keep the
+// data after it changed), from UNDER_CLAIMING up it under-claims (the data outlives
+// the TTL, and caches ask again for nothing). With no change seen, the span is a
+// lower bound of the interval, and so is the ratio.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    fmt,
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::dns::message::{Answer, Question, RecordType};
+use super::verify::normalized_rrsets;
+
+// Observations kept per sampled name.
+const RING_SIZE: usize = 32;
+// Answers from upstream that make a name hot enough to sample.
+const HOT_AFTER: u32 = 2;
+// Ratios from which a TTL under-claims.
+const UNDER_CLAIMING: f64 = 2.0;
+// Names listed in each direction by the report.
+const REPORT_TOP: usize = 10;
+
+struct Observation {
+    at: Instant,
+    // The answer's RRsets, without TTLs.
+    digest: u64,
+    ttl: u32,
+}
+
+// How a sampled name's TTL compares with how often its data changed.
+#[derive(Debug)]
+pub struct Honesty {
+    pub name: String,
+    pub r#type: RecordType,
+    // The TTL advertised last.
+    pub ttl: u32,
+    pub changes: usize,
+    // From the first observation kept to the last.
+    pub span: Duration,
+    // The change interval over the TTL; a lower bound if `changes` is 0.
+    pub ratio: f64,
+}
+
+impl Honesty {
+    pub fn over_claims(&self) -> bool {
+        self.changes > 0 && self.ratio < 1.0
+    }
+
+    pub fn under_claims(&self) -> bool {
+        self.ratio >= UNDER_CLAIMING
+    }
+}
+
+impl fmt::Display for Honesty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}. {} ttl {}s, ", self.name, self.r#type, self.ttl)?;
+        if self.changes == 0 {
+            write!(
+                f,
+                "unchanged in {}s, ratio >= {:.2}",
+                self.span.as_secs(),
+                self.ratio
+            )
+        } else {
+            write!(
+                f,
+                "changed every {}s ({} change(s) in {}s), ratio {:.2}",
+                (self.span / self.changes as u32).as_secs(),
+                self.changes,
+                self.span.as_secs(),
+                self.ratio
+            )
+        }
+    }
+}
+
+pub struct TtlHonesty {
+    // How many names are sampled at most.
+    capacity: usize,
+    // By lowercased name and type. Locked before the candidates when both are.
+    sampled: Mutex<HashMap<(String, RecordType), VecDeque<Observation>>>,
+    // Answers seen for names not sampled (yet); cleared when it fills up.
+    candidates: Mutex<HashMap<(String, RecordType), u32>>,
+}
+
+impl TtlHonesty {
+    pub fn new(capacity: usize) -> TtlHonesty {
+        TtlHonesty {
+            capacity,
+            sampled: Default::default(),
+            candidates: Default::default(),
+        }
+    }
+
+    // Takes note of the upstream's `answers` to `question`, got at `now`.
+    pub fn observe(&self, question: &Question, answers: &[Answer], now: Instant) {
+        let Some(ttl) = answers.iter().map(Answer::get_ttl).min() else {
+            return;
+        };
+        let key = (
+            question.get_name().to_string().to_ascii_lowercase(),
+            question.get_type(),
+        );
+        let mut sampled = self.sampled.lock().unwrap();
+        if !sampled.contains_key(&key) {
+            if sampled.len() >= self.capacity {
+                return;
+            }
+            let mut candidates = self.candidates.lock().unwrap();
+            if candidates.len() >= self.capacity * 4 && !candidates.contains_key(&key) {
+                candidates.clear();
+            }
+            let seen = candidates.entry(key.clone()).or_default();
+            *seen += 1;
+            if *seen < HOT_AFTER {
+                return;
+            }
+            candidates.remove(&key);
+        }
+        let mut hasher = DefaultHasher::new();
+        for record in normalized_rrsets(answers) {
+            record.encode().hash(&mut hasher);
+        }
+        let ring = sampled.entry(key).or_default();
+        if ring.len() == RING_SIZE {
+            ring.pop_front();
+        }
+        ring.push_back(Observation {
+            at: now,
+            digest: hasher.finish(),
+            ttl,
+        });
+    }
+
+    // The sampled names with two observations or more.
+    pub fn names(&self) -> Vec<Honesty> {
+        self.sampled
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, ring)| ring.len() >= 2 && ring.back().is_some_and(|last| last.ttl > 0))
+            .map(|((name, r#type), ring)| {
+                let (first, last) = (&ring[0], &ring[ring.len() - 1]);
+                let changes = ring
+                    .iter()
+                    .zip(ring.iter().skip(1))
+                    .filter(|(before, after)| before.digest != after.digest)
+                    .count();
+                let span = last.at - first.at;
+                let interval = span.as_secs_f64() / changes.max(1) as f64;
+                Honesty {
+                    name: name.clone(),
+                    r#type: *r#type,
+                    ttl: last.ttl,
+                    changes,
+                    span,
+                    ratio: interval / last.ttl as f64,
+                }
+            })
+            .collect()
+    }
+
+    // The aggregates, then the names whose TTLs over-claim most and under-claim
+    // most, for the control socket.
+    pub fn report(&self) -> Vec<String> {
+        let mut names = self.names();
+        names.sort_by(|a, b| a.ratio.total_cmp(&b.ratio));
+        let median = names
+            .get(names.len() / 2)
+            .map_or(String::from("n/a"), |honesty| {
+                format!("{:.2}", honesty.ratio)
+            });
+        let over: Vec<&Honesty> = names
+            .iter()
+            .filter(|honesty| honesty.over_claims())
+            .collect();
+        let under: Vec<&Honesty> = names
+            .iter()
+            .rev()
+            .filter(|honesty| honesty.under_claims())
+            .collect();
+        let mut report = vec![format!(
+            "{} name(s) observed of {} sampled: {} over-claiming, {} under-claiming, median ratio {}",
+            names.len(),
+            self.sampled.lock().unwrap().len(),
+            over.len(),
+            under.len(),
+            median
+        )];
+        if !over.is_empty() {
+            report.push(String::from(
+                "over-claiming (the data changed sooner than the TTL):",
+            ));
+            report.extend(
+                over.iter()
+                    .take(REPORT_TOP)
+                    .map(|honesty| format!("  {}", honesty)),
+            );
+        }
+        if !under.is_empty() {
+            report.push(String::from("under-claiming (the data outlived the TTL):"));
+            report.extend(
+                under
+                    .iter()
+                    .take(REPORT_TOP)
+                    .map(|honesty| format!("  {}", honesty)),
+            );
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    use super::super::dns::message::RecordClass;
+    use super::*;
+
+    fn question(name: &str) -> Question {
+        Question::new(
+            &Arc::new(name.parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        )
+    }
+
+    // An upstream's answer at `elapsed`, for a name whose data changes every `every`
+    // and is advertised with `ttl`.
+    fn answer(question: &Question, ttl: u32, every: Duration, elapsed: Duration) -> Vec<Answer> {
+        let generation = (elapsed.as_secs() / every.as_secs()) as u8;
+        vec![Answer::a(
+            question.get_name(),
+            ttl,
+            Ipv4Addr::new(192, 0, 2, generation),
+        )]
+    }
+
+    #[test]
+    fn the_ratio_compares_the_change_interval_with_the_ttl() {
+        let honesty = TtlHonesty::new(2);
+        let started = Instant::now();
+        let churning = question("churning.example");
+        let steady = question("steady.example");
+        let cold = question("cold.example");
+        // Every 20 seconds for 5 minutes: one name changes every minute and claims 5,
+        // the other never changes and claims 30 seconds.
+        for step in 0..=15 {
+            let elapsed = Duration::from_secs(step * 20);
+            let now = started + elapsed;
+            honesty.observe(
+                &churning,
+                &answer(&churning, 300, Duration::from_secs(60), elapsed),
+                now,
+            );
+            honesty.observe(
+                &steady,
+                &answer(&steady, 30, Duration::from_secs(3600), elapsed),
+                now,
+            );
+        }
+        // No room left for another name.
+        for step in 0..5 {
+            honesty.observe(
+                &cold,
+                &answer(&cold, 60, Duration::from_secs(60), Duration::ZERO),
+                started + Duration::from_secs(step),
+            );
+        }
+
+        let mut names = honesty.names();
+        names.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(names.len(), 2);
+        // The first answer only made it hot, so the ring starts 20 seconds in.
+        let churning = &names[0];
+        assert_eq!(churning.changes, 5);
+        assert_eq!(churning.span, Duration::from_secs(280));
+        assert!(
+            (churning.ratio - 280.0 / 5.0 / 300.0).abs() < 1e-9,
+            "{:?}",
+            churning
+        );
+        assert!(churning.over_claims() && !churning.under_claims());
+        let steady = &names[1];
+        assert_eq!(steady.changes, 0);
+        assert!((steady.ratio - 280.0 / 30.0).abs() < 1e-9, "{:?}", steady);
+        assert!(steady.under_claims() && !steady.over_claims());
+
+        assert_eq!(
+            honesty.report(),
+            [
+                "2 name(s) observed of 2 sampled: 1 over-claiming, 1 under-claiming, median ratio 9.33",
+                "over-claiming (the data changed sooner than the TTL):",
+                "  churning.example. A ttl 300s, changed every 56s (5 change(s) in 280s), ratio 0.19",
+                "under-claiming (the data outlived the TTL):",
+                "  steady.example. A ttl 30s, unchanged in 280s, ratio >= 9.33",
+            ]
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// How often learned state is written back to disk while the server runs.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// What has been learned about one upstream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamState {
+    // Smoothed round-trip time (RFC 6298, 2).
+    pub srtt: Duration,
+    pub updated_at: SystemTime,
+}
+
+// Learned per-upstream state, keyed by "<transport>/<address>" (e.g. "udp/8.8.8.8:53")
+// and optionally persisted across restarts in a small line-based file:
+//   <key> <srtt in microseconds> <last update, seconds since the epoch>
+pub struct UpstreamStateStore {
+    path: Option<PathBuf>,
+    entries: HashMap<String, UpstreamState>,
+    last_saved_at: Option<Instant>,
+    // Whether entries changed since the last save.
+    dirty: bool,
+}
+
+impl UpstreamStateStore {
+    pub fn in_memory() -> UpstreamStateStore {
+        UpstreamStateStore {
+            path: None,
+            entries: HashMap::new(),
+            last_saved_at: None,
+            dirty: false,
+        }
+    }
+
+    // Loads the state saved at `path`, dropping entries older than `max_age`. A missing
+    // file starts empty; an unreadable or corrupt one is ignored with a warning.
+    pub fn load(path: PathBuf, max_age: Duration) -> UpstreamStateStore {
+        let mut store = UpstreamStateStore::in_memory();
+        match fs::read_to_string(&path) {
+            Ok(content) => match UpstreamStateStore::parse(&content) {
+                Some(entries) => {
+                    let now = SystemTime::now();
+                    store.entries = entries
+                        .into_iter()
+                        .filter(|(_, state)| {
+                            now.duration_since(state.updated_at)
+                                .map(|age| age <= max_age)
+                                .unwrap_or(true)
+                        })
+                        .collect();
+                }
+                None => eprintln!("Ignoring corrupt upstream state file {}.", path.display()),
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => eprintln!(
+                "Ignoring unreadable upstream state file {}: {}",
+                path.display(),
+                err
+            ),
+        }
+        store.path = Some(path);
+        store
+    }
+
+    fn parse(content: &str) -> Option<HashMap<String, UpstreamState>> {
+        let mut entries = HashMap::new();
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return None;
+            }
+            let srtt = Duration::from_micros(fields[1].parse().ok()?);
+            let updated_at = UNIX_EPOCH + Duration::from_secs(fields[2].parse().ok()?);
+            entries.insert(fields[0].to_string(), UpstreamState { srtt, updated_at });
+        }
+        Some(entries)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&UpstreamState> {
+        self.entries.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Folds a new RTT sample into the upstream's SRTT.
+    pub fn record_rtt(&mut self, key: &str, rtt: Duration) {
+        let srtt = match self.entries.get(key) {
+            Some(state) => (state.srtt * 7 + rtt) / 8,
+            None => rtt,
+        };
+        self.entries.insert(
+            key.to_string(),
+            UpstreamState {
+                srtt,
+                updated_at: SystemTime::now(),
+            },
+        );
+        self.dirty = true;
+    }
+
+    // Saves the store if it changed and the last save was long enough ago.
+    // Called from the server's maintenance timer, off the query path.
+    pub fn save_if_due(&mut self) {
+        let save_due = self
+            .last_saved_at
+            .map_or(true, |saved_at| saved_at.elapsed() >= SAVE_INTERVAL);
+        if self.dirty && save_due {
+            if let Err(err) = self.save() {
+                eprintln!("Failed to save upstream state: {}", err);
+            }
+        }
+    }
+
+    // Saves the store if it changed since it was last saved, however recently; at
+    // shutdown, so that the latest estimates aren't lost.
+    pub fn save_if_dirty(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    // Writes the store to its file (if it has one) via a temporary file and a rename,
+    // so a crash mid-write never leaves a half-written file behind.
+    pub fn save(&mut self) -> io::Result<()> {
+        self.last_saved_at = Some(Instant::now());
+        self.dirty = false;
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut content = String::new();
+        for (key, state) in &self.entries {
+            let updated_at = state
+                .updated_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            content.push_str(&format!(
+                "{} {} {}\n",
+                key,
+                state.srtt.as_micros(),
+                updated_at
+            ));
+        }
+        let temporary_path = path.with_extension("tmp");
+        fs::write(&temporary_path, content)?;
+        fs::rename(&temporary_path, path)
+    }
+}
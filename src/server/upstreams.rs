@@ -0,0 +1,217 @@
+// The upstreams queries are forwarded to (--resolver, repeatable), with failover:
+// a query the first upstream doesn't answer in time, or answers SERVFAIL, is sent
+// to the next one, and so on down the list, before any of them is asked again. An
+// upstream that failed --upstream-skip-failures queries in a row is skipped, that
+// is, asked after the others, for --upstream-skip-secs, rather than being made to
+// cost every query a timeout; the first query to get an answer from it again
+// brings it back. One set, with what it learned, is shared by the forwarders of the
+// server loop and of the workers.
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Health {
+    failures_in_a_row: u32,
+    skipped_until: Option<Instant>,
+    successes: u64,
+    failures: u64,
+}
+
+// How an upstream fared, for the stats.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamCounts {
+    pub upstream: SocketAddr,
+    // Queries it answered, and those it didn't answer in time or answered SERVFAIL.
+    pub successes: u64,
+    pub failures: u64,
+    pub skipped: bool,
+}
+
+impl fmt::Display for UpstreamCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} answered, {} failed{}",
+            self.upstream,
+            self.successes,
+            self.failures,
+            if self.skipped { ", skipped" } else { "" }
+        )
+    }
+}
+
+pub struct UpstreamSet {
+    pub addresses: Vec<SocketAddr>,
+    // Failures in a row after which an upstream is skipped, and for how long.
+    pub skip_failures: u32,
+    pub skip: Duration,
+    health: Mutex<Vec<Health>>,
+}
+
+impl UpstreamSet {
+    pub fn new(addresses: Vec<SocketAddr>, skip_failures: u32, skip: Duration) -> UpstreamSet {
+        UpstreamSet {
+            health: Mutex::new(vec![Health::default(); addresses.len()]),
+            addresses,
+            skip_failures: skip_failures.max(1),
+            skip,
+        }
+    }
+
+    // A single upstream, which there's nothing to skip to.
+    pub fn single(address: SocketAddr) -> UpstreamSet {
+        UpstreamSet::new(vec![address], u32::MAX, Duration::ZERO)
+    }
+
+    pub fn first(&self) -> SocketAddr {
+        self.addresses[0]
+    }
+
+    // The order to ask the upstreams in: as given, but those skipped after the others,
+    // the soonest back first.
+    pub fn order(&self, now: Instant) -> Vec<SocketAddr> {
+        let health = self.health.lock().unwrap();
+        let mut order: Vec<(Option<Instant>, usize)> = health
+            .iter()
+            .enumerate()
+            .map(|(index, health)| (health.skipped_until.filter(|&until| until > now), index))
+            .collect();
+        order.sort();
+        order
+            .into_iter()
+            .map(|(_, index)| self.addresses[index])
+            .collect()
+    }
+
+    // Records whether `upstream` answered a query.
+    pub fn record(&self, upstream: SocketAddr, answered: bool, now: Instant) {
+        let Some(index) = self
+            .addresses
+            .iter()
+            .position(|&address| address == upstream)
+        else {
+            return;
+        };
+        let mut health = self.health.lock().unwrap();
+        let health = &mut health[index];
+        if answered {
+            health.successes += 1;
+            if health.skipped_until.take().is_some_and(|until| until > now) {
+                println!(
+                    "[FORWARD] {} answered again; no longer skipping it.",
+                    upstream
+                );
+            }
+            health.failures_in_a_row = 0;
+            return;
+        }
+        health.failures += 1;
+        health.failures_in_a_row += 1;
+        if health.failures_in_a_row >= self.skip_failures && self.addresses.len() > 1 {
+            println!(
+                "[FORWARD] {} failed {} queries in a row; asking it last for {:?}.",
+                upstream, health.failures_in_a_row, self.skip
+            );
+            health.failures_in_a_row = 0;
+            health.skipped_until = Some(now + self.skip);
+        }
+    }
+
+    pub fn counts(&self, now: Instant) -> Vec<UpstreamCounts> {
+        let health = self.health.lock().unwrap();
+        self.addresses
+            .iter()
+            .zip(health.iter())
+            .map(|(&upstream, health)| UpstreamCounts {
+                upstream,
+                successes: health.successes,
+                failures: health.failures,
+                skipped: health.skipped_until.is_some_and(|until| until > now),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, sync::Arc};
+
+    use super::super::dns::message::{Header, Question, RecordClass, RecordType};
+    use super::super::testing::{forwarder, mock_upstream};
+    use super::super::{Lookup, Resolve};
+    use super::*;
+
+    #[test]
+    fn queries_fail_over_and_then_skip_a_dead_upstream() {
+        // The first upstream never answers; the second does at once.
+        let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+        dead.set_nonblocking(true).unwrap();
+        let live = mock_upstream(&[[192, 0, 2, 1]], 60, Duration::ZERO);
+        let upstreams = Arc::new(UpstreamSet::new(
+            vec![dead.local_addr().unwrap(), live.local_addr().unwrap()],
+            1,
+            Duration::from_secs(60),
+        ));
+        let mut resolver = forwarder(dead.local_addr().unwrap(), 10.0);
+        resolver.upstreams = Arc::clone(&upstreams);
+        resolver.timeout = Duration::from_millis(300);
+        resolver.retries = 2;
+        let question = Question::new(
+            &Arc::new("example.com".parse().unwrap()),
+            RecordType::A,
+            RecordClass::In,
+        );
+
+        // The first query waits out one timeout, not the retries, before failing over.
+        let start = Instant::now();
+        let resolved = resolver.lookup(&Header::default(), &question);
+        assert!(matches!(resolved.lookup, Lookup::Found(ref answers) if answers.len() == 1));
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= resolver.timeout && elapsed < resolver.timeout * 2,
+            "{elapsed:?}"
+        );
+        assert_eq!(
+            resolved.provenance,
+            Some(format!(
+                "source=upstream upstream={}",
+                live.local_addr().unwrap()
+            ))
+        );
+
+        // The next ones go straight to the second upstream.
+        let start = Instant::now();
+        for _ in 0..3 {
+            let lookup = resolver.lookup(&Header::default(), &question).lookup;
+            assert!(matches!(lookup, Lookup::Found(_)));
+        }
+        assert!(start.elapsed() < resolver.timeout, "{:?}", start.elapsed());
+        let mut buf = [0; 512];
+        let mut received = 0;
+        while dead.recv(&mut buf).is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, 1);
+
+        let counts: Vec<String> = upstreams
+            .counts(Instant::now())
+            .iter()
+            .map(|counts| counts.to_string())
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                format!(
+                    "{}: 0 answered, 1 failed, skipped",
+                    dead.local_addr().unwrap()
+                ),
+                format!("{}: 4 answered, 0 failed", live.local_addr().unwrap()),
+            ]
+        );
+    }
+}
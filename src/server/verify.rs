@@ -0,0 +1,477 @@
+// Read-back verification of encoded responses: the final bytes are parsed again,
+// strictly, and compared with the message they were encoded from, so that a late
+// step corrupting them (truncation, patching, ...) is caught before they are sent.
+// The answers must be in CNAME chain order too (see chain.rs). On by default in
+// debug builds, and with --verify-encoding.
+
+use std::{fmt::Write, sync::Arc};
+
+use super::chain;
+use super::dns::message::{
+    rdata_layout, Answer, Appendix, Header, Label, LabelSequence, Message, OptRecord, Question,
+    RdataField, RecordClass, RecordType, PADDING_OPTION,
+};
+
+#[cfg(test)]
+thread_local! {
+    // Test hook: flips the bits of the byte at this offset in the next response
+    // encoded by `encode_verified`.
+    pub static CORRUPT_NEXT: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+}
+
+// An encoding that didn't read back as the message it was encoded from.
+#[derive(Debug)]
+pub struct EncodingMismatch {
+    pub differences: Vec<String>,
+    pub encoded: Arc<[u8]>,
+}
+
+// Encodes `message` followed by `appendix` and, if `verify`, checks that the bytes
+// read back as the message, once what the appendix added is taken off again.
+pub fn encode_verified(
+    message: &Message,
+    appendix: &Appendix,
+    verify: bool,
+) -> Result<Arc<[u8]>, EncodingMismatch> {
+    #[allow(unused_mut)]
+    let mut encoded = message.encode_with(appendix);
+    #[cfg(test)]
+    if let Some(offset) = CORRUPT_NEXT.with(|hook| hook.take()) {
+        let mut bytes = encoded.to_vec();
+        bytes[offset] ^= 0xFF;
+        encoded = bytes.into();
+    }
+    if !verify {
+        return Ok(encoded);
+    }
+    let mut differences = match parse_strict(&encoded) {
+        Ok(parsed) => diff_messages(message, &without_appendix(&parsed, appendix)),
+        Err(err) => vec![format!("unparseable: {}", err)],
+    };
+    if let Some(question) = message.get_questions().first() {
+        differences.extend(chain::violations(
+            question.get_name(),
+            message.get_answers(),
+        ));
+    }
+    if differences.is_empty() {
+        Ok(encoded)
+    } else {
+        Err(EncodingMismatch {
+            differences,
+            encoded,
+        })
+    }
+}
+
+// Parses a whole message, additional section included, and fails rather than
+// panics on anything malformed: a section ending early, a name pointer that
+// doesn't point backwards, or bytes left over.
+pub fn parse_strict(data: &[u8]) -> Result<Message, String> {
+    let header: [u8; 12] = data
+        .get(..12)
+        .and_then(|s| s.try_into().ok())
+        .ok_or_else(|| format!("{} bytes is shorter than a header", data.len()))?;
+    let header = Header::parse_from(&header);
+    let mut offset: usize = 12;
+
+    let mut questions: Vec<Question> = Vec::new();
+    for index in 0..header.get_qd_count() {
+        let name =
+            read_name(data, &mut offset).map_err(|err| format!("question #{}: {}", index, err))?;
+        let fields =
+            take(data, &mut offset, 4).map_err(|err| format!("question #{}: {}", index, err))?;
+        questions.push(Question::new(
+            &Arc::new(name),
+            RecordType::from(u16::from_be_bytes([fields[0], fields[1]])),
+            RecordClass::from(u16::from_be_bytes([fields[2], fields[3]])),
+        ));
+    }
+    let answers = read_records(data, &mut offset, header.get_an_count(), "answer")?;
+    let authorities = read_records(data, &mut offset, header.get_ns_count(), "authority")?;
+    let additionals = read_records(data, &mut offset, header.get_ar_count(), "additional")?;
+    if offset != data.len() {
+        return Err(format!("{} trailing byte(s)", data.len() - offset));
+    }
+    Ok(Message::new(&Arc::new(header), &questions.into(), &answers)
+        .with_authorities(&authorities)
+        .with_additionals(&additionals))
+}
+
+// Takes what `appendix` added off a message read back: the signature, last, and the
+// padding option, last in the OPT record. The counts follow.
+fn without_appendix(message: &Message, appendix: &Appendix) -> Message {
+    let mut additionals: Vec<Answer> = message.get_additionals().to_vec();
+    if appendix.signer.is_some() {
+        additionals.pop();
+    }
+    let opt = additionals.last().and_then(OptRecord::from_answer);
+    if let (Some(_), Some(mut opt)) = (appendix.padding, opt) {
+        if opt.remove_trailing_option(PADDING_OPTION) {
+            *additionals.last_mut().expect("the OPT record is there") = opt.to_answer();
+        }
+    }
+    message.with_additionals(&additionals.into())
+}
+
+fn take<'a>(data: &'a [u8], offset: &mut usize, length: usize) -> Result<&'a [u8], String> {
+    let bytes = data
+        .get(*offset..*offset + length)
+        .ok_or_else(|| format!("ends {} byte(s) short", *offset + length - data.len()))?;
+    *offset += length;
+    Ok(bytes)
+}
+
+// Reads a possibly compressed name at `offset`, leaving `offset` past it.
+fn read_name(data: &[u8], offset: &mut usize) -> Result<LabelSequence, String> {
+    let mut labels: Vec<Label> = Vec::new();
+    let mut position = *offset;
+    let mut end: Option<usize> = None;
+    // Each pointer must jump to before where the last one did, or to before the
+    // name for the first; a pointer back into its own name would loop.
+    let mut bound = *offset;
+    let mut name_length: usize = 1;
+    loop {
+        let length = *data.get(position).ok_or("name runs past the end")? as usize;
+        match length {
+            0 => {
+                *offset = end.unwrap_or(position + 1);
+                return Ok(LabelSequence::new(&labels.into()));
+            }
+            1..=63 => {
+                let content = data
+                    .get(position + 1..position + 1 + length)
+                    .ok_or("label runs past the end")?;
+                let content = std::str::from_utf8(content).map_err(|_| "label isn't UTF-8")?;
+                name_length += 1 + length;
+                if name_length > 255 {
+                    return Err(format!("name at {} is longer than 255 bytes", *offset));
+                }
+                labels.push(Label::new(&Arc::from(content)));
+                position += 1 + length;
+            }
+            0xC0..=0xFF => {
+                let low = *data.get(position + 1).ok_or("pointer runs past the end")? as usize;
+                let target = ((length & 0x3F) << 8) | low;
+                if target >= bound {
+                    return Err(format!("pointer at {} doesn't point backwards", position));
+                }
+                end.get_or_insert(position + 2);
+                position = target;
+                bound = target;
+            }
+            _ => return Err(format!("reserved label type at {}", position)),
+        }
+    }
+}
+
+fn read_records(
+    data: &[u8],
+    offset: &mut usize,
+    count: u16,
+    section: &str,
+) -> Result<Arc<[Answer]>, String> {
+    let mut records: Vec<Answer> = Vec::new();
+    for index in 0..count {
+        let context = |err: String| format!("{} #{}: {}", section, index, err);
+        let name = read_name(data, offset).map_err(context)?;
+        let fields = take(data, offset, 10).map_err(context)?;
+        let length = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+        let start = *offset;
+        let rdata = take(data, offset, length).map_err(context)?;
+        let r#type = RecordType::from(u16::from_be_bytes([fields[0], fields[1]]));
+        let rdata: Arc<[u8]> = match rdata_layout(r#type) {
+            Some(layout) => read_rdata_names(data, start, length, layout).map_err(context)?,
+            None => Arc::from(rdata),
+        };
+        records.push(Answer::new(
+            /* name= */ &Arc::new(name),
+            /* type= */ r#type,
+            /* class= */ RecordClass::from(u16::from_be_bytes([fields[2], fields[3]])),
+            /* ttl= */ u32::from_be_bytes([fields[4], fields[5], fields[6], fields[7]]),
+            /* data= */ &rdata,
+        ));
+    }
+    Ok(records.into())
+}
+
+// Reads the RDATA at `start` laid out as `layout`, with its names decompressed.
+fn read_rdata_names(
+    data: &[u8],
+    start: usize,
+    length: usize,
+    layout: &[RdataField],
+) -> Result<Arc<[u8]>, String> {
+    let mut rdata: Vec<u8> = Vec::new();
+    let mut position = start;
+    for field in layout {
+        match field {
+            RdataField::Name => rdata.extend_from_slice(&read_name(data, &mut position)?.encode()),
+            RdataField::Fixed(size) => rdata.extend_from_slice(take(data, &mut position, *size)?),
+        }
+    }
+    if position != start + length {
+        return Err(format!("data at {} doesn't fit its type", start));
+    }
+    Ok(rdata.into())
+}
+
+// Compares two messages section by section and record by record, and describes
+// every difference. Names are compared in their uncompressed form, so how they
+// were compressed doesn't matter.
+pub fn diff_messages(expected: &Message, actual: &Message) -> Vec<String> {
+    let mut differences: Vec<String> = Vec::new();
+    if expected.get_header().encode() != actual.get_header().encode() {
+        differences.push(format!(
+            "header: expected {}, got {}",
+            expected.get_header(),
+            actual.get_header()
+        ));
+    }
+    diff_section(
+        "question",
+        expected.get_questions(),
+        actual.get_questions(),
+        |a, b| {
+            a.get_name().encode() == b.get_name().encode()
+                && a.get_type() == b.get_type()
+                && a.get_class() == b.get_class()
+        },
+        &mut differences,
+    );
+    for (section, expected, actual) in [
+        ("answer", expected.get_answers(), actual.get_answers()),
+        (
+            "authority",
+            expected.get_authorities(),
+            actual.get_authorities(),
+        ),
+        (
+            "additional",
+            expected.get_additionals(),
+            actual.get_additionals(),
+        ),
+    ] {
+        diff_section(section, expected, actual, same_record, &mut differences);
+    }
+    differences
+}
+
+// Compares two answer sections as RRsets, ignoring TTLs, the order of the records
+// and the casing of their names, as answers to the same question from different
+// upstreams (or at different times) legitimately differ in those.
+pub fn diff_rrsets(expected: &[Answer], actual: &[Answer]) -> Vec<String> {
+    let mut differences: Vec<String> = Vec::new();
+    diff_section(
+        "answer",
+        &normalized_rrsets(expected),
+        &normalized_rrsets(actual),
+        same_record,
+        &mut differences,
+    );
+    differences
+}
+
+// The records with lowercase names and no TTLs, sorted.
+pub fn normalized_rrsets(records: &[Answer]) -> Vec<Answer> {
+    let mut normalized: Vec<Answer> = records
+        .iter()
+        .map(|record| {
+            let labels: Vec<Label> = record
+                .get_name()
+                .get_labels()
+                .iter()
+                .map(|label| Label::new(&Arc::from(label.get_content().to_ascii_lowercase())))
+                .collect();
+            Answer::from_rdata(
+                /* name= */ &Arc::new(LabelSequence::new(&labels.into())),
+                /* class= */ record.get_class(),
+                /* ttl= */ 0,
+                /* data= */ record.get_rdata().clone(),
+            )
+        })
+        .collect();
+    normalized.sort_by_key(|record| {
+        (
+            record.get_name().encode(),
+            record.get_type(),
+            record.get_class(),
+            record.get_data().clone(),
+        )
+    });
+    normalized
+}
+
+fn same_record(a: &Answer, b: &Answer) -> bool {
+    a.get_name().encode() == b.get_name().encode()
+        && a.get_type() == b.get_type()
+        && a.get_class() == b.get_class()
+        && a.get_ttl() == b.get_ttl()
+        && a.get_data() == b.get_data()
+}
+
+fn diff_section<T: std::fmt::Display>(
+    section: &str,
+    expected: &[T],
+    actual: &[T],
+    same: impl Fn(&T, &T) -> bool,
+    differences: &mut Vec<String>,
+) {
+    if expected.len() != actual.len() {
+        differences.push(format!(
+            "{} section: expected {} record(s), got {}",
+            section,
+            expected.len(),
+            actual.len()
+        ));
+    }
+    for (index, (a, b)) in expected.iter().zip(actual.iter()).enumerate() {
+        if !same(a, b) {
+            differences.push(format!("{} #{}: expected {}, got {}", section, index, a, b));
+        }
+    }
+}
+
+// Formats bytes as a hex dump, 16 to a line, with offsets and printable ASCII.
+pub fn hex_dump(data: &[u8]) -> String {
+    let mut dump = String::new();
+    for (line, chunk) in data.chunks(16).enumerate() {
+        let _ = write!(dump, "{:04x}  ", line * 16);
+        for column in 0..16 {
+            match chunk.get(column) {
+                Some(byte) => {
+                    let _ = write!(dump, "{:02x} ", byte);
+                }
+                None => dump.push_str("   "),
+            }
+        }
+        dump.push(' ');
+        dump.extend(chunk.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        dump.push('\n');
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::super::dns::message::RCode;
+    use super::super::testing::{dummy_server, query};
+    use super::super::LoopState;
+    use super::*;
+
+    fn response() -> Message {
+        let server = dummy_server();
+        let mut state = LoopState::new(&server.randomness);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .send_to(
+                &query("example.com", None),
+                server.listeners[0].socket.local_addr().unwrap(),
+            )
+            .unwrap();
+        server.run_once(&mut state).unwrap();
+        let mut buf = [0; 512];
+        let size = client.recv(&mut buf).unwrap();
+        parse_strict(&buf[..size]).unwrap()
+    }
+
+    #[test]
+    fn intact_responses_read_back_as_themselves() {
+        let message = response();
+        assert_eq!(message.get_answers().len(), 1);
+        let encoded = encode_verified(&message, &Appendix::default(), true).unwrap();
+        assert_eq!(encoded, message.encode());
+    }
+
+    #[test]
+    fn corruption_is_described_record_by_record() {
+        let message = response();
+        // 12 + 17 (question) + 2 (owner name, a pointer to the question's) + 4 bytes
+        // in: the TTL's top byte.
+        CORRUPT_NEXT.with(|hook| hook.set(Some(12 + 17 + 2 + 4)));
+        let EncodingMismatch {
+            differences,
+            encoded,
+        } = encode_verified(&message, &Appendix::default(), true).unwrap_err();
+        assert_eq!(differences.len(), 1);
+        assert!(
+            differences[0].starts_with("answer #0: expected example.com")
+                && differences[0].contains("got example.com.    4278190140"),
+            "{}",
+            differences[0]
+        );
+        assert_eq!(encoded[12 + 17 + 2 + 4], 0xFF);
+
+        // A mangled label length makes the rest of the message unreadable.
+        CORRUPT_NEXT.with(|hook| hook.set(Some(12)));
+        let differences = encode_verified(&message, &Appendix::default(), true)
+            .unwrap_err()
+            .differences;
+        assert_eq!(differences.len(), 1);
+        assert!(
+            differences[0].starts_with("unparseable: "),
+            "{}",
+            differences[0]
+        );
+
+        // Without verification the bytes go out as they are.
+        CORRUPT_NEXT.with(|hook| hook.set(Some(12 + 17 + 2 + 4)));
+        assert!(encode_verified(&message, &Appendix::default(), false).is_ok());
+    }
+
+    #[test]
+    fn corrupted_responses_fall_back_to_servfail() {
+        let server = dummy_server();
+        let mut state = LoopState::new(&server.randomness);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .send_to(
+                &query("example.com", None),
+                server.listeners[0].socket.local_addr().unwrap(),
+            )
+            .unwrap();
+        CORRUPT_NEXT.with(|hook| hook.set(Some(2)));
+        server.run_once(&mut state).unwrap();
+        let mut buf = [0; 512];
+        let size = client.recv(&mut buf).unwrap();
+        let fallback = parse_strict(&buf[..size]).unwrap();
+        assert_eq!(*fallback.get_header().get_rcode(), RCode::ServerError);
+        assert_eq!(fallback.get_header().get_id(), 0x1234);
+        assert_eq!(fallback.get_questions().len(), 1);
+        assert!(fallback.get_answers().is_empty());
+        assert_eq!(server.stats.snapshot().encoding_failures, 1);
+    }
+
+    #[test]
+    fn names_pointing_back_into_themselves_are_errors() {
+        let looping = [
+            0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x61,
+            0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01,
+        ];
+        let err = parse_strict(&looping).unwrap_err();
+        assert!(err.contains("pointer at 14"), "{err}");
+    }
+
+    #[test]
+    fn hex_dumps_show_offsets_bytes_and_text() {
+        let dump = hex_dump(b"\x00\x01example\x03com\x00\xff\x10!");
+        let mut lines = dump.lines();
+        assert_eq!(
+            lines.next(),
+            Some("0000  00 01 65 78 61 6d 70 6c 65 03 63 6f 6d 00 ff 10  ..example.com...")
+        );
+        assert_eq!(
+            lines.next(),
+            Some(format!("0010  21{}!", " ".repeat(47)).as_str())
+        );
+        assert_eq!(lines.next(), None);
+    }
+}
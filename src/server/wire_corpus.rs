@@ -0,0 +1,181 @@
+// Runs the wire-format conformance corpus in tests/wire/ (see tests/wire/MANIFEST).
+// Every entry must parse, re-encode to itself or to its checked-in canonical form,
+// and display as its checked-in golden text. Run with UPDATE_GOLDEN=1 to (re)write
+// the canonical and golden files of new entries; review the result before committing.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use super::dns::message::Message;
+
+const ACCEPTED_DIFFERENCES: [&str; 1] = ["recompressed"];
+
+struct Entry {
+    name: String,
+    // Empty if the message must re-encode byte for byte.
+    differences: Vec<String>,
+}
+
+fn corpus_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("wire")
+}
+
+fn read_manifest() -> Vec<Entry> {
+    let content =
+        fs::read_to_string(corpus_dir().join("MANIFEST")).expect("Failed to read MANIFEST");
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            assert!(fields.len() >= 3, "Malformed MANIFEST line: '{}'.", line);
+            let differences: Vec<String> = match fields[2] {
+                "identical" => Vec::new(),
+                list => list.split(',').map(str::to_string).collect(),
+            };
+            for difference in &differences {
+                assert!(
+                    ACCEPTED_DIFFERENCES.contains(&difference.as_str()),
+                    "{}: '{}' is not an accepted re-encoding difference.",
+                    fields[0],
+                    difference
+                );
+            }
+            Entry {
+                name: fields[0].to_string(),
+                differences,
+            }
+        })
+        .collect()
+}
+
+fn read_hex(path: &Path) -> Result<Vec<u8>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+    let digits: String = content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(|line| line.chars().filter(|c| !c.is_whitespace()))
+        .collect();
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| {
+            digits
+                .get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("{} is not valid hex", path.display()))
+        })
+        .collect()
+}
+
+fn to_hex(data: &[u8]) -> String {
+    let rows: Vec<String> = data
+        .chunks(16)
+        .map(|row| {
+            row.iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<String>>()
+                .join(" ")
+        })
+        .collect();
+    rows.join("\n") + "\n"
+}
+
+// The message of the corpus entry `name`, e.g. to check what we send against it.
+pub fn corpus_entry(name: &str) -> Vec<u8> {
+    read_hex(&corpus_dir().join(format!("{}.hex", name))).unwrap()
+}
+
+fn parse(data: &[u8]) -> Result<Message, String> {
+    Message::parse_from(data).map_err(|err| err.to_string())
+}
+
+// Compares `actual` with the checked-in file at `path`, or writes it there when updating.
+fn compare_with_file(path: &Path, actual: &str, update: bool) -> Result<(), String> {
+    if update {
+        return fs::write(path, actual).map_err(|err| err.to_string());
+    }
+    let expected = fs::read_to_string(path)
+        .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+    if expected != actual {
+        return Err(format!(
+            "{} differs; got:\n{}",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            actual
+        ));
+    }
+    Ok(())
+}
+
+fn check(entry: &Entry, update: bool) -> Result<(), String> {
+    let dir = corpus_dir();
+    let wire = read_hex(&dir.join(format!("{}.hex", entry.name)))?;
+    let message = parse(&wire)?;
+
+    let encoded = message.encode();
+    if entry.differences.is_empty() {
+        if encoded.as_ref() != wire.as_slice() {
+            return Err(format!(
+                "re-encoded differently although listed as identical; got:\n{}",
+                to_hex(&encoded)
+            ));
+        }
+    } else {
+        let canonical_path = dir.join(format!("{}.canonical.hex", entry.name));
+        if !update && encoded.as_ref() != read_hex(&canonical_path)?.as_slice() {
+            return Err(format!(
+                "re-encoded form differs from {}; got:\n{}",
+                canonical_path.display(),
+                to_hex(&encoded)
+            ));
+        }
+        compare_with_file(&canonical_path, &to_hex(&encoded), update)?;
+    }
+
+    let text = message.to_string() + "\n";
+    compare_with_file(&dir.join(format!("{}.txt", entry.name)), &text, update)?;
+
+    // Whatever the re-encoding changed, it must carry the same content.
+    if parse(&encoded)?.to_string() + "\n" != text {
+        return Err("the re-encoded message displays differently".to_string());
+    }
+    Ok(())
+}
+
+#[test]
+fn corpus_messages_parse_reencode_and_display_as_recorded() {
+    let update = env::var_os("UPDATE_GOLDEN").is_some();
+    let failures: Vec<String> = read_manifest()
+        .iter()
+        .filter_map(|entry| {
+            check(entry, update)
+                .err()
+                .map(|err| format!("{}: {}", entry.name, err))
+        })
+        .collect();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
+
+#[test]
+fn every_message_is_listed_in_the_manifest() {
+    let names: Vec<String> = read_manifest()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    for file in fs::read_dir(corpus_dir()).expect("Failed to list tests/wire") {
+        let file_name = file.expect("Failed to list tests/wire").file_name();
+        let file_name = file_name.to_string_lossy();
+        if let Some(name) = file_name.strip_suffix(".hex") {
+            let name = name.strip_suffix(".canonical").unwrap_or(name);
+            assert!(
+                names.iter().any(|listed| listed == name),
+                "{} is not listed in tests/wire/MANIFEST.",
+                file_name
+            );
+        }
+    }
+}
@@ -0,0 +1,374 @@
+// Worker threads for queries (--workers), so that one waiting on a slow upstream
+// doesn't hold up the others. The server loop still receives every datagram and
+// every message on a connection, and serves DSO messages, the control socket and
+// maintenance on its own thread; it hands each query to the worker with the fewest
+// queued, which answers it with a server the function the pool is given builds for
+// it: the server loop's handlers, and so its resolvers, with copies of the
+// listeners' sockets to send from, and the connection's writer for queries that
+// came over TCP. What the resolvers learn (e.g. an upstream's response times) is
+// learned once for all, and the maintainer (see maintainer.rs) maintains them. The
+// query log, the capture and the anomaly detector are shared the same way.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, OnceLock,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use super::connection::Pending;
+use super::listener::{QueryInfo, Transport};
+use super::overload::Load;
+use super::quota::Admission;
+use super::{DnsServer, Listener};
+
+// A query for a worker to answer.
+pub struct Job {
+    // Which of the listeners it came in on.
+    pub listener: usize,
+    pub client: SocketAddr,
+    pub load: Load,
+    pub data: Vec<u8>,
+    // Held until the query is answered, for it to count against its client's quota
+    // until then; None if the client was over it.
+    pub admission: Option<Admission>,
+    pub reply: Reply,
+}
+
+// Where the response to a job goes.
+pub enum Reply {
+    // From the listener's socket, to the client.
+    Datagram,
+    // Down the connection the query came in on.
+    Stream(Pending),
+}
+
+struct Worker {
+    jobs: mpsc::Sender<Job>,
+    // Jobs handed to it that it hasn't finished.
+    queued: Arc<AtomicUsize>,
+    // Ends with how many jobs it left unanswered.
+    thread: JoinHandle<usize>,
+}
+
+pub struct WorkerPool {
+    workers: Vec<Worker>,
+    // Set when the pool is stopped; the jobs not started by then are left unanswered.
+    deadline: Arc<OnceLock<Instant>>,
+}
+
+impl WorkerPool {
+    // Starts `count` workers, each answering from copies of `listeners` with the
+    // server `build` makes for it on its thread, given its index and the copies.
+    pub fn spawn(
+        count: usize,
+        listeners: &[Listener],
+        build: impl Fn(usize, Vec<Listener>) -> DnsServer + Send + Sync + 'static,
+    ) -> io::Result<WorkerPool> {
+        let build = Arc::new(build);
+        let deadline: Arc<OnceLock<Instant>> = Arc::new(OnceLock::new());
+        let mut workers = Vec::with_capacity(count);
+        for index in 0..count {
+            let copies = listeners
+                .iter()
+                .map(Listener::try_clone)
+                .collect::<io::Result<Vec<Listener>>>()?;
+            let (jobs, received) = mpsc::channel();
+            let queued = Arc::new(AtomicUsize::new(0));
+            let thread = thread::Builder::new()
+                .name(format!("worker-{}", index))
+                .spawn({
+                    let build = Arc::clone(&build);
+                    let queued = Arc::clone(&queued);
+                    let deadline = Arc::clone(&deadline);
+                    move || work(build(index, copies), received, &queued, &deadline)
+                })?;
+            workers.push(Worker {
+                jobs,
+                queued,
+                thread,
+            });
+        }
+        Ok(WorkerPool { workers, deadline })
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    // Jobs handed out and not finished yet, across the workers.
+    pub fn queued(&self) -> usize {
+        self.workers
+            .iter()
+            .map(|worker| worker.queued.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    // Hands `job` to the worker with the fewest jobs queued.
+    pub fn dispatch(&self, job: Job) {
+        let Some(worker) = self
+            .workers
+            .iter()
+            .min_by_key(|worker| worker.queued.load(Ordering::Relaxed))
+        else {
+            return;
+        };
+        worker.queued.fetch_add(1, Ordering::Relaxed);
+        if let Err(mpsc::SendError(job)) = worker.jobs.send(job) {
+            worker.queued.fetch_sub(1, Ordering::Relaxed);
+            eprintln!(
+                "[WORKER] A worker has stopped; dropping the query from {}.",
+                job.client
+            );
+        }
+    }
+
+    // Has the workers answer the jobs handed to them, those they start before
+    // `deadline`, and waits for them to finish. Fails if some were left unanswered.
+    pub fn stop(self, deadline: Instant) -> io::Result<()> {
+        let _ = self.deadline.set(deadline);
+        let mut unanswered = 0;
+        let mut panicked = 0;
+        for worker in self.workers {
+            drop(worker.jobs);
+            match worker.thread.join() {
+                Ok(left) => unanswered += left,
+                Err(_) => panicked += 1,
+            }
+        }
+        if panicked > 0 {
+            return Err(io::Error::other(format!("{} worker(s) panicked", panicked)));
+        }
+        if unanswered > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} queries left unanswered", unanswered),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// A worker's loop: answers the jobs as they come, until the pool is stopped.
+// Returns how many jobs it left unanswered.
+fn work(
+    server: DnsServer,
+    jobs: mpsc::Receiver<Job>,
+    queued: &AtomicUsize,
+    deadline: &OnceLock<Instant>,
+) -> usize {
+    let mut unanswered = 0;
+    for job in jobs {
+        if deadline.get().is_some_and(|&at| Instant::now() >= at) {
+            unanswered += 1;
+        } else {
+            let listener = &server.listeners[job.listener];
+            let info = QueryInfo {
+                listener: &listener.tag,
+                client: job.client,
+                transport: match job.reply {
+                    Reply::Datagram => Transport::Udp,
+                    Reply::Stream(_) => Transport::Tcp,
+                },
+                load: job.load,
+            };
+            match &job.reply {
+                Reply::Datagram => server.serve_datagram(listener, &info, &job.data),
+                Reply::Stream(pending) => {
+                    if let Some(response) = server.answer_stream(&info, &job.data) {
+                        pending.send(&response);
+                        server.count_sent(&info);
+                    }
+                }
+            }
+        }
+        // Lets the query stop counting against its quotas.
+        drop(job);
+        queued.fetch_sub(1, Ordering::Relaxed);
+    }
+    unanswered
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpStream, UdpSocket},
+        sync::Arc,
+        thread,
+        time::Duration,
+    };
+
+    use super::super::dns::message::{Answer, Header, Message, Question};
+    use super::super::testing::{dummy_server, query, query_handler};
+    use super::super::{ClientQuotas, Lookup, LoopState, Resolve, Resolved};
+    use super::*;
+
+    // Answers every name with an address, after 2 seconds for those under slow.test,
+    // as an upstream slow to answer would.
+    struct Sluggish;
+
+    impl Resolve for Sluggish {
+        fn lookup(&self, _header: &Header, question: &Question) -> Resolved {
+            if question.get_name().to_string().ends_with("slow.test") {
+                thread::sleep(Duration::from_secs(2));
+            }
+            Lookup::Found(vec![Answer::a(
+                question.get_name(),
+                60,
+                "192.0.2.1".parse().unwrap(),
+            )])
+            .into()
+        }
+
+        fn provenance(&self, _question: &Question) -> String {
+            String::from("source=sluggish")
+        }
+
+        fn answers_locally(&self, _question: &Question) -> bool {
+            false
+        }
+    }
+
+    // A server answering with Sluggish on two workers, which share its handlers and
+    // stats.
+    fn sluggish_server() -> DnsServer {
+        let mut server = dummy_server();
+        server.handlers = vec![Arc::new(query_handler(Sluggish))];
+        let handlers = server.handlers.clone();
+        let stats = Arc::clone(&server.stats);
+        server.workers = Some(
+            WorkerPool::spawn(2, &server.listeners, move |index, listeners| {
+                let mut worker = dummy_server();
+                worker.listeners = listeners;
+                worker.handlers = handlers.clone();
+                worker.randomness = worker.randomness.for_worker(index);
+                worker.stats = Arc::clone(&stats);
+                worker
+            })
+            .unwrap(),
+        );
+        server
+    }
+
+    #[test]
+    fn a_slow_upstream_answer_holds_up_no_other_query() {
+        let server = sluggish_server();
+        let stats = Arc::clone(&server.stats);
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let started = Instant::now();
+        client
+            .send_to(&query("www.slow.test", None), address)
+            .unwrap();
+        client
+            .send_to(&query("www.fast.test", None), address)
+            .unwrap();
+
+        // Both are handed out in one iteration, and the fast one answered first.
+        let mut state = LoopState::new(&server.randomness);
+        server.run_once(&mut state).unwrap();
+        let mut buf = [0; 512];
+        let mut answered = Vec::new();
+        for _ in 0..2 {
+            let (size, _) = client.recv_from(&mut buf).unwrap();
+            let response = Message::parse_from(&buf[..size]).unwrap();
+            answered.push((
+                response.get_questions()[0].get_name().to_string(),
+                started.elapsed(),
+            ));
+        }
+        assert_eq!(answered[0].0, "www.fast.test");
+        assert!(answered[0].1 < Duration::from_secs(1), "{:?}", answered);
+        assert_eq!(answered[1].0, "www.slow.test");
+        assert!(answered[1].1 >= Duration::from_secs(2), "{:?}", answered);
+
+        let reports = server.shut_down(state);
+        assert!(reports.iter().all(|report| report.error.is_none()));
+        // Counted in the stats the server loop reports.
+        assert_eq!(stats.responses_sent.get(), 2);
+    }
+
+    #[test]
+    fn queries_on_one_connection_are_answered_as_they_are_ready() {
+        let server = sluggish_server();
+        let address = server.listeners[0].stream_listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut state = LoopState::new(&server.randomness);
+        while state.connections.is_empty() {
+            server.run_once(&mut state).unwrap();
+        }
+        for name in ["www.slow.test", "www.fast.test"] {
+            let query = query(name, None);
+            client
+                .write_all(&(query.len() as u16).to_be_bytes())
+                .unwrap();
+            client.write_all(&query).unwrap();
+        }
+        while server.workers.as_ref().unwrap().queued() < 2 {
+            server.run_once(&mut state).unwrap();
+        }
+
+        // The fast one doesn't wait behind the slow one (RFC 7766, 7).
+        let mut answered = Vec::new();
+        for _ in 0..2 {
+            let mut length = [0; 2];
+            client.read_exact(&mut length).unwrap();
+            let mut response = vec![0; u16::from_be_bytes(length) as usize];
+            client.read_exact(&mut response).unwrap();
+            let response = Message::parse_from(&response).unwrap();
+            answered.push(response.get_questions()[0].get_name().to_string());
+        }
+        assert_eq!(answered, ["www.fast.test", "www.slow.test"]);
+
+        let reports = server.shut_down(state);
+        assert!(reports.iter().all(|report| report.error.is_none()));
+    }
+
+    #[test]
+    fn a_query_counts_against_its_client_quota_until_a_worker_answers_it() {
+        let mut server = sluggish_server();
+        server.quotas = Arc::new(ClientQuotas::new(1, 1).unwrap());
+        let (stats, quotas) = (Arc::clone(&server.stats), Arc::clone(&server.quotas));
+        let address = server.listeners[0].socket.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut state = LoopState::new(&server.randomness);
+        client
+            .send_to(&query("www.slow.test", None), address)
+            .unwrap();
+        server.run_once(&mut state).unwrap();
+        assert_eq!(quotas.report(), ["127.0.0.1 inflight=1 queued=0"]);
+
+        // While a worker is on the first, the second is over the client's quota.
+        client
+            .send_to(&query("www.fast.test", None), address)
+            .unwrap();
+        server.run_once(&mut state).unwrap();
+        assert_eq!(stats.over_client_quota.get(), 1);
+        let mut buf = [0; 512];
+        for _ in 0..2 {
+            client.recv_from(&mut buf).unwrap();
+        }
+
+        let reports = server.shut_down(state);
+        assert!(reports.iter().all(|report| report.error.is_none()));
+        assert!(quotas.report().is_empty(), "{:?}", quotas.report());
+    }
+}
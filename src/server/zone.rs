@@ -0,0 +1,671 @@
+// Authoritative zones (--zone ORIGIN FILE): zones read from master files (RFC 1035,
+// 5) and answered with AA set, for every name under their origin. A name the zone
+// doesn't have is NXDOMAIN, and a name without records of the type asked for is
+// NODATA, both with the zone's SOA in the authority section; a name under a wildcard
+// (RFC 4592) that doesn't exist gets the wildcard's records, under its own name.
+// Names outside every zone go on to the resolver wrapped, or are REFUSED with
+// --zone-refuse-outside.
+//
+// The files take $ORIGIN (starting as ORIGIN), $TTL, $INCLUDE and $GENERATE (see
+// zone_file.rs); names relative to the origin or absolute, @ for the origin itself;
+// records of type SOA, NS, A, AAAA, CNAME, MX, TXT, SRV and PTR, in class IN; TTLs
+// in seconds or with units (1h30m); quoted strings; and records spread over lines in
+// parentheses. A record without an owner has the previous one's, and one without a
+// TTL that of $TTL, or else of the previous record with one. Errors name the file,
+// line and column they are about; the column is within the record's first line.
+
+use std::{
+    collections::HashSet,
+    io,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+
+use super::dns::message::{
+    Answer, Header, LabelSequence, LabelSequenceParseError, Question, RCode, RData, RecordClass,
+    RecordType,
+};
+use super::policy::{parse_record_type, DomainSuffix};
+use super::records::StaticRecords;
+use super::rpz::absolute;
+use super::zone_file::{self, split_fields_at, ZoneEvent, ZoneSource};
+use super::{Lookup, Resolve, Resolved};
+
+// A zone's origin and file, given on the command line as "--zone example.com
+// zones/example.com.zone".
+#[derive(Clone, Debug)]
+pub struct ZoneSpec {
+    pub origin: String,
+    pub path: PathBuf,
+}
+
+impl ZoneSpec {
+    pub fn new(origin: &str, path: &Path) -> Result<ZoneSpec, String> {
+        DomainSuffix::parse(origin)?;
+        Ok(ZoneSpec {
+            origin: origin.to_string(),
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+pub struct Zone {
+    pub origin: Arc<LabelSequence>,
+    suffix: DomainSuffix,
+    records: StaticRecords,
+    soa: Answer,
+    // The names in the zone, lowercased without the trailing dot: the owners, and
+    // the names between them and the origin, which exist without records.
+    names: HashSet<String>,
+}
+
+impl Zone {
+    pub fn load(spec: &ZoneSpec) -> Result<Zone, String> {
+        let source = zone_file::read(&spec.path)
+            .map_err(|err| format!("{}: {}", spec.path.display(), err))?;
+        Zone::from_source(&spec.origin, source)
+            .map_err(|err| format!("{}: {}", spec.path.display(), err))
+    }
+
+    #[cfg(test)]
+    pub fn parse(origin: &str, text: &str) -> Result<Zone, String> {
+        Zone::from_source(origin, zone_file::from_text(text)?)
+    }
+
+    fn from_source(origin: &str, source: ZoneSource) -> Result<Zone, String> {
+        let apex = absolute(origin, Some(""))?;
+        let suffix = DomainSuffix::parse(&apex)?;
+        let mut origin = apex.clone();
+        let mut default_ttl: Option<u32> = None;
+        let mut last_ttl: Option<u32> = None;
+        let mut owner: Option<String> = None;
+        let mut including: Vec<(String, Option<String>)> = Vec::new();
+        let mut records = StaticRecords::new();
+        let mut soa: Option<Answer> = None;
+        let mut names: HashSet<String> = HashSet::new();
+        for event in source.events {
+            let (location, line) = match event {
+                ZoneEvent::Line { location, text } => (location, text),
+                ZoneEvent::Include {
+                    location,
+                    origin: given,
+                } => {
+                    including.push((origin.clone(), owner.clone()));
+                    if let Some(given) = given {
+                        origin = absolute(&given, Some(&origin))
+                            .map_err(|err| format!("{}: {}", location, err))?;
+                    }
+                    continue;
+                }
+                ZoneEvent::EndInclude => {
+                    if let Some((outer, outer_owner)) = including.pop() {
+                        origin = outer;
+                        owner = outer_owner;
+                    }
+                    continue;
+                }
+            };
+            let mut fields = split_fields_at(&line);
+            let at =
+                |column: usize, err: String| format!("{}, column {}: {}", location, column, err);
+            let end = line.chars().count() + 1;
+            let Some((column, first)) = fields.first().cloned() else {
+                continue;
+            };
+            match first.to_ascii_uppercase().as_str() {
+                "$ORIGIN" => {
+                    let (column, name) = fields
+                        .get(1)
+                        .ok_or_else(|| at(end, String::from("$ORIGIN needs a name.")))?;
+                    origin = absolute(name, Some(&origin)).map_err(|err| at(*column, err))?;
+                    continue;
+                }
+                "$TTL" => {
+                    let (column, ttl) = fields
+                        .get(1)
+                        .ok_or_else(|| at(end, String::from("$TTL needs a TTL.")))?;
+                    default_ttl = Some(parse_ttl(ttl).map_err(|err| at(*column, err))?);
+                    continue;
+                }
+                directive if directive.starts_with('$') => {
+                    return Err(at(column, format!("{} is not supported.", first)));
+                }
+                _ => {}
+            }
+            if !line.starts_with(char::is_whitespace) {
+                fields.remove(0);
+                owner = Some(absolute(&first, Some(&origin)).map_err(|err| at(column, err))?);
+            }
+            let Some(name) = owner.clone() else {
+                return Err(at(column, String::from("The first record needs an owner.")));
+            };
+            if !suffix.matches(&parse_name(&name).map_err(|err| at(column, err))?) {
+                return Err(at(column, format!("{} is outside of {}.", name, apex)));
+            }
+            // [TTL] [CLASS] TYPE DATA..., TTL and class in either order.
+            let mut ttl: Option<u32> = None;
+            while let Some((column, field)) = fields.first() {
+                if field.starts_with(|c: char| c.is_ascii_digit()) {
+                    ttl = Some(parse_ttl(field).map_err(|err| at(*column, err))?);
+                } else if let Ok(class) = field.parse::<RecordClass>() {
+                    if class != RecordClass::In {
+                        return Err(at(
+                            *column,
+                            format!("Only class IN is served, not {}.", field),
+                        ));
+                    }
+                } else {
+                    break;
+                }
+                fields.remove(0);
+            }
+            if fields.is_empty() {
+                return Err(at(end, format!("{} has no type.", name)));
+            }
+            let (column, r#type) = fields.remove(0);
+            let r#type = parse_record_type(&r#type).map_err(|err| at(column, err))?;
+            if ttl.is_some() {
+                last_ttl = ttl;
+            }
+            let ttl = ttl.or(default_ttl).or(last_ttl).ok_or_else(|| {
+                at(
+                    column,
+                    format!("{} has no TTL, and there is no $TTL before it.", name),
+                )
+            })?;
+            let data = parse_data(r#type, &fields, &origin)
+                .map_err(|(column, err)| at(column.unwrap_or(end), err))?;
+            let answer = Answer::from_rdata(
+                /* name= */ &Arc::new(parse_name(&name).map_err(|err| at(column, err))?),
+                /* class= */ RecordClass::In,
+                /* ttl= */ ttl,
+                /* data= */ data,
+            );
+            if r#type == RecordType::Soa {
+                if name != apex {
+                    return Err(at(
+                        column,
+                        format!("{} is not the zone's apex, {}.", name, apex),
+                    ));
+                }
+                if soa.is_some() {
+                    return Err(at(column, String::from("The zone has a second SOA.")));
+                }
+                soa = Some(answer.clone());
+            }
+            // The owner, and the names between it and the apex.
+            let mut rest = name.as_str();
+            while names.insert(rest.to_string()) && rest != apex {
+                rest = rest.split_once('.').map_or("", |(_, parent)| parent);
+            }
+            records.add(answer);
+        }
+        let soa = soa.ok_or_else(|| format!("The zone {} has no SOA.", apex))?;
+        Ok(Zone {
+            origin: Arc::new(parse_name(&apex)?),
+            suffix,
+            records,
+            soa,
+            names,
+        })
+    }
+
+    // Records, the SOA included.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn lookup(&self, question: &Question) -> Lookup {
+        let lookup = self.records.lookup(question);
+        if !matches!(lookup, Lookup::NotFound) {
+            return lookup;
+        }
+        let name = key(question.get_name());
+        if self.names.contains(&name) {
+            return Lookup::FoundNoData;
+        }
+        // The wildcard at the closest name above that exists, if there's one there.
+        let mut rest = name.as_str();
+        while let Some((_, parent)) = rest.split_once('.') {
+            if !self.names.contains(parent) {
+                rest = parent;
+                continue;
+            }
+            let Ok(wildcard) = format!("*.{}", parent).parse::<LabelSequence>() else {
+                break;
+            };
+            let wildcard = Arc::new(wildcard);
+            let lookup = self.records.lookup(&Question::new(
+                &wildcard,
+                question.get_type(),
+                question.get_class(),
+            ));
+            return match lookup {
+                Lookup::Found(answers) => Lookup::Found(
+                    answers
+                        .into_iter()
+                        .map(|answer| {
+                            if !answer.get_name().eq_ignore_case(&wildcard) {
+                                return answer;
+                            }
+                            Answer::from_rdata(
+                                /* name= */ question.get_name(),
+                                /* class= */ answer.get_class(),
+                                /* ttl= */ answer.get_ttl(),
+                                /* data= */ answer.get_rdata().clone(),
+                            )
+                        })
+                        .collect(),
+                ),
+                lookup => lookup,
+            };
+        }
+        Lookup::NotFound
+    }
+}
+
+// A name as the zone's names are kept.
+fn key(name: &LabelSequence) -> String {
+    name.to_string().trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn parse_name(name: &str) -> Result<LabelSequence, String> {
+    name.parse()
+        .map_err(|err: LabelSequenceParseError| err.message)
+}
+
+// A TTL in seconds, or in units as BIND takes them, e.g. 1h30m or 2D.
+pub(super) fn parse_ttl(value: &str) -> Result<u32, String> {
+    let invalid = || format!("'{}' is not a TTL.", value);
+    if let Ok(seconds) = value.parse() {
+        return Ok(seconds);
+    }
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit: u64 = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(invalid()),
+        };
+        let count: u64 = number.parse().map_err(|_| invalid())?;
+        total += count * unit;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+    u32::try_from(total).map_err(|_| invalid())
+}
+
+// The data of a record of `type` from its fields, names in it relative to `origin`;
+// or what's wrong with them, and the column where, if it's at a field.
+pub(super) fn parse_data(
+    r#type: RecordType,
+    fields: &[(usize, String)],
+    origin: &str,
+) -> Result<RData, (Option<usize>, String)> {
+    let name = |(column, value): &(usize, String)| {
+        absolute(value, Some(origin))
+            .and_then(|name| parse_name(&name))
+            .map_err(|err| (Some(*column), err))
+    };
+    let number = |(column, value): &(usize, String)| {
+        value.parse::<u16>().map_err(|_| {
+            (
+                Some(*column),
+                format!("'{}' is not a number up to 65535.", value),
+            )
+        })
+    };
+    let needs = |what: &str| (None, format!("{} record needs {}.", r#type, what));
+    Ok(match (r#type, fields) {
+        (RecordType::A, [(column, address)]) => {
+            RData::A(address.parse::<Ipv4Addr>().map_err(|_| {
+                (
+                    Some(*column),
+                    format!("'{}' is not an IPv4 address.", address),
+                )
+            })?)
+        }
+        (RecordType::A, _) => return Err(needs("an IPv4 address")),
+        (RecordType::Aaaa, [(column, address)]) => {
+            RData::Aaaa(address.parse::<Ipv6Addr>().map_err(|_| {
+                (
+                    Some(*column),
+                    format!("'{}' is not an IPv6 address.", address),
+                )
+            })?)
+        }
+        (RecordType::Aaaa, _) => return Err(needs("an IPv6 address")),
+        (RecordType::Cname, [target]) => RData::Cname(name(target)?),
+        (RecordType::Ns, [target]) => RData::Ns(name(target)?),
+        (RecordType::Ptr, [target]) => RData::Ptr(name(target)?),
+        (RecordType::Cname | RecordType::Ns | RecordType::Ptr, _) => return Err(needs("a name")),
+        (RecordType::Mx, [preference, exchange]) => RData::Mx {
+            preference: number(preference)?,
+            exchange: name(exchange)?,
+        },
+        (RecordType::Mx, _) => return Err(needs("a preference and an exchange")),
+        (RecordType::Srv, [priority, weight, port, target]) => RData::Srv {
+            priority: number(priority)?,
+            weight: number(weight)?,
+            port: number(port)?,
+            target: name(target)?,
+        },
+        (RecordType::Srv, _) => return Err(needs("a priority, a weight, a port and a target")),
+        (RecordType::Txt, []) => return Err(needs("a string")),
+        (RecordType::Txt, strings) => {
+            if let Some((column, _)) = strings.iter().find(|(_, string)| string.len() > 255) {
+                return Err((
+                    Some(*column),
+                    String::from("The string is longer than 255 bytes."),
+                ));
+            }
+            RData::Txt(
+                strings
+                    .iter()
+                    .map(|(_, string)| Arc::from(string.as_str()))
+                    .collect(),
+            )
+        }
+        (RecordType::Soa, [mname, rname, numbers @ ..]) if numbers.len() == 5 => {
+            let mut parsed: Vec<u32> = Vec::new();
+            for (index, (column, value)) in numbers.iter().enumerate() {
+                // REFRESH, RETRY, EXPIRE and MINIMUM are TTLs; the serial isn't.
+                let value = if index == 0 {
+                    value
+                        .parse()
+                        .map_err(|_| format!("'{}' is not a serial.", value))
+                } else {
+                    parse_ttl(value)
+                };
+                parsed.push(value.map_err(|err| (Some(*column), err))?);
+            }
+            RData::Soa {
+                mname: name(mname)?,
+                rname: name(rname)?,
+                serial: parsed[0],
+                refresh: parsed[1],
+                retry: parsed[2],
+                expire: parsed[3],
+                minimum: parsed[4],
+            }
+        }
+        (RecordType::Soa, _) => return Err(needs("two names and five numbers")),
+        _ => {
+            return Err((
+                None,
+                format!("{} records aren't served from zones.", r#type),
+            ))
+        }
+    })
+}
+
+pub struct ZoneResolver {
+    pub zones: Vec<Zone>,
+    // Whether names outside the zones are REFUSED rather than passed on.
+    pub refuse_outside: bool,
+    pub next: Box<dyn Resolve>,
+}
+
+impl ZoneResolver {
+    // The zone `name` is in, the closest if they nest.
+    fn zone(&self, name: &LabelSequence) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|zone| zone.suffix.matches(name))
+            .max_by_key(|zone| zone.suffix.label_count())
+    }
+}
+
+impl Resolve for ZoneResolver {
+    // The zones are the authority on their names, and nobody else's.
+    fn lookup(&self, header: &Header, question: &Question) -> Resolved {
+        let mut response_header = Header::default();
+        let resolved = match self.zone(question.get_name()) {
+            Some(zone) => {
+                let lookup = zone.lookup(question);
+                let rcode = match lookup {
+                    Lookup::NotFound => RCode::NameError,
+                    _ => RCode::NoError,
+                };
+                response_header.set_aa(true).set_rcode(rcode);
+                Resolved {
+                    authorities: match lookup {
+                        Lookup::NotFound | Lookup::FoundNoData => Arc::from([zone.soa.clone()]),
+                        _ => Arc::from([]),
+                    },
+                    ..lookup.into()
+                }
+            }
+            None if self.refuse_outside => {
+                println!("[ZONE] Refusing {}: it's outside of the zones.", question);
+                response_header.set_rcode(RCode::Refused);
+                Lookup::NotFound.into()
+            }
+            None => return self.next.lookup(header, question),
+        };
+        Resolved {
+            header: Some(Arc::new(response_header)),
+            ..resolved
+        }
+    }
+
+    fn provenance(&self, question: &Question) -> String {
+        match self.zone(question.get_name()) {
+            Some(zone) => format!("source=zone zone={}", zone.origin),
+            None if self.refuse_outside => String::from("source=refused"),
+            None => self.next.provenance(question),
+        }
+    }
+
+    fn answers_locally(&self, question: &Question) -> bool {
+        self.refuse_outside
+            || self.zone(question.get_name()).is_some()
+            || self.next.answers_locally(question)
+    }
+
+    fn claims(&self, question: &Question) -> bool {
+        self.refuse_outside
+            || self.zone(question.get_name()).is_some()
+            || self.next.claims(question)
+    }
+
+    fn maintain(&self) {
+        self.next.maintain();
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        self.next.persist()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.next.next_due()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::super::DummyDnsResolver;
+    use super::*;
+
+    const ZONE: &str = r#"
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+            2024010101 ; serial
+            2h         ; refresh
+            15m        ; retry
+            2w         ; expire
+            300 )      ; minimum
+    IN  NS  ns1
+    IN  NS  ns2.example.net.
+    IN  MX  10 mail
+ns1         A       192.0.2.53
+www     600 A       192.0.2.80
+            AAAA    2001:db8::80
+web         CNAME   www
+mail        A       192.0.2.25
+            TXT     "v=spf1 mx -all" "second string"
+_sip._tcp   SRV     10 60 5060 sip
+sip.deep    A       192.0.2.60
+*.users     A       192.0.2.99
+$ORIGIN lab.example.com.
+host        A       192.0.2.70
+"#;
+
+    fn resolver(refuse_outside: bool) -> ZoneResolver {
+        ZoneResolver {
+            zones: vec![Zone::parse("Example.COM", ZONE).unwrap()],
+            refuse_outside,
+            next: Box::new(DummyDnsResolver {
+                ipv6_address: Ipv6Addr::LOCALHOST,
+            }),
+        }
+    }
+
+    fn question(name: &str, r#type: RecordType) -> Question {
+        Question::new(&Arc::new(name.parse().unwrap()), r#type, RecordClass::In)
+    }
+
+    // The answers as "NAME TTL DATA", and the status, AA and authorities.
+    fn ask(resolver: &ZoneResolver, name: &str, r#type: RecordType) -> (Vec<String>, String) {
+        let question = question(name, r#type);
+        let resolved = resolver.lookup(&Header::default(), &question);
+        let answers = match &resolved.lookup {
+            Lookup::Found(answers) => answers
+                .iter()
+                .map(|answer| {
+                    format!(
+                        "{} {} {:?}",
+                        answer.get_name(),
+                        answer.get_ttl(),
+                        answer.get_rdata()
+                    )
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let status = match resolved.header {
+            Some(header) => format!(
+                "{:?} aa={} authorities={}",
+                header.get_rcode(),
+                header.get_aa(),
+                resolved.authorities.len()
+            ),
+            None => String::from("forwarded"),
+        };
+        (answers, status)
+    }
+
+    #[test]
+    fn zone_files_are_answered_authoritatively() {
+        let resolver = resolver(false);
+        assert_eq!(resolver.zones[0].len(), 14);
+
+        // In any case; the TTL given applies to its record only, $TTL to the next.
+        let (answers, status) = ask(&resolver, "WWW.example.com", RecordType::Aaaa);
+        assert_eq!(answers, ["www.example.com 3600 Aaaa(2001:db8::80)"]);
+        assert_eq!(status, "NoError aa=true authorities=0");
+
+        // The CNAME, and its target in the zone.
+        let (answers, _) = ask(&resolver, "web.example.com", RecordType::A);
+        assert_eq!(answers.len(), 2);
+        assert!(
+            answers[0].starts_with("web.example.com 3600 Cname"),
+            "{answers:?}"
+        );
+        assert_eq!(answers[1], "www.example.com 600 A(192.0.2.80)");
+
+        let (answers, _) = ask(&resolver, "mail.example.com", RecordType::Txt);
+        assert_eq!(
+            answers,
+            ["mail.example.com 3600 Txt([\"v=spf1 mx -all\", \"second string\"])"]
+        );
+        let (answers, _) = ask(&resolver, "_sip._tcp.example.com", RecordType::Srv);
+        assert!(answers[0].contains("port: 5060"), "{answers:?}");
+        let (answers, _) = ask(&resolver, "host.lab.example.com", RecordType::A);
+        assert_eq!(answers, ["host.lab.example.com 3600 A(192.0.2.70)"]);
+
+        // Names under the wildcard get its records; the names that exist don't.
+        let (answers, status) = ask(&resolver, "alice.users.example.com", RecordType::A);
+        assert_eq!(answers, ["alice.users.example.com 3600 A(192.0.2.99)"]);
+        assert_eq!(status, "NoError aa=true authorities=0");
+        let (answers, status) = ask(&resolver, "deep.example.com", RecordType::A);
+        assert!(answers.is_empty());
+        assert_eq!(status, "NoError aa=true authorities=1");
+
+        // NODATA and NXDOMAIN, with the SOA.
+        let (answers, status) = ask(&resolver, "www.example.com", RecordType::Mx);
+        assert!(answers.is_empty());
+        assert_eq!(status, "NoError aa=true authorities=1");
+        let (answers, status) = ask(&resolver, "nope.example.com", RecordType::A);
+        assert!(answers.is_empty());
+        assert_eq!(status, "NameError aa=true authorities=1");
+        let soa = &resolver
+            .lookup(
+                &Header::default(),
+                &question("nope.example.com", RecordType::A),
+            )
+            .authorities[0];
+        assert!(
+            format!("{:?}", soa.get_rdata()).contains("refresh: 7200, retry: 900, expire: 1209600"),
+            "{soa:?}"
+        );
+
+        // Other names are passed on, or refused.
+        let (answers, status) = ask(&resolver, "www.example.org", RecordType::A);
+        assert_eq!(answers, ["www.example.org 60 A(8.8.8.8)"]);
+        assert_eq!(status, "forwarded");
+        let (answers, status) = ask(&self::resolver(true), "www.example.org", RecordType::A);
+        assert!(answers.is_empty());
+        assert_eq!(status, "Refused aa=false authorities=0");
+    }
+
+    #[test]
+    fn errors_name_the_line_and_column() {
+        for (text, err) in [
+            (
+                "@ 3600 SOA ns1 hostmaster 1 2 3 4 5\nwww A 192.0.2.300\n",
+                "line 2, column 7: '192.0.2.300' is not an IPv4 address.",
+            ),
+            (
+                "$TTL 1h\n@ SOA ns1 hostmaster 1 2 3 4 5\nwww.example.org. A 192.0.2.1\n",
+                "line 3, column 1: www.example.org is outside of example.com.",
+            ),
+            (
+                "@ SOA ns1 hostmaster 1 2 3 4 5\n",
+                "line 1, column 3: example.com has no TTL, and there is no $TTL before it.",
+            ),
+            (
+                "$TTL 1h\n@ SOA ns1 hostmaster 1 2 3 4 5\nmail MX 10\n",
+                "line 3, column 11: MX record needs a preference and an exchange.",
+            ),
+            (
+                "$TTL 1h\n@ SOA ns1 hostmaster 1 2 3 4 5\nwww CH A 192.0.2.1\n",
+                "line 3, column 5: Only class IN is served, not CH.",
+            ),
+            (
+                "$TTL 1h\nwww A 192.0.2.1\n",
+                "The zone example.com has no SOA.",
+            ),
+        ] {
+            assert_eq!(Zone::parse("example.com", text).err().as_deref(), Some(err));
+        }
+    }
+}
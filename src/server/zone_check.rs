@@ -0,0 +1,307 @@
+use std::fmt;
+
+use super::dns::message::{Answer, LabelSequence, RecordType};
+use super::legacy::{deprecation, LegacyRdata};
+
+const RRSIG: RecordType = RecordType::Unknown(46);
+const NSEC: RecordType = RecordType::Unknown(47);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    // The data must not be served.
+    Error,
+    // The data can be served but is probably not what was meant.
+    Warning,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub name: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", severity, self.name, self.message)
+    }
+}
+
+fn diagnostic(severity: Severity, name: &str, message: String) -> Diagnostic {
+    Diagnostic {
+        severity,
+        name: name.to_string(),
+        message,
+    }
+}
+
+// The owner of a record, lowercased so that names compare case-insensitively.
+fn owner(answer: &Answer) -> String {
+    answer.get_name().to_string().to_ascii_lowercase()
+}
+
+// The name a record points at, for the types whose target must not be an alias.
+fn target(answer: &Answer) -> Option<String> {
+    let offset = match answer.get_type() {
+        RecordType::Ns => 0,
+        RecordType::Mx => 2,
+        RecordType::Srv => 6,
+        _ => return None,
+    };
+    let (name, _) = LabelSequence::decode_uncompressed(answer.get_data().get(offset..)?)?;
+    Some(name.to_string().to_ascii_lowercase())
+}
+
+// Checks a set of records for data that is well-formed on the wire but wrong as DNS
+// data. Errors:
+//   - a CNAME next to other data at the same name (RFC 1034, 3.6.2; RRSIG and NSEC
+//     are allowed, RFC 4035, 2.5), or more than one CNAME at a name (RFC 2181, 10.1);
+//   - with an `apex`, anything but exactly one SOA there, or no NS (RFC 1035, 5.2).
+// Warnings:
+//   - NS, MX and SRV targets that are aliases (RFC 2181, 10.3; RFC 2782);
+//   - TTLs that differ within an RRset; the first one is used (RFC 2181, 5.2);
+//   - RRsets of the retired RFC 1035 types (MD, MF, MB, MG, MR, NULL, WKS), which
+//     are served as they are; malformed RDATA of those types is an error.
+pub fn validate(records: &[Answer], apex: Option<&LabelSequence>) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let mut names: Vec<String> = records.iter().map(owner).collect();
+    names.sort();
+    names.dedup();
+    let at = |name: &str| -> Vec<&Answer> {
+        records
+            .iter()
+            .filter(|answer| owner(answer) == name)
+            .collect()
+    };
+
+    for name in &names {
+        let node = at(name);
+        let cnames = node
+            .iter()
+            .filter(|answer| answer.get_type() == RecordType::Cname)
+            .count();
+        if cnames > 1 {
+            diagnostics.push(diagnostic(
+                Severity::Error,
+                name,
+                format!("{} CNAME records; a name can have only one", cnames),
+            ));
+        }
+        let others = node
+            .iter()
+            .filter(|answer| !matches!(answer.get_type(), RecordType::Cname | RRSIG | NSEC))
+            .count();
+        if cnames > 0 && others > 0 {
+            diagnostics.push(diagnostic(
+                Severity::Error,
+                name,
+                format!("CNAME next to {} record(s) of other types", others),
+            ));
+        }
+
+        let mut types: Vec<RecordType> = node.iter().map(|answer| answer.get_type()).collect();
+        types.sort();
+        types.dedup();
+        for r#type in types {
+            if let Some(reason) = deprecation(r#type) {
+                diagnostics.push(diagnostic(
+                    Severity::Warning,
+                    name,
+                    format!("type {} is {}", r#type, reason),
+                ));
+            }
+            let mut rrset = node.iter().filter(|answer| answer.get_type() == r#type);
+            let first = rrset.next().map_or(0, |answer| answer.get_ttl());
+            if rrset.any(|answer| answer.get_ttl() != first) {
+                diagnostics.push(diagnostic(
+                    Severity::Warning,
+                    name,
+                    format!(
+                        "TTLs differ within the type {} RRset; {} (the first) is used",
+                        r#type, first
+                    ),
+                ));
+            }
+        }
+    }
+
+    for answer in records {
+        if deprecation(answer.get_type()).is_some()
+            && LegacyRdata::decode(answer.get_type(), &answer.get_data()).is_none()
+        {
+            diagnostics.push(diagnostic(
+                Severity::Error,
+                &owner(answer),
+                format!("malformed type {} RDATA", answer.get_type()),
+            ));
+        }
+        let Some(target) = target(answer) else {
+            continue;
+        };
+        if at(&target)
+            .iter()
+            .any(|other| other.get_type() == RecordType::Cname)
+        {
+            diagnostics.push(diagnostic(
+                Severity::Warning,
+                &owner(answer),
+                format!(
+                    "type {} target {} is an alias (CNAME)",
+                    answer.get_type(),
+                    target
+                ),
+            ));
+        }
+    }
+
+    if let Some(apex) = apex {
+        let name = apex.to_string().to_ascii_lowercase();
+        let node = at(&name);
+        let soas = node
+            .iter()
+            .filter(|answer| answer.get_type() == RecordType::Soa)
+            .count();
+        if soas != 1 {
+            diagnostics.push(diagnostic(
+                Severity::Error,
+                &name,
+                format!(
+                    "{} SOA records at the apex; there must be exactly one",
+                    soas
+                ),
+            ));
+        }
+        if !node
+            .iter()
+            .any(|answer| answer.get_type() == RecordType::Ns)
+        {
+            diagnostics.push(diagnostic(
+                Severity::Error,
+                &name,
+                String::from("no NS records at the apex"),
+            ));
+        }
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::super::dns::message::RecordClass;
+    use super::*;
+
+    fn record(name: &str, r#type: RecordType, ttl: u32, data: &[u8]) -> Answer {
+        Answer::new(
+            /* name= */ &Arc::new(name.parse().unwrap()),
+            /* type= */ r#type,
+            /* class= */ RecordClass::In,
+            /* ttl= */ ttl,
+            /* data= */ &Arc::from(data),
+        )
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        name.parse::<LabelSequence>().unwrap().encode().to_vec()
+    }
+
+    fn with_prefix(prefix: &[u8], target: &str) -> Vec<u8> {
+        let mut data = prefix.to_vec();
+        data.extend(name(target));
+        data
+    }
+
+    // A zone that breaks every rule once.
+    fn broken_zone() -> Vec<Answer> {
+        vec![
+            record("example.com", RecordType::Soa, 3600, &[0; 22]),
+            record("example.com", RecordType::Soa, 3600, &[1; 22]),
+            record(
+                "www.example.com",
+                RecordType::Cname,
+                300,
+                &name("web.example.com"),
+            ),
+            record("www.example.com", RecordType::A, 300, &[192, 0, 2, 1]),
+            record(
+                "alias.example.com",
+                RecordType::Cname,
+                300,
+                &name("a.example.com"),
+            ),
+            record(
+                "Alias.example.com",
+                RecordType::Cname,
+                300,
+                &name("b.example.com"),
+            ),
+            record(
+                "example.com",
+                RecordType::Mx,
+                3600,
+                &with_prefix(&[0, 10], "www.example.com"),
+            ),
+            record(
+                "_sip._udp.example.com",
+                RecordType::Srv,
+                60,
+                &with_prefix(&[0; 6], "alias.example.com"),
+            ),
+            record("web.example.com", RecordType::A, 300, &[192, 0, 2, 2]),
+            record("web.example.com", RecordType::A, 600, &[192, 0, 2, 3]),
+            record(
+                "signed.example.com",
+                RecordType::Cname,
+                300,
+                &name("web.example.com"),
+            ),
+            record("signed.example.com", RRSIG, 300, &[0, 5]),
+        ]
+    }
+
+    #[test]
+    fn every_broken_rule_is_reported() {
+        let apex: LabelSequence = "example.com".parse().unwrap();
+        let diagnostics: Vec<String> = validate(&broken_zone(), Some(&apex))
+            .iter()
+            .map(Diagnostic::to_string)
+            .collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "error: alias.example.com: 2 CNAME records; a name can have only one",
+                "warning: web.example.com: TTLs differ within the type A RRset; 300 (the first) is used",
+                "error: www.example.com: CNAME next to 1 record(s) of other types",
+                "warning: example.com: type MX target www.example.com is an alias (CNAME)",
+                "warning: _sip._udp.example.com: type SRV target alias.example.com is an alias (CNAME)",
+                "error: example.com: 2 SOA records at the apex; there must be exactly one",
+                "error: example.com: no NS records at the apex",
+            ]
+        );
+    }
+
+    #[test]
+    fn clean_zone_has_no_diagnostics() {
+        let zone = vec![
+            record("example.com", RecordType::Soa, 3600, &[0; 22]),
+            record("example.com", RecordType::Ns, 3600, &name("ns.example.com")),
+            record("ns.example.com", RecordType::A, 3600, &[192, 0, 2, 53]),
+            record(
+                "www.example.com",
+                RecordType::Cname,
+                300,
+                &name("ns.example.com"),
+            ),
+        ];
+        let apex: LabelSequence = "example.com".parse().unwrap();
+        assert_eq!(validate(&zone, Some(&apex)), []);
+        // Without an apex, only the node rules apply.
+        assert_eq!(validate(&zone[2..], None), []);
+    }
+}
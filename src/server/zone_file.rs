@@ -0,0 +1,473 @@
+// Reading zone files (RFC 1035, 5) into the lines the zone parsers take: comments
+// dropped, parenthesized records joined, and two directives expanded.
+//
+// $INCLUDE FILE [ORIGIN] reads FILE in place of the directive, resolved against the
+// directory of the file that includes it. The included lines come between the
+// Include and EndInclude events, for the parser to apply ORIGIN to them and to put
+// its origin and current owner back after them. Files that include themselves,
+// through any chain, are refused, as are chains deeper than MAX_INCLUDE_DEPTH.
+//
+// $GENERATE RANGE LHS [TTL] [CLASS] TYPE RHS writes a record for each value in
+// RANGE, START-STOP[/STEP], as BIND does: in LHS and RHS, $ stands for the value,
+// and ${OFFSET,WIDTH,BASE} for the value plus OFFSET, zero-padded to WIDTH, in base
+// d, o, x or X, or n or N for nibbles (the hex digits reversed, a label each, as in
+// ip6.arpa names); \$ or $$ is a $. For example, ${-20,3,d} at 25 is 005. Only A,
+// AAAA, PTR, CNAME and NS records are generated.
+//
+// Errors name the line (and file, for included ones) they are about; for generated
+// records, the $GENERATE's.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+// Deepest chain of $INCLUDEs.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+// Most records one $GENERATE writes.
+const MAX_GENERATED: u64 = 65536;
+
+const GENERATED_TYPES: [&str; 5] = ["A", "AAAA", "PTR", "CNAME", "NS"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ZoneEvent {
+    // A logical line, and where it is, e.g. "line 3" or "shared.zone, line 3".
+    Line {
+        location: String,
+        text: String,
+    },
+    // The lines of an $INCLUDE follow, under its origin, if it gives one.
+    Include {
+        location: String,
+        origin: Option<String>,
+    },
+    EndInclude,
+}
+
+// A zone as read, with the text of every file read, to tell when any of them change.
+#[derive(Debug, Default)]
+pub struct ZoneSource {
+    pub events: Vec<ZoneEvent>,
+    pub text: String,
+}
+
+// Splits a line into fields, keeping quoted strings (without their quotes) whole.
+pub fn split_fields(line: &str) -> Vec<String> {
+    split_fields_at(line)
+        .into_iter()
+        .map(|(_, field)| field)
+        .collect()
+}
+
+// The fields of a line, as split_fields has them, with the column each starts at,
+// counted in characters from 1.
+pub fn split_fields_at(line: &str) -> Vec<(usize, String)> {
+    let mut fields: Vec<(usize, String)> = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let column = line[..line.len() - rest.len()].chars().count() + 1;
+        let (field, tail) = match rest.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some((field, tail)) => (field, tail),
+                None => (quoted, ""),
+            },
+            None => rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len())),
+        };
+        fields.push((column, field.to_string()));
+        rest = tail.trim_start();
+    }
+    fields
+}
+
+// The logical lines of a zone file, without comments, with parenthesized records
+// joined into one line. Continuation lines keep their leading whitespace, which
+// means "the previous owner".
+pub fn logical_lines(source: &str) -> Vec<(usize, String)> {
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    let mut open: Option<(usize, String)> = None;
+    for (index, line) in source.lines().enumerate() {
+        let mut quoted = false;
+        let end = line
+            .char_indices()
+            .find(|&(_, c)| {
+                quoted ^= c == '"';
+                c == ';' && !quoted
+            })
+            .map_or(line.len(), |(end, _)| end);
+        let line = &line[..end];
+        match open.take() {
+            Some((number, mut joined)) => {
+                joined.push(' ');
+                joined.push_str(&line.replace(')', " "));
+                if line.contains(')') {
+                    lines.push((number, joined));
+                } else {
+                    open = Some((number, joined));
+                }
+            }
+            None if line.contains('(') && !line.contains(')') => {
+                open = Some((index + 1, line.replace('(', " ")));
+            }
+            None if !line.trim().is_empty() => {
+                lines.push((index + 1, line.replace(['(', ')'], " ")));
+            }
+            None => {}
+        }
+    }
+    lines.extend(open);
+    lines
+}
+
+// Reads the zone file at `path`, with the files it includes.
+pub fn read(path: &Path) -> Result<ZoneSource, String> {
+    let mut zone = ZoneSource::default();
+    let source = fs::read_to_string(path).map_err(|err| format!("Can't read it: {}", err))?;
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    expand(
+        &source,
+        Some(&canonical),
+        None,
+        &mut vec![canonical.clone()],
+        &mut zone,
+    )?;
+    Ok(zone)
+}
+
+// Reads a zone given as text; it can't include files, having none to be relative to.
+#[cfg(test)]
+pub fn from_text(source: &str) -> Result<ZoneSource, String> {
+    let mut zone = ZoneSource::default();
+    expand(source, None, None, &mut Vec::new(), &mut zone)?;
+    Ok(zone)
+}
+
+// Adds the events of `source`, read from `path` (None for text, and for the top
+// file as far as locations go when `label` is None), with `chain` the files
+// including it, itself last.
+fn expand(
+    source: &str,
+    path: Option<&Path>,
+    label: Option<&str>,
+    chain: &mut Vec<PathBuf>,
+    zone: &mut ZoneSource,
+) -> Result<(), String> {
+    zone.text.push_str(source);
+    for (number, text) in logical_lines(source) {
+        let location = match label {
+            Some(label) => format!("{}, line {}", label, number),
+            None => format!("line {}", number),
+        };
+        let fields = split_fields(&text);
+        match fields
+            .first()
+            .map(|field| field.to_ascii_uppercase())
+            .as_deref()
+        {
+            Some("$INCLUDE") => {
+                let at = |err: String| format!("{}: {}", location, err);
+                let Some(file) = fields.get(1) else {
+                    return Err(at(String::from("$INCLUDE needs a file.")));
+                };
+                let Some(including) = path else {
+                    return Err(at(String::from("$INCLUDE needs the zone to be in a file.")));
+                };
+                let included = including.parent().unwrap_or(Path::new("")).join(file);
+                let canonical = included
+                    .canonicalize()
+                    .map_err(|err| at(format!("Can't read {}: {}", included.display(), err)))?;
+                if chain.contains(&canonical) {
+                    return Err(at(format!("{} includes itself.", file)));
+                }
+                if chain.len() >= MAX_INCLUDE_DEPTH {
+                    return Err(at(format!(
+                        "{} is more than {} $INCLUDEs deep.",
+                        file, MAX_INCLUDE_DEPTH
+                    )));
+                }
+                let source = fs::read_to_string(&canonical)
+                    .map_err(|err| at(format!("Can't read {}: {}", included.display(), err)))?;
+                zone.events.push(ZoneEvent::Include {
+                    location: location.clone(),
+                    origin: fields.get(2).cloned(),
+                });
+                chain.push(canonical.clone());
+                expand(&source, Some(&canonical), Some(file), chain, zone)?;
+                chain.pop();
+                zone.events.push(ZoneEvent::EndInclude);
+            }
+            Some("$GENERATE") => {
+                let location = format!("{} ($GENERATE)", location);
+                for text in
+                    generate(&fields[1..]).map_err(|err| format!("{}: {}", location, err))?
+                {
+                    zone.events.push(ZoneEvent::Line {
+                        location: location.clone(),
+                        text,
+                    });
+                }
+            }
+            _ => zone.events.push(ZoneEvent::Line { location, text }),
+        }
+    }
+    Ok(())
+}
+
+// The records of a $GENERATE, from the fields after it, as lines.
+fn generate(fields: &[String]) -> Result<Vec<String>, String> {
+    let needs = || String::from("$GENERATE needs a range, an owner, a type and data.");
+    let [range, lhs, rest @ ..] = fields else {
+        return Err(needs());
+    };
+    // [TTL] [CLASS], in either order, before the type.
+    let typed = rest
+        .iter()
+        .position(|field| {
+            field.parse::<u32>().is_err()
+                && !["IN", "CH", "HS", "CS"].contains(&field.to_ascii_uppercase().as_str())
+        })
+        .ok_or_else(needs)?;
+    let (middle, [r#type, data @ ..]) = rest.split_at(typed) else {
+        return Err(needs());
+    };
+    if !GENERATED_TYPES.contains(&r#type.to_ascii_uppercase().as_str()) {
+        return Err(format!(
+            "$GENERATE doesn't write {} records, only {}.",
+            r#type,
+            GENERATED_TYPES.join(", ")
+        ));
+    }
+    let [rhs] = data else {
+        return Err(format!(
+            "A generated {} record takes one field of data.",
+            r#type
+        ));
+    };
+    let invalid = || format!("'{}' is not a range, START-STOP[/STEP].", range);
+    let (bounds, step) = match range.split_once('/') {
+        Some((bounds, step)) => (bounds, step.parse::<u64>().map_err(|_| invalid())?),
+        None => (range.as_str(), 1),
+    };
+    let (start, stop) = bounds.split_once('-').ok_or_else(invalid)?;
+    let start: u64 = start.parse().map_err(|_| invalid())?;
+    let stop: u64 = stop.parse().map_err(|_| invalid())?;
+    if start > stop || step == 0 {
+        return Err(invalid());
+    }
+    if (stop - start) / step + 1 > MAX_GENERATED {
+        return Err(format!(
+            "{} would write more than {} records.",
+            range, MAX_GENERATED
+        ));
+    }
+    let mut lines: Vec<String> = Vec::new();
+    for value in (start..=stop).step_by(step as usize) {
+        let mut line = substitute(lhs, value)?;
+        for field in middle {
+            line.push(' ');
+            line.push_str(field);
+        }
+        line.push(' ');
+        line.push_str(r#type);
+        line.push(' ');
+        line.push_str(&substitute(rhs, value)?);
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+// `template` with its $s replaced by `value`, as modified.
+fn substitute(template: &str, value: u64) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some('$')) | ('$', Some('$')) => {
+                chars.next();
+                out.push('$');
+            }
+            ('$', Some('{')) => {
+                chars.next();
+                let mut modifier = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => modifier.push(c),
+                        None => return Err(format!("'{}' has an unclosed ${{.", template)),
+                    }
+                }
+                out.push_str(&modified(&modifier, value)?);
+            }
+            ('$', _) => out.push_str(&value.to_string()),
+            (c, _) => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+// `value` as the modifier OFFSET[,WIDTH[,BASE]] has it.
+fn modified(modifier: &str, value: u64) -> Result<String, String> {
+    let invalid = || format!("'${{{}}}' is not OFFSET[,WIDTH[,BASE]].", modifier);
+    let parts: Vec<&str> = modifier.split(',').collect();
+    if parts.len() > 3 {
+        return Err(invalid());
+    }
+    let offset: i64 = parts[0].trim().parse().map_err(|_| invalid())?;
+    let width: usize = match parts.get(1) {
+        Some(width) => width.trim().parse().map_err(|_| invalid())?,
+        None => 0,
+    };
+    let value = u64::try_from(value as i64 + offset)
+        .map_err(|_| format!("'${{{}}}' makes {} negative.", modifier, value))?;
+    Ok(match parts.get(2).map(|base| base.trim()).unwrap_or("d") {
+        "d" => format!("{:0width$}", value),
+        "o" => format!("{:0width$o}", value),
+        "x" => format!("{:0width$x}", value),
+        "X" => format!("{:0width$X}", value),
+        "n" => nibbles(value, width, false),
+        "N" => nibbles(value, width, true),
+        _ => return Err(invalid()),
+    })
+}
+
+// The hex digits of `value`, least significant first, a label each, padded with
+// zero labels to `width` characters, separators included; as BIND writes them.
+fn nibbles(mut value: u64, mut width: usize, upper: bool) -> String {
+    let mut out = String::new();
+    loop {
+        let digit = char::from_digit((value & 0xf) as u32, 16).expect("a hex digit");
+        out.push(if upper {
+            digit.to_ascii_uppercase()
+        } else {
+            digit
+        });
+        value >>= 4;
+        width = width.saturating_sub(1);
+        if width > 0 || value != 0 {
+            out.push('.');
+            width = width.saturating_sub(1);
+        }
+        if value == 0 && width == 0 {
+            return out;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory of its own for each test, as they run in parallel.
+    fn zone_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zones-{}-{}", std::process::id(), name));
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        for (file, source) in files {
+            fs::write(dir.join(file), source).unwrap();
+        }
+        dir
+    }
+
+    fn lines(zone: &ZoneSource) -> Vec<String> {
+        zone.events
+            .iter()
+            .map(|event| match event {
+                ZoneEvent::Line { location, text } => format!("{}: {}", location, text.trim()),
+                ZoneEvent::Include { location, origin } => {
+                    format!("{}: include {:?}", location, origin)
+                }
+                ZoneEvent::EndInclude => String::from("end"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn includes_are_read_relative_to_the_including_file() {
+        let dir = zone_dir(
+            "chain",
+            &[
+                (
+                    "main.zone",
+                    "$ORIGIN example.\n$INCLUDE shared/hosts.zone hosts\nwww A 192.0.2.1\n",
+                ),
+                ("shared/hosts.zone", "a A 192.0.2.2\n$INCLUDE more.zone\n"),
+                ("shared/more.zone", "b A 192.0.2.3\n"),
+            ],
+        );
+        let zone = read(&dir.join("main.zone")).unwrap();
+        assert_eq!(
+            lines(&zone),
+            [
+                "line 1: $ORIGIN example.",
+                "line 2: include Some(\"hosts\")",
+                "shared/hosts.zone, line 1: a A 192.0.2.2",
+                "shared/hosts.zone, line 2: include None",
+                "more.zone, line 1: b A 192.0.2.3",
+                "end",
+                "end",
+                "line 3: www A 192.0.2.1",
+            ]
+        );
+        assert!(zone.text.contains("b A 192.0.2.3"));
+
+        // A file including itself, through another, is refused where it does.
+        fs::write(dir.join("shared/more.zone"), "$INCLUDE hosts.zone\n").unwrap();
+        let err = read(&dir.join("main.zone")).unwrap_err();
+        assert_eq!(err, "more.zone, line 1: hosts.zone includes itself.");
+        let err = from_text("$INCLUDE shared/hosts.zone\n").unwrap_err();
+        assert_eq!(err, "line 1: $INCLUDE needs the zone to be in a file.");
+    }
+
+    #[test]
+    fn generate_substitutes_as_bind_does() {
+        // The examples of the BIND 9 Administrator Reference Manual.
+        let zone = from_text(
+            "$ORIGIN 0.0.192.IN-ADDR.ARPA.\n\
+             $GENERATE 1-2 @ NS SERVER$.EXAMPLE.\n\
+             $GENERATE 1-127/63 $ CNAME $.0\n",
+        )
+        .unwrap();
+        assert_eq!(
+            lines(&zone)[1..],
+            [
+                "line 2 ($GENERATE): @ NS SERVER1.EXAMPLE.",
+                "line 2 ($GENERATE): @ NS SERVER2.EXAMPLE.",
+                "line 3 ($GENERATE): 1 CNAME 1.0",
+                "line 3 ($GENERATE): 64 CNAME 64.0",
+                "line 3 ($GENERATE): 127 CNAME 127.0",
+            ]
+        );
+
+        for (template, value, expected) in [
+            ("host-${-20,3,d}", 25, "host-005"),
+            ("${0,4,x}", 255, "00ff"),
+            ("${0,0,X}", 255, "FF"),
+            ("${8,3,o}", 0, "010"),
+            ("${0,0,n}", 0x1a, "a.1"),
+            ("${0,4,N}", 0x1a, "A.1."),
+            ("${0,7,n}.ip6.arpa.", 0x1a, "a.1.0.0.ip6.arpa."),
+            ("\\$$$-$", 7, "$$-7"),
+        ] {
+            assert_eq!(substitute(template, value).unwrap(), expected, "{template}");
+        }
+        for (directive, err) in [
+            (
+                "$GENERATE 1-2 host-$ MX 10 mail",
+                "$GENERATE doesn't write MX records",
+            ),
+            ("$GENERATE 5-1 host-$ A 10.0.0.$", "'5-1' is not a range"),
+            (
+                "$GENERATE 1-2 host-${-5} A 10.0.0.$",
+                "'${-5}' makes 1 negative",
+            ),
+            (
+                "$GENERATE 1-2 host-${0,x} A 10.0.0.$",
+                "'${0,x}' is not OFFSET",
+            ),
+        ] {
+            let result = from_text(&format!("$ORIGIN example.\n{directive}\n"));
+            let message = result.unwrap_err();
+            assert!(message.starts_with("line 2 ($GENERATE): "), "{message}");
+            assert!(message.contains(err), "{message}");
+        }
+    }
+}
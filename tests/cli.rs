@@ -0,0 +1,100 @@
+// The command line as a user meets it: the completion scripts for each shell, where an
+// option's value comes from when it's given both on the command line and in the
+// environment, and the options that don't go together. The tests run the binary;
+// `check` exits before any socket is bound.
+
+use std::process::{Command, Output};
+
+fn run(args: &[&str], env: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_codecrafters-dns-server"))
+        .args(args)
+        .envs(env.iter().copied())
+        .output()
+        .expect("Failed to run the server")
+}
+
+#[test]
+fn completions_are_generated_for_every_shell() {
+    for shell in ["bash", "elvish", "fish", "powershell", "zsh"] {
+        let output = run(&["completions", shell], &[]);
+        assert!(output.status.success(), "{shell}: {output:?}");
+        let script = String::from_utf8(output.stdout).unwrap();
+        for word in [
+            "codecrafters-dns-server",
+            "--resolver",
+            "completions",
+            "replay",
+        ] {
+            assert!(script.contains(word), "{shell}: no {word} in the script");
+        }
+    }
+    let output = run(&["completions", "tcsh"], &[]);
+    assert_eq!(output.status.code(), Some(2), "{output:?}");
+}
+
+#[test]
+fn the_command_line_wins_over_the_environment() {
+    let records = (
+        "DNS_SERVER_RECORDS",
+        "a.test A 192.0.2.1;b.test A 192.0.2.2",
+    );
+    let checked = |args: &[&str], env: &[(&str, &str)]| {
+        let output = run(&[args, &["check"]].concat(), env);
+        assert!(output.status.success(), "{args:?} {env:?}: {output:?}");
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert!(checked(&[], &[records]).starts_with("Checked 2 local record(s)"));
+    // The command line's values replace the environment's, rather than adding to them.
+    assert!(checked(&["--record", "c.test A 192.0.2.3"], &[records])
+        .starts_with("Checked 1 local record(s)"));
+
+    // A value the environment gets wrong doesn't matter when the command line has one.
+    let ad_mode = ("DNS_SERVER_AD_MODE", "bogus");
+    let output = run(&["check"], &[ad_mode]);
+    assert_eq!(output.status.code(), Some(2), "{output:?}");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unknown AD mode 'bogus'"));
+    checked(&["--ad-mode", "trust-all"], &[ad_mode]);
+
+    // So it goes in every group of the help, the two above aside.
+    for (group, variable, flag, value) in [
+        ("Listeners", "DNS_SERVER_PORT", "--port", "5353"),
+        ("Upstreams", "DNS_SERVER_CACHE_SIZE", "--cache-size", "100"),
+        (
+            "Zone transfers",
+            "DNS_SERVER_MAX_CONCURRENT_TRANSFERS",
+            "--max-concurrent-transfers",
+            "2",
+        ),
+        ("Overload", "DNS_SERVER_WORKERS", "--workers", "2"),
+        (
+            "Responses",
+            "DNS_SERVER_MAX_UDP_SIZE",
+            "--max-udp-size",
+            "1232",
+        ),
+        ("Debugging", "DNS_SERVER_RANDOM_SEED", "--random-seed", "42"),
+        (
+            "Logs and artifacts",
+            "DNS_SERVER_QUERY_LOG_ROTATE_SIZE",
+            "--query-log-rotate-size",
+            "1048576",
+        ),
+        (
+            "Anomaly detection",
+            "DNS_SERVER_ANOMALY_WINDOW",
+            "--anomaly-window",
+            "60",
+        ),
+        (
+            "Service level objectives",
+            "DNS_SERVER_SLO_WINDOW",
+            "--slo-window",
+            "60",
+        ),
+    ] {
+        let bogus = (variable, "bogus");
+        let output = run(&["check"], &[bogus]);
+        assert_eq!(output.status.code(), Some(2), "{group}: {output:?}");
+        checked(&[flag, value], &[bogus]);
+    }
+}
@@ -0,0 +1,252 @@
+// The library's blocking client (server::client), against a mock upstream, and the
+// query subcommand built on it.
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, UdpSocket},
+    process::Command,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use codecrafters_dns_server::server::{Client, ClientError, RCode, RecordType, UpstreamSpec};
+
+const A: u16 = 1;
+const CNAME: u16 = 5;
+
+// A record owned by `owner`, in wire format.
+fn record(owner: &str, r#type: u16, data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::new();
+    for label in owner.split('.') {
+        record.push(label.len() as u8);
+        record.extend_from_slice(label.as_bytes());
+    }
+    record.push(0);
+    record.extend_from_slice(&r#type.to_be_bytes());
+    record.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+    record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    record.extend_from_slice(data);
+    record
+}
+
+fn name(name: &str) -> Vec<u8> {
+    record(name, 0, &[])[..name.len() + 2].to_vec()
+}
+
+// The question of `query`, lowercased, and its type.
+fn question(query: &[u8]) -> (String, u16, usize) {
+    let mut labels = Vec::new();
+    let mut at = 12;
+    while query[at] != 0 {
+        let length = query[at] as usize;
+        labels.push(String::from_utf8_lossy(&query[at + 1..at + 1 + length]).to_lowercase());
+        at += 1 + length;
+    }
+    let r#type = u16::from_be_bytes([query[at + 1], query[at + 2]]);
+    (labels.join("."), r#type, at + 5)
+}
+
+// An upstream on UDP and TCP, on the same port:
+// - big.client.test comes back truncated over UDP, with three addresses over TCP;
+// - broken.client.test is SERVFAIL, missing.client.test NXDOMAIN;
+// - alias.client.test is a CNAME to www.client.test, without its records;
+// - www.client.test has an A record and no AAAA.
+// It records each question it's asked and whether over TCP.
+struct MockUpstream {
+    address: SocketAddr,
+    asked: Arc<Mutex<Vec<(String, bool)>>>,
+}
+
+impl MockUpstream {
+    fn start() -> MockUpstream {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(address).unwrap();
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let udp_asked = Arc::clone(&asked);
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let response = MockUpstream::respond(&buf[..size], false, &udp_asked);
+                let _ = socket.send_to(&response, source);
+            }
+        });
+        let tcp_asked = Arc::clone(&asked);
+        thread::spawn(move || {
+            for mut stream in listener.incoming().map_while(Result::ok) {
+                let mut length = [0; 2];
+                while stream.read_exact(&mut length).is_ok() {
+                    let mut query = vec![0; u16::from_be_bytes(length) as usize];
+                    stream.read_exact(&mut query).unwrap();
+                    let response = MockUpstream::respond(&query, true, &tcp_asked);
+                    let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+                    framed.extend_from_slice(&response);
+                    stream.write_all(&framed).unwrap();
+                }
+            }
+        });
+        MockUpstream { address, asked }
+    }
+
+    fn respond(query: &[u8], tcp: bool, asked: &Mutex<Vec<(String, bool)>>) -> Vec<u8> {
+        let (asked_name, r#type, end) = question(query);
+        asked.lock().unwrap().push((asked_name.clone(), tcp));
+        let (rcode, truncated, answers) = match (asked_name.as_str(), r#type) {
+            ("big.client.test", A) if !tcp => (0, true, Vec::new()),
+            ("big.client.test", A) => (
+                0,
+                false,
+                (1..=3)
+                    .map(|last| record("big.client.test", A, &[192, 0, 2, last]))
+                    .collect(),
+            ),
+            ("broken.client.test", _) => (2, false, Vec::new()),
+            ("missing.client.test", _) => (3, false, Vec::new()),
+            ("alias.client.test", _) => (
+                0,
+                false,
+                vec![record("alias.client.test", CNAME, &name("www.client.test"))],
+            ),
+            ("www.client.test", A) => (
+                0,
+                false,
+                vec![record("www.client.test", A, &[192, 0, 2, 10])],
+            ),
+            _ => (0, false, Vec::new()),
+        };
+        // The query's header and question, without its OPT record.
+        let mut response = query[..end].to_vec();
+        response[2] = 0x81 | if truncated { 0x02 } else { 0 };
+        response[3] = 0x80 | rcode;
+        response[6..12].copy_from_slice(&[0, answers.len() as u8, 0, 0, 0, 0]);
+        response.extend(answers.concat());
+        response
+    }
+}
+
+fn client(upstream: &MockUpstream, tcp_fallback: bool) -> Client {
+    let mut spec = UpstreamSpec::new(upstream.address);
+    spec.set_timeout(Duration::from_secs(2))
+        .set_tcp_fallback(tcp_fallback);
+    Client::new(spec)
+}
+
+#[test]
+fn truncated_answers_are_asked_again_over_tcp() {
+    let upstream = MockUpstream::start();
+    let resolution = client(&upstream, true)
+        .query("big.client.test", RecordType::A)
+        .unwrap();
+    assert_eq!(resolution.rcode, RCode::NoError);
+    let answers: Vec<String> = resolution
+        .answers
+        .iter()
+        .map(|answer| answer.to_string())
+        .collect();
+    assert_eq!(
+        answers,
+        [
+            "big.client.test.    60    IN    A    192.0.2.1",
+            "big.client.test.    60    IN    A    192.0.2.2",
+            "big.client.test.    60    IN    A    192.0.2.3",
+        ]
+    );
+    assert_eq!(
+        *upstream.asked.lock().unwrap(),
+        [
+            (String::from("big.client.test"), false),
+            (String::from("big.client.test"), true)
+        ]
+    );
+
+    let truncated = client(&upstream, false).query("big.client.test", RecordType::A);
+    assert!(
+        matches!(truncated, Err(ClientError::Truncated)),
+        "{truncated:?}"
+    );
+}
+
+#[test]
+fn failures_are_typed_errors_and_nxdomain_is_an_answer() {
+    let upstream = MockUpstream::start();
+    let client = client(&upstream, true);
+    let failed = client.query("broken.client.test", RecordType::A);
+    assert!(
+        matches!(failed, Err(ClientError::ServerFailure)),
+        "{failed:?}"
+    );
+    let missing = client.query("missing.client.test", RecordType::A).unwrap();
+    assert_eq!(missing.rcode, RCode::NameError);
+    assert!(missing.answers.is_empty());
+    let missing = client.lookup_ip("missing.client.test");
+    assert!(
+        matches!(missing, Err(ClientError::NoSuchName(_))),
+        "{missing:?}"
+    );
+    let invalid = client.query("a..b", RecordType::A);
+    assert!(
+        matches!(invalid, Err(ClientError::InvalidName(_))),
+        "{invalid:?}"
+    );
+
+    // An upstream that never answers.
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut spec = UpstreamSpec::new(silent.local_addr().unwrap());
+    spec.set_timeout(Duration::from_millis(200));
+    let unanswered = Client::new(spec).query("www.client.test", RecordType::A);
+    assert!(
+        matches!(unanswered, Err(ClientError::Timeout)),
+        "{unanswered:?}"
+    );
+}
+
+#[test]
+fn lookup_ip_follows_cnames_to_the_addresses() {
+    let upstream = MockUpstream::start();
+    let addresses = client(&upstream, true)
+        .lookup_ip("alias.client.test")
+        .unwrap();
+    assert_eq!(
+        addresses,
+        ["192.0.2.10".parse::<std::net::IpAddr>().unwrap()]
+    );
+    // The end of the chain was asked for, as the upstream didn't answer for it.
+    assert_eq!(
+        *upstream.asked.lock().unwrap(),
+        [
+            (String::from("alias.client.test"), false),
+            (String::from("www.client.test"), false),
+            (String::from("alias.client.test"), false),
+            (String::from("www.client.test"), false),
+        ]
+    );
+}
+
+#[test]
+fn the_query_subcommand_prints_the_answers_or_the_error() {
+    let upstream = MockUpstream::start();
+    let address = upstream.address.to_string();
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_codecrafters-dns-server"))
+            .arg("query")
+            .arg(&address)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = run(&["big.client.test"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("3 answer(s)"), "{stdout}");
+    assert!(stdout.contains("192.0.2.3"), "{stdout}");
+
+    let output = run(&["big.client.test", "A", "--no-tcp"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("truncated"));
+
+    let output = run(&["broken.client.test", "AAAA"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("SERVFAIL"));
+}
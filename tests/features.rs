@@ -0,0 +1,90 @@
+// The cargo feature matrix: every build accepts the flags of the optional features,
+// and either honours them or fails with a clear "compiled without" error. The tests
+// run the `check` subcommand, which exits before any socket is bound; run them once
+// per feature set, as CI would:
+//
+//     cargo test --test features
+//     cargo test --test features --features mdns
+//     cargo test --test features --features dnssec
+//     cargo test --test features --features scripting
+//     cargo test --test features --features scripting,time-zones
+//     cargo test --test features --all-features
+
+use std::process::{Command, Output};
+
+fn check(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_codecrafters-dns-server"))
+        .args(args)
+        .arg("check")
+        .output()
+        .expect("Failed to run the server")
+}
+
+// Checks that `args` pass when `enabled`, or else fail naming `feature`.
+fn assert_gated(args: &[&str], feature: &str, enabled: bool) {
+    let output = check(args);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if enabled {
+        assert!(
+            output.status.success(),
+            "{args:?} failed with the {feature} feature: {stderr}"
+        );
+    } else {
+        assert_eq!(output.status.code(), Some(2), "{args:?}: {stderr}");
+        assert!(
+            stderr.contains(&format!(
+                "needs the '{feature}' feature, and this build was compiled without it"
+            )),
+            "{args:?}: {stderr}"
+        );
+    }
+}
+
+#[test]
+fn core_build_checks_without_optional_flags() {
+    let output = check(&["--only-types", "A,AAAA"]);
+    assert!(output.status.success(), "{output:?}");
+}
+
+#[test]
+fn service_registration_needs_mdns() {
+    assert_gated(
+        &["--service", "web:_http._tcp:host.lan:8080"],
+        "mdns",
+        cfg!(feature = "mdns"),
+    );
+}
+
+#[test]
+fn trust_anchors_need_dnssec() {
+    // `check` exits before the anchors are loaded, so the file needn't exist.
+    assert_gated(
+        &["--trust-anchors", "/nonexistent/root-anchors.xml"],
+        "dnssec",
+        cfg!(feature = "dnssec"),
+    );
+}
+
+#[test]
+fn policy_scripts_need_scripting() {
+    assert_gated(
+        &["--policy-script", "/nonexistent/policy.script"],
+        "scripting",
+        cfg!(feature = "scripting"),
+    );
+}
+
+#[test]
+#[cfg(feature = "scripting")]
+fn schedule_time_zones_need_time_zones() {
+    assert_gated(
+        &[
+            "--policy-script",
+            "/nonexistent/policy.script",
+            "--policy-script-schedule",
+            "mon-fri 20:00-07:00 Europe/Berlin",
+        ],
+        "time-zones",
+        cfg!(feature = "time-zones"),
+    );
+}
@@ -0,0 +1,112 @@
+// The library's public API from outside the crate: a query built with the codec
+// (dns::message), answered by a server put together from the library's parts, or
+// set up from a command line as the binary does (cli::setup).
+
+use std::{
+    net::{SocketAddr, UdpSocket},
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use clap::Parser;
+use codecrafters_dns_server::cli::{setup, CliArgs};
+use codecrafters_dns_server::dns::message::{
+    Header, Message, Question, RCode, RData, RecordClass, RecordType,
+};
+use codecrafters_dns_server::server::{
+    DnsServer, DummyDnsResolver, Json, Listener, ListenerSpec, QueryOpcodeHandler, Randomness,
+};
+
+// Starts the server `build` returns, on its own thread, and returns the address of
+// its first listener. It serves until the test process exits.
+fn serve(build: impl FnOnce() -> DnsServer + Send + 'static) -> SocketAddr {
+    let (address, bound) = mpsc::channel();
+    thread::spawn(move || {
+        let server = build();
+        address
+            .send(server.listeners[0].socket.local_addr().unwrap())
+            .unwrap();
+        server.work();
+    });
+    bound.recv().unwrap()
+}
+
+// A server answering with the dummy resolver on a port the system picks.
+fn dummy_server() -> SocketAddr {
+    serve(|| {
+        let spec = ListenerSpec::parse("test=127.0.0.1:0").unwrap();
+        DnsServer::new(
+            vec![Listener::bind(&spec).unwrap()],
+            vec![Arc::new(QueryOpcodeHandler::new(Arc::new(
+                DummyDnsResolver::default(),
+            )))],
+        )
+    })
+}
+
+// Asks the server at `server` for `name` IN A, and returns its response.
+fn ask(server: SocketAddr, name: &str) -> Message {
+    let mut header = Header::default();
+    header.set_id(0x1234).set_rd(true).set_qd_count(1);
+    let question = Question::new(
+        &Arc::new(name.parse().unwrap()),
+        RecordType::A,
+        RecordClass::In,
+    );
+    let query = Message::new(&Arc::new(header), &Arc::from([question]), &Arc::from([]));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.send_to(&query.encode(), server).unwrap();
+    let mut buf = [0; 512];
+    let size = client.recv(&mut buf).unwrap();
+    Message::parse_from(&buf[..size]).unwrap()
+}
+
+#[test]
+fn a_query_built_with_the_codec_is_answered_by_the_server() {
+    let response = ask(dummy_server(), "example.com");
+
+    let header = response.get_header();
+    assert_eq!(header.get_id(), 0x1234);
+    assert!(header.get_qr());
+    assert_eq!(header.get_rcode(), &RCode::NoError);
+    assert_eq!(response.get_questions().len(), 1);
+    let answers = response.get_answers();
+    assert_eq!(answers.len(), 1);
+    assert!(matches!(
+        answers[0].get_rdata(),
+        RData::A(address) if address.octets() == [8, 8, 8, 8]
+    ));
+}
+
+#[test]
+fn a_server_set_up_from_a_command_line_answers_from_its_records() {
+    let server = serve(|| {
+        let cli = CliArgs::try_parse_from([
+            "codecrafters-dns-server",
+            "--port",
+            "0",
+            "--workers",
+            "1",
+            "--record",
+            "printer.lan A 192.0.2.7",
+        ])
+        .unwrap();
+        let randomness = Randomness::seeded(1);
+        let resolvers = setup::resolvers(&cli, setup::local_records(&cli), None, &randomness);
+        setup::server(cli, Json::Null, resolvers, randomness)
+    });
+    let response = ask(server, "printer.lan");
+
+    assert_eq!(response.get_header().get_rcode(), &RCode::NoError);
+    let answers = response.get_answers();
+    assert_eq!(answers.len(), 1);
+    assert!(matches!(
+        answers[0].get_rdata(),
+        RData::A(address) if address.octets() == [192, 0, 2, 7]
+    ));
+}
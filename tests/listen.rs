@@ -0,0 +1,79 @@
+// Where the server binary listens: --bind and --port, and what it says when it
+// can't bind. The server is run on a port the system picks (--port 0), which it
+// reports in its "Listening on" line.
+
+use std::{
+    io::{BufRead, BufReader},
+    net::{SocketAddr, UdpSocket},
+    process::{Child, Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+fn server(args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_codecrafters-dns-server"));
+    command.args(args);
+    command
+}
+
+// Reads the server's output up to its first "Listening on" line, and returns the
+// address in it. The rest is read and dropped, so that the server can go on writing.
+fn listening_on(child: &mut Child) -> SocketAddr {
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    for line in lines.by_ref().map_while(Result::ok) {
+        if let Some(rest) = line.strip_prefix("Listening on ") {
+            thread::spawn(move || lines.for_each(drop));
+            return rest.split(' ').next().unwrap().parse().unwrap();
+        }
+    }
+    panic!("the server exited without listening");
+}
+
+#[test]
+fn port_zero_binds_a_free_port_and_reports_it() {
+    let mut child = server(&["--bind", "127.0.0.1", "--port", "0"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let address = listening_on(&mut child);
+    assert!(address.ip().is_loopback());
+    assert_ne!(address.port(), 0);
+
+    // "example.com IN A", answered by the dummy resolver.
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut query = vec![0xAB, 0xCD, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    query.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+    client.send_to(&query, address).unwrap();
+    let mut response = [0; 512];
+    let (size, _) = client.recv_from(&mut response).unwrap();
+    assert!(size > query.len());
+    assert_eq!(response[..2], [0xAB, 0xCD]);
+
+    // SAFETY: kill has no memory safety requirements.
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn a_port_in_use_is_reported_with_the_address() {
+    let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port().to_string();
+    let output = server(&["--bind", "lan=127.0.0.1", "--port", &port])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!(
+            "Couldn't bind [lan] to 127.0.0.1:{port} (UDP and TCP): "
+        )),
+        "{stderr}"
+    );
+
+    let output = server(&["--bind", "localhost"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not a valid ADDRESS[:PORT]"));
+}
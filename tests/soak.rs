@@ -0,0 +1,635 @@
+// End-to-end soak test: runs the server binary with the TCP listener, a response
+// policy zone, the control socket and two mock upstreams (the second one for
+// cross-checks), then, all at once for a while:
+//
+// - client threads send a mixed workload over UDP and TCP;
+// - the first upstream flaps, answering SERVFAIL while it is down;
+// - the policy zone is rewritten every couple of seconds, for the server to reload;
+// - the control socket pins and unpins a name and is polled for the stats.
+//
+// Throughout, it checks that every query gets a response within QUERY_DEADLINE
+// with its ID, an expected RCODE and its answers in CNAME chain order, that the counters never go down, that live
+// memory stays under MAX_LIVE_BYTES (with --features profiling, which counts it),
+// and that the server neither panics nor exits. At the end, the server is sent
+// SIGTERM, and must shut down cleanly and in time, removing its control socket. A
+// failure dumps the provenance trace of the offending queries and the server's log
+// lines about them.
+//
+// The tests are ignored by default. Before a release, run the smoke version and a
+// long one, ideally with allocation counting:
+//
+//     cargo test --test soak -- --ignored soak_smoke
+//     SOAK_SECONDS=1800 SOAK_CLIENTS=32 cargo test --release --features profiling \
+//         --test soak -- --ignored soak_long --nocapture
+
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// How long a client waits for each response.
+const QUERY_DEADLINE: Duration = Duration::from_secs(3);
+
+// Bound on the server's live heap memory, checked when it is counted.
+const MAX_LIVE_BYTES: u64 = 256 << 20;
+
+// How long the server may take to shut down on SIGTERM.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(10);
+
+// Server log lines kept for the failure dump.
+const LOG_LINES: usize = 50_000;
+
+// EDNS option asking for the provenance annotation (see src/server/provenance.rs).
+const PROVENANCE_OPTION: u16 = 65001;
+
+// The workload: names the upstream answers, one the policy zone always blocks, one
+// it blocks every other reload, one that is pinned every now and then, and an alias
+// the upstream answers with its chain out of order.
+const NAMES: [&str; 6] = [
+    "www.soak.test",
+    "blocked.soak.test",
+    "mail.soak.test",
+    "flip.soak.test",
+    "pinned.soak.test",
+    "alias.soak.test",
+];
+
+// What went wrong with a query, or with an invariant.
+struct Failure {
+    name: String,
+    reason: String,
+    // The provenance annotation of the response, if there was one.
+    trace: Option<String>,
+}
+
+type Failures = Arc<Mutex<Vec<Failure>>>;
+
+fn fail(failures: &Failures, name: &str, reason: String, trace: Option<String>) {
+    failures.lock().unwrap().push(Failure {
+        name: name.to_string(),
+        reason,
+        trace,
+    });
+}
+
+// An upstream answering A questions with 192.0.2.1, or SERVFAIL while `down`. For
+// alias.soak.test it answers www.soak.test's address first and the CNAME to it
+// after, as a response pieced together from several sources might.
+struct MockUpstream {
+    address: SocketAddr,
+    down: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl MockUpstream {
+    fn start(stop: &Arc<AtomicBool>) -> MockUpstream {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let address = socket.local_addr().unwrap();
+        let down = Arc::new(AtomicBool::new(false));
+        let (stop, is_down) = (Arc::clone(stop), Arc::clone(&down));
+        let thread = thread::spawn(move || {
+            let mut buf = [0; 512];
+            while !stop.load(Ordering::Relaxed) {
+                let Ok((size, source)) = socket.recv_from(&mut buf) else {
+                    continue;
+                };
+                let mut response = buf[..size].to_vec();
+                response[2] |= 0x80;
+                response[3] |= 0x80;
+                let qtype = &response[size - 4..size - 2];
+                if is_down.load(Ordering::Relaxed) {
+                    response[3] = (response[3] & 0xF0) | 2;
+                } else if qtype == [0, 1] && response[13..].starts_with(b"alias") {
+                    let target = b"\x03www\x04soak\x04test\x00";
+                    response[7] = 2;
+                    response.extend_from_slice(target);
+                    response.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+                    response.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0]);
+                    response.push(target.len() as u8);
+                    response.extend_from_slice(target);
+                } else if qtype == [0, 1] {
+                    response[7] = 1;
+                    response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    response.extend_from_slice(&[192, 0, 2, 1]);
+                }
+                let _ = socket.send_to(&response, source);
+            }
+        });
+        MockUpstream {
+            address,
+            down,
+            thread,
+        }
+    }
+}
+
+// A port free for both UDP and TCP on the loopback address, for the server.
+fn free_port() -> u16 {
+    loop {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = tcp.local_addr().unwrap().port();
+        if UdpSocket::bind(("127.0.0.1", port)).is_ok() {
+            return port;
+        }
+    }
+}
+
+// The policy zone, with "flip" blocked or not.
+fn write_zone(path: &Path, serial: u32, flip: bool) {
+    let mut zone = format!(
+        "$TTL 300\n$ORIGIN rpz.soak.\n\
+         @ SOA localhost. hostmaster.localhost. {serial} 3600 600 86400 60\n\
+         blocked.soak.test CNAME .\n"
+    );
+    if flip {
+        zone.push_str("flip.soak.test CNAME .\n");
+    }
+    // Renamed into place, so that the server never reads half a file.
+    let staging = path.with_extension("tmp");
+    fs::write(&staging, zone).unwrap();
+    fs::rename(&staging, path).unwrap();
+}
+
+// A query for `name` IN A with RD set and an OPT record asking for provenance.
+fn query(id: u16, name: &str) -> Vec<u8> {
+    let mut data: Vec<u8> = id.to_be_bytes().to_vec();
+    data.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]);
+    for label in name.split('.') {
+        data.push(label.len() as u8);
+        data.extend_from_slice(label.as_bytes());
+    }
+    data.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x01]);
+    data.extend_from_slice(&[0x00, 0x00, 0x29, 0x04, 0xD0, 0x00, 0x00, 0x00, 0x00]);
+    data.extend_from_slice(&[0x00, 0x04]);
+    data.extend_from_slice(&PROVENANCE_OPTION.to_be_bytes());
+    data.extend_from_slice(&[0x00, 0x00]);
+    data
+}
+
+// The provenance annotation in a response: the text of a TXT record owned by
+// provenance.invalid, which the server puts in the additional section.
+fn trace(response: &[u8]) -> Option<String> {
+    let marker = b"\x0aprovenance\x07invalid\x00\x00\x10";
+    let at = response
+        .windows(marker.len())
+        .position(|window| window == marker)?;
+    let text = response.get(at + marker.len() + 9..)?;
+    let length = *text.first()? as usize;
+    Some(String::from_utf8_lossy(text.get(1..1 + length)?).to_string())
+}
+
+// The name at `offset` of `message`, lowercased, following compression pointers,
+// and the offset past it.
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    for _ in 0..128 {
+        let length = *message.get(offset)? as usize;
+        if length == 0 {
+            return Some((labels.join("."), end.unwrap_or(offset + 1)));
+        }
+        if length >= 0xC0 {
+            end.get_or_insert(offset + 2);
+            offset = ((length & 0x3F) << 8) | *message.get(offset + 1)? as usize;
+            continue;
+        }
+        let label = message.get(offset + 1..offset + 1 + length)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        offset += 1 + length;
+    }
+    None
+}
+
+// The answer records of a response: owner, and the target if it's a CNAME.
+fn answers(response: &[u8]) -> Option<Vec<(String, Option<String>)>> {
+    let count = u16::from_be_bytes([*response.get(6)?, *response.get(7)?]);
+    // Past the one question.
+    let (_, mut offset) = read_name(response, 12)?;
+    offset += 4;
+    let mut records = Vec::new();
+    for _ in 0..count {
+        let (owner, after) = read_name(response, offset)?;
+        let fields = response.get(after..after + 10)?;
+        let r#type = u16::from_be_bytes([fields[0], fields[1]]);
+        let length = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+        let target = match r#type {
+            5 => Some(read_name(response, after + 10)?.0),
+            _ => None,
+        };
+        records.push((owner, target));
+        offset = after + 10 + length;
+    }
+    Some(records)
+}
+
+// The first answer that comes before the CNAME to its owner, if any (the rule the
+// server's chain.rs keeps to).
+fn out_of_chain_order(response: &[u8]) -> Option<String> {
+    let Some(records) = answers(response) else {
+        return Some(String::from("unparseable answers"));
+    };
+    records.iter().enumerate().find_map(|(index, (owner, _))| {
+        records[index + 1..]
+            .iter()
+            .any(|(_, target)| target.as_ref() == Some(owner))
+            .then(|| format!("answer #{index} ({owner}) comes before the CNAME to it"))
+    })
+}
+
+// A client's connection to the server.
+enum Transport {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+impl Transport {
+    fn connect(server: SocketAddr, tcp: bool) -> Transport {
+        if tcp {
+            let stream = TcpStream::connect(server).unwrap();
+            stream.set_read_timeout(Some(QUERY_DEADLINE)).unwrap();
+            Transport::Tcp(stream)
+        } else {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.connect(server).unwrap();
+            socket.set_read_timeout(Some(QUERY_DEADLINE)).unwrap();
+            Transport::Udp(socket)
+        }
+    }
+
+    fn exchange(&mut self, request: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Transport::Udp(socket) => {
+                socket.send(request)?;
+                let mut buf = [0; 4096];
+                let size = socket.recv(&mut buf)?;
+                Ok(buf[..size].to_vec())
+            }
+            Transport::Tcp(stream) => {
+                let mut framed = (request.len() as u16).to_be_bytes().to_vec();
+                framed.extend_from_slice(request);
+                stream.write_all(&framed)?;
+                let mut length = [0; 2];
+                stream.read_exact(&mut length)?;
+                let mut response = vec![0; u16::from_be_bytes(length) as usize];
+                stream.read_exact(&mut response)?;
+                Ok(response)
+            }
+        }
+    }
+}
+
+// Sends queries for NAMES in turn until `stop`, checking each response.
+fn client(index: usize, server: SocketAddr, stop: Arc<AtomicBool>, failures: Failures) -> u64 {
+    let tcp = index % 2 == 1;
+    let mut transport = Transport::connect(server, tcp);
+    let mut sent: u64 = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let name = NAMES[(index + sent as usize) % NAMES.len()];
+        let id = ((index as u16) << 10) | (sent as u16 & 0x3FF);
+        sent += 1;
+        let started = Instant::now();
+        let response = match transport.exchange(&query(id, name)) {
+            Ok(response) => response,
+            Err(err) => {
+                let over = if tcp { "TCP" } else { "UDP" };
+                fail(
+                    &failures,
+                    name,
+                    format!("no response over {over}: {err}"),
+                    None,
+                );
+                // Starts over on a fresh connection, for the next query.
+                transport = Transport::connect(server, tcp);
+                continue;
+            }
+        };
+        if started.elapsed() > QUERY_DEADLINE {
+            let reason = format!("answered after {:?}", started.elapsed());
+            fail(&failures, name, reason, trace(&response));
+        }
+        if response.len() < 12 || response[..2] != id.to_be_bytes() {
+            fail(
+                &failures,
+                name,
+                String::from("response to another query"),
+                None,
+            );
+            continue;
+        }
+        let rcode = response[3] & 0x0F;
+        let expected: &[u8] = match name {
+            "blocked.soak.test" => &[3],
+            "flip.soak.test" => &[0, 2, 3],
+            _ => &[0, 2],
+        };
+        if !expected.contains(&rcode) {
+            let reason = format!("RCODE {rcode}, expected one of {expected:?}");
+            fail(&failures, name, reason, trace(&response));
+        }
+        if let Some(reason) = out_of_chain_order(&response) {
+            fail(&failures, name, reason, trace(&response));
+        }
+    }
+    sent
+}
+
+// Sends a command over the control socket and returns its output.
+fn control(path: &Path, command: &str) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    writeln!(stream, "{command}")?;
+    let mut output = String::new();
+    stream.read_to_string(&mut output)?;
+    Ok(output)
+}
+
+// The counters in the output of the stats command, by name, e.g. ("queries", 12)
+// or ("[soak] responses", 10). Live memory goes up and down, so it is left out;
+// ratios aren't counters either.
+fn counters(stats: &str) -> Vec<(String, u64)> {
+    let mut counters: Vec<(String, u64)> = Vec::new();
+    for group in stats.trim().split("; ") {
+        let (prefix, group) = match group.strip_prefix('[') {
+            Some(rest) => rest
+                .split_once("] ")
+                .map_or(("", group), |(tag, rest)| (tag, rest)),
+            None => ("", group),
+        };
+        for field in group.split(", ") {
+            let Some((name, value)) = field.split_once(": ") else {
+                continue;
+            };
+            let value = value.split(' ').next().unwrap_or_default();
+            if name == "live" || name.contains('/') {
+                continue;
+            }
+            if let Ok(value) = value.parse::<u64>() {
+                counters.push((format!("{prefix}{name}"), value));
+            }
+        }
+    }
+    counters
+}
+
+// The server binary, run with its output collected for the failure dump.
+struct Server {
+    child: Child,
+    log: Arc<Mutex<VecDeque<String>>>,
+    errors: Arc<Mutex<Vec<String>>>,
+}
+
+impl Server {
+    fn start(args: &[String]) -> Server {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_codecrafters-dns-server"))
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let log: Arc<Mutex<VecDeque<String>>> = Arc::default();
+        let errors: Arc<Mutex<Vec<String>>> = Arc::default();
+        let stdout = child.stdout.take().unwrap();
+        let lines = Arc::clone(&log);
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                let mut lines = lines.lock().unwrap();
+                if lines.len() == LOG_LINES {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+            }
+        });
+        let stderr = child.stderr.take().unwrap();
+        let lines = Arc::clone(&errors);
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                lines.lock().unwrap().push(line);
+            }
+        });
+        Server { child, log, errors }
+    }
+
+    // The last log lines that mention `name`.
+    fn log_about(&self, name: &str) -> Vec<String> {
+        let log = self.log.lock().unwrap();
+        let lines: Vec<String> = log
+            .iter()
+            .filter(|line| line.contains(name))
+            .cloned()
+            .collect();
+        lines[lines.len().saturating_sub(10)..].to_vec()
+    }
+
+    // Sends the server SIGTERM and waits up to `deadline` for it to exit.
+    fn terminate(&mut self, deadline: Duration) -> Option<ExitStatus> {
+        // SAFETY: kill has no memory safety requirements.
+        unsafe { libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM) };
+        let until = Instant::now() + deadline;
+        while Instant::now() < until {
+            if let Ok(Some(status)) = self.child.try_wait() {
+                return Some(status);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        None
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn run_soak(duration: Duration, clients: usize) {
+    let directory: PathBuf = std::env::temp_dir().join(format!("soak-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let zone = directory.join("soak.rpz");
+    let socket = directory.join("control.sock");
+    write_zone(&zone, 1, false);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let primary = MockUpstream::start(&stop);
+    let secondary = MockUpstream::start(&stop);
+    let port = free_port();
+    let server = Server::start(&[
+        format!("--bind=soak=127.0.0.1:{port}"),
+        format!("--resolver={}", primary.address),
+        String::from("--verify-answers=10"),
+        format!("--verify-answers-upstream={}", secondary.address),
+        format!("--rpz={}", zone.display()),
+        format!("--control-socket={}", socket.display()),
+    ]);
+    let ready_by = Instant::now() + Duration::from_secs(10);
+    while control(&socket, "stats").is_err() {
+        assert!(Instant::now() < ready_by, "the server didn't start");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let failures: Failures = Arc::default();
+    let address: SocketAddr = (Ipv4Addr::LOCALHOST, port).into();
+    let client_threads: Vec<JoinHandle<u64>> = (0..clients)
+        .map(|index| {
+            let (stop, failures) = (Arc::clone(&stop), Arc::clone(&failures));
+            thread::spawn(move || client(index, address, stop, failures))
+        })
+        .collect();
+
+    // Flaps the primary upstream, reloads the zone and works the control socket
+    // until the time is up.
+    let started = Instant::now();
+    let mut previous: Vec<(String, u64)> = Vec::new();
+    let mut tick: u32 = 0;
+    while started.elapsed() < duration {
+        thread::sleep(Duration::from_millis(250));
+        tick += 1;
+        if tick % 3 == 0 {
+            primary.down.fetch_xor(true, Ordering::Relaxed);
+        }
+        if tick % 8 == 0 {
+            write_zone(&zone, 1 + tick / 8, tick % 16 == 0);
+        }
+        let command = match tick % 4 {
+            0 => "pin pinned.soak.test A 60 192.0.2.9",
+            1 => "clients",
+            2 => "unpin pinned.soak.test A",
+            _ => "rpz",
+        };
+        if let Err(err) = control(&socket, command) {
+            fail(&failures, "control", format!("{command}: {err}"), None);
+        }
+        let stats = match control(&socket, "stats") {
+            Ok(stats) => stats,
+            Err(err) => {
+                fail(&failures, "control", format!("stats: {err}"), None);
+                continue;
+            }
+        };
+        let current = counters(&stats);
+        for (name, value) in &current {
+            if let Some((_, before)) = previous.iter().find(|(known, _)| known == name) {
+                if value < before {
+                    let reason = format!("counter {name} went down from {before} to {value}");
+                    fail(&failures, "stats", reason, None);
+                }
+            }
+        }
+        previous = current;
+        if cfg!(feature = "profiling") {
+            let live = stats
+                .split(", ")
+                .find_map(|field| field.strip_prefix("live: "))
+                .and_then(|value| value.split(' ').next()?.parse::<u64>().ok());
+            match live {
+                Some(live) if live > MAX_LIVE_BYTES => {
+                    let reason = format!("{live} bytes live, over {MAX_LIVE_BYTES}");
+                    fail(&failures, "memory", reason, None);
+                }
+                Some(_) => {}
+                None => fail(
+                    &failures,
+                    "memory",
+                    format!("no live bytes in {stats}"),
+                    None,
+                ),
+            }
+        }
+    }
+    stop.store(true, Ordering::Relaxed);
+    let sent: u64 = client_threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .sum();
+    primary.thread.join().unwrap();
+    secondary.thread.join().unwrap();
+
+    let mut server = server;
+    if let Ok(Some(status)) = server.child.try_wait() {
+        fail(&failures, "server", format!("exited: {status}"), None);
+    }
+    match server.terminate(SHUTDOWN_DEADLINE) {
+        Some(status) if status.success() => {}
+        Some(status) => fail(
+            &failures,
+            "server",
+            format!("shut down with {status}"),
+            None,
+        ),
+        None => {
+            let reason = format!("didn't shut down within {SHUTDOWN_DEADLINE:?}");
+            fail(&failures, "server", reason, None);
+        }
+    }
+    if socket.exists() {
+        let reason = String::from("left its control socket behind");
+        fail(&failures, "server", reason, None);
+    }
+    for line in server.errors.lock().unwrap().iter() {
+        if line.contains("panicked") || line.contains("[SHUTDOWN]") {
+            fail(&failures, "server", line.clone(), None);
+        }
+    }
+    let responses = previous
+        .iter()
+        .find(|(name, _)| name == "responses")
+        .map_or(0, |(_, value)| *value);
+
+    let failures = failures.lock().unwrap();
+    for failure in failures.iter().take(20) {
+        eprintln!("{}: {}", failure.name, failure.reason);
+        if let Some(trace) = &failure.trace {
+            eprintln!("    trace: {trace}");
+        }
+        for line in server.log_about(&failure.name) {
+            eprintln!("    log: {line}");
+        }
+    }
+    let _ = fs::remove_dir_all(&directory);
+    assert!(
+        failures.is_empty(),
+        "{} failure(s) in {sent} queries",
+        failures.len()
+    );
+    assert!(
+        sent > 0 && responses > 0,
+        "{sent} queries, {responses} responses"
+    );
+    println!("Soaked {sent} queries over {duration:?}.");
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[test]
+#[ignore = "runs the server for 10 seconds"]
+fn soak_smoke() {
+    run_soak(Duration::from_secs(10), 8);
+}
+
+#[test]
+#[ignore = "runs the server for SOAK_SECONDS, 10 minutes by default"]
+fn soak_long() {
+    run_soak(
+        Duration::from_secs(env_or("SOAK_SECONDS", 600)),
+        env_or("SOAK_CLIENTS", 16),
+    );
+}
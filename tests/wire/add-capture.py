@@ -0,0 +1,110 @@
+#!/usr/bin/env python3
+# Adds a message to the wire-format corpus (see MANIFEST).
+#
+#   add-capture.py pcap CAPTURE.pcap ENTRY SOURCE [--index N] [--reencode DIFFS] [--description TEXT]
+#       takes the N-th (default first) DNS-over-UDP payload (port 53) from a classic pcap file
+#   add-capture.py query SERVER NAME TYPE ENTRY SOURCE [--reencode DIFFS] [--description TEXT]
+#       sends the query `dig +noedns @SERVER NAME TYPE` would send and records the response
+#
+# Afterwards run `UPDATE_GOLDEN=1 cargo test wire_corpus`, review the generated
+# <ENTRY>.txt (and <ENTRY>.canonical.hex) and commit them together with the capture.
+
+import argparse
+import os
+import random
+import socket
+import struct
+import sys
+
+CORPUS_DIR = os.path.dirname(os.path.abspath(__file__))
+RECORD_TYPES = {"A": 1, "NS": 2, "CNAME": 5, "SOA": 6, "PTR": 12, "MX": 15, "TXT": 16,
+                "AAAA": 28, "SRV": 33, "DS": 43, "RRSIG": 46, "NSEC": 47, "DNSKEY": 48,
+                "HTTPS": 65, "CAA": 257, "ANY": 255}
+
+
+def udp_payloads(path):
+    with open(path, "rb") as f:
+        data = f.read()
+    magic = data[:4]
+    if magic in (b"\xd4\xc3\xb2\xa1", b"\x4d\x3c\xb2\xa1"):
+        endian = "<"
+    elif magic in (b"\xa1\xb2\xc3\xd4", b"\xa1\xb2\x3c\x4d"):
+        endian = ">"
+    else:
+        sys.exit(f"{path} is not a classic pcap file (pcapng is not supported; convert it with editcap -F pcap)")
+    link_type = struct.unpack(endian + "I", data[20:24])[0]
+    offset = 24
+    while offset + 16 <= len(data):
+        captured = struct.unpack(endian + "I", data[offset + 8:offset + 12])[0]
+        frame = data[offset + 16:offset + 16 + captured]
+        offset += 16 + captured
+        if link_type == 1:  # Ethernet
+            ether_type, ip = struct.unpack(">H", frame[12:14])[0], frame[14:]
+        elif link_type == 113:  # Linux cooked capture
+            ether_type, ip = struct.unpack(">H", frame[14:16])[0], frame[16:]
+        elif link_type == 101:  # raw IP
+            ether_type, ip = (0x86DD if frame[0] >> 4 == 6 else 0x0800), frame
+        else:
+            sys.exit(f"Unsupported link type {link_type}")
+        if ether_type == 0x0800 and ip[9] == 17:
+            udp = ip[(ip[0] & 0x0F) * 4:]
+        elif ether_type == 0x86DD and ip[6] == 17:
+            udp = ip[40:]
+        else:
+            continue
+        source_port, destination_port, length = struct.unpack(">HHH", udp[:6])
+        if 53 in (source_port, destination_port):
+            yield udp[8:length]
+
+
+def query(server, name, record_type):
+    host, _, port = server.partition(":")
+    qtype = RECORD_TYPES.get(record_type.upper()) or int(record_type.upper().removeprefix("TYPE"))
+    labels = b"".join(bytes([len(label)]) + label.encode() for label in name.split(".") if label)
+    request = struct.pack(">HHHHHH", random.getrandbits(16), 0x0120, 1, 0, 0, 0)
+    request += labels + b"\0" + struct.pack(">HH", qtype, 1)
+    with socket.socket(socket.AF_INET6 if ":" in host else socket.AF_INET, socket.SOCK_DGRAM) as s:
+        s.settimeout(5)
+        s.sendto(request, (host, int(port or 53)))
+        response, _ = s.recvfrom(65535)
+    return response
+
+
+def main():
+    parser = argparse.ArgumentParser()
+    commands = parser.add_subparsers(dest="command", required=True)
+    pcap = commands.add_parser("pcap")
+    pcap.add_argument("capture")
+    pcap.add_argument("--index", type=int, default=0)
+    live = commands.add_parser("query")
+    live.add_argument("server")
+    live.add_argument("name")
+    live.add_argument("type")
+    for command in (pcap, live):
+        command.add_argument("entry")
+        command.add_argument("source")
+        command.add_argument("--reencode", default="identical")
+        command.add_argument("--description", default="")
+    args = parser.parse_args()
+
+    if args.command == "pcap":
+        payloads = list(udp_payloads(args.capture))
+        if args.index >= len(payloads):
+            sys.exit(f"{args.capture} has {len(payloads)} DNS payload(s)")
+        message = payloads[args.index]
+    else:
+        message = query(args.server, args.name, args.type)
+
+    path = os.path.join(CORPUS_DIR, args.entry + ".hex")
+    if os.path.exists(path):
+        sys.exit(f"{path} already exists")
+    rows = [" ".join(f"{byte:02x}" for byte in message[i:i + 16]) for i in range(0, len(message), 16)]
+    with open(path, "w") as f:
+        f.write(f"# {args.source}: {args.description}\n" + "\n".join(rows) + "\n")
+    with open(os.path.join(CORPUS_DIR, "MANIFEST"), "a") as f:
+        f.write(f"{args.entry:<26}{args.source:<12}{args.reencode:<38}{args.description}\n".rstrip() + "\n")
+    print(f"Added {path} ({len(message)} bytes).")
+
+
+if __name__ == "__main__":
+    main()